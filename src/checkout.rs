@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::fs::{Metadata, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, symlink};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::slice;
+use anyhow::{bail, Context};
+use crate::common::{ObjectMode, ObjectType};
+use crate::diff::read_blob;
+use crate::index::{Index, IndexEntry};
+use crate::object_read::find_and_decode_object;
+use crate::pathspec::Pathspec;
+use crate::tree_diff::{diff_index_to_worktree, DiffSide, drop_unchanged_worktree_files, list_index_files, list_tree_files, walk_trees};
+
+/// What the working tree is moved to another tree for, it decides how conflicts with local changes are reported
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SwitchAction {
+    Checkout,
+    Merge,
+    /// the plain messages of reading trees, used by reset and read-tree
+    Plain,
+}
+
+pub(crate) struct ReadTreeOptions {
+    /// take the last tree even where the index or the working tree have local changes
+    pub force: bool,
+    /// also resolve paths removed on one side and unchanged on the other in a three-way merge
+    pub aggressive: bool,
+    /// write the changed files to the working tree, otherwise only the index is changed
+    pub update_worktree: bool,
+    pub action: SwitchAction,
+}
+
+/// Why a file stops reading trees into the index
+enum Conflict {
+    /// the index has changes to the file
+    Staged,
+    /// the working tree has changes to the file
+    Unstaged,
+    /// the file is not tracked but exists in the working tree
+    Untracked,
+}
+
+/// What happens to a path when trees are read into the index
+#[derive(Debug, PartialEq)]
+enum Resolution {
+    /// the index entry and the working tree file stay as they are
+    Keep,
+    /// the merged version, None removes the path
+    Take(Option<DiffSide>),
+    /// the base, ours and theirs versions go to conflict stages 1, 2 and 3
+    Conflict([Option<DiffSide>; 3]),
+}
+
+/// Moves the index and the working tree from the old tree to the new one, the way git does when switching branches.
+/// Local changes to files that are the same in both trees are carried over, other local changes make it fail.
+/// With `force` the index and the working tree are reset to the new tree instead
+pub(crate) fn switch_trees(old_tree: Option<&str>, new_tree: Option<&str>, index: &mut Index, force: bool, action: SwitchAction) -> anyhow::Result<()> {
+    let options = ReadTreeOptions { force, aggressive: false, update_worktree: true, action };
+    read_trees(&[old_tree, new_tree], index, &options)
+}
+
+/// Merges one to three trees into the index with the rules of git read-tree -m.
+/// One tree replaces the index, keeping the entries that do not change. Two trees move the index from the first
+/// to the second like a checkout. Three trees are the base, ours and theirs of a merge, paths changed on only one side
+/// are resolved and the others become conflicts. The index has to match ours where the merge changes it,
+/// and the working tree has to match the index
+pub(crate) fn read_trees(trees: &[Option<&str>], index: &mut Index, options: &ReadTreeOptions) -> anyhow::Result<()> {
+    if !options.force {
+        if let Some(entry) = index.entries.iter().find(|x| x.stage != 0) {
+            match options.action {
+                SwitchAction::Checkout | SwitchAction::Merge => bail!("you need to resolve your current index first\n{}: needs merge", entry.path),
+                SwitchAction::Plain => bail!("You need to resolve your current index first"),
+            }
+        }
+    }
+    let mut paths: BTreeMap<String, Vec<Option<DiffSide>>> = BTreeMap::new();
+    walk_trees(trees, "", &mut |path, sides| {
+        paths.insert(path, sides);
+        Ok(())
+    })?;
+    for entry in &index.entries {
+        paths.entry(entry.path.clone()).or_insert_with(|| vec![None; trees.len()]);
+    }
+    let pathspec = Pathspec::new(&[]);
+    // files deleted from the working tree do not count as local changes, they are just written again
+    let dirty = drop_unchanged_worktree_files(diff_index_to_worktree(index, &pathspec)?)?
+        .into_iter()
+        .filter(|x| x.new.is_some())
+        .map(|x| x.path)
+        .collect::<HashSet<_>>();
+
+    let current_sides = index.entries
+        .iter()
+        .filter(|x| x.stage == 0)
+        .map(|x| (x.path.as_str(), DiffSide::new(x.mode, &x.hash)))
+        .collect::<HashMap<_, _>>();
+
+    let mut conflicts = vec![];
+    let mut targets = vec![];
+    let mut conflict_stages = vec![];
+    for (path, sides) in paths {
+        let current = current_sides.get(path.as_str()).cloned();
+        let clean = !dirty.contains(&path);
+        if options.force {
+            let new = sides.last().cloned().flatten();
+            if current != new || !clean {
+                targets.push((path, new));
+            }
+            continue;
+        }
+        // the version the index has to have for the path to change
+        let (expected, resolution) = match <[Option<DiffSide>; 3]>::try_from(sides) {
+            Ok([base, ours, theirs]) => (ours.clone(), merge_three_way(base, ours, theirs, options.aggressive)),
+            Err(sides) => match sides.as_slice() {
+                [old, new] if old == new => (None, Resolution::Keep),
+                [old, new] => (old.clone(), Resolution::Take(new.clone())),
+                [new] => (current.clone(), Resolution::Take(new.clone())),
+                _ => bail!("Cannot read {} trees at once", trees.len()),
+            },
+        };
+        match resolution {
+            Resolution::Keep => {},
+            Resolution::Take(new) if new == current => {},
+            Resolution::Take(_) | Resolution::Conflict(_) if current != expected => conflicts.push((path, Conflict::Staged)),
+            Resolution::Take(_) | Resolution::Conflict(_) if !clean => conflicts.push((path, Conflict::Unstaged)),
+            Resolution::Take(new) => {
+                if options.update_worktree && current.is_none() && fs::symlink_metadata(&path).is_ok_and(|x| !x.is_dir()) {
+                    conflicts.push((path, Conflict::Untracked));
+                } else {
+                    targets.push((path, new));
+                }
+            },
+            Resolution::Conflict(stages) => conflict_stages.push((path, stages)),
+        }
+    }
+    if !conflicts.is_empty() {
+        bail!(conflict_message(&conflicts, options.action));
+    }
+    apply_targets(index, targets, options.update_worktree)?;
+    add_conflict_stages(index, conflict_stages);
+    Ok(())
+}
+
+/// Replaces the index entries of the paths with their base, ours and theirs versions as conflict stages 1, 2 and 3
+pub(crate) fn add_conflict_stages(index: &mut Index, conflicts: Vec<(String, [Option<DiffSide>; 3])>) {
+    let mut changed = HashSet::new();
+    let mut entries = vec![];
+    for (path, stages) in conflicts {
+        for (stage, side) in (1..).zip(stages) {
+            if let Some(side) = side {
+                entries.push(IndexEntry { stage, ..IndexEntry::without_stat(&path, side.mode, &side.hash) });
+            }
+        }
+        changed.insert(path);
+    }
+    index.replace_entries(&changed, entries);
+}
+
+/// Adds the files of the tree to the index below the directory, none of them may be in the index yet
+pub(crate) fn read_tree_prefix(tree: &str, prefix: &str, index: &mut Index, update_worktree: bool) -> anyhow::Result<()> {
+    let prefix = match prefix.trim_end_matches('/') {
+        "" => String::new(),
+        dir => format!("{dir}/"),
+    };
+    let mut targets = vec![];
+    walk_trees(&[Some(tree)], &prefix, &mut |path, sides| {
+        targets.push((path, sides.into_iter().next().flatten()));
+        Ok(())
+    })?;
+    if let Some((path, _)) = targets.iter().find(|(path, _)| index.entries.iter().any(|x| x.path == *path)) {
+        bail!("Entry '{path}' overlaps with '{path}'.  Cannot bind.");
+    }
+    apply_targets(index, targets, update_worktree)
+}
+
+/// The trivial merges of read-tree, contents are never merged here
+fn merge_three_way(base: Option<DiffSide>, ours: Option<DiffSide>, theirs: Option<DiffSide>, aggressive: bool) -> Resolution {
+    if ours == theirs {
+        return match (&ours, &base) {
+            (Some(_), _) | (None, None) => Resolution::Take(ours),
+            // removed on both sides
+            (None, Some(_)) if aggressive => Resolution::Take(None),
+            (None, Some(_)) => Resolution::Conflict([base, None, None]),
+        };
+    }
+    match (base == ours, base == theirs) {
+        (true, _) if theirs.is_some() || aggressive => Resolution::Take(theirs),
+        (_, true) if ours.is_some() || aggressive => Resolution::Take(ours),
+        _ => Resolution::Conflict([base, ours, theirs]),
+    }
+}
+
+fn conflict_message(conflicts: &[(String, Conflict)], action: SwitchAction) -> String {
+    if action == SwitchAction::Plain {
+        // reading trees stops at the first conflict
+        let (path, conflict) = &conflicts[0];
+        return match conflict {
+            Conflict::Staged => format!("Entry '{path}' would be overwritten by merge. Cannot merge."),
+            Conflict::Unstaged => format!("Entry '{path}' not uptodate. Cannot merge."),
+            Conflict::Untracked => format!("Untracked working tree file '{path}' would be overwritten by merge."),
+        };
+    }
+    let (action, purpose) = match action {
+        SwitchAction::Merge => ("merge", "merge"),
+        _ => ("checkout", "switch branches"),
+    };
+    let (untracked, local_changes): (Vec<_>, Vec<_>) = conflicts.iter().partition(|(_, x)| matches!(x, Conflict::Untracked));
+    let mut message = String::new();
+    if !local_changes.is_empty() {
+        message.push_str(&format!("Your local changes to the following files would be overwritten by {action}:\n"));
+        local_changes.iter().for_each(|(path, _)| message.push_str(&format!("\t{path}\n")));
+        message.push_str(&format!("Please commit your changes or stash them before you {purpose}.\n"));
+    }
+    if !untracked.is_empty() {
+        message.push_str(&format!("The following untracked working tree files would be overwritten by {action}:\n"));
+        untracked.iter().for_each(|(path, _)| message.push_str(&format!("\t{path}\n")));
+        message.push_str(&format!("Please move or remove them before you {purpose}.\n"));
+    }
+    message.push_str("Aborting");
+    message
+}
+
+/// Makes the index entries matching the pathspec the same as the files of the tree, conflict stages included.
+/// Entries that do not change keep their stat data
+pub(crate) fn reset_index(tree: Option<&str>, pathspec: &Pathspec, index: &mut Index) -> anyhow::Result<()> {
+    let current_entries = index.entries
+        .iter()
+        .filter(|x| x.stage == 0)
+        .map(|x| (x.path.as_str(), x))
+        .collect::<HashMap<_, _>>();
+    let mut paths = index.entries
+        .iter()
+        .filter(|x| pathspec.matches(&x.path))
+        .map(|x| x.path.clone())
+        .collect::<HashSet<_>>();
+    let mut entries = vec![];
+    for (path, side) in list_tree_files(tree, pathspec)? {
+        let entry = match current_entries.get(path.as_str()) {
+            Some(entry) if DiffSide::new(entry.mode, &entry.hash) == side => (*entry).clone(),
+            _ => IndexEntry::without_stat(&path, side.mode, &side.hash),
+        };
+        entries.push(entry);
+        paths.insert(path);
+    }
+    index.replace_entries(&paths, entries);
+    Ok(())
+}
+
+/// Where `restore` takes the files from
+pub(crate) enum RestoreSource {
+    Index,
+    Tree(String),
+}
+
+/// Restores the paths matching the patterns in the index, the working tree or both from the source.
+/// Tracked files that are not in the source are removed
+pub(crate) fn restore_paths(source: &RestoreSource, patterns: &[String], index: &mut Index, staged: bool, worktree: bool) -> anyhow::Result<()> {
+    let pathspec = Pathspec::new(patterns);
+    let source_files = match source {
+        RestoreSource::Index => {
+            if let Some(entry) = index.entries.iter().find(|x| x.stage != 0 && pathspec.matches(&x.path)) {
+                bail!("path '{}' is unmerged", entry.path);
+            }
+            list_index_files(index, &pathspec)
+        },
+        RestoreSource::Tree(tree) => list_tree_files(Some(tree), &pathspec)?,
+    };
+    let source_files = source_files.into_iter().collect::<BTreeMap<_, _>>();
+    let mut paths = source_files.keys().cloned().collect::<BTreeSet<_>>();
+    paths.extend(index.entries.iter().filter(|x| pathspec.matches(&x.path)).map(|x| x.path.clone()));
+    for pattern in patterns {
+        let single = Pathspec::new(slice::from_ref(pattern));
+        if !paths.iter().any(|x| single.matches(x)) {
+            bail!("pathspec '{pattern}' did not match any file(s) known to git");
+        }
+    }
+
+    let current_entries = index.entries
+        .iter()
+        .filter(|x| x.stage == 0)
+        .map(|x| (x.path.clone(), x.clone()))
+        .collect::<HashMap<_, _>>();
+    let current_side = |path: &str| current_entries.get(path).map(|x| DiffSide::new(x.mode, &x.hash));
+    let mut written = HashMap::new();
+    if worktree {
+        let dirty = drop_unchanged_worktree_files(diff_index_to_worktree(index, &pathspec)?)?
+            .into_iter()
+            .map(|x| x.path)
+            .collect::<HashSet<_>>();
+        for path in &paths {
+            match source_files.get(path) {
+                Some(side) if current_side(path).as_ref() != Some(side) || dirty.contains(path) => {
+                    written.insert(path.clone(), checkout_file(path, side)?);
+                },
+                Some(_) => {},
+                None => remove_worktree_file(path)?,
+            }
+        }
+    }
+
+    let mut changed = HashSet::new();
+    let mut entries = vec![];
+    for path in paths {
+        let side = source_files.get(&path);
+        let entry = match (side, written.get(&path)) {
+            (Some(side), Some(meta)) if staged || current_side(&path).as_ref() == Some(side) => {
+                Some(IndexEntry::new(&path, side.mode, &side.hash, meta))
+            },
+            (Some(side), _) if staged => match current_entries.get(&path) {
+                Some(entry) if DiffSide::new(entry.mode, &entry.hash) == *side => Some(entry.clone()),
+                _ => Some(IndexEntry::without_stat(&path, side.mode, &side.hash)),
+            },
+            (None, _) if staged => None,
+            _ => continue,
+        };
+        entries.extend(entry);
+        changed.insert(path);
+    }
+    index.replace_entries(&changed, entries);
+    Ok(())
+}
+
+/// Updates the index to the new versions of the files, removing the paths without one, and writes the files
+/// to the working tree or removes them there too. All removals happen first, so that a file can replace a directory
+/// and the other way around
+pub(crate) fn apply_targets(index: &mut Index, targets: Vec<(String, Option<DiffSide>)>, update_worktree: bool) -> anyhow::Result<()> {
+    if update_worktree {
+        for (path, _) in targets.iter().filter(|(_, new)| new.is_none()) {
+            remove_worktree_file(path)?;
+        }
+    }
+    let mut entries = vec![];
+    for (path, new) in &targets {
+        if let Some(new) = new {
+            let entry = match update_worktree {
+                true => IndexEntry::new(path, new.mode, &new.hash, &checkout_file(path, new)?),
+                false => IndexEntry::without_stat(path, new.mode, &new.hash),
+            };
+            entries.push(entry);
+        }
+    }
+    let changed = targets.into_iter().map(|(path, _)| path).collect::<HashSet<_>>();
+    index.replace_entries(&changed, entries);
+    Ok(())
+}
+
+/// Writes a blob to the working tree with the mode of the entry, replacing whatever is at the path,
+/// and returns the metadata of the new file for the index
+pub(crate) fn checkout_file(path: &str, side: &DiffSide) -> anyhow::Result<Metadata> {
+    let file_path = Path::new(path);
+    if let Some(parent) = file_path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+    }
+    match fs::symlink_metadata(file_path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir(file_path).context(format!("Failed to remove directory {path}"))?,
+        Ok(_) => fs::remove_file(file_path).context(format!("Failed to remove {path}"))?,
+        Err(_) => {},
+    }
+    match side.mode {
+        ObjectMode::Symlink => {
+            let target = read_blob(&side.hash)?;
+            symlink(OsStr::from_bytes(&target), file_path).context(format!("Failed to create symlink {path}"))?;
+        },
+        // submodules are not cloned, they are just an empty directory
+        ObjectMode::Gitlink => fs::create_dir(file_path).context(format!("Failed to create {path}"))?,
+        ObjectMode::Tree => bail!("Cannot check out a tree at {path}"),
+        ObjectMode::Normal | ObjectMode::Executable => {
+            let object = find_and_decode_object(&side.hash)?;
+            if object.object_type != ObjectType::Blob {
+                bail!("Object {} at {path} is not a blob, it is actually a {}", side.hash, object.object_type);
+            }
+            let permissions = if side.mode == ObjectMode::Executable { 0o777 } else { 0o666 };
+            let file = OpenOptions::new().write(true).create_new(true).mode(permissions).open(file_path)
+                .context(format!("Failed to create {path}"))?;
+            let mut writer = BufWriter::new(file);
+            object.drain_into_writer_raw(&mut writer)?;
+            writer.flush().context(format!("Failed to write {path}"))?;
+        },
+    }
+    fs::symlink_metadata(file_path).context(format!("Failed to read metadata for {path}"))
+}
+
+/// Removes a file from the working tree together with the directories that become empty
+pub(crate) fn remove_worktree_file(path: &str) -> anyhow::Result<()> {
+    let file_path = Path::new(path);
+    match fs::symlink_metadata(file_path) {
+        Ok(meta) if meta.is_dir() => return Ok(()),
+        Ok(_) => fs::remove_file(file_path).context(format!("Failed to remove {path}"))?,
+        Err(error) if error.kind() == ErrorKind::NotFound || error.kind() == ErrorKind::NotADirectory => {},
+        Err(error) => return Err(error).context(format!("Failed to read metadata for {path}")),
+    }
+    for dir in file_path.ancestors().skip(1).filter(|x| !x.as_os_str().is_empty()) {
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge_three_way() {
+        let side = |x: &str| Some(DiffSide::new(ObjectMode::Normal, &x.repeat(40)));
+        let take = |x: Option<DiffSide>| Resolution::Take(x);
+        assert_eq!(take(side("a")), merge_three_way(side("b"), side("a"), side("a"), false));
+        assert_eq!(take(side("t")), merge_three_way(side("b"), side("b"), side("t"), false));
+        assert_eq!(take(side("o")), merge_three_way(side("b"), side("o"), side("b"), false));
+        assert_eq!(take(side("o")), merge_three_way(None, side("o"), None, false));
+        assert_eq!(Resolution::Conflict([None, side("o"), side("t")]), merge_three_way(None, side("o"), side("t"), false));
+        // removals are only resolved when aggressive
+        assert_eq!(Resolution::Conflict([side("b"), side("b"), None]), merge_three_way(side("b"), side("b"), None, false));
+        assert_eq!(take(None), merge_three_way(side("b"), side("b"), None, true));
+        assert_eq!(Resolution::Conflict([side("b"), None, None]), merge_three_way(side("b"), None, None, false));
+        assert_eq!(take(None), merge_three_way(side("b"), None, None, true));
+        assert_eq!(Resolution::Conflict([side("b"), None, side("t")]), merge_three_way(side("b"), None, side("t"), true));
+    }
+}
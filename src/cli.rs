@@ -1,5 +1,12 @@
+use std::ffi::OsString;
 use clap::{Args, Parser, Subcommand};
+use crate::color::ColorWhen;
 use crate::common::ObjectType;
+use crate::diff::{DiffAlgorithm, DiffOptions, Whitespace, WordDiff};
+use crate::diff_output::DiffFormat;
+use crate::file_merge::MARKER_SIZE;
+use crate::message_cleanup::CleanupMode;
+use crate::rename::{parse_score, RenameDetection, RenameOptions};
 
 /// a subset of git, implemented as a learning challenge
 #[derive(Parser)]
@@ -8,6 +15,27 @@ pub(crate) struct Cli {
     pub command: Command,
 }
 
+impl Cli {
+    /// Parses the command line, after turning similarities attached to short options like `-M90%`,
+    /// which clap would read as a group of flags, into their long forms like `--find-renames=90%`
+    pub fn parse_args(args: impl Iterator<Item = OsString>) -> Self {
+        let mut options_ended = false;
+        let args = args.map(|arg| {
+            options_ended |= arg == "--";
+            let similarity = arg.to_str()
+                .filter(|_| !options_ended)
+                .and_then(|x| Some((x.get(..2)?, x.get(2..)?)))
+                .filter(|(_, value)| value.starts_with(|x: char| x.is_ascii_digit() || x == '.'));
+            match similarity {
+                Some(("-M", value)) => OsString::from(format!("--find-renames={value}")),
+                Some(("-C", value)) => OsString::from(format!("--find-copies={value}")),
+                _ => arg,
+            }
+        });
+        Self::parse_from(args)
+    }
+}
+
 #[derive(Subcommand)]
 pub(crate) enum Command {
     /// Create an empty Git repository
@@ -62,7 +90,866 @@ pub(crate) enum Command {
         dry_run: bool,
         /// An existing tree object
         tree: String,
+    },
+    /// Lists commit objects in reverse chronological order
+    RevList {
+        /// Print the object IDs of any object referenced by the listed commits
+        #[arg(long)]
+        objects: bool,
+        /// Similar to --objects, but also print the IDs of excluded commits prefixed with a "-"
+        #[arg(long)]
+        objects_edge: bool,
+        /// Print a number stating how many commits would have been listed
+        #[arg(long)]
+        count: bool,
+        /// Print also the parents of the commit
+        #[arg(long)]
+        parents: bool,
+        /// Output excluded boundary commits, prefixed with a "-"
+        #[arg(long)]
+        boundary: bool,
+        /// Limit the number of commits to output
+        #[arg(long, short = 'n')]
+        max_count: Option<usize>,
+        /// Pretend as if all the refs and HEAD are listed on the command line
+        #[arg(long)]
+        all: bool,
+        /// Commits to list, or to exclude when prefixed with "^", or a range <from>..<to>
+        #[arg(required_unless_present = "all")]
+        revs: Vec<String>,
+    },
+    /// Show changes between the working tree and the index, the index and a commit, two commits or two blobs
+    Diff {
+        /// Compare the given two paths on the filesystem
+        #[arg(long)]
+        no_index: bool,
+        /// View the changes staged for the next commit relative to a commit, HEAD by default
+        #[arg(long, visible_alias = "staged")]
+        cached: bool,
+        #[clap(flatten)]
+        format: DiffFormatFlags,
+        #[clap(flatten)]
+        flags: DiffFlags,
+        /// Commits, ranges like <commit>..<commit>, blobs like <rev>:<path> or paths to limit the diff to.
+        /// File paths with --no-index
+        args: Vec<String>,
+        /// Paths to limit the diff to, after "--"
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    /// Show commits with their changes, tags with their target, trees and blobs
+    Show {
+        #[clap(flatten)]
+        format: DiffFormatFlags,
+        #[clap(flatten)]
+        flags: DiffFlags,
+        /// The objects to show
+        #[arg(default_value = "HEAD")]
+        objects: Vec<String>,
+        /// Paths to limit the shown changes to, after "--"
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    /// Compares the content and mode of blobs found via two tree objects, or a commit and its parent
+    DiffTree {
+        /// Recurse into sub-trees
+        #[arg(short)]
+        recursive: bool,
+        /// Show the initial commit as a big creation event
+        #[arg(long)]
+        root: bool,
+        #[clap(flatten)]
+        format: DiffFormatFlags,
+        #[clap(flatten)]
+        flags: DiffFlags,
+        /// One or two tree-ish objects, optionally followed by paths to limit the diff to
+        #[arg(required = true)]
+        args: Vec<String>,
+    },
+    /// Compare a tree to the working tree or index
+    DiffIndex {
+        /// Do not consider the on-disk files at all, compare with the index
+        #[arg(long)]
+        cached: bool,
+        #[clap(flatten)]
+        format: DiffFormatFlags,
+        #[clap(flatten)]
+        flags: DiffFlags,
+        /// The tree-ish to compare with
+        tree_ish: String,
+        /// Limit the diff to the given paths
+        paths: Vec<String>,
+    },
+    /// Compares files in the working tree and the index
+    DiffFiles {
+        #[clap(flatten)]
+        format: DiffFormatFlags,
+        #[clap(flatten)]
+        flags: DiffFlags,
+        /// Limit the diff to the given paths
+        paths: Vec<String>,
+    },
+    /// Record changes to the repository
+    Commit {
+        #[clap(flatten)]
+        flags: CommitFlags,
+    },
+    /// Switch branches or detach HEAD at a commit
+    Checkout {
+        /// Create a new branch starting at <branch> or HEAD and switch to it
+        #[arg(short = 'b', value_name = "new-branch", conflicts_with = "force_create")]
+        create: Option<String>,
+        /// Create the branch or reset it if it exists, and switch to it
+        #[arg(short = 'B', value_name = "new-branch")]
+        force_create: Option<String>,
+        /// Detach HEAD at the commit even if it is a branch
+        #[arg(long)]
+        detach: bool,
+        /// Throw away local changes
+        #[arg(short, long)]
+        force: bool,
+        /// Suppress feedback messages
+        #[arg(short, long)]
+        quiet: bool,
+        /// The branch to switch to, "-" for the previous one, or a commit to detach HEAD at
+        branch: Option<String>,
+    },
+    /// Switch branches
+    Switch {
+        /// Create a new branch starting at <start-point> or HEAD and switch to it
+        #[arg(short = 'c', long, value_name = "new-branch", conflicts_with = "force_create")]
+        create: Option<String>,
+        /// Create the branch or reset it if it exists, and switch to it
+        #[arg(short = 'C', long, value_name = "new-branch")]
+        force_create: Option<String>,
+        /// Switch to a commit for inspection and discardable experiments
+        #[arg(short, long)]
+        detach: bool,
+        /// Throw away local changes
+        #[arg(short, long, visible_alias = "discard-changes")]
+        force: bool,
+        /// Suppress feedback messages
+        #[arg(short, long)]
+        quiet: bool,
+        /// The branch to switch to, "-" for the previous one, or the start point of a new branch
+        branch: Option<String>,
+    },
+    /// Restore files in the working tree or the index
+    Restore {
+        /// Restore from this tree-ish instead of the index, or HEAD with --staged
+        #[arg(short, long)]
+        source: Option<String>,
+        /// Restore the index
+        #[arg(short = 'S', long)]
+        staged: bool,
+        /// Restore the working tree, the default unless --staged is given
+        #[arg(short = 'W', long)]
+        worktree: bool,
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Copy files from the index to the working tree
+    CheckoutIndex {
+        /// Check out all files in the index
+        #[arg(short, long)]
+        all: bool,
+        /// Overwrite existing files
+        #[arg(short, long)]
+        force: bool,
+        /// Do not complain about existing files and files that are not in the index
+        #[arg(short, long)]
+        quiet: bool,
+        /// Update the stat information of the checked out entries in the index
+        #[arg(short = 'u', long = "index")]
+        update: bool,
+        /// Write the files with this string prepended to their paths
+        #[arg(long, default_value = "")]
+        prefix: String,
+        files: Vec<String>,
+    },
+    /// Reset HEAD to a commit, or the index entries of paths to their version in a tree
+    Reset {
+        #[clap(flatten)]
+        mode: ResetModeFlags,
+        /// Only report errors
+        #[arg(short, long)]
+        quiet: bool,
+        /// The commit to reset to, HEAD by default, optionally followed by paths to reset in the index
+        args: Vec<String>,
+        /// Paths to reset in the index, after "--"
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    /// Read trees into the index, merging them with -m
+    ReadTree {
+        /// Merge the trees: one tree replaces the index keeping the stat data of unchanged entries, two trees move
+        /// the index from the first to the second, three trees are merged as the base, ours and theirs
+        #[arg(short = 'm')]
+        merge: bool,
+        /// Also resolve paths removed on both sides, or removed on one side and unchanged on the other, in a three-way merge
+        #[arg(long, requires = "merge")]
+        aggressive: bool,
+        /// Update the files in the working tree with the result
+        #[arg(short = 'u')]
+        update: bool,
+        /// Add the tree to the index below this directory, keeping the current entries
+        #[arg(long, value_name = "dir", conflicts_with = "merge")]
+        prefix: Option<String>,
+        #[arg(required = true, num_args = 1..=3)]
+        trees: Vec<String>,
+    },
+    /// Find the best common ancestors of commits
+    MergeBase {
+        /// Print all the best common ancestors instead of one of them
+        #[arg(short, long, conflicts_with_all = ["is_ancestor", "independent", "fork_point"])]
+        all: bool,
+        #[clap(flatten)]
+        mode: MergeBaseModeFlags,
+        #[arg(required = true)]
+        commits: Vec<String>,
+    },
+    /// Merge two commits without touching the index or the working tree
+    MergeTree {
+        /// Write the merged tree, with the conflicted files in it, and print its hash followed by the conflicts
+        #[arg(long, required = true)]
+        write_tree: bool,
+        /// List only the names of the conflicted files
+        #[arg(long)]
+        name_only: bool,
+        /// Leave out the informational messages
+        #[arg(long)]
+        no_messages: bool,
+        /// Merge commits without a common ancestor, as if all their files were added
+        #[arg(long)]
+        allow_unrelated_histories: bool,
+        branch1: String,
+        branch2: String,
+    },
+    /// Merge the changes from a base file to the current file and to the other file into the current file
+    MergeFile {
+        /// Write the result to the standard output instead of overwriting the current file
+        #[arg(short = 'p', long)]
+        stdout: bool,
+        /// Labels of the current, base and other file in the conflict markers, instead of their names
+        #[arg(short = 'L', value_name = "name")]
+        labels: Vec<String>,
+        /// Show the base lines of each conflict between our and their lines
+        #[arg(long, overrides_with = "zdiff3")]
+        diff3: bool,
+        /// Like --diff3, but moves the lines both sides start or end with out of the conflict
+        #[arg(long, overrides_with = "diff3")]
+        zdiff3: bool,
+        /// Resolve conflicts with the lines of the current file
+        #[arg(long, overrides_with_all = ["theirs", "union"])]
+        ours: bool,
+        /// Resolve conflicts with the lines of the other file
+        #[arg(long, overrides_with_all = ["ours", "union"])]
+        theirs: bool,
+        /// Resolve conflicts with the lines of both files
+        #[arg(long, overrides_with_all = ["ours", "theirs"])]
+        union: bool,
+        /// The number of characters of the conflict markers
+        #[arg(long, default_value_t = MARKER_SIZE)]
+        marker_size: usize,
+        current: String,
+        base: String,
+        other: String,
+    },
+    /// Join the history of another commit into the current branch
+    Merge {
+        /// Create a merge commit even when the merge can be resolved as a fast-forward
+        #[arg(long)]
+        no_ff: bool,
+        /// Refuse to merge unless the current branch can be fast-forwarded
+        #[arg(long, conflicts_with = "no_ff")]
+        ff_only: bool,
+        /// Merge into the index and the working tree without committing or recording the merge
+        #[arg(long, conflicts_with = "no_ff")]
+        squash: bool,
+        /// Abort the merge in progress and restore the state before it
+        #[arg(long, exclusive = true)]
+        abort: bool,
+        /// The message of the merge commit
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Merge commits without a common ancestor, as if all their files were added
+        #[arg(long)]
+        allow_unrelated_histories: bool,
+        #[arg(required_unless_present = "abort")]
+        commit: Option<String>,
+    },
+    /// Apply the changes introduced by existing commits
+    CherryPick {
+        #[clap(flatten)]
+        flags: ReplayFlags,
+        /// Append a line that says which commit was cherry-picked to the message
+        #[arg(short = 'x')]
+        record_origin: bool,
+    },
+    /// Record new commits that undo the changes introduced by existing commits
+    Revert {
+        #[clap(flatten)]
+        flags: ReplayFlags,
+    },
+    /// Reapply commits on top of another base commit
+    Rebase {
+        #[clap(flatten)]
+        flags: RebaseFlags,
+        /// Commit the resolved conflicts or the edited commit and go on with the remaining commands
+        #[arg(long = "continue", exclusive = true)]
+        resume: bool,
+        /// Skip the current commit and go on with the remaining commands
+        #[arg(long, exclusive = true)]
+        skip: bool,
+        /// Cancel the rebase and go back to the branch as it was before it
+        #[arg(long, exclusive = true)]
+        abort: bool,
+        /// Edit the list of the remaining commands
+        #[arg(long, exclusive = true)]
+        edit_todo: bool,
+    },
+    /// Save the local changes away to get a clean working tree, and apply them again later
+    #[command(args_conflicts_with_subcommands = true)]
+    Stash {
+        #[command(subcommand)]
+        command: Option<StashCommand>,
+        /// The flags of push, which is the default command
+        #[clap(flatten)]
+        push: StashPushFlags,
+    },
+    /// Clone a repository into a new directory
+    Clone {
+        #[clap(flatten)]
+        flags: CloneFlags,
+        /// The repository to clone from, a path, a file:// URL, an http(s):// URL or a git:// URL
+        repository: String,
+        /// The new directory, named after the repository by default
+        directory: Option<String>,
+    },
+    /// Download objects and refs from another repository
+    Fetch {
+        #[clap(flatten)]
+        flags: FetchFlags,
+        /// The remote to fetch from, a configured name or a URL, the remote of the current branch or origin by default
+        remote: Option<String>,
+        /// Which refs to fetch and where to store them, the configured remote.<name>.fetch by default
+        refspecs: Vec<String>,
+    },
+    /// Update the refs of another repository and send the objects they need
+    Push {
+        #[clap(flatten)]
+        flags: PushFlags,
+        /// The remote to push to, a configured name or a URL, the push remote of the current branch or origin by default
+        repository: Option<String>,
+        /// Which local refs or revisions to push to which refs of the remote, push.default decides by default
+        refspecs: Vec<String>,
+    },
+    /// Send the objects a fetch asks for over the standard input and output, the server side of fetch and clone
+    UploadPack {
+        #[clap(flatten)]
+        flags: ServiceFlags,
+        /// Only serve the directory if it is a git dir itself, instead of also trying its .git
+        #[arg(long)]
+        strict: bool,
+        /// The repository to serve
+        directory: String,
+    },
+    /// Receive what a push sends over the standard input and output and update the refs, the server side of push
+    ReceivePack {
+        #[clap(flatten)]
+        flags: ServiceFlags,
+        /// The repository to serve
+        directory: String,
+    },
+    /// Serve repositories over smart HTTP on a port of its own, the way http-backend does behind a web server,
+    /// logging each request to stderr
+    HttpBackend {
+        /// The address to listen on, port 0 picks a free one
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Also serve info/refs and objects/ to clients that only speak the dumb protocol
+        #[arg(long)]
+        dumb: bool,
+        /// Refuse pushes
+        #[arg(long)]
+        read_only: bool,
+        /// The repositories to serve, each below the name of its directory
+        #[arg(required = true)]
+        repositories: Vec<String>,
+    },
+    /// Serve repositories read-only to git:// clients, running upload-pack for each connection and logging to stderr
+    Daemon {
+        /// The address to listen on, port 0 picks a free one
+        #[arg(long, default_value = "127.0.0.1:9418")]
+        listen: String,
+        /// Resolve the paths of the requests relative to this dir
+        #[arg(long)]
+        base_path: Option<String>,
+        /// Serve the repositories that have no git-daemon-export-ok file as well
+        #[arg(long)]
+        export_all: bool,
+        /// Only serve the repositories below these dirs
+        directories: Vec<String>,
+    },
+    /// Remove unnecessary whitespace from the standard input, the way commit messages are cleaned up
+    Stripspace {
+        /// Also strip lines starting with the comment character
+        #[arg(short, long)]
+        strip_comments: bool,
+        /// Prepend the comment character and a space to each line instead
+        #[arg(short, long, conflicts_with = "strip_comments")]
+        comment_lines: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub(crate) enum StashCommand {
+    /// Save the local changes as a new stash entry and revert them to HEAD
+    Push {
+        #[clap(flatten)]
+        flags: StashPushFlags,
+    },
+    /// List the stash entries, the latest one first
+    List,
+    /// Show the changes of a stash entry from the commit it is based on, as a diffstat by default
+    Show {
+        #[clap(flatten)]
+        format: DiffFormatFlags,
+        #[clap(flatten)]
+        flags: DiffFlags,
+        /// The entry, like stash@{1} or just 1, the latest one by default
+        stash: Option<String>,
+    },
+    /// Apply the changes of a stash entry to the working tree
+    Apply {
+        #[clap(flatten)]
+        flags: StashApplyFlags,
+    },
+    /// Apply the changes of a stash entry and remove it from the list
+    Pop {
+        #[clap(flatten)]
+        flags: StashApplyFlags,
+    },
+    /// Remove a stash entry from the list
+    Drop {
+        /// Do not report the removed entry
+        #[arg(short, long)]
+        quiet: bool,
+        /// The entry, like stash@{1} or just 1, the latest one by default
+        stash: Option<String>,
+    },
+    /// Create a branch at the commit a stash entry is based on, apply the entry there and remove it from the list
+    Branch {
+        branch: Option<String>,
+        /// The entry, like stash@{1} or just 1, the latest one by default
+        stash: Option<String>,
+    },
+}
+
+#[derive(Args)]
+pub(crate) struct CloneFlags {
+    /// Make a bare repository, with the branches of the remote as its own branches
+    #[arg(long)]
+    pub bare: bool,
+    /// Make a bare repository that copies all refs of the remote and fetches them as they are
+    #[arg(long)]
+    pub mirror: bool,
+    /// Check out this branch, or detach HEAD at this tag, instead of the one HEAD of the remote points to
+    #[arg(short, long)]
+    pub branch: Option<String>,
+    /// Only fetch this many commits of the history of the branch
+    #[arg(long)]
+    pub depth: Option<usize>,
+    /// Do not check out HEAD after the clone
+    #[arg(short, long)]
+    pub no_checkout: bool,
+    /// Copy the objects of a local repository as hardlinks, the default for paths
+    #[arg(short, long)]
+    pub local: bool,
+    /// Transfer the objects of a local repository in a pack, as for URLs
+    #[arg(long, conflicts_with = "local")]
+    pub no_local: bool,
+}
+
+#[derive(Args)]
+pub(crate) struct FetchFlags {
+    /// Remove the remote-tracking refs whose refs no longer exist on the remote
+    #[arg(short, long, overrides_with = "no_prune")]
+    pub prune: bool,
+    /// Keep the stale remote-tracking refs, even if fetch.prune or remote.<name>.prune is set
+    #[arg(long, overrides_with = "prune")]
+    pub no_prune: bool,
+    /// Fetch all tags of the remote
+    #[arg(short, long, conflicts_with = "no_tags")]
+    pub tags: bool,
+    /// Do not fetch the tags that point to the fetched commits
+    #[arg(short = 'n', long)]
+    pub no_tags: bool,
+    /// Update local refs even if they are not fast-forwards
+    #[arg(short, long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub(crate) struct PushFlags {
+    /// Update the refs of the remote even if they are not fast-forwards
+    #[arg(short, long)]
+    pub force: bool,
+    /// Only update a ref if the remote still has the expected value, by default the one of its remote-tracking ref
+    #[arg(long, value_name = "REFNAME[:EXPECT]", num_args = 0..=1, require_equals = true, default_missing_value = "")]
+    pub force_with_lease: Vec<String>,
+    /// Delete the refs of the remote that the refspecs name
+    #[arg(short, long)]
+    pub delete: bool,
+    /// Update all refs of the remote or none of them
+    #[arg(long)]
+    pub atomic: bool,
+    /// Push all tags, in addition to the refspecs
+    #[arg(long)]
+    pub tags: bool,
+    /// Send a string to the hooks of the remote, which must allow push options
+    #[arg(short = 'o', long, value_name = "OPTION")]
+    pub push_option: Vec<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct ServiceFlags {
+    /// Answer a single request and exit, without advertising the refs first, the way smart HTTP runs the command
+    #[arg(long)]
+    pub stateless_rpc: bool,
+    /// Only advertise the refs and capabilities, then exit
+    #[arg(long, alias = "http-backend-info-refs")]
+    pub advertise_refs: bool,
+}
+
+#[derive(Args)]
+pub(crate) struct StashPushFlags {
+    /// Also stash the untracked files, which are removed afterwards
+    #[arg(short = 'u', long)]
+    pub include_untracked: bool,
+    /// Keep the changes that are staged in the index and in the working tree
+    #[arg(short, long)]
+    pub keep_index: bool,
+    /// Do not report what was saved
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// The description of the entry, instead of the commit it is based on
+    #[arg(short, long)]
+    pub message: Option<String>,
+    /// Stash only the changes to these paths
+    pub paths: Vec<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct StashApplyFlags {
+    /// Also restore the changes to the index, which are otherwise left unstaged
+    #[arg(long)]
+    pub index: bool,
+    /// Do not show the status afterwards
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// The entry, like stash@{1} or just 1, the latest one by default
+    pub stash: Option<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct DiffFormatFlags {
+    /// Generate patch
+    #[arg(short = 'p', short_alias = 'u', long)]
+    pub patch: bool,
+    /// Generate the diff in raw format, this is the default
+    #[arg(long)]
+    pub raw: bool,
+    /// Show only names of changed files
+    #[arg(long)]
+    pub name_only: bool,
+    /// Show only names and status of changed files
+    #[arg(long)]
+    pub name_status: bool,
+    /// Generate a diffstat
+    #[arg(long)]
+    pub stat: bool,
+    /// Show the number of added and deleted lines in decimal notation
+    #[arg(long)]
+    pub numstat: bool,
+    /// Output only the last line of the stat, with the numbers of changed files and lines
+    #[arg(long)]
+    pub shortstat: bool,
+    /// Output a condensed summary of created, deleted and renamed files and mode changes
+    #[arg(long)]
+    pub summary: bool,
+    /// Suppress all output of the diff
+    #[arg(short = 's', long)]
+    pub no_patch: bool,
+}
+impl DiffFormatFlags {
+    /// Plumbing commands output the raw format when no format is selected
+    pub fn to_format(&self) -> DiffFormat {
+        self.to_format_with_default(DiffFormat { raw: true, ..Default::default() })
+    }
+
+    /// Porcelain commands show a patch when no format is selected
+    pub fn to_porcelain_format(&self) -> DiffFormat {
+        let format = self.to_format_with_default(DiffFormat { patch: true, ..Default::default() });
+        DiffFormat { abbrev: true, ..format }
     }
+
+    /// The selected format, or the default when no format is selected
+    pub fn to_format_with_default(&self, default: DiffFormat) -> DiffFormat {
+        if self.no_patch {
+            return DiffFormat::default();
+        }
+        let format = DiffFormat {
+            raw: self.raw,
+            name_only: self.name_only,
+            name_status: self.name_status,
+            patch: self.patch,
+            stat: self.stat,
+            numstat: self.numstat,
+            shortstat: self.shortstat,
+            summary: self.summary,
+            abbrev: false,
+        };
+        if format.is_empty() { default } else { format }
+    }
+}
+
+#[derive(Args)]
+pub(crate) struct DiffFlags {
+    /// Generate diffs with <n> lines of context
+    #[arg(short = 'U', long = "unified", default_value_t = 3)]
+    pub context_lines: usize,
+    /// Show whole function as context lines for each change
+    #[arg(short = 'W', long)]
+    pub function_context: bool,
+    /// Choose a diff algorithm
+    #[arg(value_enum, long, default_value = "myers")]
+    pub diff_algorithm: DiffAlgorithm,
+    /// Generate a diff using the "patience diff" algorithm
+    #[arg(long)]
+    pub patience: bool,
+    /// Generate a diff using the "histogram diff" algorithm
+    #[arg(long)]
+    pub histogram: bool,
+    /// Ignore changes in amount of whitespace
+    #[arg(short = 'b', long)]
+    pub ignore_space_change: bool,
+    /// Ignore whitespace when comparing lines
+    #[arg(short = 'w', long)]
+    pub ignore_all_space: bool,
+    /// Ignore changes in whitespace at end of line
+    #[arg(long)]
+    pub ignore_space_at_eol: bool,
+    /// Show a word diff, using the <mode> to delimit changed words
+    #[arg(value_enum, long, value_name = "mode", num_args = 0..=1, require_equals = true, default_missing_value = "plain")]
+    pub word_diff: Option<WordDiff>,
+    /// Show colored diff
+    #[arg(value_enum, long, value_name = "when", num_args = 0..=1, require_equals = true, default_missing_value = "always", overrides_with = "no_color")]
+    pub color: Option<ColorWhen>,
+    /// Turn off colored diff
+    #[arg(long, overrides_with = "color")]
+    pub no_color: bool,
+    /// Detect renames, optionally only of files at least <n> similar
+    #[arg(short = 'M', long, value_name = "n", num_args = 0..=1, require_equals = true, default_missing_value = "50%", value_parser = parse_score)]
+    pub find_renames: Option<usize>,
+    /// Detect copies as well as renames
+    #[arg(short = 'C', long, value_name = "n", num_args = 0..=1, require_equals = true, default_missing_value = "50%", value_parser = parse_score)]
+    pub find_copies: Option<usize>,
+    /// Use unmodified files as the source of copies too
+    #[arg(long)]
+    pub find_copies_harder: bool,
+    /// Turn off rename detection
+    #[arg(long)]
+    pub no_renames: bool,
+    /// Limit the number of files compared by contents when detecting renames and copies
+    #[arg(short = 'l', value_name = "num")]
+    pub rename_limit: Option<usize>,
+}
+impl DiffFlags {
+    /// The color and the rename detection are used when they are not set on the command line,
+    /// porcelain commands take them from the config
+    pub fn to_options(&self, default_color: ColorWhen, default_renames: RenameOptions) -> DiffOptions {
+        let algorithm = if self.patience {
+            DiffAlgorithm::Patience
+        } else if self.histogram {
+            DiffAlgorithm::Histogram
+        } else {
+            self.diff_algorithm
+        };
+        let whitespace = if self.ignore_all_space {
+            Whitespace::IgnoreAll
+        } else if self.ignore_space_change {
+            Whitespace::IgnoreChange
+        } else if self.ignore_space_at_eol {
+            Whitespace::IgnoreAtEol
+        } else {
+            Whitespace::Exact
+        };
+        let mut renames = default_renames;
+        if let Some(min_score) = self.find_copies.or(self.find_renames) {
+            renames.min_score = min_score;
+        }
+        renames.detection = if self.find_copies_harder {
+            RenameDetection::CopiesHarder
+        } else if self.find_copies.is_some() {
+            RenameDetection::Copies
+        } else if self.find_renames.is_some() {
+            RenameDetection::Renames
+        } else if self.no_renames {
+            RenameDetection::Off
+        } else {
+            renames.detection
+        };
+        if let Some(limit) = self.rename_limit {
+            renames.limit = limit;
+        }
+        let word_diff = self.word_diff.unwrap_or_default();
+        let color = if self.no_color { ColorWhen::Never } else { self.color.unwrap_or(default_color) };
+        DiffOptions {
+            algorithm,
+            context_lines: self.context_lines,
+            function_context: self.function_context,
+            whitespace,
+            word_diff,
+            // the color word diff implies colors
+            color: word_diff == WordDiff::Color || color.is_enabled(),
+            renames,
+        }
+    }
+}
+
+#[derive(Args)]
+pub(crate) struct CommitFlags {
+    /// Use the given message, multiple messages are concatenated as separate paragraphs
+    #[arg(short, long, value_name = "msg", conflicts_with = "file")]
+    pub message: Vec<String>,
+    /// Take the message from the given file, "-" reads it from the standard input
+    #[arg(short = 'F', long, value_name = "file")]
+    pub file: Option<String>,
+    /// Stage the files that have been modified and deleted first, new files are not affected
+    #[arg(short, long)]
+    pub all: bool,
+    /// Replace the tip of the current branch by creating a new commit, the message of the original commit is reused by default
+    #[arg(long)]
+    pub amend: bool,
+    /// Allow recording a commit that has the exact same tree as its parent
+    #[arg(long)]
+    pub allow_empty: bool,
+    /// Override the commit author, in the form "Name <email>"
+    #[arg(long, value_name = "author")]
+    pub author: Option<String>,
+    /// Override the author date
+    #[arg(long, value_name = "date")]
+    pub date: Option<String>,
+    /// Suppress the commit summary message
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// Edit the message taken from -m, -F or the amended commit
+    #[arg(short, long, overrides_with = "no_edit")]
+    pub edit: bool,
+    /// Use the message of the amended commit without launching an editor
+    #[arg(long, overrides_with = "edit")]
+    pub no_edit: bool,
+    /// How to clean up the message, from commit.cleanup by default
+    #[arg(value_enum, long, value_name = "mode")]
+    pub cleanup: Option<CleanupMode>,
+}
+
+/// The last of the flags wins, like in git
+#[derive(Args)]
+pub(crate) struct ResetModeFlags {
+    /// Only move HEAD, the index and the working tree stay as they are
+    #[arg(long, overrides_with_all = ["mixed", "hard", "keep"])]
+    pub soft: bool,
+    /// Reset the index but not the working tree, the default
+    #[arg(long, overrides_with_all = ["soft", "hard", "keep"])]
+    pub mixed: bool,
+    /// Reset the index and the working tree, local changes to tracked files are lost
+    #[arg(long, overrides_with_all = ["soft", "mixed", "keep"])]
+    pub hard: bool,
+    /// Reset the index and the files that differ between HEAD and the commit, fails if these files have local changes
+    #[arg(long, overrides_with_all = ["soft", "mixed", "hard"])]
+    pub keep: bool,
+}
+impl ResetModeFlags {
+    pub fn mode(&self) -> ResetMode {
+        match (self.soft, self.mixed, self.hard, self.keep) {
+            (true, _, _, _) => ResetMode::Soft,
+            (_, _, true, _) => ResetMode::Hard,
+            (_, _, _, true) => ResetMode::Keep,
+            (_, _, _, _) => ResetMode::Mixed,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ResetMode {
+    Soft,
+    Mixed,
+    Hard,
+    Keep,
+}
+
+#[derive(Args)]
+pub(crate) struct RebaseFlags {
+    /// Let the user edit the list of commits to rebase
+    #[arg(short, long)]
+    pub interactive: bool,
+    /// The commit to replay the commits onto, instead of the upstream
+    #[arg(long, value_name = "newbase")]
+    pub onto: Option<String>,
+    /// Rebase all the commits of the branch, down to its root commits
+    #[arg(long, conflicts_with = "upstream")]
+    pub root: bool,
+    /// Move the commits whose subjects start with fixup!, squash! or amend! after the commits they name,
+    /// in interactive mode
+    #[arg(long)]
+    pub autosquash: bool,
+    /// Run the shell command after each commit
+    #[arg(short = 'x', long = "exec", value_name = "cmd")]
+    pub exec: Vec<String>,
+    /// The branch to compare with, the commits it already contains are not rebased
+    #[arg(required_unless_present_any = ["root", "resume", "skip", "abort", "edit_todo"])]
+    pub upstream: Option<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct ReplayFlags {
+    /// The parent number, starting from 1, that the changes of merge commits are taken relative to
+    #[arg(short, long, value_name = "parent-number")]
+    pub mainline: Option<usize>,
+    /// Apply the changes to the index and the working tree without committing them
+    #[arg(short, long)]
+    pub no_commit: bool,
+    /// Commit the resolved conflicts and go on with the remaining commits
+    #[arg(long = "continue", exclusive = true)]
+    pub resume: bool,
+    /// Skip the current commit and go on with the remaining commits
+    #[arg(long, exclusive = true)]
+    pub skip: bool,
+    /// Cancel the operation and go back to the commit it started from
+    #[arg(long, exclusive = true)]
+    pub abort: bool,
+    /// Commits, or ranges of commits like <from>..<to>
+    #[arg(required_unless_present_any = ["resume", "skip", "abort"])]
+    pub commits: Vec<String>,
+}
+
+#[derive(Args)]
+#[group(multiple = false)]
+pub(crate) struct MergeBaseModeFlags {
+    /// Find the best common ancestors of all the commits, for an octopus merge
+    #[arg(long)]
+    pub octopus: bool,
+    /// Exit with 0 if the first commit is an ancestor of the second one, with 1 otherwise
+    #[arg(long)]
+    pub is_ancestor: bool,
+    /// List the commits that cannot be reached from any other of them
+    #[arg(long)]
+    pub independent: bool,
+    /// Find where the commit, HEAD by default, forked from any of the past values of the ref in its reflog
+    #[arg(long)]
+    pub fork_point: bool,
 }
 
 #[derive(Args)]
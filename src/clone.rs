@@ -0,0 +1,296 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+use crate::detached_head_advice;
+use crate::checkout::{switch_trees, SwitchAction};
+use crate::cli::CloneFlags;
+use crate::common::{GIT_PATH, HEAD_PATH, init_repo, OBJECTS_PATH, ObjectType, SHALLOW_PATH};
+use crate::config::{append_config_values, Config};
+use crate::ident::{read_ident_or_default, Role};
+use crate::index::Index;
+use crate::pack::index_pack;
+use crate::refs::{Head, HEADS_PREFIX, read_ref, REMOTES_PREFIX, TAGS_PREFIX, update_ref, write_head, write_packed_refs, write_symref};
+use crate::repository::EnteredRepository;
+use crate::rev_parse::peel;
+use crate::smart_http::Service;
+use crate::transport::Transport;
+use crate::upload_pack::UploadPackOptions;
+
+pub(crate) const DEFAULT_REMOTE: &str = "origin";
+
+/// The directory a repository is cloned into by default: the last part of its path without `.git`,
+/// with `.git` added back for a bare clone
+pub(crate) fn clone_directory_name(repository: &str, bare: bool) -> String {
+    let path = repository.trim_end_matches('/');
+    let path = path.strip_suffix(&format!("/{GIT_PATH}")).unwrap_or(path).trim_end_matches('/');
+    let name = path.rsplit(['/', ':']).next().unwrap_or(path);
+    let name = name.strip_suffix(".git").filter(|x| !x.is_empty()).unwrap_or(name);
+    match bare {
+        true => format!("{name}.git"),
+        false => name.to_string(),
+    }
+}
+
+/// How a clone names the refs of the remote in the new repository, None for the refs it does not fetch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RefMapping {
+    /// branches become remote-tracking branches, tags are kept as they are
+    Tracking,
+    /// branches and tags are kept as they are
+    Bare,
+    /// all refs are kept as they are
+    Mirror,
+}
+impl RefMapping {
+    pub fn local_name(self, name: &str) -> Option<String> {
+        match self {
+            Self::Mirror if name.starts_with("refs/") => Some(name.to_string()),
+            Self::Tracking => match name.strip_prefix(HEADS_PREFIX) {
+                Some(branch) => Some(format!("{REMOTES_PREFIX}{DEFAULT_REMOTE}/{branch}")),
+                None => name.starts_with(TAGS_PREFIX).then(|| name.to_string()),
+            },
+            Self::Bare if name.starts_with(HEADS_PREFIX) || name.starts_with(TAGS_PREFIX) => Some(name.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// The files of an object store, relative to it
+pub(crate) fn list_object_files(objects_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        let dir_path = objects_dir.join(&dir);
+        let dir_iterator = fs::read_dir(&dir_path).context(format!("Failed to read dir {}", dir_path.display()))?;
+        for dir_entry in dir_iterator {
+            let dir_entry = dir_entry.context(format!("Some weird error while reading dir entry name in {}", dir_path.display()))?;
+            let path = dir.join(dir_entry.file_name());
+            match dir_entry.file_type().context(format!("Failed to read the type of {}", path.display()))?.is_dir() {
+                true => pending.push(path),
+                false => files.push(path),
+            }
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Copies object files from another object store into the one of the current repository, as hardlinks when possible
+pub(crate) fn link_object_files(source_objects: &Path, files: &[PathBuf]) -> anyhow::Result<()> {
+    for file in files {
+        let source = source_objects.join(file);
+        let target = Path::new(OBJECTS_PATH).join(file);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+        }
+        if fs::hard_link(&source, &target).is_err() {
+            fs::copy(&source, &target).context(format!("failed to copy file to '{}'", target.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies a repository into a new directory, with its branches as remote-tracking branches of the remote "origin",
+/// and checks out the branch its HEAD points to
+pub(crate) fn clone_command(repository: String, directory: Option<String>, flags: CloneFlags) -> anyhow::Result<()> {
+    let config = Config::read()?;
+    let bare = flags.bare || flags.mirror;
+    let mut transport = Transport::open(&repository, Service::UploadPack, &config)?;
+    // like git, a local path is checked before cloning and a remote url only while cloning
+    let (url, local_git_dir) = match &transport {
+        Transport::Local { git_dir, url, is_path, .. } => (url.clone(), Some(git_dir.clone()).filter(|_| *is_path && !flags.no_local)),
+        Transport::Smart(_) => (repository.clone(), None),
+    };
+    let directory = directory.unwrap_or_else(|| clone_directory_name(&repository, bare));
+    // the new repository is entered to fill it, so the paths to clean up after a failure are absolute
+    let destination = env::current_dir().context("failed to get current dir")?.join(&directory);
+    if destination.exists() && fs::read_dir(&destination).map_or(true, |mut x| x.next().is_some()) {
+        bail!("destination path '{directory}' already exists and is not an empty directory.");
+    }
+    match bare {
+        true => eprintln!("Cloning into bare repository '{directory}'..."),
+        false => eprintln!("Cloning into '{directory}'..."),
+    }
+    let depth = match flags.depth {
+        Some(_) if local_git_dir.is_some() => {
+            eprintln!("warning: --depth is ignored in local clones; use file:// instead.");
+            None
+        },
+        depth => depth,
+    };
+
+    let prefixes = match flags.mirror {
+        true => vec![],
+        false => vec!["HEAD", HEADS_PREFIX, TAGS_PREFIX],
+    };
+    let advertisement = transport.list_refs(&prefixes)?;
+    let head_target = advertisement.head_target.clone().filter(|x| advertisement.find(x).is_some());
+    let checkout = match &flags.branch {
+        Some(branch) => {
+            let found = [format!("{HEADS_PREFIX}{branch}"), format!("{TAGS_PREFIX}{branch}")]
+                .into_iter()
+                .find_map(|x| advertisement.find(&x).cloned());
+            Some(found.context(format!("Remote branch {branch} not found in upstream origin"))?)
+        },
+        None => head_target.as_deref().and_then(|x| advertisement.find(x)).cloned(),
+    };
+    // a shallow clone only fetches the branch it checks out
+    let single_branch = depth.is_some();
+    let mapping = match (flags.mirror, bare) {
+        (true, _) => RefMapping::Mirror,
+        (false, true) => RefMapping::Bare,
+        (false, false) => RefMapping::Tracking,
+    };
+    let fetched = advertisement.refs
+        .iter()
+        .filter(|x| !single_branch || checkout.as_ref().is_some_and(|checkout| checkout.name == x.name))
+        .filter_map(|x| Some((mapping.local_name(&x.name)?, x.clone())))
+        .collect::<Vec<_>>();
+    let mut wants = fetched.iter().map(|(_, x)| x.hash.clone()).collect::<Vec<_>>();
+    wants.dedup();
+    let (object_files, received) = match &local_git_dir {
+        Some(git_dir) => (list_object_files(&git_dir.join("objects"))?, None),
+        None if wants.is_empty() => (vec![], None),
+        None => (vec![], Some(transport.fetch(&wants, &[], &UploadPackOptions { depth, ..UploadPackOptions::default() })?)),
+    };
+    if advertisement.refs.is_empty() {
+        eprintln!("warning: You appear to have cloned an empty repository.");
+    }
+
+    let created = !destination.exists();
+    let git_dir = match bare {
+        true => destination.clone(),
+        false => destination.join(GIT_PATH),
+    };
+    let cloned = fs::create_dir_all(&git_dir)
+        .context(format!("could not create leading directories of '{}'", git_dir.display()))
+        .and_then(|_| EnteredRepository::enter(&git_dir))
+        .and_then(|_entered| {
+            init_repo()?;
+            let branch = match &checkout {
+                Some(checkout) => checkout.name.strip_prefix(HEADS_PREFIX).map(str::to_string),
+                None => Some(advertisement.head_target.as_deref().unwrap_or("refs/heads/main").trim_start_matches(HEADS_PREFIX).to_string()),
+            };
+            let mut values = vec![
+                ("core.repositoryformatversion".to_string(), "0".to_string()),
+                ("core.filemode".to_string(), "true".to_string()),
+                ("core.bare".to_string(), bare.to_string()),
+            ];
+            if !bare {
+                values.push(("core.logallrefupdates".to_string(), "true".to_string()));
+            }
+            values.push((format!("remote.{DEFAULT_REMOTE}.url"), url.clone()));
+            match (mapping, &checkout) {
+                (RefMapping::Mirror, _) => {
+                    values.push((format!("remote.{DEFAULT_REMOTE}.fetch"), "+refs/*:refs/*".to_string()));
+                    values.push((format!("remote.{DEFAULT_REMOTE}.mirror"), "true".to_string()));
+                },
+                (RefMapping::Bare, _) => {},
+                (RefMapping::Tracking, Some(checkout)) if single_branch => {
+                    let tracking = mapping.local_name(&checkout.name).unwrap_or_default();
+                    values.push((format!("remote.{DEFAULT_REMOTE}.fetch"), format!("+{}:{tracking}", checkout.name)));
+                },
+                (RefMapping::Tracking, _) => {
+                    values.push((format!("remote.{DEFAULT_REMOTE}.fetch"), format!("+{HEADS_PREFIX}*:{REMOTES_PREFIX}{DEFAULT_REMOTE}/*")));
+                },
+            }
+            if let (false, Some(branch)) = (bare, &branch) {
+                values.push((format!("branch.{branch}.remote"), DEFAULT_REMOTE.to_string()));
+                values.push((format!("branch.{branch}.merge"), format!("{HEADS_PREFIX}{branch}")));
+            }
+            append_config_values(&values.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect::<Vec<_>>())?;
+
+            match (&received, &local_git_dir) {
+                (Some(received), _) => {
+                    index_pack(&received.pack)?;
+                    if !received.shallow.is_empty() {
+                        fs::write(SHALLOW_PATH, received.shallow.iter().map(|x| format!("{x}\n")).collect::<String>())
+                            .context(format!("Failed to write {SHALLOW_PATH}"))?;
+                    }
+                },
+                (None, Some(git_dir)) => link_object_files(&git_dir.join("objects"), &object_files)?,
+                (None, None) => {},
+            }
+            let packed_refs = fetched.iter()
+                .map(|(name, x)| (name.clone(), x.hash.clone(), x.peeled.clone()))
+                .collect::<Vec<_>>();
+            if !packed_refs.is_empty() {
+                write_packed_refs(&packed_refs)?;
+            }
+            if local_git_dir.is_some() {
+                eprintln!("done.");
+            }
+
+            let committer = read_ident_or_default(Role::Committer, &config)?;
+            let message = format!("clone: from {url}");
+            let remote_head = head_target.as_deref()
+                .and_then(|x| x.strip_prefix(HEADS_PREFIX))
+                .map(|x| format!("{REMOTES_PREFIX}{DEFAULT_REMOTE}/{x}"))
+                .and_then(|x| Some((read_ref(&x).ok()??, x)));
+            if let (RefMapping::Tracking, Some((hash, target))) = (mapping, remote_head) {
+                write_symref(&format!("{REMOTES_PREFIX}{DEFAULT_REMOTE}/HEAD"), &target, &hash, &committer, &message)?;
+            }
+            let commit = match &checkout {
+                Some(checkout) => peel(&checkout.hash, ObjectType::Commit)?,
+                None => {
+                    fs::write(HEAD_PATH, format!("ref: {HEADS_PREFIX}{}\n", branch.unwrap_or_default())).context(format!("Failed to write {HEAD_PATH}"))?;
+                    return Ok(());
+                },
+            };
+            match &branch {
+                Some(branch) => {
+                    let ref_name = format!("{HEADS_PREFIX}{branch}");
+                    fs::write(HEAD_PATH, format!("ref: {ref_name}\n")).context(format!("Failed to write {HEAD_PATH}"))?;
+                    if !bare {
+                        update_ref(&ref_name, &commit, None, &committer, &message)?;
+                    }
+                },
+                None => {
+                    write_head(&Head::Detached(commit.clone()), None, &commit, &committer, &message)?;
+                    if !bare && !matches!(config.get("advice.detachedHead"), Some("false" | "no" | "off" | "0")) {
+                        eprint!("{}", detached_head_advice(&commit));
+                    }
+                },
+            }
+            if !bare && !flags.no_checkout {
+                let tree = peel(&commit, ObjectType::Tree)?;
+                let mut index = Index::default();
+                switch_trees(None, Some(&tree), &mut index, false, SwitchAction::Checkout)?;
+                index.write()?;
+            }
+            Ok(())
+        });
+    if cloned.is_err() {
+        // like git, a failed clone leaves nothing behind
+        let _ = match created {
+            true => fs::remove_dir_all(&destination),
+            false if !bare => fs::remove_dir_all(&git_dir),
+            false => Ok(()),
+        };
+    }
+    cloned
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clone_directory_name() {
+        assert_eq!("repo", clone_directory_name("/tmp/repo", false));
+        assert_eq!("repo", clone_directory_name("/tmp/repo/.git/", false));
+        assert_eq!("repo", clone_directory_name("file:///tmp/repo.git", false));
+        assert_eq!("repo.git", clone_directory_name("../repo", true));
+        assert_eq!("repo.git", clone_directory_name("host:repo.git", true));
+    }
+
+    #[test]
+    fn test_ref_mapping() {
+        assert_eq!(Some("refs/remotes/origin/main".to_string()), RefMapping::Tracking.local_name("refs/heads/main"));
+        assert_eq!(Some("refs/tags/v1".to_string()), RefMapping::Tracking.local_name("refs/tags/v1"));
+        assert_eq!(None, RefMapping::Tracking.local_name("refs/notes/commits"));
+        assert_eq!(None, RefMapping::Bare.local_name("HEAD"));
+        assert_eq!(Some("refs/notes/commits".to_string()), RefMapping::Mirror.local_name("refs/notes/commits"));
+    }
+}
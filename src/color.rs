@@ -0,0 +1,55 @@
+use std::env;
+use std::io::{IsTerminal, stdout};
+use clap::ValueEnum;
+use crate::config::Config;
+
+pub(crate) const RESET: &str = "\x1b[m";
+pub(crate) const BOLD: &str = "\x1b[1m";
+pub(crate) const RED: &str = "\x1b[31m";
+pub(crate) const GREEN: &str = "\x1b[32m";
+pub(crate) const YELLOW: &str = "\x1b[33m";
+pub(crate) const CYAN: &str = "\x1b[36m";
+pub(crate) const RED_BACKGROUND: &str = "\x1b[41m";
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub(crate) enum ColorWhen {
+    Always,
+    Never,
+    /// only when the output goes to a terminal
+    Auto,
+}
+impl ColorWhen {
+    /// Parses a color setting from the config, booleans are accepted too
+    pub fn from_config(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "always" => Some(Self::Always),
+            "never" | "false" | "no" | "off" | "0" => Some(Self::Never),
+            "auto" | "true" | "yes" | "on" | "1" => Some(Self::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn is_enabled(self) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => stdout().is_terminal() && env::var("TERM").map_or(true, |x| x != "dumb"),
+        }
+    }
+}
+
+/// The color setting of a porcelain command when none is given on the command line:
+/// the command specific variable like color.diff, then color.ui, which is auto by default
+pub(crate) fn color_from_config(config: &Config, key: &str) -> ColorWhen {
+    [key, "color.ui"].iter()
+        .find_map(|x| config.get(x).and_then(ColorWhen::from_config))
+        .unwrap_or(ColorWhen::Auto)
+}
+
+/// Wraps the text into the color if colors are enabled
+pub(crate) fn paint(text: &str, color: &str, enabled: bool) -> String {
+    match enabled {
+        true => format!("{color}{text}{RESET}"),
+        false => text.to_string(),
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::ErrorKind;
+use anyhow::{bail, Context};
+use crate::common::{get_hash_by_object_path, ObjectType, SHALLOW_PATH};
+use crate::object_read::find_and_decode_object;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+pub(crate) const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+pub(crate) const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Signature {
+    pub name: String,
+    pub email: String,
+    pub timestamp: i64,
+    pub timezone: String,
+}
+impl Signature {
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        let Some((name, rest)) = line.split_once('<') else {
+            bail!("Failed to parse signature {line}: email start not found");
+        };
+        let Some((email, rest)) = rest.split_once('>') else {
+            bail!("Failed to parse signature {line}: email end not found");
+        };
+        let mut date = rest.split_whitespace();
+        let timestamp = date.next().unwrap_or("0");
+        let timestamp = timestamp.parse::<i64>().context(format!("Failed to parse signature {line}: invalid timestamp {timestamp}"))?;
+        let timezone = date.next().unwrap_or("+0000").to_string();
+        let res = Self {
+            name: name.trim_end().to_string(),
+            email: email.to_string(),
+            timestamp,
+            timezone,
+        };
+        Ok(res)
+    }
+
+    /// The date in the default git format and in the timezone of the signature, like "Tue Nov 14 22:13:20 2023 +0000"
+    pub fn format_date(&self) -> String {
+        let local_time = self.timestamp + timezone_offset(&self.timezone);
+        let days = local_time.div_euclid(SECONDS_PER_DAY);
+        let seconds = local_time.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        // the epoch was on a Thursday
+        let weekday = WEEKDAYS[(days + 4).rem_euclid(7) as usize];
+        format!(
+            "{weekday} {} {day} {:02}:{:02}:{:02} {year} {}",
+            MONTHS[month - 1], seconds / 3600, seconds / 60 % 60, seconds % 60, self.timezone,
+        )
+    }
+}
+impl Display for Signature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} <{}> {} {}", self.name, self.email, self.timestamp, self.timezone)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CommitObject {
+    pub hash: String,
+    pub tree: String,
+    pub parents: Vec<String>,
+    pub author: Signature,
+    pub committer: Signature,
+    /// headers other than the ones above, for example gpgsig or encoding
+    pub extra_headers: Vec<(String, String)>,
+    pub message: String,
+}
+impl CommitObject {
+    pub fn read(hash: &str) -> anyhow::Result<Self> {
+        let object = find_and_decode_object(hash)?;
+        if object.object_type != ObjectType::Commit {
+            bail!("Object {hash} is not a commit, it is actually a {}", object.object_type);
+        }
+        let (file_path, _, _, data) = object.into_vec()?;
+        let hash = get_hash_by_object_path(&file_path);
+        Self::parse(hash, &data).context(format!("Failed to parse commit from {file_path}"))
+    }
+    /// Reads the commit the way history walks see it: the parents of the commits at the edge of a shallow clone
+    /// are not in the repository, so those commits have none
+    pub fn read_cut(hash: &str, shallow: &HashSet<String>) -> anyhow::Result<Self> {
+        let mut commit = Self::read(hash)?;
+        if shallow.contains(&commit.hash) {
+            commit.parents.clear();
+        }
+        Ok(commit)
+    }
+    pub fn parse(hash: String, data: &[u8]) -> anyhow::Result<Self> {
+        let data = String::from_utf8_lossy(data);
+        let (headers, message) = split_headers(&data);
+
+        let mut tree = None;
+        let mut parents = vec![];
+        let mut author = None;
+        let mut committer = None;
+        let mut extra_headers = vec![];
+        for (key, value) in headers {
+            match key.as_str() {
+                "tree" => tree = Some(value),
+                "parent" => parents.push(value),
+                "author" => author = Some(Signature::parse(&value)?),
+                "committer" => committer = Some(Signature::parse(&value)?),
+                _ => extra_headers.push((key, value)),
+            }
+        }
+        let Some(tree) = tree else {
+            bail!("Commit {hash} has no tree");
+        };
+        let Some(author) = author else {
+            bail!("Commit {hash} has no author");
+        };
+        let committer = committer.unwrap_or_else(|| author.clone());
+        let res = Self {
+            hash,
+            tree,
+            parents,
+            author,
+            committer,
+            extra_headers,
+            message: message.to_string(),
+        };
+        Ok(res)
+    }
+    /// The first paragraph of the message joined into one line
+    pub fn subject(&self) -> String {
+        message_subject(&self.message)
+    }
+}
+
+/// The commits whose history was cut off by a shallow clone, read once by each history walk
+pub(crate) fn read_shallow_commits() -> anyhow::Result<HashSet<String>> {
+    match fs::read_to_string(SHALLOW_PATH) {
+        Ok(contents) => Ok(contents.lines().map(str::to_string).collect()),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(error) => Err(error).context(format!("Failed to read {SHALLOW_PATH}")),
+    }
+}
+
+/// The first paragraph of a commit message joined into one line, the way git shows one-line summaries
+pub(crate) fn message_subject(message: &str) -> String {
+    message.lines()
+        .map(|x| x.trim_end())
+        .skip_while(|x| x.is_empty())
+        .take_while(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Offset in seconds of a timezone like "+0130", invalid timezones are treated as UTC
+fn timezone_offset(timezone: &str) -> i64 {
+    let (sign, digits) = match timezone.split_at_checked(1) {
+        Some(("+", digits)) => (1, digits),
+        Some(("-", digits)) => (-1, digits),
+        _ => return 0,
+    };
+    if digits.len() != 4 || !digits.bytes().all(|x| x.is_ascii_digit()) {
+        return 0;
+    }
+    let value = digits.parse::<i64>().unwrap_or(0);
+    sign * (value / 100 * 3600 + value % 100 * 60)
+}
+
+/// Converts days since the epoch to a year, a month (1-12) and a day of the month, using Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, usize, i64) {
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // months counted from March, so that the leap day is at the end
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as usize, day)
+}
+
+/// Splits a commit or tag body into headers and the message.
+/// Header values can span multiple lines, continuation lines start with a space.
+pub(crate) fn split_headers(data: &str) -> (Vec<(String, String)>, &str) {
+    let (header_block, message) = match data.split_once("\n\n") {
+        Some((headers, message)) => (headers, message),
+        None => (data.trim_end_matches('\n'), ""),
+    };
+    let mut headers: Vec<(String, String)> = vec![];
+    for line in header_block.lines() {
+        if let Some(continuation) = line.strip_prefix(' ') {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push('\n');
+                value.push_str(continuation);
+            }
+            continue;
+        }
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        headers.push((key.to_string(), value.to_string()));
+    }
+    (headers, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_commit() -> anyhow::Result<()> {
+        let data = b"tree 0b70d742c267c707ebd81d8968fc2e696a9e2edb
+parent 810e2b66b9a81b642795d05af640fa4a2f5fe269
+author test <example@example.com> 1713381411 +0400
+committer other name <other@example.com> 1713381500 -0100
+gpgsig -----BEGIN PGP SIGNATURE-----
+ line
+ -----END PGP SIGNATURE-----
+
+test message
+
+body
+";
+        let commit = CommitObject::parse("eed950c7ed93db7ab0e15de6821498e5c9a826f5".to_string(), data)?;
+        assert_eq!("0b70d742c267c707ebd81d8968fc2e696a9e2edb", commit.tree);
+        assert_eq!(vec!["810e2b66b9a81b642795d05af640fa4a2f5fe269".to_string()], commit.parents);
+        let expected_author = Signature {
+            name: "test".to_string(),
+            email: "example@example.com".to_string(),
+            timestamp: 1713381411,
+            timezone: "+0400".to_string(),
+        };
+        assert_eq!(expected_author, commit.author);
+        assert_eq!("other name <other@example.com> 1713381500 -0100", commit.committer.to_string());
+        let expected_headers = vec![("gpgsig".to_string(), "-----BEGIN PGP SIGNATURE-----\nline\n-----END PGP SIGNATURE-----".to_string())];
+        assert_eq!(expected_headers, commit.extra_headers);
+        assert_eq!("test message\n\nbody\n", commit.message);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_date() -> anyhow::Result<()> {
+        let signature = Signature::parse("a <a@b.c> 1700000000 +0000")?;
+        assert_eq!("Tue Nov 14 22:13:20 2023 +0000", signature.format_date());
+        let signature = Signature::parse("a <a@b.c> 1700000000 +0130")?;
+        assert_eq!("Tue Nov 14 23:43:20 2023 +0130", signature.format_date());
+        let signature = Signature::parse("a <a@b.c> 1709164800 -1000")?;
+        assert_eq!("Wed Feb 28 14:00:00 2024 -1000", signature.format_date());
+        let signature = Signature::parse("a <a@b.c> 0 +0000")?;
+        assert_eq!("Thu Jan 1 00:00:00 1970 +0000", signature.format_date());
+        Ok(())
+    }
+}
@@ -6,18 +6,34 @@ use std::path::Path;
 use clap::ValueEnum;
 use anyhow::Context;
 
-pub(crate) const GIT_PATH: &'static str = ".git";
-pub(crate) const OBJECTS_PATH: &'static str = ".git/objects";
-pub(crate) const HEAD_PATH: &'static str = ".git/HEAD";
+pub(crate) const GIT_PATH: &str = ".git";
+pub(crate) const OBJECTS_PATH: &str = ".git/objects";
+pub(crate) const HEAD_PATH: &str = ".git/HEAD";
+pub(crate) const COMMIT_EDITMSG_PATH: &str = ".git/COMMIT_EDITMSG";
+/// the commit being merged while a merge waits for its conflicts to be resolved
+pub(crate) const MERGE_HEAD_PATH: &str = ".git/MERGE_HEAD";
+pub(crate) const MERGE_MSG_PATH: &str = ".git/MERGE_MSG";
+pub(crate) const MERGE_MODE_PATH: &str = ".git/MERGE_MODE";
+/// the message prepared by a squash merge for the next commit
+pub(crate) const SQUASH_MSG_PATH: &str = ".git/SQUASH_MSG";
+/// the commit being cherry-picked or reverted while its conflicts wait to be resolved
+pub(crate) const CHERRY_PICK_HEAD_PATH: &str = ".git/CHERRY_PICK_HEAD";
+pub(crate) const REVERT_HEAD_PATH: &str = ".git/REVERT_HEAD";
+/// the commits whose parents are missing from a shallow clone, one per line
+pub(crate) const SHALLOW_PATH: &str = ".git/shallow";
+/// the commit a rebase stopped at, because of conflicts or to be edited
+pub(crate) const REBASE_HEAD_PATH: &str = ".git/REBASE_HEAD";
+/// the refs the last fetch fetched, the ones to merge first
+pub(crate) const FETCH_HEAD_PATH: &str = ".git/FETCH_HEAD";
 
 #[cfg(test)]
-pub(crate) const TEST_REPO_PATH: &'static str = "test_data";
+pub(crate) const TEST_REPO_PATH: &str = "test_data";
 
-pub(crate) const MAX_OBJECT_SIZE: u64 = 1 * 1024 * 1024 * 1024; // 1 GB
+pub(crate) const MAX_OBJECT_SIZE: u64 = 1024 * 1024 * 1024; // 1 GB
 
-pub(crate) const COMMIT_AUTHOR: &'static str =  "test";
-pub(crate) const COMMIT_EMAIL: &'static str =  "example@example.com";
-pub(crate) const COMMIT_TIMEZONE: &'static str =  "+0400";
+pub(crate) const COMMIT_AUTHOR: &str =  "test";
+pub(crate) const COMMIT_EMAIL: &str =  "example@example.com";
+pub(crate) const COMMIT_TIMEZONE: &str =  "+0400";
 
 pub(crate) const HASH_ENCODED_LEN: usize = 40;
 pub(crate) const HASH_RAW_LEN: usize = 20;
@@ -32,12 +48,12 @@ pub(crate) enum ObjectType {
     Tag,
 }
 impl ObjectType {
-    pub fn to_str(&self) -> &'static str {
+    pub fn to_str(self) -> &'static str {
         match self {
             ObjectType::Blob => "blob",
             ObjectType::Tree => "tree",
             ObjectType::Commit => "commit",
-            ObjectType::Tag => "blob",
+            ObjectType::Tag => "tag",
         }
     }
 }
@@ -77,6 +93,8 @@ pub(crate) enum ObjectMode {
     Executable = 100755,
     Symlink = 120000,
     Tree = 40000,
+    /// a commit of a submodule
+    Gitlink = 160000,
 }
 impl ObjectMode {
     pub fn get_type(&self) -> ObjectType {
@@ -84,7 +102,8 @@ impl ObjectMode {
             Self::Tree => ObjectType::Tree,
             Self::Normal => ObjectType::Blob,
             Self::Executable => ObjectType::Blob,
-            Self::Symlink => todo!("Handling symlinks is not implemented yet")
+            Self::Symlink => ObjectType::Blob,
+            Self::Gitlink => ObjectType::Commit,
         }
     }
 }
@@ -97,6 +116,7 @@ impl TryFrom<usize> for ObjectMode {
             x if x == (Self::Executable as usize) => Ok(Self::Executable),
             x if x == (Self::Symlink as usize) => Ok(Self::Symlink),
             x if x == (Self::Tree as usize) => Ok(Self::Tree),
+            x if x == (Self::Gitlink as usize) => Ok(Self::Gitlink),
             _ => Err(ConversionError),
         }
     }
@@ -116,6 +136,16 @@ impl Display for ConversionError {
 }
 impl Error for ConversionError {}
 
+/// Ends a command with the exit status, after the command has already told the user why
+#[derive(Debug)]
+pub(crate) struct Exit(pub u8);
+impl Display for Exit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "exit status {}", self.0)
+    }
+}
+impl Error for Exit {}
+
 pub(crate) fn get_object_path_by_hash(hash: &str) -> String {
     let (dir, new_file_name) = hash.split_at(OBJECT_DIR_LEN);
     format!("{OBJECTS_PATH}/{dir}/{new_file_name}")
@@ -128,19 +158,34 @@ pub(crate) fn get_hash_by_object_path(file_path: &str) -> String {
 }
 
 #[cfg(test)]
-pub(crate) fn init_test() -> anyhow::Result<()> {
+static TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(test)]
+pub(crate) fn init_test() -> anyhow::Result<std::sync::MutexGuard<'static, ()>> {
     /*
     alternatively i could provide the base dir as a param for all functions, but this seems much simpler
-    this makes it glitch when running test in multiple threads
-    i could fix this to work properly with multi threads, but it would still fail in other places
-    for example, i'm using one temporary file with a constant name
+    the current dir and the temporary file with a constant name are shared by all threads,
+    so the tests that use them hold the returned guard while they run
      */
-    let current_dir = std::env::current_dir().context("failed to get current dir")?;
-    if current_dir.file_name().unwrap().as_encoded_bytes() != TEST_REPO_PATH.as_bytes() {
-        std::env::set_current_dir(TEST_REPO_PATH).context("failed to switch dir")?;
+    let guard = TEST_LOCK.lock().unwrap_or_else(|x| x.into_inner());
+    let test_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(TEST_REPO_PATH);
+    std::env::set_current_dir(test_dir).context("failed to switch dir")?;
+    init_repo()?;
+    Ok(guard)
+}
+
+/// Like `init_test`, but in a new empty repository of its own, for tests that change refs, the index or the working tree
+#[cfg(test)]
+pub(crate) fn init_worktree_test(name: &str) -> anyhow::Result<std::sync::MutexGuard<'static, ()>> {
+    let guard = TEST_LOCK.lock().unwrap_or_else(|x| x.into_inner());
+    let test_dir = std::env::temp_dir().join(format!("git-test-{}-{name}", std::process::id()));
+    if test_dir.exists() {
+        fs::remove_dir_all(&test_dir).context("failed to clean the test dir")?;
     }
+    fs::create_dir_all(&test_dir).context("failed to create the test dir")?;
+    std::env::set_current_dir(test_dir).context("failed to switch dir")?;
     init_repo()?;
-    Ok(())
+    Ok(guard)
 }
 
 pub(crate) fn init_repo() -> anyhow::Result<()> {
@@ -0,0 +1,263 @@
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::iter::Peekable;
+use std::path::PathBuf;
+use std::str::Chars;
+use anyhow::{bail, Context};
+use crate::lock_file::LockFile;
+
+pub(crate) const CONFIG_PATH: &str = ".git/config";
+
+/// Variables from the user and the repository config files, later files override earlier ones.
+/// Keys are stored as "section.name" or "section.subsection.name", with the section and the name lowercased
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Config {
+    entries: Vec<(String, String)>,
+}
+impl Config {
+    /// Reads the user config (~/.config/git/config, ~/.gitconfig) and then the repository config
+    pub fn read() -> anyhow::Result<Self> {
+        let mut paths = vec![];
+        let xdg_dir = env::var_os("XDG_CONFIG_HOME").filter(|x| !x.is_empty()).map(PathBuf::from);
+        let home_dir = env::var_os("HOME").filter(|x| !x.is_empty()).map(PathBuf::from);
+        if let Some(xdg_dir) = xdg_dir.or_else(|| home_dir.as_ref().map(|x| x.join(".config"))) {
+            paths.push(xdg_dir.join("git/config"));
+        }
+        if let Some(home_dir) = home_dir {
+            paths.push(home_dir.join(".gitconfig"));
+        }
+        paths.push(PathBuf::from(CONFIG_PATH));
+
+        let mut config = Self::default();
+        for path in paths.iter().filter(|x| x.is_file()) {
+            let data = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+            config.parse(&data).context(format!("Failed to parse {}", path.display()))?;
+        }
+        Ok(config)
+    }
+
+    /// Adds the variables of one config file
+    pub fn parse(&mut self, data: &str) -> anyhow::Result<()> {
+        let mut chars = data.chars().peekable();
+        let mut section = None;
+        let mut line_no = 1;
+        while let Some(char) = chars.next() {
+            match char {
+                '\n' => line_no += 1,
+                ' ' | '\t' | '\r' => {},
+                '#' | ';' => skip_line(&mut chars),
+                '[' => section = Some(parse_section(&mut chars).context(format!("Invalid section header on line {line_no}"))?),
+                char if char.is_ascii_alphabetic() => {
+                    let Some(section) = &section else {
+                        bail!("Variable outside of a section on line {line_no}");
+                    };
+                    let mut name = char.to_ascii_lowercase().to_string();
+                    while let Some(char) = chars.next_if(|x| x.is_ascii_alphanumeric() || *x == '-') {
+                        name.push(char.to_ascii_lowercase());
+                    }
+                    while chars.next_if(|x| *x == ' ' || *x == '\t').is_some() {}
+                    // a variable without a value is a true boolean
+                    let value = match chars.next_if(|x| *x == '=') {
+                        Some(_) => parse_value(&mut chars, &mut line_no).context(format!("Invalid value on line {line_no}"))?,
+                        None => {
+                            if chars.peek().is_some_and(|x| !matches!(x, '\n' | '\r' | '#' | ';')) {
+                                bail!("Invalid variable name on line {line_no}");
+                            }
+                            "true".to_string()
+                        },
+                    };
+                    self.entries.push((format!("{section}.{name}"), value));
+                },
+                _ => bail!("Unexpected character '{char}' on line {line_no}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// The last value of the variable, the section and the name are case insensitive
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.entries.iter().rev().find(|(name, _)| *name == key).map(|(_, value)| value.as_str())
+    }
+
+    /// All the values of a multi-valued variable, in the order they were read
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        let key = normalize_key(key);
+        self.entries.iter().filter(|(name, _)| *name == key).map(|(_, value)| value.as_str()).collect()
+    }
+}
+
+/// Adds variables to the repository config file, the keys are "section.name" or "section.subsection.name".
+/// A section header is written before each run of variables of the same section
+pub(crate) fn append_config_values(values: &[(&str, &str)]) -> anyhow::Result<()> {
+    let mut contents = match fs::read_to_string(CONFIG_PATH) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(error).context(format!("Failed to read {CONFIG_PATH}")),
+    };
+    let mut current_section = None;
+    for (key, value) in values {
+        let (section, name) = key.rsplit_once('.').context(format!("key does not contain a section: {key}"))?;
+        if current_section != Some(section) {
+            match section.split_once('.') {
+                Some((section, subsection)) => contents.push_str(&format!("[{section} \"{}\"]\n", escape_value(subsection))),
+                None => contents.push_str(&format!("[{section}]\n")),
+            }
+            current_section = Some(section);
+        }
+        let value = match value.starts_with(char::is_whitespace) || value.ends_with(char::is_whitespace) || value.contains(['#', ';']) {
+            true => format!("\"{}\"", escape_value(value)),
+            false => escape_value(value),
+        };
+        contents.push_str(&format!("\t{name} = {value}\n"));
+    }
+    let mut lock = LockFile::acquire(CONFIG_PATH)?;
+    lock.write_all(contents.as_bytes())?;
+    lock.commit()
+}
+
+fn escape_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\t', "\\t")
+}
+
+/// Lowercases the section and the variable name, the subsection is case sensitive
+fn normalize_key(key: &str) -> String {
+    let (section, rest) = key.split_once('.').unwrap_or((key, ""));
+    let (subsection, name) = match rest.rsplit_once('.') {
+        Some((subsection, name)) => (Some(subsection), name),
+        None => (None, rest),
+    };
+    match subsection {
+        Some(subsection) => format!("{}.{subsection}.{}", section.to_ascii_lowercase(), name.to_ascii_lowercase()),
+        None => format!("{}.{}", section.to_ascii_lowercase(), name.to_ascii_lowercase()),
+    }
+}
+
+fn skip_line(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|x| *x != '\n').is_some() {}
+}
+
+/// Parses `[section]`, `[section "subsection"]` or the legacy `[section.subsection]`, after the opening bracket
+fn parse_section(chars: &mut Peekable<Chars>) -> anyhow::Result<String> {
+    let mut section = String::new();
+    while let Some(char) = chars.next() {
+        match char {
+            ']' => {
+                if section.is_empty() {
+                    bail!("Empty section name");
+                }
+                // the legacy subsection syntax is case insensitive
+                return Ok(section.to_ascii_lowercase());
+            },
+            ' ' | '\t' => {
+                while chars.next_if(|x| *x == ' ' || *x == '\t').is_some() {}
+                if chars.next() != Some('"') {
+                    bail!("Expected a quoted subsection");
+                }
+                let mut subsection = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => subsection.push(chars.next().context("Unterminated subsection")?),
+                        Some('\n') | None => bail!("Unterminated subsection"),
+                        Some(char) => subsection.push(char),
+                    }
+                }
+                if chars.next() != Some(']') {
+                    bail!("Expected ']' after the subsection");
+                }
+                return Ok(format!("{}.{subsection}", section.to_ascii_lowercase()));
+            },
+            char if char.is_ascii_alphanumeric() || char == '-' || char == '.' => section.push(char),
+            char => bail!("Invalid character '{char}' in section name"),
+        }
+    }
+    bail!("Unterminated section header")
+}
+
+/// Parses a value after the equals sign up to the end of the line: quotes are removed, escapes are expanded,
+/// whitespace outside quotes becomes spaces and is trimmed at both ends, comments are dropped
+fn parse_value(chars: &mut Peekable<Chars>, line_no: &mut usize) -> anyhow::Result<String> {
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut comment = false;
+    let mut spaces = 0;
+    while let Some(char) = chars.next_if(|x| *x != '\n') {
+        if comment {
+            continue;
+        }
+        if !quoted && matches!(char, ' ' | '\t' | '\r') {
+            if !value.is_empty() {
+                spaces += 1;
+            }
+            continue;
+        }
+        if !quoted && matches!(char, '#' | ';') {
+            comment = true;
+            continue;
+        }
+        value.extend(std::iter::repeat_n(' ', spaces));
+        spaces = 0;
+        match char {
+            '\\' => match chars.next() {
+                Some('\n') => *line_no += 1,
+                Some('t') => value.push('\t'),
+                Some('b') => value.push('\x08'),
+                Some('n') => value.push('\n'),
+                Some(char @ ('\\' | '"')) => value.push(char),
+                Some(char) => bail!("Invalid escape sequence \\{char}"),
+                None => bail!("Unexpected end of file after a backslash"),
+            },
+            '"' => quoted = !quoted,
+            char => value.push(char),
+        }
+    }
+    if quoted {
+        bail!("Unterminated quoted value");
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() -> anyhow::Result<()> {
+        let mut config = Config::default();
+        config.parse("# comment
+[core]
+\tbare = false
+\tFileMode
+[Color]
+    UI = auto ; comment
+[remote \"Origin\"]
+\turl = \"https://example.com/a b\"   # comment
+\tfetch = +refs/heads/*:refs/remotes/origin/*
+[user]
+\tname = first   name \\
+continued
+\temail = \"a\\\"b\\\\c\"
+[branch.Main]
+\tremote = origin
+")?;
+        assert_eq!(Some("false"), config.get("core.bare"));
+        assert_eq!(Some("true"), config.get("core.filemode"));
+        assert_eq!(Some("auto"), config.get("color.ui"));
+        assert_eq!(Some("https://example.com/a b"), config.get("remote.Origin.url"));
+        assert_eq!(None, config.get("remote.origin.url"));
+        assert_eq!(Some("+refs/heads/*:refs/remotes/origin/*"), config.get("Remote.Origin.Fetch"));
+        assert_eq!(Some("first   name continued"), config.get("user.name"));
+        assert_eq!(Some("a\"b\\c"), config.get("user.email"));
+        assert_eq!(Some("origin"), config.get("branch.main.remote"));
+
+        config.parse("[core]\n\tbare = true\n")?;
+        assert_eq!(Some("true"), config.get("core.bare"));
+
+        assert!(Config::default().parse("[core\n").is_err());
+        assert!(Config::default().parse("name = value\n").is_err());
+        assert!(Config::default().parse("[core]\nname = \"unterminated\n").is_err());
+        Ok(())
+    }
+}
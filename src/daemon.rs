@@ -0,0 +1,215 @@
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
+use std::path::{Component, Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use anyhow::{bail, Context};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+use crate::pkt_line::{AsyncPacketReader, AsyncPacketWriter, Packet};
+use crate::repository::find_git_dir;
+
+/// the file that allows a daemon to serve a repository without --export-all
+const EXPORT_OK_FILE: &str = "git-daemon-export-ok";
+
+/// Which repositories a daemon serves
+pub(crate) struct DaemonOptions {
+    /// the dir the paths of the requests are relative to
+    pub base_path: Option<PathBuf>,
+    /// serve repositories without the export-ok file
+    pub export_all: bool,
+    /// only serve repositories below these dirs, any of them if empty
+    pub whitelist: Vec<PathBuf>,
+}
+impl DaemonOptions {
+    /// The base path and the whitelist are made absolute, so that the dirs of the repositories can be compared to them
+    pub fn new(base_path: Option<&str>, export_all: bool, whitelist: &[String]) -> anyhow::Result<Self> {
+        let resolve = |path: &str| fs::canonicalize(path).context(format!("'{path}' does not exist"));
+        let base_path = base_path.map(resolve).transpose()?;
+        if base_path.as_ref().is_some_and(|x| !x.is_dir()) {
+            bail!("--base-path must be a directory");
+        }
+        let whitelist = whitelist.iter().map(|x| resolve(x)).collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { base_path, export_all, whitelist })
+    }
+}
+
+/// What a client asks for in the first packet: `git-upload-pack /path\0host=example.com\0`,
+/// then the extra parameters like the protocol version after another NUL
+#[derive(Debug, PartialEq)]
+struct DaemonRequest {
+    service: String,
+    path: String,
+    host: Option<String>,
+    /// the extra parameters joined by colons, the way GIT_PROTOCOL passes them
+    protocol: String,
+}
+impl DaemonRequest {
+    fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let text = String::from_utf8_lossy(data);
+        let mut fields = text.split('\0');
+        let command = fields.next().unwrap_or_default().trim_end_matches('\n');
+        let (service, path) = command.split_once(' ').context(format!("invalid request: {command}"))?;
+        let mut host = None;
+        let mut parameters = vec![];
+        let mut extra = false;
+        for field in fields {
+            match field.strip_prefix("host=") {
+                _ if field.is_empty() => extra = true,
+                Some(value) if !extra => host = Some(value.to_string()),
+                _ if extra => parameters.push(field),
+                _ => {},
+            }
+        }
+        Ok(Self { service: service.to_string(), path: path.to_string(), host, protocol: parameters.join(":") })
+    }
+}
+
+/// Listens on the address and serves the repositories to git:// clients until the process is stopped,
+/// logging each request to stderr
+pub(crate) fn run_daemon(listen: &str, options: DaemonOptions) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().context("Failed to start the daemon")?;
+    runtime.block_on(async {
+        let listener = TcpListener::bind(listen).await.context(format!("unable to listen on {listen}"))?;
+        eprintln!("Listening on git://{}/", listener.local_addr()?);
+        let options = Arc::new(options);
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    eprintln!("error: failed to accept a connection: {error}");
+                    continue;
+                },
+            };
+            let options = options.clone();
+            tokio::spawn(async move {
+                if let Err(error) = serve_connection(stream, peer, &options).await {
+                    eprintln!("{peer} error: {error:#}");
+                }
+            });
+        }
+    })
+}
+
+/// Reads the request and hands the connection to upload-pack, anything else is refused with an ERR packet
+async fn serve_connection(mut stream: TcpStream, peer: SocketAddr, options: &DaemonOptions) -> anyhow::Result<()> {
+    // the packet is read without buffering, everything after it is for the service
+    let data = match AsyncPacketReader::new(&mut stream).read_packet().await? {
+        Some(Packet::Data(data)) => data,
+        _ => return Ok(()),
+    };
+    let request = DaemonRequest::parse(&data)?;
+    let line = format!("{} {}", request.service, request.path);
+    let git_dir = match request.service.as_str() {
+        "git-upload-pack" => resolve_repository(&request.path, options).ok_or("access denied or repository not exported"),
+        // a read-only daemon
+        "git-receive-pack" | "git-upload-archive" => Err("service not enabled"),
+        _ => {
+            eprintln!("{peer} \"{line}\" unknown service");
+            return Ok(());
+        },
+    };
+    let git_dir = match git_dir {
+        Ok(git_dir) => git_dir,
+        Err(message) => {
+            eprintln!("{peer} \"{line}\" {message}");
+            let mut writer = AsyncPacketWriter::new(&mut stream);
+            writer.write_line(&format!("ERR {message}: {}", request.path)).await?;
+            return writer.flush().await;
+        },
+    };
+    let status = run_upload_pack(stream, &git_dir, &request.protocol).await?;
+    eprintln!("{peer} \"{line}\" {}", match status.success() {
+        true => "served".to_string(),
+        false => format!("upload-pack failed with {status}"),
+    });
+    Ok(())
+}
+
+/// The git dir a request path leads to if it may be served: a path below the base path without `.` or `..`,
+/// with or without its `.git` suffix, inside the whitelist and exported
+fn resolve_repository(path: &str, options: &DaemonOptions) -> Option<PathBuf> {
+    let relative = path.strip_prefix('/')?;
+    if Path::new(relative).components().any(|x| !matches!(x, Component::Normal(_))) {
+        return None;
+    }
+    let directory = match &options.base_path {
+        Some(base_path) => base_path.join(relative),
+        None => PathBuf::from(path),
+    };
+    let git_dir = find_git_dir(&directory).or_else(|| find_git_dir(&PathBuf::from(format!("{}.git", directory.display()))))?;
+    let git_dir = fs::canonicalize(git_dir).ok()?;
+    if !options.whitelist.is_empty() && !options.whitelist.iter().any(|x| git_dir.starts_with(x)) {
+        return None;
+    }
+    (options.export_all || git_dir.join(EXPORT_OK_FILE).is_file()).then_some(git_dir)
+}
+
+/// Runs upload-pack of this program on the repository with the connection as its input and output
+async fn run_upload_pack(stream: TcpStream, git_dir: &Path, protocol: &str) -> anyhow::Result<ExitStatus> {
+    let stream = stream.into_std().context("Failed to hand over the connection")?;
+    stream.set_nonblocking(false).context("Failed to hand over the connection")?;
+    let input = stream.try_clone().context("Failed to hand over the connection")?;
+    let program = env::current_exe().context("Failed to find the daemon program")?;
+    let mut command = Command::new(program);
+    command.arg("upload-pack").arg("--strict").arg(git_dir)
+        .stdin(Stdio::from(OwnedFd::from(input)))
+        .stdout(Stdio::from(OwnedFd::from(stream)))
+        .env_remove("GIT_PROTOCOL");
+    if !protocol.is_empty() {
+        command.env("GIT_PROTOCOL", protocol);
+    }
+    let mut child = command.spawn().context("cannot run upload-pack")?;
+    // the command holds the connection until it is dropped, the child closes it when it is done
+    drop(command);
+    let status = child.wait().await.context("upload-pack failed")?;
+    Ok(status)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() -> anyhow::Result<()> {
+        let request = DaemonRequest::parse(b"git-upload-pack /project.git\0host=example.com:9418\0\0version=2\0")?;
+        let expected = DaemonRequest {
+            service: "git-upload-pack".to_string(),
+            path: "/project.git".to_string(),
+            host: Some("example.com:9418".to_string()),
+            protocol: "version=2".to_string(),
+        };
+        assert_eq!(expected, request);
+        let request = DaemonRequest::parse(b"git-upload-pack /a\n")?;
+        assert_eq!(("/a", None, ""), (request.path.as_str(), request.host, request.protocol.as_str()));
+        assert!(DaemonRequest::parse(b"git-upload-pack").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_repository() -> anyhow::Result<()> {
+        let base_path = env::temp_dir().join(format!("git-daemon-test-{}", std::process::id()));
+        for repository in ["exported.git", "hidden.git"] {
+            for dir in ["objects", "refs"] {
+                fs::create_dir_all(base_path.join(repository).join(dir))?;
+            }
+            fs::write(base_path.join(repository).join("HEAD"), "ref: refs/heads/main\n")?;
+        }
+        fs::write(base_path.join("exported.git").join(EXPORT_OK_FILE), "")?;
+        let base_path = fs::canonicalize(&base_path)?;
+        let mut options = DaemonOptions { base_path: Some(base_path.clone()), export_all: false, whitelist: vec![] };
+        assert_eq!(Some(base_path.join("exported.git")), resolve_repository("/exported.git", &options));
+        assert_eq!(Some(base_path.join("exported.git")), resolve_repository("/exported", &options));
+        assert_eq!(None, resolve_repository("/hidden.git", &options));
+        assert_eq!(None, resolve_repository("/hidden.git/../exported.git", &options));
+        assert_eq!(None, resolve_repository("exported.git", &options));
+        options.export_all = true;
+        assert_eq!(Some(base_path.join("hidden.git")), resolve_repository("/hidden.git", &options));
+        options.whitelist = vec![base_path.join("exported.git")];
+        assert_eq!(None, resolve_repository("/hidden.git", &options));
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+}
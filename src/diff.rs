@@ -0,0 +1,1558 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::Range;
+use anyhow::bail;
+use clap::ValueEnum;
+use crate::color::{BOLD, CYAN, GREEN, RED, RED_BACKGROUND, RESET};
+use crate::common::{ObjectMode, ObjectType};
+use crate::object_read::find_and_decode_object;
+use crate::rename::RenameOptions;
+use crate::tree_diff::ChangeStatus;
+
+/// git looks for NUL bytes only in the beginning of the file
+const BINARY_CHECK_LEN: usize = 8000;
+/// lines that occur more often than this are not used as anchors by the histogram diff
+const HISTOGRAM_MAX_CHAIN: usize = 64;
+const FUNCNAME_MAX_LEN: usize = 80;
+/// lines that have more matches than this are considered to have many matches when searching lines to discard
+const MAX_EQUAL_LIMIT: usize = 1024;
+const SIMILAR_SCAN_WINDOW: usize = 100;
+const KEEP_DISCARDED_RUN: usize = 4;
+pub(crate) const ABBREV_LEN: usize = 7;
+pub(crate) const NULL_HASH: &str = "0000000000000000000000000000000000000000";
+
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub(crate) enum DiffAlgorithm {
+    #[default]
+    Myers,
+    Patience,
+    Histogram,
+}
+
+/// Which whitespace differences are ignored when comparing lines
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub(crate) enum Whitespace {
+    #[default]
+    Exact,
+    IgnoreAtEol,
+    /// any runs of whitespace are equal, whitespace at the end of lines is ignored
+    IgnoreChange,
+    IgnoreAll,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub(crate) enum WordDiff {
+    #[default]
+    None,
+    /// mark removed and added words with [-removed-] and {+added+}
+    Plain,
+    /// only highlight changed words with colors
+    Color,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct DiffOptions {
+    pub algorithm: DiffAlgorithm,
+    pub context_lines: usize,
+    /// show the whole function as context lines for each change
+    pub function_context: bool,
+    pub whitespace: Whitespace,
+    pub word_diff: WordDiff,
+    pub color: bool,
+    pub renames: RenameOptions,
+}
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            algorithm: DiffAlgorithm::Myers,
+            context_lines: 3,
+            function_context: false,
+            whitespace: Whitespace::Exact,
+            word_diff: WordDiff::None,
+            color: false,
+            renames: RenameOptions::default(),
+        }
+    }
+}
+
+/// A region where the old and the new lines differ, ranges are line indexes, one of them may be empty
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Chunk {
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+pub(crate) fn read_blob(hash: &str) -> anyhow::Result<Vec<u8>> {
+    let object = find_and_decode_object(hash)?;
+    if object.object_type != ObjectType::Blob {
+        bail!("Object {hash} is not a blob, it is actually a {}", object.object_type);
+    }
+    let (_, _, _, data) = object.into_vec()?;
+    Ok(data)
+}
+
+pub(crate) fn is_binary(data: &[u8]) -> bool {
+    let check_len = data.len().min(BINARY_CHECK_LEN);
+    data[..check_len].contains(&0)
+}
+
+/// Splits data into lines, each line keeps its trailing newline, the last one may not have it
+pub(crate) fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|x| *x == b'\n').collect()
+}
+
+/// Numbers of added and deleted lines between two versions of a text file
+pub(crate) fn count_line_changes(old: &[u8], new: &[u8], options: &DiffOptions) -> (usize, usize) {
+    let chunks = diff_lines(&split_lines(old), &split_lines(new), options);
+    chunks.iter().fold((0, 0), |(added, deleted), chunk| (added + chunk.new.len(), deleted + chunk.old.len()))
+}
+
+/// Compares two lists of lines and returns the regions that differ
+pub(crate) fn diff_lines(old: &[&[u8]], new: &[&[u8]], options: &DiffOptions) -> Vec<Chunk> {
+    let mut ids = HashMap::new();
+    let mut line_id = |line: &[u8]| {
+        let next_id = ids.len();
+        *ids.entry(normalize_whitespace(line, options.whitespace)).or_insert(next_id)
+    };
+    let old_ids = old.iter().map(|x| line_id(x)).collect::<Vec<_>>();
+    let new_ids = new.iter().map(|x| line_id(x)).collect::<Vec<_>>();
+    diff_sequences(old, new, &old_ids, &new_ids, options.algorithm, true)
+}
+
+/// Whitespace as git defines it, without vertical tabs and form feeds
+fn is_space(char: u8) -> bool {
+    matches!(char, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+/// The part of the line that is compared, lines that differ only in ignored whitespace get the same key
+fn normalize_whitespace(line: &[u8], whitespace: Whitespace) -> Vec<u8> {
+    let trimmed = || {
+        let end = line.iter().rposition(|x| !is_space(*x)).map_or(0, |x| x + 1);
+        &line[..end]
+    };
+    match whitespace {
+        Whitespace::Exact => line.to_vec(),
+        Whitespace::IgnoreAtEol => trimmed().to_vec(),
+        Whitespace::IgnoreChange => {
+            let mut key = Vec::with_capacity(line.len());
+            for char in trimmed() {
+                if !is_space(*char) {
+                    key.push(*char);
+                } else if key.last() != Some(&b' ') {
+                    key.push(b' ');
+                }
+            }
+            key
+        },
+        Whitespace::IgnoreAll => line.iter().copied().filter(|x| !is_space(*x)).collect(),
+    }
+}
+
+/// Runs the diff algorithm on sequences of lines or words that were mapped to ids, equal items have equal ids
+fn diff_sequences(old: &[&[u8]], new: &[&[u8]], old_ids: &[usize], new_ids: &[usize], algorithm: DiffAlgorithm, indent_heuristic: bool) -> Vec<Chunk> {
+    let mut old_changed = vec![false; old.len()];
+    let mut new_changed = vec![false; new.len()];
+    let mut changes = Changes { old: &mut old_changed, new: &mut new_changed };
+    match algorithm {
+        DiffAlgorithm::Myers => myers(old_ids, new_ids, 0, 0, &mut changes),
+        DiffAlgorithm::Patience => patience(old_ids, new_ids, 0, 0, &mut changes),
+        DiffAlgorithm::Histogram => histogram(old_ids, new_ids, 0, 0, &mut changes),
+    }
+    let mut old_side = CompactSide { changed: &mut old_changed, ids: old_ids, lines: old };
+    let mut new_side = CompactSide { changed: &mut new_changed, ids: new_ids, lines: new };
+    compact(&mut old_side, &new_side, indent_heuristic);
+    compact(&mut new_side, &old_side, indent_heuristic);
+    changes_into_chunks(&old_changed, &new_changed)
+}
+
+/// Marks which lines of both sides are not a part of the common subsequence
+struct Changes<'a> {
+    old: &'a mut [bool],
+    new: &'a mut [bool],
+}
+impl Changes<'_> {
+    fn mark(&mut self, old: Range<usize>, new: Range<usize>) {
+        self.old[old].fill(true);
+        self.new[new].fill(true);
+    }
+}
+
+fn changes_into_chunks(old: &[bool], new: &[bool]) -> Vec<Chunk> {
+    let mut chunks = vec![];
+    let (mut old_index, mut new_index) = (0, 0);
+    while old_index < old.len() || new_index < new.len() {
+        let old_changed = old_index < old.len() && old[old_index];
+        let new_changed = new_index < new.len() && new[new_index];
+        if !old_changed && !new_changed {
+            old_index += 1;
+            new_index += 1;
+            continue;
+        }
+        let (old_start, new_start) = (old_index, new_index);
+        while old_index < old.len() && old[old_index] {
+            old_index += 1;
+        }
+        while new_index < new.len() && new[new_index] {
+            new_index += 1;
+        }
+        chunks.push(Chunk { old: old_start..old_index, new: new_start..new_index });
+    }
+    chunks
+}
+
+/// Myers diff with the linear space refinement, the same way git does it: common ends are trimmed first,
+/// lines that have no match on the other side are marked as changed without running the algorithm on them
+fn myers(old: &[usize], new: &[usize], old_offset: usize, new_offset: usize, changes: &mut Changes) {
+    let common_len = old.len().min(new.len());
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old.iter().rev()
+        .zip(new.iter().rev())
+        .take(common_len - prefix)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut old_counts: HashMap<usize, usize> = HashMap::new();
+    for id in old {
+        *old_counts.entry(*id).or_default() += 1;
+    }
+    let mut new_counts: HashMap<usize, usize> = HashMap::new();
+    for id in new {
+        *new_counts.entry(*id).or_default() += 1;
+    }
+    let (old_kept, old_kept_index) = discard_unmatched(old, prefix, old.len() - suffix, &new_counts);
+    let (new_kept, new_kept_index) = discard_unmatched(new, prefix, new.len() - suffix, &old_counts);
+    for index in prefix..old.len() - suffix {
+        if !old_kept_index.contains(&index) {
+            changes.old[old_offset + index] = true;
+        }
+    }
+    for index in prefix..new.len() - suffix {
+        if !new_kept_index.contains(&index) {
+            changes.new[new_offset + index] = true;
+        }
+    }
+
+    let mut split = MyersSplit {
+        old: &old_kept,
+        new: &new_kept,
+        old_index: &old_kept_index,
+        new_index: &new_kept_index,
+        old_offset,
+        new_offset,
+        forward: vec![0; old_kept.len() + new_kept.len() + 3],
+        backward: vec![0; old_kept.len() + new_kept.len() + 3],
+        diagonal_offset: new_kept.len() as isize + 1,
+    };
+    split.compare(0, old_kept.len(), 0, new_kept.len(), changes);
+}
+
+/// Removes lines that have no matches in the other side, and lines with many matches that are surrounded by them.
+/// Returns the remaining line ids and their original indexes.
+fn discard_unmatched(ids: &[usize], start: usize, end: usize, other_counts: &HashMap<usize, usize>) -> (Vec<usize>, Vec<usize>) {
+    let match_limit = bogo_sqrt(ids.len()).min(MAX_EQUAL_LIMIT);
+    // 0 - no matches, 1 - a few matches, 2 - many matches
+    let mut discard = vec![0u8; ids.len()];
+    for index in start..end {
+        let matches = other_counts.get(&ids[index]).copied().unwrap_or(0);
+        discard[index] = match matches {
+            0 => 0,
+            x if x >= match_limit => 2,
+            _ => 1,
+        };
+    }
+    let mut kept = vec![];
+    let mut kept_index = vec![];
+    for index in start..end {
+        if discard[index] == 1 || (discard[index] == 2 && !should_discard_multimatch(&discard, index, start, end)) {
+            kept.push(ids[index]);
+            kept_index.push(index);
+        }
+    }
+    (kept, kept_index)
+}
+
+/// A line with many matches is discarded when it is in the middle of a run of lines without matches
+fn should_discard_multimatch(discard: &[u8], index: usize, start: usize, end: usize) -> bool {
+    let start = start.max(index.saturating_sub(SIMILAR_SCAN_WINDOW));
+    let end = end.min(index + SIMILAR_SCAN_WINDOW + 1);
+
+    let mut no_match_before = 0;
+    let mut multi_match_before = 1;
+    for discarded in discard[start..index].iter().rev() {
+        match discarded {
+            0 => no_match_before += 1,
+            2 => multi_match_before += 1,
+            _ => break,
+        }
+    }
+    if no_match_before == 0 {
+        return false;
+    }
+    let mut no_match_after = 0;
+    let mut multi_match_after = 1;
+    for discarded in &discard[index + 1..end] {
+        match discarded {
+            0 => no_match_after += 1,
+            2 => multi_match_after += 1,
+            _ => break,
+        }
+    }
+    if no_match_after == 0 {
+        return false;
+    }
+    let no_match = no_match_before + no_match_after;
+    let multi_match = multi_match_before + multi_match_after;
+    multi_match * KEEP_DISCARDED_RUN < multi_match + no_match
+}
+
+/// Rough square root approximation used by git to limit matches
+fn bogo_sqrt(mut value: usize) -> usize {
+    let mut result = 1;
+    while value > 0 {
+        result <<= 1;
+        value >>= 2;
+    }
+    result
+}
+
+struct MyersSplit<'a> {
+    old: &'a [usize],
+    new: &'a [usize],
+    /// indexes of the lines in the original sides
+    old_index: &'a [usize],
+    new_index: &'a [usize],
+    old_offset: usize,
+    new_offset: usize,
+    /// the furthest reaching paths for each diagonal, forward and backward
+    forward: Vec<isize>,
+    backward: Vec<isize>,
+    /// diagonals can be negative, this is added to them to get the index
+    diagonal_offset: isize,
+}
+impl MyersSplit<'_> {
+    fn compare(&mut self, mut old_start: usize, mut old_end: usize, mut new_start: usize, mut new_end: usize, changes: &mut Changes) {
+        while old_start < old_end && new_start < new_end && self.old[old_start] == self.new[new_start] {
+            old_start += 1;
+            new_start += 1;
+        }
+        while old_start < old_end && new_start < new_end && self.old[old_end - 1] == self.new[new_end - 1] {
+            old_end -= 1;
+            new_end -= 1;
+        }
+        if old_start == old_end {
+            for index in &self.new_index[new_start..new_end] {
+                changes.new[self.new_offset + index] = true;
+            }
+        } else if new_start == new_end {
+            for index in &self.old_index[old_start..old_end] {
+                changes.old[self.old_offset + index] = true;
+            }
+        } else {
+            let (old_split, new_split) = self.split(old_start, old_end, new_start, new_end);
+            self.compare(old_start, old_split, new_start, new_split, changes);
+            self.compare(old_split, old_end, new_split, new_end, changes);
+        }
+    }
+
+    /// Finds the point where the forward and the backward searches meet
+    fn split(&mut self, old_start: usize, old_end: usize, new_start: usize, new_end: usize) -> (usize, usize) {
+        let (off1, lim1, off2, lim2) = (old_start as isize, old_end as isize, new_start as isize, new_end as isize);
+        let base = self.diagonal_offset;
+        let old = self.old;
+        let new = self.new;
+        let forward = &mut self.forward;
+        let backward = &mut self.backward;
+
+        let diagonal_min = off1 - lim2;
+        let diagonal_max = lim1 - off2;
+        let forward_mid = off1 - off2;
+        let backward_mid = lim1 - lim2;
+        let odd = (forward_mid - backward_mid) & 1 != 0;
+        let (mut forward_min, mut forward_max) = (forward_mid, forward_mid);
+        let (mut backward_min, mut backward_max) = (backward_mid, backward_mid);
+        forward[(forward_mid + base) as usize] = off1;
+        backward[(backward_mid + base) as usize] = lim1;
+
+        loop {
+            if forward_min > diagonal_min {
+                forward_min -= 1;
+                forward[(forward_min - 1 + base) as usize] = -1;
+            } else {
+                forward_min += 1;
+            }
+            if forward_max < diagonal_max {
+                forward_max += 1;
+                forward[(forward_max + 1 + base) as usize] = -1;
+            } else {
+                forward_max -= 1;
+            }
+            let mut diagonal = forward_max;
+            while diagonal >= forward_min {
+                let index = (diagonal + base) as usize;
+                let mut i1 = if forward[index - 1] >= forward[index + 1] {
+                    forward[index - 1] + 1
+                } else {
+                    forward[index + 1]
+                };
+                let mut i2 = i1 - diagonal;
+                while i1 < lim1 && i2 < lim2 && old[i1 as usize] == new[i2 as usize] {
+                    i1 += 1;
+                    i2 += 1;
+                }
+                forward[index] = i1;
+                if odd && backward_min <= diagonal && diagonal <= backward_max && backward[index] <= i1 {
+                    return (i1 as usize, i2 as usize);
+                }
+                diagonal -= 2;
+            }
+
+            if backward_min > diagonal_min {
+                backward_min -= 1;
+                backward[(backward_min - 1 + base) as usize] = isize::MAX;
+            } else {
+                backward_min += 1;
+            }
+            if backward_max < diagonal_max {
+                backward_max += 1;
+                backward[(backward_max + 1 + base) as usize] = isize::MAX;
+            } else {
+                backward_max -= 1;
+            }
+            let mut diagonal = backward_max;
+            while diagonal >= backward_min {
+                let index = (diagonal + base) as usize;
+                let mut i1 = if backward[index - 1] < backward[index + 1] {
+                    backward[index - 1]
+                } else {
+                    backward[index + 1] - 1
+                };
+                let mut i2 = i1 - diagonal;
+                while i1 > off1 && i2 > off2 && old[(i1 - 1) as usize] == new[(i2 - 1) as usize] {
+                    i1 -= 1;
+                    i2 -= 1;
+                }
+                backward[index] = i1;
+                if !odd && forward_min <= diagonal && diagonal <= forward_max && i1 <= forward[index] {
+                    return (i1 as usize, i2 as usize);
+                }
+                diagonal -= 2;
+            }
+        }
+    }
+}
+
+/// Patience diff: matches lines that are unique in both sides, then recurses between them
+fn patience(old: &[usize], new: &[usize], old_offset: usize, new_offset: usize, changes: &mut Changes) {
+    if old.is_empty() || new.is_empty() {
+        changes.mark(old_offset..old_offset + old.len(), new_offset..new_offset + new.len());
+        return;
+    }
+    let Some(anchors) = unique_common_lines(old, new) else {
+        // there are no common lines at all
+        changes.mark(old_offset..old_offset + old.len(), new_offset..new_offset + new.len());
+        return;
+    };
+    if anchors.is_empty() {
+        myers(old, new, old_offset, new_offset, changes);
+        return;
+    }
+
+    let (mut line1, mut line2) = (0, 0);
+    let mut anchor_index = 0;
+    loop {
+        let (next1, next2) = match anchors.get(anchor_index) {
+            Some(&(mut next1, mut next2)) => {
+                while next1 > line1 && next2 > line2 && old[next1 - 1] == new[next2 - 1] {
+                    next1 -= 1;
+                    next2 -= 1;
+                }
+                (next1, next2)
+            },
+            None => (old.len(), new.len()),
+        };
+        while line1 < next1 && line2 < next2 && old[line1] == new[line2] {
+            line1 += 1;
+            line2 += 1;
+        }
+        if next1 > line1 || next2 > line2 {
+            patience(&old[line1..next1], &new[line2..next2], old_offset + line1, new_offset + line2, changes);
+        }
+        if anchor_index >= anchors.len() {
+            return;
+        }
+        while anchors.get(anchor_index + 1).is_some_and(|x| *x == (anchors[anchor_index].0 + 1, anchors[anchor_index].1 + 1)) {
+            anchor_index += 1;
+        }
+        (line1, line2) = (anchors[anchor_index].0 + 1, anchors[anchor_index].1 + 1);
+        anchor_index += 1;
+    }
+}
+
+/// Longest increasing sequence of lines that occur exactly once in both sides, None if sides have no common lines
+fn unique_common_lines(old: &[usize], new: &[usize]) -> Option<Vec<(usize, usize)>> {
+    // line id => (position in old, count in old, position in new, count in new)
+    let mut occurrences: HashMap<usize, (usize, usize, usize, usize)> = HashMap::new();
+    for (index, id) in old.iter().enumerate() {
+        let entry = occurrences.entry(*id).or_insert((index, 0, 0, 0));
+        entry.1 += 1;
+    }
+    let mut has_matches = false;
+    for (index, id) in new.iter().enumerate() {
+        if let Some(entry) = occurrences.get_mut(id) {
+            has_matches = true;
+            entry.2 = index;
+            entry.3 += 1;
+        }
+    }
+    if !has_matches {
+        return None;
+    }
+    let mut pairs = occurrences.into_values()
+        .filter(|(_, old_count, _, new_count)| *old_count == 1 && *new_count == 1)
+        .map(|(old_index, _, new_index, _)| (old_index, new_index))
+        .collect::<Vec<_>>();
+    pairs.sort_unstable();
+
+    // patience sorting: piles keep the index of the pair with the smallest new index on top
+    let mut piles: Vec<usize> = vec![];
+    let mut predecessors = vec![None; pairs.len()];
+    for (index, (_, new_index)) in pairs.iter().enumerate() {
+        let pile = piles.partition_point(|top| pairs[*top].1 < *new_index);
+        if pile > 0 {
+            predecessors[index] = Some(piles[pile - 1]);
+        }
+        if pile == piles.len() {
+            piles.push(index);
+        } else {
+            piles[pile] = index;
+        }
+    }
+    let mut result = vec![];
+    let mut current = piles.last().copied();
+    while let Some(index) = current {
+        result.push(pairs[index]);
+        current = predecessors[index];
+    }
+    result.reverse();
+    Some(result)
+}
+
+/// Histogram diff: splits around the longest common region built from the least frequent lines
+fn histogram(old: &[usize], new: &[usize], old_offset: usize, new_offset: usize, changes: &mut Changes) {
+    let (mut old_start, mut new_start) = (0, 0);
+    loop {
+        let old = &old[old_start..];
+        let new = &new[new_start..];
+        let (old_offset, new_offset) = (old_offset + old_start, new_offset + new_start);
+        if old.is_empty() || new.is_empty() {
+            changes.mark(old_offset..old_offset + old.len(), new_offset..new_offset + new.len());
+            return;
+        }
+        let region = match find_common_region(old, new) {
+            CommonRegion::TooManyOccurrences => {
+                myers(old, new, old_offset, new_offset, changes);
+                return;
+            },
+            CommonRegion::NotFound => {
+                changes.mark(old_offset..old_offset + old.len(), new_offset..new_offset + new.len());
+                return;
+            },
+            CommonRegion::Found(region) => region,
+        };
+        histogram(&old[..region.old.start], &new[..region.new.start], old_offset, new_offset, changes);
+        old_start += region.old.end;
+        new_start += region.new.end;
+    }
+}
+
+enum CommonRegion {
+    Found(Chunk),
+    NotFound,
+    /// all common lines occur too often
+    TooManyOccurrences,
+}
+
+fn find_common_region(old: &[usize], new: &[usize]) -> CommonRegion {
+    // line id => (first position in old, count in old)
+    let mut records: HashMap<usize, (usize, usize)> = HashMap::new();
+    // next position of the same line in old
+    let mut next_positions = vec![None; old.len()];
+    for (index, id) in old.iter().enumerate().rev() {
+        let record = records.entry(*id).or_insert((index, 0));
+        if record.1 > 0 {
+            next_positions[index] = Some(record.0);
+            record.0 = index;
+        }
+        record.1 += 1;
+    }
+    let count_of = |index: usize| records[&old[index]].1;
+
+    let mut best: Option<Chunk> = None;
+    let mut best_count = HISTOGRAM_MAX_CHAIN + 1;
+    let mut has_common = false;
+    let mut new_index = 0;
+    while new_index < new.len() {
+        let mut next_new_index = new_index + 1;
+        let Some(&(first_position, count)) = records.get(&new[new_index]) else {
+            new_index = next_new_index;
+            continue;
+        };
+        has_common = true;
+        if count > best_count {
+            new_index = next_new_index;
+            continue;
+        }
+        let mut old_index = first_position;
+        loop {
+            let next_position = next_positions[old_index];
+            let (mut old_start, mut new_start) = (old_index, new_index);
+            let (mut old_end, mut new_end) = (old_index, new_index);
+            let mut region_count = count;
+            while old_start > 0 && new_start > 0 && old[old_start - 1] == new[new_start - 1] {
+                old_start -= 1;
+                new_start -= 1;
+                if region_count > 1 {
+                    region_count = region_count.min(count_of(old_start));
+                }
+            }
+            while old_end + 1 < old.len() && new_end + 1 < new.len() && old[old_end + 1] == new[new_end + 1] {
+                old_end += 1;
+                new_end += 1;
+                if region_count > 1 {
+                    region_count = region_count.min(count_of(old_end));
+                }
+            }
+            next_new_index = next_new_index.max(new_end + 1);
+            let best_len = best.as_ref().map_or(0, |x| x.old.len() - 1);
+            if best_len < old_end - old_start || region_count < best_count {
+                best = Some(Chunk { old: old_start..old_end + 1, new: new_start..new_end + 1 });
+                best_count = region_count;
+            }
+
+            let mut next_position = next_position;
+            while let Some(position) = next_position {
+                if position > old_end {
+                    break;
+                }
+                next_position = next_positions[position];
+            }
+            let Some(position) = next_position else {
+                break;
+            };
+            old_index = position;
+        }
+        new_index = next_new_index;
+    }
+
+    match best {
+        _ if has_common && best_count > HISTOGRAM_MAX_CHAIN => CommonRegion::TooManyOccurrences,
+        Some(region) => CommonRegion::Found(region),
+        None => CommonRegion::NotFound,
+    }
+}
+
+/// A run of changed lines in one side, can be empty
+struct Group {
+    start: usize,
+    end: usize,
+}
+
+/// One side of the diff during compaction
+struct CompactSide<'a> {
+    changed: &'a mut [bool],
+    ids: &'a [usize],
+    lines: &'a [&'a [u8]],
+}
+impl CompactSide<'_> {
+    fn first_group(&self) -> Group {
+        let mut end = 0;
+        while end < self.changed.len() && self.changed[end] {
+            end += 1;
+        }
+        Group { start: 0, end }
+    }
+    fn next_group(&self, group: &mut Group) -> bool {
+        if group.end >= self.changed.len() {
+            return false;
+        }
+        group.start = group.end + 1;
+        group.end = group.start;
+        while group.end < self.changed.len() && self.changed[group.end] {
+            group.end += 1;
+        }
+        true
+    }
+    fn previous_group(&self, group: &mut Group) -> bool {
+        if group.start == 0 {
+            return false;
+        }
+        group.end = group.start - 1;
+        group.start = group.end;
+        while group.start > 0 && self.changed[group.start - 1] {
+            group.start -= 1;
+        }
+        true
+    }
+    fn slide_down(&mut self, group: &mut Group) -> bool {
+        if group.end >= self.changed.len() || self.ids[group.start] != self.ids[group.end] {
+            return false;
+        }
+        self.changed[group.start] = false;
+        self.changed[group.end] = true;
+        group.start += 1;
+        group.end += 1;
+        while group.end < self.changed.len() && self.changed[group.end] {
+            group.end += 1;
+        }
+        true
+    }
+    fn slide_up(&mut self, group: &mut Group) -> bool {
+        if group.start == 0 || self.ids[group.start - 1] != self.ids[group.end - 1] {
+            return false;
+        }
+        group.start -= 1;
+        group.end -= 1;
+        self.changed[group.start] = true;
+        self.changed[group.end] = false;
+        while group.start > 0 && self.changed[group.start - 1] {
+            group.start -= 1;
+        }
+        true
+    }
+}
+
+/// Moves groups of changed lines to the positions where git would show them:
+/// groups are merged where possible, aligned with changes of the other side,
+/// otherwise placed using the indent heuristic, or as low as possible without it
+fn compact(side: &mut CompactSide, other: &CompactSide, indent_heuristic: bool) {
+    let mut group = side.first_group();
+    let mut other_group = other.first_group();
+    loop {
+        if group.end != group.start {
+            let mut group_size;
+            let mut earliest_end;
+            let mut end_matching_other;
+            loop {
+                group_size = group.end - group.start;
+                end_matching_other = None;
+                while side.slide_up(&mut group) {
+                    other.previous_group(&mut other_group);
+                }
+                earliest_end = group.end;
+                if other_group.end > other_group.start {
+                    end_matching_other = Some(group.end);
+                }
+                while side.slide_down(&mut group) {
+                    other.next_group(&mut other_group);
+                    if other_group.end > other_group.start {
+                        end_matching_other = Some(group.end);
+                    }
+                }
+                if group_size == group.end - group.start {
+                    break;
+                }
+            }
+
+            if group.end == earliest_end {
+                // the group can not be moved
+            } else if end_matching_other.is_some() {
+                while other_group.end == other_group.start {
+                    side.slide_up(&mut group);
+                    other.previous_group(&mut other_group);
+                }
+            } else if indent_heuristic {
+                let mut shift = earliest_end
+                    .max((group.end - group_size).saturating_sub(1))
+                    .max(group.end.saturating_sub(INDENT_HEURISTIC_MAX_SLIDING));
+                let mut best: Option<(usize, SplitScore)> = None;
+                while shift <= group.end {
+                    let mut score = SplitScore::default();
+                    score.add(&measure_split(side.lines, shift));
+                    score.add(&measure_split(side.lines, shift - group_size));
+                    if best.as_ref().is_none_or(|(_, best_score)| score.compare(best_score) <= 0) {
+                        best = Some((shift, score));
+                    }
+                    shift += 1;
+                }
+                let (best_shift, _) = best.unwrap();
+                while group.end > best_shift {
+                    side.slide_up(&mut group);
+                    other.previous_group(&mut other_group);
+                }
+            }
+        }
+        if !side.next_group(&mut group) {
+            break;
+        }
+        other.next_group(&mut other_group);
+    }
+}
+
+const MAX_INDENT: isize = 200;
+const MAX_BLANKS: isize = 20;
+const INDENT_HEURISTIC_MAX_SLIDING: usize = 100;
+const START_OF_FILE_PENALTY: isize = 1;
+const END_OF_FILE_PENALTY: isize = 21;
+const TOTAL_BLANK_WEIGHT: isize = -30;
+const POST_BLANK_WEIGHT: isize = 6;
+const RELATIVE_INDENT_PENALTY: isize = -4;
+const RELATIVE_INDENT_WITH_BLANK_PENALTY: isize = 10;
+const RELATIVE_OUTDENT_PENALTY: isize = 24;
+const RELATIVE_OUTDENT_WITH_BLANK_PENALTY: isize = 17;
+const RELATIVE_DEDENT_PENALTY: isize = 23;
+const RELATIVE_DEDENT_WITH_BLANK_PENALTY: isize = 17;
+const INDENT_WEIGHT: isize = 60;
+
+/// Indentation width of a line, -1 for lines that contain only whitespace
+fn get_indent(line: &[u8]) -> isize {
+    let mut indent = 0;
+    for byte in line {
+        match byte {
+            b' ' => indent += 1,
+            b'\t' => indent += 8 - indent % 8,
+            x if x.is_ascii_whitespace() => {},
+            _ => return indent,
+        }
+        if indent >= MAX_INDENT {
+            return MAX_INDENT;
+        }
+    }
+    -1
+}
+
+/// Properties of the lines around a place where a group of changed lines could start or end
+struct SplitMeasurement {
+    end_of_file: bool,
+    indent: isize,
+    pre_blank: isize,
+    pre_indent: isize,
+    post_blank: isize,
+    post_indent: isize,
+}
+
+fn measure_split(lines: &[&[u8]], split: usize) -> SplitMeasurement {
+    let (end_of_file, indent) = match lines.get(split) {
+        Some(line) => (false, get_indent(line)),
+        None => (true, -1),
+    };
+    let mut pre_blank = 0;
+    let mut pre_indent = -1;
+    for line in lines[..split.min(lines.len())].iter().rev() {
+        pre_indent = get_indent(line);
+        if pre_indent != -1 {
+            break;
+        }
+        pre_blank += 1;
+        if pre_blank == MAX_BLANKS {
+            pre_indent = 0;
+            break;
+        }
+    }
+    let mut post_blank = 0;
+    let mut post_indent = -1;
+    for line in lines.iter().skip(split + 1) {
+        post_indent = get_indent(line);
+        if post_indent != -1 {
+            break;
+        }
+        post_blank += 1;
+        if post_blank == MAX_BLANKS {
+            post_indent = 0;
+            break;
+        }
+    }
+    SplitMeasurement { end_of_file, indent, pre_blank, pre_indent, post_blank, post_indent }
+}
+
+#[derive(Default)]
+struct SplitScore {
+    effective_indent: isize,
+    penalty: isize,
+}
+impl SplitScore {
+    fn add(&mut self, measurement: &SplitMeasurement) {
+        let SplitMeasurement { end_of_file, indent, pre_blank, pre_indent, post_blank, post_indent } = *measurement;
+        if pre_indent == -1 && pre_blank == 0 {
+            self.penalty += START_OF_FILE_PENALTY;
+        }
+        if end_of_file {
+            self.penalty += END_OF_FILE_PENALTY;
+        }
+        let post_blank = if indent == -1 { 1 + post_blank } else { 0 };
+        let total_blank = pre_blank + post_blank;
+        self.penalty += TOTAL_BLANK_WEIGHT * total_blank;
+        self.penalty += POST_BLANK_WEIGHT * post_blank;
+        let indent = if indent != -1 { indent } else { post_indent };
+        let any_blanks = total_blank != 0;
+        self.effective_indent += indent;
+        if indent == -1 || pre_indent == -1 || indent == pre_indent {
+            // no adjustments
+        } else if indent > pre_indent {
+            self.penalty += if any_blanks { RELATIVE_INDENT_WITH_BLANK_PENALTY } else { RELATIVE_INDENT_PENALTY };
+        } else if post_indent != -1 && post_indent > indent {
+            self.penalty += if any_blanks { RELATIVE_OUTDENT_WITH_BLANK_PENALTY } else { RELATIVE_OUTDENT_PENALTY };
+        } else {
+            self.penalty += if any_blanks { RELATIVE_DEDENT_WITH_BLANK_PENALTY } else { RELATIVE_DEDENT_PENALTY };
+        }
+    }
+    fn compare(&self, other: &Self) -> isize {
+        let indent_cmp = (self.effective_indent > other.effective_indent) as isize - (self.effective_indent < other.effective_indent) as isize;
+        INDENT_WEIGHT * indent_cmp + (self.penalty - other.penalty)
+    }
+}
+
+/// One side of a file patch, a missing side means the file was added or deleted
+pub(crate) struct PatchSide<'a> {
+    pub path: &'a str,
+    pub mode: ObjectMode,
+    pub hash: &'a str,
+    pub data: &'a [u8],
+}
+
+/// How a line of a hunk is shown
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum LineKind {
+    Context,
+    Removed,
+    Added,
+    /// an added blank line at the end of the file, highlighted as a whitespace error
+    AddedBlankAtEof,
+}
+
+/// Writes a git style patch for one file, including the headers. The status tells whether the file was renamed or copied.
+pub(crate) fn write_patch(
+    old: Option<&PatchSide>,
+    new: Option<&PatchSide>,
+    status: ChangeStatus,
+    options: &DiffOptions,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let similarity = match status {
+        ChangeStatus::Renamed(similarity) => Some(("rename", similarity)),
+        ChangeStatus::Copied(similarity) => Some(("copy", similarity)),
+        _ => None,
+    };
+    let (old_path, new_path) = match (old, new) {
+        (Some(old), Some(new)) if old.hash == new.hash && old.mode == new.mode && similarity.is_none() => return Ok(()),
+        (Some(old), Some(new)) => (old.path, new.path),
+        (Some(old), None) => (old.path, old.path),
+        (None, Some(new)) => (new.path, new.path),
+        (None, None) => return Ok(()),
+    };
+    let old_hash = old.map_or(NULL_HASH, |x| x.hash);
+    let new_hash = new.map_or(NULL_HASH, |x| x.hash);
+    let old_data = old.map_or(&[][..], |x| x.data);
+    let new_data = new.map_or(&[][..], |x| x.data);
+    let binary = is_binary(old_data) || is_binary(new_data);
+    let old_lines = split_lines(old_data);
+    let new_lines = split_lines(new_data);
+    let chunks = match binary || old_hash == new_hash {
+        true => vec![],
+        false => diff_lines(&old_lines, &new_lines, options),
+    };
+    // when whitespace is ignored, files with only whitespace changes are not shown at all
+    let mode_changed = old.zip(new).is_none_or(|(old, new)| old.mode != new.mode);
+    if options.whitespace != Whitespace::Exact && !binary && !mode_changed && similarity.is_none() && chunks.is_empty() {
+        return Ok(());
+    }
+
+    write_meta(&format!("diff --git a/{old_path} b/{new_path}"), options, writer)?;
+    let index_line = format!("index {}..{}", abbreviate(old_hash), abbreviate(new_hash));
+    match (old, new) {
+        (Some(old), Some(new)) => {
+            if old.mode != new.mode {
+                write_meta(&format!("old mode {}", old.mode), options, writer)?;
+                write_meta(&format!("new mode {}", new.mode), options, writer)?;
+            }
+            if let Some((kind, similarity)) = similarity {
+                write_meta(&format!("similarity index {similarity}%"), options, writer)?;
+                write_meta(&format!("{kind} from {}", old.path), options, writer)?;
+                write_meta(&format!("{kind} to {}", new.path), options, writer)?;
+            }
+            if old.hash != new.hash {
+                match old.mode == new.mode {
+                    true => write_meta(&format!("{index_line} {}", old.mode), options, writer)?,
+                    false => write_meta(&index_line, options, writer)?,
+                }
+            }
+        },
+        (None, Some(new)) => {
+            write_meta(&format!("new file mode {}", new.mode), options, writer)?;
+            write_meta(&index_line, options, writer)?;
+        },
+        (Some(old), None) => {
+            write_meta(&format!("deleted file mode {}", old.mode), options, writer)?;
+            write_meta(&index_line, options, writer)?;
+        },
+        (None, None) => unreachable!(),
+    }
+    if old_hash == new_hash {
+        return Ok(());
+    }
+
+    let old_name = old.map_or("/dev/null".to_string(), |x| format!("a/{}", x.path));
+    let new_name = new.map_or("/dev/null".to_string(), |x| format!("b/{}", x.path));
+    if binary {
+        writeln!(writer, "Binary files {old_name} and {new_name} differ")?;
+        return Ok(());
+    }
+    if chunks.is_empty() {
+        return Ok(());
+    }
+    write_meta(&format!("--- {old_name}"), options, writer)?;
+    write_meta(&format!("+++ {new_name}"), options, writer)?;
+    write_hunks(&old_lines, &new_lines, &chunks, options, writer)
+}
+
+pub(crate) fn abbreviate(hash: &str) -> &str {
+    &hash[..hash.len().min(ABBREV_LEN)]
+}
+
+fn write_colored(text: &[u8], color: &str, options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    if options.color {
+        writer.write_all(color.as_bytes())?;
+    }
+    writer.write_all(text)?;
+    if options.color {
+        writer.write_all(RESET.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Writes one of the header lines of a file patch
+fn write_meta(line: &str, options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    write_colored(line.as_bytes(), BOLD, options, writer)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Writes unified diff hunks for the chunks of two versions of a text file, without the file headers
+fn write_hunks(old_lines: &[&[u8]], new_lines: &[&[u8]], chunks: &[Chunk], options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    let blank_at_eof = find_blank_at_eof(old_lines, new_lines);
+    for hunk in group_hunks(chunks, old_lines, new_lines, options) {
+        write_hunk(&hunk, old_lines, new_lines, blank_at_eof, options, writer)?;
+    }
+    Ok(())
+}
+
+/// Chunks that are shown together with a common header
+struct Hunk<'a> {
+    old: Range<usize>,
+    new: Range<usize>,
+    chunks: &'a [Chunk],
+}
+
+/// Groups chunks into hunks the same way git does, including its rules for extending to whole functions
+fn group_hunks<'a>(chunks: &'a [Chunk], old_lines: &[&[u8]], new_lines: &[&[u8]], options: &DiffOptions) -> Vec<Hunk<'a>> {
+    let context = options.context_lines;
+    let mut hunks = vec![];
+    let mut first = 0;
+    while first < chunks.len() {
+        let mut last = first;
+        while chunks.get(last + 1).is_some_and(|x| x.old.start - chunks[last].old.end <= 2 * context) {
+            last += 1;
+        }
+
+        let chunk = &chunks[first];
+        let mut old_start = chunk.old.start.saturating_sub(context);
+        let mut new_start = chunk.new.start.saturating_sub(context);
+        if options.function_context {
+            if let Some(function_start) = function_context_start(chunk, old_lines, new_lines) {
+                if function_start < old_start {
+                    new_start = new_start.saturating_sub(old_start - function_start);
+                    old_start = function_start;
+                }
+            }
+        }
+
+        let (old_end, new_end) = loop {
+            let chunk = &chunks[last];
+            let trailing = context.min(old_lines.len() - chunk.old.end).min(new_lines.len() - chunk.new.end);
+            let mut old_end = chunk.old.end + trailing;
+            let mut new_end = chunk.new.end + trailing;
+            if !options.function_context {
+                break (old_end, new_end);
+            }
+            let function_end = match find_function_line(old_lines, chunk.old.end, old_lines.len()) {
+                Some(mut end) => {
+                    while end > 0 && is_blank_line(old_lines[end - 1]) {
+                        end -= 1;
+                    }
+                    end
+                },
+                None => old_lines.len(),
+            };
+            if function_end > old_end {
+                new_end = (new_end + function_end - old_end).min(new_lines.len());
+                old_end = function_end;
+            }
+            // the next chunk is included when it is close or there is no function between them
+            let Some(next) = chunks.get(last + 1) else {
+                break (old_end, new_end);
+            };
+            let next_start = next.old.start.min(old_lines.len().saturating_sub(1));
+            let function_between = find_function_line(old_lines, old_end + 1, next_start + 1).is_some();
+            if next_start <= old_end + context || !function_between {
+                last += 1;
+                continue;
+            }
+            break (old_end, new_end);
+        };
+
+        hunks.push(Hunk { old: old_start..old_end, new: new_start..new_end, chunks: &chunks[first..=last] });
+        first = last + 1;
+    }
+    hunks
+}
+
+/// Where the hunk should start to show the whole function around the chunk, None if no extension is needed
+fn function_context_start(chunk: &Chunk, old_lines: &[&[u8]], new_lines: &[&[u8]]) -> Option<usize> {
+    let mut search_from = chunk.old.start;
+    if search_from >= old_lines.len() {
+        // there is no need for more context if whole functions were appended
+        if new_lines[chunk.new.start..].iter().any(|x| is_function_line(x)) {
+            return None;
+        }
+        search_from = old_lines.len().checked_sub(1)?;
+    }
+    let mut start = (0..=search_from).rev().find(|x| is_function_line(old_lines[*x])).unwrap_or(0);
+    // comments right before the function belong to it
+    while start > 0 && !is_blank_line(old_lines[start - 1]) && !is_function_line(old_lines[start - 1]) {
+        start -= 1;
+    }
+    Some(start)
+}
+
+/// Default git rule for function headers: a line that starts with a letter, underscore or a dollar sign
+fn is_function_line(line: &[u8]) -> bool {
+    line.first().is_some_and(|x| x.is_ascii_alphabetic() || *x == b'_' || *x == b'$')
+}
+
+fn is_blank_line(line: &[u8]) -> bool {
+    line.iter().all(|x| is_space(*x))
+}
+
+/// First function line in `start..end`
+fn find_function_line(lines: &[&[u8]], start: usize, end: usize) -> Option<usize> {
+    (start..end).find(|x| is_function_line(lines[*x]))
+}
+
+fn function_name<'a>(lines: &[&'a [u8]], before: usize) -> Option<&'a [u8]> {
+    let line = (0..before.min(lines.len())).rev()
+        .map(|x| lines[x])
+        .find(|x| is_function_line(x))?;
+    let line = line.trim_ascii_end();
+    Some(&line[..line.len().min(FUNCNAME_MAX_LEN)])
+}
+
+fn format_range(range: &Range<usize>) -> String {
+    match range.len() {
+        0 => format!("{},0", range.start),
+        1 => format!("{}", range.start + 1),
+        len => format!("{},{len}", range.start + 1),
+    }
+}
+
+/// Line numbers from which blank lines at the end of both versions start, set only if blank lines were added there.
+/// Numbered from one, or one past the last line when there are no blank lines, the same way as git does
+fn find_blank_at_eof(old_lines: &[&[u8]], new_lines: &[&[u8]]) -> Option<(usize, usize)> {
+    let old_blank = count_trailing_blank_lines(old_lines);
+    let new_blank = count_trailing_blank_lines(new_lines);
+    if new_blank <= old_blank {
+        return None;
+    }
+    Some((old_lines.len() - old_blank + 1, new_lines.len() - new_blank + 1))
+}
+
+/// Port of git's counting that never counts the first line of the file, or an empty line right after an empty first line
+fn count_trailing_blank_lines(lines: &[&[u8]]) -> usize {
+    let data = lines.concat();
+    if data.is_empty() {
+        return 0;
+    }
+    let mut end = data.len() as isize - 1;
+    if data[end as usize] == b'\n' {
+        end -= 1;
+    }
+    let mut count = 0;
+    while 0 < end {
+        let mut previous_eol = end;
+        while previous_eol >= 0 && data[previous_eol as usize] != b'\n' {
+            previous_eol -= 1;
+        }
+        if !is_blank_line(&data[(previous_eol + 1) as usize..=end as usize]) {
+            break;
+        }
+        count += 1;
+        end = previous_eol - 1;
+    }
+    count
+}
+
+fn write_hunk(hunk: &Hunk, old_lines: &[&[u8]], new_lines: &[&[u8]], blank_at_eof: Option<(usize, usize)>, options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    let ranges = format!("@@ -{} +{} @@", format_range(&hunk.old), format_range(&hunk.new));
+    write_colored(ranges.as_bytes(), CYAN, options, writer)?;
+    if let Some(name) = function_name(old_lines, hunk.old.start) {
+        write_colored(b" ", "", options, writer)?;
+        write_colored(name, "", options, writer)?;
+    }
+    writeln!(writer)?;
+
+    // line numbers as in the hunk header, incremented before each line like git does
+    let mut old_line_no = hunk.old.start + usize::from(!hunk.old.is_empty());
+    let mut new_line_no = hunk.new.start + usize::from(!hunk.new.is_empty());
+    let mut new_index = hunk.new.start;
+    for chunk in hunk.chunks {
+        let context = &new_lines[new_index..chunk.new.start];
+        write_context_lines(context, options, writer)?;
+        old_line_no += context.len();
+        new_line_no += context.len();
+        new_index = chunk.new.end;
+        if options.word_diff != WordDiff::None {
+            write_word_diff(&join_lines(&old_lines[chunk.old.clone()]), &join_lines(&new_lines[chunk.new.clone()]), options, writer)?;
+            continue;
+        }
+        for line in &old_lines[chunk.old.clone()] {
+            old_line_no += 1;
+            write_line(LineKind::Removed, line, options, writer)?;
+        }
+        for line in &new_lines[chunk.new.clone()] {
+            new_line_no += 1;
+            let at_eof = blank_at_eof.is_some_and(|(old_start, new_start)| old_start <= old_line_no && new_start <= new_line_no);
+            let kind = if at_eof && is_blank_line(line) { LineKind::AddedBlankAtEof } else { LineKind::Added };
+            write_line(kind, line, options, writer)?;
+        }
+    }
+    write_context_lines(&new_lines[new_index..hunk.new.end], options, writer)
+}
+
+fn write_context_lines(lines: &[&[u8]], options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    for line in lines {
+        match options.word_diff {
+            WordDiff::None => write_line(LineKind::Context, line, options, writer)?,
+            _ => write_word_context_line(line, options, writer)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes a line with its sign, a line without a newline at the end is followed by a marker.
+/// Colored added lines have whitespace errors highlighted
+fn write_line(kind: LineKind, line: &[u8], options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    let content = line.strip_suffix(b"\n").unwrap_or(line);
+    let sign = match kind {
+        LineKind::Context => b" ",
+        LineKind::Removed => b"-",
+        LineKind::Added | LineKind::AddedBlankAtEof => b"+",
+    };
+    if !options.color {
+        writer.write_all(sign)?;
+        writer.write_all(content)?;
+    } else if kind == LineKind::Added {
+        write_colored(sign, GREEN, options, writer)?;
+        write_whitespace_errors(content, GREEN, writer)?;
+    } else {
+        let color = match kind {
+            LineKind::Removed => RED,
+            LineKind::AddedBlankAtEof => RED_BACKGROUND,
+            _ => "",
+        };
+        // the carriage return is written after the reset code, so that terminals do not show a colored line
+        let (content, carriage_return) = match content.strip_suffix(b"\r") {
+            Some(content) => (content, &b"\r"[..]),
+            None => (content, &b""[..]),
+        };
+        write_colored(&[sign, content].concat(), color, options, writer)?;
+        writer.write_all(carriage_return)?;
+    }
+    writeln!(writer)?;
+    if content.len() == line.len() {
+        write_colored(b"\\ No newline at end of file", "", options, writer)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Writes the line in the color, except for whitespace errors: trailing whitespace and spaces before a tab in the indent
+fn write_whitespace_errors(line: &[u8], color: &str, writer: &mut impl Write) -> anyhow::Result<()> {
+    let trailing_start = line.iter().rposition(|x| !is_space(*x)).map_or(0, |x| x + 1);
+    let mut written = 0;
+    for (index, char) in line[..trailing_start].iter().enumerate() {
+        match char {
+            b' ' => continue,
+            b'\t' if written < index => {
+                write!(writer, "{RED_BACKGROUND}")?;
+                writer.write_all(&line[written..index])?;
+                write!(writer, "{RESET}\t")?;
+            },
+            b'\t' => writer.write_all(&line[written..=index])?,
+            _ => break,
+        }
+        written = index + 1;
+    }
+    if written < trailing_start {
+        writer.write_all(color.as_bytes())?;
+        writer.write_all(&line[written..trailing_start])?;
+        writer.write_all(RESET.as_bytes())?;
+    }
+    if trailing_start < line.len() {
+        writer.write_all(RED_BACKGROUND.as_bytes())?;
+        writer.write_all(&line[trailing_start..])?;
+        writer.write_all(RESET.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Joins lines of a chunk for the word diff, the last line always ends with a newline
+fn join_lines(lines: &[&[u8]]) -> Vec<u8> {
+    let mut text = lines.concat();
+    if text.last().is_some_and(|x| *x != b'\n') {
+        text.push(b'\n');
+    }
+    text
+}
+
+/// Context lines of a word diff have no sign
+fn write_word_context_line(line: &[u8], options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    let content = line.strip_suffix(b"\n").unwrap_or(line);
+    let (content, carriage_return) = match content.strip_suffix(b"\r") {
+        Some(content) => (content, &b"\r"[..]),
+        None => (content, &b""[..]),
+    };
+    if !content.is_empty() {
+        write_colored(content, "", options, writer)?;
+    }
+    writer.write_all(carriage_return)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Shows the removed and the added lines of a chunk as changed words. Words are runs of non-whitespace characters,
+/// the whitespace between them is taken from the new version
+fn write_word_diff(old: &[u8], new: &[u8], options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    if new.is_empty() {
+        return write_words(old, LineKind::Removed, options, writer);
+    }
+    let old_words = split_words(old);
+    let new_words = split_words(new);
+    let old_slices = old_words.iter().map(|x| &old[x.clone()]).collect::<Vec<_>>();
+    let new_slices = new_words.iter().map(|x| &new[x.clone()]).collect::<Vec<_>>();
+    let mut ids = HashMap::new();
+    let mut word_id = |word: &[u8]| {
+        let next_id = ids.len();
+        *ids.entry(word.to_vec()).or_insert(next_id)
+    };
+    let old_ids = old_slices.iter().map(|x| word_id(x)).collect::<Vec<_>>();
+    let new_ids = new_slices.iter().map(|x| word_id(x)).collect::<Vec<_>>();
+    let chunks = diff_sequences(&old_slices, &new_slices, &old_ids, &new_ids, DiffAlgorithm::Myers, false);
+
+    // an empty range of words is located at the end of the word before it
+    let text_range = |words: &[Range<usize>], range: &Range<usize>| match range.is_empty() {
+        true => {
+            let end = range.start.checked_sub(1).map_or(0, |x| words[x].end);
+            end..end
+        },
+        false => words[range.start].start..words[range.end - 1].end,
+    };
+    let mut new_written = 0;
+    for chunk in &chunks {
+        let old_range = text_range(&old_words, &chunk.old);
+        let new_range = text_range(&new_words, &chunk.new);
+        if new_written != new_range.start {
+            write_words(&new[new_written..new_range.start], LineKind::Context, options, writer)?;
+        }
+        if !old_range.is_empty() {
+            write_words(&old[old_range], LineKind::Removed, options, writer)?;
+        }
+        if !new_range.is_empty() {
+            write_words(&new[new_range.clone()], LineKind::Added, options, writer)?;
+        }
+        new_written = new_range.end;
+    }
+    if new_written != new.len() {
+        write_words(&new[new_written..], LineKind::Context, options, writer)?;
+    }
+    Ok(())
+}
+
+fn split_words(text: &[u8]) -> Vec<Range<usize>> {
+    let mut words = vec![];
+    let mut index = 0;
+    while index < text.len() {
+        if is_space(text[index]) {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < text.len() && !is_space(text[index]) {
+            index += 1;
+        }
+        words.push(start..index);
+    }
+    words
+}
+
+/// Writes a part of the word diff, the markers and colors are applied to each line separately
+fn write_words(text: &[u8], kind: LineKind, options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    let (color, prefix, suffix) = match (kind, options.word_diff) {
+        (LineKind::Removed, WordDiff::Plain) => (RED, "[-", "-]"),
+        (LineKind::Removed, _) => (RED, "", ""),
+        (LineKind::Added, WordDiff::Plain) => (GREEN, "{+", "+}"),
+        (LineKind::Added, _) => (GREEN, "", ""),
+        _ => ("", "", ""),
+    };
+    for (index, part) in text.split(|x| *x == b'\n').enumerate() {
+        if index > 0 {
+            writeln!(writer)?;
+        }
+        if part.is_empty() {
+            continue;
+        }
+        let colored = options.color && !color.is_empty();
+        if colored {
+            writer.write_all(color.as_bytes())?;
+        }
+        writer.write_all(prefix.as_bytes())?;
+        writer.write_all(part)?;
+        writer.write_all(suffix.as_bytes())?;
+        if colored {
+            writer.write_all(RESET.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// simple pseudo random generator, so that the test is reproducible without extra dependencies
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn lcs_len(old: &[&[u8]], new: &[&[u8]]) -> usize {
+        let mut table = vec![vec![0; new.len() + 1]; old.len() + 1];
+        for i in 0..old.len() {
+            for j in 0..new.len() {
+                table[i + 1][j + 1] = if old[i] == new[j] { table[i][j] + 1 } else { table[i][j + 1].max(table[i + 1][j]) };
+            }
+        }
+        table[old.len()][new.len()]
+    }
+
+    /// checks that lines outside of chunks match, returns the number of changed lines
+    fn check_chunks(old: &[&[u8]], new: &[&[u8]], chunks: &[Chunk]) -> usize {
+        let (mut old_index, mut new_index) = (0, 0);
+        let mut changed = 0;
+        for chunk in chunks.iter().chain([&Chunk { old: old.len()..old.len(), new: new.len()..new.len() }]) {
+            assert_eq!(chunk.old.start - old_index, chunk.new.start - new_index);
+            assert_eq!(old[old_index..chunk.old.start], new[new_index..chunk.new.start]);
+            changed += chunk.old.len() + chunk.new.len();
+            (old_index, new_index) = (chunk.old.end, chunk.new.end);
+        }
+        changed
+    }
+
+    #[test]
+    fn test_diff_algorithms() {
+        let mut state = 42;
+        let alphabet = ["a\n", "b\n", "\n", "    c\n", "\td\n", "}\n"].map(|x| x.as_bytes());
+        for _ in 0..500 {
+            let old_len = (next_random(&mut state) % 30) as usize;
+            let new_len = (next_random(&mut state) % 30) as usize;
+            let used_alphabet = next_random(&mut state) % alphabet.len() as u64 + 1;
+            let old = (0..old_len).map(|_| alphabet[(next_random(&mut state) % used_alphabet) as usize]).collect::<Vec<_>>();
+            let new = (0..new_len).map(|_| alphabet[(next_random(&mut state) % used_alphabet) as usize]).collect::<Vec<_>>();
+            let minimal_changes = old.len() + new.len() - 2 * lcs_len(&old, &new);
+
+            let chunks = diff_lines(&old, &new, &DiffOptions::default());
+            assert_eq!(minimal_changes, check_chunks(&old, &new, &chunks), "{old:?} {new:?}");
+            let chunks = diff_lines(&old, &new, &DiffOptions { algorithm: DiffAlgorithm::Patience, ..Default::default() });
+            check_chunks(&old, &new, &chunks);
+            let chunks = diff_lines(&old, &new, &DiffOptions { algorithm: DiffAlgorithm::Histogram, ..Default::default() });
+            check_chunks(&old, &new, &chunks);
+        }
+    }
+
+    fn format_hunks(old: &[u8], new: &[u8], options: &DiffOptions) -> anyhow::Result<String> {
+        let old_lines = split_lines(old);
+        let new_lines = split_lines(new);
+        let chunks = diff_lines(&old_lines, &new_lines, options);
+        let mut output = vec![];
+        write_hunks(&old_lines, &new_lines, &chunks, options, &mut output)?;
+        Ok(String::from_utf8(output)?)
+    }
+
+    #[test]
+    fn test_write_hunks() -> anyhow::Result<()> {
+        let old = b"fn one() {\n    1\n}\n\nfn two() {\n    2\n    2\n    2\n    2\n}\nlast";
+        let new = b"fn one() {\n    1\n}\n\nfn two() {\n    2\n    2\n    3\n    2\n}\nlast\n";
+        let output = format_hunks(old, new, &DiffOptions { context_lines: 1, ..Default::default() })?;
+        let expected = "@@ -7,5 +7,5 @@ fn two() {
+     2
+-    2
++    3
+     2
+ }
+-last
+\\ No newline at end of file
++last
+";
+        assert_eq!(expected, output);
+
+        let output = format_hunks(old, new, &DiffOptions { context_lines: 0, function_context: true, ..Default::default() })?;
+        let expected = "@@ -5,7 +5,7 @@ fn one() {
+ fn two() {
+     2
+     2
+-    2
++    3
+     2
+ }
+-last
+\\ No newline at end of file
++last
+";
+        assert_eq!(expected, output);
+        Ok(())
+    }
+    #[test]
+    fn test_ignore_whitespace() {
+        let old = b"a b\n\tc\nd\n";
+        let new = b"a  b \n  c\nd";
+        let count = |whitespace| count_line_changes(old, new, &DiffOptions { whitespace, ..Default::default() });
+        assert_eq!((3, 3), count(Whitespace::Exact));
+        assert_eq!((2, 2), count(Whitespace::IgnoreAtEol));
+        assert_eq!((0, 0), count(Whitespace::IgnoreChange));
+        assert_eq!((0, 0), count(Whitespace::IgnoreAll));
+    }
+
+    #[test]
+    fn test_write_colored_hunks() -> anyhow::Result<()> {
+        let old = b"one\ntwo\n";
+        let new = b"one\n  \tthree \n\n";
+        let output = format_hunks(old, new, &DiffOptions { color: true, ..Default::default() })?;
+        let expected = "\x1b[36m@@ -1,2 +1,3 @@\x1b[m
+ one\x1b[m
+\x1b[31m-two\x1b[m
+\x1b[32m+\x1b[m\x1b[41m  \x1b[m\t\x1b[32mthree\x1b[m\x1b[41m \x1b[m
+\x1b[41m+\x1b[m
+";
+        assert_eq!(expected, output);
+
+        let old = b"keep this\nold words here\n";
+        let new = b"keep this\nnew  words\nadded line\n";
+        let output = format_hunks(old, new, &DiffOptions { word_diff: WordDiff::Plain, ..Default::default() })?;
+        let expected = "@@ -1,2 +1,3 @@
+keep this
+[-old-]{+new+}  words
+[-here-]{+added line+}
+";
+        assert_eq!(expected, output);
+        Ok(())
+    }
+}
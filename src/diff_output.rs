@@ -0,0 +1,411 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use anyhow::Context;
+use crate::common::ObjectMode;
+use crate::color::{GREEN, RED, RESET};
+use crate::diff::{abbreviate, count_line_changes, DiffOptions, is_binary, NULL_HASH, PatchSide, read_blob, Whitespace, write_patch};
+use crate::tree_diff::{ChangeStatus, DiffSide, FileChange, hash_worktree_file, read_worktree_file};
+
+const DEFAULT_STAT_WIDTH: usize = 80;
+/// the width of "Bin XXX -> YYY bytes" without the numbers
+const STAT_BINARY_WIDTH: usize = 14;
+
+/// Which representations of the changes to output, several can be combined
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DiffFormat {
+    pub raw: bool,
+    pub name_only: bool,
+    pub name_status: bool,
+    pub patch: bool,
+    pub stat: bool,
+    pub numstat: bool,
+    /// only the totals line of the stat
+    pub shortstat: bool,
+    /// created, deleted and renamed files and mode changes
+    pub summary: bool,
+    /// raw output shows abbreviated hashes, like the porcelain commands do
+    pub abbrev: bool,
+}
+impl DiffFormat {
+    pub fn is_empty(&self) -> bool {
+        !(self.raw || self.name_only || self.name_status || self.patch || self.stat || self.numstat || self.shortstat || self.summary)
+    }
+}
+
+/// A version of a file with its contents loaded, hashed if it comes from the working tree
+pub(crate) struct LoadedSide {
+    pub mode: ObjectMode,
+    pub hash: String,
+    pub data: Vec<u8>,
+}
+
+impl LoadedSide {
+    fn to_patch_side<'a>(&'a self, path: &'a str) -> PatchSide<'a> {
+        PatchSide { path, mode: self.mode, hash: &self.hash, data: &self.data }
+    }
+}
+
+struct LoadedChange<'a> {
+    change: &'a FileChange,
+    old: Option<LoadedSide>,
+    new: Option<LoadedSide>,
+}
+
+struct StatEntry {
+    /// renames are shown as `old => new`, with the common parts of the paths outside of braces
+    name: String,
+    added: usize,
+    deleted: usize,
+    /// binary files show sizes instead of line counts
+    binary: bool,
+    /// conflicted files have no line counts
+    unmerged: bool,
+}
+
+pub(crate) fn write_changes(changes: &[FileChange], format: &DiffFormat, options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    let mut separator = false;
+    if format.raw || format.name_only || format.name_status {
+        for change in changes {
+            if format.name_only {
+                writeln!(writer, "{}", change.path)?;
+            } else if format.name_status {
+                match &change.source {
+                    Some(source) => writeln!(writer, "{}\t{source}\t{}", change.status.label(), change.path)?,
+                    None => writeln!(writer, "{}\t{}", change.status.label(), change.path)?,
+                }
+            } else {
+                write_raw(change, format.abbrev, writer)?;
+            }
+        }
+        separator = true;
+    }
+    if !(format.patch || format.stat || format.numstat || format.shortstat || format.summary) {
+        return Ok(());
+    }
+
+    let mut loaded = vec![];
+    for change in changes {
+        let old = change.old.as_ref().map(|x| load_side(change.old_path(), x)).transpose()?;
+        let new = change.new.as_ref().map(|x| load_side(&change.path, x)).transpose()?;
+        // files that only looked modified because of their timestamps
+        let same = change.source.is_none()
+            && old.as_ref().zip(new.as_ref()).is_some_and(|(old, new)| old.hash == new.hash && old.mode == new.mode);
+        if !same {
+            loaded.push(LoadedChange { change, old, new });
+        }
+    }
+
+    if format.stat || format.numstat || format.shortstat {
+        let mut stats = loaded.iter().map(|x| (x, get_stat(x, options))).collect::<Vec<_>>();
+        if options.whitespace != Whitespace::Exact {
+            // files with only whitespace changes are left out
+            stats.retain(|(loaded, stat)| {
+                let same_mode = loaded.old.as_ref().zip(loaded.new.as_ref()).is_some_and(|(old, new)| old.mode == new.mode);
+                stat.binary || stat.added + stat.deleted > 0 || !same_mode || loaded.change.source.is_some()
+            });
+        }
+        let stats = stats.into_iter().map(|(_, stat)| stat).collect::<Vec<_>>();
+        if format.numstat {
+            for stat in &stats {
+                match stat.binary {
+                    true => writeln!(writer, "-\t-\t{}", stat.name)?,
+                    false => writeln!(writer, "{}\t{}\t{}", stat.added, stat.deleted, stat.name)?,
+                }
+            }
+        }
+        if format.stat {
+            write_stat(&stats, options.color, writer)?;
+        } else if format.shortstat {
+            write_stat_totals(&stats, writer)?;
+        }
+        separator = true;
+    }
+    if format.summary {
+        for loaded in &loaded {
+            write_summary(loaded.change, writer)?;
+        }
+        separator = true;
+    }
+    if format.patch {
+        if separator {
+            writeln!(writer)?;
+        }
+        for loaded in &loaded {
+            let change = loaded.change;
+            if change.status == ChangeStatus::Unmerged {
+                writeln!(writer, "* Unmerged path {}", change.path)?;
+                continue;
+            }
+            let old = loaded.old.as_ref().map(|x| x.to_patch_side(change.old_path()));
+            let new = loaded.new.as_ref().map(|x| x.to_patch_side(&change.path));
+            write_patch(old.as_ref(), new.as_ref(), change.status, options, writer)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_raw(change: &FileChange, abbrev: bool, writer: &mut impl Write) -> anyhow::Result<()> {
+    let mode = |x: &Option<DiffSide>| x.as_ref().map_or("000000".to_string(), |x| format!("{:0>6}", x.mode.to_string()));
+    let hash = |x: &Option<DiffSide>| {
+        let hash = x.as_ref().map_or(NULL_HASH, |x| x.hash.as_str());
+        if abbrev { abbreviate(hash) } else { hash }.to_string()
+    };
+    write!(writer, ":{} {} {} {} {}\t", mode(&change.old), mode(&change.new), hash(&change.old), hash(&change.new), change.status.label())?;
+    if let Some(source) = &change.source {
+        write!(writer, "{source}\t")?;
+    }
+    writeln!(writer, "{}", change.path)?;
+    Ok(())
+}
+
+pub(crate) fn load_side(path: &str, side: &DiffSide) -> anyhow::Result<LoadedSide> {
+    let (hash, data) = if side.mode == ObjectMode::Gitlink {
+        (side.hash.clone(), format!("Subproject commit {}\n", side.hash).into_bytes())
+    } else if side.is_in_worktree() {
+        let path = Path::new(path);
+        let meta = fs::symlink_metadata(path).context(format!("Failed to read metadata for {}", path.display()))?;
+        (hash_worktree_file(path, &meta)?, read_worktree_file(path, &meta)?)
+    } else {
+        (side.hash.clone(), read_blob(&side.hash)?)
+    };
+    Ok(LoadedSide { mode: side.mode, hash, data })
+}
+
+fn get_stat(loaded: &LoadedChange, options: &DiffOptions) -> StatEntry {
+    let old = loaded.old.as_ref().map_or(&[][..], |x| &x.data);
+    let new = loaded.new.as_ref().map_or(&[][..], |x| &x.data);
+    let name = match &loaded.change.source {
+        Some(source) => format_rename(source, &loaded.change.path),
+        None => loaded.change.path.clone(),
+    };
+    if loaded.change.status == ChangeStatus::Unmerged {
+        return StatEntry { name, added: 0, deleted: 0, binary: false, unmerged: true };
+    }
+    if is_binary(old) || is_binary(new) {
+        return StatEntry { name, added: new.len(), deleted: old.len(), binary: true, unmerged: false };
+    }
+    let (added, deleted) = count_line_changes(old, new, options);
+    StatEntry { name, added, deleted, binary: false, unmerged: false }
+}
+
+/// Shows a rename as `old => new` with the common leading and trailing directories outside of braces,
+/// like `src/{old => new}/file`
+fn format_rename(old: &str, new: &str) -> String {
+    let (old_bytes, new_bytes) = (old.as_bytes(), new.as_bytes());
+    let prefix_len = old_bytes.iter().zip(new_bytes)
+        .take_while(|(a, b)| a == b)
+        .enumerate()
+        .filter(|(_, (x, _))| **x == b'/')
+        .last()
+        .map_or(0, |(index, _)| index + 1);
+    // the suffix may share the slash that ends the prefix, but it must not reach further into it
+    let min_start = prefix_len.saturating_sub(1);
+    let mut suffix_len = 0;
+    for len in 1.. {
+        if old_bytes.len() < len + min_start || new_bytes.len() < len + min_start {
+            break;
+        }
+        let (a, b) = (old_bytes[old_bytes.len() - len], new_bytes[new_bytes.len() - len]);
+        if a != b {
+            break;
+        }
+        if a == b'/' {
+            suffix_len = len;
+        }
+    }
+    let old_middle = &old[prefix_len..old.len().saturating_sub(suffix_len).max(prefix_len)];
+    let new_middle = &new[prefix_len..new.len().saturating_sub(suffix_len).max(prefix_len)];
+    if prefix_len + suffix_len == 0 {
+        return format!("{old_middle} => {new_middle}");
+    }
+    format!("{}{{{old_middle} => {new_middle}}}{}", &old[..prefix_len], &old[old.len() - suffix_len..])
+}
+
+/// Writes the `--stat` histogram, sizing the columns the same way git does
+fn write_stat(stats: &[StatEntry], color: bool, writer: &mut impl Write) -> anyhow::Result<()> {
+    let (added_color, deleted_color, reset) = if color { (GREEN, RED, RESET) } else { ("", "", "") };
+    if stats.is_empty() {
+        return Ok(());
+    }
+    let width = env::var("COLUMNS").ok()
+        .and_then(|x| x.parse::<usize>().ok())
+        .filter(|x| *x > 0)
+        .unwrap_or(DEFAULT_STAT_WIDTH);
+    let max_len = stats.iter().map(|x| x.name.chars().count()).max().unwrap_or(0);
+    let max_change = stats.iter().filter(|x| !x.binary).map(|x| x.added + x.deleted).max().unwrap_or(0);
+    let binary_width = stats.iter()
+        .filter(|x| x.binary)
+        .map(|x| STAT_BINARY_WIDTH + decimal_width(x.added) + decimal_width(x.deleted))
+        .max()
+        .unwrap_or(0);
+    // binary files show "Bin" in the number column
+    let number_width = decimal_width(max_change).max(if binary_width > 0 { 3 } else { 0 });
+    let width = width.max(16 + 6 + number_width);
+
+    let mut graph_width = if max_change + 4 > binary_width { max_change } else { binary_width - 4 };
+    let mut name_width = max_len;
+    if name_width + number_width + 6 + graph_width > width {
+        if graph_width + number_width + 6 > width * 3 / 8 {
+            graph_width = (width * 3 / 8).saturating_sub(number_width + 6).max(6);
+        }
+        if name_width + number_width + 6 + graph_width > width {
+            name_width = width.saturating_sub(number_width + 6 + graph_width);
+        } else {
+            graph_width = width - number_width - 6 - name_width;
+        }
+    }
+
+    for stat in stats {
+        let (name, prefix) = shorten_stat_name(&stat.name, name_width);
+        let padding = name_width.saturating_sub(prefix.len() + name.chars().count());
+        write!(writer, " {prefix}{name}{:padding$} | ", "")?;
+        if stat.unmerged {
+            writeln!(writer, "Unmerged")?;
+            continue;
+        }
+        if stat.binary {
+            write!(writer, "{:>number_width$}", "Bin")?;
+            if stat.added != 0 || stat.deleted != 0 {
+                write!(writer, " {deleted_color}{}{reset} -> {added_color}{}{reset} bytes", stat.deleted, stat.added)?;
+            }
+            writeln!(writer)?;
+            continue;
+        }
+        let total = stat.added + stat.deleted;
+        let (mut added, mut deleted) = (stat.added, stat.deleted);
+        if graph_width <= max_change {
+            let mut scaled_total = scale_linear(total, graph_width, max_change);
+            if scaled_total < 2 && added > 0 && deleted > 0 {
+                scaled_total = 2;
+            }
+            if added < deleted {
+                added = scale_linear(added, graph_width, max_change);
+                deleted = scaled_total - added;
+            } else {
+                deleted = scale_linear(deleted, graph_width, max_change);
+                added = scaled_total - deleted;
+            }
+        }
+        write!(writer, "{total:>number_width$}")?;
+        if total > 0 {
+            write!(writer, " ")?;
+            if added > 0 {
+                write!(writer, "{added_color}{}{reset}", "+".repeat(added))?;
+            }
+            if deleted > 0 {
+                write!(writer, "{deleted_color}{}{reset}", "-".repeat(deleted))?;
+            }
+        }
+        writeln!(writer)?;
+    }
+    write_stat_totals(stats, writer)
+}
+
+/// The line with the numbers of changed files and lines, binary files count only as changed files
+fn write_stat_totals(stats: &[StatEntry], writer: &mut impl Write) -> anyhow::Result<()> {
+    let counted = stats.iter().filter(|x| !x.unmerged).collect::<Vec<_>>();
+    if counted.is_empty() {
+        return Ok(());
+    }
+    let files = counted.len();
+    let insertions = counted.iter().filter(|x| !x.binary).map(|x| x.added).sum::<usize>();
+    let deletions = counted.iter().filter(|x| !x.binary).map(|x| x.deleted).sum::<usize>();
+    write!(writer, " {files} file{} changed", if files == 1 { "" } else { "s" })?;
+    if insertions > 0 || deletions == 0 {
+        write!(writer, ", {insertions} insertion{}(+)", if insertions == 1 { "" } else { "s" })?;
+    }
+    if deletions > 0 || insertions == 0 {
+        write!(writer, ", {deletions} deletion{}(-)", if deletions == 1 { "" } else { "s" })?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Describes changes that are not visible in the stat, like "create mode 100644 path"
+fn write_summary(change: &FileChange, writer: &mut impl Write) -> anyhow::Result<()> {
+    let mode = |x: &Option<DiffSide>| x.as_ref().map_or("000000".to_string(), |x| format!("{:0>6}", x.mode.to_string()));
+    match change.status {
+        ChangeStatus::Added => writeln!(writer, " create mode {} {}", mode(&change.new), change.path)?,
+        ChangeStatus::Deleted => writeln!(writer, " delete mode {} {}", mode(&change.old), change.path)?,
+        ChangeStatus::Renamed(score) | ChangeStatus::Copied(score) => {
+            let kind = if matches!(change.status, ChangeStatus::Renamed(_)) { "rename" } else { "copy" };
+            writeln!(writer, " {kind} {} ({score}%)", format_rename(change.old_path(), &change.path))?;
+        },
+        _ => {},
+    }
+    let (old_mode, new_mode) = (mode(&change.old), mode(&change.new));
+    if change.old.is_some() && change.new.is_some() && old_mode != new_mode {
+        write!(writer, " mode change {old_mode} => {new_mode}")?;
+        // renames already named the file
+        match change.source {
+            Some(_) => writeln!(writer)?,
+            None => writeln!(writer, " {}", change.path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Long names are cut from the start, preferably at a directory boundary, and prefixed with "..."
+fn shorten_stat_name(path: &str, width: usize) -> (&str, &'static str) {
+    let len = path.chars().count();
+    if len <= width {
+        return (path, "");
+    }
+    let keep = width.saturating_sub(3);
+    let start = path.char_indices().nth(len - keep).map_or(path.len(), |(x, _)| x);
+    let name = &path[start..];
+    let name = name.find('/').map_or(name, |x| &name[x..]);
+    (name, "...")
+}
+
+fn scale_linear(value: usize, width: usize, max_change: usize) -> usize {
+    if value == 0 {
+        return 0;
+    }
+    1 + value * (width - 1) / max_change
+}
+
+fn decimal_width(value: usize) -> usize {
+    value.to_string().len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_stat() -> anyhow::Result<()> {
+        let stats = [
+            StatEntry { name: "src/main.rs".to_string(), added: 10, deleted: 2, binary: false, unmerged: false },
+            StatEntry { name: "README".to_string(), added: 0, deleted: 0, binary: false, unmerged: false },
+            StatEntry { name: "logo.png".to_string(), added: 120, deleted: 0, binary: true, unmerged: false },
+        ];
+        let mut output = vec![];
+        write_stat(&stats, false, &mut output)?;
+        let expected = " src/main.rs |  12 ++++++++++--
+ README      |   0
+ logo.png    | Bin 0 -> 120 bytes
+ 3 files changed, 10 insertions(+), 2 deletions(-)
+";
+        assert_eq!(expected, String::from_utf8(output)?);
+
+        let stats = [StatEntry { name: "a/very/long/directory/name/file.txt".to_string(), added: 1000, deleted: 0, binary: false, unmerged: false }];
+        let mut output = vec![];
+        write_stat(&stats, false, &mut output)?;
+        let expected = format!(" a/very/long/directory/name/file.txt | 1000 {}\n 1 file changed, 1000 insertions(+)\n", "+".repeat(35));
+        assert_eq!(expected, String::from_utf8(output)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_rename() {
+        assert_eq!("a => b", format_rename("a", "b"));
+        assert_eq!("src/{old => new}/file.rs", format_rename("src/old/file.rs", "src/new/file.rs"));
+        assert_eq!("src/{a.rs => b.rs}", format_rename("src/a.rs", "src/b.rs"));
+        assert_eq!("{old => new}/file", format_rename("old/file", "new/file"));
+        assert_eq!("a/{ => c}/b", format_rename("a/b", "a/c/b"));
+        assert_eq!("file => dir/file", format_rename("file", "dir/file"));
+    }
+}
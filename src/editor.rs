@@ -0,0 +1,54 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use anyhow::{bail, Context};
+use crate::config::Config;
+
+const DEFAULT_EDITOR: &str = "vi";
+
+/// The editor from $GIT_EDITOR, core.editor, $VISUAL or $EDITOR, in this order. $VISUAL is skipped on dumb terminals
+pub(crate) fn editor_command(config: &Config) -> anyhow::Result<String> {
+    let dumb_terminal = env::var("TERM").map_or(true, |x| x == "dumb");
+    let editor = env::var("GIT_EDITOR").ok()
+        .or_else(|| config.get("core.editor").map(str::to_string))
+        .or_else(|| env::var("VISUAL").ok().filter(|_| !dumb_terminal))
+        .or_else(|| env::var("EDITOR").ok());
+    match editor {
+        Some(editor) => Ok(editor),
+        None if dumb_terminal => bail!("Terminal is dumb, but EDITOR unset"),
+        None => Ok(DEFAULT_EDITOR.to_string()),
+    }
+}
+
+/// Lets the user edit the file and waits until the editor exits.
+/// The editor is run by the shell, so it can contain arguments, ":" leaves the file as it is
+pub(crate) fn launch_editor(path: &Path, config: &Config) -> anyhow::Result<()> {
+    run_editor(&editor_command(config)?, path)
+}
+
+/// Lets the user edit the todo list of a rebase, with $GIT_SEQUENCE_EDITOR or sequence.editor if one is set,
+/// which lets scripts edit the list without touching commit messages
+pub(crate) fn launch_sequence_editor(path: &Path, config: &Config) -> anyhow::Result<()> {
+    let editor = match env::var("GIT_SEQUENCE_EDITOR").ok().or_else(|| config.get("sequence.editor").map(str::to_string)) {
+        Some(editor) => editor,
+        None => editor_command(config)?,
+    };
+    run_editor(&editor, path)
+}
+
+fn run_editor(editor: &str, path: &Path) -> anyhow::Result<()> {
+    if editor == ":" {
+        return Ok(());
+    }
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$@\""))
+        .arg(editor)
+        .arg(path)
+        .status()
+        .context(format!("unable to start editor '{editor}'"))?;
+    if !status.success() {
+        bail!("There was a problem with the editor '{editor}'.");
+    }
+    Ok(())
+}
@@ -0,0 +1,505 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use anyhow::{bail, Context};
+use crate::cli::FetchFlags;
+use crate::commit_object_read::Signature;
+use crate::common::{Exit, FETCH_HEAD_PATH, ObjectType};
+use crate::config::Config;
+use crate::diff::{abbreviate, ABBREV_LEN};
+use crate::ident::{read_ident_or_default, Role};
+use crate::merge_base::CommitGraph;
+use crate::pack::index_pack;
+use crate::refs::{delete_ref, Head, HEADS_PREFIX, is_symref, list_refs, read_head, read_head_commit, read_ref, REMOTES_PREFIX, TAGS_PREFIX, update_ref};
+use crate::refspec::Refspec;
+use crate::remote::{Remote, TagMode};
+use crate::rev_list::{rev_list, RevListOptions};
+use crate::rev_parse::{peel, read_object_type};
+use crate::smart_http::Service;
+use crate::transport::Transport;
+use crate::upload_pack::{AdvertisedRef, RefAdvertisement, UploadPackOptions};
+
+/// the width of the summary column, enough for two abbreviated hashes joined by `...`
+pub(crate) const SUMMARY_WIDTH: usize = 2 * ABBREV_LEN + 3;
+const MIN_REF_WIDTH: usize = 10;
+/// lines longer than this do not widen the ref column, git assumes this width when not writing to a terminal
+const TERMINAL_WIDTH: usize = 80;
+pub(crate) const TAGS_REFSPEC: &str = "refs/tags/*:refs/tags/*";
+
+/// How a fetched ref is listed in FETCH_HEAD
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum FetchHeadStatus {
+    /// a ref a following merge merges
+    Merge,
+    NotForMerge,
+    /// only fetched to update its remote-tracking ref, not listed
+    Ignore,
+}
+
+/// A ref of the remote a fetch transfers, with the local ref it is stored in
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FetchedRef {
+    pub remote: AdvertisedRef,
+    /// None for a ref that is only recorded in FETCH_HEAD
+    pub local: Option<String>,
+    /// update the local ref even if it is not a fast-forward
+    pub force: bool,
+    pub status: FetchHeadStatus,
+}
+
+/// The refs to fetch and whether to also fetch the tags that point to them
+pub(crate) struct RefMap {
+    pub refs: Vec<FetchedRef>,
+    pub follow_tags: bool,
+}
+
+/// The prefixes of the refs the remote has to list for the refspecs and the refs to merge
+pub(crate) fn ref_prefixes(refspecs: &[Refspec], merge_refs: &[String], tags: TagMode) -> Vec<String> {
+    let mut prefixes = merge_refs.to_vec();
+    for refspec in refspecs.iter().filter(|x| !x.negative) {
+        match refspec.is_pattern() {
+            true => prefixes.push(refspec.source_prefix().to_string()),
+            false => prefixes.extend(refspec.source_candidates()),
+        }
+    }
+    if refspecs.iter().all(|x| x.negative) && merge_refs.is_empty() {
+        prefixes.push("HEAD".to_string());
+    }
+    if tags != TagMode::None {
+        prefixes.push(TAGS_PREFIX.to_string());
+    }
+    prefixes
+}
+
+/// Maps the refs of the remote the way git does: the refspecs of the command line are all merged and also update
+/// the remote-tracking refs of the configured refspecs. Without them the configured refspecs are used, with the
+/// branches of `merge_refs` merged, or the first ref of the first refspec if it is not a pattern.
+/// Without any refspecs, HEAD is fetched and merged
+pub(crate) fn map_refs(advertisement: &RefAdvertisement, remote: &Remote, refspecs: &[Refspec], merge_refs: &[String]) -> anyhow::Result<RefMap> {
+    let mut refs = vec![];
+    let mut follow_tags = false;
+    let active = match refspecs.is_empty() {
+        true => &remote.fetch,
+        false => refspecs,
+    };
+    if !refspecs.is_empty() {
+        for refspec in refspecs.iter().filter(|x| !x.negative) {
+            refs.extend(map_refspec(advertisement, refspec, FetchHeadStatus::Merge, false)?);
+            follow_tags |= refspec.dst.is_some();
+        }
+        // the configured refspecs opportunistically update the remote-tracking refs of what was asked for
+        let mut tracking = vec![];
+        for refspec in remote.fetch.iter().filter(|x| !x.negative) {
+            for fetched in refs.iter().filter(|x| refspec.matches_source(&x.remote.name)) {
+                let local = refspec.destination(&fetched.remote.name);
+                tracking.push(FetchedRef { remote: fetched.remote.clone(), local, force: refspec.force, status: FetchHeadStatus::Ignore });
+            }
+        }
+        refs.extend(tracking.into_iter().filter(|x| x.local.is_some()));
+    } else if !remote.fetch.is_empty() || !merge_refs.is_empty() {
+        for (position, refspec) in remote.fetch.iter().filter(|x| !x.negative).enumerate() {
+            let mut mapped = map_refspec(advertisement, refspec, FetchHeadStatus::NotForMerge, true)?;
+            if let (0, true, false, Some(first)) = (position, merge_refs.is_empty(), refspec.is_pattern(), mapped.first_mut()) {
+                first.status = FetchHeadStatus::Merge;
+            }
+            refs.extend(mapped);
+            follow_tags |= refspec.dst.is_some();
+        }
+        for merge_ref in merge_refs {
+            let mut merged = refs.iter_mut().filter(|x| x.remote.name == *merge_ref).peekable();
+            if merged.peek().is_none() {
+                if let Some(remote_ref) = advertisement.find(merge_ref) {
+                    refs.push(FetchedRef { remote: remote_ref.clone(), local: None, force: false, status: FetchHeadStatus::Merge });
+                }
+                continue;
+            }
+            merged.for_each(|x| x.status = FetchHeadStatus::Merge);
+        }
+    } else {
+        let Some(head) = advertisement.find("HEAD") else {
+            bail!("couldn't find remote ref HEAD");
+        };
+        refs.push(FetchedRef { remote: head.clone(), local: None, force: false, status: FetchHeadStatus::Merge });
+    }
+    if remote.tags == TagMode::All {
+        refs.extend(map_refspec(advertisement, &Refspec::parse(TAGS_REFSPEC)?, FetchHeadStatus::NotForMerge, true)?);
+    }
+
+    let negative = active.iter().filter(|x| x.negative).collect::<Vec<_>>();
+    refs.retain(|x| !negative.iter().any(|refspec| refspec.matches_source(&x.remote.name)));
+    let mut unique: Vec<FetchedRef> = vec![];
+    for fetched in refs {
+        let duplicate = unique.iter().find(|x| x.local.is_some() && x.local == fetched.local);
+        match duplicate {
+            Some(existing) if existing.remote.name != fetched.remote.name => {
+                bail!("{} tracks both {} and {}", fetched.local.unwrap_or_default(), existing.remote.name, fetched.remote.name);
+            },
+            Some(_) => {},
+            None => unique.push(fetched),
+        }
+    }
+    Ok(RefMap { refs: unique, follow_tags: follow_tags && remote.tags == TagMode::Follow })
+}
+
+/// The refs of the remote a refspec matches. A missing exact ref is an error unless `missing_ok`
+fn map_refspec(advertisement: &RefAdvertisement, refspec: &Refspec, status: FetchHeadStatus, missing_ok: bool) -> anyhow::Result<Vec<FetchedRef>> {
+    let matched = match refspec.is_pattern() {
+        true => advertisement.refs.iter().filter(|x| refspec.matches_source(&x.name)).collect(),
+        false => {
+            let found = refspec.source_candidates().into_iter().find_map(|x| advertisement.find(&x));
+            match found {
+                Some(found) => vec![found],
+                None if missing_ok => vec![],
+                None => bail!("couldn't find remote ref {}", refspec.src),
+            }
+        },
+    };
+    let fetched = matched.into_iter()
+        .map(|x| FetchedRef { remote: x.clone(), local: refspec.destination(&x.name), force: refspec.force, status })
+        .collect();
+    Ok(fetched)
+}
+
+/// The tags of the remote that are not yet local and point to a fetched object or to one that is already here
+pub(crate) fn follow_tags(advertisement: &RefAdvertisement, fetched: &[FetchedRef]) -> anyhow::Result<Vec<FetchedRef>> {
+    let fetched_hashes = fetched.iter().map(|x| x.remote.hash.as_str()).collect::<HashSet<_>>();
+    let mut tags = vec![];
+    for tag in advertisement.refs.iter().filter(|x| x.name.starts_with(TAGS_PREFIX)) {
+        if read_ref(&tag.name)?.is_some() || fetched.iter().any(|x| x.remote.name == tag.name) {
+            continue;
+        }
+        let target = tag.peeled.as_deref().unwrap_or(&tag.hash);
+        if fetched_hashes.contains(target) || has_object(target) {
+            tags.push(FetchedRef { remote: tag.clone(), local: Some(tag.name.clone()), force: false, status: FetchHeadStatus::NotForMerge });
+        }
+    }
+    Ok(tags)
+}
+
+pub(crate) fn has_object(hash: &str) -> bool {
+    read_object_type(hash).is_ok()
+}
+
+/// The commits of HEAD and the local refs, newest first, to tell the remote what it does not have to send
+pub(crate) fn local_commits() -> anyhow::Result<Vec<String>> {
+    let mut tips = vec![];
+    tips.extend(read_head_commit()?);
+    for (_, hash) in list_refs("refs/")? {
+        if let Ok(commit) = peel(&hash, ObjectType::Commit) {
+            tips.push(commit);
+        }
+    }
+    let listed = rev_list(&tips, &[], &RevListOptions::default())?;
+    Ok(listed.commits.into_iter().map(|x| x.hash).collect())
+}
+
+/// The local refs the refspecs store refs of the remote in, whose refs the remote no longer has
+pub(crate) fn stale_refs(advertisement: &RefAdvertisement, refspecs: &[Refspec]) -> anyhow::Result<Vec<String>> {
+    let negative = refspecs.iter().filter(|x| x.negative).collect::<Vec<_>>();
+    let mut stale = vec![];
+    for refspec in refspecs.iter().filter(|x| !x.negative) {
+        for (local, _) in list_refs("refs/")? {
+            let Some(source) = refspec.source_of(&local) else {
+                continue;
+            };
+            let exists = match refspec.is_pattern() {
+                true => advertisement.find(&source).is_some(),
+                false => advertisement.refs.iter().any(|x| refspec.matches_source(&x.name)),
+            };
+            if exists || is_symref(&local) || negative.iter().any(|x| x.matches_source(&source)) || stale.contains(&local) {
+                continue;
+            }
+            stale.push(local);
+        }
+    }
+    Ok(stale)
+}
+
+/// How storing a fetched ref changes the local ref
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RefChange {
+    UpToDate,
+    Created,
+    FastForward,
+    Forced,
+    /// an existing tag moved, which only happens when forced
+    TagUpdate,
+    Rejected(&'static str),
+}
+impl RefChange {
+    /// Decides how the local ref changes from the old value to the new one of the remote
+    pub fn classify(local: &str, old: Option<&str>, new: &str, force: bool, graph: &mut CommitGraph) -> anyhow::Result<Self> {
+        let Some(old) = old else {
+            return Ok(Self::Created);
+        };
+        if old == new {
+            return Ok(Self::UpToDate);
+        }
+        if local.starts_with(TAGS_PREFIX) {
+            return Ok(if force { Self::TagUpdate } else { Self::Rejected("would clobber existing tag") });
+        }
+        let commits = (peel(old, ObjectType::Commit), peel(new, ObjectType::Commit));
+        if let (Ok(old), Ok(new)) = commits {
+            if graph.is_ancestor(&old, &new)? {
+                return Ok(Self::FastForward);
+            }
+        }
+        Ok(if force { Self::Forced } else { Self::Rejected("non-fast-forward") })
+    }
+
+    /// The reflog message of the update, None if the ref is not updated
+    pub fn reflog_message(self, remote_name: &str) -> Option<&'static str> {
+        match self {
+            Self::Created if remote_name.starts_with(TAGS_PREFIX) => Some("storing tag"),
+            Self::Created if remote_name.starts_with(HEADS_PREFIX) => Some("storing head"),
+            Self::Created => Some("storing ref"),
+            Self::FastForward => Some("fast-forward"),
+            Self::Forced => Some("forced-update"),
+            Self::TagUpdate => Some("updating tag"),
+            Self::UpToDate | Self::Rejected(_) => None,
+        }
+    }
+
+    /// The flag, summary and reason of the line that reports the update
+    pub fn summary(self, remote_name: &str, old: Option<&str>, new: &str) -> (char, String, Option<&'static str>) {
+        let old = abbreviate(old.unwrap_or_default());
+        let new = abbreviate(new);
+        match self {
+            Self::UpToDate => ('=', "[up to date]".to_string(), None),
+            Self::Created if remote_name.starts_with(TAGS_PREFIX) => ('*', "[new tag]".to_string(), None),
+            Self::Created if remote_name.starts_with(HEADS_PREFIX) => ('*', "[new branch]".to_string(), None),
+            Self::Created => ('*', "[new ref]".to_string(), None),
+            Self::FastForward => (' ', format!("{old}..{new}"), None),
+            Self::Forced => ('+', format!("{old}...{new}"), Some("forced update")),
+            Self::TagUpdate => ('t', "[tag update]".to_string(), None),
+            Self::Rejected(reason) => ('!', "[rejected]".to_string(), Some(reason)),
+        }
+    }
+}
+
+/// A ref name without refs/heads/, refs/tags/ or refs/remotes/
+pub(crate) fn pretty_ref_name(name: &str) -> &str {
+    [HEADS_PREFIX, TAGS_PREFIX, REMOTES_PREFIX].iter()
+        .find_map(|x| name.strip_prefix(x))
+        .unwrap_or(name)
+}
+
+/// The width of the column of remote ref names, wide enough for the names of the refs that are shown
+pub(crate) fn ref_column_width(shown: &[(&str, &str)]) -> usize {
+    shown.iter()
+        .map(|(remote, local)| (pretty_ref_name(remote).len(), pretty_ref_name(local).len()))
+        .filter(|(remote, local)| 21 + remote + 4 + local < TERMINAL_WIDTH)
+        .map(|(remote, _)| remote)
+        .fold(MIN_REF_WIDTH, usize::max)
+}
+
+/// A line of the summary of a fetch or push, like ` + 1234567...89abcde main -> origin/main  (forced update)`
+pub(crate) fn format_ref_line(flag: char, summary: &str, remote: &str, local: &str, reason: Option<&str>, width: usize) -> String {
+    let mut line = format!(" {flag} {summary:<SUMMARY_WIDTH$} {remote:<width$} -> {local}");
+    if let Some(reason) = reason {
+        line.push_str(&format!("  ({reason})"));
+    }
+    line
+}
+
+/// The url as it is shown and recorded in FETCH_HEAD, without a trailing slash or `.git`
+pub(crate) fn display_url(url: &str) -> &str {
+    let url = url.trim_end_matches('/');
+    url.strip_suffix(".git").filter(|x| !x.is_empty()).unwrap_or(url)
+}
+
+/// The kind and name of a remote ref in FETCH_HEAD and the summary, like `branch` and `main`
+pub(crate) fn describe_remote_ref(name: &str) -> (&'static str, &str) {
+    if name == "HEAD" {
+        return ("", "");
+    }
+    let kinds = [("branch", HEADS_PREFIX), ("tag", TAGS_PREFIX), ("remote-tracking branch", REMOTES_PREFIX)];
+    kinds.iter()
+        .find_map(|(kind, prefix)| Some((*kind, name.strip_prefix(prefix)?)))
+        .unwrap_or(("", name))
+}
+
+/// Writes FETCH_HEAD, the refs to merge first, then the others. Refs with the Ignore status are left out
+pub(crate) fn write_fetch_head(refs: &[FetchedRef], url: &str) -> anyhow::Result<()> {
+    let url = display_url(url);
+    let mut contents = String::new();
+    for status in [FetchHeadStatus::Merge, FetchHeadStatus::NotForMerge] {
+        for fetched in refs.iter().filter(|x| x.status == status) {
+            let marker = match status {
+                FetchHeadStatus::Merge => "",
+                _ => "not-for-merge",
+            };
+            let (kind, what) = describe_remote_ref(&fetched.remote.name);
+            let mut note = String::new();
+            if !kind.is_empty() {
+                note.push_str(&format!("{kind} "));
+            }
+            if !what.is_empty() {
+                note.push_str(&format!("'{what}' of "));
+            }
+            contents.push_str(&format!("{}\t{marker}\t{note}{url}\n", fetched.remote.hash));
+        }
+    }
+    fs::write(FETCH_HEAD_PATH, contents).context(format!("Failed to write {FETCH_HEAD_PATH}"))
+}
+
+/// Fetches the refs of a remote with their objects and updates the remote-tracking refs they map to
+pub(crate) fn fetch_command(remote: Option<String>, refspecs: Vec<String>, flags: FetchFlags) -> anyhow::Result<()> {
+    let config = Config::read()?;
+    let mut remote = Remote::resolve(remote.as_deref(), &config)?;
+    let refspecs = refspecs.iter().map(|x| Refspec::parse(x)).collect::<anyhow::Result<Vec<_>>>()?;
+    if flags.tags {
+        remote.tags = TagMode::All;
+    } else if flags.no_tags {
+        remote.tags = TagMode::None;
+    }
+    let prune = (remote.prune || flags.prune) && !flags.no_prune;
+    let merge_refs = match refspecs.is_empty() {
+        true => remote.merge_refs(&config)?.into_iter().map(str::to_string).collect(),
+        false => vec![],
+    };
+    let active = match refspecs.is_empty() {
+        true => &remote.fetch,
+        false => &refspecs,
+    };
+
+    let mut transport = Transport::open(&remote.url, Service::UploadPack, &config)?;
+    let prefixes = ref_prefixes(active, &merge_refs, remote.tags);
+    let advertisement = transport.list_refs(&prefixes.iter().map(String::as_str).collect::<Vec<_>>())?;
+    let mut ref_map = map_refs(&advertisement, &remote, &refspecs, &merge_refs)?;
+    if flags.force {
+        ref_map.refs.iter_mut().for_each(|x| x.force = true);
+    }
+    if ref_map.follow_tags {
+        let tags = follow_tags(&advertisement, &ref_map.refs)?;
+        ref_map.refs.extend(tags);
+    }
+    let bare = matches!(config.get("core.bare"), Some("true" | "yes" | "on" | "1"));
+    if let (false, Head::Branch(current)) = (bare, read_head()?) {
+        if ref_map.refs.iter().any(|x| x.local.as_deref() == Some(current.as_str())) {
+            let worktree = env::current_dir().context("Failed to read the current dir")?;
+            bail!("refusing to fetch into branch '{current}' checked out at '{}'", worktree.display());
+        }
+    }
+    fetch_missing_objects(&mut transport, &ref_map.refs, ref_map.follow_tags)?;
+
+    let mut shown = vec![];
+    for fetched in ref_map.refs.iter().filter(|x| x.remote.name != "HEAD") {
+        if let Some(local) = &fetched.local {
+            if read_ref(local)?.as_deref() != Some(fetched.remote.hash.as_str()) {
+                shown.push((fetched.remote.name.as_str(), local.as_str()));
+            }
+        }
+    }
+    let width = ref_column_width(&shown);
+    let mut header = Some(format!("From {}", display_url(&remote.url)));
+    if prune {
+        for stale in stale_refs(&advertisement, active)? {
+            delete_ref(&stale)?;
+            eprint!("{}", header.take().map(|x| x + "\n").unwrap_or_default());
+            eprintln!("{}", format_ref_line('-', "[deleted]", "(none)", pretty_ref_name(&stale), None, width));
+        }
+    }
+    let committer = read_ident_or_default(Role::Committer, &config)?;
+    // like git, the reflog records the command line of the fetch
+    let reflog_prefix = env::args().skip_while(|x| x != "fetch").collect::<Vec<_>>().join(" ");
+    let mut graph = CommitGraph::default();
+    let mut rejected = store_fetched_refs(&ref_map.refs, width, &reflog_prefix, &committer, &mut header, &mut graph)?;
+    if ref_map.follow_tags {
+        // tags of commits that only arrived as the history of the fetched ones
+        let tags = follow_tags(&advertisement, &ref_map.refs)?;
+        fetch_missing_objects(&mut transport, &tags, false)?;
+        rejected |= store_fetched_refs(&tags, width, &reflog_prefix, &committer, &mut header, &mut graph)?;
+        ref_map.refs.extend(tags);
+    }
+    write_fetch_head(&ref_map.refs, &remote.url)?;
+    if rejected {
+        return Err(Exit(1).into());
+    }
+    Ok(())
+}
+
+/// Fetches the objects of the refs that are not in the repository yet, with the local commits as haves.
+/// With `include_tag`, the annotated tags of the fetched commits come along
+fn fetch_missing_objects(transport: &mut Transport, refs: &[FetchedRef], include_tag: bool) -> anyhow::Result<()> {
+    let mut wants = vec![];
+    for hash in refs.iter().map(|x| &x.remote.hash) {
+        if !has_object(hash) && !wants.contains(hash) {
+            wants.push(hash.clone());
+        }
+    }
+    if wants.is_empty() {
+        return Ok(());
+    }
+    let received = transport.fetch(&wants, &local_commits()?, &UploadPackOptions { include_tag, ..UploadPackOptions::default() })?;
+    index_pack(&received.pack)?;
+    Ok(())
+}
+
+/// Updates the local refs of the fetched refs, the ones to merge first, and prints a line for each change.
+/// Returns whether an update was rejected
+fn store_fetched_refs(refs: &[FetchedRef], width: usize, reflog_prefix: &str, committer: &Signature, header: &mut Option<String>, graph: &mut CommitGraph) -> anyhow::Result<bool> {
+    let mut rejected = false;
+    let mut ordered = refs.iter().collect::<Vec<_>>();
+    ordered.sort_by_key(|x| x.status);
+    for fetched in ordered {
+        let line = match &fetched.local {
+            Some(local) => {
+                let old = read_ref(local)?;
+                let new = &fetched.remote.hash;
+                let change = RefChange::classify(local, old.as_deref(), new, fetched.force, graph)?;
+                if let Some(message) = change.reflog_message(&fetched.remote.name) {
+                    update_ref(local, new, old.as_deref(), committer, &format!("{reflog_prefix}: {message}"))?;
+                }
+                if change == RefChange::UpToDate {
+                    continue;
+                }
+                rejected |= matches!(change, RefChange::Rejected(_));
+                let (flag, summary, reason) = change.summary(&fetched.remote.name, old.as_deref(), new);
+                format_ref_line(flag, &summary, pretty_ref_name(&fetched.remote.name), pretty_ref_name(local), reason, width)
+            },
+            None => {
+                let (kind, what) = describe_remote_ref(&fetched.remote.name);
+                let kind = Some(kind).filter(|x| !x.is_empty()).unwrap_or("branch");
+                let what = Some(what).filter(|x| !x.is_empty()).unwrap_or("HEAD");
+                format_ref_line('*', kind, what, "FETCH_HEAD", None, width)
+            },
+        };
+        eprint!("{}", header.take().map(|x| x + "\n").unwrap_or_default());
+        eprintln!("{line}");
+    }
+    Ok(rejected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_map_refs() -> anyhow::Result<()> {
+        let advertised = |name: &str, hash: &str| AdvertisedRef { name: name.to_string(), hash: hash.to_string(), peeled: None };
+        let advertisement = RefAdvertisement {
+            refs: vec![advertised("HEAD", "1"), advertised("refs/heads/feat", "2"), advertised("refs/heads/main", "1")],
+            head_target: Some("refs/heads/main".to_string()),
+        };
+        let remote = Remote {
+            name: Some("origin".to_string()),
+            url: "/tmp/x.git".to_string(),
+            fetch: vec![Refspec::parse("+refs/heads/*:refs/remotes/origin/*")?, Refspec::parse("^refs/heads/feat")?],
+            push: vec![],
+            tags: TagMode::None,
+            prune: false,
+        };
+        let mapped = map_refs(&advertisement, &remote, &[], &["refs/heads/main".to_string()])?;
+        let statuses = mapped.refs.iter().map(|x| (x.local.as_deref().unwrap_or_default(), x.status)).collect::<Vec<_>>();
+        assert_eq!(vec![("refs/remotes/origin/main", FetchHeadStatus::Merge)], statuses);
+
+        // a ref of the command line still updates its remote-tracking ref, the configured negative refspec does not apply
+        let mapped = map_refs(&advertisement, &remote, &[Refspec::parse("feat")?], &[])?;
+        let statuses = mapped.refs.iter().map(|x| (x.local.as_deref(), x.status)).collect::<Vec<_>>();
+        assert_eq!(vec![(None, FetchHeadStatus::Merge), (Some("refs/remotes/origin/feat"), FetchHeadStatus::Ignore)], statuses);
+        assert!(map_refs(&advertisement, &remote, &[Refspec::parse("nope")?], &[]).is_err());
+        assert_eq!("git.example.com/repo", display_url("git.example.com/repo.git/"));
+        Ok(())
+    }
+}
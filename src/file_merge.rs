@@ -0,0 +1,331 @@
+use std::ops::Range;
+use clap::ValueEnum;
+use crate::diff::{Chunk, diff_lines, DiffOptions, split_lines};
+
+/// the number of characters of a conflict marker
+pub(crate) const MARKER_SIZE: usize = 7;
+/// conflicts separated by at most this many lines are joined into one
+const MAX_JOIN_DISTANCE: usize = 3;
+
+/// How conflicts are written into a merged file
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub(crate) enum ConflictStyle {
+    /// our and their version of each conflict
+    #[default]
+    Merge,
+    /// the base version is shown between our and their version
+    Diff3,
+    /// like diff3, but lines at the start and the end that both sides have in common are moved out of the conflict
+    Zdiff3,
+}
+impl ConflictStyle {
+    /// The style from merge.conflictStyle
+    pub fn from_config(value: Option<&str>) -> anyhow::Result<Self> {
+        match value {
+            None => Ok(Self::Merge),
+            Some(value) => Self::from_str(value, true).map_err(|_| anyhow::anyhow!("unknown style '{value}' given for 'merge.conflictstyle'")),
+        }
+    }
+}
+
+/// Which lines replace the conflict markers, to resolve the conflicts automatically
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub(crate) enum MergeFavor {
+    Ours,
+    Theirs,
+    /// our lines followed by their lines
+    Union,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FileMergeOptions {
+    pub style: ConflictStyle,
+    pub favor: Option<MergeFavor>,
+    pub marker_size: usize,
+}
+impl Default for FileMergeOptions {
+    fn default() -> Self {
+        Self { style: ConflictStyle::Merge, favor: None, marker_size: MARKER_SIZE }
+    }
+}
+
+/// The names shown after the conflict markers, empty ones are left out
+pub(crate) struct MergeLabels<'a> {
+    pub ours: &'a str,
+    pub base: &'a str,
+    pub theirs: &'a str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RegionKind {
+    Conflict,
+    /// only our side changed the lines
+    Ours,
+    /// only their side changed the lines
+    Theirs,
+    /// both sides made the same change, which is already in our lines
+    Same,
+}
+
+/// Lines changed by one or both sides, with the ranges of the lines in the base, ours and theirs
+#[derive(Clone, Debug, PartialEq)]
+struct Region {
+    kind: RegionKind,
+    base: Range<usize>,
+    ours: Range<usize>,
+    theirs: Range<usize>,
+}
+
+/// Merges the changes from the base to ours and to theirs line by line, the way git does.
+/// Returns the merged text, which has conflict markers around the lines both sides changed differently,
+/// and the number of conflicts, which is 0 when they are resolved in favor of a side
+pub(crate) fn merge_texts(base: &[u8], ours: &[u8], theirs: &[u8], labels: &MergeLabels, options: &FileMergeOptions) -> (Vec<u8>, usize) {
+    let base = split_lines(base);
+    let ours = split_lines(ours);
+    let theirs = split_lines(theirs);
+    let mut regions = find_regions(&base, &ours, &theirs);
+    // the base lines of a conflict can not be shown after it has been split
+    match options.style {
+        ConflictStyle::Merge => {
+            regions = refine_conflicts(regions, &ours, &theirs);
+            regions = join_close_conflicts(regions, &ours);
+        },
+        ConflictStyle::Diff3 => {},
+        ConflictStyle::Zdiff3 => regions = trim_conflicts(regions, &ours, &theirs),
+    }
+
+    let mut output = vec![];
+    let mut conflicts = 0;
+    let mut position = 0;
+    for region in regions {
+        if region.kind == RegionKind::Same {
+            continue;
+        }
+        output.extend(ours[position..region.ours.start].concat());
+        let marker_size = options.marker_size;
+        match (region.kind, options.favor) {
+            (RegionKind::Ours, _) | (_, Some(MergeFavor::Ours)) => output.extend(ours[region.ours.clone()].concat()),
+            (RegionKind::Theirs, _) | (_, Some(MergeFavor::Theirs)) => output.extend(theirs[region.theirs.clone()].concat()),
+            (_, Some(MergeFavor::Union)) => {
+                write_conflict_side(&mut output, &ours[region.ours.clone()]);
+                output.extend(theirs[region.theirs.clone()].concat());
+            },
+            (_, None) => {
+                conflicts += 1;
+                write_marker(&mut output, b'<', marker_size, labels.ours);
+                write_conflict_side(&mut output, &ours[region.ours.clone()]);
+                if options.style != ConflictStyle::Merge {
+                    write_marker(&mut output, b'|', marker_size, labels.base);
+                    write_conflict_side(&mut output, &base[region.base.clone()]);
+                }
+                write_marker(&mut output, b'=', marker_size, "");
+                write_conflict_side(&mut output, &theirs[region.theirs.clone()]);
+                write_marker(&mut output, b'>', marker_size, labels.theirs);
+            },
+        }
+        position = region.ours.end;
+    }
+    output.extend(ours[position..].concat());
+    (output, conflicts)
+}
+
+/// Pairs up the changes of both sides. Changes that overlap or touch each other in the base are conflicts,
+/// unless both sides changed the same lines in the same way
+fn find_regions(base: &[&[u8]], ours: &[&[u8]], theirs: &[&[u8]]) -> Vec<Region> {
+    let options = DiffOptions::default();
+    let ours_chunks = diff_lines(base, ours, &options);
+    let theirs_chunks = diff_lines(base, theirs, &options);
+    // the line of the other side that a base line before its next change corresponds to
+    let map_line = |line: usize, next: Option<&Chunk>, other_len: usize| match next {
+        Some(chunk) => chunk.new.start + line - chunk.old.start,
+        None => line + other_len - base.len(),
+    };
+
+    let mut regions = vec![];
+    let (mut next_ours, mut next_theirs) = (0, 0);
+    loop {
+        let (ours_chunk, theirs_chunk) = match (ours_chunks.get(next_ours), theirs_chunks.get(next_theirs)) {
+            (None, None) => break,
+            (Some(chunk), other) if other.is_none_or(|x| chunk.old.end < x.old.start) => {
+                let start = map_line(chunk.old.start, other, theirs.len());
+                append_region(&mut regions, RegionKind::Ours, chunk.old.clone(), chunk.new.clone(), start..start + chunk.old.len());
+                next_ours += 1;
+                continue;
+            },
+            (other, Some(chunk)) if other.is_none_or(|x| chunk.old.end < x.old.start) => {
+                let start = map_line(chunk.old.start, other, ours.len());
+                append_region(&mut regions, RegionKind::Theirs, chunk.old.clone(), start..start + chunk.old.len(), chunk.new.clone());
+                next_theirs += 1;
+                continue;
+            },
+            (Some(ours_chunk), Some(theirs_chunk)) => (ours_chunk, theirs_chunk),
+            _ => unreachable!("a single remaining chunk never overlaps"),
+        };
+        if ours_chunk.old != theirs_chunk.old || ours[ours_chunk.new.clone()] != theirs[theirs_chunk.new.clone()] {
+            // both sides are extended to the base lines that either of them changed
+            let base_range = ours_chunk.old.start.min(theirs_chunk.old.start)..ours_chunk.old.end.max(theirs_chunk.old.end);
+            let extend = |chunk: &Chunk| {
+                chunk.new.start - (chunk.old.start - base_range.start)..chunk.new.end + (base_range.end - chunk.old.end)
+            };
+            let (ours_range, theirs_range) = (extend(ours_chunk), extend(theirs_chunk));
+            append_region(&mut regions, RegionKind::Conflict, base_range, ours_range, theirs_range);
+        }
+        if ours_chunk.old.end >= theirs_chunk.old.end {
+            next_theirs += 1;
+        }
+        if theirs_chunk.old.end >= ours_chunk.old.end {
+            next_ours += 1;
+        }
+    }
+    regions
+}
+
+/// Adds a region, or extends the last one if they overlap. Overlapping regions of different kinds are a conflict
+fn append_region(regions: &mut Vec<Region>, kind: RegionKind, base: Range<usize>, ours: Range<usize>, theirs: Range<usize>) {
+    if let Some(last) = regions.last_mut() {
+        if ours.start <= last.ours.end || theirs.start <= last.theirs.end {
+            if last.kind != kind {
+                last.kind = RegionKind::Conflict;
+            }
+            last.base.end = last.base.end.max(base.end);
+            last.ours.end = last.ours.end.max(ours.end);
+            last.theirs.end = last.theirs.end.max(theirs.end);
+            return;
+        }
+    }
+    regions.push(Region { kind, base, ours, theirs });
+}
+
+/// Compares our and their lines of each conflict and only keeps the lines that differ in conflicts
+fn refine_conflicts(regions: Vec<Region>, ours: &[&[u8]], theirs: &[&[u8]]) -> Vec<Region> {
+    let mut refined = Vec::with_capacity(regions.len());
+    for region in regions {
+        if region.kind != RegionKind::Conflict || region.ours.is_empty() || region.theirs.is_empty() {
+            refined.push(region);
+            continue;
+        }
+        let chunks = diff_lines(&ours[region.ours.clone()], &theirs[region.theirs.clone()], &DiffOptions::default());
+        if chunks.is_empty() {
+            refined.push(Region { kind: RegionKind::Same, ..region });
+            continue;
+        }
+        let offset = |range: Range<usize>, start: usize| range.start + start..range.end + start;
+        for chunk in chunks {
+            refined.push(Region {
+                kind: RegionKind::Conflict,
+                base: region.base.clone(),
+                ours: offset(chunk.old, region.ours.start),
+                theirs: offset(chunk.new, region.theirs.start),
+            });
+        }
+    }
+    refined
+}
+
+/// Moves the lines that our and their side of each conflict start or end with out of the conflict,
+/// the base lines stay as they are
+fn trim_conflicts(mut regions: Vec<Region>, ours: &[&[u8]], theirs: &[&[u8]]) -> Vec<Region> {
+    for region in regions.iter_mut().filter(|x| x.kind == RegionKind::Conflict) {
+        while !region.ours.is_empty() && !region.theirs.is_empty() && ours[region.ours.start] == theirs[region.theirs.start] {
+            region.ours.start += 1;
+            region.theirs.start += 1;
+        }
+        while !region.ours.is_empty() && !region.theirs.is_empty() && ours[region.ours.end - 1] == theirs[region.theirs.end - 1] {
+            region.ours.end -= 1;
+            region.theirs.end -= 1;
+        }
+    }
+    regions
+}
+
+/// Joins conflicts that are only separated by a few lines, or by lines without letters and digits
+fn join_close_conflicts(regions: Vec<Region>, ours: &[&[u8]]) -> Vec<Region> {
+    let mut joined: Vec<Region> = Vec::with_capacity(regions.len());
+    for region in regions {
+        if let Some(last) = joined.last_mut() {
+            let between = &ours[last.ours.end..region.ours.start];
+            let close = between.len() <= MAX_JOIN_DISTANCE || !between.iter().any(|x| x.iter().any(u8::is_ascii_alphanumeric));
+            if last.kind == RegionKind::Conflict && region.kind == RegionKind::Conflict && close {
+                last.base.end = region.base.end;
+                last.ours.end = region.ours.end;
+                last.theirs.end = region.theirs.end;
+                continue;
+            }
+        }
+        joined.push(region);
+    }
+    joined
+}
+
+fn write_marker(output: &mut Vec<u8>, marker: u8, size: usize, label: &str) {
+    output.extend(std::iter::repeat_n(marker, size));
+    if !label.is_empty() {
+        output.push(b' ');
+        output.extend(label.as_bytes());
+    }
+    output.push(b'\n');
+}
+
+/// Writes the lines of one side of a conflict, the marker after them has to start on a new line
+fn write_conflict_side(output: &mut Vec<u8>, lines: &[&[u8]]) {
+    output.extend(lines.concat());
+    if lines.last().is_some_and(|x| !x.ends_with(b"\n")) {
+        output.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LABELS: MergeLabels = MergeLabels { ours: "ours", base: "base", theirs: "theirs" };
+
+    fn style(style: ConflictStyle) -> FileMergeOptions {
+        FileMergeOptions { style, ..FileMergeOptions::default() }
+    }
+
+    #[test]
+    fn test_merge_texts() {
+        let base = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let (merged, conflicts) = merge_texts(base, b"one\n2\n3\n4\n5\n6\n7\n8\n9\n", b"1\n2\n3\n4\n5\n6\n7\n8\nnine\n", &LABELS, &style(ConflictStyle::Merge));
+        assert_eq!((b"one\n2\n3\n4\n5\n6\n7\n8\nnine\n".to_vec(), 0), (merged, conflicts));
+
+        let (merged, conflicts) = merge_texts(base, b"1\n2\na\nb\n5\n6\n7\n8\n9", b"1\n2\na\nc\n5\n6\n7\n8\n9\n", &LABELS, &style(ConflictStyle::Merge));
+        assert_eq!(1, conflicts);
+        assert_eq!("1\n2\na\n<<<<<<< ours\nb\n=======\nc\n>>>>>>> theirs\n5\n6\n7\n8\n9", String::from_utf8_lossy(&merged));
+
+        let (merged, conflicts) = merge_texts(base, b"1\n2\na\nb\n5\n6\n7\n8\n9\n", b"1\n2\na\nc\n5\n6\n7\n8\n9\n", &LABELS, &style(ConflictStyle::Diff3));
+        assert_eq!(1, conflicts);
+        let expected = "1\n2\n<<<<<<< ours\na\nb\n||||||| base\n3\n4\n=======\na\nc\n>>>>>>> theirs\n5\n6\n7\n8\n9\n";
+        assert_eq!(expected, String::from_utf8_lossy(&merged));
+    }
+
+    #[test]
+    fn test_join_close_conflicts() {
+        let base = b"1\n2\n3\n4\n5\n";
+        let (merged, conflicts) = merge_texts(base, b"a\n2\n3\n4\nb\n", b"c\n2\n3\n4\nd\n", &LABELS, &style(ConflictStyle::Merge));
+        assert_eq!(1, conflicts);
+        assert_eq!("<<<<<<< ours\na\n2\n3\n4\nb\n=======\nc\n2\n3\n4\nd\n>>>>>>> theirs\n", String::from_utf8_lossy(&merged));
+
+        let (merged, conflicts) = merge_texts(b"", b"a\n", b"", &LABELS, &style(ConflictStyle::Merge));
+        assert_eq!((b"a\n".to_vec(), 0), (merged, conflicts));
+    }
+
+    #[test]
+    fn test_zdiff3_and_favor() {
+        let base = b"1\n2\n3\n";
+        let (ours, theirs) = (b"1\na\nb\nc\n3\n", b"1\na\nx\nc\n3\n");
+        let (merged, conflicts) = merge_texts(base, ours, theirs, &LABELS, &style(ConflictStyle::Zdiff3));
+        assert_eq!(1, conflicts);
+        assert_eq!("1\na\n<<<<<<< ours\nb\n||||||| base\n2\n=======\nx\n>>>>>>> theirs\nc\n3\n", String::from_utf8_lossy(&merged));
+
+        let options = |favor| FileMergeOptions { favor: Some(favor), marker_size: 3, ..FileMergeOptions::default() };
+        assert_eq!((b"1\na\nb\nc\n3\n".to_vec(), 0), merge_texts(base, ours, theirs, &LABELS, &options(MergeFavor::Ours)));
+        assert_eq!((b"1\na\nx\nc\n3\n".to_vec(), 0), merge_texts(base, ours, theirs, &LABELS, &options(MergeFavor::Theirs)));
+        assert_eq!((b"1\na\nb\nx\nc\n3\n".to_vec(), 0), merge_texts(base, ours, theirs, &LABELS, &options(MergeFavor::Union)));
+
+        let options = FileMergeOptions { marker_size: 3, ..FileMergeOptions::default() };
+        let (merged, _) = merge_texts(base, ours, theirs, &LABELS, &options);
+        assert_eq!("1\na\n<<< ours\nb\n===\nx\n>>> theirs\nc\n3\n", String::from_utf8_lossy(&merged));
+    }
+}
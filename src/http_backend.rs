@@ -0,0 +1,530 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use anyhow::{bail, Context};
+use flate2::read::GzDecoder;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::process::Command;
+use crate::pkt_line::{AsyncPacketReader, AsyncPacketWriter, Band, MAX_PACKET_LEN, Packet, PacketReader, SMALL_SIDEBAND_PACKET_LEN, text_line};
+use crate::repository::find_git_dir;
+use crate::smart_http::{CAPABILITIES_REF, ProtocolVersion, Service};
+
+/// the longest request line and headers a request may have
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+/// What an http-backend server serves and how
+pub(crate) struct HttpBackendOptions {
+    /// the repositories by the first component of the url path that leads to them
+    pub repositories: Vec<(String, PathBuf)>,
+    /// also serve the files the dumb protocol reads
+    pub dumb: bool,
+    /// refuse receive-pack
+    pub read_only: bool,
+}
+impl HttpBackendOptions {
+    /// Serves each repository below the name of its directory, like `/project.git/info/refs` for `srv/project.git`
+    pub fn new(paths: &[String], dumb: bool, read_only: bool) -> anyhow::Result<Self> {
+        let mut repositories: Vec<(String, PathBuf)> = vec![];
+        for path in paths {
+            let git_dir = find_git_dir(Path::new(path)).context(format!("'{path}' does not appear to be a git repository"))?;
+            let git_dir = fs::canonicalize(&git_dir).context(format!("Failed to resolve {}", git_dir.display()))?;
+            let directory = fs::canonicalize(path).context(format!("Failed to resolve {path}"))?;
+            let name = directory.file_name().context(format!("'{path}' has no name to serve it as"))?.to_string_lossy().to_string();
+            if repositories.iter().any(|(x, _)| *x == name) {
+                bail!("more than one repository is named '{name}'");
+            }
+            repositories.push((name, git_dir));
+        }
+        Ok(Self { repositories, dumb, read_only })
+    }
+
+    fn find(&self, name: &str) -> Option<&Path> {
+        self.repositories.iter().find(|(x, _)| x == name).map(|(_, git_dir)| git_dir.as_path())
+    }
+}
+
+/// A request as far as the server cares about it, the body is already decoded
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    /// the path and the query of the url
+    target: String,
+    /// the header names in lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(x, _)| x == name).map(|(_, value)| value.as_str())
+    }
+
+    fn path(&self) -> &str {
+        self.target.split_once('?').map_or(&self.target, |(path, _)| path)
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.target.split_once('?')?;
+        query.split('&').find_map(|x| x.strip_prefix(name)?.strip_prefix('='))
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: String,
+    body: Vec<u8>,
+    /// what the server log shows about the request after its status, like the refs a push was refused
+    log: Vec<String>,
+}
+impl Response {
+    fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self { status, content_type: content_type.to_string(), body, log: vec![] }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::new(status, "text/plain", format!("{message}\n").into_bytes())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Listens on the address and serves the repositories until the process is stopped, logging each request to stderr
+pub(crate) fn run_http_backend(listen: &str, options: HttpBackendOptions) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().context("Failed to start the server")?;
+    runtime.block_on(async {
+        let listener = TcpListener::bind(listen).await.context(format!("unable to listen on {listen}"))?;
+        eprintln!("Listening on http://{}/", listener.local_addr()?);
+        let options = Arc::new(options);
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    eprintln!("error: failed to accept a connection: {error}");
+                    continue;
+                },
+            };
+            let options = options.clone();
+            tokio::spawn(async move {
+                if let Err(error) = serve_connection(stream, peer, &options).await {
+                    eprintln!("{peer} error: {error:#}");
+                }
+            });
+        }
+    })
+}
+
+/// Answers a single request, the connection is closed afterwards
+async fn serve_connection(stream: tokio::net::TcpStream, peer: SocketAddr, options: &HttpBackendOptions) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let (request_line, response) = match read_request(&mut reader, &mut writer).await {
+        Ok(Some(request)) => (format!("{} {}", request.method, request.target), handle_request(&request, options).await),
+        Ok(None) => return Ok(()),
+        Err(error) => ("-".to_string(), Response::error(400, &format!("{error:#}"))),
+    };
+    eprintln!("{peer} \"{request_line}\" {} {}", response.status, response.body.len());
+    for line in &response.log {
+        eprintln!("{peer} {line}");
+    }
+    write_response(&mut writer, &response).await
+}
+
+/// Reads the request line, the headers and the body, which may be chunked and compressed.
+/// A client that waits for `100 Continue` before sending the body is told to go on. None if the connection ends first
+async fn read_request(reader: &mut (impl AsyncBufRead + Unpin), writer: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<Option<Request>> {
+    let mut head_len = 0;
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await? == 0 {
+        return Ok(None);
+    }
+    head_len += request_line.len();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(_)) = (parts.next(), parts.next(), parts.next()) else {
+        bail!("invalid request line: {}", request_line.trim_end());
+    };
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        head_len += reader.read_line(&mut line).await?;
+        if head_len > MAX_HEAD_LEN {
+            bail!("the request headers are too long");
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').context(format!("invalid header: {line}"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+    let mut request = Request { method: method.to_string(), target: target.to_string(), headers, body: vec![] };
+    if request.header("expect").is_some_and(|x| x.eq_ignore_ascii_case("100-continue")) {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        writer.flush().await?;
+    }
+    if request.header("transfer-encoding").is_some_and(|x| x.eq_ignore_ascii_case("chunked")) {
+        request.body = read_chunked_body(reader).await?;
+    } else if let Some(length) = request.header("content-length") {
+        let length: usize = length.parse().context(format!("invalid content length: {length}"))?;
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).await.context("the request body ended early")?;
+    }
+    if request.header("content-encoding").is_some_and(|x| x == "gzip" || x == "x-gzip") {
+        let mut body = vec![];
+        GzDecoder::new(request.body.as_slice()).read_to_end(&mut body).context("the request body is not valid gzip")?;
+        request.body = body;
+    }
+    Ok(Some(request))
+}
+
+async fn read_chunked_body(reader: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).context(format!("invalid chunk size: {size}"))?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await.context("the request body ended early")?;
+        let mut end = [0; 2];
+        reader.read_exact(&mut end).await.context("the request body ended early")?;
+    }
+    // the trailer, which ends with an empty line
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+            return Ok(body);
+        }
+    }
+}
+
+async fn write_response(writer: &mut (impl AsyncWrite + Unpin), response: &Response) -> anyhow::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache, max-age=0, must-revalidate\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&response.body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Routes a request to the smart protocol, the files of the dumb one, or an error
+async fn handle_request(request: &Request, options: &HttpBackendOptions) -> Response {
+    match route(request, options).await {
+        Ok(response) => response,
+        Err(error) => Response::error(500, &format!("{error:#}")),
+    }
+}
+
+async fn route(request: &Request, options: &HttpBackendOptions) -> anyhow::Result<Response> {
+    let path = request.path().trim_start_matches('/');
+    let Some((name, file)) = path.split_once('/') else {
+        return Ok(Response::error(404, "Not Found"));
+    };
+    let Some(git_dir) = options.find(name) else {
+        return Ok(Response::error(404, "Not Found"));
+    };
+    // the service is asked for in the query of the discovery and in the path of the requests after it
+    let service = match if file == "info/refs" { request.query("service") } else { Some(file) } {
+        Some("git-upload-pack") => Some(Service::UploadPack),
+        Some("git-receive-pack") => Some(Service::ReceivePack),
+        _ => None,
+    };
+    match (request.method.as_str(), file, service) {
+        (_, "info/refs" | "git-upload-pack" | "git-receive-pack", Some(Service::ReceivePack)) if options.read_only => {
+            Ok(Response::error(403, "Service not enabled: 'receive-pack'"))
+        },
+        ("GET", "info/refs", Some(service)) => advertise_refs(request, git_dir, service).await,
+        ("POST", "git-upload-pack" | "git-receive-pack", Some(service)) => {
+            let request_type = service.content_type("request");
+            if request.header("content-type") != Some(request_type.as_str()) {
+                return Ok(Response::error(415, &format!("Expected content type '{request_type}'")));
+            }
+            let sideband = requested_sideband(&request.body).await;
+            let output = match run_service(service, git_dir, request, false, &request.body).await {
+                Ok(output) => output,
+                // the client shows the error to its user, where it would only report the status of the response
+                Err(error) => error_report(&format!("{error:#}"), service, sideband).await?,
+            };
+            let log = match service {
+                Service::ReceivePack => refused_updates(&output, sideband.is_some()).await,
+                Service::UploadPack => vec![],
+            };
+            Ok(Response { log, ..Response::new(200, &service.content_type("result"), output) })
+        },
+        (_, "git-upload-pack" | "git-receive-pack", _) | (_, "info/refs", Some(_)) => Ok(Response::error(405, "Method Not Allowed")),
+        ("GET", _, _) if options.dumb => serve_dumb_file(git_dir, file).await,
+        ("GET", _, _) if is_dumb_file(file) => Ok(Response::error(403, "Request not supported")),
+        _ => Ok(Response::error(404, "Not Found")),
+    }
+}
+
+/// The ref advertisement of a service, the versions before v2 start it with the service line
+async fn advertise_refs(request: &Request, git_dir: &Path, service: Service) -> anyhow::Result<Response> {
+    let output = run_service(service, git_dir, request, true, &[]).await?;
+    let version = ProtocolVersion::requested(request.header("git-protocol").unwrap_or_default());
+    let mut writer = AsyncPacketWriter::new(vec![]);
+    if service == Service::ReceivePack || version != ProtocolVersion::V2 {
+        writer.write_line(&format!("# service={}", service.name())).await?;
+        writer.write_flush().await?;
+    }
+    let mut body = writer.into_inner();
+    body.extend(output);
+    Ok(Response::new(200, &service.content_type("advertisement"), body))
+}
+
+/// Runs upload-pack or receive-pack of this program on the repository for a single request,
+/// the version the client asked for is passed on in GIT_PROTOCOL
+async fn run_service(service: Service, git_dir: &Path, request: &Request, advertise_refs: bool, input: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let program = env::current_exe().context("Failed to find the server program")?;
+    let name = service.name().trim_start_matches("git-");
+    let mut command = Command::new(program);
+    command.arg(name).arg("--stateless-rpc");
+    if advertise_refs {
+        command.arg("--advertise-refs");
+    }
+    command.arg(git_dir).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::inherit()).env_remove("GIT_PROTOCOL");
+    if let Some(protocol) = request.header("git-protocol") {
+        command.env("GIT_PROTOCOL", protocol);
+    }
+    let mut child = command.spawn().context(format!("cannot run {name}"))?;
+    let mut stdin = child.stdin.take().context(format!("cannot run {name}"))?;
+    let input = input.to_vec();
+    // the service may answer before it read everything, so the input is written alongside
+    let feeder = tokio::spawn(async move {
+        let _ = stdin.write_all(&input).await;
+    });
+    let output = child.wait_with_output().await.context(format!("{name} failed"))?;
+    let _ = feeder.await;
+    if !output.status.success() && output.stdout.is_empty() {
+        bail!("{name} failed");
+    }
+    Ok(output.stdout)
+}
+
+/// The longest packet of the sideband a push asks for among the capabilities of its first command, None without one
+async fn requested_sideband(body: &[u8]) -> Option<usize> {
+    let (lines, _) = AsyncPacketReader::new(body).read_until_special().await.ok()?;
+    let line = text_line(lines.first()?);
+    let (_, capabilities) = line.split_once('\0')?;
+    let capabilities = capabilities.split(' ').collect::<Vec<_>>();
+    match () {
+        _ if capabilities.contains(&"side-band-64k") => Some(MAX_PACKET_LEN),
+        _ if capabilities.contains(&"side-band") => Some(SMALL_SIDEBAND_PACKET_LEN),
+        _ => None,
+    }
+}
+
+/// The response that reports an error to the client: on the error band of the sideband of a push,
+/// or in an ERR packet, which fetches and pushes without one die with
+async fn error_report(message: &str, service: Service, sideband: Option<usize>) -> anyhow::Result<Vec<u8>> {
+    let mut writer = AsyncPacketWriter::new(vec![]);
+    match (service, sideband) {
+        (Service::ReceivePack, Some(max_packet_len)) => {
+            writer.write_band(Band::Error, format!("{message}\n").as_bytes(), max_packet_len).await?;
+            writer.write_flush().await?;
+        },
+        _ => writer.write_line(&format!("ERR {message}")).await?,
+    }
+    Ok(writer.into_inner())
+}
+
+/// The lines of the report of receive-pack about the updates it refused, and the error it stopped with
+async fn refused_updates(output: &[u8], sideband: bool) -> Vec<String> {
+    let report = match sideband {
+        true => match AsyncPacketReader::new(output).read_sideband_to_end(&mut io::sink()).await {
+            Ok(report) => report,
+            Err(error) => return vec![format!("{error:#}")],
+        },
+        false => output.to_vec(),
+    };
+    // without report-status there is no report
+    let Ok((lines, _)) = AsyncPacketReader::new(report.as_slice()).read_until_special().await else {
+        return vec![];
+    };
+    lines.iter()
+        .map(|x| text_line(x))
+        .filter(|x| x.starts_with("ng ") || x.starts_with("unpack ") && x != "unpack ok")
+        .collect()
+}
+
+/// The files the dumb protocol reads: the refs, HEAD, and the object files with the lists of packs and alternates
+fn is_dumb_file(file: &str) -> bool {
+    let is_hex = |x: &str, len: usize| x.len() == len && x.bytes().all(|x| x.is_ascii_hexdigit());
+    let loose_object = file.strip_prefix("objects/").and_then(|x| x.split_once('/')).is_some_and(|(dir, name)| is_hex(dir, 2) && is_hex(name, 38));
+    let pack_file = file.strip_prefix("objects/pack/pack-")
+        .and_then(|x| x.strip_suffix(".pack").or(x.strip_suffix(".idx")))
+        .is_some_and(|x| is_hex(x, 40));
+    loose_object || pack_file || matches!(file, "HEAD" | "info/refs" | "objects/info/packs" | "objects/info/alternates" | "objects/info/http-alternates")
+}
+
+/// A file of the dumb protocol. The refs and the list of packs are made from the repository
+/// as it is, so that the repository never needs update-server-info
+async fn serve_dumb_file(git_dir: &Path, file: &str) -> anyhow::Result<Response> {
+    if !is_dumb_file(file) {
+        return Ok(Response::error(404, "Not Found"));
+    }
+    match file {
+        "info/refs" => {
+            let request = Request { method: "GET".to_string(), target: String::new(), headers: vec![], body: vec![] };
+            let advertisement = run_service(Service::UploadPack, git_dir, &request, true, &[]).await?;
+            Ok(Response::new(200, "text/plain", dumb_refs(&advertisement)?.into_bytes()))
+        },
+        "objects/info/packs" => {
+            let mut packs = vec![];
+            if let Ok(entries) = fs::read_dir(git_dir.join("objects/pack")) {
+                for entry in entries {
+                    let name = entry?.file_name().to_string_lossy().to_string();
+                    if name.ends_with(".pack") {
+                        packs.push(name);
+                    }
+                }
+            }
+            packs.sort();
+            let list: String = packs.iter().map(|x| format!("P {x}\n")).chain(["\n".to_string()]).collect();
+            Ok(Response::new(200, "text/plain; charset=utf-8", list.into_bytes()))
+        },
+        _ => {
+            let content_type = match file {
+                _ if file.ends_with(".pack") => "application/x-git-packed-objects",
+                _ if file.ends_with(".idx") => "application/x-git-packed-objects-toc",
+                _ if file.starts_with("objects/") && !file.starts_with("objects/info/") => "application/x-git-loose-object",
+                _ => "text/plain",
+            };
+            match tokio::fs::read(git_dir.join(file)).await {
+                Ok(data) => Ok(Response::new(200, content_type, data)),
+                Err(_) => Ok(Response::error(404, "Not Found")),
+            }
+        },
+    }
+}
+
+/// The `info/refs` file of the dumb protocol made from a v0 ref advertisement: a line with the hash and the name
+/// of each ref, followed by its peeled value for an annotated tag, without HEAD
+fn dumb_refs(advertisement: &[u8]) -> anyhow::Result<String> {
+    let mut reader = PacketReader::new(advertisement);
+    let mut refs = String::new();
+    while let Some(Packet::Data(data)) = reader.read_packet()? {
+        let line = text_line(&data);
+        let line = line.split_once('\0').map_or(line.as_str(), |(line, _)| line);
+        let (hash, name) = line.split_once(' ').context(format!("invalid ref line: {line}"))?;
+        if name != "HEAD" && name != CAPABILITIES_REF {
+            refs.push_str(&format!("{hash}\t{name}\n"));
+        }
+    }
+    Ok(refs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use super::*;
+    use crate::pkt_line::PacketWriter;
+
+    #[test]
+    fn test_read_request() -> anyhow::Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(b"0009done\n0000")?;
+            let compressed = encoder.finish()?;
+            let (first, second) = compressed.split_at(5);
+            let mut data = b"POST /repo.git/git-upload-pack HTTP/1.1\r\nContent-Encoding: gzip\r\nTransfer-Encoding: chunked\r\nExpect: 100-continue\r\n\r\n".to_vec();
+            data.extend(format!("{:x}\r\n", first.len()).as_bytes());
+            data.extend(first);
+            data.extend(format!("\r\n{:x};name=value\r\n", second.len()).as_bytes());
+            data.extend(second);
+            data.extend(b"\r\n0\r\n\r\n");
+
+            let mut written = vec![];
+            let request = read_request(&mut data.as_slice(), &mut written).await?.unwrap();
+            assert_eq!(b"HTTP/1.1 100 Continue\r\n\r\n".to_vec(), written);
+            assert_eq!("/repo.git/git-upload-pack", request.path());
+            assert_eq!(Some("chunked"), request.header("transfer-encoding"));
+            assert_eq!(b"0009done\n0000".to_vec(), request.body);
+
+            let data = b"GET /repo.git/info/refs?service=git-upload-pack HTTP/1.1\r\nGit-Protocol: version=2\r\n\r\n";
+            let request = read_request(&mut data.as_slice(), &mut vec![]).await?.unwrap();
+            assert_eq!((Some("git-upload-pack"), Some("version=2")), (request.query("service"), request.header("git-protocol")));
+            assert_eq!(None, read_request(&mut b"".as_slice(), &mut vec![]).await?);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_dumb_refs() -> anyhow::Result<()> {
+        let (commit, tag) = ("a".repeat(40), "b".repeat(40));
+        let mut writer = PacketWriter::new(vec![]);
+        writer.write_line(&format!("{commit} HEAD\0multi_ack symref=HEAD:refs/heads/main"))?;
+        writer.write_line(&format!("{commit} refs/heads/main"))?;
+        writer.write_line(&format!("{tag} refs/tags/v1"))?;
+        writer.write_line(&format!("{commit} refs/tags/v1^{{}}"))?;
+        writer.write_flush()?;
+        let expected = format!("{commit}\trefs/heads/main\n{tag}\trefs/tags/v1\n{commit}\trefs/tags/v1^{{}}\n");
+        assert_eq!(expected, dumb_refs(&writer.into_inner())?);
+
+        assert!(is_dumb_file("objects/pack/pack-0123456789abcdef0123456789abcdef01234567.idx"));
+        assert!(is_dumb_file(&format!("objects/ab/{}", "c".repeat(38))));
+        assert!(!is_dumb_file("objects/../config") && !is_dumb_file("config"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_report() -> anyhow::Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let (old, new) = ("0".repeat(40), "a".repeat(40));
+            let mut writer = PacketWriter::new(vec![]);
+            writer.write_line(&format!("{old} {new} refs/heads/main\0report-status side-band-64k"))?;
+            writer.write_flush()?;
+            assert_eq!(Some(MAX_PACKET_LEN), requested_sideband(&writer.into_inner()).await);
+            assert_eq!(None, requested_sideband(format!("0032want {new}\n0000").as_bytes()).await);
+            assert_eq!(None, requested_sideband(b"").await);
+
+            let mut report = PacketWriter::new(vec![]);
+            report.write_line("unpack ok")?;
+            report.write_line("ok refs/heads/main")?;
+            report.write_line("ng refs/heads/next non-fast-forward")?;
+            report.write_flush()?;
+            let report = report.into_inner();
+            let mut writer = PacketWriter::new(vec![]);
+            writer.write_band(Band::Progress, b"hook says hi\n", MAX_PACKET_LEN)?;
+            writer.write_band(Band::Data, &report, MAX_PACKET_LEN)?;
+            writer.write_flush()?;
+            assert_eq!(vec!["ng refs/heads/next non-fast-forward"], refused_updates(&writer.into_inner(), true).await);
+            assert_eq!(vec!["ng refs/heads/next non-fast-forward"], refused_updates(&report, false).await);
+            assert!(refused_updates(b"", false).await.is_empty());
+
+            let error = error_report("receive-pack failed", Service::ReceivePack, Some(MAX_PACKET_LEN)).await?;
+            assert_eq!(vec!["remote error: receive-pack failed"], refused_updates(&error, true).await);
+            let error = error_report("upload-pack failed", Service::UploadPack, None).await?;
+            assert_eq!(b"001bERR upload-pack failed\n".to_vec(), error);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,311 @@
+use std::{env, fs};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context};
+use chrono::{Local, Offset, TimeZone};
+use crate::commit_object_read::{MONTHS, Signature, WEEKDAYS};
+use crate::config::Config;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+/// numbers above this are taken as seconds since the epoch rather than parts of a date
+const MIN_RAW_TIMESTAMP: i64 = 100_000_000;
+
+/// The two identities recorded in a commit
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Role {
+    Author,
+    Committer,
+}
+impl Role {
+    fn env_prefix(self) -> &'static str {
+        match self {
+            Self::Author => "GIT_AUTHOR",
+            Self::Committer => "GIT_COMMITTER",
+        }
+    }
+    fn config_section(self) -> &'static str {
+        match self {
+            Self::Author => "author",
+            Self::Committer => "committer",
+        }
+    }
+    fn title(self) -> &'static str {
+        match self {
+            Self::Author => "Author",
+            Self::Committer => "Committer",
+        }
+    }
+}
+
+/// The identity from the environment or the config, dated now unless the environment gives a date
+pub(crate) fn read_ident(role: Role, config: &Config) -> anyhow::Result<Signature> {
+    let prefix = role.env_prefix();
+    let section = role.config_section();
+    let name = env::var(format!("{prefix}_NAME")).ok()
+        .or_else(|| config.get(&format!("{section}.name")).map(str::to_string))
+        .or_else(|| config.get("user.name").map(str::to_string));
+    let email = env::var(format!("{prefix}_EMAIL")).ok()
+        .or_else(|| config.get(&format!("{section}.email")).map(str::to_string))
+        .or_else(|| config.get("user.email").map(str::to_string))
+        .or_else(|| env::var("EMAIL").ok());
+    let (Some(name), Some(email)) = (name, email) else {
+        bail!("{} identity unknown
+
+*** Please tell me who you are.
+
+Run
+
+  git config --global user.email \"you@example.com\"
+  git config --global user.name \"Your Name\"
+
+to set your account's default identity.
+Omit --global to set the identity only in this repository.
+", role.title());
+    };
+    let name = name.trim();
+    let email = email.trim();
+    if name.is_empty() {
+        bail!("empty ident name (for <{email}>) not allowed");
+    }
+    let (timestamp, timezone) = match env::var(format!("{prefix}_DATE")) {
+        Ok(date) => parse_date(&date)?,
+        Err(_) => now()?,
+    };
+    Ok(Signature { name: name.to_string(), email: email.to_string(), timestamp, timezone })
+}
+
+/// Like read_ident, but falls back to the user name and the host name, for records like reflogs that do not need a real identity
+pub(crate) fn read_ident_or_default(role: Role, config: &Config) -> anyhow::Result<Signature> {
+    if let Ok(ident) = read_ident(role, config) {
+        return Ok(ident);
+    }
+    let user = env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    let host = fs::read_to_string("/etc/hostname").map_or("localhost".to_string(), |x| x.trim().to_string());
+    let (timestamp, timezone) = now()?;
+    Ok(Signature { name: user.clone(), email: format!("{user}@{host}"), timestamp, timezone })
+}
+
+/// Parses an identity given as "Name <email>", the date is left for the caller
+pub(crate) fn parse_ident(value: &str, timestamp: i64, timezone: &str) -> anyhow::Result<Signature> {
+    let Some((name, rest)) = value.split_once('<') else {
+        bail!("--author '{value}' is not 'Name <email>'");
+    };
+    let Some(email) = rest.strip_suffix('>') else {
+        bail!("--author '{value}' is not 'Name <email>'");
+    };
+    let res = Signature {
+        name: name.trim().to_string(),
+        email: email.trim().to_string(),
+        timestamp,
+        timezone: timezone.to_string(),
+    };
+    Ok(res)
+}
+
+/// The current time and the local timezone
+pub(crate) fn now() -> anyhow::Result<(i64, String)> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+        .context("Failed to get current timestamp")?
+        .as_secs() as i64;
+    Ok((timestamp, format_timezone(local_offset(timestamp))))
+}
+
+/// Parses a date in one of the formats git accepts for GIT_AUTHOR_DATE and --date:
+/// raw ("1700000000 +0100" or "@1700000000"), ISO 8601 ("2023-11-14 10:00:05+02:00"),
+/// RFC 2822 ("Tue, 14 Nov 2023 22:13:20 -0500") and the default git format ("Tue Nov 14 22:13:20 2023 +0000").
+/// Dates without a timezone are in the local time
+pub(crate) fn parse_date(value: &str) -> anyhow::Result<(i64, String)> {
+    parse_date_parts(value).context(format!("invalid date format: {value}"))
+}
+
+#[derive(Default)]
+struct DateParts {
+    raw: Option<i64>,
+    year: Option<i64>,
+    month: Option<i64>,
+    day: Option<i64>,
+    time: Option<(i64, i64, i64)>,
+    offset: Option<i64>,
+}
+
+fn parse_date_parts(value: &str) -> anyhow::Result<(i64, String)> {
+    let mut parts = DateParts::default();
+    for token in value.split(|x: char| x.is_whitespace() || x == ',').filter(|x| !x.is_empty()) {
+        parse_date_token(token, &mut parts)?;
+    }
+
+    if let Some(timestamp) = parts.raw {
+        return Ok((timestamp, format_timezone(parts.offset.unwrap_or(0))));
+    }
+    let (Some(year), Some(month), Some(day), Some((hour, minute, second))) = (parts.year, parts.month, parts.day, parts.time) else {
+        bail!("incomplete date");
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        bail!("date out of range");
+    }
+    let local_time = days_from_civil(year, month, day) * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second;
+    let offset = match parts.offset {
+        Some(offset) => offset,
+        // the offset of the local time is close enough to the offset of the result, except around a dst switch
+        None => local_offset(local_time - local_offset(local_time)),
+    };
+    Ok((local_time - offset, format_timezone(offset)))
+}
+
+fn parse_date_token(token: &str, parts: &mut DateParts) -> anyhow::Result<()> {
+    if let Some(digits) = token.strip_prefix('@') {
+        parts.raw = Some(parse_number(digits)?);
+        return Ok(());
+    }
+    if let Some(offset) = parse_offset(token) {
+        parts.offset = Some(offset);
+        return Ok(());
+    }
+    if let Some((date, time)) = token.split_once('T').filter(|(x, _)| x.contains('-')) {
+        parse_date_token(date, parts)?;
+        return parse_date_token(time, parts);
+    }
+    if token.contains(':') {
+        // the time can be followed by a timezone without a space
+        let zone_start = token.find(['Z', '+', '-']).unwrap_or(token.len());
+        let (time, zone) = token.split_at(zone_start);
+        let mut fields = time.split(':');
+        let hour = parse_number(fields.next().unwrap_or(""))?;
+        let minute = parse_number(fields.next().unwrap_or(""))?;
+        let second = match fields.next() {
+            // fractions of a second are dropped
+            Some(second) => parse_number(second.split('.').next().unwrap())?,
+            None => 0,
+        };
+        if fields.next().is_some() {
+            bail!("invalid time {token}");
+        }
+        parts.time = Some((hour, minute, second));
+        if !zone.is_empty() {
+            let Some(offset) = parse_offset(zone) else {
+                bail!("invalid timezone {zone}");
+            };
+            parts.offset = Some(offset);
+        }
+        return Ok(());
+    }
+    let fields = token.split(['-', '.', '/']).collect::<Vec<_>>();
+    if fields.len() == 3 {
+        let numbers = fields.iter().map(|x| parse_number(x)).collect::<anyhow::Result<Vec<_>>>()?;
+        let (year, month, day) = if fields[0].len() == 4 {
+            (numbers[0], numbers[1], numbers[2])
+        } else if token.contains('/') {
+            (numbers[2], numbers[0], numbers[1])
+        } else {
+            (numbers[2], numbers[1], numbers[0])
+        };
+        parts.year = Some(year);
+        parts.month = Some(month);
+        parts.day = Some(day);
+        return Ok(());
+    }
+    if token.bytes().all(|x| x.is_ascii_digit()) {
+        let number = parse_number(token)?;
+        if number > MIN_RAW_TIMESTAMP {
+            parts.raw = Some(number);
+        } else if token.len() == 4 {
+            parts.year = Some(number);
+        } else {
+            parts.day = Some(number);
+        }
+        return Ok(());
+    }
+    let prefix = token.get(..3).unwrap_or(token);
+    if let Some(month) = MONTHS.iter().position(|x| x.eq_ignore_ascii_case(prefix)) {
+        parts.month = Some(month as i64 + 1);
+        return Ok(());
+    }
+    if WEEKDAYS.iter().any(|x| x.eq_ignore_ascii_case(prefix)) {
+        return Ok(());
+    }
+    bail!("unknown date part {token}");
+}
+
+/// Offset in seconds of a timezone like "+0130", "+01:30", "+01", "Z" or "UTC"
+fn parse_offset(value: &str) -> Option<i64> {
+    if ["Z", "UTC", "GMT"].contains(&value) {
+        return Some(0);
+    }
+    let (sign, digits) = match value.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    let digits = digits.replacen(':', "", 1);
+    if !digits.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i64>().ok()?, 0),
+        4 => (digits[..2].parse::<i64>().ok()?, digits[2..].parse::<i64>().ok()?),
+        _ => return None,
+    };
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+fn parse_number(value: &str) -> anyhow::Result<i64> {
+    if value.is_empty() || !value.bytes().all(|x| x.is_ascii_digit()) {
+        bail!("invalid number {value}");
+    }
+    value.parse::<i64>().context(format!("invalid number {value}"))
+}
+
+/// Formats an offset in seconds like "+0130"
+fn format_timezone(offset: i64) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let minutes = offset.abs() / 60;
+    format!("{sign}{:02}{:02}", minutes / 60, minutes % 60)
+}
+
+/// Offset of the local timezone from UTC in seconds at the given time, UTC if it is unknown
+fn local_offset(timestamp: i64) -> i64 {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.offset().fix().local_minus_utc() as i64,
+        None => 0,
+    }
+}
+
+/// Converts a date to days since the epoch, the inverse of civil_from_days
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // years counted from March, so that the leap day is at the end
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() -> anyhow::Result<()> {
+        let date = |value: &str| parse_date(value).map(|(timestamp, timezone)| format!("{timestamp} {timezone}"));
+        assert_eq!("1700000000 +0000", date("@1700000000")?);
+        assert_eq!("1700000000 +0130", date("1700000000 +0130")?);
+        assert_eq!("1699956005 +0000", date("2023-11-14T10:00:05Z")?);
+        assert_eq!("1699948805 +0200", date("2023-11-14 10:00:05+02:00")?);
+        assert_eq!("1700018000 -0500", date("Tue, 14 Nov 2023 22:13:20 -0500")?);
+        assert_eq!("1700000000 +0000", date("Tue Nov 14 22:13:20 2023 +0000")?);
+        assert_eq!("951782400 +0000", date("2000-02-29 00:00 +0000")?);
+        assert!(date("2023-11-14").is_err());
+        assert!(date("garbage").is_err());
+        assert!(date("2023-13-14 10:00").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ident() -> anyhow::Result<()> {
+        let ident = parse_ident("Some Name <name@example.com>", 1700000000, "+0100")?;
+        assert_eq!("Some Name <name@example.com> 1700000000 +0100", ident.to_string());
+        assert!(parse_ident("name@example.com", 0, "+0000").is_err());
+        Ok(())
+    }
+}
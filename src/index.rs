@@ -0,0 +1,381 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+use crate::common::{HASH_RAW_LEN, ObjectMode, ObjectType};
+use crate::lock_file::LockFile;
+use crate::object_write::hash_object;
+use crate::tree_diff::read_worktree_file;
+use crate::tree_object_write::mode_from_metadata;
+
+pub(crate) const INDEX_PATH: &str = ".git/index";
+
+const INDEX_SIGNATURE: &[u8] = b"DIRC";
+const INDEX_HEADER_LEN: usize = 12;
+/// stat data, hash and flags of an entry before the path
+const ENTRY_FIXED_LEN: usize = 62;
+const EXTENDED_FLAGS_LEN: usize = 2;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_STAGE_SHIFT: u16 = 12;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+/// the version that is written, it has no extended flags and no path compression
+const WRITE_VERSION: u32 = 2;
+
+/// One file in the index (staging area), with the stat data used to detect changes in the working tree
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct IndexEntry {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: ObjectMode,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub hash: String,
+    /// 0 for normal entries, 1-3 for the base, ours and theirs versions of a conflicted file
+    pub stage: u8,
+    /// slash separated path relative to the repository root
+    pub path: String,
+}
+impl IndexEntry {
+    /// An entry for a file in the working tree with the stat data from its metadata
+    pub fn new(path: &str, mode: ObjectMode, hash: &str, meta: &Metadata) -> Self {
+        Self {
+            ctime: (meta.ctime() as u32, meta.ctime_nsec() as u32),
+            mtime: (meta.mtime() as u32, meta.mtime_nsec() as u32),
+            dev: meta.dev() as u32,
+            ino: meta.ino() as u32,
+            mode,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: meta.size() as u32,
+            hash: hash.to_string(),
+            stage: 0,
+            path: path.to_string(),
+        }
+    }
+
+    /// An entry whose file was not written to the working tree, the empty stat data never matches a file
+    pub fn without_stat(path: &str, mode: ObjectMode, hash: &str) -> Self {
+        Self {
+            ctime: (0, 0),
+            mtime: (0, 0),
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            hash: hash.to_string(),
+            stage: 0,
+            path: path.to_string(),
+        }
+    }
+
+    /// Whether the file on disk looks unchanged since the entry was written, without reading its contents
+    pub fn matches_stat(&self, meta: &Metadata) -> bool {
+        self.mtime == (meta.mtime() as u32, meta.mtime_nsec() as u32)
+            && self.ctime == (meta.ctime() as u32, meta.ctime_nsec() as u32)
+            && self.ino == meta.ino() as u32
+            && self.uid == meta.uid()
+            && self.gid == meta.gid()
+            && self.size == meta.size() as u32
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Index {
+    pub version: u32,
+    /// sorted by path and then by stage
+    pub entries: Vec<IndexEntry>,
+    /// modification time of the index file, entries modified at the same time or later need a content check
+    pub timestamp: Option<(u32, u32)>,
+}
+impl Index {
+    /// Reads the index of the repository, the index is empty if the file does not exist yet
+    pub fn read() -> anyhow::Result<Self> {
+        let path = Path::new(INDEX_PATH);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = fs::read(path).context(format!("Failed to read {INDEX_PATH}"))?;
+        let mut index = Self::parse(&data).context(format!("Failed to parse {INDEX_PATH}"))?;
+        let meta = path.metadata().context(format!("Failed to read metadata for {INDEX_PATH}"))?;
+        index.timestamp = Some((meta.mtime() as u32, meta.mtime_nsec() as u32));
+        Ok(index)
+    }
+
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < INDEX_HEADER_LEN + HASH_RAW_LEN {
+            bail!("Index is too short: {} bytes", data.len());
+        }
+        let (content, checksum) = data.split_at(data.len() - HASH_RAW_LEN);
+        if Sha1::digest(content).as_slice() != checksum {
+            bail!("Index checksum does not match");
+        }
+        if &content[..4] != INDEX_SIGNATURE {
+            bail!("Invalid index signature");
+        }
+        let version = read_u32(content, 4)?;
+        if !(2..=4).contains(&version) {
+            bail!("Unsupported index version {version}");
+        }
+        let count = read_u32(content, 8)? as usize;
+
+        let mut entries = Vec::with_capacity(count);
+        let mut offset = INDEX_HEADER_LEN;
+        let mut previous_path = String::new();
+        for entry_no in 0..count {
+            let (entry, next_offset) = parse_entry(content, offset, version, &previous_path)
+                .context(format!("Failed to parse index entry {entry_no}"))?;
+            previous_path.clone_from(&entry.path);
+            entries.push(entry);
+            offset = next_offset;
+        }
+
+        while offset < content.len() {
+            let signature = content.get(offset..offset + 4).context("Truncated index extension")?;
+            let size = read_u32(content, offset + 4)? as usize;
+            // extensions starting with an uppercase letter are optional caches that can be dropped
+            if !signature[0].is_ascii_uppercase() {
+                bail!("Unsupported index extension {}", String::from_utf8_lossy(signature));
+            }
+            offset += 8 + size;
+        }
+        if offset != content.len() {
+            bail!("Truncated index extension");
+        }
+
+        Ok(Self { version, entries, timestamp: None })
+    }
+
+    /// Writes the index in version 2 under a lock, optional extensions like the cached trees are not kept
+    pub fn write(&self) -> anyhow::Result<()> {
+        let mut lock = LockFile::acquire(INDEX_PATH)?;
+        self.write_to(&mut lock)?;
+        lock.commit()
+    }
+
+    /// Writes the index into a lock of it, the index is only replaced when the lock is committed
+    pub fn write_to(&self, lock: &mut LockFile) -> anyhow::Result<()> {
+        lock.write_all(&self.serialize()?)
+    }
+
+    fn serialize(&self) -> anyhow::Result<Vec<u8>> {
+        let mut data = INDEX_SIGNATURE.to_vec();
+        data.extend_from_slice(&WRITE_VERSION.to_be_bytes());
+        data.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            let start = data.len();
+            let raw_mode = u32::from_str_radix(&entry.mode.to_string(), 8).unwrap();
+            let fields = [
+                entry.ctime.0, entry.ctime.1, entry.mtime.0, entry.mtime.1, entry.dev, entry.ino,
+                raw_mode, entry.uid, entry.gid, entry.size,
+            ];
+            for field in fields {
+                data.extend_from_slice(&field.to_be_bytes());
+            }
+            data.extend(hex::decode(&entry.hash).context(format!("Invalid hash {} of {}", entry.hash, entry.path))?);
+            let name_len = entry.path.len().min(FLAG_NAME_MASK as usize) as u16;
+            let flags = (entry.stage as u16) << FLAG_STAGE_SHIFT | name_len;
+            data.extend_from_slice(&flags.to_be_bytes());
+            data.extend_from_slice(entry.path.as_bytes());
+            // entries are padded with 1-8 nul bytes to a multiple of 8
+            let entry_len = data.len() - start;
+            data.resize(start + (entry_len + 8) / 8 * 8, 0);
+        }
+        let checksum = Sha1::digest(&data);
+        data.extend_from_slice(&checksum);
+        Ok(data)
+    }
+
+    /// Stores the file from the working tree as a blob and puts it into the index, replacing any conflict stages
+    pub fn add_file(&mut self, path: &str) -> anyhow::Result<()> {
+        let file_path = Path::new(path);
+        let meta = fs::symlink_metadata(file_path).context(format!("Failed to read metadata for {path}"))?;
+        let Some(mode) = mode_from_metadata(&meta).filter(|x| *x != ObjectMode::Tree) else {
+            bail!("{path} is not a file");
+        };
+        let data = read_worktree_file(file_path, &meta)?;
+        let hash = hash_object(data.as_slice(), ObjectType::Blob, data.len() as u64, true)?;
+        let entry = IndexEntry::new(path, mode, &hash, &meta);
+        self.remove(path);
+        let position = self.entries.partition_point(|x| x.path.as_str() < path);
+        self.entries.insert(position, entry);
+        Ok(())
+    }
+
+    /// Removes all stages of the path
+    pub fn remove(&mut self, path: &str) {
+        self.entries.retain(|x| x.path != path);
+    }
+
+    /// Replaces all stages of the paths with the new entries, paths without a new entry are removed
+    pub fn replace_entries(&mut self, paths: &HashSet<String>, entries: Vec<IndexEntry>) {
+        self.entries.retain(|x| !paths.contains(&x.path));
+        self.entries.extend(entries);
+        self.entries.sort_by(|left, right| left.path.cmp(&right.path).then(left.stage.cmp(&right.stage)));
+    }
+
+    /// The entry would be reported as changed by the stat check even if it is not, because it was modified
+    /// in the same second the index was written
+    pub fn is_racy(&self, entry: &IndexEntry) -> bool {
+        self.timestamp.is_some_and(|timestamp| entry.mtime >= timestamp)
+    }
+}
+
+fn parse_entry(data: &[u8], start: usize, version: u32, previous_path: &str) -> anyhow::Result<(IndexEntry, usize)> {
+    let fields = data.get(start..start + ENTRY_FIXED_LEN).context("Truncated entry")?;
+    let field = |index: usize| u32::from_be_bytes(fields[index * 4..index * 4 + 4].try_into().unwrap());
+    let raw_mode = field(6);
+    let mode = format!("{raw_mode:o}").parse::<usize>()?;
+    let mode = ObjectMode::try_from(mode).context(format!("Invalid mode {raw_mode:o}"))?;
+    let hash = hex::encode(&fields[40..40 + HASH_RAW_LEN]);
+    let flags = u16::from_be_bytes([fields[60], fields[61]]);
+    let mut offset = start + ENTRY_FIXED_LEN;
+    if flags & FLAG_EXTENDED != 0 {
+        if version < 3 {
+            bail!("Extended flags in index version {version}");
+        }
+        offset += EXTENDED_FLAGS_LEN;
+    }
+
+    let path = if version == 4 {
+        // the path is stored as the number of bytes to remove from the previous path and a suffix to append
+        let (strip_len, varint_len) = read_offset_varint(data, offset)?;
+        offset += varint_len;
+        let suffix_len = data[offset..].iter().position(|x| *x == 0).context("Unterminated path")?;
+        let Some(kept_len) = previous_path.len().checked_sub(strip_len) else {
+            bail!("Invalid path prefix length {strip_len}");
+        };
+        let mut path = previous_path.as_bytes()[..kept_len].to_vec();
+        path.extend_from_slice(&data[offset..offset + suffix_len]);
+        offset += suffix_len + 1;
+        path
+    } else {
+        let mut name_len = (flags & FLAG_NAME_MASK) as usize;
+        if name_len == FLAG_NAME_MASK as usize {
+            // the length does not fit into the flags
+            name_len = data[offset..].iter().position(|x| *x == 0).context("Unterminated path")?;
+        }
+        let path = data.get(offset..offset + name_len).context("Truncated path")?.to_vec();
+        // entries are padded with 1-8 nul bytes to a multiple of 8
+        let entry_len = offset + name_len - start;
+        offset = start + (entry_len + 8) / 8 * 8;
+        path
+    };
+    let path = String::from_utf8(path).context("Path is not valid utf-8")?;
+
+    let entry = IndexEntry {
+        ctime: (field(0), field(1)),
+        mtime: (field(2), field(3)),
+        dev: field(4),
+        ino: field(5),
+        mode,
+        uid: field(7),
+        gid: field(8),
+        size: field(9),
+        hash,
+        stage: ((flags & FLAG_STAGE_MASK) >> FLAG_STAGE_SHIFT) as u8,
+        path,
+    };
+    Ok((entry, offset))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    let bytes = data.get(offset..offset + 4).context("Unexpected end of index")?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Variable length integer where each continuation adds one, the same encoding as offsets in packfiles
+fn read_offset_varint(data: &[u8], offset: usize) -> anyhow::Result<(usize, usize)> {
+    let mut len = 0;
+    let mut byte = *data.get(offset).context("Unexpected end of index")?;
+    let mut value = (byte & 0x7f) as usize;
+    while byte & 0x80 != 0 {
+        len += 1;
+        byte = *data.get(offset + len).context("Unexpected end of index")?;
+        value = ((value + 1) << 7) | (byte & 0x7f) as usize;
+    }
+    Ok((value, len + 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode_entry(path: &str, mode: u32, hash: &str, stage: u16) -> Vec<u8> {
+        let mut data = vec![];
+        for field in [1, 2, 3, 4, 5, 6, mode, 7, 8, 9] {
+            data.extend_from_slice(&u32::to_be_bytes(field));
+        }
+        data.extend(hex::decode(hash).unwrap());
+        data.extend_from_slice(&u16::to_be_bytes(stage << FLAG_STAGE_SHIFT | path.len() as u16));
+        data.extend_from_slice(path.as_bytes());
+        let padding = 8 - data.len() % 8;
+        data.extend(vec![0; padding]);
+        data
+    }
+
+    #[test]
+    fn test_parse_index() -> anyhow::Result<()> {
+        let hash = "bae42c55f9e0a4e297a4d197d8aadfe147ef269b";
+        let mut data = b"DIRC".to_vec();
+        data.extend_from_slice(&u32::to_be_bytes(2));
+        data.extend_from_slice(&u32::to_be_bytes(2));
+        data.extend(encode_entry("data/data.txt", 0o100644, hash, 0));
+        data.extend(encode_entry("run.sh", 0o100755, hash, 2));
+        let entries_end = data.len();
+        // an optional cache extension, it is skipped
+        data.extend_from_slice(b"TREE");
+        data.extend_from_slice(&u32::to_be_bytes(3));
+        data.extend_from_slice(b"abc");
+        let checksum = Sha1::digest(&data);
+        data.extend_from_slice(&checksum);
+
+        let index = Index::parse(&data)?;
+        assert_eq!(2, index.version);
+        assert_eq!(2, index.entries.len());
+        let entry = &index.entries[0];
+        assert_eq!("data/data.txt", entry.path);
+        assert_eq!(ObjectMode::Normal, entry.mode);
+        assert_eq!(hash, entry.hash);
+        assert_eq!((1, 2), entry.ctime);
+        assert_eq!((3, 4), entry.mtime);
+        assert_eq!(9, entry.size);
+        assert_eq!(0, entry.stage);
+        let entry = &index.entries[1];
+        assert_eq!("run.sh", entry.path);
+        assert_eq!(ObjectMode::Executable, entry.mode);
+        assert_eq!(2, entry.stage);
+
+        // the written index is the same without the extension
+        let written = index.serialize()?;
+        assert_eq!(data[..entries_end], written[..written.len() - HASH_RAW_LEN]);
+        assert_eq!(index, Index::parse(&written)?);
+
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(Index::parse(&data).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_entries() {
+        let hash = "bae42c55f9e0a4e297a4d197d8aadfe147ef269b";
+        let entry = |path: &str, stage: u8| IndexEntry { stage, ..IndexEntry::without_stat(path, ObjectMode::Normal, hash) };
+        let mut index = Index {
+            entries: vec![entry("a", 0), entry("b", 1), entry("b", 2), entry("c", 0)],
+            ..Default::default()
+        };
+        let paths = ["b", "c", "d"].iter().map(|x| x.to_string()).collect();
+        index.replace_entries(&paths, vec![entry("d", 0), entry("b", 0)]);
+        let paths = index.entries.iter().map(|x| (x.path.as_str(), x.stage)).collect::<Vec<_>>();
+        assert_eq!(vec![("a", 0), ("b", 0), ("d", 0)], paths);
+    }
+}
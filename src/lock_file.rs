@@ -0,0 +1,53 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+
+const LOCK_SUFFIX: &str = ".lock";
+
+/// Exclusive access to a file for rewriting it, the way git does it: the new contents are written to `<path>.lock`,
+/// which is renamed over the file on commit. The lock is removed if it is dropped without committing.
+pub(crate) struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: Option<File>,
+}
+impl LockFile {
+    pub fn acquire(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(LOCK_SUFFIX);
+        let lock_path = PathBuf::from(lock_path);
+        if let Some(parent) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+        }
+        let file = match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+                bail!("Unable to create '{}': File exists. Another git process seems to be running in this repository", lock_path.display());
+            },
+            Err(error) => return Err(error).context(format!("Unable to create '{}'", lock_path.display())),
+        };
+        Ok(Self { path, lock_path, file: Some(file) })
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let file = self.file.as_mut().unwrap();
+        file.write_all(data).context(format!("Failed to write {}", self.lock_path.display()))
+    }
+
+    /// Replaces the locked file with the written contents
+    pub fn commit(mut self) -> anyhow::Result<()> {
+        self.file.take();
+        fs::rename(&self.lock_path, &self.path)
+            .context(format!("Failed to rename {} to {}", self.lock_path.display(), self.path.display()))
+    }
+}
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
@@ -1,24 +1,114 @@
-use std::io::{BufWriter, stdout, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::{BufWriter, stdin, stdout, Write};
+use std::io;
 use anyhow::{bail, Context};
-use clap::{Parser};
+use clap::ValueEnum;
+use std::fs;
 use std::path::Path;
+use std::process::ExitCode;
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::cli::{CatFlags, Cli, Command};
-use crate::common::{COMMIT_AUTHOR, COMMIT_EMAIL, COMMIT_TIMEZONE, init_repo, ObjectType, TreeItem};
-use crate::object_write::{hash_blob, hash_commit};
+use crate::checkout::{add_conflict_stages, apply_targets, checkout_file, read_tree_prefix, read_trees, ReadTreeOptions, reset_index, restore_paths, RestoreSource, switch_trees, SwitchAction};
+use crate::cli::{CatFlags, Cli, Command, CommitFlags, MergeBaseModeFlags, ResetMode, ServiceFlags, StashCommand};
+use crate::color::{color_from_config, ColorWhen, paint, YELLOW};
+use crate::config::Config;
+use crate::diff::{abbreviate, DiffOptions, is_binary, PatchSide, read_blob, write_patch};
+use crate::common::{CHERRY_PICK_HEAD_PATH, COMMIT_AUTHOR, COMMIT_EDITMSG_PATH, COMMIT_EMAIL, COMMIT_TIMEZONE, Exit, GIT_PATH, HEAD_PATH, init_repo, MERGE_HEAD_PATH, MERGE_MODE_PATH, MERGE_MSG_PATH, ObjectMode, ObjectType, REVERT_HEAD_PATH, SQUASH_MSG_PATH, TreeItem};
+use crate::object_write::{hash_blob, hash_commit, hash_object, write_commit};
 use crate::object_read::{*};
 use crate::tree_object_read::TreeObjectIterator;
-use crate::tree_object_write::hash_tree;
+use crate::tree_object_write::{get_file_mode, hash_tree, mode_from_metadata, write_index_tree};
+use crate::rev_list::{parse_rev_args, rev_list, RevListOptions};
+use crate::refs::{dwim_ref, Head, HEADS_PREFIX, is_valid_ref_name, list_refs, previous_checkout, read_head, read_head_commit, read_ref, read_reflog, reflog_commits, update_head, update_ref, write_head, write_orig_head};
+use crate::rev_parse::{peel, read_object_type, resolve_revision};
+use crate::commit_object_read::{CommitObject, message_subject, read_shallow_commits, Signature};
+use crate::tag_object_read::TagObject;
+use crate::diff_output::{DiffFormat, write_changes};
+use crate::index::{Index, IndexEntry, INDEX_PATH};
+use crate::lock_file::LockFile;
+use crate::pathspec::Pathspec;
+use crate::rename::{detect_renames, RenameDetection, RenameOptions};
+use crate::ident::{parse_date, parse_ident, read_ident, read_ident_or_default, Role};
+use crate::editor::launch_editor;
+use crate::daemon::{DaemonOptions, run_daemon};
+use crate::http_backend::{HttpBackendOptions, run_http_backend};
+use crate::file_merge::{ConflictStyle, FileMergeOptions, MARKER_SIZE, merge_texts, MergeFavor, MergeLabels};
+use crate::merge_base::{CommitGraph, merge_bases};
+use crate::tree_merge::{merge_command, merge_commits, MergeOptions, MergeResult};
+use crate::rebase::{rebase_abort, rebase_command, rebase_continue, rebase_edit_todo, rebase_skip, RebaseState};
+use crate::fetch::fetch_command;
+use crate::clone::clone_command;
+use crate::push::push_command;
+use crate::receive_pack::serve_receive_pack;
+use crate::repository::{EnteredRepository, find_git_dir};
+use crate::smart_http::ProtocolVersion;
+use crate::upload_pack::{serve_upload_pack, ServiceOptions};
+use crate::stash::{Stash, stash_apply, stash_drop, stash_pop, stash_push, STASH_REF};
+use crate::sequencer::{replay_command, replay_in_progress, ReplayAction, Sequencer};
+use crate::message_cleanup::{cleanup_message, CleanupMode, comment_lines, is_message_empty, SCISSORS_LINE, strip_space};
+use crate::tree_diff::{ChangeStatus, diff_index_to_worktree, drop_unchanged_worktree_files, DiffSide, FileChange, diff_tree_to_index, hash_worktree_file, read_worktree_file, diff_trees, list_index_files, list_tree_files, list_untracked_files};
 
+mod checkout;
 mod cli;
+mod clone;
+mod color;
+mod commit_object_read;
 mod common;
+mod config;
+mod credential;
+mod daemon;
+mod diff;
+mod diff_output;
+mod editor;
+mod fetch;
+mod file_merge;
+mod http_backend;
+mod ident;
+mod index;
+mod lock_file;
+mod merge_base;
+mod message_cleanup;
 mod object_read;
 mod object_write;
+mod pack;
+mod pathspec;
+mod pkt_line;
+mod push;
+mod rebase;
+mod receive_pack;
+mod refspec;
+mod refs;
+mod remote;
+mod rename;
+mod repository;
+mod rev_list;
+mod rev_parse;
+mod sequencer;
+mod smart_http;
+mod stash;
+mod tag_object_read;
+mod transport;
+mod tree_diff;
+mod tree_merge;
 mod tree_object_read;
 mod tree_object_write;
+mod upload_pack;
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => match error.downcast_ref::<Exit>() {
+            Some(Exit(code)) => ExitCode::from(*code),
+            None => {
+                eprintln!("Error: {error:?}");
+                ExitCode::FAILURE
+            },
+        },
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    let cli = Cli::parse_args(env::args_os());
     match cli.command {
         Command::Init => init_command(),
         Command::CatFile { object, flags, force_raw } => cat_file_command(object, flags, force_raw),
@@ -26,6 +116,100 @@ fn main() -> anyhow::Result<()> {
         Command::LsTree { tree_sha, name_only } => ls_tree_command(tree_sha, name_only),
         Command::WriteTree { dry_run } => write_tree_command(dry_run),
         Command::CommitTree { parent, message, dry_run, tree } => commit_tree_command(tree, parent, message, dry_run),
+        Command::RevList { objects, objects_edge, count, parents, boundary, max_count, all, revs } => {
+            let options = RevListOptions {
+                objects: objects || objects_edge,
+                boundary,
+                edges: objects_edge,
+                max_count,
+                ..RevListOptions::default()
+            };
+            rev_list_command(revs, all, options, count, parents)
+        },
+        Command::Diff { no_index, cached, format, flags, args, paths } => {
+            let config = Config::read()?;
+            let options = flags.to_options(color_from_config(&config, "color.diff"), RenameOptions::from_config(&config, true)?);
+            diff_command(no_index, cached, args, paths, format.to_porcelain_format(), options)
+        },
+        Command::Show { format, flags, objects, paths } => {
+            let config = Config::read()?;
+            let options = flags.to_options(color_from_config(&config, "color.diff"), RenameOptions::from_config(&config, true)?);
+            show_command(objects, paths, format.to_porcelain_format(), options)
+        },
+        Command::DiffTree { recursive, root, format, flags, args } => {
+            let options = flags.to_options(ColorWhen::Never, RenameOptions::from_config(&Config::read()?, false)?);
+            diff_tree_command(args, recursive, root, format.to_format(), options)
+        },
+        Command::DiffIndex { cached, format, flags, tree_ish, paths } => {
+            let options = flags.to_options(ColorWhen::Never, RenameOptions::from_config(&Config::read()?, false)?);
+            diff_index_command(tree_ish, cached, paths, format.to_format(), options)
+        },
+        Command::DiffFiles { format, flags, paths } => {
+            let options = flags.to_options(ColorWhen::Never, RenameOptions::from_config(&Config::read()?, false)?);
+            diff_files_command(paths, format.to_format(), options)
+        },
+        Command::Commit { flags } => commit_command(flags),
+        Command::Checkout { create, force_create, detach, force, quiet, branch } => {
+            let new_branch = create.map(|x| (x, false)).or(force_create.map(|x| (x, true)));
+            switch_command(branch, new_branch, detach, force, quiet, false)
+        },
+        Command::Switch { create, force_create, detach, force, quiet, branch } => {
+            let new_branch = create.map(|x| (x, false)).or(force_create.map(|x| (x, true)));
+            switch_command(branch, new_branch, detach, force, quiet, true)
+        },
+        Command::Restore { source, staged, worktree, paths } => restore_command(source, staged, worktree, paths),
+        Command::CheckoutIndex { all, force, quiet, update, prefix, files } => {
+            checkout_index_command(all, force, quiet, update, prefix, files)
+        },
+        Command::Reset { mode, quiet, args, paths } => reset_command(mode.mode(), quiet, args, paths),
+        Command::ReadTree { merge, aggressive, update, prefix, trees } => read_tree_command(merge, aggressive, update, prefix, trees),
+        Command::MergeBase { all, mode, commits } => merge_base_command(all, mode, commits),
+        Command::MergeTree { write_tree: _, name_only, no_messages, allow_unrelated_histories, branch1, branch2 } => {
+            merge_tree_command(branch1, branch2, name_only, no_messages, allow_unrelated_histories)
+        },
+        Command::MergeFile { stdout, labels, diff3, zdiff3, ours, theirs, union, marker_size, current, base, other } => {
+            let style = match (diff3, zdiff3) {
+                (true, _) => ConflictStyle::Diff3,
+                (_, true) => ConflictStyle::Zdiff3,
+                // the configuration only applies inside a repository
+                _ if Path::new(GIT_PATH).is_dir() => ConflictStyle::from_config(Config::read()?.get("merge.conflictstyle"))?,
+                _ => ConflictStyle::Merge,
+            };
+            let favor = match (ours, theirs, union) {
+                (true, _, _) => Some(MergeFavor::Ours),
+                (_, true, _) => Some(MergeFavor::Theirs),
+                (_, _, true) => Some(MergeFavor::Union),
+                _ => None,
+            };
+            let options = FileMergeOptions { style, favor, marker_size };
+            merge_file_command([current, base, other], labels, stdout, options)
+        },
+        Command::Merge { no_ff, ff_only, squash, abort, message, allow_unrelated_histories, commit } => match (abort, commit) {
+            (true, _) => merge_abort_command(),
+            (false, Some(commit)) => merge_command(commit, no_ff, ff_only, squash, message, allow_unrelated_histories),
+            (false, None) => bail!("No commit specified to merge"),
+        },
+        Command::CherryPick { flags, record_origin } => replay_command(ReplayAction::Pick, flags, record_origin),
+        Command::Revert { flags } => replay_command(ReplayAction::Revert, flags, false),
+        Command::Rebase { flags, resume, skip, abort, edit_todo } => {
+            let config = Config::read()?;
+            match (resume, skip, abort, edit_todo) {
+                (true, _, _, _) => rebase_continue(&config),
+                (_, true, _, _) => rebase_skip(&config),
+                (_, _, true, _) => rebase_abort(&config),
+                (_, _, _, true) => rebase_edit_todo(&config),
+                _ => rebase_command(flags),
+            }
+        },
+        Command::Stash { command, push } => stash_command(command.unwrap_or(StashCommand::Push { flags: push })),
+        Command::Clone { flags, repository, directory } => clone_command(repository, directory, flags),
+        Command::Fetch { flags, remote, refspecs } => fetch_command(remote, refspecs, flags),
+        Command::Push { flags, repository, refspecs } => push_command(repository, refspecs, flags),
+        Command::UploadPack { flags, strict, directory } => upload_pack_command(directory, strict, flags),
+        Command::ReceivePack { flags, directory } => receive_pack_command(directory, flags),
+        Command::HttpBackend { listen, dumb, read_only, repositories } => http_backend_command(listen, dumb, read_only, repositories),
+        Command::Daemon { listen, base_path, export_all, directories } => daemon_command(listen, base_path, export_all, directories),
+        Command::Stripspace { strip_comments, comment_lines } => stripspace_command(strip_comments, comment_lines),
     }
 }
 
@@ -55,8 +239,8 @@ fn cat_file_command(object: String, flags: CatFlags, force_raw: bool) -> anyhow:
                     let TreeItem {mode, file_name, hash} = item?;
                     let object_type = mode.get_type();
                     print!("{mode:0>6} {object_type} {hash}\t");
-                    stdout().write(file_name.as_encoded_bytes())?;
-                    print!("\n");
+                    stdout().write_all(file_name.as_encoded_bytes())?;
+                    println!();
                 }
             }
             _ => {
@@ -87,7 +271,7 @@ fn ls_tree_command(object: String, name_only: bool) -> anyhow::Result<()> {
         let iterator = TreeObjectIterator::from_decoded_object(object).unwrap();
         for item in iterator {
             let item = item?;
-            stdout().write(item.file_name.as_encoded_bytes())?;
+            stdout().write_all(item.file_name.as_encoded_bytes())?;
         }
         return Ok(());
     }
@@ -120,7 +304,7 @@ fn commit_tree_command(tree: String, parent: Option<String>, message: String, dr
 
     let hash = hash_commit(
         &tree,
-        parent.as_ref().map(|x| x.as_str()),
+        parent.as_deref(),
         &message,
         COMMIT_AUTHOR,
         COMMIT_EMAIL,
@@ -132,3 +316,1571 @@ fn commit_tree_command(tree: String, parent: Option<String>, message: String, dr
 
     Ok(())
 }
+
+fn rev_list_command(revs: Vec<String>, all: bool, options: RevListOptions, count: bool, parents: bool) -> anyhow::Result<()> {
+    let (include, exclude) = parse_rev_args(&revs, all)?;
+    let result = rev_list(&include, &exclude, &options)?;
+
+    let mut writer = BufWriter::new(stdout().lock());
+    if count {
+        writeln!(writer, "{}", result.commits.len())?;
+        return Ok(());
+    }
+    for edge in &result.edges {
+        writeln!(writer, "-{edge}")?;
+    }
+    let commits = result.commits.iter().map(|x| ("", x));
+    let boundary = result.boundary.iter().map(|x| ("-", x));
+    for (prefix, commit) in commits.chain(boundary) {
+        write!(writer, "{prefix}{}", commit.hash)?;
+        if parents {
+            for parent in &commit.parents {
+                write!(writer, " {parent}")?;
+            }
+        }
+        writeln!(writer)?;
+    }
+    for object in &result.objects {
+        writeln!(writer, "{} {}", object.hash, object.path)?;
+    }
+    Ok(())
+}
+
+fn diff_command(no_index: bool, cached: bool, args: Vec<String>, paths: Vec<String>, format: DiffFormat, options: DiffOptions) -> anyhow::Result<()> {
+    if no_index {
+        return diff_no_index_command(&[args, paths].concat(), &options);
+    }
+    // leading arguments that are revisions select what to compare, the rest are paths
+    let rev_count = args.iter()
+        .take_while(|x| expand_diff_range(x).iter().all(|x| resolve_revision(x).is_ok()))
+        .count();
+    let (revs, arg_paths) = args.split_at(rev_count);
+    for path in arg_paths {
+        if !paths.is_empty() {
+            bail!("bad revision '{path}'");
+        }
+        if !Path::new(path).exists() {
+            bail!("ambiguous argument '{path}': unknown revision or path not in the working tree");
+        }
+    }
+    let pathspec = Pathspec::new(&[arg_paths, &paths].concat());
+    let rev_names = revs.iter().flat_map(|x| expand_diff_range(x)).collect::<Vec<_>>();
+    let hashes = rev_names.iter().map(|x| resolve_revision(x)).collect::<anyhow::Result<Vec<_>>>()?;
+
+    let changes = match hashes.as_slice() {
+        [] if cached => {
+            let head_tree = read_head_commit()?.map(|x| CommitObject::read(&x)).transpose()?.map(|x| x.tree);
+            let changes = diff_tree_to_index(head_tree.as_deref(), &Index::read()?, true, &pathspec)?;
+            detect_renames(changes, &options.renames, || list_tree_files(head_tree.as_deref(), &pathspec))?
+        },
+        [] => {
+            let index = Index::read()?;
+            let changes = diff_index_to_worktree(&index, &pathspec)?;
+            detect_renames(changes, &options.renames, || Ok(list_index_files(&index, &pathspec)))?
+        },
+        [rev] => {
+            let tree = peel(rev, ObjectType::Tree)?;
+            let changes = diff_tree_to_index(Some(&tree), &Index::read()?, cached, &pathspec)?;
+            detect_renames(changes, &options.renames, || list_tree_files(Some(&tree), &pathspec))?
+        },
+        [old, new] if read_object_type(old)? == ObjectType::Blob && read_object_type(new)? == ObjectType::Blob => {
+            return diff_blobs_command(rev_names[0], rev_names[1], &options);
+        },
+        [old, new] => {
+            let old_tree = peel(old, ObjectType::Tree)?;
+            let changes = diff_trees(Some(&old_tree), Some(&peel(new, ObjectType::Tree)?), true, &pathspec)?;
+            detect_renames(changes, &options.renames, || list_tree_files(Some(&old_tree), &pathspec))?
+        },
+        _ => bail!("Comparing more than two revisions is not supported"),
+    };
+    let mut writer = BufWriter::new(stdout().lock());
+    write_changes(&changes, &format, &options, &mut writer)
+}
+
+/// Splits a `<from>..<to>` range into its ends, a missing end means HEAD
+fn expand_diff_range(arg: &str) -> Vec<&str> {
+    match arg.split_once("..") {
+        Some((from, to)) => [from, to].into_iter().map(|x| if x.is_empty() { "HEAD" } else { x }).collect(),
+        None => vec![arg],
+    }
+}
+
+fn diff_no_index_command(paths: &[String], options: &DiffOptions) -> anyhow::Result<()> {
+    let [old_path, new_path] = paths else {
+        bail!("Expected exactly two paths to compare");
+    };
+    let old_meta = fs::symlink_metadata(old_path).context(format!("Failed to read {old_path}"))?;
+    let new_meta = fs::symlink_metadata(new_path).context(format!("Failed to read {new_path}"))?;
+    let old_data = read_worktree_file(Path::new(old_path), &old_meta)?;
+    let new_data = read_worktree_file(Path::new(new_path), &new_meta)?;
+    let old_hash = hash_object(old_data.as_slice(), ObjectType::Blob, old_data.len() as u64, false)?;
+    let new_hash = hash_object(new_data.as_slice(), ObjectType::Blob, new_data.len() as u64, false)?;
+    let old_mode = get_file_mode(Path::new(old_path))?;
+    let new_mode = get_file_mode(Path::new(new_path))?;
+    // absolute paths are shown relative to the root, like a/tmp/file
+    let old = PatchSide { path: old_path.trim_start_matches('/'), mode: old_mode, hash: &old_hash, data: &old_data };
+    let new = PatchSide { path: new_path.trim_start_matches('/'), mode: new_mode, hash: &new_hash, data: &new_data };
+
+    let mut writer = BufWriter::new(stdout().lock());
+    write_patch(Some(&old), Some(&new), ChangeStatus::Modified, options, &mut writer)
+}
+
+fn diff_blobs_command(old_rev: &str, new_rev: &str, options: &DiffOptions) -> anyhow::Result<()> {
+    let old_hash = resolve_revision(old_rev)?;
+    let new_hash = resolve_revision(new_rev)?;
+    let old_data = read_blob(&old_hash)?;
+    let new_data = read_blob(&new_hash)?;
+    let old_path = old_rev.split_once(':').map_or(old_rev, |(_, path)| path);
+    let new_path = new_rev.split_once(':').map_or(new_rev, |(_, path)| path);
+    let old = PatchSide { path: old_path, mode: ObjectMode::Normal, hash: &old_hash, data: &old_data };
+    let new = PatchSide { path: new_path, mode: ObjectMode::Normal, hash: &new_hash, data: &new_data };
+
+    let mut writer = BufWriter::new(stdout().lock());
+    write_patch(Some(&old), Some(&new), ChangeStatus::Modified, options, &mut writer)
+}
+
+fn show_command(objects: Vec<String>, paths: Vec<String>, format: DiffFormat, options: DiffOptions) -> anyhow::Result<()> {
+    let pathspec = Pathspec::new(&paths);
+    let mut writer = BufWriter::new(stdout().lock());
+    // commits, tags and trees are separated by an empty line
+    let mut shown_one = false;
+    let mut queue = objects.iter().map(|x| Ok((resolve_revision(x)?, x.clone()))).collect::<anyhow::Result<Vec<_>>>()?;
+    queue.reverse();
+    // commits at the edge of a shallow clone are shown as root commits
+    let shallow = read_shallow_commits()?;
+    while let Some((hash, name)) = queue.pop() {
+        match read_object_type(&hash)? {
+            ObjectType::Commit => {
+                let commit = CommitObject::read_cut(&hash, &shallow)?;
+                if !paths.is_empty() && !changes_paths(&commit, &pathspec)? {
+                    continue;
+                }
+                if shown_one {
+                    writeln!(writer)?;
+                }
+                show_commit(&commit, &pathspec, &format, &options, &mut writer)?;
+                shown_one = true;
+            },
+            ObjectType::Tag => {
+                let tag = TagObject::read(&hash)?;
+                if shown_one {
+                    writeln!(writer)?;
+                }
+                writeln!(writer, "{}", paint(&format!("tag {}", tag.tag), YELLOW, options.color))?;
+                if let Some(tagger) = &tag.tagger {
+                    writeln!(writer, "Tagger: {} <{}>\nDate:   {}", tagger.name, tagger.email, tagger.format_date())?;
+                }
+                write!(writer, "\n{}", tag.message)?;
+                shown_one = true;
+                // the tagged object is shown next
+                queue.push((tag.object, tag.tag));
+            },
+            ObjectType::Tree => {
+                if shown_one {
+                    writeln!(writer)?;
+                }
+                writeln!(writer, "{}\n", paint(&format!("tree {name}"), YELLOW, options.color))?;
+                for item in TreeObjectIterator::from_decoded_object(find_and_decode_object(&hash)?).unwrap() {
+                    let item = item?;
+                    writer.write_all(item.file_name.as_encoded_bytes())?;
+                    writeln!(writer, "{}", if item.mode == ObjectMode::Tree { "/" } else { "" })?;
+                }
+                shown_one = true;
+            },
+            ObjectType::Blob => writer.write_all(&read_blob(&hash)?)?,
+        }
+    }
+    Ok(())
+}
+
+/// Whether the commit changes the paths, a merge does not if any of its parents has the same contents of them
+fn changes_paths(commit: &CommitObject, pathspec: &Pathspec) -> anyhow::Result<bool> {
+    if commit.parents.is_empty() {
+        return Ok(!diff_trees(None, Some(&commit.tree), true, pathspec)?.is_empty());
+    }
+    for parent in &commit.parents {
+        let parent_tree = CommitObject::read(parent)?.tree;
+        if diff_trees(Some(&parent_tree), Some(&commit.tree), true, pathspec)?.is_empty() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Shows the commit in the default "medium" format, followed by its changes against the first parent.
+/// Merges only show the statistics against the first parent, conflict resolutions are not shown
+pub(crate) fn show_commit(commit: &CommitObject, pathspec: &Pathspec, format: &DiffFormat, options: &DiffOptions, writer: &mut impl Write) -> anyhow::Result<()> {
+    writeln!(writer, "{}", paint(&format!("commit {}", commit.hash), YELLOW, options.color))?;
+    if let [_, _, ..] = commit.parents.as_slice() {
+        let parents = commit.parents.iter().map(|x| abbreviate(x)).collect::<Vec<_>>();
+        writeln!(writer, "Merge: {}", parents.join(" "))?;
+    }
+    writeln!(writer, "Author: {} <{}>", commit.author.name, commit.author.email)?;
+    writeln!(writer, "Date:   {}\n", commit.author.format_date())?;
+    // leading and trailing empty lines and trailing whitespace are dropped
+    let lines = commit.message.lines().map(|x| x.trim_end()).skip_while(|x| x.is_empty()).collect::<Vec<_>>();
+    let end = lines.iter().rposition(|x| !x.is_empty()).map_or(0, |x| x + 1);
+    for line in &lines[..end] {
+        writeln!(writer, "    {}", expand_tabs(line))?;
+    }
+
+    let parent_tree = match commit.parents.first() {
+        Some(parent) => Some(CommitObject::read(parent)?.tree),
+        None => None,
+    };
+    let changes = diff_trees(parent_tree.as_deref(), Some(&commit.tree), true, pathspec)?;
+    let changes = detect_renames(changes, &options.renames, || list_tree_files(parent_tree.as_deref(), pathspec))?;
+    if commit.parents.len() > 1 {
+        if format.is_empty() {
+            return Ok(());
+        }
+        writeln!(writer)?;
+        let format = DiffFormat { stat: format.stat, numstat: format.numstat, ..Default::default() };
+        return write_changes(&changes, &format, options, writer);
+    }
+    if changes.is_empty() || format.is_empty() {
+        return Ok(());
+    }
+    // the stat and the patch are separated from the message by a line of three dashes
+    writeln!(writer, "{}", if format.stat && format.patch { "---" } else { "" })?;
+    write_changes(&changes, format, options, writer)
+}
+
+/// Replaces tabs with spaces up to the next multiple of 8 columns
+fn expand_tabs(line: &str) -> String {
+    let mut expanded = String::with_capacity(line.len());
+    for char in line.chars() {
+        match char {
+            '\t' => expanded.extend(std::iter::repeat_n(' ', 8 - expanded.chars().count() % 8)),
+            char => expanded.push(char),
+        }
+    }
+    expanded
+}
+
+fn diff_tree_command(args: Vec<String>, recursive: bool, root: bool, format: DiffFormat, options: DiffOptions) -> anyhow::Result<()> {
+    let (first, rest) = args.split_first().context("Expected a tree-ish to compare")?;
+    let first = resolve_revision(first)?;
+    // the second argument is a path if it is not a valid revision
+    let second = rest.first().and_then(|x| resolve_revision(x).ok());
+    let mut writer = BufWriter::new(stdout().lock());
+
+    if let Some(second) = second {
+        let pathspec = Pathspec::new(&rest[1..]);
+        let old_tree = peel(&first, ObjectType::Tree)?;
+        let new_tree = peel(&second, ObjectType::Tree)?;
+        let changes = diff_trees(Some(&old_tree), Some(&new_tree), recursive, &pathspec)?;
+        let changes = detect_renames(changes, &options.renames, || list_tree_files(Some(&old_tree), &pathspec))?;
+        return write_changes(&changes, &format, &options, &mut writer);
+    }
+
+    let pathspec = Pathspec::new(rest);
+    let commit = CommitObject::read_cut(&peel(&first, ObjectType::Commit)?, &read_shallow_commits()?)?;
+    let parent_tree = match commit.parents.as_slice() {
+        [] if root => None,
+        [parent] => Some(CommitObject::read(parent)?.tree),
+        // root commits are shown only with --root, merges are not shown at all
+        _ => return Ok(()),
+    };
+    let changes = diff_trees(parent_tree.as_deref(), Some(&commit.tree), recursive, &pathspec)?;
+    let changes = detect_renames(changes, &options.renames, || list_tree_files(parent_tree.as_deref(), &pathspec))?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+    writeln!(writer, "{}", commit.hash)?;
+    write_changes(&changes, &format, &options, &mut writer)
+}
+
+fn diff_index_command(tree_ish: String, cached: bool, paths: Vec<String>, format: DiffFormat, options: DiffOptions) -> anyhow::Result<()> {
+    let tree = peel(&resolve_revision(&tree_ish)?, ObjectType::Tree)?;
+    let index = Index::read()?;
+    let pathspec = Pathspec::new(&paths);
+    let changes = diff_tree_to_index(Some(&tree), &index, cached, &pathspec)?;
+    let changes = detect_renames(changes, &options.renames, || list_tree_files(Some(&tree), &pathspec))?;
+
+    let mut writer = BufWriter::new(stdout().lock());
+    write_changes(&changes, &format, &options, &mut writer)
+}
+
+fn diff_files_command(paths: Vec<String>, format: DiffFormat, options: DiffOptions) -> anyhow::Result<()> {
+    let index = Index::read()?;
+    let pathspec = Pathspec::new(&paths);
+    let changes = diff_index_to_worktree(&index, &pathspec)?;
+    let changes = detect_renames(changes, &options.renames, || Ok(list_index_files(&index, &pathspec)))?;
+
+    let mut writer = BufWriter::new(stdout().lock());
+    write_changes(&changes, &format, &options, &mut writer)
+}
+
+pub(crate) fn commit_command(flags: CommitFlags) -> anyhow::Result<()> {
+    let config = Config::read()?;
+    let head = read_head()?;
+    let head_commit = read_head_commit()?;
+    let merge_head = fs::read_to_string(MERGE_HEAD_PATH).ok().map(|x| x.trim().to_string());
+    let cherry_pick_head = fs::read_to_string(CHERRY_PICK_HEAD_PATH).ok().map(|x| x.trim().to_string());
+    if flags.amend && merge_head.is_some() {
+        bail!("You are in the middle of a merge -- cannot amend.");
+    }
+    let amended = match (flags.amend, &head_commit) {
+        // like git, the amended commit has the parents a history walk sees, none at the edge of a shallow clone
+        (true, Some(hash)) => Some(CommitObject::read_cut(hash, &read_shallow_commits()?)?),
+        (true, None) => bail!("You have nothing to amend."),
+        (false, _) => None,
+    };
+
+    let mut index = Index::read()?;
+    // the staged index replaces the index only after the commit is made, an aborted commit leaves the index alone
+    let index_lock = match flags.all {
+        true => Some(stage_tracked_changes(&mut index)?),
+        false => None,
+    };
+    check_unmerged_files(&index, "Committing", true)?;
+    let tree = write_index_tree(&index)?;
+    let parents = match &amended {
+        Some(commit) => commit.parents.clone(),
+        None => head_commit.iter().chain(&merge_head).cloned().collect(),
+    };
+    let parent_tree = match parents.first() {
+        Some(parent) => Some(CommitObject::read(parent)?.tree),
+        None => None,
+    };
+    let unchanged = match &parent_tree {
+        Some(parent_tree) => *parent_tree == tree,
+        None => index.entries.is_empty(),
+    };
+    // concluding a merge records the merged commit even if nothing changed
+    if unchanged && !flags.allow_empty && merge_head.is_none() {
+        if flags.amend {
+            eprint!("You asked to amend the most recent commit, but doing so would make
+it empty. You can repeat your command with --allow-empty, or you can
+remove the commit entirely with \"git reset HEAD^\".
+");
+        } else {
+            print_nothing_to_commit(&head, head_commit.is_none(), &index, &config)?;
+        }
+        return Err(Exit(1).into());
+    }
+
+    let committer = read_ident(Role::Committer, &config)?;
+    // an amended or cherry-picked commit keeps its author and date unless they are overridden
+    let mut author = match (&amended, &cherry_pick_head) {
+        (Some(commit), _) => commit.author.clone(),
+        (None, Some(hash)) => CommitObject::read(hash)?.author,
+        (None, None) => read_ident(Role::Author, &config)?,
+    };
+    if let Some(value) = &flags.author {
+        author = parse_ident(value, author.timestamp, &author.timezone)?;
+    }
+    if let Some(date) = &flags.date {
+        (author.timestamp, author.timezone) = parse_date(date)?;
+    }
+    let show_date = flags.amend || flags.date.is_some() || cherry_pick_head.is_some();
+
+    let editing = flags.edit || (flags.message.is_empty() && flags.file.is_none() && !flags.no_edit);
+    let cleanup = match (flags.cleanup, config.get("commit.cleanup")) {
+        (Some(mode), _) => mode,
+        (None, Some(value)) => {
+            let Ok(mode) = CleanupMode::from_str(value, true) else {
+                bail!("Invalid cleanup mode {value}");
+            };
+            mode
+        },
+        (None, None) => CleanupMode::Default,
+    };
+    let cleanup = cleanup.resolve(editing);
+    let mut message = if let Some(file) = &flags.file {
+        match file.as_str() {
+            "-" => io::read_to_string(stdin()).context("Failed to read the message from the standard input")?,
+            _ => fs::read_to_string(file).context(format!("could not read log file '{file}'"))?,
+        }
+    } else if !flags.message.is_empty() {
+        format!("{}\n", flags.message.join("\n\n"))
+    } else if let Some(commit) = &amended {
+        commit.message.clone()
+    } else {
+        // the message prepared by a merge
+        [MERGE_MSG_PATH, SQUASH_MSG_PATH].iter().find_map(|x| fs::read_to_string(x).ok()).unwrap_or_default()
+    };
+    if cleanup != CleanupMode::Verbatim {
+        message = strip_space(&message, false);
+    }
+    if editing {
+        let idents = template_idents(&author, &committer, show_date);
+        message = edit_commit_message(&message, cleanup, &idents, parent_tree.as_deref(), &index, &config)?;
+    } else {
+        fs::write(COMMIT_EDITMSG_PATH, &message).context(format!("Failed to write {COMMIT_EDITMSG_PATH}"))?;
+    }
+    let message = cleanup_message(&message, cleanup);
+    if is_message_empty(&message, cleanup) {
+        eprintln!("Aborting commit due to empty commit message.");
+        return Err(Exit(1).into());
+    }
+
+    let parent_refs = parents.iter().map(String::as_str).collect::<Vec<_>>();
+    let hash = write_commit(&tree, &parent_refs, &author, &committer, &message)?;
+    let first_line = message.lines().next().unwrap_or("");
+    let reflog_message = match (flags.amend, parents.len()) {
+        (true, _) => format!("commit (amend): {first_line}"),
+        (false, 0) => format!("commit (initial): {first_line}"),
+        (false, 1) if cherry_pick_head.is_some() => format!("commit (cherry-pick): {first_line}"),
+        (false, 1) => format!("commit: {first_line}"),
+        (false, _) => format!("commit (merge): {first_line}"),
+    };
+    update_head(&hash, head_commit.as_deref(), &committer, &reflog_message)?;
+    if let Some(lock) = index_lock {
+        lock.commit()?;
+    }
+    remove_merge_state()?;
+    if flags.quiet {
+        return Ok(());
+    }
+    print_commit_summary(&head, &hash, &message, parent_tree.as_deref(), &tree, &author, &committer, show_date, &config)
+}
+
+/// The line with the branch, the abbreviated hash and the subject of a new commit, followed by the summary of its changes
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn print_commit_summary(
+    head: &Head,
+    hash: &str,
+    message: &str,
+    parent_tree: Option<&str>,
+    tree: &str,
+    author: &Signature,
+    committer: &Signature,
+    show_date: bool,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let show_author = author.name != committer.name || author.email != committer.email;
+    let branch = match head {
+        Head::Branch(name) => name.strip_prefix(HEADS_PREFIX).unwrap_or(name),
+        Head::Detached(_) => "detached HEAD",
+    };
+    let root = if parent_tree.is_none() { " (root-commit)" } else { "" };
+    let subject = message_subject(message);
+    let mut writer = BufWriter::new(stdout().lock());
+    writeln!(writer, "[{branch}{root} {}] {subject}", abbreviate(hash))?;
+    if show_author {
+        writeln!(writer, " Author: {} <{}>", author.name, author.email)?;
+    }
+    if show_date {
+        writeln!(writer, " Date: {}", author.format_date())?;
+    }
+    // like git log, merges are shown without a diff
+    if CommitObject::read(hash)?.parents.len() > 1 {
+        return Ok(writer.flush()?);
+    }
+
+    let pathspec = Pathspec::new(&[]);
+    let changes = diff_trees(parent_tree, Some(tree), true, &pathspec)?;
+    let renames = RenameOptions { detection: RenameDetection::Renames, ..RenameOptions::from_config(config, true)? };
+    let changes = detect_renames(changes, &renames, || list_tree_files(parent_tree, &pathspec))?;
+    let format = DiffFormat { shortstat: true, summary: true, ..Default::default() };
+    let options = DiffOptions { renames, ..Default::default() };
+    write_changes(&changes, &format, &options, &mut writer)
+}
+
+/// Lets the user edit the message below the template that describes the commit, returns the edited text
+pub(crate) fn edit_commit_message(
+    message: &str,
+    cleanup: CleanupMode,
+    idents: &str,
+    parent_tree: Option<&str>,
+    index: &Index,
+    config: &Config,
+) -> anyhow::Result<String> {
+    let mut message = message.to_string();
+    if !message.is_empty() && !message.ends_with('\n') {
+        message.push('\n');
+    }
+    message.push('\n');
+    message.push_str(&match cleanup {
+        CleanupMode::Strip => comment_lines("Please enter the commit message for your changes. Lines starting
+with '#' will be ignored, and an empty message aborts the commit.
+"),
+        CleanupMode::Scissors => format!("{SCISSORS_LINE}\n{}", comment_lines("Do not modify or remove the line above.
+Everything below it will be ignored.
+")),
+        _ => comment_lines("Please enter the commit message for your changes. Lines starting
+with '#' will be kept; you may remove them yourself if you want to.
+An empty message aborts the commit.
+"),
+    });
+    message.push_str("#\n");
+    if !idents.is_empty() {
+        message.push_str(&comment_lines(&format!("{idents}\n")));
+    }
+    let staged = diff_tree_to_index(parent_tree, index, true, &Pathspec::new(&[]))?;
+    let renames = RenameOptions { detection: RenameDetection::Renames, ..RenameOptions::from_config(config, true)? };
+    let staged = detect_renames(staged, &renames, || Ok(vec![]))?;
+    let unstaged = worktree_changes(index)?;
+    message.push_str(&comment_lines(&commit_status(&read_head()?, parent_tree.is_none(), &staged, &unstaged)?));
+    fs::write(COMMIT_EDITMSG_PATH, &message).context(format!("Failed to write {COMMIT_EDITMSG_PATH}"))?;
+    launch_editor(Path::new(COMMIT_EDITMSG_PATH), config)?;
+    fs::read_to_string(COMMIT_EDITMSG_PATH).context(format!("Failed to read {COMMIT_EDITMSG_PATH}"))
+}
+
+/// The author and the date shown in the message template, the author only when it is not the committer
+pub(crate) fn template_idents(author: &Signature, committer: &Signature, show_date: bool) -> String {
+    let mut idents = String::new();
+    if author.name != committer.name || author.email != committer.email {
+        idents.push_str(&format!("Author:    {} <{}>\n", author.name, author.email));
+    }
+    if show_date {
+        idents.push_str(&format!("Date:      {}\n", author.format_date()));
+    }
+    idents
+}
+
+/// Puts the current contents of the tracked files into the index, files deleted from the working tree are removed from it,
+/// and writes it into the returned lock of the index
+fn stage_tracked_changes(index: &mut Index) -> anyhow::Result<LockFile> {
+    let mut lock = LockFile::acquire(INDEX_PATH)?;
+    let changes = diff_index_to_worktree(index, &Pathspec::new(&[]))?;
+    for change in changes {
+        if fs::symlink_metadata(&change.path).is_ok() {
+            index.add_file(&change.path)?;
+        } else {
+            index.remove(&change.path);
+        }
+    }
+    index.write_to(&mut lock)?;
+    Ok(lock)
+}
+
+/// Changes of the working tree compared with the index, without files that only have new timestamps
+pub(crate) fn worktree_changes(index: &Index) -> anyhow::Result<Vec<FileChange>> {
+    drop_unchanged_worktree_files(diff_index_to_worktree(index, &Pathspec::new(&[]))?)
+}
+
+/// The status shown in the commit message template, without the hints of the status command
+fn commit_status(head: &Head, initial: bool, staged: &[FileChange], unstaged: &[FileChange]) -> anyhow::Result<String> {
+    let mut status = match head {
+        _ if RebaseState::in_progress() => format!("{}\n", RebaseState::read()?.status(false)),
+        Head::Branch(name) => format!("On branch {}\n", name.strip_prefix(HEADS_PREFIX).unwrap_or(name)),
+        Head::Detached(hash) => format!("HEAD detached at {}\n", abbreviate(hash)),
+    };
+    if initial {
+        status.push_str("\nInitial commit\n\n");
+    }
+    for (title, changes) in [("Changes to be committed:", staged), ("Changes not staged for commit:", unstaged)] {
+        let changes = changes.iter().filter(|x| x.status != ChangeStatus::Unmerged).collect::<Vec<_>>();
+        if changes.is_empty() {
+            continue;
+        }
+        status.push_str(title);
+        status.push('\n');
+        for change in changes {
+            status.push_str(&status_line(change));
+        }
+        status.push('\n');
+    }
+    Ok(status)
+}
+
+/// A changed file in the status, like "\tnew file:   path"
+fn status_line(change: &FileChange) -> String {
+    let label = match change.status {
+        ChangeStatus::Added => "new file:",
+        ChangeStatus::Deleted => "deleted:",
+        ChangeStatus::Renamed(_) => "renamed:",
+        ChangeStatus::Copied(_) => "copied:",
+        ChangeStatus::TypeChanged => "typechange:",
+        ChangeStatus::Modified | ChangeStatus::Unmerged => "modified:",
+    };
+    match &change.source {
+        Some(source) => format!("\t{label:<12}{source} -> {}\n", change.path),
+        None => format!("\t{label:<12}{}\n", change.path),
+    }
+}
+
+/// The status shown when there is nothing to commit, with advice for a cherry-pick that became empty
+pub(crate) fn print_nothing_to_commit(head: &Head, initial: bool, index: &Index, config: &Config) -> anyhow::Result<()> {
+    if Path::new(CHERRY_PICK_HEAD_PATH).exists() {
+        let command = if RebaseState::in_progress() { "rebase" } else { "cherry-pick" };
+        eprint!("The previous cherry-pick is now empty, possibly due to conflict resolution.
+If you wish to commit it anyway, use:
+
+    git commit --allow-empty
+
+Otherwise, please use 'git {command} --skip'
+");
+    }
+    print_status(head, initial, index, config)
+}
+
+/// The long status of git status: the branch, the operation in progress, and the staged, unmerged, unstaged
+/// and untracked files with hints
+pub(crate) fn print_status(head: &Head, initial: bool, index: &Index, config: &Config) -> anyhow::Result<()> {
+    let rebase = match RebaseState::in_progress() {
+        true => Some(RebaseState::read()?),
+        false => None,
+    };
+    match (&rebase, head) {
+        (Some(rebase), _) => println!("{}", rebase.status(true)),
+        (None, Head::Branch(name)) => println!("On branch {}", name.strip_prefix(HEADS_PREFIX).unwrap_or(name.as_str())),
+        (None, Head::Detached(hash)) => println!("HEAD detached at {}", abbreviate(hash.as_str())),
+    }
+    // a rebase uses CHERRY_PICK_HEAD for its commits that became empty
+    if let Some((action, hash)) = replay_in_progress()?.filter(|_| rebase.is_none()) {
+        let command = action.command();
+        match (action, hash) {
+            (ReplayAction::Pick, Some(hash)) => println!("You are currently cherry-picking commit {}.", abbreviate(&hash)),
+            (ReplayAction::Revert, Some(hash)) => println!("You are currently reverting commit {}.", abbreviate(&hash)),
+            (ReplayAction::Pick, None) => println!("Cherry-pick currently in progress."),
+            (ReplayAction::Revert, None) => println!("Revert currently in progress."),
+        }
+        if Sequencer::in_progress() {
+            println!("  (run \"git {command} --continue\" to continue)");
+        } else {
+            println!("  (all conflicts fixed: run \"git {command} --continue\")");
+        }
+        println!("  (use \"git {command} --skip\" to skip this patch)");
+        println!("  (use \"git {command} --abort\" to cancel the {command} operation)");
+        println!();
+    }
+    if initial {
+        println!("\nNo commits yet\n");
+    }
+
+    let head_tree = read_head_commit()?.map(|x| peel(&x, ObjectType::Tree)).transpose()?;
+    let pathspec = Pathspec::new(&[]);
+    let staged = diff_tree_to_index(head_tree.as_deref(), index, true, &pathspec)?;
+    let renames = RenameOptions { detection: RenameDetection::Renames, ..RenameOptions::from_config(config, true)? };
+    let staged = detect_renames(staged, &renames, || list_tree_files(head_tree.as_deref(), &pathspec))?
+        .into_iter()
+        .filter(|x| x.status != ChangeStatus::Unmerged)
+        .collect::<Vec<_>>();
+    if !staged.is_empty() {
+        println!("Changes to be committed:");
+        match initial {
+            true => println!("  (use \"git rm --cached <file>...\" to unstage)"),
+            false => println!("  (use \"git restore --staged <file>...\" to unstage)"),
+        }
+        staged.iter().for_each(|x| print!("{}", status_line(x)));
+        println!();
+    }
+    let unmerged = unmerged_paths(index);
+    if !unmerged.is_empty() {
+        println!("Unmerged paths:");
+        match initial {
+            true => println!("  (use \"git rm --cached <file>...\" to unstage)"),
+            false => println!("  (use \"git restore --staged <file>...\" to unstage)"),
+        }
+        let deleted_one_side = unmerged.iter().any(|(_, label)| label.starts_with("deleted by"));
+        let deleted_both = unmerged.iter().any(|(_, label)| *label == "both deleted:");
+        match (deleted_one_side, deleted_both) {
+            (false, false) => println!("  (use \"git add <file>...\" to mark resolution)"),
+            (false, true) if unmerged.iter().all(|(_, label)| *label == "both deleted:") => {
+                println!("  (use \"git rm <file>...\" to mark resolution)");
+            },
+            _ => println!("  (use \"git add/rm <file>...\" as appropriate to mark resolution)"),
+        }
+        unmerged.iter().for_each(|(path, label)| println!("\t{label:<17}{path}"));
+        println!();
+    }
+    let unstaged = worktree_changes(index)?.into_iter().filter(|x| x.status != ChangeStatus::Unmerged).collect::<Vec<_>>();
+    if !unstaged.is_empty() {
+        println!("Changes not staged for commit:");
+        let deleted = unstaged.iter().any(|x| x.status == ChangeStatus::Deleted);
+        println!("  (use \"git {} <file>...\" to update what will be committed)", if deleted { "add/rm" } else { "add" });
+        println!("  (use \"git restore <file>...\" to discard changes in working directory)");
+        unstaged.iter().for_each(|x| print!("{}", status_line(x)));
+        println!();
+    }
+    let untracked = list_untracked_files(index, &pathspec, true)?;
+    if !untracked.is_empty() {
+        println!("Untracked files:");
+        println!("  (use \"git add <file>...\" to include in what will be committed)");
+        untracked.iter().for_each(|x| println!("\t{x}"));
+        println!();
+    }
+
+    if !staged.is_empty() {
+        return Ok(());
+    }
+    if !unstaged.is_empty() || !unmerged.is_empty() {
+        println!("no changes added to commit (use \"git add\" and/or \"git commit -a\")");
+    } else if !untracked.is_empty() {
+        println!("nothing added to commit but untracked files present (use \"git add\" to track)");
+    } else if initial {
+        println!("nothing to commit (create/copy files and use \"git add\" to track)");
+    } else {
+        println!("nothing to commit, working tree clean");
+    }
+    Ok(())
+}
+
+/// The conflicted paths of the index with the labels that say which sides changed them, like "both modified:"
+fn unmerged_paths(index: &Index) -> Vec<(&str, &'static str)> {
+    let mut stages = BTreeMap::new();
+    for entry in index.entries.iter().filter(|x| x.stage != 0) {
+        *stages.entry(entry.path.as_str()).or_insert(0) |= 1 << (entry.stage - 1);
+    }
+    stages.into_iter()
+        .map(|(path, mask)| {
+            let label = match mask {
+                0b001 => "both deleted:",
+                0b010 => "added by us:",
+                0b011 => "deleted by them:",
+                0b100 => "added by them:",
+                0b101 => "deleted by us:",
+                0b110 => "both added:",
+                _ => "both modified:",
+            };
+            (path, label)
+        })
+        .collect()
+}
+
+fn restore_command(source: Option<String>, staged: bool, worktree: bool, paths: Vec<String>) -> anyhow::Result<()> {
+    let source = match (source, staged) {
+        (Some(rev), _) => {
+            let tree = resolve_revision(&rev).and_then(|x| peel(&x, ObjectType::Tree)).context(format!("could not resolve {rev}"))?;
+            RestoreSource::Tree(tree)
+        },
+        (None, true) => RestoreSource::Tree(peel(&read_head_commit()?.context("could not resolve HEAD")?, ObjectType::Tree)?),
+        (None, false) => RestoreSource::Index,
+    };
+    let mut index = Index::read()?;
+    restore_paths(&source, &paths, &mut index, staged, worktree || !staged)?;
+    index.write()
+}
+
+/// Writes index entries to the working tree, files that already exist are only replaced with `force`
+fn checkout_index_command(all: bool, force: bool, quiet: bool, update: bool, prefix: String, files: Vec<String>) -> anyhow::Result<()> {
+    let mut index = Index::read()?;
+    let paths = match all {
+        true => index.entries.iter().filter(|x| x.stage == 0).map(|x| x.path.clone()).collect(),
+        false => files,
+    };
+    let mut failed = false;
+    let mut refreshed = vec![];
+    for path in paths {
+        let Some(entry) = index.entries.iter().find(|x| x.path == path && x.stage == 0) else {
+            if !quiet {
+                eprintln!("git checkout-index: {path} is not in the cache");
+            }
+            failed = true;
+            continue;
+        };
+        let target = format!("{prefix}{path}");
+        if let Ok(meta) = fs::symlink_metadata(&target) {
+            // a file that is already up to date is skipped silently
+            let up_to_date = prefix.is_empty()
+                && mode_from_metadata(&meta) == Some(entry.mode)
+                && ((entry.matches_stat(&meta) && !index.is_racy(entry)) || hash_worktree_file(Path::new(&target), &meta)? == entry.hash);
+            if up_to_date {
+                continue;
+            }
+            if !force {
+                if !quiet {
+                    eprintln!("{target} already exists, no checkout");
+                }
+                failed = true;
+                continue;
+            }
+        }
+        let meta = checkout_file(&target, &DiffSide::new(entry.mode, &entry.hash))?;
+        if update && prefix.is_empty() {
+            refreshed.push(IndexEntry::new(&path, entry.mode, &entry.hash, &meta));
+        }
+    }
+    if !refreshed.is_empty() {
+        let paths = refreshed.iter().map(|x| x.path.clone()).collect();
+        index.replace_entries(&paths, refreshed);
+        index.write()?;
+    }
+    if failed {
+        return Err(Exit(1).into());
+    }
+    Ok(())
+}
+
+/// Moves HEAD and the current branch to a commit and resets the index and the working tree according to the mode,
+/// or with paths only resets their index entries
+fn reset_command(mode: ResetMode, quiet: bool, args: Vec<String>, paths: Vec<String>) -> anyhow::Result<()> {
+    // a leading argument that resolves is the commit, the rest are paths. Before "--" there can only be the commit
+    let (rev, arg_paths) = match args.split_first() {
+        Some((first, [])) if !paths.is_empty() => {
+            resolve_revision(first).context(format!("Failed to resolve '{first}' as a valid tree."))?;
+            (Some(first.as_str()), &[][..])
+        },
+        Some((first, rest)) if resolve_revision(first).is_ok() => (Some(first.as_str()), rest),
+        _ => (None, args.as_slice()),
+    };
+    for path in arg_paths {
+        if !paths.is_empty() || !Path::new(path).exists() {
+            bail!("ambiguous argument '{path}': unknown revision or path not in the working tree");
+        }
+    }
+    let paths = [arg_paths, &paths].concat();
+    let old_commit = read_head_commit()?;
+    let mut index = Index::read()?;
+
+    // resetting without a commit to go to only unstages files
+    if !paths.is_empty() || (rev.is_none() && old_commit.is_none()) {
+        match mode {
+            ResetMode::Mixed => {},
+            ResetMode::Soft => bail!("Cannot do soft reset with paths."),
+            ResetMode::Hard => bail!("Cannot do hard reset with paths."),
+            ResetMode::Keep => bail!("Cannot do keep reset with paths."),
+        }
+        let tree = match rev.map(resolve_revision).transpose()?.or(old_commit) {
+            Some(hash) => Some(peel(&hash, ObjectType::Tree)?),
+            None => None,
+        };
+        reset_index(tree.as_deref(), &Pathspec::new(&paths), &mut index)?;
+        index.write()?;
+        return match quiet {
+            true => Ok(()),
+            false => print_unstaged_changes(&index),
+        };
+    }
+
+    let rev = rev.unwrap_or("HEAD");
+    let commit = peel(&resolve_revision(rev)?, ObjectType::Commit)?;
+    let new_tree = peel(&commit, ObjectType::Tree)?;
+    let old_tree = old_commit.as_deref().map(|x| peel(x, ObjectType::Tree)).transpose()?;
+    match mode {
+        ResetMode::Soft => {
+            if index.entries.iter().any(|x| x.stage != 0) {
+                bail!("Cannot do a soft reset in the middle of a merge.");
+            }
+        },
+        ResetMode::Mixed => reset_index(Some(&new_tree), &Pathspec::new(&[]), &mut index)?,
+        ResetMode::Hard => switch_trees(old_tree.as_deref(), Some(&new_tree), &mut index, true, SwitchAction::Plain)?,
+        ResetMode::Keep => {
+            switch_trees(old_tree.as_deref(), Some(&new_tree), &mut index, false, SwitchAction::Plain)
+                .context(format!("Could not reset index file to revision '{rev}'."))?;
+        },
+    }
+    if mode != ResetMode::Soft {
+        index.write()?;
+    }
+
+    let committer = read_ident_or_default(Role::Committer, &Config::read()?)?;
+    if let Some(old_commit) = &old_commit {
+        write_orig_head(old_commit)?;
+    }
+    update_head(&commit, old_commit.as_deref(), &committer, &format!("reset: moving to {rev}"))?;
+    if quiet {
+        return Ok(());
+    }
+    match mode {
+        ResetMode::Hard => println!("HEAD is now at {} {}", abbreviate(&commit), CommitObject::read(&commit)?.subject()),
+        ResetMode::Mixed => print_unstaged_changes(&index)?,
+        ResetMode::Soft | ResetMode::Keep => {},
+    }
+    Ok(())
+}
+
+fn print_unstaged_changes(index: &Index) -> anyhow::Result<()> {
+    let changes = worktree_changes(index)?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let mut writer = BufWriter::new(stdout().lock());
+    writeln!(writer, "Unstaged changes after reset:")?;
+    for change in changes {
+        writeln!(writer, "{}\t{}", change.status.letter(), change.path)?;
+    }
+    Ok(())
+}
+
+fn read_tree_command(merge: bool, aggressive: bool, update: bool, prefix: Option<String>, trees: Vec<String>) -> anyhow::Result<()> {
+    if update && !merge && prefix.is_none() {
+        bail!("-u is meaningless without -m or --prefix");
+    }
+    let trees = trees.iter()
+        .map(|x| resolve_revision(x).and_then(|hash| peel(&hash, ObjectType::Tree)).context(format!("failed to unpack tree object {x}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut index = Index::read()?;
+    match (trees.as_slice(), prefix) {
+        ([tree], Some(prefix)) => read_tree_prefix(tree, &prefix, &mut index, update)?,
+        (_, Some(_)) => bail!("--prefix takes exactly one tree"),
+        ([tree], None) if !merge => reset_index(Some(tree), &Pathspec::new(&[]), &mut index)?,
+        (_, None) if !merge => bail!("reading several trees needs -m"),
+        (trees, None) => {
+            let trees = trees.iter().map(|x| Some(x.as_str())).collect::<Vec<_>>();
+            let options = ReadTreeOptions { force: false, aggressive, update_worktree: update, action: SwitchAction::Plain };
+            read_trees(&trees, &mut index, &options)?;
+        },
+    }
+    index.write()
+}
+
+fn merge_base_command(all: bool, mode: MergeBaseModeFlags, commits: Vec<String>) -> anyhow::Result<()> {
+    let resolve = |name: &str| resolve_revision(name)
+        .and_then(|x| peel(&x, ObjectType::Commit))
+        .context(format!("Not a valid object name {name}"));
+    let mut graph = CommitGraph::default();
+    if mode.fork_point {
+        return merge_base_fork_point(&mut graph, &commits);
+    }
+    let commits = commits.iter().map(|x| resolve(x)).collect::<anyhow::Result<Vec<_>>>()?;
+    let result = if mode.is_ancestor {
+        let [ancestor, descendant] = commits.as_slice() else {
+            bail!("--is-ancestor takes exactly two commits");
+        };
+        if graph.is_ancestor(ancestor, descendant)? {
+            return Ok(());
+        }
+        vec![]
+    } else if mode.independent {
+        graph.remove_redundant(&commits)?
+    } else if mode.octopus {
+        graph.octopus_merge_bases(&commits)?
+    } else {
+        let [one, others @ ..] = commits.as_slice() else {
+            bail!("No commits given");
+        };
+        if others.is_empty() {
+            bail!("At least two commits are needed to find their merge bases");
+        }
+        graph.merge_bases(one, others)?
+    };
+    if result.is_empty() {
+        return Err(Exit(1).into());
+    }
+    let count = if all || mode.independent { result.len() } else { 1 };
+    for hash in &result[..count] {
+        println!("{hash}");
+    }
+    Ok(())
+}
+
+/// The merge base of the commit and the past values of the ref, if it is one of these values
+fn merge_base_fork_point(graph: &mut CommitGraph, args: &[String]) -> anyhow::Result<()> {
+    let (ref_arg, commit_arg) = match args {
+        [ref_arg] => (ref_arg, "HEAD"),
+        [ref_arg, commit_arg] => (ref_arg, commit_arg.as_str()),
+        _ => bail!("--fork-point takes a ref and an optional commit"),
+    };
+    let Some((ref_name, hash)) = dwim_ref(ref_arg)? else {
+        bail!("No such ref: '{ref_arg}'");
+    };
+    let commit = resolve_revision(commit_arg)
+        .and_then(|x| peel(&x, ObjectType::Commit))
+        .context(format!("Not a valid object name: '{commit_arg}'"))?;
+    let mut past_values = reflog_commits(&ref_name)?;
+    // commits that were pruned since then do not count
+    past_values.retain(|x| read_object_type(x).is_ok_and(|x| x == ObjectType::Commit));
+    if past_values.is_empty() {
+        past_values.push(hash);
+    }
+    match graph.merge_bases(&commit, &past_values)?.as_slice() {
+        [fork_point] if past_values.contains(fork_point) => println!("{fork_point}"),
+        _ => return Err(Exit(1).into()),
+    }
+    Ok(())
+}
+
+fn merge_tree_command(branch1: String, branch2: String, name_only: bool, no_messages: bool, allow_unrelated: bool) -> anyhow::Result<()> {
+    let resolve = |name: &str| resolve_revision(name)
+        .and_then(|x| peel(&x, ObjectType::Commit))
+        .ok()
+        .context(format!("merge-tree: {name} - not something we can merge"));
+    let ours = resolve(&branch1)?;
+    let theirs = resolve(&branch2)?;
+    let bases = merge_bases(&ours, &theirs)?;
+    if bases.is_empty() && !allow_unrelated {
+        bail!("refusing to merge unrelated histories");
+    }
+    let options = merge_options(branch1, branch2, &Config::read()?)?;
+    let result = merge_commits(&bases, &ours, &theirs, &options)?;
+
+    let mut writer = BufWriter::new(stdout().lock());
+    writeln!(writer, "{}", result.tree)?;
+    for (path, stages) in &result.conflicts {
+        if name_only {
+            writeln!(writer, "{path}")?;
+            continue;
+        }
+        for (stage, side) in (1..).zip(stages) {
+            if let Some(side) = side {
+                writeln!(writer, "{} {} {stage}\t{path}", side.mode, side.hash)?;
+            }
+        }
+    }
+    if result.conflicts.is_empty() {
+        return Ok(());
+    }
+    if !no_messages {
+        writeln!(writer)?;
+        for message in &result.messages {
+            writeln!(writer, "{message}")?;
+        }
+    }
+    writer.flush()?;
+    Err(Exit(1).into())
+}
+
+pub(crate) fn merge_options(ours_label: String, theirs_label: String, config: &Config) -> anyhow::Result<MergeOptions> {
+    Ok(MergeOptions {
+        ours_label,
+        theirs_label,
+        style: ConflictStyle::from_config(config.get("merge.conflictstyle"))?,
+        renames: RenameOptions::from_config(config, true)?,
+        marker_size: MARKER_SIZE,
+    })
+}
+
+/// Checks out the result of a merge into the tree of HEAD, with the conflicts as stages of the index,
+/// and prints the messages about the merged files, only when there are conflicts if `quiet_if_clean` is set.
+/// The failure message, if any, follows the error about local changes that would be overwritten.
+/// Returns the conflicted paths
+pub(crate) fn apply_merge_result(ours_tree: &str, result: MergeResult, index: &mut Index, failure: &str, quiet_if_clean: bool) -> anyhow::Result<Vec<String>> {
+    let read_options = ReadTreeOptions { force: false, aggressive: false, update_worktree: true, action: SwitchAction::Merge };
+    if let Err(error) = read_trees(&[Some(ours_tree), Some(&result.tree)], index, &read_options) {
+        match failure {
+            "" => bail!(error),
+            _ => bail!("{error}\n{failure}"),
+        }
+    }
+    let conflicted_paths = result.conflicts.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>();
+    add_conflict_stages(index, result.conflicts);
+    index.write()?;
+    if !quiet_if_clean || !conflicted_paths.is_empty() {
+        for message in &result.messages {
+            println!("{message}");
+        }
+    }
+    Ok(conflicted_paths)
+}
+
+/// Prepares the message of the commit that concludes a merge, listing the conflicts in comments
+pub(crate) fn write_merge_message(message: &str, conflicted_paths: &[String]) -> anyhow::Result<()> {
+    let mut message = message.to_string();
+    if !conflicted_paths.is_empty() {
+        message.push_str("\n# Conflicts:\n");
+        message.extend(conflicted_paths.iter().map(|x| format!("#\t{x}\n")));
+    }
+    fs::write(MERGE_MSG_PATH, message).context(format!("Failed to write {MERGE_MSG_PATH}"))
+}
+
+fn merge_file_command(files: [String; 3], labels: Vec<String>, stdout: bool, options: FileMergeOptions) -> anyhow::Result<()> {
+    if labels.len() > 3 {
+        bail!("too many labels on the command line");
+    }
+    let mut contents = vec![];
+    for file in &files {
+        let data = fs::read(file).context(format!("Could not read {file}"))?;
+        if is_binary(&data) {
+            bail!("Cannot merge binary files: {file}");
+        }
+        contents.push(data);
+    }
+    let label = |i: usize| labels.get(i).unwrap_or(&files[i]).as_str();
+    let labels = MergeLabels { ours: label(0), base: label(1), theirs: label(2) };
+    let (merged, conflicts) = merge_texts(&contents[1], &contents[0], &contents[2], &labels, &options);
+    if stdout {
+        io::stdout().write_all(&merged)?;
+    } else {
+        fs::write(&files[0], merged).context(format!("Could not write {}", files[0]))?;
+    }
+    // the exit code is the number of conflicts, as far as it fits
+    if conflicts > 0 {
+        io::stdout().flush()?;
+        return Err(Exit(conflicts.min(127) as u8).into());
+    }
+    Ok(())
+}
+
+/// Goes back to HEAD like reset --merge, the paths the merge changed in the index are reset and other local changes are kept
+fn merge_abort_command() -> anyhow::Result<()> {
+    if !Path::new(MERGE_HEAD_PATH).exists() {
+        bail!("There is no merge to abort (MERGE_HEAD missing).");
+    }
+    reset_merge(read_head_commit()?.as_deref())
+}
+
+/// Resets the paths that differ between the index and the commit to the commit, in the index and the working tree,
+/// and forgets the merge in progress. Local changes to other files are kept
+pub(crate) fn reset_merge(commit: Option<&str>) -> anyhow::Result<()> {
+    let tree = match commit {
+        Some(hash) => Some(peel(hash, ObjectType::Tree)?),
+        None => None,
+    };
+    let mut index = Index::read()?;
+    let pathspec = Pathspec::new(&[]);
+    let tree_files = list_tree_files(tree.as_deref(), &pathspec)?.into_iter().collect::<HashMap<_, _>>();
+    let targets = diff_tree_to_index(tree.as_deref(), &index, true, &pathspec)?
+        .into_iter()
+        .map(|x| {
+            let side = tree_files.get(&x.path).cloned();
+            (x.path, side)
+        })
+        .collect();
+    apply_targets(&mut index, targets, true)?;
+    index.write()?;
+    remove_merge_state()
+}
+
+/// Fails with hints when the index has conflicts, optionally listing the conflicted files
+pub(crate) fn check_unmerged_files(index: &Index, action: &str, list: bool) -> anyhow::Result<()> {
+    if report_unmerged_files(index, action, list) {
+        bail!("Exiting because of an unresolved conflict.");
+    }
+    Ok(())
+}
+
+/// Prints the error and the hints about conflicts in the index, returns whether there are any
+pub(crate) fn report_unmerged_files(index: &Index, action: &str, list: bool) -> bool {
+    let mut unmerged = index.entries.iter().filter(|x| x.stage != 0).map(|x| x.path.as_str()).collect::<Vec<_>>();
+    if unmerged.is_empty() {
+        return false;
+    }
+    unmerged.dedup();
+    eprintln!("error: {action} is not possible because you have unmerged files.");
+    eprintln!("hint: Fix them up in the work tree, and then use 'git add/rm <file>'");
+    eprintln!("hint: as appropriate to mark resolution and make a commit.");
+    if list {
+        unmerged.iter().for_each(|x| println!("U\t{x}"));
+    }
+    true
+}
+
+/// Forgets the merge, cherry-pick or revert in progress and the message prepared by a squash merge
+pub(crate) fn remove_merge_state() -> anyhow::Result<()> {
+    for path in [MERGE_HEAD_PATH, MERGE_MSG_PATH, MERGE_MODE_PATH, SQUASH_MSG_PATH, CHERRY_PICK_HEAD_PATH, REVERT_HEAD_PATH] {
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error).context(format!("Failed to remove {path}")),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+fn stash_command(command: StashCommand) -> anyhow::Result<()> {
+    let config = Config::read()?;
+    match command {
+        StashCommand::Push { flags } => stash_push(flags, &config),
+        StashCommand::List => {
+            for (position, entry) in read_reflog(STASH_REF)?.iter().rev().enumerate() {
+                println!("stash@{{{position}}}: {}", entry.message);
+            }
+            Ok(())
+        },
+        StashCommand::Show { format, flags, stash } => {
+            let stash = Stash::find(stash.as_deref())?;
+            let options = flags.to_options(color_from_config(&config, "color.diff"), RenameOptions::from_config(&config, true)?);
+            let default_format = DiffFormat {
+                stat: !matches!(config.get("stash.showStat"), Some("false" | "no" | "off" | "0")),
+                patch: matches!(config.get("stash.showPatch"), Some("true" | "yes" | "on" | "1")),
+                abbrev: true,
+                ..Default::default()
+            };
+            let format = DiffFormat { abbrev: true, ..format.to_format_with_default(default_format) };
+            let pathspec = Pathspec::new(&[]);
+            let changes = diff_trees(Some(&stash.base_tree), Some(&stash.tree), true, &pathspec)?;
+            let changes = detect_renames(changes, &options.renames, || list_tree_files(Some(&stash.base_tree), &pathspec))?;
+            write_changes(&changes, &format, &options, &mut BufWriter::new(stdout().lock()))
+        },
+        StashCommand::Apply { flags } => {
+            if !stash_apply(&Stash::find(flags.stash.as_deref())?, flags.index, flags.quiet, &config)? {
+                return Err(Exit(1).into());
+            }
+            Ok(())
+        },
+        StashCommand::Pop { flags } => {
+            if !stash_pop(&Stash::find(flags.stash.as_deref())?, flags.index, flags.quiet, &config)? {
+                return Err(Exit(1).into());
+            }
+            Ok(())
+        },
+        StashCommand::Drop { quiet, stash } => stash_drop(&Stash::find(stash.as_deref())?, quiet),
+        StashCommand::Branch { branch, stash } => {
+            let branch = branch.context("No branch name specified")?;
+            let stash = Stash::find(stash.as_deref())?;
+            switch_command(Some(stash.base.clone()), Some((branch, false)), false, false, false, false)?;
+            if !stash_apply(&stash, true, false, &config)? {
+                return Err(Exit(1).into());
+            }
+            match stash.position {
+                Some(_) => stash_drop(&stash, false),
+                None => Ok(()),
+            }
+        },
+    }
+}
+
+fn upload_pack_command(directory: String, strict: bool, flags: ServiceFlags) -> anyhow::Result<()> {
+    let git_dir = find_git_dir(Path::new(&directory))
+        .filter(|x| !strict || x == Path::new(&directory))
+        .context(format!("'{directory}' does not appear to be a git repository"))?;
+    let _entered = EnteredRepository::enter(&git_dir)?;
+    serve_upload_pack(stdin().lock(), BufWriter::new(stdout().lock()), &service_options(&flags))
+}
+
+fn receive_pack_command(directory: String, flags: ServiceFlags) -> anyhow::Result<()> {
+    let git_dir = find_git_dir(Path::new(&directory)).context(format!("'{directory}' does not appear to be a git repository"))?;
+    let _entered = EnteredRepository::enter(&git_dir)?;
+    serve_receive_pack(stdin().lock(), BufWriter::new(stdout().lock()), &service_options(&flags))
+}
+
+/// How a service talks to its client, the version it asked for is passed in GIT_PROTOCOL
+fn service_options(flags: &ServiceFlags) -> ServiceOptions {
+    let version = ProtocolVersion::requested(&env::var("GIT_PROTOCOL").unwrap_or_default());
+    ServiceOptions { stateless_rpc: flags.stateless_rpc, advertise_refs: flags.advertise_refs, version }
+}
+
+fn http_backend_command(listen: String, dumb: bool, read_only: bool, repositories: Vec<String>) -> anyhow::Result<()> {
+    run_http_backend(&listen, HttpBackendOptions::new(&repositories, dumb, read_only)?)
+}
+
+fn daemon_command(listen: String, base_path: Option<String>, export_all: bool, directories: Vec<String>) -> anyhow::Result<()> {
+    run_daemon(&listen, DaemonOptions::new(base_path.as_deref(), export_all, &directories)?)
+}
+
+fn stripspace_command(strip_comments: bool, comment: bool) -> anyhow::Result<()> {
+    let input = io::read_to_string(stdin()).context("Failed to read the standard input")?;
+    let output = if comment { comment_lines(&input) } else { strip_space(&input, strip_comments) };
+    stdout().write_all(output.as_bytes())?;
+    Ok(())
+}
+
+/// Switches to a branch or detaches HEAD at a commit, optionally creating or resetting the branch first.
+/// `branch_required` gives the stricter behavior of switch, which only detaches HEAD with --detach
+fn switch_command(
+    target: Option<String>,
+    new_branch: Option<(String, bool)>,
+    detach: bool,
+    force: bool,
+    quiet: bool,
+    branch_required: bool,
+) -> anyhow::Result<()> {
+    let config = Config::read()?;
+    let old_head = read_head()?;
+    let old_commit = read_head_commit()?;
+    let target = match target.as_deref() {
+        Some("-") => Some(previous_checkout()?.context("invalid reference: @{-1}")?),
+        _ => target,
+    };
+
+    // the old value of the branch and the start point, when the branch is created or reset
+    let mut created = None;
+    let (new_head, new_commit) = match (&new_branch, target.as_deref()) {
+        (Some((name, reset)), start) => {
+            if !is_valid_ref_name(name) {
+                bail!("'{name}' is not a valid branch name");
+            }
+            let ref_name = format!("{HEADS_PREFIX}{name}");
+            let existing = read_ref(&ref_name)?;
+            if existing.is_some() && !reset {
+                bail!("a branch named '{name}' already exists");
+            }
+            // on an unborn branch there is nothing to start the new branch from, so HEAD only points to it
+            if let (None, None, None) = (start, &old_commit, &existing) {
+                fs::write(HEAD_PATH, format!("ref: {ref_name}\n")).context(format!("Failed to write {HEAD_PATH}"))?;
+                if !quiet {
+                    eprintln!("Switched to a new branch '{name}'");
+                }
+                return Ok(());
+            }
+            let start = start.unwrap_or("HEAD");
+            let commit = resolve_revision(start)
+                .and_then(|x| peel(&x, ObjectType::Commit))
+                .context(format!("invalid reference: {start}"))?;
+            created = Some((existing, start.to_string()));
+            (Head::Branch(ref_name), commit)
+        },
+        (None, None) if detach => {
+            let commit = old_commit.clone().context("You are on a branch yet to be born")?;
+            (Head::Detached(commit.clone()), commit)
+        },
+        (None, None) if branch_required => bail!("missing branch or commit argument"),
+        (None, None) => {
+            // without a target checkout only shows the local changes
+            let index = Index::read()?;
+            if !quiet {
+                print_local_changes(old_commit.as_deref(), &index)?;
+            }
+            return Ok(());
+        },
+        (None, Some(name)) => {
+            let ref_name = format!("{HEADS_PREFIX}{name}");
+            match read_ref(&ref_name)? {
+                Some(commit) if !detach => (Head::Branch(ref_name), commit),
+                _ => match resolve_revision(name).and_then(|x| peel(&x, ObjectType::Commit)) {
+                    Ok(commit) if detach || !branch_required => (Head::Detached(commit.clone()), commit),
+                    Ok(_) => bail!("a branch is expected, got commit '{name}'\n\
+                        hint: If you want to detach HEAD at the commit, try again with the --detach option."),
+                    Err(_) if branch_required => bail!("invalid reference: {name}"),
+                    Err(_) => bail!("pathspec '{name}' did not match any file(s) known to git"),
+                },
+            }
+        },
+    };
+
+    // a new branch at HEAD leaves the index and the working tree alone
+    if new_branch.is_none() || target.is_some() || force {
+        let old_tree = old_commit.as_deref().map(|x| peel(x, ObjectType::Tree)).transpose()?;
+        let new_tree = peel(&new_commit, ObjectType::Tree)?;
+        let mut index = Index::read()?;
+        switch_trees(old_tree.as_deref(), Some(&new_tree), &mut index, force, SwitchAction::Checkout)?;
+        index.write()?;
+        if !quiet && !force {
+            print_local_changes(Some(&new_commit), &index)?;
+        }
+    }
+
+    let committer = read_ident_or_default(Role::Committer, &config)?;
+    if let (Some((existing, start)), Head::Branch(ref_name)) = (&created, &new_head) {
+        let message = match existing {
+            Some(_) => format!("branch: Reset to {start}"),
+            None => format!("branch: Created from {start}"),
+        };
+        update_ref(ref_name, &new_commit, existing.as_deref(), &committer, &message)?;
+    }
+    let old_name = match &old_head {
+        Head::Branch(name) => name.strip_prefix(HEADS_PREFIX).unwrap_or(name),
+        Head::Detached(hash) => hash,
+    };
+    let new_name = match (&new_head, &target) {
+        (Head::Branch(name), _) => name.strip_prefix(HEADS_PREFIX).unwrap_or(name),
+        (Head::Detached(_), Some(target)) => target,
+        (Head::Detached(_), None) => "HEAD",
+    };
+    // moving a detached HEAD to the commit it is already at leaves no trace in the reflog
+    if matches!(new_head, Head::Branch(_)) || new_head != old_head {
+        write_head(&new_head, old_commit.as_deref(), &new_commit, &committer, &format!("checkout: moving from {old_name} to {new_name}"))?;
+    }
+    if quiet {
+        return Ok(());
+    }
+
+    match &old_head {
+        Head::Detached(old_commit) if *old_commit != new_commit => print_orphaned_commits(old_commit, &new_commit)?,
+        _ => {},
+    }
+    match (&new_head, &created) {
+        (Head::Branch(_), Some((Some(_), _))) if new_head == old_head => eprintln!("Reset branch '{new_name}'"),
+        (Head::Branch(_), Some((Some(_), _))) => eprintln!("Switched to and reset branch '{new_name}'"),
+        (Head::Branch(_), Some((None, _))) => eprintln!("Switched to a new branch '{new_name}'"),
+        (Head::Branch(_), None) if new_head == old_head => eprintln!("Already on '{new_name}'"),
+        (Head::Branch(_), None) => eprintln!("Switched to branch '{new_name}'"),
+        (Head::Detached(hash), _) => {
+            let advice = !matches!(config.get("advice.detachedHead"), Some("false" | "no" | "off" | "0"));
+            if matches!(old_head, Head::Branch(_)) && advice && !detach {
+                eprint!("{}", detached_head_advice(new_name));
+            }
+            eprintln!("HEAD is now at {} {}", abbreviate(hash), CommitObject::read(hash)?.subject());
+        },
+    }
+    Ok(())
+}
+
+/// Lists the files whose contents in the index or the working tree differ from the commit, like git does after a checkout
+fn print_local_changes(commit: Option<&str>, index: &Index) -> anyhow::Result<()> {
+    let tree = commit.map(|x| peel(x, ObjectType::Tree)).transpose()?;
+    let changes = drop_unchanged_worktree_files(diff_tree_to_index(tree.as_deref(), index, false, &Pathspec::new(&[]))?)?;
+    let mut writer = BufWriter::new(stdout().lock());
+    for change in changes {
+        writeln!(writer, "{}\t{}", change.status.letter(), change.path)?;
+    }
+    Ok(())
+}
+
+/// Tells which commits of a detached HEAD are no longer reachable from any ref after switching away from them
+fn print_orphaned_commits(old_commit: &str, new_commit: &str) -> anyhow::Result<()> {
+    const MAX_LISTED: usize = 4;
+    let mut exclude = list_refs("refs/")?.into_iter().map(|(_, hash)| hash).collect::<Vec<_>>();
+    exclude.push(new_commit.to_string());
+    let lost = rev_list(&[old_commit.to_string()], &exclude, &RevListOptions::default())?.commits;
+    if lost.is_empty() {
+        eprintln!("Previous HEAD position was {} {}", abbreviate(old_commit), CommitObject::read(old_commit)?.subject());
+        return Ok(());
+    }
+    let (noun, pronoun) = if lost.len() == 1 { ("commit", "it") } else { ("commits", "them") };
+    let mut message = format!("Warning: you are leaving {} {noun} behind, not connected to\nany of your branches:\n\n", lost.len());
+    for commit in lost.iter().take(MAX_LISTED) {
+        message.push_str(&format!("  {} {}\n", abbreviate(&commit.hash), commit.subject()));
+    }
+    if lost.len() > MAX_LISTED + 1 {
+        message.push_str(&format!(" ... and {} more.\n", lost.len() - MAX_LISTED));
+    } else if lost.len() == MAX_LISTED + 1 {
+        let commit = &lost[MAX_LISTED];
+        message.push_str(&format!("  {} {}\n", abbreviate(&commit.hash), commit.subject()));
+    }
+    message.push_str(&format!(
+        "\nIf you want to keep {pronoun} by creating a new branch, this may be a good time\nto do so with:\n\n git branch <new-branch-name> {}\n\n",
+        abbreviate(old_commit),
+    ));
+    eprint!("{message}");
+    Ok(())
+}
+
+pub(crate) fn detached_head_advice(target: &str) -> String {
+    format!("Note: switching to '{target}'.
+
+You are in 'detached HEAD' state. You can look around, make experimental
+changes and commit them, and you can discard any commits you make in this
+state without impacting any branches by switching back to a branch.
+
+If you want to create a new branch to retain commits you create, you may
+do so (now or later) by using -c with the switch command. Example:
+
+  git switch -c <new-branch-name>
+
+Or undo this operation with:
+
+  git switch -
+
+Turn off this advice by setting config variable advice.detachedHead to false
+
+")
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use super::*;
+    use crate::common::init_worktree_test;
+    use crate::refs::LOGS_PATH;
+    use crate::tree_object_write::write_tree_level;
+
+    /// Writes a commit with the files, given by path, mode and contents, and points the branch to it
+    fn commit_files(branch: &str, files: &[(&str, ObjectMode, &str)], parents: &[&str]) -> anyhow::Result<String> {
+        let mut entries = vec![];
+        for (path, mode, content) in files {
+            let blob = hash_object(content.as_bytes(), ObjectType::Blob, content.len() as u64, true)?;
+            entries.push((*path, *mode, blob));
+        }
+        entries.sort_by(|left, right| left.0.cmp(right.0));
+        let entries: Vec<_> = entries.iter().map(|(path, mode, hash)| (*path, *mode, hash.as_str())).collect();
+        let tree = write_tree_level(&entries)?;
+        let signature = Signature { name: "test".to_string(), email: "test@example.com".to_string(), timestamp: 1_000, timezone: "+0000".to_string() };
+        let hash = write_commit(&tree, parents, &signature, &signature, branch)?;
+        let ref_name = format!("{HEADS_PREFIX}{branch}");
+        update_ref(&ref_name, &hash, read_ref(&ref_name)?.as_deref(), &signature, "test")?;
+        Ok(hash)
+    }
+
+    fn switch(target: &str, force: bool) -> anyhow::Result<()> {
+        switch_command(Some(target.to_string()), None, false, force, true, true)
+    }
+
+    #[test]
+    fn test_switch() -> anyhow::Result<()> {
+        let _guard = init_worktree_test("switch")?;
+        let one = commit_files("one", &[("a.txt", ObjectMode::Normal, "a\n"), ("dir/gone.txt", ObjectMode::Normal, "gone\n")], &[])?;
+        let two_files = [
+            ("a.txt", ObjectMode::Normal, "changed\n"),
+            ("link", ObjectMode::Symlink, "a.txt"),
+            ("run.sh", ObjectMode::Executable, "#!/bin/sh\n"),
+        ];
+        commit_files("two", &two_files, &[&one])?;
+
+        switch("one", false)?;
+        assert_eq!(Head::Branch("refs/heads/one".to_string()), read_head()?);
+        assert_eq!("a\n", fs::read_to_string("a.txt")?);
+        assert_eq!("gone\n", fs::read_to_string("dir/gone.txt")?);
+
+        // the files of the old commit that the new one does not have are removed, with their empty dirs
+        switch("two", false)?;
+        assert_eq!(Head::Branch("refs/heads/two".to_string()), read_head()?);
+        assert_eq!("changed\n", fs::read_to_string("a.txt")?);
+        assert!(!Path::new("dir").exists());
+        assert_eq!(Path::new("a.txt"), fs::read_link("link")?);
+        assert_ne!(0, fs::metadata("run.sh")?.permissions().mode() & 0o111);
+        let paths = Index::read()?.entries.into_iter().map(|x| (x.path, x.mode)).collect::<Vec<_>>();
+        let expected = two_files.iter().map(|(path, mode, _)| (path.to_string(), *mode)).collect::<Vec<_>>();
+        assert_eq!(expected, paths);
+
+        // a local change of a file that differs between the commits is not overwritten
+        fs::write("a.txt", "local\n")?;
+        assert!(switch("one", false).is_err());
+        assert_eq!(Head::Branch("refs/heads/two".to_string()), read_head()?);
+        assert_eq!("local\n", fs::read_to_string("a.txt")?);
+        switch("one", true)?;
+        assert_eq!("a\n", fs::read_to_string("a.txt")?);
+        assert!(!Path::new("link").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch_unborn() -> anyhow::Result<()> {
+        let _guard = init_worktree_test("switch-unborn")?;
+        // a new branch without a start point only moves HEAD, the branch is born with the first commit
+        switch_command(None, Some(("new".to_string(), false)), false, false, true, false)?;
+        assert_eq!(Head::Branch("refs/heads/new".to_string()), read_head()?);
+        assert_eq!((None, None), (read_ref("refs/heads/new")?, read_ref("refs/heads/main")?));
+        assert!(!Path::new(LOGS_PATH).exists());
+
+        // a start point has to be a commit
+        assert!(switch_command(Some("HEAD".to_string()), Some(("other".to_string(), false)), false, false, true, false).is_err());
+        assert!(switch_command(None, Some(("bad..name".to_string(), false)), false, false, true, false).is_err());
+        assert_eq!(Head::Branch("refs/heads/new".to_string()), read_head()?);
+
+        let one = commit_files("new", &[("a.txt", ObjectMode::Normal, "a\n")], &[])?;
+        switch_command(None, Some(("after".to_string(), false)), false, false, true, false)?;
+        assert_eq!((Head::Branch("refs/heads/after".to_string()), Some(one)), (read_head()?, read_ref("refs/heads/after")?));
+        Ok(())
+    }
+
+    fn reset(mode: ResetMode, args: &[&str], paths: &[&str]) -> anyhow::Result<()> {
+        let strings = |values: &[&str]| values.iter().map(|x| x.to_string()).collect();
+        reset_command(mode, true, strings(args), strings(paths))
+    }
+
+    fn index_tree() -> anyhow::Result<String> {
+        write_index_tree(&Index::read()?)
+    }
+
+    #[test]
+    fn test_reset() -> anyhow::Result<()> {
+        let _guard = init_worktree_test("reset")?;
+        let one = commit_files("main", &[("a.txt", ObjectMode::Normal, "a\n"), ("b.txt", ObjectMode::Normal, "b\n")], &[])?;
+        let two_files = [("a.txt", ObjectMode::Normal, "two\n"), ("b.txt", ObjectMode::Normal, "b\n"), ("c.txt", ObjectMode::Normal, "c\n")];
+        let two = commit_files("main", &two_files, &[&one])?;
+        let (one_tree, two_tree) = (peel(&one, ObjectType::Tree)?, peel(&two, ObjectType::Tree)?);
+
+        reset(ResetMode::Hard, &[], &[])?;
+        assert_eq!(two_tree, index_tree()?);
+        assert_eq!("two\n", fs::read_to_string("a.txt")?);
+
+        // soft only moves HEAD, the old one is kept in ORIG_HEAD
+        reset(ResetMode::Soft, &[&one], &[])?;
+        assert_eq!(Some(one.clone()), read_head_commit()?);
+        assert_eq!(two, resolve_revision("ORIG_HEAD")?);
+        assert_eq!(two_tree, index_tree()?);
+
+        // mixed also resets the index, but not the working tree
+        reset(ResetMode::Mixed, &[], &[])?;
+        assert_eq!(one, resolve_revision("ORIG_HEAD")?);
+        assert_eq!(one_tree, index_tree()?);
+        assert_eq!("two\n", fs::read_to_string("a.txt")?);
+        reset(ResetMode::Mixed, &[&two], &[])?;
+        assert_eq!((Some(two.clone()), two_tree.clone()), (read_head_commit()?, index_tree()?));
+
+        // paths are reset in the index only, HEAD stays
+        reset(ResetMode::Mixed, &[&one], &["a.txt"])?;
+        assert_eq!(Some(two.clone()), read_head_commit()?);
+        let index = Index::read()?;
+        let a = index.entries.iter().find(|x| x.path == "a.txt").unwrap();
+        assert_eq!(hash_object(&b"a\n"[..], ObjectType::Blob, 2, false)?, a.hash);
+        assert!(index.entries.iter().any(|x| x.path == "c.txt"));
+        reset(ResetMode::Mixed, &[], &["a.txt"])?;
+        assert_eq!(two_tree, index_tree()?);
+
+        // keep updates the files that differ between the commits and keeps the local changes of the others
+        fs::write("b.txt", "local b\n")?;
+        reset(ResetMode::Keep, &[&one], &[])?;
+        assert_eq!(Some(one.clone()), read_head_commit()?);
+        assert_eq!("a\n", fs::read_to_string("a.txt")?);
+        assert_eq!("local b\n", fs::read_to_string("b.txt")?);
+        assert!(!Path::new("c.txt").exists());
+        fs::write("a.txt", "local a\n")?;
+        assert!(reset(ResetMode::Keep, &[&two], &[]).is_err());
+        assert_eq!(Some(one.clone()), read_head_commit()?);
+
+        // hard drops all local changes
+        reset(ResetMode::Hard, &[&two], &[])?;
+        assert_eq!((Some(two), two_tree), (read_head_commit()?, index_tree()?));
+        assert_eq!(one, resolve_revision("ORIG_HEAD")?);
+        for (path, _, content) in two_files {
+            assert_eq!(content, fs::read_to_string(path)?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use crate::commit_object_read::{CommitObject, read_shallow_commits};
+
+const PARENT1: u8 = 1;
+const PARENT2: u8 = 2;
+const STALE: u8 = 4;
+const RESULT: u8 = 8;
+
+struct CommitNode {
+    parents: Vec<String>,
+    timestamp: i64,
+    /// one more than the highest generation of the parents, 1 for root commits. A commit can only be reached
+    /// from commits of higher generations, whatever their dates say
+    generation: u64,
+}
+
+/// The parents, dates and generations of the commits read by the walks, so that walks sharing a graph read each commit once
+#[derive(Default)]
+pub(crate) struct CommitGraph {
+    nodes: HashMap<String, CommitNode>,
+    /// the commits at the edge of a shallow clone, read with the first commit
+    shallow: Option<HashSet<String>>,
+}
+impl CommitGraph {
+    /// The best common ancestors of one commit and the hypothetical merge of the other commits, the common ancestors
+    /// that are not ancestors of other common ancestors. Newest commits come first
+    pub fn merge_bases(&mut self, one: &str, others: &[String]) -> anyhow::Result<Vec<String>> {
+        if others.iter().any(|x| x == one) {
+            return Ok(vec![one.to_string()]);
+        }
+        let (candidates, flags) = self.paint_down_to_common(one, others, 0)?;
+        // candidates reached from other candidates are not the best ones
+        let candidates = candidates.into_iter().filter(|x| flags[x] & STALE == 0).collect::<Vec<_>>();
+        let mut bases = self.remove_redundant(&candidates)?;
+        bases.sort_by_key(|x| -self.nodes[x].timestamp);
+        Ok(bases)
+    }
+
+    /// The merge bases of all the commits, found by merging them one after another
+    pub fn octopus_merge_bases(&mut self, commits: &[String]) -> anyhow::Result<Vec<String>> {
+        let Some((first, rest)) = commits.split_first() else {
+            return Ok(vec![]);
+        };
+        let mut result = vec![first.clone()];
+        for commit in rest {
+            let mut next = vec![];
+            for current in &result {
+                next.extend(self.merge_bases(commit, std::slice::from_ref(current))?);
+            }
+            result = next;
+        }
+        self.remove_redundant(&result)
+    }
+
+    pub fn is_ancestor(&mut self, ancestor: &str, descendant: &str) -> anyhow::Result<bool> {
+        self.is_ancestor_of_any(ancestor, &[descendant.to_string()])
+    }
+
+    /// Whether the commit can be reached from any of the others, which is when the walk down from them paints it.
+    /// Only the commits of higher generations can reach it, and the walk stops below its generation
+    fn is_ancestor_of_any(&mut self, ancestor: &str, descendants: &[String]) -> anyhow::Result<bool> {
+        if descendants.iter().any(|x| x == ancestor) {
+            return Ok(true);
+        }
+        let generation = self.node(ancestor)?.generation;
+        let mut candidates = vec![];
+        for descendant in descendants {
+            if self.node(descendant)?.generation > generation {
+                candidates.push(descendant.clone());
+            }
+        }
+        if candidates.is_empty() {
+            return Ok(false);
+        }
+        let (_, flags) = self.paint_down_to_common(ancestor, &candidates, generation)?;
+        Ok(flags[ancestor] & PARENT2 != 0)
+    }
+
+    /// Drops the commits that can be reached from the other ones, and duplicates, keeping the order
+    pub fn remove_redundant(&mut self, commits: &[String]) -> anyhow::Result<Vec<String>> {
+        let mut unique = vec![];
+        for commit in commits {
+            if !unique.contains(commit) {
+                unique.push(commit.clone());
+            }
+        }
+        let mut result = vec![];
+        for commit in &unique {
+            let others = unique.iter().filter(|x| *x != commit).cloned().collect::<Vec<_>>();
+            if others.is_empty() || !self.is_ancestor_of_any(commit, &others)? {
+                result.push(commit.clone());
+            }
+        }
+        Ok(result)
+    }
+
+    /// Walks down from all the commits, highest generations first and newest commit dates among equal generations,
+    /// marking which side reaches each commit. Commits reached from both sides are the candidates, and their ancestors
+    /// become stale. The walk stops when only stale commits are queued, or at the first commit below `min_generation`.
+    /// Returns the candidates with the marks of all the commits the walk reached
+    fn paint_down_to_common(&mut self, one: &str, others: &[String], min_generation: u64) -> anyhow::Result<(Vec<String>, HashMap<String, u8>)> {
+        let mut walk = PaintWalk::default();
+        walk.paint(one, PARENT1);
+        walk.push(self.node(one)?, one);
+        for other in others {
+            walk.paint(other, PARENT2);
+            walk.push(self.node(other)?, other);
+        }
+        let mut result = vec![];
+        while walk.nonstale > 0 {
+            let Some(hash) = walk.pop() else {
+                break;
+            };
+            if self.node(&hash)?.generation < min_generation {
+                break;
+            }
+            let own_flags = walk.flags[&hash];
+            let mut painted = own_flags & (PARENT1 | PARENT2 | STALE);
+            if painted == PARENT1 | PARENT2 {
+                if own_flags & RESULT == 0 {
+                    walk.flags.insert(hash.clone(), own_flags | RESULT);
+                    result.push(hash.clone());
+                }
+                painted |= STALE;
+            }
+            for parent in self.node(&hash)?.parents.clone() {
+                if walk.flags.get(&parent).is_some_and(|x| x & painted == painted) {
+                    continue;
+                }
+                walk.paint(&parent, painted);
+                walk.push(self.node(&parent)?, &parent);
+            }
+        }
+        Ok((result, walk.flags))
+    }
+
+    /// Reads the commit unless an earlier walk already did. Its generation needs the generations of all its ancestors,
+    /// which are read down to the root commits the first time
+    fn node(&mut self, hash: &str) -> anyhow::Result<&CommitNode> {
+        if self.shallow.is_none() {
+            self.shallow = Some(read_shallow_commits()?);
+        }
+        let mut read: HashMap<String, CommitObject> = HashMap::new();
+        let mut stack = vec![hash.to_string()];
+        while let Some(current) = stack.last() {
+            if self.nodes.contains_key(current) {
+                stack.pop();
+                continue;
+            }
+            if !read.contains_key(current) {
+                read.insert(current.clone(), CommitObject::read_cut(current, self.shallow.as_ref().unwrap())?);
+            }
+            let commit = &read[current];
+            let unknown = commit.parents.iter().filter(|x| !self.nodes.contains_key(*x)).cloned().collect::<Vec<_>>();
+            if !unknown.is_empty() {
+                stack.extend(unknown);
+                continue;
+            }
+            let commit = read.remove(current).unwrap();
+            let generation = commit.parents.iter().map(|x| self.nodes[x].generation).max().unwrap_or(0) + 1;
+            let node = CommitNode { parents: commit.parents, timestamp: commit.committer.timestamp, generation };
+            self.nodes.insert(stack.pop().unwrap(), node);
+        }
+        Ok(&self.nodes[hash])
+    }
+}
+
+/// The queue of a paint walk, highest generations first, then newest commit dates, then the earlier queued.
+/// A commit can be queued more than once, the number of queued entries of commits that are not stale ends the walk
+#[derive(Default)]
+struct PaintWalk {
+    queue: BinaryHeap<(u64, i64, Reverse<u64>, String)>,
+    flags: HashMap<String, u8>,
+    queued: HashMap<String, usize>,
+    pushed: u64,
+    nonstale: usize,
+}
+impl PaintWalk {
+    fn paint(&mut self, hash: &str, flags: u8) {
+        let old = self.flags.entry(hash.to_string()).or_default();
+        if *old & STALE == 0 && flags & STALE != 0 {
+            self.nonstale -= self.queued.get(hash).copied().unwrap_or(0);
+        }
+        *old |= flags;
+    }
+
+    fn push(&mut self, node: &CommitNode, hash: &str) {
+        *self.queued.entry(hash.to_string()).or_default() += 1;
+        if self.flags[hash] & STALE == 0 {
+            self.nonstale += 1;
+        }
+        self.queue.push((node.generation, node.timestamp, Reverse(self.pushed), hash.to_string()));
+        self.pushed += 1;
+    }
+
+    fn pop(&mut self) -> Option<String> {
+        let (_, _, _, hash) = self.queue.pop()?;
+        *self.queued.get_mut(&hash).unwrap() -= 1;
+        if self.flags[&hash] & STALE == 0 {
+            self.nonstale -= 1;
+        }
+        Some(hash)
+    }
+}
+
+/// The best common ancestors of two commits, newest first
+pub(crate) fn merge_bases(one: &str, two: &str) -> anyhow::Result<Vec<String>> {
+    CommitGraph::default().merge_bases(one, &[two.to_string()])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A graph of the commits, parents first, dated one after another
+    fn graph(commits: &[(&str, &[&str])]) -> CommitGraph {
+        let dated = (0..).zip(commits).map(|(timestamp, (hash, parents))| (*hash, *parents, timestamp)).collect::<Vec<_>>();
+        dated_graph(&dated)
+    }
+
+    fn dated_graph(commits: &[(&str, &[&str], i64)]) -> CommitGraph {
+        let mut nodes: HashMap<String, CommitNode> = HashMap::new();
+        for (hash, parents, timestamp) in commits {
+            let generation = parents.iter().map(|x| nodes[*x].generation).max().unwrap_or(0) + 1;
+            let parents = parents.iter().map(|x| x.to_string()).collect();
+            nodes.insert(hash.to_string(), CommitNode { parents, timestamp: *timestamp, generation });
+        }
+        CommitGraph { nodes, shallow: Some(HashSet::new()) }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_merge_bases() {
+        // a - b - c - d
+        //      \     /
+        //       e - f - g
+        let mut commits = graph(&[
+            ("a", &[]), ("b", &["a"]), ("c", &["b"]), ("e", &["b"]), ("f", &["e"]), ("d", &["c", "f"]), ("g", &["f"]),
+        ]);
+        assert_eq!(strings(&["f"]), commits.merge_bases("d", &strings(&["g"])).unwrap());
+        assert_eq!(strings(&["b"]), commits.merge_bases("c", &strings(&["g"])).unwrap());
+        assert_eq!(strings(&["c"]), commits.merge_bases("c", &strings(&["d"])).unwrap());
+        assert_eq!(strings(&["b"]), commits.octopus_merge_bases(&strings(&["d", "g", "c", "e"])).unwrap());
+        assert!(commits.is_ancestor("e", "d").unwrap());
+        assert!(!commits.is_ancestor("g", "d").unwrap());
+        assert_eq!(strings(&["c", "g"]), commits.remove_redundant(&strings(&["a", "c", "f", "g", "c"])).unwrap());
+    }
+
+    #[test]
+    fn test_criss_cross_merge_bases() {
+        // a - b - d - f
+        //   \   X
+        //     c - e - g
+        let mut commits = graph(&[
+            ("a", &[]), ("b", &["a"]), ("c", &["a"]), ("d", &["b", "c"]), ("e", &["c", "b"]), ("f", &["d"]), ("g", &["e"]),
+        ]);
+        assert_eq!(strings(&["c", "b"]), commits.merge_bases("f", &strings(&["g"])).unwrap());
+        assert_eq!(strings(&["a"]), commits.merge_bases("b", &strings(&["c"])).unwrap());
+    }
+
+    #[test]
+    fn test_skewed_dates() {
+        // a - b - c - d - e
+        //              //       f - g
+        // c and d claim to be older than everything else, like commits made with a wrong clock
+        let mut commits = dated_graph(&[
+            ("a", &[], 100), ("b", &["a"], 200), ("c", &["b"], 10), ("d", &["c"], 20), ("e", &["d"], 500),
+            ("f", &["b"], 300), ("g", &["f"], 400),
+        ]);
+        assert!(commits.is_ancestor("b", "e").unwrap());
+        assert!(commits.is_ancestor("c", "e").unwrap());
+        assert!(!commits.is_ancestor("f", "e").unwrap());
+        assert!(!commits.is_ancestor("e", "c").unwrap());
+        assert_eq!(strings(&["b"]), commits.merge_bases("e", &strings(&["g"])).unwrap());
+        assert_eq!(strings(&["d"]), commits.merge_bases("d", &strings(&["e"])).unwrap());
+        assert_eq!(strings(&["e", "g"]), commits.remove_redundant(&strings(&["c", "e", "g", "b"])).unwrap());
+
+        // the walk stops below the generation of the ancestor instead of going down to the root by the dates
+        let (_, flags) = commits.paint_down_to_common("f", &strings(&["e"]), commits.nodes["f"].generation).unwrap();
+        assert_eq!(0, flags["f"] & PARENT2);
+        assert!(!flags.contains_key("a"));
+        let (_, flags) = commits.paint_down_to_common("f", &strings(&["e"]), 0).unwrap();
+        assert!(flags.contains_key("a"));
+    }
+}
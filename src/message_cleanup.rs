@@ -0,0 +1,122 @@
+use clap::ValueEnum;
+
+pub(crate) const COMMENT_PREFIX: &str = "#";
+/// everything from this line on is dropped from an edited message with the scissors cleanup
+pub(crate) const SCISSORS_LINE: &str = "# ------------------------ >8 ------------------------";
+
+/// How a commit message is cleaned up before it is stored
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub(crate) enum CleanupMode {
+    /// Strip leading and trailing empty lines, trailing whitespace, repeated empty lines and comments
+    Strip,
+    /// Same as strip, except comments are kept
+    Whitespace,
+    /// Do not change the message at all
+    Verbatim,
+    /// Same as whitespace, except everything from the scissors line on is dropped when the message is edited
+    Scissors,
+    /// Strip if the message is edited, whitespace otherwise
+    Default,
+}
+impl CleanupMode {
+    /// The mode that is actually applied, the default and scissors modes depend on whether an editor is used
+    pub fn resolve(self, editing: bool) -> Self {
+        match (self, editing) {
+            (Self::Default, true) => Self::Strip,
+            (Self::Default | Self::Scissors, false) => Self::Whitespace,
+            (mode, _) => mode,
+        }
+    }
+}
+
+/// Cleans up a message according to an already resolved mode
+pub(crate) fn cleanup_message(message: &str, mode: CleanupMode) -> String {
+    match mode {
+        CleanupMode::Verbatim => message.to_string(),
+        CleanupMode::Strip => strip_space(message, true),
+        CleanupMode::Scissors => strip_space(truncate_at_scissors(message), false),
+        CleanupMode::Whitespace | CleanupMode::Default => strip_space(message, false),
+    }
+}
+
+/// Whether the message has no content, comments count as empty unless the message is kept verbatim
+pub(crate) fn is_message_empty(message: &str, mode: CleanupMode) -> bool {
+    if mode == CleanupMode::Verbatim && !message.is_empty() {
+        return false;
+    }
+    message.lines().all(|x| x.trim().is_empty() || x.starts_with(COMMENT_PREFIX))
+}
+
+/// Removes trailing whitespace from the lines and empty lines at the start and the end, and collapses repeated empty lines.
+/// Every line ends with a newline afterwards
+pub(crate) fn strip_space(text: &str, strip_comments: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut empty_lines = 0;
+    for line in text.lines() {
+        if strip_comments && line.starts_with(COMMENT_PREFIX) {
+            continue;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            empty_lines += 1;
+            continue;
+        }
+        if empty_lines > 0 && !result.is_empty() {
+            result.push('\n');
+        }
+        empty_lines = 0;
+        result.push_str(line);
+        result.push('\n');
+    }
+    result
+}
+
+/// Prefixes every line with a comment marker, followed by a space unless the line is empty or starts with a tab
+pub(crate) fn comment_lines(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for line in text.lines() {
+        result.push_str(COMMENT_PREFIX);
+        if !line.is_empty() && !line.starts_with('\t') {
+            result.push(' ');
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+    result
+}
+
+fn truncate_at_scissors(message: &str) -> &str {
+    let mut start = 0;
+    for line in message.split_inclusive('\n') {
+        if line.trim_end_matches('\n') == SCISSORS_LINE {
+            return &message[..start];
+        }
+        start += line.len();
+    }
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strip_space() {
+        assert_eq!("  a\n\n#c\n\tb\n", strip_space("\n\n  a  \n\n\n#c\n\tb\n\n", false));
+        assert_eq!("  a\n\n\tb\n", strip_space("\n\n  a  \n\n\n#c\n\tb\n\n", true));
+        assert_eq!("a\nb\n", strip_space("a\nb", false));
+        assert_eq!("", strip_space(" \n\t\n", false));
+        assert_eq!("# a\n#\n#\tb\n#  c\n", comment_lines("a\n\n\tb\n c"));
+    }
+
+    #[test]
+    fn test_cleanup_message() {
+        let message = format!("subject\n# comment\n\n\n{SCISSORS_LINE}\n# Do not modify\ndiff\n");
+        assert_eq!("subject\n\ndiff\n", cleanup_message(&message, CleanupMode::Default.resolve(true)));
+        assert_eq!("subject\n# comment\n", cleanup_message(&message, CleanupMode::Scissors.resolve(true)));
+        assert_eq!(message, cleanup_message(&message, CleanupMode::Verbatim));
+        assert!(is_message_empty("\n# comment\n  \n", CleanupMode::Whitespace));
+        assert!(!is_message_empty("\n# comment\n", CleanupMode::Verbatim));
+        assert!(!is_message_empty("# comment\ntext", CleanupMode::Strip));
+    }
+}
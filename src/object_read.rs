@@ -1,10 +1,13 @@
 use std::{fs, io};
 use std::fs::File;
-use std::io::{Read, BufReader, Write};
+use std::collections::BTreeMap;
+use std::io::{Read, BufReader, Cursor, Write};
 use std::io::prelude::*;
+use std::path::Path;
 use anyhow::{bail, Context};
 use flate2::read::ZlibDecoder;
-use crate::common::{get_hash_by_object_path, HASH_ENCODED_LEN, HASH_RAW_LEN, MAX_OBJECT_SIZE, MIN_OBJECT_SEARCH_LEN, OBJECT_DIR_LEN, OBJECTS_PATH, ObjectType};
+use crate::common::{get_hash_by_object_path, get_object_path_by_hash, HASH_ENCODED_LEN, HASH_RAW_LEN, MAX_OBJECT_SIZE, MIN_OBJECT_SEARCH_LEN, OBJECT_DIR_LEN, OBJECTS_PATH, ObjectType};
+use crate::pack::{find_packed_objects, PackedObject, read_packed_object};
 
 pub(crate) struct LazyDecodedObject<R: Read> {
     pub file_path: String,
@@ -23,7 +26,12 @@ impl<R: Read> LazyDecodedObject<R> {
         if !is_end_of_reader(reader) {
             bail!("content size is larger than expected {size}");
         }
-        return Ok((file_path, object_type, size));
+        Ok((file_path, object_type, size))
+    }
+    pub fn into_vec(self) -> anyhow::Result<(String, ObjectType, u64, Vec<u8>)> {
+        let mut vec = Vec::with_capacity(self.size as usize);
+        let (file_path, object_type, size) = self.drain_into_writer_raw(&mut vec)?;
+        Ok((file_path, object_type, size, vec))
     }
     pub fn destruct(self) -> (String, ObjectType, u64, R) {
         let Self {file_path, object_type, size, reader} = self;
@@ -39,7 +47,7 @@ impl<R: Read> LazyDecodedObject<R> {
 }
 
 pub(crate) fn validate_existing_hash(hash: &str, expected_type: ObjectType) -> anyhow::Result<String> {
-    let object = find_and_decode_object(&hash)?;
+    let object = find_and_decode_object(hash)?;
     if object.object_type != expected_type {
         bail!("Provided object {hash} is not a {}, it is actually a {}", expected_type.to_str(), object.object_type.to_str());
     }
@@ -48,56 +56,108 @@ pub(crate) fn validate_existing_hash(hash: &str, expected_type: ObjectType) -> a
 }
 
 pub(crate) fn find_and_decode_object(object: &str) -> anyhow::Result<LazyDecodedObject<impl BufRead>> {
-    let file_path = find_object_file(object)?;
-    let mut reader = get_compressed_file_reader(&file_path)?;
-    let object_type = read_object_type(&mut reader, &file_path)?;
-    let size = read_object_size(&mut reader, &file_path)?;
-    let res = LazyDecodedObject {
-        file_path,
-        object_type,
-        size,
-        reader,
-    };
-    Ok(res)
+    match find_object(object)? {
+        ObjectLocation::Loose(file_path) => {
+            let mut reader = get_compressed_file_reader(&file_path)?;
+            let object_type = read_object_type(&mut reader, &file_path)?;
+            let size = read_object_size(&mut reader, &file_path)?;
+            let reader = ObjectReader::Loose(reader);
+            Ok(LazyDecodedObject { file_path, object_type, size, reader })
+        },
+        ObjectLocation::Packed(packed) => {
+            let (object_type, data) = read_packed_object(&packed)?;
+            Ok(LazyDecodedObject {
+                file_path: get_object_path_by_hash(&packed.hash),
+                object_type,
+                size: data.len() as u64,
+                reader: ObjectReader::Packed(Cursor::new(data)),
+            })
+        },
+    }
+}
+
+/// Where the contents of an object are stored
+enum ObjectLocation {
+    Loose(String),
+    Packed(PackedObject),
+}
+
+/// Reads a loose object as it is decompressed, or a packed object that was already unpacked
+enum ObjectReader {
+    Loose(BufReader<ZlibDecoder<File>>),
+    Packed(Cursor<Vec<u8>>),
+}
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Loose(reader) => reader.read(buf),
+            Self::Packed(reader) => reader.read(buf),
+        }
+    }
+}
+impl BufRead for ObjectReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Self::Loose(reader) => reader.fill_buf(),
+            Self::Packed(reader) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        match self {
+            Self::Loose(reader) => reader.consume(amount),
+            Self::Packed(reader) => reader.consume(amount),
+        }
+    }
 }
 
-pub(crate) fn find_object_file(object: &str) -> anyhow::Result<String> {
+/// Finds the object with the hash or the unique object whose hash starts with the prefix, loose or in a pack
+fn find_object(object: &str) -> anyhow::Result<ObjectLocation> {
     let len = object.len();
-    if (len < MIN_OBJECT_SEARCH_LEN) || (len > HASH_ENCODED_LEN) {
+    if !(MIN_OBJECT_SEARCH_LEN..=HASH_ENCODED_LEN).contains(&len) {
         bail!("Invalid object name {object}");
     }
     let (dir, file_search) = object.split_at(OBJECT_DIR_LEN);
     let dir_path = format!("{OBJECTS_PATH}/{dir}/");
-
-    let mut found_name = None;
-    let dir_files = fs::read_dir(&dir_path).context(format!("Failed to read dir {dir_path}"))?;
-    for dir_entry in dir_files {
-        let dir_entry = dir_entry.context(format!("Some weird error while reading file name in {dir_path}"))?;
-        let file_name_os = dir_entry.file_name();
-        let Some(file_name) = file_name_os.to_str() else {
-            bail!("Failed to convert file name to str {file_name_os:?}");
-        };
-        if file_name.len() != (HASH_ENCODED_LEN - OBJECT_DIR_LEN) {
-            continue;
+    if len == HASH_ENCODED_LEN {
+        let file_path = format!("{dir_path}{file_search}");
+        if Path::new(&file_path).is_file() {
+            return Ok(ObjectLocation::Loose(file_path));
         }
-        if !file_name.starts_with(file_search) {
-            continue;
-        }
-        if found_name.is_none() {
-            found_name = Some(file_name.to_string());
-        } else {
-            bail!("Found multiple objects starting with {object}");
+    }
+
+    // the same object can be both loose and packed
+    let mut found = BTreeMap::new();
+    if Path::new(&dir_path).is_dir() {
+        let dir_files = fs::read_dir(&dir_path).context(format!("Failed to read dir {dir_path}"))?;
+        for dir_entry in dir_files {
+            let dir_entry = dir_entry.context(format!("Some weird error while reading file name in {dir_path}"))?;
+            let file_name_os = dir_entry.file_name();
+            let Some(file_name) = file_name_os.to_str() else {
+                bail!("Failed to convert file name to str {file_name_os:?}");
+            };
+            if file_name.len() != (HASH_ENCODED_LEN - OBJECT_DIR_LEN) {
+                continue;
+            }
+            if !file_name.starts_with(file_search) {
+                continue;
+            }
+            found.insert(format!("{dir}{file_name}"), ObjectLocation::Loose(format!("{dir_path}{file_name}")));
         }
     }
-    let Some(found_name) = found_name else {
+    for packed in find_packed_objects(object)? {
+        found.entry(packed.hash.clone()).or_insert(ObjectLocation::Packed(packed));
+    }
+    if found.len() > 1 {
+        bail!("Found multiple objects starting with {object}");
+    }
+    let Some((_, location)) = found.pop_first() else {
         bail!("Found no objects starting with {object}");
     };
-
-    let file_path = format!("{dir_path}{found_name}");
-    Ok(file_path)
+    Ok(location)
 }
 
-fn get_compressed_file_reader(file_path: &str) -> anyhow::Result<impl BufRead> {
+fn get_compressed_file_reader(file_path: &str) -> anyhow::Result<BufReader<ZlibDecoder<File>>> {
     let file = File::open(file_path).context(format!("Failed to open object file at {file_path}"))?;
     let decoder = ZlibDecoder::new(file);
     let reader = BufReader::new(decoder);
@@ -8,6 +8,7 @@ use anyhow::{bail, Context};
 use std::io::prelude::*;
 use std::path::Path;
 use crate::object_read::validate_existing_hash;
+use crate::commit_object_read::Signature;
 
 struct HashWriter<W: Write, H: Digest> {
     hasher: H,
@@ -26,7 +27,7 @@ impl<W: Write, H: Digest> Write for HashWriter<W, H> {
     }
 }
 
-static TEMPORARY_FILE: &'static str = ".git/temp_file";
+static TEMPORARY_FILE: &str = ".git/temp_file";
 
 pub(crate) fn hash_blob(path: &Path, write_file: bool) -> anyhow::Result<String> {
     let file = File::open(path).context(format!("Failed to open file at {}", path.display()))?;
@@ -34,29 +35,40 @@ pub(crate) fn hash_blob(path: &Path, write_file: bool) -> anyhow::Result<String>
     hash_object(file, ObjectType::Blob, meta.len(), write_file)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn hash_commit(tree: &str, parent: Option<&str>, message: &str, author: &str, email: &str, timestamp: u64, timezone: &str, write_file: bool) -> anyhow::Result<String> {
-    let data = create_commit_body(tree, parent, message, author, email, timestamp, timezone)?;
-    let hash = hash_object(data.as_bytes(), ObjectType::Commit, data.as_bytes().len() as u64, write_file)?;
+    let signature = Signature {
+        name: author.to_string(),
+        email: email.to_string(),
+        timestamp: timestamp as i64,
+        timezone: timezone.to_string(),
+    };
+    let parents = parent.into_iter().collect::<Vec<_>>();
+    let data = create_commit_body(tree, &parents, &signature, &signature, &format!("{message}\n"))?;
+    let hash = hash_object(data.as_bytes(), ObjectType::Commit, data.len() as u64, write_file)?;
     Ok(hash)
 }
 
-fn create_commit_body(tree: &str, parent: Option<&str>, message: &str, author: &str, email: &str, timestamp: u64, timezone: &str) -> anyhow::Result<String> {
+/// Writes a commit object, the message is stored as is, so it should already end with a newline
+pub(crate) fn write_commit(tree: &str, parents: &[&str], author: &Signature, committer: &Signature, message: &str) -> anyhow::Result<String> {
+    let data = create_commit_body(tree, parents, author, committer, message)?;
+    hash_object(data.as_bytes(), ObjectType::Commit, data.len() as u64, true)
+}
+
+fn create_commit_body(tree: &str, parents: &[&str], author: &Signature, committer: &Signature, message: &str) -> anyhow::Result<String> {
     let tree = validate_existing_hash(tree, ObjectType::Tree)?;
 
-    let parent_line = match parent {
-        Some(parent) => {
-            let parent = validate_existing_hash(parent, ObjectType::Commit)?;
-            format!("\nparent {parent}")
-        }
-        None => String::new(),
-    };
+    let mut parent_lines = String::new();
+    for parent in parents {
+        let parent = validate_existing_hash(parent, ObjectType::Commit)?;
+        parent_lines.push_str(&format!("parent {parent}\n"));
+    }
 
-    let data = format!("tree {tree}{parent_line}
-author {author} <{email}> {timestamp} {timezone}
-committer {author} <{email}> {timestamp} {timezone}
+    let data = format!("tree {tree}
+{parent_lines}author {author}
+committer {committer}
 
-{message}
-");
+{message}");
     Ok(data)
 }
 
@@ -113,7 +125,7 @@ mod test {
 
     #[test]
     fn test_hash_blob() -> anyhow::Result<()> {
-        init_test()?;
+        let _guard = init_test()?;
         let path = Path::new("data/data.txt");
         let hash = hash_blob(path, true)?;
         assert_eq!("bae42c55f9e0a4e297a4d197d8aadfe147ef269b", hash);
@@ -130,7 +142,7 @@ mod test {
 
     #[test]
     fn test_hash_commit() -> anyhow::Result<()> {
-        init_test()?;
+        let _guard = init_test()?;
         let path = Path::new(".");
         let tree = hash_tree(path, true)?.unwrap();
         assert_eq!("0b70d742c267c707ebd81d8968fc2e696a9e2edb", tree);
@@ -0,0 +1,950 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use anyhow::{bail, Context};
+use flate2::Compression;
+use flate2::Crc;
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use sha1::{Digest, Sha1};
+use crate::common::{HASH_RAW_LEN, MAX_OBJECT_SIZE, OBJECTS_PATH, ObjectType};
+use crate::commit_object_read::CommitObject;
+use crate::object_read::find_and_decode_object;
+use crate::pathspec::Pathspec;
+use crate::refs::{list_refs, TAGS_PREFIX};
+use crate::rev_list::{rev_list, RevListOptions};
+use crate::rev_parse::{peel_tags, read_object_type};
+use crate::tree_diff::list_tree_files;
+
+pub(crate) const PACK_PATH: &str = ".git/objects/pack";
+
+const PACK_SIGNATURE: &[u8] = b"PACK";
+const PACK_VERSION: u32 = 2;
+const PACK_HEADER_LEN: usize = 12;
+const INDEX_SIGNATURE: &[u8] = b"\xfftOc";
+const INDEX_VERSION: u32 = 2;
+const FANOUT_LEN: usize = 256;
+/// offsets with this bit set are positions in the table of 64-bit offsets
+const LARGE_OFFSET_FLAG: u32 = 0x8000_0000;
+
+const OFS_DELTA: u8 = 6;
+const REF_DELTA: u8 = 7;
+/// copy instructions of a delta with a size of 0 copy this many bytes
+const DEFAULT_COPY_SIZE: usize = 0x10000;
+/// the bytes a created delta copies at least, the base is indexed in blocks of this size
+const DELTA_BLOCK_LEN: usize = 16;
+const MAX_INSERT_LEN: usize = 0x7f;
+/// a copy instruction has three bytes for its size
+const MAX_COPY_LEN: usize = 0xff_ffff;
+
+thread_local! {
+    /// the indexes of the packs that were already read, pack names contain the hash of their contents,
+    /// so they never change
+    static LOADED_INDEXES: RefCell<HashMap<PathBuf, Rc<PackIndex>>> = RefCell::new(HashMap::new());
+}
+
+/// An object stored in a pack, found through the index of the pack
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PackedObject {
+    pub hash: String,
+    pub pack_path: PathBuf,
+    pub offset: u64,
+}
+
+/// The `.idx` file of a pack: the hashes of its objects, sorted, and where they start in the pack
+struct PackIndex {
+    pack_path: PathBuf,
+    hashes: Vec<[u8; HASH_RAW_LEN]>,
+    offsets: Vec<u64>,
+}
+impl PackIndex {
+    fn read(index_path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(index_path).context(format!("Failed to read {}", index_path.display()))?;
+        Self::parse(&data, index_path.with_extension("pack")).context(format!("Invalid pack index {}", index_path.display()))
+    }
+
+    fn parse(data: &[u8], pack_path: PathBuf) -> anyhow::Result<Self> {
+        let header_len = INDEX_SIGNATURE.len() + 4 + FANOUT_LEN * 4;
+        if data.len() < header_len + 2 * HASH_RAW_LEN || &data[..INDEX_SIGNATURE.len()] != INDEX_SIGNATURE {
+            bail!("unsupported index format");
+        }
+        let version = read_u32(data, INDEX_SIGNATURE.len());
+        if version != INDEX_VERSION {
+            bail!("unsupported index version {version}");
+        }
+        let count = read_u32(data, header_len - 4) as usize;
+        let hashes_start = header_len;
+        let offsets_start = hashes_start + count * (HASH_RAW_LEN + 4);
+        let large_offsets_start = offsets_start + count * 4;
+        if data.len() < large_offsets_start + 2 * HASH_RAW_LEN {
+            bail!("index is truncated");
+        }
+        let hashes = data[hashes_start..hashes_start + count * HASH_RAW_LEN]
+            .chunks_exact(HASH_RAW_LEN)
+            .map(|x| x.try_into().unwrap())
+            .collect();
+        let mut offsets = Vec::with_capacity(count);
+        for position in 0..count {
+            let offset = read_u32(data, offsets_start + position * 4);
+            if offset & LARGE_OFFSET_FLAG == 0 {
+                offsets.push(offset as u64);
+                continue;
+            }
+            let large_position = large_offsets_start + (offset & !LARGE_OFFSET_FLAG) as usize * 8;
+            let Some(large_offset) = data.get(large_position..large_position + 8) else {
+                bail!("invalid large offset for object {position}");
+            };
+            offsets.push(u64::from_be_bytes(large_offset.try_into().unwrap()));
+        }
+        Ok(Self { pack_path, hashes, offsets })
+    }
+
+    /// The objects whose hashes start with the hex prefix
+    fn find(&self, prefix: &str) -> Vec<PackedObject> {
+        // the smallest hash with the prefix, odd prefixes are completed with a zero
+        let mut lowest = [0; HASH_RAW_LEN];
+        let padded = format!("{prefix:0<width$}", width = prefix.len() + prefix.len() % 2);
+        if hex::decode_to_slice(&padded, &mut lowest[..padded.len() / 2]).is_err() {
+            return vec![];
+        }
+        let start = self.hashes.partition_point(|x| *x < lowest);
+        self.hashes[start..]
+            .iter()
+            .zip(&self.offsets[start..])
+            .map(|(hash, offset)| (hex::encode(hash), *offset))
+            .take_while(|(hash, _)| hash.starts_with(prefix))
+            .map(|(hash, offset)| PackedObject { hash, pack_path: self.pack_path.clone(), offset })
+            .collect()
+    }
+}
+
+/// Finds the packed objects whose hashes start with the hex prefix, in all packs of the repository
+pub(crate) fn find_packed_objects(prefix: &str) -> anyhow::Result<Vec<PackedObject>> {
+    let Ok(dir_iterator) = fs::read_dir(PACK_PATH) else {
+        return Ok(vec![]);
+    };
+    let mut index_paths = vec![];
+    for dir_entry in dir_iterator {
+        let dir_entry = dir_entry.context(format!("Some weird error while reading dir entry name in {PACK_PATH}"))?;
+        let path = dir_entry.path();
+        if path.extension().is_some_and(|x| x == "idx") && path.with_extension("pack").is_file() {
+            index_paths.push(path);
+        }
+    }
+    index_paths.sort_unstable();
+
+    let mut found = vec![];
+    for index_path in index_paths {
+        let loaded = LOADED_INDEXES.with(|x| x.borrow().get(&index_path).cloned());
+        let index = match loaded {
+            Some(index) => index,
+            None => {
+                let index = Rc::new(PackIndex::read(&index_path)?);
+                LOADED_INDEXES.with(|x| x.borrow_mut().insert(index_path, index.clone()));
+                index
+            },
+        };
+        found.extend(index.find(prefix));
+    }
+    Ok(found)
+}
+
+/// Reads a packed object, applying the deltas it is stored as
+pub(crate) fn read_packed_object(object: &PackedObject) -> anyhow::Result<(ObjectType, Vec<u8>)> {
+    let file = File::open(&object.pack_path).context(format!("Failed to open {}", object.pack_path.display()))?;
+    let mut reader = BufReader::new(file);
+    read_object_at(&mut reader, object.offset)
+        .context(format!("Failed to read object {} from {}", object.hash, object.pack_path.display()))
+}
+
+fn read_object_at(reader: &mut BufReader<File>, offset: u64) -> anyhow::Result<(ObjectType, Vec<u8>)> {
+    reader.seek(SeekFrom::Start(offset)).context("Failed to seek in the pack")?;
+    let mut header = [0; 32];
+    let header_len = reader.read(&mut header).context("Failed to read the object header")?;
+    let entry = parse_entry_header(&header[..header_len], offset)?;
+    reader.seek(SeekFrom::Start(offset + entry.data_offset as u64)).context("Failed to seek in the pack")?;
+    let data = inflate(&mut *reader, entry.size)?;
+    match entry.base {
+        EntryBase::None(object_type) => Ok((object_type, data)),
+        EntryBase::Offset(base_offset) => {
+            let (object_type, base) = read_object_at(reader, base_offset)?;
+            Ok((object_type, apply_delta(&base, &data)?))
+        },
+        EntryBase::Hash(base_hash) => {
+            let (_, object_type, _, base) = find_and_decode_object(&base_hash)?.into_vec()?;
+            Ok((object_type, apply_delta(&base, &data)?))
+        },
+    }
+}
+
+/// What the data of a pack entry is: an object of a type, or a delta against another object
+#[derive(Clone, Debug, PartialEq)]
+enum EntryBase {
+    None(ObjectType),
+    Offset(u64),
+    Hash(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct EntryHeader {
+    base: EntryBase,
+    /// the size of the inflated data, for deltas the size of the delta itself
+    size: usize,
+    /// where the compressed data starts, relative to the entry
+    data_offset: usize,
+}
+
+fn parse_entry_header(data: &[u8], offset: u64) -> anyhow::Result<EntryHeader> {
+    let mut bytes = data.iter().copied();
+    let mut next = || bytes.next().context("pack entry header is truncated");
+    let mut byte = next()?;
+    let kind = (byte >> 4) & 0b111;
+    let mut size = (byte & 0b1111) as usize;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = next()?;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if shift > 63 {
+            bail!("pack entry size is too large");
+        }
+    }
+    if size as u64 > MAX_OBJECT_SIZE {
+        bail!("Object size {size} is larger than max allowed size {MAX_OBJECT_SIZE}");
+    }
+    let base = match kind {
+        OFS_DELTA => {
+            // each continuation adds one, so that there is a single encoding of each distance
+            byte = next()?;
+            let mut distance = (byte & 0x7f) as u64;
+            while byte & 0x80 != 0 {
+                byte = next()?;
+                distance = ((distance + 1) << 7) | (byte & 0x7f) as u64;
+            }
+            if distance == 0 || distance > offset {
+                bail!("delta base offset is out of bound");
+            }
+            EntryBase::Offset(offset - distance)
+        },
+        REF_DELTA => {
+            let mut hash = [0; HASH_RAW_LEN];
+            for x in hash.iter_mut() {
+                *x = next()?;
+            }
+            EntryBase::Hash(hex::encode(hash))
+        },
+        1 => EntryBase::None(ObjectType::Commit),
+        2 => EntryBase::None(ObjectType::Tree),
+        3 => EntryBase::None(ObjectType::Blob),
+        4 => EntryBase::None(ObjectType::Tag),
+        kind => bail!("invalid object type {kind}"),
+    };
+    let data_offset = data.len() - bytes.len();
+    Ok(EntryHeader { base, size, data_offset })
+}
+
+fn inflate(reader: impl BufRead, size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size);
+    ZlibDecoder::new(reader)
+        .take(size as u64 + 1)
+        .read_to_end(&mut data)
+        .context("Failed to inflate pack entry")?;
+    if data.len() != size {
+        bail!("inflated size {} does not match the expected size {size}", data.len());
+    }
+    Ok(data)
+}
+
+fn read_delta_size(delta: &[u8], position: &mut usize) -> anyhow::Result<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*position).context("delta is truncated")?;
+        *position += 1;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Rebuilds an object from its base and a delta: a list of copies from the base and of inserted data
+pub(crate) fn apply_delta(base: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut position = 0;
+    let base_size = read_delta_size(delta, &mut position)?;
+    if base_size != base.len() {
+        bail!("delta base size {base_size} does not match the actual size {}", base.len());
+    }
+    let result_size = read_delta_size(delta, &mut position)?;
+    let mut result = Vec::with_capacity(result_size);
+    while let Some(&instruction) = delta.get(position) {
+        position += 1;
+        if instruction & 0x80 != 0 {
+            // the bits tell which bytes of the offset and the size are present
+            let mut values = [0usize; 2];
+            for (bit, value) in (0..7).map(|x| (x, if x < 4 { 0 } else { 1 })) {
+                if instruction & (1 << bit) != 0 {
+                    let byte = *delta.get(position).context("delta is truncated")?;
+                    position += 1;
+                    let shift = if bit < 4 { bit * 8 } else { (bit - 4) * 8 };
+                    values[value] |= (byte as usize) << shift;
+                }
+            }
+            let [offset, size] = values;
+            let size = if size == 0 { DEFAULT_COPY_SIZE } else { size };
+            let Some(copied) = base.get(offset..offset + size) else {
+                bail!("delta copies past the end of the base");
+            };
+            result.extend_from_slice(copied);
+        } else if instruction != 0 {
+            let size = instruction as usize;
+            let Some(inserted) = delta.get(position..position + size) else {
+                bail!("delta is truncated");
+            };
+            result.extend_from_slice(inserted);
+            position += size;
+        } else {
+            bail!("unexpected delta opcode 0");
+        }
+    }
+    if result.len() != result_size {
+        bail!("delta result size {} does not match the expected size {result_size}", result.len());
+    }
+    Ok(result)
+}
+
+/// What to pack besides the objects reachable from the included commits
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct PackObjectsOptions {
+    /// only pack this many commits of the history of each included commit
+    pub depth: Option<usize>,
+    /// do not pack the parents of these commits, where the history the receiver gets ends
+    pub shallow: Vec<String>,
+    /// also pack the annotated tags that point to packed objects
+    pub include_tag: bool,
+    /// store changed files as deltas against their versions in the excluded commits, which the receiver has
+    pub thin: bool,
+    /// leave out the blobs a partial clone does not want, unless they are included themselves
+    pub filter: Option<ObjectFilter>,
+}
+
+/// Which blobs a partial clone leaves out, like `--filter=blob:none`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ObjectFilter {
+    BlobNone,
+    /// only blobs smaller than this many bytes
+    BlobLimit(u64),
+}
+impl ObjectFilter {
+    /// Parses a filter spec, `blob:none` or `blob:limit=<n>[kmg]`
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        if spec == "blob:none" {
+            return Ok(Self::BlobNone);
+        }
+        let limit = spec.strip_prefix("blob:limit=").context(format!("invalid filter-spec '{spec}'"))?;
+        let (number, unit) = match limit.char_indices().last() {
+            Some((position, 'k' | 'K')) => (&limit[..position], 1 << 10),
+            Some((position, 'm' | 'M')) => (&limit[..position], 1 << 20),
+            Some((position, 'g' | 'G')) => (&limit[..position], 1 << 30),
+            _ => (limit, 1),
+        };
+        let number = number.parse::<u64>().ok().context(format!("invalid filter-spec '{spec}'"))?;
+        Ok(Self::BlobLimit(number.saturating_mul(unit)))
+    }
+
+    fn includes_blob(self, hash: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::BlobNone => Ok(false),
+            Self::BlobLimit(limit) => {
+                let (_, _, size, _) = find_and_decode_object(hash)?.destruct();
+                Ok(size < limit)
+            },
+        }
+    }
+}
+
+/// Packs the objects reachable from the included objects that are not reachable from the excluded ones, like git pack-objects
+pub(crate) fn pack_objects(include: &[String], exclude: &[String], options: &PackObjectsOptions) -> anyhow::Result<Vec<u8>> {
+    let rev_list_options = RevListOptions {
+        objects: true,
+        edges: options.thin,
+        depth: options.depth,
+        shallow: options.shallow.clone(),
+        ..RevListOptions::default()
+    };
+    let listed = rev_list(include, exclude, &rev_list_options)?;
+    let mut seen = HashSet::new();
+    let mut hashes = listed.commits.iter().map(|x| x.hash.clone()).filter(|x| seen.insert(x.clone())).collect::<Vec<_>>();
+    for object in &listed.objects {
+        if let Some(filter) = options.filter.filter(|_| object.object_type == ObjectType::Blob && !include.contains(&object.hash)) {
+            if !filter.includes_blob(&object.hash)? {
+                continue;
+            }
+        }
+        if seen.insert(object.hash.clone()) {
+            hashes.push(object.hash.clone());
+        }
+    }
+    if options.include_tag {
+        for (_, hash) in list_refs(TAGS_PREFIX)? {
+            if read_object_type(&hash)? == ObjectType::Tag && seen.contains(&peel_tags(&hash)?) && seen.insert(hash.clone()) {
+                hashes.push(hash);
+            }
+        }
+    }
+    // a file that changed since an edge commit is most likely similar to its version there
+    let mut edge_files = HashMap::new();
+    for edge in &listed.edges {
+        let tree = CommitObject::read(edge)?.tree;
+        for (path, side) in list_tree_files(Some(&tree), &Pathspec::default())? {
+            edge_files.entry(path).or_insert(side.hash);
+        }
+    }
+    let bases = listed.objects.iter()
+        .filter(|x| x.object_type == ObjectType::Blob && seen.contains(&x.hash))
+        .filter_map(|x| Some((x.hash.clone(), edge_files.get(&x.path)?.clone())))
+        .filter(|(hash, base)| hash != base)
+        .collect();
+    let mut pack = vec![];
+    write_pack(&hashes, &bases, &mut pack)?;
+    Ok(pack)
+}
+
+/// Encodes the target as a delta against the base: blocks found in the base are copied from it, the rest is inserted
+pub(crate) fn create_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = vec![];
+    encode_delta_size(base.len(), &mut delta);
+    encode_delta_size(target.len(), &mut delta);
+    let mut blocks = HashMap::new();
+    for offset in (0..base.len().saturating_sub(DELTA_BLOCK_LEN - 1)).step_by(DELTA_BLOCK_LEN) {
+        blocks.entry(&base[offset..offset + DELTA_BLOCK_LEN]).or_insert(offset);
+    }
+    let mut inserted = vec![];
+    let mut position = 0;
+    while position < target.len() {
+        let Some(&offset) = target.get(position..position + DELTA_BLOCK_LEN).and_then(|x| blocks.get(x)) else {
+            inserted.push(target[position]);
+            position += 1;
+            continue;
+        };
+        let mut len = DELTA_BLOCK_LEN;
+        while position + len < target.len() && offset + len < base.len() && base[offset + len] == target[position + len] && len < MAX_COPY_LEN {
+            len += 1;
+        }
+        encode_delta_insert(&mut inserted, &mut delta);
+        let mut instruction = vec![0x80];
+        for (bit, byte) in (offset as u32).to_le_bytes().into_iter().chain((len as u32).to_le_bytes().into_iter().take(3)).enumerate() {
+            if byte != 0 {
+                instruction[0] |= 1 << bit;
+                instruction.push(byte);
+            }
+        }
+        delta.extend(instruction);
+        position += len;
+    }
+    encode_delta_insert(&mut inserted, &mut delta);
+    delta
+}
+
+fn encode_delta_size(mut size: usize, delta: &mut Vec<u8>) {
+    while size >= 0x80 {
+        delta.push((size & 0x7f) as u8 | 0x80);
+        size >>= 7;
+    }
+    delta.push(size as u8);
+}
+
+/// Adds the bytes as insert instructions, which insert at most 127 bytes each
+fn encode_delta_insert(inserted: &mut Vec<u8>, delta: &mut Vec<u8>) {
+    for chunk in inserted.chunks(MAX_INSERT_LEN) {
+        delta.push(chunk.len() as u8);
+        delta.extend(chunk);
+    }
+    inserted.clear();
+}
+
+/// Writes a pack of the objects. The ones with a base are stored as deltas against it if that is smaller,
+/// the bases are not in the pack, so the receiver must have them
+pub(crate) fn write_pack(hashes: &[String], bases: &HashMap<String, String>, writer: impl Write) -> anyhow::Result<()> {
+    let mut writer = PackWriter { writer, hasher: Sha1::new() };
+    writer.write(PACK_SIGNATURE)?;
+    writer.write(&PACK_VERSION.to_be_bytes())?;
+    writer.write(&(hashes.len() as u32).to_be_bytes())?;
+    for hash in hashes {
+        let (_, object_type, size, data) = find_and_decode_object(hash)?.into_vec()?;
+        let delta = match bases.get(hash) {
+            Some(base) => {
+                let (_, _, _, base_data) = find_and_decode_object(base)?.into_vec()?;
+                Some((base, create_delta(&base_data, &data))).filter(|(_, delta)| delta.len() < data.len())
+            },
+            None => None,
+        };
+        let data = match delta {
+            Some((base, delta)) => {
+                writer.write(&encode_header(REF_DELTA, delta.len() as u64))?;
+                writer.write(&hex::decode(base).context(format!("Invalid hash {base}"))?)?;
+                delta
+            },
+            None => {
+                writer.write(&encode_entry_header(object_type, size))?;
+                data
+            },
+        };
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&data).context(format!("Failed to compress object {hash}"))?;
+        writer.write(&encoder.finish().context(format!("Failed to compress object {hash}"))?)?;
+    }
+    let checksum = writer.hasher.finalize();
+    writer.writer.write_all(&checksum).context("Failed to write pack checksum")?;
+    writer.writer.flush().context("Failed to flush the pack")
+}
+
+struct PackWriter<W: Write> {
+    writer: W,
+    hasher: Sha1,
+}
+impl<W: Write> PackWriter<W> {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.hasher.update(data);
+        self.writer.write_all(data).context("Failed to write pack data")
+    }
+}
+
+fn encode_entry_header(object_type: ObjectType, size: u64) -> Vec<u8> {
+    let kind = match object_type {
+        ObjectType::Commit => 1,
+        ObjectType::Tree => 2,
+        ObjectType::Blob => 3,
+        ObjectType::Tag => 4,
+    };
+    encode_header(kind, size)
+}
+
+/// The header of a pack entry, the kind of the entry and the size of its data
+fn encode_header(kind: u8, size: u64) -> Vec<u8> {
+    let mut header = vec![(kind << 4) | (size & 0b1111) as u8];
+    let mut rest = size >> 4;
+    while rest != 0 {
+        *header.last_mut().unwrap() |= 0x80;
+        header.push((rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    header
+}
+
+/// Reads one pack from a stream and stops right after its checksum, for a stream that goes on or stays open
+/// after the pack, like the one of receive-pack. The pack is only checked as far as it takes to find its end
+pub(crate) fn read_pack_stream(reader: &mut impl BufRead) -> anyhow::Result<Vec<u8>> {
+    let mut recorder = RecordingReader { reader, recorded: vec![] };
+    let mut header = [0; PACK_HEADER_LEN];
+    recorder.read_exact(&mut header).context("early EOF")?;
+    if &header[..PACK_SIGNATURE.len()] != PACK_SIGNATURE {
+        bail!("protocol error: bad pack header");
+    }
+    let count = read_u32(&header, 8);
+    for _ in 0..count {
+        let mut byte = read_byte(&mut recorder)?;
+        let kind = (byte >> 4) & 0b111;
+        while byte & 0x80 != 0 {
+            byte = read_byte(&mut recorder)?;
+        }
+        match kind {
+            OFS_DELTA => {
+                byte = read_byte(&mut recorder)?;
+                while byte & 0x80 != 0 {
+                    byte = read_byte(&mut recorder)?;
+                }
+            },
+            REF_DELTA => recorder.read_exact(&mut [0; HASH_RAW_LEN]).context("early EOF")?,
+            _ => {},
+        }
+        // the decoder only consumes the compressed data, so the next entry starts right after it
+        io::copy(&mut ZlibDecoder::new(&mut recorder), &mut io::sink()).context("Failed to inflate pack entry")?;
+    }
+    recorder.read_exact(&mut [0; HASH_RAW_LEN]).context("early EOF")?;
+    Ok(recorder.recorded)
+}
+
+fn read_byte(reader: &mut impl Read) -> anyhow::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte).context("early EOF")?;
+    Ok(byte[0])
+}
+
+/// Keeps a copy of everything consumed from the reader
+struct RecordingReader<'a, R> {
+    reader: &'a mut R,
+    recorded: Vec<u8>,
+}
+impl<R: BufRead> Read for RecordingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+impl<R: BufRead> BufRead for RecordingReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if let Ok(available) = self.reader.fill_buf() {
+            self.recorded.extend_from_slice(&available[..amount]);
+        }
+        self.reader.consume(amount);
+    }
+}
+
+/// An entry of a pack being indexed, with its data inflated
+struct IndexedEntry {
+    offset: u64,
+    crc: u32,
+    base: EntryBase,
+    data: Vec<u8>,
+    /// the type and the hash, once the deltas are resolved
+    resolved: Option<(ObjectType, String)>,
+}
+
+/// Checks a received pack, then stores it with an index of its objects in the pack directory, like git index-pack.
+/// Delta bases must be in the pack or already in the repository, those are appended to the stored pack like
+/// index-pack --fix-thin does. Returns the hash of the stored pack
+pub(crate) fn index_pack(pack: &[u8]) -> anyhow::Result<String> {
+    if pack.len() < PACK_HEADER_LEN + HASH_RAW_LEN || &pack[..PACK_SIGNATURE.len()] != PACK_SIGNATURE {
+        bail!("protocol error: bad pack header");
+    }
+    let version = read_u32(pack, 4);
+    if version != 2 && version != 3 {
+        bail!("pack version {version} unsupported");
+    }
+    let (contents, checksum) = pack.split_at(pack.len() - HASH_RAW_LEN);
+    if Sha1::digest(contents).as_slice() != checksum {
+        bail!("pack is corrupted (SHA1 mismatch)");
+    }
+    let count = read_u32(pack, 8) as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    let mut position = PACK_HEADER_LEN;
+    for _ in 0..count {
+        let offset = position as u64;
+        let header = parse_entry_header(&contents[position..], offset).context(format!("Invalid pack entry at offset {offset}"))?;
+        let mut decoder = ZlibDecoder::new(&contents[position + header.data_offset..]);
+        let mut data = Vec::with_capacity(header.size);
+        decoder.read_to_end(&mut data).context(format!("Failed to inflate pack entry at offset {offset}"))?;
+        if data.len() != header.size {
+            bail!("inflated size of the pack entry at offset {offset} does not match its header");
+        }
+        let end = position + header.data_offset + decoder.total_in() as usize;
+        let mut crc = Crc::new();
+        crc.update(&contents[position..end]);
+        entries.push(IndexedEntry { offset, crc: crc.sum(), base: header.base, data, resolved: None });
+        position = end;
+    }
+    if position != contents.len() {
+        bail!("pack has junk at the end");
+    }
+
+    let positions = entries.iter().enumerate().map(|(i, x)| (x.offset, i)).collect::<HashMap<_, _>>();
+    for i in 0..entries.len() {
+        resolve_entry(&mut entries, i, &positions)?;
+    }
+    // deltas against objects that are only known by their hash, their bases may be deltas themselves
+    loop {
+        let hashes = entries.iter()
+            .enumerate()
+            .filter_map(|(i, x)| x.resolved.as_ref().map(|(_, hash)| (hash.clone(), i)))
+            .collect::<HashMap<_, _>>();
+        let mut progress = false;
+        for i in 0..entries.len() {
+            if entries[i].resolved.is_some() {
+                continue;
+            }
+            let EntryBase::Hash(base_hash) = &entries[i].base else {
+                continue;
+            };
+            let base = match hashes.get(base_hash) {
+                Some(&base_position) => {
+                    let (object_type, _) = entries[base_position].resolved.clone().unwrap();
+                    Some((object_type, entries[base_position].data.clone()))
+                },
+                None => None,
+            };
+            if let Some((object_type, base)) = base {
+                resolve_delta(&mut entries, i, object_type, &base)?;
+                progress = true;
+            }
+        }
+        if !progress {
+            break;
+        }
+        for i in 0..entries.len() {
+            resolve_entry(&mut entries, i, &positions)?;
+        }
+    }
+    // a thin pack has deltas against objects of the repository, which are appended to make the pack complete
+    let mut appended: Vec<(String, ObjectType, Vec<u8>)> = vec![];
+    for i in 0..entries.len() {
+        if entries[i].resolved.is_some() {
+            continue;
+        }
+        let EntryBase::Hash(base_hash) = entries[i].base.clone() else {
+            continue;
+        };
+        let (_, object_type, _, base) = find_and_decode_object(&base_hash)
+            .and_then(|x| x.into_vec())
+            .context(format!("pack has unresolved delta against {base_hash}"))?;
+        resolve_delta(&mut entries, i, object_type, &base)?;
+        if !appended.iter().any(|(hash, _, _)| *hash == base_hash) {
+            appended.push((base_hash, object_type, base));
+        }
+        for j in 0..entries.len() {
+            resolve_entry(&mut entries, j, &positions)?;
+        }
+    }
+
+    let mut objects = entries.iter()
+        .map(|x| {
+            let (_, hash) = x.resolved.as_ref().unwrap();
+            (hash.clone(), x.crc, x.offset)
+        })
+        .collect::<Vec<_>>();
+    let mut pack = pack.to_vec();
+    if !appended.is_empty() {
+        pack.truncate(contents.len());
+        pack[8..12].copy_from_slice(&((count + appended.len()) as u32).to_be_bytes());
+        for (hash, object_type, data) in appended {
+            let mut entry = encode_entry_header(object_type, data.len() as u64);
+            let mut encoder = ZlibEncoder::new(entry, Compression::default());
+            encoder.write_all(&data).context(format!("Failed to compress object {hash}"))?;
+            entry = encoder.finish().context(format!("Failed to compress object {hash}"))?;
+            let mut crc = Crc::new();
+            crc.update(&entry);
+            objects.push((hash, crc.sum(), pack.len() as u64));
+            pack.extend(entry);
+        }
+        let checksum = Sha1::digest(&pack);
+        pack.extend(checksum);
+    }
+    let checksum = &pack[pack.len() - HASH_RAW_LEN..];
+    let pack_hash = hex::encode(checksum);
+    let mut objects = objects.into_iter()
+        .map(|(hash, crc, offset)| {
+            let mut raw = [0; HASH_RAW_LEN];
+            hex::decode_to_slice(hash, &mut raw).unwrap();
+            (raw, crc, offset)
+        })
+        .collect::<Vec<_>>();
+    objects.sort_unstable();
+    if objects.windows(2).any(|x| x[0].0 == x[1].0) {
+        bail!("pack contains duplicate objects");
+    }
+
+    fs::create_dir_all(PACK_PATH).context(format!("Failed to create {PACK_PATH}"))?;
+    let pack_path = format!("{PACK_PATH}/pack-{pack_hash}.pack");
+    let temporary_path = format!("{OBJECTS_PATH}/tmp_pack_{pack_hash}");
+    fs::write(&temporary_path, &pack).context(format!("Failed to write {temporary_path}"))?;
+    fs::rename(&temporary_path, &pack_path).context(format!("Failed to move the pack to {pack_path}"))?;
+    let index_path = format!("{PACK_PATH}/pack-{pack_hash}.idx");
+    let index = encode_index(&objects, checksum);
+    fs::write(&temporary_path, index).context(format!("Failed to write {temporary_path}"))?;
+    fs::rename(&temporary_path, &index_path).context(format!("Failed to move the pack index to {index_path}"))?;
+    Ok(pack_hash)
+}
+
+/// Resolves an entry whose base is found by its offset in the pack, the data of the entry becomes the object itself
+fn resolve_entry(entries: &mut [IndexedEntry], i: usize, positions: &HashMap<u64, usize>) -> anyhow::Result<()> {
+    if entries[i].resolved.is_some() {
+        return Ok(());
+    }
+    match entries[i].base.clone() {
+        EntryBase::None(object_type) => {
+            let hash = hash_data(object_type, &entries[i].data);
+            entries[i].resolved = Some((object_type, hash));
+        },
+        EntryBase::Offset(base_offset) => {
+            let Some(&base_position) = positions.get(&base_offset) else {
+                bail!("delta base offset {base_offset} is not the start of an entry");
+            };
+            if base_position >= i {
+                bail!("delta base of the entry at offset {} comes after it", entries[i].offset);
+            }
+            resolve_entry(entries, base_position, positions)?;
+            let Some((object_type, _)) = entries[base_position].resolved.clone() else {
+                return Ok(());
+            };
+            let base = std::mem::take(&mut entries[base_position].data);
+            let result = resolve_delta(entries, i, object_type, &base);
+            entries[base_position].data = base;
+            result?;
+        },
+        EntryBase::Hash(_) => {},
+    }
+    Ok(())
+}
+
+fn resolve_delta(entries: &mut [IndexedEntry], i: usize, object_type: ObjectType, base: &[u8]) -> anyhow::Result<()> {
+    let data = apply_delta(base, &entries[i].data).context(format!("Failed to apply the delta at offset {}", entries[i].offset))?;
+    entries[i].resolved = Some((object_type, hash_data(object_type, &data)));
+    entries[i].data = data;
+    Ok(())
+}
+
+fn hash_data(object_type: ObjectType, data: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{object_type} {}\0", data.len()));
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+/// Version 2 of the index format: the fanout table, the sorted hashes, the CRCs of the entries and their offsets
+fn encode_index(objects: &[([u8; HASH_RAW_LEN], u32, u64)], pack_checksum: &[u8]) -> Vec<u8> {
+    let mut index = vec![];
+    index.extend_from_slice(INDEX_SIGNATURE);
+    index.extend_from_slice(&INDEX_VERSION.to_be_bytes());
+    for first_byte in 0..FANOUT_LEN {
+        let count = objects.partition_point(|(hash, _, _)| (hash[0] as usize) <= first_byte);
+        index.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+    for (hash, _, _) in objects {
+        index.extend_from_slice(hash);
+    }
+    for (_, crc, _) in objects {
+        index.extend_from_slice(&crc.to_be_bytes());
+    }
+    let mut large_offsets = vec![];
+    for (_, _, offset) in objects {
+        let offset = match u32::try_from(*offset) {
+            Ok(offset) if offset & LARGE_OFFSET_FLAG == 0 => offset,
+            _ => {
+                large_offsets.push(*offset);
+                LARGE_OFFSET_FLAG | (large_offsets.len() - 1) as u32
+            },
+        };
+        index.extend_from_slice(&offset.to_be_bytes());
+    }
+    for offset in large_offsets {
+        index.extend_from_slice(&offset.to_be_bytes());
+    }
+    index.extend_from_slice(pack_checksum);
+    let checksum = Sha1::digest(&index);
+    index.extend_from_slice(&checksum);
+    index
+}
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    u32::from_be_bytes(data[position..position + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::init_test;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_index_pack() -> anyhow::Result<()> {
+        let _guard = init_test()?;
+        let base = b"hello world";
+        let base_hash = hash_data(ObjectType::Blob, base);
+        let offset_delta = [11, 16, 0x90, 6, 5, b't', b'h', b'e', b'r', b'e', 0x91, 6, 5];
+        let hash_delta = [11, 11, 0x91, 6, 5, 1, b' ', 0x90, 5];
+
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x03".to_vec();
+        pack.extend(encode_entry_header(ObjectType::Blob, base.len() as u64));
+        pack.extend(compress(base));
+        let distance = pack.len() - PACK_HEADER_LEN;
+        pack.extend([OFS_DELTA << 4 | offset_delta.len() as u8, distance as u8]);
+        pack.extend(compress(&offset_delta));
+        pack.push(REF_DELTA << 4 | hash_delta.len() as u8);
+        pack.extend(hex::decode(&base_hash)?);
+        pack.extend(compress(&hash_delta));
+        let checksum = Sha1::digest(&pack);
+        pack.extend(checksum);
+
+        assert_eq!(hex::encode(checksum), index_pack(&pack)?);
+        let expected = [(ObjectType::Blob, b"hello thereworld".to_vec()), (ObjectType::Blob, b"world hello".to_vec())];
+        for (object_type, data) in expected {
+            let packed = find_packed_objects(&hash_data(object_type, &data))?;
+            assert_eq!(1, packed.len());
+            assert_eq!((object_type, data), read_packed_object(&packed[0])?);
+        }
+        pack[PACK_HEADER_LEN + 1] ^= 1;
+        assert!(index_pack(&pack).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_pack_stream() -> anyhow::Result<()> {
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x02".to_vec();
+        pack.extend(encode_entry_header(ObjectType::Blob, 11));
+        pack.extend(compress(b"hello world"));
+        let distance = pack.len() - PACK_HEADER_LEN;
+        pack.extend([OFS_DELTA << 4 | 9, distance as u8]);
+        pack.extend(compress(&[11, 11, 0x91, 6, 5, 1, b' ', 0x90, 5]));
+        pack.extend(Sha1::digest(&pack));
+        let mut stream = pack.clone();
+        stream.extend(b"0000");
+        let mut reader = BufReader::with_capacity(7, stream.as_slice());
+        assert_eq!(pack, read_pack_stream(&mut reader)?);
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest)?;
+        assert_eq!(b"0000".to_vec(), rest);
+        assert!(read_pack_stream(&mut &pack[..pack.len() - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_filter() -> anyhow::Result<()> {
+        assert_eq!(ObjectFilter::BlobNone, ObjectFilter::parse("blob:none")?);
+        assert_eq!(ObjectFilter::BlobLimit(100), ObjectFilter::parse("blob:limit=100")?);
+        assert_eq!(ObjectFilter::BlobLimit(2048), ObjectFilter::parse("blob:limit=2k")?);
+        assert!(ObjectFilter::parse("tree:0").is_err());
+        assert!(ObjectFilter::parse("blob:limit=k").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_delta() -> anyhow::Result<()> {
+        let base = b"hello world";
+        // copy "hello " from offset 0, insert "there", copy "world" from offset 6
+        let delta = [11, 16, 0x90, 6, 5, b't', b'h', b'e', b'r', b'e', 0x91, 6, 5];
+        assert_eq!(b"hello thereworld".to_vec(), apply_delta(base, &delta)?);
+        assert!(apply_delta(b"short", &delta).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_delta() -> anyhow::Result<()> {
+        let base = (0..200).map(|x| format!("line {x}\n")).collect::<String>();
+        let target = base.replace("line 100\n", "changed\n") + "appended";
+        let delta = create_delta(base.as_bytes(), target.as_bytes());
+        assert!(delta.len() < target.len() / 10);
+        assert_eq!(target.as_bytes(), apply_delta(base.as_bytes(), &delta)?);
+        assert_eq!(b"new".to_vec(), apply_delta(b"", &create_delta(b"", b"new"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_header() -> anyhow::Result<()> {
+        let header = encode_entry_header(ObjectType::Blob, 1000);
+        let parsed = parse_entry_header(&header, 0)?;
+        assert_eq!(EntryHeader { base: EntryBase::None(ObjectType::Blob), size: 1000, data_offset: 2 }, parsed);
+        // a distance of 128 takes two bytes, the continuation adds one to the first one
+        let parsed = parse_entry_header(&[OFS_DELTA << 4 | 5, 0x80, 0x00], 200)?;
+        assert_eq!(EntryHeader { base: EntryBase::Offset(72), size: 5, data_offset: 3 }, parsed);
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+/// Limits commands to a set of paths. A pattern matches the path itself and everything inside it
+/// if it is a directory, patterns with `*`, `?` or `[` are matched as globs against the whole path.
+/// An empty pathspec matches everything.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Pathspec {
+    patterns: Vec<String>,
+}
+impl Pathspec {
+    pub fn new(patterns: &[String]) -> Self {
+        let patterns = patterns.iter()
+            .map(|x| x.trim_start_matches("./").trim_end_matches('/').to_string())
+            .map(|x| if x == "." { String::new() } else { x })
+            .collect();
+        Self { patterns }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.patterns.is_empty() || self.patterns.iter().any(|x| pattern_matches(x, path))
+    }
+
+    /// Whether the directory or anything inside it can match, used to skip walking unrelated trees
+    pub fn matches_dir(&self, dir: &str) -> bool {
+        if self.matches(dir) {
+            return true;
+        }
+        let dir_prefix = format!("{dir}/");
+        self.patterns.iter().any(|pattern| {
+            match pattern.find(is_glob_special) {
+                None => pattern.starts_with(&dir_prefix),
+                // a glob can match inside the directory if their literal prefixes agree
+                Some(literal_end) => dir_prefix.starts_with(&pattern[..literal_end]) || pattern[..literal_end].starts_with(&dir_prefix),
+            }
+        })
+    }
+}
+
+fn is_glob_special(char: char) -> bool {
+    matches!(char, '*' | '?' | '[')
+}
+
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    if pattern.is_empty() {
+        return true;
+    }
+    if let Some(rest) = path.strip_prefix(pattern) {
+        if rest.is_empty() || rest.starts_with('/') {
+            return true;
+        }
+    }
+    pattern.contains(is_glob_special) && glob_matches(pattern.as_bytes(), path.as_bytes())
+}
+
+/// Shell like matching where `*` also matches slashes, the same way git matches pathspecs by default.
+/// A mismatch goes back to the last `*` and lets it take one more char, so patterns with many stars
+/// stay linear in the number of stars times the length of the text
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut pattern_index, mut text_index) = (0, 0);
+    // the pattern after the last star and the position in the text it was tried at
+    let mut star = None;
+    while text_index < text.len() {
+        if pattern.get(pattern_index) == Some(&b'*') {
+            pattern_index += 1;
+            star = Some((pattern_index, text_index));
+        } else if let Some(len) = char_matches(&pattern[pattern_index..], text[text_index]) {
+            pattern_index += len;
+            text_index += 1;
+        } else if let Some((star_pattern_index, star_text_index)) = star {
+            pattern_index = star_pattern_index;
+            text_index = star_text_index + 1;
+            star = Some((star_pattern_index, text_index));
+        } else {
+            return false;
+        }
+    }
+    pattern[pattern_index..].iter().all(|x| *x == b'*')
+}
+
+/// Whether the first `?`, `[class]` or literal of the pattern matches the char, returns the length it takes in the pattern
+fn char_matches(pattern: &[u8], char: u8) -> Option<usize> {
+    let (first, rest) = pattern.split_first()?;
+    match first {
+        b'?' => Some(1),
+        b'[' => {
+            let Some(class_len) = rest.iter().skip(1).position(|x| *x == b']').map(|x| x + 1) else {
+                return (*first == char).then_some(1);
+            };
+            let (negated, class) = match rest[..class_len].split_first() {
+                Some((b'!' | b'^', class)) => (true, class),
+                _ => (false, &rest[..class_len]),
+            };
+            let mut matched = false;
+            let mut index = 0;
+            while index < class.len() {
+                if index + 2 < class.len() && class[index + 1] == b'-' {
+                    matched |= (class[index]..=class[index + 2]).contains(&char);
+                    index += 3;
+                } else {
+                    matched |= class[index] == char;
+                    index += 1;
+                }
+            }
+            (matched != negated).then_some(class_len + 2)
+        },
+        _ => (*first == char).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pathspec() {
+        let pathspec = Pathspec::new(&["src/".to_string(), "docs/*.md".to_string(), "t?st[0-9].txt".to_string()]);
+        assert!(pathspec.matches("src"));
+        assert!(pathspec.matches("src/main.rs"));
+        assert!(!pathspec.matches("src2/main.rs"));
+        assert!(pathspec.matches("docs/readme.md"));
+        assert!(pathspec.matches("docs/a/b.md"));
+        assert!(!pathspec.matches("docs/readme.txt"));
+        assert!(pathspec.matches("test1.txt"));
+        assert!(!pathspec.matches("testa.txt"));
+
+        assert!(pathspec.matches_dir("docs"));
+        assert!(pathspec.matches_dir("docs/a"));
+        assert!(!pathspec.matches_dir("other"));
+        assert!(Pathspec::new(&["a/b/c".to_string()]).matches_dir("a/b"));
+        assert!(!Pathspec::new(&["a/b/c".to_string()]).matches_dir("a/bc"));
+
+        assert!(Pathspec::new(&[]).matches("any/path"));
+        assert!(Pathspec::new(&[".".to_string()]).matches("any/path"));
+
+        // the stars go back to the last one instead of trying every split of the text
+        assert!(Pathspec::new(&["*a*b*c".to_string()]).matches("xaybzc"));
+        assert!(!Pathspec::new(&["*a*b*c".to_string()]).matches("xaybzcd"));
+        assert!(Pathspec::new(&["a*[!x]".to_string()]).matches("a/b/y"));
+        assert!(!Pathspec::new(&["a*".repeat(30) + "b"]).matches(&"a".repeat(100)));
+    }
+}
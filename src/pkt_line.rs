@@ -0,0 +1,554 @@
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use anyhow::{bail, Context};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// the longest packet, including its 4 length bytes
+pub(crate) const MAX_PACKET_LEN: usize = 65520;
+pub(crate) const MAX_PACKET_DATA_LEN: usize = MAX_PACKET_LEN - LENGTH_LEN;
+/// the longest packet of the old side-band capability, side-band-64k allows `MAX_PACKET_LEN`
+pub(crate) const SMALL_SIDEBAND_PACKET_LEN: usize = 1000;
+
+const LENGTH_LEN: usize = 4;
+const FLUSH_PACKET: &[u8] = b"0000";
+const DELIM_PACKET: &[u8] = b"0001";
+const RESPONSE_END_PACKET: &[u8] = b"0002";
+const REMOTE_PREFIX: &[u8] = b"remote: ";
+
+/// One pkt-line: data prefixed with its length as 4 hex digits, or one of the special packets
+/// whose lengths are too short to hold data
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Packet {
+    /// `0000`, ends a message
+    Flush,
+    /// `0001`, separates the sections of a message in protocol v2
+    Delim,
+    /// `0002`, ends a response in stateless protocol v2
+    ResponseEnd,
+    Data(Vec<u8>),
+}
+impl Packet {
+    /// The data of a packet that holds a line of text, without its trailing newline
+    pub fn text(&self) -> Option<String> {
+        match self {
+            Self::Data(data) => Some(text_line(data)),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::Flush => Ok(FLUSH_PACKET.to_vec()),
+            Self::Delim => Ok(DELIM_PACKET.to_vec()),
+            Self::ResponseEnd => Ok(RESPONSE_END_PACKET.to_vec()),
+            Self::Data(data) => {
+                if data.len() > MAX_PACKET_DATA_LEN {
+                    bail!("protocol error: impossibly long line");
+                }
+                let mut packet = format!("{:04x}", data.len() + LENGTH_LEN).into_bytes();
+                packet.extend_from_slice(data);
+                Ok(packet)
+            },
+        }
+    }
+}
+
+/// The text of a packet that holds a line, without its trailing newline
+pub(crate) fn text_line(data: &[u8]) -> String {
+    String::from_utf8_lossy(data.strip_suffix(b"\n").unwrap_or(data)).into_owned()
+}
+
+/// What the length of a packet says about it: a special packet, or how much data follows
+fn decode_length(header: [u8; LENGTH_LEN]) -> anyhow::Result<Result<Packet, usize>> {
+    let length = std::str::from_utf8(&header).ok()
+        .filter(|x| x.bytes().all(|x| x.is_ascii_hexdigit()))
+        .and_then(|x| usize::from_str_radix(x, 16).ok())
+        .context(format!("protocol error: bad line length character: {}", String::from_utf8_lossy(&header)))?;
+    match length {
+        0 => Ok(Ok(Packet::Flush)),
+        1 => Ok(Ok(Packet::Delim)),
+        2 => Ok(Ok(Packet::ResponseEnd)),
+        LENGTH_LEN..=MAX_PACKET_LEN => Ok(Err(length - LENGTH_LEN)),
+        _ => bail!("protocol error: bad line length {length}"),
+    }
+}
+
+fn hung_up(error: io::Error) -> anyhow::Error {
+    match error.kind() {
+        ErrorKind::UnexpectedEof => anyhow::anyhow!("the remote end hung up unexpectedly"),
+        _ => anyhow::Error::new(error).context("Failed to read from the remote"),
+    }
+}
+
+/// The channels of a sideband: each data packet starts with the number of the band it belongs to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Band {
+    /// the pack data
+    Data = 1,
+    /// progress messages to show to the user
+    Progress = 2,
+    /// an error message, the remote stops after it
+    Error = 3,
+}
+
+pub(crate) fn split_band(data: &[u8]) -> anyhow::Result<(Band, &[u8])> {
+    match data.split_first() {
+        Some((1, rest)) => Ok((Band::Data, rest)),
+        Some((2, rest)) => Ok((Band::Progress, rest)),
+        Some((3, rest)) => Ok((Band::Error, rest)),
+        Some((band, _)) => bail!("protocol error: bad band #{band}"),
+        None => bail!("protocol error: no band designator"),
+    }
+}
+
+/// The data packets that send the data on a band, split to fit packets of at most `max_packet_len`
+pub(crate) fn band_packets(band: Band, data: &[u8], max_packet_len: usize) -> impl Iterator<Item = Packet> + '_ {
+    data.chunks(max_packet_len - LENGTH_LEN - 1).map(move |x| {
+        let mut packet = Vec::with_capacity(x.len() + 1);
+        packet.push(band as u8);
+        packet.extend_from_slice(x);
+        Packet::Data(packet)
+    })
+}
+
+/// What to do with the data of a sideband packet: the data band is returned, the others are handled here
+fn demultiplex(data: &[u8], progress: &mut impl Write) -> anyhow::Result<Option<Vec<u8>>> {
+    match split_band(data)? {
+        (Band::Data, data) => Ok(Some(data.to_vec())),
+        (Band::Progress, message) => {
+            progress.write_all(message).context("Failed to write progress")?;
+            progress.flush().context("Failed to write progress")?;
+            Ok(None)
+        },
+        (Band::Error, message) => bail!("remote error: {}", String::from_utf8_lossy(message).trim_end()),
+    }
+}
+
+pub(crate) struct PacketReader<R> {
+    reader: R,
+}
+impl<R: Read> PacketReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    /// The next packet, None at the end of the input
+    pub fn read_packet(&mut self) -> anyhow::Result<Option<Packet>> {
+        let mut header = [0; LENGTH_LEN];
+        let read = read_fully(&mut self.reader, &mut header).map_err(hung_up)?;
+        match read {
+            0 => return Ok(None),
+            LENGTH_LEN => {},
+            _ => bail!("the remote end hung up unexpectedly"),
+        }
+        match decode_length(header)? {
+            Ok(packet) => Ok(Some(packet)),
+            Err(length) => {
+                let mut data = vec![0; length];
+                self.reader.read_exact(&mut data).map_err(hung_up)?;
+                Ok(Some(Packet::Data(data)))
+            },
+        }
+    }
+
+    /// The data of the packets up to the next special packet, which is returned with it
+    pub fn read_until_special(&mut self) -> anyhow::Result<(Vec<Vec<u8>>, Packet)> {
+        let mut data = vec![];
+        loop {
+            match self.read_packet()? {
+                Some(Packet::Data(packet)) => data.push(packet),
+                Some(packet) => return Ok((data, packet)),
+                None => bail!("the remote end hung up unexpectedly"),
+            }
+        }
+    }
+
+    /// The data band of the sideband that follows, progress messages are written to `progress`
+    pub fn sideband<P: Write>(self, progress: P) -> SidebandReader<R, P> {
+        SidebandReader { packets: self, progress, buffer: vec![], position: 0, done: false }
+    }
+
+    /// The underlying reader, positioned right after the last packet read
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Like `read_exact`, but an end right at the start is not an error: how much was read is returned
+fn read_fully(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(count) => read += count,
+            Err(error) if error.kind() == ErrorKind::Interrupted => {},
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(read)
+}
+
+pub(crate) struct PacketWriter<W> {
+    writer: W,
+}
+impl<W: Write> PacketWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn write_packet(&mut self, packet: &Packet) -> anyhow::Result<()> {
+        self.writer.write_all(&packet.encode()?).context("Failed to write to the remote")
+    }
+
+    pub fn write_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.write_packet(&Packet::Data(data.to_vec()))
+    }
+
+    /// Writes a line of text, with the newline git puts at the end of text packets
+    pub fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.write_data(format!("{line}\n").as_bytes())
+    }
+
+    /// Writes a flush packet and sends everything written so far
+    pub fn write_flush(&mut self) -> anyhow::Result<()> {
+        self.write_packet(&Packet::Flush)?;
+        self.flush()
+    }
+
+    pub fn write_delim(&mut self) -> anyhow::Result<()> {
+        self.write_packet(&Packet::Delim)
+    }
+
+    // a stateless v2 response is ended by the helper that relays it, which none of the transports is
+    #[allow(dead_code)]
+    pub fn write_response_end(&mut self) -> anyhow::Result<()> {
+        self.write_packet(&Packet::ResponseEnd)?;
+        self.flush()
+    }
+
+    /// Writes data on a band of a sideband, in as many packets as it takes
+    pub fn write_band(&mut self, band: Band, data: &[u8], max_packet_len: usize) -> anyhow::Result<()> {
+        for packet in band_packets(band, data, max_packet_len) {
+            self.write_packet(&packet)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush().context("Failed to write to the remote")
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Sends what is written to it on a band of a sideband, like the messages a server shows to its client
+pub(crate) struct BandWriter<'a, W> {
+    writer: &'a mut PacketWriter<W>,
+    band: Band,
+    max_packet_len: usize,
+}
+impl<'a, W: Write> BandWriter<'a, W> {
+    pub fn new(writer: &'a mut PacketWriter<W>, band: Band, max_packet_len: usize) -> Self {
+        Self { writer, band, max_packet_len }
+    }
+}
+impl<W: Write> Write for BandWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write_band(self.band, buf, self.max_packet_len).map_err(|error| io::Error::other(format!("{error:#}")))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush().map_err(|error| io::Error::other(format!("{error:#}")))
+    }
+}
+
+/// The data band of a sideband as a stream, which ends at the flush packet that ends the sideband.
+/// An error message on the error band is returned as an error.
+pub(crate) struct SidebandReader<R, P> {
+    packets: PacketReader<R>,
+    progress: P,
+    buffer: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+impl<R: Read, P: Write> SidebandReader<R, P> {
+    fn fill_buffer(&mut self) -> anyhow::Result<()> {
+        while self.position == self.buffer.len() && !self.done {
+            match self.packets.read_packet()? {
+                Some(Packet::Data(data)) => {
+                    if let Some(data) = demultiplex(&data, &mut self.progress)? {
+                        self.buffer = data;
+                        self.position = 0;
+                    }
+                },
+                Some(Packet::Flush) | None => self.done = true,
+                Some(packet) => bail!("protocol error: unexpected {packet:?} packet in sideband"),
+            }
+        }
+        Ok(())
+    }
+}
+impl<R: Read, P: Write> Read for SidebandReader<R, P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill_buffer().map_err(|error| io::Error::other(format!("{error:#}")))?;
+        let count = buf.len().min(self.buffer.len() - self.position);
+        buf[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// Shows the messages of a remote the way git does, each line prefixed with `remote: `
+pub(crate) struct RemoteMessages<W> {
+    writer: W,
+    at_line_start: bool,
+}
+impl<W: Write> RemoteMessages<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, at_line_start: true }
+    }
+}
+impl<W: Write> Write for RemoteMessages<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // progress lines are rewritten in place with `\r`, so they start a line just like `\n` does
+        for line in buf.split_inclusive(|x| *x == b'\n' || *x == b'\r') {
+            if self.at_line_start {
+                self.writer.write_all(REMOTE_PREFIX)?;
+            }
+            self.writer.write_all(line)?;
+            self.at_line_start = line.ends_with(b"\n") || line.ends_with(b"\r");
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub(crate) struct AsyncPacketReader<R> {
+    reader: R,
+}
+impl<R: AsyncRead + Unpin> AsyncPacketReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    /// The next packet, None at the end of the input
+    pub async fn read_packet(&mut self) -> anyhow::Result<Option<Packet>> {
+        let mut header = [0; LENGTH_LEN];
+        let mut read = 0;
+        while read < LENGTH_LEN {
+            match self.reader.read(&mut header[read..]).await.map_err(hung_up)? {
+                0 if read == 0 => return Ok(None),
+                0 => bail!("the remote end hung up unexpectedly"),
+                count => read += count,
+            }
+        }
+        match decode_length(header)? {
+            Ok(packet) => Ok(Some(packet)),
+            Err(length) => {
+                let mut data = vec![0; length];
+                self.reader.read_exact(&mut data).await.map_err(hung_up)?;
+                Ok(Some(Packet::Data(data)))
+            },
+        }
+    }
+
+    /// The data of the packets up to the next special packet, which is returned with it
+    pub async fn read_until_special(&mut self) -> anyhow::Result<(Vec<Vec<u8>>, Packet)> {
+        let mut data = vec![];
+        loop {
+            match self.read_packet().await? {
+                Some(Packet::Data(packet)) => data.push(packet),
+                Some(packet) => return Ok((data, packet)),
+                None => bail!("the remote end hung up unexpectedly"),
+            }
+        }
+    }
+
+    /// The next data of the data band of a sideband, None at the flush packet that ends it.
+    /// Progress messages are written to `progress`.
+    pub async fn read_sideband(&mut self, progress: &mut impl Write) -> anyhow::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_packet().await? {
+                Some(Packet::Data(data)) => {
+                    if let Some(data) = demultiplex(&data, progress)? {
+                        return Ok(Some(data));
+                    }
+                },
+                Some(Packet::Flush) | None => return Ok(None),
+                Some(packet) => bail!("protocol error: unexpected {packet:?} packet in sideband"),
+            }
+        }
+    }
+
+    /// All the data of the data band of a sideband
+    pub async fn read_sideband_to_end(&mut self, progress: &mut impl Write) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![];
+        while let Some(chunk) = self.read_sideband(progress).await? {
+            data.extend(chunk);
+        }
+        Ok(data)
+    }
+}
+
+pub(crate) struct AsyncPacketWriter<W> {
+    writer: W,
+}
+impl<W: AsyncWrite + Unpin> AsyncPacketWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub async fn write_packet(&mut self, packet: &Packet) -> anyhow::Result<()> {
+        self.writer.write_all(&packet.encode()?).await.context("Failed to write to the remote")
+    }
+
+    pub async fn write_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.write_packet(&Packet::Data(data.to_vec())).await
+    }
+
+    /// Writes a line of text, with the newline git puts at the end of text packets
+    pub async fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.write_data(format!("{line}\n").as_bytes()).await
+    }
+
+    /// Writes a flush packet and sends everything written so far
+    pub async fn write_flush(&mut self) -> anyhow::Result<()> {
+        self.write_packet(&Packet::Flush).await?;
+        self.flush().await
+    }
+
+    // the async side serves the daemon and the http backend, which hand v2 sections and responses over to upload-pack
+    #[allow(dead_code)]
+    pub async fn write_delim(&mut self) -> anyhow::Result<()> {
+        self.write_packet(&Packet::Delim).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn write_response_end(&mut self) -> anyhow::Result<()> {
+        self.write_packet(&Packet::ResponseEnd).await?;
+        self.flush().await
+    }
+
+    /// Writes data on a band of a sideband, in as many packets as it takes
+    pub async fn write_band(&mut self, band: Band, data: &[u8], max_packet_len: usize) -> anyhow::Result<()> {
+        for packet in band_packets(band, data, max_packet_len) {
+            self.write_packet(&packet).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush().await.context("Failed to write to the remote")
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packets() -> anyhow::Result<()> {
+        let mut writer = PacketWriter::new(vec![]);
+        writer.write_line("want 1234")?;
+        writer.write_delim()?;
+        writer.write_data(b"")?;
+        writer.write_flush()?;
+        writer.write_response_end()?;
+        let data = writer.into_inner();
+        assert_eq!(b"000ewant 1234\n000100040000".to_vec(), data[..data.len() - 4].to_vec());
+
+        let mut reader = PacketReader::new(data.as_slice());
+        let (lines, end) = reader.read_until_special()?;
+        assert_eq!((vec![b"want 1234\n".to_vec()], Packet::Delim), (lines, end));
+        assert_eq!(Some(Packet::Data(vec![])), reader.read_packet()?);
+        assert_eq!(Some(Packet::Flush), reader.read_packet()?);
+        assert_eq!(Some(Packet::ResponseEnd), reader.read_packet()?);
+        assert_eq!(None, reader.read_packet()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_packets() {
+        for data in [b"0003".as_slice(), b"fff1", b"00g5", b"000", b"0009abc"] {
+            assert!(PacketReader::new(data).read_packet().is_err());
+        }
+        assert!(Packet::Data(vec![0; MAX_PACKET_DATA_LEN + 1]).encode().is_err());
+        assert_eq!(MAX_PACKET_LEN, Packet::Data(vec![0; MAX_PACKET_DATA_LEN]).encode().unwrap().len());
+    }
+
+    #[test]
+    fn test_sideband() -> anyhow::Result<()> {
+        let mut writer = PacketWriter::new(vec![]);
+        writer.write_band(Band::Progress, b"Counting: 1\rCounting: 2, done.\n", MAX_PACKET_LEN)?;
+        writer.write_band(Band::Data, b"PACK data", 8)?;
+        writer.write_flush()?;
+        let data = writer.into_inner();
+        let mut progress = vec![];
+        let mut pack = vec![];
+        PacketReader::new(data.as_slice()).sideband(RemoteMessages::new(&mut progress)).read_to_end(&mut pack)?;
+        assert_eq!(b"PACK data".to_vec(), pack);
+        assert_eq!(b"remote: Counting: 1\rremote: Counting: 2, done.\n".to_vec(), progress);
+
+        let mut writer = PacketWriter::new(vec![]);
+        writer.write_band(Band::Error, b"access denied\n", MAX_PACKET_LEN)?;
+        let data = writer.into_inner();
+        let error = PacketReader::new(data.as_slice()).sideband(io::sink()).read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!("remote error: access denied", error.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_async_packets() -> anyhow::Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let mut writer = AsyncPacketWriter::new(vec![]);
+            writer.write_line("ls-refs").await?;
+            writer.write_band(Band::Data, b"PACK", MAX_PACKET_LEN).await?;
+            writer.write_band(Band::Progress, b"done.\n", SMALL_SIDEBAND_PACKET_LEN).await?;
+            writer.write_band(Band::Data, b" data", SMALL_SIDEBAND_PACKET_LEN).await?;
+            writer.write_flush().await?;
+            let data = writer.into_inner();
+
+            let mut reader = AsyncPacketReader::new(data.as_slice());
+            assert_eq!(Some("ls-refs".to_string()), reader.read_packet().await?.and_then(|x| x.text()));
+            let mut progress = vec![];
+            assert_eq!(b"PACK data".to_vec(), reader.read_sideband_to_end(&mut progress).await?);
+            assert_eq!(b"done.\n".to_vec(), progress);
+            assert_eq!(None, reader.read_packet().await?);
+
+            // the sections of a v2 response, and an error that ends a sideband
+            let mut writer = AsyncPacketWriter::new(vec![]);
+            writer.write_line("acknowledgments").await?;
+            writer.write_delim().await?;
+            writer.write_line("packfile").await?;
+            writer.write_band(Band::Progress, b"Counting objects\r", MAX_PACKET_LEN).await?;
+            writer.write_band(Band::Error, b"upload-pack: not our ref\n", MAX_PACKET_LEN).await?;
+            writer.write_response_end().await?;
+            let data = writer.into_inner();
+
+            let mut reader = AsyncPacketReader::new(data.as_slice());
+            assert_eq!((vec![b"acknowledgments\n".to_vec()], Packet::Delim), reader.read_until_special().await?);
+            assert_eq!(Some("packfile".to_string()), reader.read_packet().await?.and_then(|x| x.text()));
+            let mut progress = vec![];
+            let error = reader.read_sideband(&mut progress).await.unwrap_err();
+            assert_eq!("remote error: upload-pack: not our ref", error.to_string());
+            assert_eq!(b"Counting objects\r".to_vec(), progress);
+            assert_eq!(Some(Packet::ResponseEnd), reader.read_packet().await?);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,466 @@
+use anyhow::{bail, Context};
+use crate::cli::PushFlags;
+use crate::common::{Exit, ObjectType};
+use crate::config::Config;
+use crate::diff::abbreviate;
+use crate::fetch::{has_object, pretty_ref_name, SUMMARY_WIDTH, TAGS_REFSPEC};
+use crate::ident::{read_ident_or_default, Role};
+use crate::merge_base::CommitGraph;
+use crate::pack::{pack_objects, PackObjectsOptions};
+use crate::receive_pack::{ReceivePackOptions, RefUpdate};
+use crate::refs::{delete_ref, dwim_ref, Head, HEADS_PREFIX, list_refs, read_head, read_ref, TAGS_PREFIX, update_ref};
+use crate::refspec::Refspec;
+use crate::remote::Remote;
+use crate::rev_parse::{peel, read_object_type, resolve_revision};
+use crate::smart_http::Service;
+use crate::transport::Transport;
+use crate::upload_pack::AdvertisedRef;
+
+const FAST_FORWARD_NOTE: &str = "See the 'Note about fast-forwards' in 'git push --help' for details.";
+
+/// A ref of the remote that a push creates, updates or deletes
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PushedRef {
+    /// the local ref the value comes from without its prefix or the revision as given, empty for a deletion
+    pub source: String,
+    /// the full name of the ref on the remote
+    pub name: String,
+    /// the value on the remote, None if the ref does not exist there
+    pub old: Option<String>,
+    /// None to delete the ref
+    pub new: Option<String>,
+    /// update the ref even if it is not a fast-forward
+    pub force: bool,
+    /// --force-with-lease: the value the ref must have on the remote, None if it must not exist
+    pub expect: Option<Option<String>>,
+    pub status: PushStatus,
+}
+impl PushedRef {
+    pub fn update(&self) -> RefUpdate {
+        RefUpdate { name: self.name.clone(), old: self.old.clone(), new: self.new.clone() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum PushStatus {
+    UpToDate,
+    /// the update is sent, forced if it does not fast-forward the ref
+    Ok { forced: bool },
+    /// refused before anything is sent, with the reason
+    Rejected(&'static str),
+    /// refused by the remote, with its reason
+    RemoteRejected(String),
+}
+
+/// The refspecs a push without refspecs uses: remote.<name>.push, or the current branch depending on push.default
+pub(crate) fn default_push_refspecs(remote: &Remote, config: &Config) -> anyhow::Result<Vec<Refspec>> {
+    if !remote.push.is_empty() {
+        return Ok(remote.push.clone());
+    }
+    let remote_name = remote.name.as_deref().unwrap_or(&remote.url);
+    let mode = config.get("push.default").unwrap_or("simple");
+    match mode {
+        "nothing" => bail!("You didn't specify any refspecs to push, and push.default is \"nothing\"."),
+        "matching" => return Ok(vec![Refspec::parse(":")?]),
+        "current" | "simple" | "upstream" | "tracking" => {},
+        _ => bail!("malformed value for push.default: {mode}"),
+    }
+    let Head::Branch(ref_name) = read_head()? else {
+        bail!("You are not currently on a branch.\nTo push the history leading to the current (detached HEAD)\n\
+            state now, use\n\n    git push {remote_name} HEAD:<name-of-remote-branch>\n");
+    };
+    let branch = ref_name.strip_prefix(HEADS_PREFIX).unwrap_or(&ref_name);
+    let branch_remote = config.get(&format!("branch.{branch}.remote")).unwrap_or("origin");
+    // pushing to another remote than the one the branch merges from pushes it under its own name
+    let triangular = remote.name.as_deref() != Some(branch_remote);
+    if mode == "current" || (mode == "simple" && triangular) {
+        return Ok(vec![Refspec::parse(&format!("{ref_name}:{ref_name}"))?]);
+    }
+    if triangular {
+        bail!("You are pushing to remote '{remote_name}', which is not the upstream of\n\
+            your current branch '{branch}', without telling me what to push\nto update which remote branch.");
+    }
+    let Some(merge) = config.get(&format!("branch.{branch}.merge")) else {
+        bail!("The current branch {branch} has no upstream branch.\n\
+            To push the current branch and set the remote as upstream, use\n\n    git push --set-upstream {remote_name} {branch}\n\n\
+            To have this happen automatically for branches without a tracking\n\
+            upstream, see 'push.autoSetupRemote' in 'git help config'.\n");
+    };
+    if mode == "simple" && merge != ref_name {
+        let upstream = merge.strip_prefix(HEADS_PREFIX).unwrap_or(merge);
+        bail!("The upstream branch of your current branch does not match\n\
+            the name of your current branch.  To push to the upstream branch\non the remote, use\n\n    \
+            git push {remote_name} HEAD:{upstream}\n\nTo push to the branch of the same name on the remote, use\n\n    \
+            git push {remote_name} HEAD\n\nTo choose either option permanently, see push.default in 'git help config'.\n\n\
+            To avoid automatically configuring an upstream branch when its name\n\
+            won't match the local branch, see option 'simple' of branch.autoSetupMerge\nin 'git help config'.\n");
+    }
+    Ok(vec![Refspec::parse(&format!("{ref_name}:{merge}"))?])
+}
+
+/// The refs of the remote the refspecs update, refs the remote has first in its order, then the new ones
+pub(crate) fn match_push_refs(refspecs: &[Refspec], remote_refs: &[AdvertisedRef]) -> anyhow::Result<Vec<PushedRef>> {
+    let mut pushed: Vec<PushedRef> = vec![];
+    let mut add = |source: String, name: String, new: Option<String>, force: bool| {
+        if pushed.iter().all(|x| x.name != name) {
+            let old = remote_refs.iter().find(|x| x.name == name).map(|x| x.hash.clone());
+            pushed.push(PushedRef { source, name, old, new, force, expect: None, status: PushStatus::UpToDate });
+        }
+    };
+    for refspec in refspecs.iter().filter(|x| !x.negative) {
+        if refspec.src.is_empty() && refspec.dst.is_none() {
+            // `:` pushes the branches that exist on both sides
+            for (name, hash) in list_refs(HEADS_PREFIX)? {
+                if remote_refs.iter().any(|x| x.name == name) {
+                    add(pretty_ref_name(&name).to_string(), name, Some(hash), refspec.force);
+                }
+            }
+            continue;
+        }
+        if refspec.is_pattern() {
+            for (name, hash) in list_refs(refspec.source_prefix())? {
+                let excluded = refspecs.iter().any(|x| x.negative && x.matches_source(&name));
+                match refspec.dst.as_ref().map_or(Some(name.clone()), |_| refspec.destination(&name)) {
+                    Some(dst) if !excluded => add(pretty_ref_name(&name).to_string(), dst, Some(hash), refspec.force),
+                    _ => {},
+                }
+            }
+            continue;
+        }
+        if refspec.src.is_empty() {
+            let dst = refspec.dst.as_deref().unwrap_or_default();
+            let name = match_remote_ref(dst, remote_refs)?.context(format!("unable to delete '{dst}': remote ref does not exist"))?;
+            add(String::new(), name, None, refspec.force);
+            continue;
+        }
+        let (source, full_name, hash) = match dwim_ref(&refspec.src)? {
+            Some((full_name, hash)) => (pretty_ref_name(&full_name).to_string(), Some(full_name), hash),
+            None => {
+                let hash = resolve_revision(&refspec.src).ok().context(format!("src refspec {} does not match any", refspec.src))?;
+                (refspec.src.clone(), None, hash)
+            },
+        };
+        // HEAD is pushed to the branch it is on
+        let full_name = match (full_name.as_deref(), read_head()?) {
+            (Some("HEAD"), Head::Branch(branch)) => Some(branch),
+            _ => full_name,
+        };
+        let name = match refspec.dst.as_deref() {
+            Some(dst) if dst.starts_with("refs/") => dst.to_string(),
+            Some(dst) => match (match_remote_ref(dst, remote_refs)?, full_name.as_deref()) {
+                (Some(name), _) => name,
+                (None, Some(full_name)) if full_name.starts_with(HEADS_PREFIX) => format!("{HEADS_PREFIX}{dst}"),
+                (None, Some(full_name)) if full_name.starts_with(TAGS_PREFIX) => format!("{TAGS_PREFIX}{dst}"),
+                _ => bail!("{}", unqualified_destination_message(&refspec.src, dst, &hash)?),
+            },
+            None => full_name.filter(|x| x.starts_with("refs/")).context(format!("src refspec {} does not match any", refspec.src))?,
+        };
+        add(source, name, Some(hash), refspec.force);
+    }
+    // like git, the refs the remote has come first
+    let position = |name: &str| remote_refs.iter().position(|x| x.name == name).unwrap_or(remote_refs.len());
+    pushed.sort_by_key(|x| position(&x.name));
+    Ok(pushed)
+}
+
+/// The ref of the remote a short name stands for, the way git matches a short name against full ref names
+fn match_remote_ref(name: &str, remote_refs: &[AdvertisedRef]) -> anyhow::Result<Option<String>> {
+    let candidates = Refspec::parse(name)?.source_candidates();
+    let matches = remote_refs.iter().filter(|x| candidates.contains(&x.name)).collect::<Vec<_>>();
+    match matches.as_slice() {
+        [] => Ok(None),
+        [found] => Ok(Some(found.name.clone())),
+        _ => bail!("dst refspec {name} matches more than one"),
+    }
+}
+
+fn unqualified_destination_message(src: &str, dst: &str, hash: &str) -> anyhow::Result<String> {
+    let mut message = format!("The destination you provided is not a full refname (i.e.,\n\
+        starting with \"refs/\"). We tried to guess what you meant by:\n\n\
+        - Looking for a ref that matches '{dst}' on the remote side.\n\
+        - Checking if the <src> being pushed ('{src}')\n  is a ref in \"refs/{{heads,tags}}/\". If so we add a corresponding\n  \
+        refs/{{heads,tags}}/ prefix on the remote side.\n\n\
+        Neither worked, so we gave up. You must fully qualify the ref.");
+    let (kind, suggestion) = match read_object_type(hash)? {
+        ObjectType::Commit => ("commit", "create a new branch"),
+        ObjectType::Tag => ("tag", "create a new tag"),
+        ObjectType::Tree => ("tree", "tag a new tree"),
+        ObjectType::Blob => ("blob", "tag a new blob"),
+    };
+    let prefix = if kind == "commit" { HEADS_PREFIX } else { TAGS_PREFIX };
+    message.push_str(&format!("\nhint: The <src> part of the refspec is a {kind} object.\n\
+        hint: Did you mean to {suggestion} by pushing to\nhint: '{src}:{prefix}{dst}'?"));
+    Ok(message)
+}
+
+/// Applies --force-with-lease: a lease without a value expects the value of the remote-tracking ref, or that the
+/// ref does not exist on the remote if there is no remote-tracking ref
+pub(crate) fn apply_leases(pushed: &mut [PushedRef], leases: &[String], remote: &Remote) -> anyhow::Result<()> {
+    for lease in leases {
+        let (name, expect) = match lease.split_once(':') {
+            Some((name, expect)) => (Some(name), Some(expect)),
+            None => (Some(lease.as_str()).filter(|x| !x.is_empty()), None),
+        };
+        let candidates = name.map(|x| Refspec::parse(x).map(|x| x.source_candidates())).transpose()?;
+        for pushed_ref in pushed.iter_mut() {
+            if candidates.as_ref().is_some_and(|x| !x.contains(&pushed_ref.name)) {
+                continue;
+            }
+            let expect = match expect {
+                Some("") => None,
+                Some(expect) => Some(resolve_revision(expect).context(format!("cannot parse expected object name '{expect}'"))?),
+                None => tracking_ref(remote, &pushed_ref.name).map(|x| read_ref(&x)).transpose()?.flatten(),
+            };
+            pushed_ref.expect = Some(expect);
+        }
+    }
+    Ok(())
+}
+
+/// The remote-tracking ref that the fetch refspecs of the remote store a ref of the remote in
+pub(crate) fn tracking_ref(remote: &Remote, name: &str) -> Option<String> {
+    remote.name.as_ref()?;
+    let negative = remote.fetch.iter().any(|x| x.negative && x.matches_source(name));
+    remote.fetch.iter()
+        .filter(|x| !x.negative && !negative && x.matches_source(name))
+        .find_map(|x| x.destination(name))
+}
+
+/// Decides which updates can be sent, the way git checks them before pushing
+pub(crate) fn check_pushed_refs(pushed: &mut [PushedRef], force_all: bool, graph: &mut CommitGraph) -> anyhow::Result<()> {
+    for pushed_ref in pushed.iter_mut() {
+        if pushed_ref.new.is_some() && pushed_ref.new == pushed_ref.old {
+            pushed_ref.status = PushStatus::UpToDate;
+            continue;
+        }
+        let mut force = pushed_ref.force || force_all;
+        let fast_forward = match (&pushed_ref.old, &pushed_ref.new) {
+            (Some(old), Some(new)) if has_object(old) => match (peel(old, ObjectType::Commit), peel(new, ObjectType::Commit)) {
+                (Ok(old), Ok(new)) => graph.is_ancestor(&old, &new)?,
+                _ => false,
+            },
+            _ => false,
+        };
+        let reason = match (&pushed_ref.expect, &pushed_ref.old, &pushed_ref.new) {
+            (Some(expect), old, _) if expect != old => Some("stale info"),
+            (Some(_), _, _) => {
+                // a lease that holds is what makes the update safe
+                force = true;
+                None
+            },
+            (None, Some(_), Some(_)) if pushed_ref.name.starts_with(TAGS_PREFIX) => Some("already exists"),
+            (None, Some(old), Some(_)) if !has_object(old) => Some("fetch first"),
+            (None, Some(old), Some(new)) if peel(old, ObjectType::Commit).is_err() || peel(new, ObjectType::Commit).is_err() => Some("needs force"),
+            (None, Some(_), Some(_)) if !fast_forward => Some("non-fast-forward"),
+            _ => None,
+        };
+        // an update that needs to be forced is one, as is one a lease allows that is not a fast-forward
+        let forced = reason.is_some() || (pushed_ref.expect.is_some() && pushed_ref.old.is_some() && pushed_ref.new.is_some() && !fast_forward);
+        pushed_ref.status = match reason {
+            Some(reason) if !force => PushStatus::Rejected(reason),
+            _ => PushStatus::Ok { forced },
+        };
+    }
+    Ok(())
+}
+
+/// The line that reports the result of pushing a ref, None for a ref that is up to date
+pub(crate) fn format_push_line(pushed_ref: &PushedRef) -> Option<String> {
+    let to = pretty_ref_name(&pushed_ref.name);
+    let from = &pushed_ref.source;
+    let (flag, summary, reason) = match &pushed_ref.status {
+        PushStatus::UpToDate => return None,
+        PushStatus::Ok { .. } if pushed_ref.new.is_none() => ('-', "[deleted]".to_string(), None),
+        PushStatus::Ok { forced } => match (&pushed_ref.old, &pushed_ref.new) {
+            (Some(old), Some(new)) if *forced => ('+', format!("{}...{}", abbreviate(old), abbreviate(new)), Some("forced update")),
+            (Some(old), Some(new)) => (' ', format!("{}..{}", abbreviate(old), abbreviate(new)), None),
+            _ if pushed_ref.name.starts_with(TAGS_PREFIX) => ('*', "[new tag]".to_string(), None),
+            _ if pushed_ref.name.starts_with(HEADS_PREFIX) => ('*', "[new branch]".to_string(), None),
+            _ => ('*', "[new reference]".to_string(), None),
+        },
+        PushStatus::Rejected(reason) => ('!', "[rejected]".to_string(), Some(*reason)),
+        PushStatus::RemoteRejected(reason) => ('!', "[remote rejected]".to_string(), Some(reason.as_str())),
+    };
+    let mut line = match pushed_ref.new {
+        Some(_) => format!(" {flag} {summary:<SUMMARY_WIDTH$} {from} -> {to}"),
+        None => format!(" {flag} {summary:<SUMMARY_WIDTH$} {to}"),
+    };
+    if let Some(reason) = reason {
+        line.push_str(&format!(" ({reason})"));
+    }
+    Some(line)
+}
+
+/// The advice git gives after rejected updates, for the most important reason. `current_branch` is the full name
+/// of the branch HEAD is on
+pub(crate) fn rejection_hint(pushed: &[PushedRef], current_branch: Option<&str>) -> Option<String> {
+    let rejected = |reason: &'static str| pushed.iter().filter(move |x| x.status == PushStatus::Rejected(reason));
+    let hint = if rejected("non-fast-forward").any(|x| Some(x.name.as_str()) == current_branch) {
+        format!("Updates were rejected because the tip of your current branch is behind\n\
+            its remote counterpart. Integrate the remote changes (e.g.\n'git pull ...') before pushing again.\n{FAST_FORWARD_NOTE}")
+    } else if rejected("non-fast-forward").next().is_some() {
+        format!("Updates were rejected because a pushed branch tip is behind its remote\n\
+            counterpart. Check out this branch and integrate the remote changes\n\
+            (e.g. 'git pull ...') before pushing again.\n{FAST_FORWARD_NOTE}")
+    } else if rejected("already exists").next().is_some() {
+        "Updates were rejected because the tag already exists in the remote.".to_string()
+    } else if rejected("fetch first").next().is_some() {
+        format!("Updates were rejected because the remote contains work that you do\n\
+            not have locally. This is usually caused by another repository pushing\n\
+            to the same ref. You may want to first integrate the remote changes\n\
+            (e.g., 'git pull ...') before pushing again.\n{FAST_FORWARD_NOTE}")
+    } else if rejected("needs force").next().is_some() {
+        "You cannot update a remote ref that points at a non-commit object,\n\
+            or update a remote ref to make it point at a non-commit object,\n\
+            without using the '--force' option.\n".to_string()
+    } else {
+        return None;
+    };
+    Some(hint.lines().map(|x| format!("hint: {x}").trim_end().to_string()).collect::<Vec<_>>().join("\n"))
+}
+
+/// Updates the refs of a remote with local refs and sends the objects they need, then updates the remote-tracking refs
+pub(crate) fn push_command(repository: Option<String>, refspecs: Vec<String>, flags: PushFlags) -> anyhow::Result<()> {
+    let config = Config::read()?;
+    let remote = Remote::resolve_push(repository.as_deref(), &config)?;
+    if flags.delete && refspecs.is_empty() {
+        bail!("--delete doesn't make sense without any refs");
+    }
+    let mut refspecs = refspecs.iter()
+        .map(|x| match flags.delete {
+            true => Refspec::parse(&format!(":{x}")),
+            false => Refspec::parse(x),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if flags.tags {
+        refspecs.push(Refspec::parse(TAGS_REFSPEC)?);
+    }
+    if refspecs.is_empty() {
+        refspecs = default_push_refspecs(&remote, &config)?;
+    }
+
+    let mut transport = Transport::open(&remote.url, Service::ReceivePack, &config)?;
+    let advertisement = transport.push_advertisement()?;
+    if flags.atomic && !advertisement.has_capability("atomic") {
+        bail!("the receiving end does not support --atomic push");
+    }
+    if !flags.push_option.is_empty() && !advertisement.has_capability("push-options") {
+        bail!("the receiving end does not support push options");
+    }
+    let mut pushed = match match_push_refs(&refspecs, &advertisement.refs) {
+        Ok(pushed) => pushed,
+        Err(error) => {
+            eprintln!("error: {error:#}");
+            eprintln!("error: failed to push some refs to '{}'", remote.url);
+            return Err(Exit(1).into());
+        },
+    };
+    apply_leases(&mut pushed, &flags.force_with_lease, &remote)?;
+    check_pushed_refs(&mut pushed, flags.force, &mut CommitGraph::default())?;
+    let is_sent = |x: &PushedRef| matches!(x.status, PushStatus::Ok { .. });
+    let is_failed = |x: &PushedRef| matches!(x.status, PushStatus::Rejected(_) | PushStatus::RemoteRejected(_));
+    // an atomic push sends nothing if one of the updates is rejected
+    if flags.atomic && pushed.iter().any(is_failed) {
+        for pushed_ref in pushed.iter_mut().filter(|x| is_sent(x)) {
+            pushed_ref.status = PushStatus::Rejected("atomic push failed");
+        }
+    }
+
+    let updates = pushed.iter().filter(|x| is_sent(x)).map(PushedRef::update).collect::<Vec<_>>();
+    if !updates.is_empty() {
+        let include = updates.iter().filter_map(|x| x.new.clone()).collect::<Vec<_>>();
+        let pack = match include.is_empty() {
+            true => None,
+            false => {
+                // the remote has the objects of its refs, they are the bases of the deltas of a thin pack
+                let exclude = advertisement.refs.iter().map(|x| x.hash.clone()).filter(|x| has_object(x)).collect::<Vec<_>>();
+                let options = PackObjectsOptions { thin: true, ..PackObjectsOptions::default() };
+                Some(pack_objects(&include, &exclude, &options)?)
+            },
+        };
+        let options = ReceivePackOptions { atomic: flags.atomic, push_options: flags.push_option.clone() };
+        let report = transport.push(&updates, pack.as_deref(), &options)?;
+        if let Some(error) = &report.unpack_error {
+            eprintln!("error: remote unpack failed: {error}");
+        }
+        for pushed_ref in pushed.iter_mut().filter(|x| is_sent(x)) {
+            match report.refs.iter().find(|(name, _)| *name == pushed_ref.name) {
+                Some((_, None)) => {},
+                Some((_, Some(reason))) => pushed_ref.status = PushStatus::RemoteRejected(reason.clone()),
+                None => pushed_ref.status = PushStatus::RemoteRejected("remote failed to report status".to_string()),
+            }
+        }
+    }
+    update_tracking_refs(&pushed, &remote, &config)?;
+
+    let mut lines = pushed.iter().filter(|x| is_sent(x))
+        .chain(pushed.iter().filter(|x| is_failed(x)))
+        .filter_map(format_push_line)
+        .peekable();
+    if lines.peek().is_some() {
+        eprintln!("To {}", remote.url);
+    }
+    lines.for_each(|x| eprintln!("{x}"));
+    if !pushed.iter().any(is_failed) {
+        if !pushed.iter().any(is_sent) {
+            eprintln!("Everything up-to-date");
+        }
+        return Ok(());
+    }
+    eprintln!("error: failed to push some refs to '{}'", remote.url);
+    let current_branch = match read_head()? {
+        Head::Branch(ref_name) => Some(ref_name),
+        Head::Detached(_) => None,
+    };
+    if let Some(hint) = rejection_hint(&pushed, current_branch.as_deref()) {
+        eprintln!("{hint}");
+    }
+    Err(Exit(1).into())
+}
+
+/// Points the remote-tracking refs of the pushed refs to their new values, like a fetch right after the push would
+fn update_tracking_refs(pushed: &[PushedRef], remote: &Remote, config: &Config) -> anyhow::Result<()> {
+    let committer = read_ident_or_default(Role::Committer, config)?;
+    for pushed_ref in pushed.iter().filter(|x| matches!(x.status, PushStatus::Ok { .. } | PushStatus::UpToDate)) {
+        let Some(tracking) = tracking_ref(remote, &pushed_ref.name) else {
+            continue;
+        };
+        let old = read_ref(&tracking)?;
+        match &pushed_ref.new {
+            Some(new) if old.as_ref() != Some(new) => update_ref(&tracking, new, old.as_deref(), &committer, "update by push")?,
+            None if old.is_some() => delete_ref(&tracking)?,
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pushed_ref(name: &str, old: Option<&str>, new: Option<&str>, status: PushStatus) -> PushedRef {
+        let (old, new) = (old.map(str::to_string), new.map(str::to_string));
+        PushedRef { source: pretty_ref_name(name).to_string(), name: name.to_string(), old, new, force: false, expect: None, status }
+    }
+
+    #[test]
+    fn test_format_push_line() {
+        let (old, new) = ("1".repeat(40), "2".repeat(40));
+        let lines = [
+            pushed_ref("refs/heads/main", Some(&old), Some(&new), PushStatus::Ok { forced: false }),
+            pushed_ref("refs/heads/main", Some(&old), Some(&new), PushStatus::Ok { forced: true }),
+            pushed_ref("refs/tags/v1", None, Some(&new), PushStatus::Ok { forced: false }),
+            pushed_ref("refs/heads/feat", Some(&old), None, PushStatus::Ok { forced: false }),
+            pushed_ref("refs/heads/main", Some(&old), Some(&new), PushStatus::Rejected("fetch first")),
+            pushed_ref("refs/heads/main", Some(&old), Some(&new), PushStatus::UpToDate),
+        ].iter().filter_map(format_push_line).collect::<Vec<_>>();
+        assert_eq!(vec![
+            "   1111111..2222222  main -> main",
+            " + 1111111...2222222 main -> main (forced update)",
+            " * [new tag]         v1 -> v1",
+            " - [deleted]         feat",
+            " ! [rejected]        main -> main (fetch first)",
+        ], lines);
+    }
+}
@@ -0,0 +1,1237 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::slice;
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+use crate::{apply_merge_result, edit_commit_message, merge_options, print_commit_summary, print_nothing_to_commit, remove_merge_state, template_idents, worktree_changes, write_merge_message};
+use crate::checkout::{switch_trees, SwitchAction};
+use crate::cli::RebaseFlags;
+use crate::commit_object_read::{CommitObject, Signature};
+use crate::common::{CHERRY_PICK_HEAD_PATH, Exit, GIT_PATH, MERGE_HEAD_PATH, MERGE_MSG_PATH, ObjectType, REBASE_HEAD_PATH};
+use crate::config::Config;
+use crate::diff::{abbreviate, DiffOptions};
+use crate::diff_output::{DiffFormat, write_changes};
+use crate::editor::launch_sequence_editor;
+use crate::ident::{read_ident, Role};
+use crate::index::Index;
+use crate::merge_base::merge_bases;
+use crate::message_cleanup::{cleanup_message, CleanupMode, comment_lines, is_message_empty, strip_space};
+use crate::object_write::{hash_object, write_commit};
+use crate::pathspec::Pathspec;
+use crate::refs::{Head, HEADS_PREFIX, LOGS_PATH, read_head, read_head_commit, read_ref, update_head, update_ref, write_head, write_orig_head};
+use crate::rev_list::{rev_list, RevListOptions};
+use crate::rev_parse::{peel, resolve_revision};
+use crate::sequencer::first_line;
+use crate::tree_diff::{diff_tree_to_index, diff_trees};
+use crate::tree_merge::{merge_commits, merge_trees};
+use crate::tree_object_write::write_index_tree;
+
+/// the state of a rebase, while it waits for conflicts to be resolved or a commit to be edited
+const REBASE_MERGE_PATH: &str = ".git/rebase-merge";
+/// the commands that are still to be run, the file the user edits
+pub(crate) const TODO_PATH: &str = ".git/rebase-merge/git-rebase-todo";
+/// the commands as they were before the user edited them
+const TODO_BACKUP_PATH: &str = ".git/rebase-merge/git-rebase-todo.backup";
+const DONE_PATH: &str = ".git/rebase-merge/done";
+/// the number of the current command, counting from 1, and the number of all commands
+const MSGNUM_PATH: &str = ".git/rebase-merge/msgnum";
+const END_PATH: &str = ".git/rebase-merge/end";
+/// the rebased branch, or "detached HEAD"
+const HEAD_NAME_PATH: &str = ".git/rebase-merge/head-name";
+const ONTO_PATH: &str = ".git/rebase-merge/onto";
+const ORIG_HEAD_PATH: &str = ".git/rebase-merge/orig-head";
+const INTERACTIVE_PATH: &str = ".git/rebase-merge/interactive";
+/// commits that become empty are dropped instead of stopping the rebase
+const DROP_REDUNDANT_PATH: &str = ".git/rebase-merge/drop_redundant_commits";
+/// the empty commit that root commits are replayed onto when there is no other commit to start from
+const SQUASH_ONTO_PATH: &str = ".git/rebase-merge/squash-onto";
+const STOPPED_SHA_PATH: &str = ".git/rebase-merge/stopped-sha";
+const MESSAGE_PATH: &str = ".git/rebase-merge/message";
+const AUTHOR_SCRIPT_PATH: &str = ".git/rebase-merge/author-script";
+/// the commit made for an edit command, HEAD still points to it if the user did not commit since
+const AMEND_PATH: &str = ".git/rebase-merge/amend";
+/// the combined message of the commits melded into the previous one so far, and the commands that melded them
+const MESSAGE_SQUASH_PATH: &str = ".git/rebase-merge/message-squash";
+const CURRENT_FIXUPS_PATH: &str = ".git/rebase-merge/current-fixups";
+
+/// the refs of the labels that the todo list marks commits with, they are removed when the rebase ends
+pub(crate) const LABELS_PREFIX: &str = "refs/rewritten/";
+
+const FIXUP_PREFIXES: [&str; 3] = ["fixup! ", "squash! ", "amend! "];
+
+const TODO_HELP: &str = "
+Commands:
+p, pick <commit> = use commit
+r, reword <commit> = use commit, but edit the commit message
+e, edit <commit> = use commit, but stop for amending
+s, squash <commit> = use commit, but meld into previous commit
+f, fixup [-C | -c] <commit> = like \"squash\" but keep only the previous
+                   commit's log message, unless -C is used, in which case
+                   keep only this commit's message; -c is same as -C but
+                   opens the editor
+x, exec <command> = run command (the rest of the line) using shell
+b, break = stop here (continue rebase later with 'git rebase --continue')
+d, drop <commit> = remove commit
+l, label <label> = label current HEAD with a name
+t, reset <label> = reset HEAD to a label
+m, merge [-C <commit> | -c <commit>] <label> [# <oneline>]
+        create a merge commit using the original merge commit's
+        message (or the oneline, if no original merge commit was
+        specified); use -c <commit> to reword the commit message
+
+These lines can be re-ordered; they are executed from top to bottom.
+
+If you remove a line here THAT COMMIT WILL BE LOST.
+";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TodoCommand {
+    Pick,
+    Reword,
+    Edit,
+    Squash,
+    /// Melds the commit into the previous one and keeps the previous message. With -C the message of this commit
+    /// replaces it, with -c the replaced message is also edited
+    Fixup { replace_message: bool, edit: bool },
+    Drop,
+    Exec,
+    Break,
+    Label,
+    Reset,
+    /// Merges the labelled commit into HEAD, with -c the message of the original merge is edited
+    Merge { edit: bool },
+    Noop,
+}
+impl TodoCommand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Pick => "pick",
+            Self::Reword => "reword",
+            Self::Edit => "edit",
+            Self::Squash => "squash",
+            Self::Fixup { .. } => "fixup",
+            Self::Drop => "drop",
+            Self::Exec => "exec",
+            Self::Break => "break",
+            Self::Label => "label",
+            Self::Reset => "reset",
+            Self::Merge { .. } => "merge",
+            Self::Noop => "noop",
+        }
+    }
+
+    /// Whether the command melds its commit into the previous one
+    pub fn is_fixup(&self) -> bool {
+        matches!(self, Self::Squash | Self::Fixup { .. })
+    }
+
+    fn parse(word: &str) -> Option<Self> {
+        let command = match word {
+            "p" | "pick" => Self::Pick,
+            "r" | "reword" => Self::Reword,
+            "e" | "edit" => Self::Edit,
+            "s" | "squash" => Self::Squash,
+            "f" | "fixup" => Self::Fixup { replace_message: false, edit: false },
+            "d" | "drop" => Self::Drop,
+            "x" | "exec" => Self::Exec,
+            "b" | "break" => Self::Break,
+            "l" | "label" => Self::Label,
+            "t" | "reset" => Self::Reset,
+            "m" | "merge" => Self::Merge { edit: false },
+            "noop" => Self::Noop,
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+/// A line of the todo list
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TodoItem {
+    pub command: TodoCommand,
+    /// the commit to pick, or the original merge whose message a merge uses
+    pub commit: Option<String>,
+    /// the rest of the line: the subject after a commit, the command to execute, or the label
+    pub arg: String,
+}
+impl TodoItem {
+    pub fn pick(commit: &CommitObject) -> Self {
+        Self { command: TodoCommand::Pick, commit: Some(commit.hash.clone()), arg: first_line(&commit.message).to_string() }
+    }
+
+    /// The line in the todo list, the user edits it with abbreviated hashes
+    pub fn format(&self, abbrev: bool) -> String {
+        let mut line = self.command.name().to_string();
+        match self.command {
+            TodoCommand::Fixup { replace_message: true, edit } => line.push_str(if edit { " -c" } else { " -C" }),
+            TodoCommand::Merge { edit } if self.commit.is_some() => line.push_str(if edit { " -c" } else { " -C" }),
+            _ => {},
+        }
+        if let Some(commit) = &self.commit {
+            line.push(' ');
+            line.push_str(if abbrev { abbreviate(commit) } else { commit });
+        }
+        if !self.arg.is_empty() {
+            line.push(' ');
+            line.push_str(&self.arg);
+        }
+        line
+    }
+}
+
+/// Parses a todo list, commits are resolved to full hashes. Comments and empty lines are skipped
+pub(crate) fn parse_todo(data: &str) -> anyhow::Result<Vec<TodoItem>> {
+    let mut items = vec![];
+    for (number, line) in (1..).zip(data.lines()) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let invalid = || anyhow::anyhow!("invalid line {number}: {line}");
+        let (word, mut rest) = trimmed.split_once(char::is_whitespace).unwrap_or((trimmed, ""));
+        let mut command = TodoCommand::parse(word).ok_or_else(invalid)?;
+        rest = rest.trim_start();
+        // the flags that take the message from a commit
+        let flag = ["-C ", "-c "].into_iter().find(|x| rest.starts_with(x));
+        if let Some(flag) = flag {
+            let edit = flag == "-c ";
+            match &mut command {
+                TodoCommand::Fixup { replace_message, edit: edit_message } => (*replace_message, *edit_message) = (true, edit),
+                TodoCommand::Merge { edit: edit_message } => *edit_message = edit,
+                _ => return Err(invalid()),
+            }
+            rest = rest[flag.len()..].trim_start();
+        }
+        let takes_commit = match command {
+            TodoCommand::Merge { .. } => flag.is_some(),
+            TodoCommand::Exec | TodoCommand::Break | TodoCommand::Label | TodoCommand::Reset | TodoCommand::Noop => false,
+            _ => true,
+        };
+        let commit = if takes_commit {
+            let (rev, arg) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let hash = resolve_revision(rev).and_then(|x| peel(&x, ObjectType::Commit)).map_err(|_| invalid())?;
+            rest = arg.trim_start();
+            Some(hash)
+        } else {
+            None
+        };
+        let needs_arg = matches!(command, TodoCommand::Exec | TodoCommand::Label | TodoCommand::Reset | TodoCommand::Merge { .. });
+        let takes_arg = commit.is_some() || needs_arg;
+        if (needs_arg && rest.is_empty()) || (!takes_arg && !rest.is_empty()) {
+            return Err(invalid());
+        }
+        items.push(TodoItem { command, commit, arg: rest.to_string() });
+    }
+    Ok(items)
+}
+
+/// The todo list as it is saved, with full hashes
+pub(crate) fn format_todo(items: &[TodoItem]) -> String {
+    items.iter().map(|x| format!("{}\n", x.format(false))).collect()
+}
+
+/// The todo list the user edits, with abbreviated hashes and the help about the commands
+pub(crate) fn format_todo_for_editing(items: &[TodoItem], upstream: &str, orig_head: &str, onto: &str) -> String {
+    let mut todo = items.iter().map(|x| format!("{}\n", x.format(true))).collect::<String>();
+    let count = items.len();
+    let commands = if count == 1 { "command" } else { "commands" };
+    let header = format!("Rebase {}..{} onto {} ({count} {commands})", abbreviate(upstream), abbreviate(orig_head), abbreviate(onto));
+    todo.push('\n');
+    todo.push_str(&comment_lines(&format!("{header}\n{TODO_HELP}\nHowever, if you remove everything, the rebase will be aborted.\n\n")));
+    todo
+}
+
+/// The commands left in a rebase in progress for the user to edit, with the help about the commands
+pub(crate) fn format_remaining_todo(items: &[TodoItem]) -> String {
+    let mut todo = items.iter().map(|x| format!("{}\n", x.format(true))).collect::<String>();
+    todo.push('\n');
+    todo.push_str(&comment_lines(&format!("{TODO_HELP}
+You are editing the todo file of an ongoing interactive rebase.
+To continue rebase after editing, run:
+    git rebase --continue
+
+")));
+    todo
+}
+
+/// Moves the commits whose subjects start with "fixup! ", "squash! " or "amend! " right after the commit they name,
+/// turning them into the matching commands
+pub(crate) fn autosquash(items: Vec<TodoItem>) -> Vec<TodoItem> {
+    let mut fixups = HashMap::<usize, Vec<TodoItem>>::new();
+    let mut targets = HashMap::<usize, usize>::new();
+    let mut kept = vec![];
+    for (i, item) in items.iter().enumerate() {
+        let mut command = None;
+        let mut subject = item.arg.as_str();
+        while let Some(prefix) = FIXUP_PREFIXES.into_iter().find(|x| subject.starts_with(x)) {
+            // the first prefix decides the command, fixup! amend! x is still a fixup
+            command.get_or_insert(match prefix {
+                "fixup! " => TodoCommand::Fixup { replace_message: false, edit: false },
+                "squash! " => TodoCommand::Squash,
+                _ => TodoCommand::Fixup { replace_message: true, edit: false },
+            });
+            subject = &subject[prefix.len()..];
+        }
+        let target = command.filter(|_| item.command == TodoCommand::Pick).and_then(|_| {
+            let earlier = || items[..i].iter().enumerate().filter(|(_, x)| x.command == TodoCommand::Pick);
+            earlier().find(|(_, x)| x.arg == subject)
+                .or_else(|| earlier().find(|(_, x)| !subject.is_empty() && x.commit.as_ref().is_some_and(|x| x.starts_with(subject))))
+                .or_else(|| earlier().find(|(_, x)| x.arg.starts_with(subject)))
+                .map(|(j, _)| *targets.get(&j).unwrap_or(&j))
+        });
+        match (command, target) {
+            (Some(command), Some(target)) => {
+                targets.insert(i, target);
+                fixups.entry(target).or_default().push(TodoItem { command, ..item.clone() });
+            },
+            _ => kept.push(i),
+        }
+    }
+    let mut result = vec![];
+    for i in kept {
+        result.push(items[i].clone());
+        result.extend(fixups.remove(&i).unwrap_or_default());
+    }
+    result
+}
+
+/// Adds exec commands after every pick, after the last fixup or squash of a pick that has them
+pub(crate) fn insert_exec(items: Vec<TodoItem>, commands: &[String]) -> Vec<TodoItem> {
+    let mut result = vec![];
+    let mut items = items.into_iter().peekable();
+    while let Some(item) = items.next() {
+        let picks = matches!(item.command, TodoCommand::Pick | TodoCommand::Merge { .. }) || item.command.is_fixup();
+        result.push(item);
+        if picks && !items.peek().is_some_and(|x| x.command.is_fixup()) {
+            result.extend(commands.iter().map(|x| TodoItem { command: TodoCommand::Exec, commit: None, arg: x.clone() }));
+        }
+    }
+    result
+}
+
+/// An id of the changes a commit makes to its first parent, commits that make the same changes have the same id
+pub(crate) fn patch_id(commit: &CommitObject) -> anyhow::Result<String> {
+    let parent_tree = match commit.parents.first() {
+        Some(parent) => Some(peel(parent, ObjectType::Tree)?),
+        None => None,
+    };
+    let changes = diff_trees(parent_tree.as_deref(), Some(&commit.tree), true, &Pathspec::new(&[]))?;
+    let mut patch = vec![];
+    write_changes(&changes, &DiffFormat { patch: true, ..Default::default() }, &DiffOptions::default(), &mut patch)?;
+    // the hashes of the blobs and the positions of the hunks do not matter
+    let mut hasher = Sha1::new();
+    for line in String::from_utf8_lossy(&patch).lines().filter(|x| !x.starts_with("index ")) {
+        hasher.update(if line.starts_with("@@") { "@@" } else { line });
+        hasher.update("\n");
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// A rebase in progress
+pub(crate) struct RebaseState {
+    /// the rebased branch, like refs/heads/topic, or "detached HEAD"
+    pub head_name: String,
+    pub onto: String,
+    /// the commit HEAD pointed to when the rebase started
+    pub orig_head: String,
+    pub drop_redundant_commits: bool,
+    pub squash_onto: Option<String>,
+    pub done: Vec<TodoItem>,
+    pub todo: Vec<TodoItem>,
+}
+impl RebaseState {
+    pub fn in_progress() -> bool {
+        Path::new(REBASE_MERGE_PATH).is_dir()
+    }
+
+    pub fn read() -> anyhow::Result<Self> {
+        let read = |path: &str| fs::read_to_string(path).context(format!("Failed to read {path}"));
+        let todo_data = read(TODO_PATH)?;
+        let todo = parse_todo(&todo_data).context(format!("Failed to parse {TODO_PATH}"))?;
+        let done = parse_todo(&fs::read_to_string(DONE_PATH).unwrap_or_default()).context(format!("Failed to parse {DONE_PATH}"))?;
+        Ok(Self {
+            head_name: read(HEAD_NAME_PATH)?.trim().to_string(),
+            onto: read(ONTO_PATH)?.trim().to_string(),
+            orig_head: read(ORIG_HEAD_PATH)?.trim().to_string(),
+            drop_redundant_commits: Path::new(DROP_REDUNDANT_PATH).exists(),
+            squash_onto: fs::read_to_string(SQUASH_ONTO_PATH).ok().map(|x| x.trim().to_string()),
+            done,
+            todo,
+        })
+    }
+
+    /// The rebased branch and the commit it pointed to, which an abort goes back to even if the todo list is broken
+    pub fn read_orig_head() -> anyhow::Result<(String, String)> {
+        let read = |path: &str| fs::read_to_string(path).context(format!("Failed to read {path}"));
+        Ok((read(HEAD_NAME_PATH)?.trim().to_string(), read(ORIG_HEAD_PATH)?.trim().to_string()))
+    }
+
+    /// Creates the state of a new rebase
+    pub fn write(&self) -> anyhow::Result<()> {
+        fs::create_dir_all(REBASE_MERGE_PATH).context(format!("Failed to create {REBASE_MERGE_PATH}"))?;
+        let write = |path: &str, contents: String| fs::write(path, contents).context(format!("Failed to write {path}"));
+        write(HEAD_NAME_PATH, format!("{}\n", self.head_name))?;
+        write(ONTO_PATH, format!("{}\n", self.onto))?;
+        write(ORIG_HEAD_PATH, format!("{}\n", self.orig_head))?;
+        write(INTERACTIVE_PATH, String::new())?;
+        if self.drop_redundant_commits {
+            write(DROP_REDUNDANT_PATH, String::new())?;
+        }
+        if let Some(squash_onto) = &self.squash_onto {
+            write(SQUASH_ONTO_PATH, format!("{squash_onto}\n"))?;
+        }
+        write(TODO_BACKUP_PATH, format_todo(&self.todo))?;
+        self.write_todo()
+    }
+
+    /// Saves the commands that are done and the ones that are left
+    pub fn write_todo(&self) -> anyhow::Result<()> {
+        fs::write(TODO_PATH, format_todo(&self.todo)).context(format!("Failed to write {TODO_PATH}"))?;
+        fs::write(DONE_PATH, format_todo(&self.done)).context(format!("Failed to write {DONE_PATH}"))?;
+        fs::write(MSGNUM_PATH, format!("{}\n", self.done.len())).context(format!("Failed to write {MSGNUM_PATH}"))?;
+        fs::write(END_PATH, format!("{}\n", self.done.len() + self.todo.len())).context(format!("Failed to write {END_PATH}"))
+    }
+
+    /// The rebase in progress as git status describes it, with the last commands done and the next ones
+    pub fn status(&self, hints: bool) -> String {
+        const LISTED: usize = 2;
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+        let onto = abbreviate(&self.onto);
+        let mut status = format!("interactive rebase in progress; onto {onto}\n");
+        let done = self.done.len();
+        if done > 0 {
+            status.push_str(&format!("Last command{} done ({done} command{} done):\n", plural(done), plural(done)));
+            self.done[done.saturating_sub(LISTED)..].iter().for_each(|x| status.push_str(&format!("   {}\n", x.format(true))));
+        }
+        let remaining = self.todo.len();
+        if remaining == 0 {
+            status.push_str("No commands remaining.\n");
+        } else {
+            status.push_str(&format!("Next command{} to do ({remaining} remaining command{}):\n", plural(remaining), plural(remaining)));
+            self.todo.iter().take(LISTED).for_each(|x| status.push_str(&format!("   {}\n", x.format(true))));
+            if hints {
+                status.push_str("  (use \"git rebase --edit-todo\" to view and edit)\n");
+            }
+        }
+        // a commit is being edited when no picked commit waits with its message to be committed
+        let editing = !Path::new(MERGE_MSG_PATH).exists();
+        status.push_str(&match (editing, self.head_name.strip_prefix(HEADS_PREFIX)) {
+            (true, Some(branch)) => format!("You are currently editing a commit while rebasing branch '{branch}' on '{onto}'.\n"),
+            (true, None) => "You are currently editing a commit during a rebase.\n".to_string(),
+            (false, Some(branch)) => format!("You are currently rebasing branch '{branch}' on '{onto}'.\n"),
+            (false, None) => "You are currently rebasing.\n".to_string(),
+        });
+        if hints && editing {
+            status.push_str("  (use \"git commit --amend\" to amend the current commit)\n");
+            status.push_str("  (use \"git rebase --continue\" once you are satisfied with your changes)\n");
+        } else if hints {
+            status.push_str("  (all conflicts fixed: run \"git rebase --continue\")\n");
+        }
+        status
+    }
+
+    /// Forgets the rebase along with the labels it made
+    pub fn remove() -> anyhow::Result<()> {
+        for path in [format!("{GIT_PATH}/{LABELS_PREFIX}"), format!("{LOGS_PATH}/{LABELS_PREFIX}")] {
+            match fs::remove_dir_all(&path) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error).context(format!("Failed to remove {path}")),
+                _ => {},
+            }
+        }
+        match fs::remove_dir_all(REBASE_MERGE_PATH) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error).context(format!("Failed to remove {REBASE_MERGE_PATH}")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The commit the rebase stopped at, because of conflicts or to edit it, with what the commit made when the rebase
+/// continues takes from it
+pub(crate) struct StoppedCommit {
+    pub hash: String,
+    pub message: String,
+    pub author: Signature,
+    /// the commit made for an edit command, continuing amends it with the staged changes
+    pub amend: Option<String>,
+}
+impl StoppedCommit {
+    pub fn write(&self) -> anyhow::Result<()> {
+        let write = |path: &str, contents: String| fs::write(path, contents).context(format!("Failed to write {path}"));
+        write(STOPPED_SHA_PATH, format!("{}\n", self.hash))?;
+        write(MESSAGE_PATH, self.message.clone())?;
+        let quote = |value: &str| format!("'{}'", value.replace('\'', "'\\''"));
+        let author = &self.author;
+        write(AUTHOR_SCRIPT_PATH, format!(
+            "GIT_AUTHOR_NAME={}\nGIT_AUTHOR_EMAIL={}\nGIT_AUTHOR_DATE={}\n",
+            quote(&author.name), quote(&author.email), quote(&format!("@{} {}", author.timestamp, author.timezone)),
+        ))?;
+        if let Some(amend) = &self.amend {
+            write(AMEND_PATH, format!("{amend}\n"))?;
+        }
+        Ok(())
+    }
+
+    pub fn read() -> anyhow::Result<Option<Self>> {
+        let Ok(hash) = fs::read_to_string(STOPPED_SHA_PATH) else {
+            return Ok(None);
+        };
+        let message = fs::read_to_string(MESSAGE_PATH).context(format!("Failed to read {MESSAGE_PATH}"))?;
+        let script = fs::read_to_string(AUTHOR_SCRIPT_PATH).context(format!("Failed to read {AUTHOR_SCRIPT_PATH}"))?;
+        let mut values = HashMap::new();
+        for line in script.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                bail!("Invalid line in {AUTHOR_SCRIPT_PATH}: {line}");
+            };
+            let value = value.trim_matches('\'').replace("'\\''", "'");
+            values.insert(key, value);
+        }
+        let (Some(name), Some(email), Some(date)) = (values.remove("GIT_AUTHOR_NAME"), values.remove("GIT_AUTHOR_EMAIL"), values.remove("GIT_AUTHOR_DATE")) else {
+            bail!("Incomplete {AUTHOR_SCRIPT_PATH}");
+        };
+        let date = date.strip_prefix('@').unwrap_or(&date);
+        let author = Signature::parse(&format!("{name} <{email}> {date}"))?;
+        let amend = fs::read_to_string(AMEND_PATH).ok().map(|x| x.trim().to_string());
+        Ok(Some(Self { hash: hash.trim().to_string(), message, author, amend }))
+    }
+
+    pub fn remove() -> anyhow::Result<()> {
+        remove_files(&[STOPPED_SHA_PATH, MESSAGE_PATH, AUTHOR_SCRIPT_PATH, AMEND_PATH])
+    }
+}
+
+/// The commits melded into the previous one so far by a chain of fixup and squash commands
+#[derive(Default)]
+pub(crate) struct FixupChain {
+    pub fixups: Vec<TodoItem>,
+    /// the combined message, the messages that fixups skip are commented out
+    pub message: String,
+    /// the message of the last fixup -C or -c, which replaces the others unless a squash keeps them
+    pub replacement: Option<String>,
+}
+impl FixupChain {
+    pub fn read() -> anyhow::Result<Self> {
+        let Ok(message) = fs::read_to_string(MESSAGE_SQUASH_PATH) else {
+            return Ok(Self::default());
+        };
+        let fixups = fs::read_to_string(CURRENT_FIXUPS_PATH).context(format!("Failed to read {CURRENT_FIXUPS_PATH}"))?;
+        let fixups = parse_todo(&fixups).context(format!("Failed to parse {CURRENT_FIXUPS_PATH}"))?;
+        let replacement = match fixups.iter().rfind(|x| matches!(x.command, TodoCommand::Fixup { replace_message: true, .. })) {
+            Some(TodoItem { commit: Some(commit), .. }) => Some(strip_fixup_subject(&CommitObject::read(commit)?.message)),
+            _ => None,
+        };
+        Ok(Self { fixups, message, replacement })
+    }
+
+    /// Adds a melded commit. The first one also adds the message of the commit it is melded into
+    pub fn add(&mut self, item: &TodoItem, head_message: &str, message: &str) {
+        let count = self.fixups.len() + 2;
+        let replaces = matches!(item.command, TodoCommand::Fixup { replace_message: true, .. });
+        if self.fixups.is_empty() {
+            self.message = match replaces {
+                true => format!("# This is a combination of 2 commits.\n# The 1st commit message will be skipped:\n\n{}", comment_lines(head_message)),
+                false => format!("# This is a combination of 2 commits.\n# This is the 1st commit message:\n\n{head_message}"),
+            };
+        } else {
+            let (_, rest) = self.message.split_once('\n').unwrap_or((&self.message, ""));
+            self.message = format!("# This is a combination of {count} commits.\n{rest}");
+        }
+        if !self.message.ends_with('\n') {
+            self.message.push('\n');
+        }
+        match item.command {
+            TodoCommand::Fixup { replace_message: false, .. } => {
+                self.message.push_str(&format!("\n# The commit message #{count} will be skipped:\n\n{}", comment_lines(message)));
+            },
+            _ => {
+                // the subjects that only name the target of the fixup are commented out
+                let subject_len = message.split_once("\n\n").map_or(message.len(), |(subject, _)| subject.len() + 1);
+                let (subject, body) = message.split_at(subject_len.min(message.len()));
+                let subject = match FIXUP_PREFIXES.iter().any(|x| subject.starts_with(x)) {
+                    true => comment_lines(subject),
+                    false => subject.to_string(),
+                };
+                self.message.push_str(&format!("\n# This is the commit message #{count}:\n\n{subject}{body}"));
+            },
+        }
+        if replaces {
+            self.replacement = Some(strip_fixup_subject(message));
+        }
+        self.fixups.push(item.clone());
+    }
+
+    /// Whether the final message is edited, squash keeps all messages for the user to combine
+    pub fn edits_message(&self) -> bool {
+        self.fixups.iter().any(|x| matches!(x.command, TodoCommand::Squash | TodoCommand::Fixup { edit: true, .. }))
+    }
+
+    /// The message of the last commit of the chain before it is edited, a replacement wins unless there is a squash
+    pub fn final_message(&self) -> String {
+        match (&self.replacement, self.fixups.iter().any(|x| x.command == TodoCommand::Squash)) {
+            (Some(replacement), false) => replacement.clone(),
+            _ => self.message.clone(),
+        }
+    }
+
+    pub fn write(&self) -> anyhow::Result<()> {
+        fs::write(MESSAGE_SQUASH_PATH, &self.message).context(format!("Failed to write {MESSAGE_SQUASH_PATH}"))?;
+        fs::write(CURRENT_FIXUPS_PATH, format_todo(&self.fixups)).context(format!("Failed to write {CURRENT_FIXUPS_PATH}"))
+    }
+
+    pub fn remove() -> anyhow::Result<()> {
+        remove_files(&[MESSAGE_SQUASH_PATH, CURRENT_FIXUPS_PATH])
+    }
+}
+
+/// The message of an "amend! subject" commit without the subject, which only names the amended commit
+fn strip_fixup_subject(message: &str) -> String {
+    if !FIXUP_PREFIXES.iter().any(|x| message.starts_with(x)) {
+        return message.to_string();
+    }
+    match message.split_once("\n\n") {
+        Some((_, body)) => body.trim_start_matches('\n').to_string(),
+        None => String::new(),
+    }
+}
+
+fn remove_files(paths: &[&str]) -> anyhow::Result<()> {
+    for path in paths {
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error).context(format!("Failed to remove {path}")),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// Replays the commits of the current branch that the upstream does not contain onto the upstream, or onto another
+/// commit. The commits go through a todo list, which the user edits with -i, and the rebase stops at conflicts
+pub(crate) fn rebase_command(flags: RebaseFlags) -> anyhow::Result<()> {
+    let config = Config::read()?;
+    if RebaseState::in_progress() {
+        bail!("It seems that there is already a rebase-merge directory, and
+I wonder if you are in the middle of another rebase.  If that is the
+case, please try
+\tgit rebase (--continue | --abort | --skip)
+If that is not the case, please
+\trm -fr \".git/rebase-merge\"
+and run me again.  I am stopping in case you still have something
+valuable there.
+");
+    }
+    let resolve_commit = |name: &str| resolve_revision(name).and_then(|x| peel(&x, ObjectType::Commit));
+    let upstream = match &flags.upstream {
+        Some(name) => Some(resolve_commit(name).context(format!("invalid upstream '{name}'"))?),
+        None => None,
+    };
+    let head = read_head()?;
+    let orig_head = read_head_commit()?.context("no such branch/commit 'HEAD'")?;
+    // without an upstream all the commits are replayed, the root commits onto an empty commit unless --onto is given
+    let (onto, onto_name, squash_onto) = match (&flags.onto, &upstream, &flags.upstream) {
+        (Some(name), _, _) => (resolve_commit(name).context(format!("Does not point to a valid commit '{name}'"))?, name.clone(), None),
+        (None, Some(upstream), Some(name)) => (upstream.clone(), name.clone(), None),
+        _ => {
+            let empty_tree = hash_object(&[][..], ObjectType::Tree, 0, true)?;
+            let hash = write_commit(&empty_tree, &[], &read_ident(Role::Author, &config)?, &read_ident(Role::Committer, &config)?, "")?;
+            (hash.clone(), hash.clone(), Some(hash))
+        },
+    };
+
+    let index = Index::read()?;
+    let unstaged = !worktree_changes(&index)?.is_empty();
+    let staged = !diff_tree_to_index(Some(&peel(&orig_head, ObjectType::Tree)?), &index, true, &Pathspec::new(&[]))?.is_empty();
+    if unstaged || staged {
+        match unstaged {
+            true => eprintln!("error: cannot rebase: You have unstaged changes."),
+            false => eprintln!("error: cannot rebase: Your index contains uncommitted changes."),
+        }
+        if unstaged && staged {
+            eprintln!("error: additionally, your index contains uncommitted changes.");
+        }
+        eprintln!("error: Please commit or stash them.");
+        return Err(Exit(1).into());
+    }
+
+    // a branch that is already on top of onto is left alone, unless the commits are to be edited or checked
+    let squash_fixups = flags.autosquash || matches!(config.get("rebase.autoSquash"), Some("true" | "yes" | "on" | "1"));
+    if let (false, true, false, Some(upstream)) = (flags.interactive, flags.exec.is_empty(), squash_fixups, &upstream) {
+        if merge_bases(&onto, &orig_head)? == [onto.clone()] && merge_bases(upstream, &orig_head)? == [onto.clone()] {
+            match &head {
+                Head::Branch(name) => println!("Current branch {} is up to date.", name.strip_prefix(HEADS_PREFIX).unwrap_or(name)),
+                Head::Detached(_) => println!("HEAD is up to date."),
+            }
+            return Ok(());
+        }
+    }
+
+    let mut commits = rev_list(slice::from_ref(&orig_head), upstream.as_slice(), &RevListOptions::default())?.commits;
+    commits.reverse();
+    // the commits whose changes the upstream already has are left out
+    let upstream_patches = match &upstream {
+        Some(upstream) => rev_list(slice::from_ref(upstream), slice::from_ref(&orig_head), &RevListOptions::default())?
+            .commits
+            .iter()
+            .map(patch_id)
+            .collect::<anyhow::Result<HashSet<_>>>()?,
+        None => HashSet::new(),
+    };
+    let mut todo = vec![];
+    let mut skipped = false;
+    for commit in commits.iter().filter(|x| x.parents.len() <= 1) {
+        if !upstream_patches.is_empty() && upstream_patches.contains(&patch_id(commit)?) {
+            eprintln!("warning: skipped previously applied commit {}", abbreviate(&commit.hash));
+            skipped = true;
+            continue;
+        }
+        todo.push(TodoItem::pick(commit));
+    }
+    if skipped && !matches!(config.get("advice.skippedCherryPicks"), Some("false" | "no" | "off" | "0")) {
+        eprintln!("hint: use --reapply-cherry-picks to include skipped commits");
+        eprintln!("hint: Disable this message with \"git config advice.skippedCherryPicks false\"");
+    }
+    if flags.interactive && squash_fixups {
+        todo = autosquash(todo);
+    }
+    todo = insert_exec(todo, &flags.exec);
+    if todo.is_empty() {
+        todo.push(TodoItem { command: TodoCommand::Noop, commit: None, arg: String::new() });
+    }
+
+    let head_name = match &head {
+        Head::Branch(name) => name.clone(),
+        Head::Detached(_) => "detached HEAD".to_string(),
+    };
+    let drop_redundant_commits = !flags.interactive;
+    let mut state = RebaseState { head_name, onto: onto.clone(), orig_head: orig_head.clone(), drop_redundant_commits, squash_onto, done: vec![], todo };
+    state.write()?;
+    if flags.interactive {
+        let base = upstream.as_deref().unwrap_or(&onto);
+        fs::write(TODO_PATH, format_todo_for_editing(&state.todo, base, &orig_head, &onto)).context(format!("Failed to write {TODO_PATH}"))?;
+        launch_sequence_editor(Path::new(TODO_PATH), &config)?;
+        let data = fs::read_to_string(TODO_PATH).context(format!("Failed to read {TODO_PATH}"))?;
+        match parse_todo(&data) {
+            Ok(todo) if todo.is_empty() => {
+                RebaseState::remove()?;
+                eprintln!("error: nothing to do");
+                return Err(Exit(1).into());
+            },
+            Ok(todo) => {
+                state.todo = todo;
+                state.write_todo()?;
+            },
+            // the rebase starts so that the user can fix the todo list and continue it
+            Err(error) => {
+                start_rebase(&orig_head, &onto, &onto_name, &config)?;
+                eprintln!("error: {error}");
+                eprintln!("You can fix this with 'git rebase --edit-todo' and then run 'git rebase --continue'.");
+                eprintln!("Or you can abort the rebase with 'git rebase --abort'.");
+                return Err(Exit(1).into());
+            },
+        }
+    }
+
+    // the leading commits that are already on top of onto are kept as they are
+    let mut start = onto;
+    while let Some(item) = state.todo.first() {
+        let keep = match (item.command, &item.commit) {
+            (TodoCommand::Noop, _) => true,
+            (TodoCommand::Pick, Some(hash)) => CommitObject::read(hash)?.parents == [start.clone()],
+            _ => false,
+        };
+        if !keep {
+            break;
+        }
+        if let Some(hash) = &item.commit {
+            start = hash.clone();
+        }
+        state.done.push(state.todo.remove(0));
+    }
+    state.write_todo()?;
+    start_rebase(&orig_head, &start, &onto_name, &config)?;
+    run_rebase(state, &config)
+}
+
+/// Detaches HEAD at the commit the commands of the rebase start from
+fn start_rebase(orig_head: &str, commit: &str, onto_name: &str, config: &Config) -> anyhow::Result<()> {
+    write_orig_head(orig_head)?;
+    switch_worktree(orig_head, commit, false)?;
+    let committer = read_ident(Role::Committer, config)?;
+    write_head(&Head::Detached(commit.to_string()), Some(orig_head), commit, &committer, &format!("rebase (start): checkout {onto_name}"))
+}
+
+/// Moves the index and the working tree from the tree of one commit to the tree of another,
+/// with `force` local changes are dropped like reset --hard does
+fn switch_worktree(old_commit: &str, new_commit: &str, force: bool) -> anyhow::Result<()> {
+    let old_tree = peel(old_commit, ObjectType::Tree)?;
+    let new_tree = peel(new_commit, ObjectType::Tree)?;
+    let mut index = Index::read()?;
+    let action = if force { SwitchAction::Plain } else { SwitchAction::Checkout };
+    switch_trees(Some(&old_tree), Some(&new_tree), &mut index, force, action)?;
+    index.write()
+}
+
+/// Clears the progress line of a rebase before other messages, terminals that cannot clear it get it overwritten
+fn term_clear_line() -> String {
+    match env::var("TERM") {
+        Ok(term) if term != "dumb" => "\r\x1b[K".to_string(),
+        _ => format!("\r{:80}\r", ""),
+    }
+}
+
+/// Runs the commands left in the todo list, saving the state before each one. Exits when a command stops the rebase
+fn run_rebase(mut state: RebaseState, config: &Config) -> anyhow::Result<()> {
+    while !state.todo.is_empty() {
+        let item = state.todo.remove(0);
+        state.done.push(item.clone());
+        state.write_todo()?;
+        eprint!("Rebasing ({}/{})\r", state.done.len(), state.done.len() + state.todo.len());
+        let head = read_head_commit()?.context("Could not resolve HEAD to a commit")?;
+        match item.command {
+            TodoCommand::Pick | TodoCommand::Reword | TodoCommand::Edit | TodoCommand::Squash | TodoCommand::Fixup { .. } => {
+                if !rebase_pick(&state, &item, &head, config)? {
+                    return Err(Exit(1).into());
+                }
+                if item.command == TodoCommand::Edit {
+                    let hash = item.commit.as_deref().unwrap_or_default();
+                    let commit = CommitObject::read(hash)?;
+                    let amend = read_head_commit()?;
+                    StoppedCommit { hash: hash.to_string(), message: commit.message.clone(), author: commit.author, amend }.write()?;
+                    fs::write(REBASE_HEAD_PATH, format!("{hash}\n")).context(format!("Failed to write {REBASE_HEAD_PATH}"))?;
+                    eprint!("{}Stopped at {}...  {}
+You can amend the commit now, with
+
+  git commit --amend 
+
+Once you are satisfied with your changes, run
+
+  git rebase --continue
+", term_clear_line(), abbreviate(hash), first_line(&commit.message));
+                    return Ok(());
+                }
+            },
+            TodoCommand::Drop | TodoCommand::Noop => {},
+            TodoCommand::Exec => {
+                eprintln!("{}Executing: {}", term_clear_line(), item.arg);
+                let status = std::process::Command::new("sh").arg("-c").arg(&item.arg).status()
+                    .context(format!("Failed to run {}", item.arg))?;
+                if !status.success() {
+                    eprint!("warning: execution failed: {}\nYou can fix the problem, and then run\n\n  git rebase --continue\n\n\n", item.arg);
+                    return Err(Exit(1).into());
+                }
+            },
+            TodoCommand::Break => {
+                eprintln!("{}Stopped at {} ({})", term_clear_line(), abbreviate(&head), CommitObject::read(&head)?.subject());
+                return Ok(());
+            },
+            TodoCommand::Label => {
+                let committer = read_ident(Role::Committer, config)?;
+                update_ref(&format!("{LABELS_PREFIX}{}", item.arg), &head, None, &committer, &format!("rebase (label) '{}'", item.arg))?;
+            },
+            TodoCommand::Reset => {
+                let target = resolve_label(&item.arg)?;
+                switch_worktree(&head, &target, true)?;
+                let committer = read_ident(Role::Committer, config)?;
+                update_head(&target, Some(&head), &committer, &format!("rebase (reset): '{}'", item.arg))?;
+            },
+            TodoCommand::Merge { edit } => {
+                if !rebase_merge(&item, edit, &head, config)? {
+                    return Err(Exit(1).into());
+                }
+            },
+        }
+    }
+    finish_rebase(&state, config)
+}
+
+/// The commit a label of the todo list names, or any other revision
+fn resolve_label(label: &str) -> anyhow::Result<String> {
+    match read_ref(&format!("{LABELS_PREFIX}{label}"))? {
+        Some(hash) => Ok(hash),
+        None => resolve_revision(label).and_then(|x| peel(&x, ObjectType::Commit)).context(format!("could not resolve '{label}'")),
+    }
+}
+
+/// Replays a commit onto HEAD for the pick, reword, edit, squash and fixup commands. Commits whose parent is HEAD
+/// are taken as they are. Returns false when the rebase stops because of conflicts or because the commit became empty
+fn rebase_pick(state: &RebaseState, item: &TodoItem, head: &str, config: &Config) -> anyhow::Result<bool> {
+    let hash = item.commit.as_deref().unwrap_or_default();
+    let commit = CommitObject::read(hash)?;
+    if commit.parents.len() > 1 {
+        bail!("commit {hash} is a merge but no -m option was given.");
+    }
+    let committer = read_ident(Role::Committer, config)?;
+    // the root commits are replayed onto the empty commit as new root commits
+    let unborn = state.squash_onto.as_deref() == Some(head);
+    let fast_forward = !item.command.is_fixup() && match commit.parents.first() {
+        Some(parent) => parent == head,
+        None => unborn,
+    };
+    if fast_forward {
+        switch_worktree(head, hash, false)?;
+        update_head(hash, Some(head), &committer, "rebase: fast-forward")?;
+    } else {
+        let head_commit = CommitObject::read(head)?;
+        let base = match commit.parents.first() {
+            Some(parent) => peel(parent, ObjectType::Tree)?,
+            None => hash_object(&[][..], ObjectType::Tree, 0, true)?,
+        };
+        let label = format!("{} ({})", abbreviate(hash), first_line(&commit.message));
+        let options = merge_options("HEAD".to_string(), label.clone(), config)?;
+        let result = merge_trees(Some(&base), &head_commit.tree, &commit.tree, &format!("parent of {label}"), &options)?;
+        let merged_tree = result.tree.clone();
+        let mut index = Index::read()?;
+        let failure = format!("could not apply {}... {}", abbreviate(hash), first_line(&commit.message));
+        let conflicted_paths = apply_merge_result(&head_commit.tree, result, &mut index, &failure, true)?;
+
+        let mut chain = FixupChain::read()?;
+        let message = match item.command.is_fixup() {
+            true => {
+                chain.add(item, &head_commit.message, &commit.message);
+                chain.write()?;
+                chain.message.clone()
+            },
+            false => commit.message.clone(),
+        };
+        let becomes_empty = merged_tree == head_commit.tree && !item.command.is_fixup();
+        if becomes_empty && state.drop_redundant_commits {
+            eprintln!("dropping {hash} {} -- patch contents already upstream", first_line(&commit.message));
+            return Ok(true);
+        }
+        write_merge_message(&message, &conflicted_paths)?;
+        if !conflicted_paths.is_empty() || becomes_empty {
+            StoppedCommit { hash: hash.to_string(), message: commit.message.clone(), author: commit.author.clone(), amend: None }.write()?;
+            fs::write(REBASE_HEAD_PATH, format!("{hash}\n")).context(format!("Failed to write {REBASE_HEAD_PATH}"))?;
+            if becomes_empty {
+                fs::write(CHERRY_PICK_HEAD_PATH, format!("{hash}\n")).context(format!("Failed to write {CHERRY_PICK_HEAD_PATH}"))?;
+                print_nothing_to_commit(&read_head()?, false, &index, config)?;
+            } else {
+                eprintln!("error: {failure}");
+                eprintln!("hint: Resolve all conflicts manually, mark them as resolved with");
+                eprintln!("hint: \"git add/rm <conflicted_files>\", then run \"git rebase --continue\".");
+                eprintln!("hint: You can instead skip this commit: run \"git rebase --skip\".");
+                eprintln!("hint: To abort and get back to the state before \"git rebase\", run \"git rebase --abort\".");
+            }
+            eprintln!("Could not apply {}... {}", abbreviate(hash), first_line(&commit.message));
+            return Ok(false);
+        }
+        if item.command.is_fixup() {
+            commit_fixup(state, &chain, &merged_tree, config)?;
+        } else {
+            let parents = if unborn { vec![] } else { vec![head.to_string()] };
+            let reflog_action = format!("rebase ({})", item.command.name());
+            RebasedCommit::new(&merged_tree, &parents, &commit.author, &commit.message, &reflog_action).commit(config)?;
+        }
+        remove_merge_state()?;
+    }
+    if item.command == TodoCommand::Reword {
+        let head = CommitObject::read(&read_head_commit()?.unwrap_or_default())?;
+        RebasedCommit::new(&head.tree, &head.parents, &head.author, &head.message, "rebase (reword)").edited(true).commit(config)?;
+    }
+    Ok(true)
+}
+
+/// Merges the labelled commit into HEAD, with the message of the original merge commit when there is one.
+/// A merge whose original can be taken as it is fast-forwards to it. Returns false when the merge has conflicts
+fn rebase_merge(item: &TodoItem, edit: bool, head: &str, config: &Config) -> anyhow::Result<bool> {
+    let (label, oneline) = item.arg.split_once(char::is_whitespace).unwrap_or((&item.arg, ""));
+    let theirs = resolve_label(label)?;
+    let original = item.commit.as_deref().map(CommitObject::read).transpose()?;
+    let message = match (&original, oneline.trim_start().strip_prefix('#')) {
+        (Some(commit), _) => commit.message.clone(),
+        (None, Some(oneline)) => oneline.trim().to_string(),
+        (None, None) => format!("Merge branch '{label}'"),
+    };
+    let committer = read_ident(Role::Committer, config)?;
+    if let Some(commit) = original.as_ref().filter(|x| !edit && x.parents == [head.to_string(), theirs.clone()]) {
+        switch_worktree(head, &commit.hash, false)?;
+        update_head(&commit.hash, Some(head), &committer, "rebase: fast-forward")?;
+        return Ok(true);
+    }
+    let bases = merge_bases(head, &theirs)?;
+    // merging a commit that HEAD already contains does nothing
+    if bases == [theirs.clone()] {
+        return Ok(true);
+    }
+
+    let head_tree = peel(head, ObjectType::Tree)?;
+    let options = merge_options("HEAD".to_string(), label.to_string(), config)?;
+    let result = merge_commits(&bases, head, &theirs, &options)?;
+    let merged_tree = result.tree.clone();
+    let mut index = Index::read()?;
+    let failure = match &original {
+        Some(commit) => format!("Could not apply {}... {}", abbreviate(&commit.hash), item.arg),
+        None => format!("Could not merge {label}"),
+    };
+    let conflicted_paths = apply_merge_result(&head_tree, result, &mut index, &failure, false)?;
+    if !conflicted_paths.is_empty() {
+        fs::write(MERGE_HEAD_PATH, format!("{theirs}\n")).context(format!("Failed to write {MERGE_HEAD_PATH}"))?;
+        write_merge_message(&message, &conflicted_paths)?;
+        eprintln!("{failure}");
+        return Ok(false);
+    }
+    let author = match &original {
+        Some(commit) => commit.author.clone(),
+        None => read_ident(Role::Author, config)?,
+    };
+    RebasedCommit::new(&merged_tree, &[head.to_string(), theirs], &author, &message, "rebase (pick)").commit(config)?;
+    // like git, a merge whose message was not taken from an existing commit is committed first and then amended
+    if edit || original.is_none() {
+        let merge = CommitObject::read(&read_head_commit()?.unwrap_or_default())?;
+        RebasedCommit::new(&merge.tree, &merge.parents, &merge.author, &merge.message, "rebase (pick)").edited(true).commit(config)?;
+    }
+    Ok(true)
+}
+
+/// A commit to make during a rebase, on top of HEAD or in place of it
+struct RebasedCommit<'a> {
+    tree: &'a str,
+    parents: &'a [String],
+    author: &'a Signature,
+    message: &'a str,
+    reflog_action: &'a str,
+    /// the user edits the message, and the summary of the commit is printed
+    edit: bool,
+    /// the summary shows the author date
+    show_date: bool,
+}
+impl<'a> RebasedCommit<'a> {
+    fn new(tree: &'a str, parents: &'a [String], author: &'a Signature, message: &'a str, reflog_action: &'a str) -> Self {
+        Self { tree, parents, author, message, reflog_action, edit: false, show_date: false }
+    }
+
+    /// Lets the user edit the message before committing, the summary shows the author date with `show_date`
+    fn edited(self, show_date: bool) -> Self {
+        Self { edit: true, show_date, ..self }
+    }
+
+    /// Commits the tree and moves HEAD to the commit
+    fn commit(&self, config: &Config) -> anyhow::Result<()> {
+        let committer = read_ident(Role::Committer, config)?;
+        let head_commit = read_head_commit()?;
+        let parent_tree = match self.parents.first() {
+            Some(parent) => Some(peel(parent, ObjectType::Tree)?),
+            None => None,
+        };
+        let mut message = self.message.to_string();
+        if self.edit {
+            let idents = template_idents(self.author, &committer, self.show_date);
+            let edited = edit_commit_message(&strip_space(&message, false), CleanupMode::Strip, &idents, parent_tree.as_deref(), &Index::read()?, config)?;
+            message = cleanup_message(&edited, CleanupMode::Strip);
+            if is_message_empty(&message, CleanupMode::Strip) {
+                eprintln!("Aborting commit due to empty commit message.");
+                return Err(Exit(1).into());
+            }
+        }
+        let parent_refs = self.parents.iter().map(String::as_str).collect::<Vec<_>>();
+        let hash = write_commit(self.tree, &parent_refs, self.author, &committer, &message)?;
+        // amending a commit without changing it leaves no trace in the reflog
+        if head_commit.as_deref() != Some(&hash) {
+            update_head(&hash, head_commit.as_deref(), &committer, &format!("{}: {}", self.reflog_action, first_line(&message)))?;
+        }
+        if self.edit {
+            print_commit_summary(&read_head()?, &hash, &message, parent_tree.as_deref(), self.tree, self.author, &committer, self.show_date, config)?;
+        }
+        Ok(())
+    }
+}
+
+/// Amends HEAD with the changes of a commit of a fixup chain. The last commit of the chain gets the final message,
+/// which the user edits when it combines the messages of a squash
+fn commit_fixup(state: &RebaseState, chain: &FixupChain, tree: &str, config: &Config) -> anyhow::Result<()> {
+    let head = CommitObject::read(&read_head_commit()?.unwrap_or_default())?;
+    let reflog_action = format!("rebase ({})", chain.fixups.last().map_or("fixup", |x| x.command.name()));
+    if state.todo.first().is_some_and(|x| x.command.is_fixup()) {
+        return RebasedCommit::new(tree, &head.parents, &head.author, &chain.message, &reflog_action).commit(config);
+    }
+    match chain.edits_message() {
+        true => RebasedCommit::new(tree, &head.parents, &head.author, &chain.final_message(), &reflog_action).edited(true).commit(config)?,
+        false => {
+            let message = cleanup_message(&chain.final_message(), CleanupMode::Strip);
+            RebasedCommit::new(tree, &head.parents, &head.author, &message, &reflog_action).commit(config)?;
+        },
+    }
+    FixupChain::remove()
+}
+
+/// Puts the rebased branch at HEAD and checks it out again
+fn finish_rebase(state: &RebaseState, config: &Config) -> anyhow::Result<()> {
+    let head = read_head_commit()?.context("Could not resolve HEAD to a commit")?;
+    if state.head_name.starts_with("refs/") {
+        let committer = read_ident(Role::Committer, config)?;
+        if head != state.orig_head {
+            let message = format!("rebase (finish): {} onto {}", state.head_name, state.onto);
+            update_ref(&state.head_name, &head, Some(&state.orig_head), &committer, &message)?;
+        }
+        let message = format!("rebase (finish): returning to {}", state.head_name);
+        write_head(&Head::Branch(state.head_name.clone()), Some(&head), &head, &committer, &message)?;
+    }
+    RebaseState::remove()?;
+    remove_rebase_head()?;
+    eprintln!("{}Successfully rebased and updated {}.", term_clear_line(), state.head_name);
+    Ok(())
+}
+
+fn remove_rebase_head() -> anyhow::Result<()> {
+    remove_files(&[REBASE_HEAD_PATH])
+}
+
+/// Commits the resolved conflicts or the changes to the edited commit where the rebase stopped,
+/// then runs the commands left in the todo list
+pub(crate) fn rebase_continue(config: &Config) -> anyhow::Result<()> {
+    if !RebaseState::in_progress() {
+        bail!("No rebase in progress?");
+    }
+    let data = fs::read_to_string(TODO_PATH).context(format!("Failed to read {TODO_PATH}"))?;
+    if let Err(error) = parse_todo(&data) {
+        eprintln!("error: {error}");
+        eprintln!("error: please fix this using 'git rebase --edit-todo'.");
+        return Err(Exit(1).into());
+    }
+    let state = RebaseState::read()?;
+    let index = Index::read()?;
+    let mut unmerged = index.entries.iter().filter(|x| x.stage != 0).map(|x| x.path.as_str()).collect::<Vec<_>>();
+    unmerged.dedup();
+    if !unmerged.is_empty() || !worktree_changes(&index)?.is_empty() {
+        unmerged.iter().for_each(|x| eprintln!("{x}: needs merge"));
+        eprintln!("You must edit all merge conflicts and then\nmark them as resolved using git add");
+        return Err(Exit(1).into());
+    }
+
+    let head = CommitObject::read(&read_head_commit()?.context("Could not resolve HEAD to a commit")?)?;
+    let tree = write_index_tree(&index)?;
+    let merge_head = fs::read_to_string(MERGE_HEAD_PATH).ok().map(|x| x.trim().to_string());
+    match StoppedCommit::read()? {
+        // the user may have amended the edited commit already
+        Some(StoppedCommit { amend: Some(amend), .. }) if amend == head.hash && tree != head.tree => {
+            RebasedCommit::new(&tree, &head.parents, &head.author, &head.message, "rebase (continue)").edited(true).commit(config)?;
+        },
+        Some(_) if state.done.last().is_some_and(|x| x.command.is_fixup()) => commit_fixup(&state, &FixupChain::read()?, &tree, config)?,
+        Some(stopped) if stopped.amend.is_none() && (tree != head.tree || merge_head.is_some()) => {
+            let parents = [Some(head.hash.clone()), merge_head].into_iter().flatten().collect::<Vec<_>>();
+            let message = fs::read_to_string(MERGE_MSG_PATH).unwrap_or(stopped.message);
+            RebasedCommit::new(&tree, &parents, &stopped.author, &message, "rebase (continue)").edited(false).commit(config)?;
+        },
+        _ => {},
+    }
+    remove_merge_state()?;
+    StoppedCommit::remove()?;
+    remove_rebase_head()?;
+    run_rebase(state, config)
+}
+
+/// Drops the changes of the commit the rebase stopped at and runs the commands left in the todo list
+pub(crate) fn rebase_skip(config: &Config) -> anyhow::Result<()> {
+    if !RebaseState::in_progress() {
+        bail!("No rebase in progress?");
+    }
+    let state = RebaseState::read()?;
+    let head = read_head_commit()?.context("Could not resolve HEAD to a commit")?;
+    switch_worktree(&head, &head, true)?;
+    remove_merge_state()?;
+    StoppedCommit::remove()?;
+    remove_rebase_head()?;
+    // a skipped fixup leaves the chain, which ends here if no other fixup follows
+    if state.done.last().is_some_and(|x| x.command.is_fixup()) {
+        let mut chain = FixupChain::read()?;
+        chain.fixups.pop();
+        match chain.fixups.is_empty() {
+            true => FixupChain::remove()?,
+            false => {
+                chain.write()?;
+                commit_fixup(&state, &chain, &peel(&head, ObjectType::Tree)?, config)?;
+            },
+        }
+    }
+    run_rebase(state, config)
+}
+
+/// Goes back to the branch and the commit the rebase started from
+pub(crate) fn rebase_abort(config: &Config) -> anyhow::Result<()> {
+    if !RebaseState::in_progress() {
+        bail!("No rebase in progress?");
+    }
+    let (head_name, orig_head) = RebaseState::read_orig_head()?;
+    let head = read_head_commit()?.context("Could not resolve HEAD to a commit")?;
+    switch_worktree(&head, &orig_head, true)?;
+    let committer = read_ident(Role::Committer, config)?;
+    let target = match head_name.starts_with("refs/") {
+        true => Head::Branch(head_name.clone()),
+        false => Head::Detached(orig_head.clone()),
+    };
+    let returning_to = if head_name.starts_with("refs/") { &head_name } else { &orig_head };
+    write_head(&target, Some(&head), &orig_head, &committer, &format!("rebase (abort): returning to {returning_to}"))?;
+    remove_merge_state()?;
+    remove_rebase_head()?;
+    RebaseState::remove()
+}
+
+/// Lets the user edit the commands left in the todo list, which are saved with full hashes
+pub(crate) fn rebase_edit_todo(config: &Config) -> anyhow::Result<()> {
+    if !RebaseState::in_progress() {
+        bail!("No rebase in progress?");
+    }
+    let data = fs::read_to_string(TODO_PATH).context(format!("Failed to read {TODO_PATH}"))?;
+    // a broken todo list is edited as it is
+    match parse_todo(&data) {
+        Ok(todo) => fs::write(TODO_PATH, format_remaining_todo(&todo)).context(format!("Failed to write {TODO_PATH}"))?,
+        Err(error) => eprintln!("error: {error}"),
+    }
+    launch_sequence_editor(Path::new(TODO_PATH), config)?;
+    let data = fs::read_to_string(TODO_PATH).context(format!("Failed to read {TODO_PATH}"))?;
+    match parse_todo(&data) {
+        Ok(todo) => fs::write(TODO_PATH, format_todo(&todo)).context(format!("Failed to write {TODO_PATH}")),
+        Err(error) => {
+            eprintln!("error: {error}");
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn item(command: TodoCommand, hash: &str, subject: &str) -> TodoItem {
+        TodoItem { command, commit: Some(hash.repeat(40)), arg: subject.to_string() }
+    }
+
+    #[test]
+    fn test_autosquash() {
+        let fixup = TodoCommand::Fixup { replace_message: false, edit: false };
+        let amend = TodoCommand::Fixup { replace_message: true, edit: false };
+        let items = vec![
+            item(TodoCommand::Pick, "1", "first"),
+            item(TodoCommand::Pick, "2", "second"),
+            item(TodoCommand::Pick, "3", "fixup! first"),
+            item(TodoCommand::Pick, "4", "squash! sec"),
+            item(TodoCommand::Pick, "5", "amend! fixup! first"),
+            item(TodoCommand::Pick, "6", "fixup! unknown"),
+        ];
+        let expected = vec![
+            item(TodoCommand::Pick, "1", "first"),
+            item(fixup, "3", "fixup! first"),
+            item(amend, "5", "amend! fixup! first"),
+            item(TodoCommand::Pick, "2", "second"),
+            item(TodoCommand::Squash, "4", "squash! sec"),
+            item(TodoCommand::Pick, "6", "fixup! unknown"),
+        ];
+        assert_eq!(expected, autosquash(items));
+    }
+
+    #[test]
+    fn test_fixup_chain_message() {
+        let mut chain = FixupChain::default();
+        let fixup = TodoCommand::Fixup { replace_message: false, edit: false };
+        chain.add(&item(fixup, "2", ""), "first\n", "fixup! first\n");
+        chain.add(&item(TodoCommand::Squash, "3", ""), "", "squash! first\n\nmore\n");
+        assert_eq!(
+            "# This is a combination of 3 commits.\n# This is the 1st commit message:\n\nfirst\n\n\
+            # The commit message #2 will be skipped:\n\n# fixup! first\n\n\
+            # This is the commit message #3:\n\n# squash! first\n\nmore\n",
+            chain.message,
+        );
+        assert!(chain.edits_message());
+    }
+}
@@ -0,0 +1,459 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use anyhow::{bail, Context};
+use crate::common::{GIT_PATH, ObjectType};
+use crate::config::Config;
+use crate::diff::NULL_HASH;
+use crate::fetch::has_object;
+use crate::ident::{read_ident_or_default, Role};
+use crate::merge_base::CommitGraph;
+use crate::pack::{index_pack, read_pack_stream};
+use crate::pkt_line::{Band, BandWriter, MAX_PACKET_LEN, Packet, PacketReader, PacketWriter, text_line};
+use crate::refs::{delete_ref, Head, HEADS_PREFIX, is_valid_ref_name, list_refs, read_head, read_ref, update_ref};
+use crate::rev_list::{rev_list, RevListOptions};
+use crate::rev_parse::peel;
+use crate::smart_http::{AGENT, CAPABILITIES_REF, OBJECT_FORMAT, ProtocolVersion};
+use crate::upload_pack::{AdvertisedRef, ServiceOptions};
+
+const HOOKS_PATH: &str = ".git/hooks";
+
+/// git shows this when receive.denyCurrentBranch is not set
+const DENY_CURRENT_BRANCH_MESSAGE: &str = "\
+By default, updating the current branch in a non-bare repository
+is denied, because it will make the index and work tree inconsistent
+with what you pushed, and will require 'git reset --hard' to match
+the work tree to HEAD.
+
+You can set the 'receive.denyCurrentBranch' configuration variable
+to 'ignore' or 'warn' in the remote repository to allow pushing into
+its current branch; however, this is not recommended unless you
+arranged to update its work tree to match what you pushed in some
+other way.
+
+To squelch this message and still keep the default behaviour, set
+'receive.denyCurrentBranch' configuration variable to 'refuse'.";
+
+/// git shows this when receive.denyDeleteCurrent is not set
+const DENY_DELETE_CURRENT_MESSAGE: &str = "\
+By default, deleting the current branch is denied, because the next
+'git clone' won't result in any file checked out, causing confusion.
+
+You can set 'receive.denyDeleteCurrent' configuration variable to
+'warn' or 'ignore' in the remote repository to allow deleting the
+current branch, with or without a warning message.
+
+To squelch this message, you can set it to 'refuse'.";
+
+/// A ref update a push asks for, old and new are None for a ref that does not exist before or after it
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RefUpdate {
+    pub name: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+impl RefUpdate {
+    /// The command line of the update, like `<old> <new> refs/heads/main`
+    pub fn encode(&self) -> String {
+        let old = self.old.as_deref().unwrap_or(NULL_HASH);
+        let new = self.new.as_deref().unwrap_or(NULL_HASH);
+        format!("{old} {new} {}", self.name)
+    }
+
+    /// Parses a command line of a push
+    pub fn decode(line: &str) -> anyhow::Result<Self> {
+        let mut parts = line.splitn(3, ' ');
+        let (Some(old), Some(new), Some(name)) = (parts.next(), parts.next(), parts.next()) else {
+            bail!("protocol error: expected old/new/ref, got '{line}'");
+        };
+        if [old, new].iter().any(|x| x.len() != 40 || !x.bytes().all(|x| x.is_ascii_hexdigit())) {
+            bail!("protocol error: expected old/new/ref, got '{line}'");
+        }
+        let value = |hash: &str| Some(hash.to_ascii_lowercase()).filter(|x| x != NULL_HASH);
+        Ok(Self { name: name.to_string(), old: value(old), new: value(new) })
+    }
+}
+
+/// The refs and capabilities receive-pack advertises to the ones pushing to it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct PushAdvertisement {
+    pub refs: Vec<AdvertisedRef>,
+    pub capabilities: Vec<String>,
+}
+impl PushAdvertisement {
+    /// The refs of the current repository, sorted by name, and the capabilities of its receive-pack
+    pub fn read() -> anyhow::Result<Self> {
+        let config = Config::read()?;
+        let refs = list_refs("refs/")?.into_iter().map(|(name, hash)| AdvertisedRef { name, hash, peeled: None }).collect();
+        let mut capabilities = ["report-status", "delete-refs", "side-band-64k", "quiet", "atomic", "ofs-delta"].map(str::to_string).to_vec();
+        if matches!(config.get("receive.advertisePushOptions"), Some("true" | "yes" | "on" | "1")) {
+            capabilities.push("push-options".to_string());
+        }
+        capabilities.push(format!("object-format={OBJECT_FORMAT}"));
+        capabilities.push(format!("agent={AGENT}"));
+        Ok(Self { refs, capabilities })
+    }
+
+    /// Writes the refs with the capabilities after a NUL on the first line, a repository without refs
+    /// sends them on a line of its own
+    pub fn write(&self, writer: &mut PacketWriter<impl Write>) -> anyhow::Result<()> {
+        let capabilities = self.capabilities.join(" ");
+        match self.refs.split_first() {
+            None => writer.write_data(format!("{NULL_HASH} {CAPABILITIES_REF}\0{capabilities}\n").as_bytes())?,
+            Some((first, rest)) => {
+                writer.write_data(format!("{} {}\0{capabilities}\n", first.hash, first.name).as_bytes())?;
+                for advertised in rest {
+                    writer.write_line(&format!("{} {}", advertised.hash, advertised.name))?;
+                }
+            },
+        }
+        writer.write_flush()
+    }
+
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.iter().any(|x| x == name || x.split_once('=').is_some_and(|(key, _)| key == name))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ReceivePackOptions {
+    /// update all refs or none of them
+    pub atomic: bool,
+    /// strings for the hooks of the receiving repository
+    pub push_options: Vec<String>,
+}
+
+/// What receive-pack reports back with report-status
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PushReport {
+    /// why the pack could not be stored, None if it was
+    pub unpack_error: Option<String>,
+    /// each ref with the reason it was not updated, None if it was
+    pub refs: Vec<(String, Option<String>)>,
+}
+impl PushReport {
+    /// Reads the report lines, `unpack ok` followed by `ok <ref>` or `ng <ref> <reason>` for each ref
+    pub fn read(reader: &mut PacketReader<impl Read>) -> anyhow::Result<Self> {
+        let (lines, _) = reader.read_until_special()?;
+        let mut lines = lines.iter().map(|x| text_line(x));
+        let unpack = lines.next().context("the remote end hung up unexpectedly")?;
+        let unpack_error = match unpack.strip_prefix("unpack ") {
+            Some("ok") => None,
+            Some(error) => Some(error.to_string()),
+            None => bail!("unpack failed: unexpected '{unpack}'"),
+        };
+        let mut refs = vec![];
+        for line in lines {
+            match (line.strip_prefix("ok "), line.strip_prefix("ng ")) {
+                (Some(name), _) => refs.push((name.to_string(), None)),
+                (_, Some(rest)) => {
+                    let (name, reason) = rest.split_once(' ').unwrap_or((rest, "failed"));
+                    refs.push((name.to_string(), Some(reason.to_string())));
+                },
+                _ => bail!("invalid ref status from remote: {line}"),
+            }
+        }
+        Ok(Self { unpack_error, refs })
+    }
+
+    pub fn write(&self, writer: &mut PacketWriter<impl Write>) -> anyhow::Result<()> {
+        match &self.unpack_error {
+            None => writer.write_line("unpack ok")?,
+            Some(error) => writer.write_line(&format!("unpack {error}"))?,
+        }
+        for (name, error) in &self.refs {
+            match error {
+                None => writer.write_line(&format!("ok {name}"))?,
+                Some(reason) => writer.write_line(&format!("ng {name} {reason}"))?,
+            }
+        }
+        writer.write_flush()
+    }
+}
+
+/// The request of a push: the updates, the first one with the capabilities after a NUL, then the push options
+/// and the pack
+pub(crate) fn encode_push_request(updates: &[RefUpdate], capabilities: &[&str], push_options: &[String], pack: Option<&[u8]>) -> anyhow::Result<Vec<u8>> {
+    let mut writer = PacketWriter::new(vec![]);
+    for (position, update) in updates.iter().enumerate() {
+        match position {
+            0 => writer.write_data(format!("{}\0{}\n", update.encode(), capabilities.join(" ")).as_bytes())?,
+            _ => writer.write_line(&update.encode())?,
+        }
+    }
+    writer.write_flush()?;
+    if capabilities.contains(&"push-options") {
+        for push_option in push_options {
+            writer.write_line(push_option)?;
+        }
+        writer.write_flush()?;
+    }
+    let mut request = writer.into_inner();
+    request.extend_from_slice(pack.unwrap_or_default());
+    Ok(request)
+}
+
+/// Stores the pack of a push in the current repository and applies the updates that pass the checks of the
+/// receive.* config and the hooks. Why a ref is refused is written to the messages, the way git's receive-pack does,
+/// along with the output of the hooks
+pub(crate) fn receive_pack(updates: &[RefUpdate], pack: Option<&[u8]>, options: &ReceivePackOptions, messages: &mut impl Write) -> anyhow::Result<PushReport> {
+    let config = Config::read()?;
+    if let Some(Err(error)) = pack.map(index_pack) {
+        let error = format!("{error:#}");
+        writeln!(messages, "error: unpack failed: {error}")?;
+        let refs = updates.iter().map(|x| (x.name.clone(), Some("unpacker error".to_string()))).collect();
+        return Ok(PushReport { unpack_error: Some(error), refs });
+    }
+    let mut results = vec![];
+    for update in updates {
+        results.push((!is_connected(update)?).then(|| "missing necessary objects".to_string()));
+    }
+    let pending = updates.iter().zip(&results).filter(|(_, result)| result.is_none()).map(|(update, _)| update);
+    if !run_hook("pre-receive", &[], &hook_input(pending), options, &config, messages)? {
+        for result in results.iter_mut().filter(|x| x.is_none()) {
+            *result = Some("pre-receive hook declined".to_string());
+        }
+    }
+    let mut graph = CommitGraph::default();
+    for (update, result) in updates.iter().zip(results.iter_mut()).filter(|(_, result)| result.is_none()) {
+        *result = check_update(update, options, &config, &mut graph, messages)?;
+    }
+    if options.atomic && results.iter().any(Option::is_some) {
+        for result in results.iter_mut().filter(|x| x.is_none()) {
+            *result = Some("atomic transaction failed".to_string());
+        }
+    }
+    // deleting a ref that does not exist succeeds, but there is nothing to tell post-receive about
+    let mut existed = vec![];
+    for update in updates {
+        existed.push(update.new.is_some() || read_ref(&update.name)?.is_some());
+    }
+    let committer = read_ident_or_default(Role::Committer, &config)?;
+    for (update, result) in updates.iter().zip(results.iter_mut()).filter(|(_, result)| result.is_none()) {
+        let applied = match &update.new {
+            Some(new) => update_ref(&update.name, new, update.old.as_deref(), &committer, "push"),
+            None => delete_ref(&update.name),
+        };
+        if let Err(error) = applied {
+            writeln!(messages, "error: {error:#}")?;
+            *result = Some("failed to update ref".to_string());
+        }
+    }
+    let applied = updates.iter().zip(results.iter().zip(existed)).filter(|(_, (result, existed))| result.is_none() && *existed).map(|(update, _)| update);
+    run_hook("post-receive", &[], &hook_input(applied), options, &config, messages)?;
+    let refs = updates.iter().zip(results).map(|(update, result)| (update.name.clone(), result)).collect();
+    Ok(PushReport { unpack_error: None, refs })
+}
+
+/// Whether all objects the new value of the ref needs are in the repository now, the ones reachable from
+/// the refs it already has are
+fn is_connected(update: &RefUpdate) -> anyhow::Result<bool> {
+    let Some(new) = &update.new else {
+        return Ok(true);
+    };
+    let existing = list_refs("refs/")?.into_iter().map(|(_, hash)| hash).filter(|x| has_object(x)).collect::<Vec<_>>();
+    let options = RevListOptions { objects: true, ..RevListOptions::default() };
+    match rev_list(std::slice::from_ref(new), &existing, &options) {
+        Ok(listed) => Ok(listed.objects.iter().all(|x| has_object(&x.hash))),
+        Err(_) => Ok(false),
+    }
+}
+
+/// The stdin of pre-receive and post-receive, a line `<old> <new> <ref>` for each update
+fn hook_input<'a>(updates: impl Iterator<Item = &'a RefUpdate>) -> String {
+    updates.map(|x| format!("{}\n", x.encode())).collect()
+}
+
+/// Runs a hook of the repository with the input on its stdin, and copies what it outputs to the messages.
+/// Returns whether it succeeded, which a hook that does not exist or is not executable always does
+fn run_hook(name: &str, args: &[&str], input: &str, options: &ReceivePackOptions, config: &Config, messages: &mut impl Write) -> anyhow::Result<bool> {
+    // a relative core.hooksPath is relative to where the hooks run
+    let hooks_dir = config.get("core.hooksPath").map_or(PathBuf::from(HOOKS_PATH), |x| Path::new(GIT_PATH).join(x));
+    let path = hooks_dir.join(name);
+    if !fs::metadata(&path).is_ok_and(|x| x.is_file() && x.permissions().mode() & 0o111 != 0) {
+        return Ok(true);
+    }
+    if input.is_empty() && name != "update" {
+        return Ok(true);
+    }
+    let path = fs::canonicalize(&path).context(format!("Failed to resolve {}", path.display()))?;
+    let (mut output, output_writer) = io::pipe().context(format!("cannot run {name} hook"))?;
+    let mut command = Command::new(&path);
+    command.args(args)
+        // like git's receive-pack, the hooks run in the git dir, even for a repository with a working tree
+        .current_dir(GIT_PATH)
+        .env("GIT_DIR", ".")
+        .stdin(Stdio::piped())
+        .stdout(output_writer.try_clone().context(format!("cannot run {name} hook"))?)
+        .stderr(output_writer);
+    if !options.push_options.is_empty() {
+        command.env("GIT_PUSH_OPTION_COUNT", options.push_options.len().to_string());
+        for (position, push_option) in options.push_options.iter().enumerate() {
+            command.env(format!("GIT_PUSH_OPTION_{position}"), push_option);
+        }
+    }
+    let mut child = command.spawn().context(format!("cannot run {name} hook"))?;
+    // the command holds the other ends of the pipe, the output only ends once they are closed
+    drop(command);
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_string();
+    // a hook does not have to read its input, and it may output a lot before it does
+    let feeder = thread::spawn(move || {
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    io::copy(&mut output, messages).context(format!("Failed to read the output of the {name} hook"))?;
+    let _ = feeder.join();
+    let status = child.wait().context(format!("cannot run {name} hook"))?;
+    Ok(status.success())
+}
+
+/// Serves pushes to the current repository from the client at the other end of the streams, like git receive-pack.
+/// It only speaks v0, v1 just adds a version line in front of the refs
+pub(crate) fn serve_receive_pack(input: impl BufRead, output: impl Write, options: &ServiceOptions) -> anyhow::Result<()> {
+    let mut writer = PacketWriter::new(output);
+    if options.advertise_refs || !options.stateless_rpc {
+        if options.version == ProtocolVersion::V1 {
+            writer.write_line("version 1")?;
+        }
+        PushAdvertisement::read()?.write(&mut writer)?;
+    }
+    if options.advertise_refs {
+        return Ok(());
+    }
+    let mut reader = PacketReader::new(input);
+    let mut updates = vec![];
+    let mut capabilities = vec![];
+    // a client with nothing to push hangs up or sends an empty list
+    while let Some(Packet::Data(data)) = reader.read_packet()? {
+        let line = text_line(&data);
+        let line = match line.split_once('\0').filter(|_| updates.is_empty()) {
+            Some((line, client_capabilities)) => {
+                capabilities = client_capabilities.split_whitespace().map(str::to_string).collect();
+                line.to_string()
+            },
+            None => line,
+        };
+        // the commits where the history of a shallow client ends do not change the updates
+        if !line.starts_with("shallow ") {
+            updates.push(RefUpdate::decode(&line)?);
+        }
+    }
+    if updates.is_empty() {
+        return Ok(());
+    }
+    let has = |name: &str| capabilities.iter().any(|x| x == name);
+    let mut receive_options = ReceivePackOptions { atomic: has("atomic"), push_options: vec![] };
+    if has("push-options") {
+        let (lines, _) = reader.read_until_special()?;
+        receive_options.push_options = lines.iter().map(|x| text_line(x)).collect();
+    }
+    let pack = match updates.iter().any(|x| x.new.is_some()) {
+        true => Some(read_pack_stream(reader.get_mut())?),
+        false => None,
+    };
+    let sideband = has("side-band-64k");
+    let report = match sideband {
+        true => receive_pack(&updates, pack.as_deref(), &receive_options, &mut BandWriter::new(&mut writer, Band::Progress, MAX_PACKET_LEN))?,
+        false => receive_pack(&updates, pack.as_deref(), &receive_options, &mut io::stderr())?,
+    };
+    if has("report-status") {
+        match sideband {
+            true => {
+                let mut report_writer = PacketWriter::new(vec![]);
+                report.write(&mut report_writer)?;
+                writer.write_band(Band::Data, &report_writer.into_inner(), MAX_PACKET_LEN)?;
+            },
+            false => report.write(&mut writer)?,
+        }
+    }
+    if sideband {
+        writer.write_flush()?;
+    }
+    writer.flush()
+}
+
+/// Why receive-pack refuses an update, None if it can be applied
+fn check_update(update: &RefUpdate, options: &ReceivePackOptions, config: &Config, graph: &mut CommitGraph, messages: &mut impl Write) -> anyhow::Result<Option<String>> {
+    let name = &update.name;
+    let enabled = |key: &str| matches!(config.get(key), Some("true" | "yes" | "on" | "1"));
+    if !name.starts_with("refs/") || !is_valid_ref_name(name) {
+        writeln!(messages, "error: refusing to create funny ref '{name}' remotely")?;
+        return Ok(Some("funny refname".to_string()));
+    }
+    let bare = enabled("core.bare");
+    let current = !bare && read_head()? == Head::Branch(name.clone());
+    let allowed = |key: &str| matches!(config.get(key), Some("ignore" | "warn" | "false" | "no" | "off" | "0"));
+    if current {
+        let setting = config.get("receive.denyCurrentBranch");
+        if !allowed("receive.denyCurrentBranch") {
+            writeln!(messages, "error: refusing to update checked out branch: {name}")?;
+            if setting.is_none() {
+                writeln!(messages, "error: {DENY_CURRENT_BRANCH_MESSAGE}")?;
+            }
+            return Ok(Some("branch is currently checked out".to_string()));
+        }
+        if setting == Some("warn") {
+            writeln!(messages, "warning: updating the current branch")?;
+        }
+    }
+    let Some(new) = &update.new else {
+        if name.starts_with(HEADS_PREFIX) && enabled("receive.denyDeletes") {
+            writeln!(messages, "error: denying ref deletion for {name}")?;
+            return Ok(Some("deletion prohibited".to_string()));
+        }
+        if current && !allowed("receive.denyDeleteCurrent") {
+            if config.get("receive.denyDeleteCurrent").is_none() {
+                writeln!(messages, "error: {DENY_DELETE_CURRENT_MESSAGE}")?;
+            }
+            writeln!(messages, "error: refusing to delete the current branch: {name}")?;
+            return Ok(Some("deletion of the current branch prohibited".to_string()));
+        }
+        let declined = run_update_hook(update, options, config, messages)?;
+        if declined.is_none() && read_ref(name)?.is_none() {
+            writeln!(messages, "warning: deleting a non-existent ref")?;
+        }
+        return Ok(declined);
+    };
+    if !has_object(new) {
+        writeln!(messages, "error: unpack should have generated {new}, but I can't find it!")?;
+        return Ok(Some("bad pack".to_string()));
+    }
+    if let Some(old) = update.old.as_deref().filter(|_| enabled("receive.denyNonFastForwards")) {
+        let fast_forward = match (peel(old, ObjectType::Commit), peel(new, ObjectType::Commit)) {
+            (Ok(old), Ok(new)) => graph.is_ancestor(&old, &new)?,
+            _ => false,
+        };
+        if !fast_forward {
+            writeln!(messages, "error: denying non-fast-forward {name} (you should pull first)")?;
+            return Ok(Some("non-fast-forward".to_string()));
+        }
+    }
+    if let Some(declined) = run_update_hook(update, options, config, messages)? {
+        return Ok(Some(declined));
+    }
+    let current_value = read_ref(name)?;
+    if current_value != update.old {
+        match (current_value, &update.old) {
+            (Some(current), Some(expected)) => writeln!(messages, "error: cannot lock ref '{name}': is at {current} but expected {expected}")?,
+            (Some(_), None) => writeln!(messages, "error: cannot lock ref '{name}': reference already exists")?,
+            (None, _) => writeln!(messages, "error: cannot lock ref '{name}': unable to resolve reference '{name}'")?,
+        }
+        return Ok(Some("failed to update ref".to_string()));
+    }
+    Ok(None)
+}
+
+/// Runs the update hook with the ref and its old and new values, which can refuse the update
+fn run_update_hook(update: &RefUpdate, options: &ReceivePackOptions, config: &Config, messages: &mut impl Write) -> anyhow::Result<Option<String>> {
+    let old = update.old.as_deref().unwrap_or(NULL_HASH);
+    let new = update.new.as_deref().unwrap_or(NULL_HASH);
+    if run_hook("update", &[&update.name, old, new], "", options, config, messages)? {
+        return Ok(None);
+    }
+    writeln!(messages, "error: hook declined to update {}", update.name)?;
+    Ok(Some("hook declined".to_string()))
+}
@@ -0,0 +1,433 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use anyhow::{bail, Context};
+use crate::commit_object_read::Signature;
+use crate::config::Config;
+use crate::common::{GIT_PATH, HASH_ENCODED_LEN, HEAD_PATH};
+use crate::diff::NULL_HASH;
+use crate::lock_file::LockFile;
+
+pub(crate) const REFS_PATH: &str = ".git/refs";
+pub(crate) const PACKED_REFS_PATH: &str = ".git/packed-refs";
+pub(crate) const HEADS_PREFIX: &str = "refs/heads/";
+pub(crate) const TAGS_PREFIX: &str = "refs/tags/";
+pub(crate) const REMOTES_PREFIX: &str = "refs/remotes/";
+pub(crate) const LOGS_PATH: &str = ".git/logs";
+const ORIG_HEAD_PATH: &str = ".git/ORIG_HEAD";
+
+const SYMREF_PREFIX: &str = "ref: ";
+const MAX_SYMREF_DEPTH: usize = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Head {
+    /// HEAD points to a branch, contains the full ref name, the branch itself may not exist yet
+    Branch(String),
+    Detached(String),
+}
+
+pub(crate) fn read_head() -> anyhow::Result<Head> {
+    let contents = fs::read_to_string(HEAD_PATH).context(format!("Failed to read {HEAD_PATH}"))?;
+    let contents = contents.trim_end();
+    if let Some(target) = contents.strip_prefix(SYMREF_PREFIX) {
+        return Ok(Head::Branch(target.to_string()));
+    }
+    if !is_full_hash(contents) {
+        bail!("Invalid contents of {HEAD_PATH}: {contents}");
+    }
+    Ok(Head::Detached(contents.to_string()))
+}
+
+/// Hash of the commit that HEAD points to, None if the current branch has no commits yet
+pub(crate) fn read_head_commit() -> anyhow::Result<Option<String>> {
+    match read_head()? {
+        Head::Branch(ref_name) => read_ref(&ref_name),
+        Head::Detached(hash) => Ok(Some(hash)),
+    }
+}
+
+/// Reads a ref by its full name, following symbolic refs
+pub(crate) fn read_ref(ref_name: &str) -> anyhow::Result<Option<String>> {
+    let mut ref_name = ref_name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        let path = format!("{GIT_PATH}/{ref_name}");
+        let path = Path::new(&path);
+        if !path.is_file() {
+            return read_packed_ref(&ref_name);
+        }
+        let contents = fs::read_to_string(path).context(format!("Failed to read ref {}", path.display()))?;
+        let contents = contents.trim_end();
+        if let Some(target) = contents.strip_prefix(SYMREF_PREFIX) {
+            ref_name = target.to_string();
+            continue;
+        }
+        // FETCH_HEAD contains a description after the hash
+        let contents = contents.split_whitespace().next().unwrap_or("");
+        if !is_full_hash(contents) {
+            bail!("Invalid contents of ref {}: {contents}", path.display());
+        }
+        return Ok(Some(contents.to_string()));
+    }
+    bail!("Symbolic ref {ref_name} is nested too deep");
+}
+
+/// Points HEAD, or the branch it is on, to a new commit. The current value must be the expected one, None if it does not exist yet
+pub(crate) fn update_head(new: &str, expected_old: Option<&str>, committer: &Signature, message: &str) -> anyhow::Result<()> {
+    match read_head()? {
+        Head::Branch(ref_name) => update_ref(&ref_name, new, expected_old, committer, message),
+        Head::Detached(_) => update_ref("HEAD", new, expected_old, committer, message),
+    }
+}
+
+/// Writes a loose ref under a lock and records the change in its reflog, and in the one of HEAD if it is on this branch.
+/// The current value must be the expected one, None if the ref does not exist yet
+pub(crate) fn update_ref(ref_name: &str, new: &str, expected_old: Option<&str>, committer: &Signature, message: &str) -> anyhow::Result<()> {
+    let path = format!("{GIT_PATH}/{ref_name}");
+    let mut lock = LockFile::acquire(&path).context(format!("cannot lock ref '{ref_name}'"))?;
+    let current = match ref_name {
+        "HEAD" => read_head_commit()?,
+        _ => read_ref(ref_name)?,
+    };
+    if current.as_deref() != expected_old {
+        match (current, expected_old) {
+            (Some(current), Some(expected)) => bail!("cannot lock ref '{ref_name}': is at {current} but expected {expected}"),
+            (Some(_), None) => bail!("cannot lock ref '{ref_name}': reference already exists"),
+            (None, _) => bail!("cannot lock ref '{ref_name}': unable to resolve reference '{ref_name}'"),
+        }
+    }
+    lock.write_all(format!("{new}\n").as_bytes())?;
+    // like git, the reflogs are written while the ref is locked, so a failure to log leaves the ref as it was
+    append_reflog(ref_name, expected_old, new, committer, message)?;
+    // HEAD has its own log of where it pointed to
+    if ref_name != "HEAD" && read_head()? == Head::Branch(ref_name.to_string()) {
+        append_reflog("HEAD", expected_old, new, committer, message)?;
+    }
+    lock.commit()
+}
+
+/// Points HEAD to a branch or detaches it at a commit, the reflog of HEAD records the move between the commits
+pub(crate) fn write_head(target: &Head, old: Option<&str>, new: &str, committer: &Signature, message: &str) -> anyhow::Result<()> {
+    let mut lock = LockFile::acquire(HEAD_PATH).context("cannot lock ref 'HEAD'")?;
+    let contents = match target {
+        Head::Branch(ref_name) => format!("{SYMREF_PREFIX}{ref_name}\n"),
+        Head::Detached(hash) => format!("{hash}\n"),
+    };
+    lock.write_all(contents.as_bytes())?;
+    append_reflog("HEAD", old, new, committer, message)?;
+    lock.commit()
+}
+
+/// Points a symbolic ref like refs/remotes/origin/HEAD to another ref, the reflog records the commit it resolves to
+pub(crate) fn write_symref(ref_name: &str, target: &str, hash: &str, committer: &Signature, message: &str) -> anyhow::Result<()> {
+    let path = format!("{GIT_PATH}/{ref_name}");
+    let mut lock = LockFile::acquire(&path).context(format!("cannot lock ref '{ref_name}'"))?;
+    lock.write_all(format!("{SYMREF_PREFIX}{target}\n").as_bytes())?;
+    append_reflog(ref_name, None, hash, committer, message)?;
+    lock.commit()
+}
+
+/// Remembers where HEAD was before a command that moves it further than a commit, like reset
+pub(crate) fn write_orig_head(hash: &str) -> anyhow::Result<()> {
+    let mut lock = LockFile::acquire(ORIG_HEAD_PATH).context("cannot lock ref 'ORIG_HEAD'")?;
+    lock.write_all(format!("{hash}\n").as_bytes())?;
+    lock.commit()
+}
+
+/// The branch or commit that was checked out before the current one, from the reflog of HEAD
+pub(crate) fn previous_checkout() -> anyhow::Result<Option<String>> {
+    let path = format!("{LOGS_PATH}/HEAD");
+    let Ok(log) = fs::read_to_string(&path) else {
+        return Ok(None);
+    };
+    let previous = log.lines()
+        .rev()
+        .filter_map(|x| x.split_once('\t'))
+        .filter_map(|(_, message)| message.strip_prefix("checkout: moving from "))
+        .find_map(|x| x.split_once(" to "))
+        .map(|(from, _)| from.to_string());
+    Ok(previous)
+}
+
+/// The commits a ref pointed to according to its reflog, oldest first, without deletions
+pub(crate) fn reflog_commits(ref_name: &str) -> anyhow::Result<Vec<String>> {
+    let path = format!("{LOGS_PATH}/{ref_name}");
+    let Ok(log) = fs::read_to_string(&path) else {
+        return Ok(vec![]);
+    };
+    let commits = log.lines()
+        .filter_map(|x| x.split(' ').nth(1))
+        .filter(|x| is_full_hash(x) && *x != NULL_HASH)
+        .map(|x| x.to_string())
+        .collect();
+    Ok(commits)
+}
+
+/// One line of a reflog
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ReflogEntry {
+    pub old: String,
+    pub new: String,
+    /// the ident and the time of the change, as written in the log
+    pub committer: String,
+    pub message: String,
+}
+
+/// The entries of the reflog of a ref, oldest first
+pub(crate) fn read_reflog(ref_name: &str) -> anyhow::Result<Vec<ReflogEntry>> {
+    let path = format!("{LOGS_PATH}/{ref_name}");
+    let log = match fs::read_to_string(&path) {
+        Ok(log) => log,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => return Err(error).context(format!("Failed to read {path}")),
+    };
+    log.lines()
+        .map(|line| {
+            let (header, message) = line.split_once('\t').unwrap_or((line, ""));
+            let mut fields = header.splitn(3, ' ');
+            let (Some(old), Some(new), Some(committer)) = (fields.next(), fields.next(), fields.next()) else {
+                bail!("Invalid line in {path}: {line}");
+            };
+            Ok(ReflogEntry { old: old.to_string(), new: new.to_string(), committer: committer.to_string(), message: message.to_string() })
+        })
+        .collect()
+}
+
+/// Removes the entry of the reflog at the position counted from the newest one, like git reflog delete --rewrite --updateref.
+/// The entry after it then starts where the one before it ended and the ref points to the newest entry left,
+/// the ref is deleted together with its log when no entry is left
+pub(crate) fn delete_reflog_entry(ref_name: &str, position: usize) -> anyhow::Result<()> {
+    let mut entries = read_reflog(ref_name)?;
+    let Some(index) = entries.len().checked_sub(position + 1) else {
+        bail!("log for '{ref_name}' only has {} entries", entries.len());
+    };
+    entries.remove(index);
+    let previous = match index {
+        0 => NULL_HASH.to_string(),
+        _ => entries[index - 1].new.clone(),
+    };
+    if let Some(next) = entries.get_mut(index) {
+        next.old = previous;
+    }
+    let Some(newest) = entries.last() else {
+        return delete_ref(ref_name);
+    };
+    let path = format!("{GIT_PATH}/{ref_name}");
+    let mut ref_lock = LockFile::acquire(&path).context(format!("cannot lock ref '{ref_name}'"))?;
+    ref_lock.write_all(format!("{}\n", newest.new).as_bytes())?;
+    let log_path = format!("{LOGS_PATH}/{ref_name}");
+    let mut log_lock = LockFile::acquire(&log_path).context(format!("cannot lock the log of '{ref_name}'"))?;
+    for entry in &entries {
+        log_lock.write_all(format!("{} {} {}\t{}\n", entry.old, entry.new, entry.committer, entry.message).as_bytes())?;
+    }
+    log_lock.commit()?;
+    ref_lock.commit()
+}
+
+/// Removes a ref, loose or packed, together with its reflog
+pub(crate) fn delete_ref(ref_name: &str) -> anyhow::Result<()> {
+    for path in [format!("{GIT_PATH}/{ref_name}"), format!("{LOGS_PATH}/{ref_name}")] {
+        match fs::remove_file(&path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error).context(format!("Failed to remove {path}")),
+            _ => {},
+        }
+    }
+    remove_packed_ref(ref_name)
+}
+
+/// Whether the ref is a loose ref that points to another ref, like refs/remotes/origin/HEAD
+pub(crate) fn is_symref(ref_name: &str) -> bool {
+    fs::read_to_string(format!("{GIT_PATH}/{ref_name}")).is_ok_and(|x| x.starts_with(SYMREF_PREFIX))
+}
+
+/// Checks the rules of git check-ref-format for a name below refs/
+pub(crate) fn is_valid_ref_name(name: &str) -> bool {
+    let forbidden = |x: char| x.is_ascii_control() || " ~^:?*[\\".contains(x);
+    !name.is_empty()
+        && name != "@"
+        && !name.starts_with('-')
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name.contains("//")
+        && !name.chars().any(forbidden)
+        && name.split('/').all(|x| !x.starts_with('.') && !x.ends_with(".lock"))
+}
+
+/// Adds a line to the log of the ref, a missing old value is written as the null hash.
+/// Like git, only HEAD, branches, remote-tracking branches, notes and the stash get a new log
+fn append_reflog(ref_name: &str, old: Option<&str>, new: &str, committer: &Signature, message: &str) -> anyhow::Result<()> {
+    let path = format!("{LOGS_PATH}/{ref_name}");
+    let path = Path::new(&path);
+    if !path.is_file() && !creates_reflog(ref_name)? {
+        return Ok(());
+    }
+    fs::create_dir_all(path.parent().unwrap()).context(format!("Failed to create the log dir for {ref_name}"))?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)
+        .context(format!("Failed to open {}", path.display()))?;
+    let old = old.unwrap_or(NULL_HASH);
+    let message = message.replace('\n', " ");
+    file.write_all(format!("{old} {new} {committer}\t{message}\n").as_bytes())
+        .context(format!("Failed to write {}", path.display()))
+}
+
+/// Whether a ref without a reflog gets one, which core.logAllRefUpdates decides. By default only the refs of
+/// a repository with a working tree that git keeps logs for have them, the stash always has one
+fn creates_reflog(ref_name: &str) -> anyhow::Result<bool> {
+    if ref_name == "refs/stash" {
+        return Ok(true);
+    }
+    let config = Config::read()?;
+    let default_logged = ref_name == "HEAD" || [HEADS_PREFIX, REMOTES_PREFIX, "refs/notes/"].iter().any(|x| ref_name.starts_with(x));
+    match config.get("core.logAllRefUpdates") {
+        Some("always") => Ok(true),
+        Some("false" | "no" | "off" | "0") => Ok(false),
+        Some(_) => Ok(default_logged),
+        None => Ok(default_logged && !matches!(config.get("core.bare"), Some("true" | "yes" | "on" | "1"))),
+    }
+}
+
+fn read_packed_ref(ref_name: &str) -> anyhow::Result<Option<String>> {
+    let found = read_packed_refs()?
+        .into_iter()
+        .find(|(name, _)| name == ref_name)
+        .map(|(_, hash)| hash);
+    Ok(found)
+}
+
+fn read_packed_refs() -> anyhow::Result<Vec<(String, String)>> {
+    let path = Path::new(PACKED_REFS_PATH);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let contents = fs::read_to_string(path).context(format!("Failed to read {PACKED_REFS_PATH}"))?;
+    let mut refs = vec![];
+    for line in contents.lines() {
+        if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+            continue;
+        }
+        let Some((hash, name)) = line.split_once(' ') else {
+            bail!("Invalid line in {PACKED_REFS_PATH}: {line}");
+        };
+        refs.push((name.to_string(), hash.to_string()));
+    }
+    Ok(refs)
+}
+
+/// Drops a ref from the packed refs, with the line of the object it peels to
+fn remove_packed_ref(ref_name: &str) -> anyhow::Result<()> {
+    let path = Path::new(PACKED_REFS_PATH);
+    if !path.exists() {
+        return Ok(());
+    }
+    let contents = fs::read_to_string(path).context(format!("Failed to read {PACKED_REFS_PATH}"))?;
+    let mut kept = String::new();
+    let mut removing = false;
+    for line in contents.lines() {
+        if line.starts_with('^') && removing {
+            continue;
+        }
+        removing = line.split_once(' ').is_some_and(|(_, name)| name == ref_name);
+        if !removing {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    if kept.len() == contents.len() {
+        return Ok(());
+    }
+    let mut lock = LockFile::acquire(PACKED_REFS_PATH)?;
+    lock.write_all(kept.as_bytes())?;
+    lock.commit()
+}
+
+/// Replaces the packed refs, each with the object an annotated tag points to if it is one
+pub(crate) fn write_packed_refs(refs: &[(String, String, Option<String>)]) -> anyhow::Result<()> {
+    let mut refs = refs.to_vec();
+    refs.sort_unstable();
+    let mut contents = "# pack-refs with: peeled fully-peeled sorted \n".to_string();
+    for (name, hash, peeled) in refs {
+        contents.push_str(&format!("{hash} {name}\n"));
+        if let Some(peeled) = peeled {
+            contents.push_str(&format!("^{peeled}\n"));
+        }
+    }
+    let mut lock = LockFile::acquire(PACKED_REFS_PATH)?;
+    lock.write_all(contents.as_bytes())?;
+    lock.commit()
+}
+
+/// Resolves a short ref name the same way git does, returns the full ref name and its hash
+pub(crate) fn dwim_ref(name: &str) -> anyhow::Result<Option<(String, String)>> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{name}"),
+        format!("{TAGS_PREFIX}{name}"),
+        format!("{HEADS_PREFIX}{name}"),
+        format!("{REMOTES_PREFIX}{name}"),
+        format!("{REMOTES_PREFIX}{name}/HEAD"),
+    ];
+    for candidate in candidates {
+        if !candidate.starts_with("refs/") && !is_pseudo_ref(&candidate) {
+            continue;
+        }
+        if let Some(hash) = read_ref(&candidate)? {
+            return Ok(Some((candidate, hash)));
+        }
+    }
+    Ok(None)
+}
+
+/// Lists all refs with full names starting with the prefix, sorted by name
+pub(crate) fn list_refs(prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut refs = read_packed_refs()?;
+    refs.retain(|(name, _)| name.starts_with(prefix));
+    let mut loose = vec![];
+    collect_loose_refs(Path::new(REFS_PATH), "refs", &mut loose)?;
+    for name in loose {
+        if !name.starts_with(prefix) {
+            continue;
+        }
+        let Some(hash) = read_ref(&name)? else {
+            continue;
+        };
+        match refs.iter_mut().find(|(packed, _)| *packed == name) {
+            Some(existing) => existing.1 = hash,
+            None => refs.push((name, hash)),
+        }
+    }
+    refs.sort_unstable();
+    Ok(refs)
+}
+
+fn collect_loose_refs(dir_path: &Path, name_prefix: &str, result: &mut Vec<String>) -> anyhow::Result<()> {
+    if !dir_path.is_dir() {
+        return Ok(());
+    }
+    let dir_iterator = fs::read_dir(dir_path).context(format!("Failed to read dir {}", dir_path.display()))?;
+    for dir_entry in dir_iterator {
+        let dir_entry = dir_entry.context(format!("Some weird error while reading dir entry name in {}", dir_path.display()))?;
+        let path = dir_entry.path();
+        let file_name = dir_entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        let name = format!("{name_prefix}/{file_name}");
+        if path.is_dir() {
+            collect_loose_refs(&path, &name, result)?;
+        } else if !file_name.ends_with(".lock") {
+            result.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// refs at the top level of the git dir, like HEAD or ORIG_HEAD
+fn is_pseudo_ref(name: &str) -> bool {
+    name.ends_with("HEAD") && name.bytes().all(|x| x.is_ascii_uppercase() || x == b'_')
+}
+
+pub(crate) fn is_full_hash(value: &str) -> bool {
+    value.len() == HASH_ENCODED_LEN && value.bytes().all(|x| x.is_ascii_hexdigit())
+}
@@ -0,0 +1,136 @@
+use anyhow::bail;
+use crate::refs::{HEADS_PREFIX, REMOTES_PREFIX, TAGS_PREFIX};
+
+const FORCE_PREFIX: char = '+';
+const NEGATIVE_PREFIX: char = '^';
+const WILDCARD: char = '*';
+
+/// Which refs of a remote a fetch or push transfers and where they are stored, like
+/// `+refs/heads/*:refs/remotes/origin/*`. A negative refspec `^<src>` leaves out the refs its source matches
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Refspec {
+    /// update the destination even if it is not a fast-forward
+    pub force: bool,
+    pub negative: bool,
+    pub src: String,
+    pub dst: Option<String>,
+}
+impl Refspec {
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let (force, rest) = match spec.strip_prefix(FORCE_PREFIX) {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let (negative, rest) = match rest.strip_prefix(NEGATIVE_PREFIX) {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (src, dst) = match rest.split_once(':') {
+            Some((src, dst)) => (src, Some(dst).filter(|x| !x.is_empty())),
+            None => (rest, None),
+        };
+        let wildcards = |x: &str| x.matches(WILDCARD).count();
+        let invalid = (negative && (force || dst.is_some() || src.is_empty()))
+            || wildcards(src) > 1
+            || dst.is_some_and(|dst| wildcards(dst) != wildcards(src))
+            || rest.matches(':').count() > 1;
+        if invalid {
+            bail!("invalid refspec '{spec}'");
+        }
+        Ok(Self { force, negative, src: src.to_string(), dst: dst.map(str::to_string) })
+    }
+
+    pub fn is_pattern(&self) -> bool {
+        self.src.contains(WILDCARD)
+    }
+
+    /// The full ref names the source can stand for, in the order git tries them: a short name like `main`
+    /// can be a tag, a branch or a remote-tracking branch
+    pub fn source_candidates(&self) -> Vec<String> {
+        if self.is_pattern() || self.src.starts_with("refs/") || self.src == "HEAD" {
+            return vec![self.src.clone()];
+        }
+        let name = &self.src;
+        vec![
+            name.to_string(),
+            format!("refs/{name}"),
+            format!("{TAGS_PREFIX}{name}"),
+            format!("{HEADS_PREFIX}{name}"),
+            format!("{REMOTES_PREFIX}{name}"),
+            format!("{REMOTES_PREFIX}{name}/HEAD"),
+        ]
+    }
+
+    /// The prefix the refs matching the source start with, to only ask the remote for those
+    pub fn source_prefix(&self) -> &str {
+        self.src.split(WILDCARD).next().unwrap_or_default()
+    }
+
+    /// Whether a full ref name of the remote matches the source
+    pub fn matches_source(&self, name: &str) -> bool {
+        match self.is_pattern() {
+            true => match_pattern(&self.src, name).is_some(),
+            false => self.source_candidates().iter().any(|x| x == name),
+        }
+    }
+
+    /// Where a matching ref of the remote is stored locally, None for a refspec without a destination
+    pub fn destination(&self, name: &str) -> Option<String> {
+        let dst = self.dst.as_deref()?;
+        match self.is_pattern() {
+            true => Some(dst.replacen(WILDCARD, match_pattern(&self.src, name)?, 1)),
+            false => Some(expand_local_ref(dst)),
+        }
+    }
+
+    /// The ref of the remote that is stored in a local ref, the reverse of `destination`
+    pub fn source_of(&self, local: &str) -> Option<String> {
+        let dst = self.dst.as_deref()?;
+        match self.is_pattern() {
+            true => Some(self.src.replacen(WILDCARD, match_pattern(dst, local)?, 1)),
+            false => (expand_local_ref(dst) == local).then(|| self.src.clone()),
+        }
+    }
+}
+
+/// What the wildcard of the pattern stands for in the name, None if the name does not match the pattern
+fn match_pattern<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    let (prefix, suffix) = pattern.split_once(WILDCARD)?;
+    let rest = name.strip_prefix(prefix)?;
+    rest.strip_suffix(suffix)
+}
+
+/// The full name of a local ref of a refspec, a name outside of refs/ is a branch
+fn expand_local_ref(name: &str) -> String {
+    match name.starts_with("refs/") || name == "HEAD" {
+        true => name.to_string(),
+        false if ["heads/", "tags/", "remotes/"].iter().any(|x| name.starts_with(x)) => format!("refs/{name}"),
+        false => format!("{HEADS_PREFIX}{name}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_refspec() -> anyhow::Result<()> {
+        let refspec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*")?;
+        assert!(refspec.force && refspec.is_pattern());
+        assert_eq!(Some("refs/remotes/origin/feat/x".to_string()), refspec.destination("refs/heads/feat/x"));
+        assert_eq!(None, refspec.destination("refs/tags/v1"));
+        assert_eq!(Some("refs/heads/main".to_string()), refspec.source_of("refs/remotes/origin/main"));
+        assert_eq!("refs/heads/", refspec.source_prefix());
+
+        let refspec = Refspec::parse("main:tmp")?;
+        assert!(refspec.matches_source("refs/heads/main") && !refspec.matches_source("refs/heads/other"));
+        assert_eq!(Some("refs/heads/tmp".to_string()), refspec.destination("refs/heads/main"));
+
+        let refspec = Refspec::parse("^refs/heads/feat*")?;
+        assert!(refspec.negative && refspec.matches_source("refs/heads/feature"));
+        for invalid in ["^a:b", "+^a", "refs/*/*:x/*", "refs/heads/*:refs/x", "a:b:c"] {
+            assert!(Refspec::parse(invalid).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::{bail, Context};
+use crate::config::Config;
+use crate::refs::{Head, HEADS_PREFIX, read_head};
+use crate::refspec::Refspec;
+
+/// Which tags a fetch stores besides the refs of its refspecs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TagMode {
+    /// the tags that point to fetched commits
+    Follow,
+    /// all tags of the remote
+    All,
+    None,
+}
+
+/// A repository to fetch from or push to, configured as `remote.<name>.*` or given by its url
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Remote {
+    /// None for a url that is not the url of a configured remote
+    pub name: Option<String>,
+    pub url: String,
+    pub fetch: Vec<Refspec>,
+    /// what a push without refspecs pushes, from remote.<name>.push
+    pub push: Vec<Refspec>,
+    pub tags: TagMode,
+    pub prune: bool,
+}
+impl Remote {
+    /// The remote with the name, or one for the url or path. Without one, the remote of the current branch or origin
+    pub fn resolve(name_or_url: Option<&str>, config: &Config) -> anyhow::Result<Self> {
+        let name = match name_or_url {
+            Some(name) => name.to_string(),
+            None => branch_setting("remote", config)?.unwrap_or_else(|| "origin".to_string()),
+        };
+        match Self::configured(&name, config)? {
+            Some(remote) => Ok(remote),
+            None if name_or_url.is_none() => {
+                bail!("No remote repository specified.  Please, specify either a URL or a\nremote name from which new revisions should be fetched.");
+            },
+            None => Ok(Self::from_url(name, config)),
+        }
+    }
+
+    /// The remote to push to, with its push url. Without a name, branch.<name>.pushRemote, remote.pushDefault,
+    /// the remote of the current branch or origin
+    pub fn resolve_push(name_or_url: Option<&str>, config: &Config) -> anyhow::Result<Self> {
+        let name = match name_or_url {
+            Some(name) => name.to_string(),
+            None => branch_setting("pushRemote", config)?
+                .or_else(|| config.get("remote.pushDefault").map(str::to_string))
+                .or(branch_setting("remote", config)?)
+                .unwrap_or_else(|| "origin".to_string()),
+        };
+        match Self::configured(&name, config)? {
+            Some(mut remote) => {
+                if let Some(push_url) = config.get(&format!("remote.{name}.pushurl")) {
+                    remote.url = push_url.to_string();
+                }
+                Ok(remote)
+            },
+            None if name_or_url.is_none() => bail!("No configured push destination.\n\
+                Either specify the URL from the command-line or configure a remote repository using\n\n    \
+                git remote add <name> <url>\n\nand then push using the remote name\n\n    git push <name>\n"),
+            None => Ok(Self::from_url(name, config)),
+        }
+    }
+
+    /// The remote configured with the name, None if it has no url
+    fn configured(name: &str, config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(url) = config.get(&format!("remote.{name}.url")) else {
+            return Ok(None);
+        };
+        let refspecs = |key: &str| {
+            config.get_all(&format!("remote.{name}.{key}"))
+                .into_iter()
+                .map(|x| Refspec::parse(x).context(format!("bad config variable 'remote.{name}.{key}'")))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let tags = match config.get(&format!("remote.{name}.tagOpt")) {
+            Some("--tags") => TagMode::All,
+            Some("--no-tags") => TagMode::None,
+            _ => TagMode::Follow,
+        };
+        // the setting of the remote overrides fetch.prune
+        let prune = match config.get(&format!("remote.{name}.prune")) {
+            Some(value) => matches!(value, "true" | "yes" | "on" | "1"),
+            None => fetch_prune(config),
+        };
+        let (fetch, push) = (refspecs("fetch")?, refspecs("push")?);
+        Ok(Some(Self { url: url.to_string(), name: Some(name.to_string()), fetch, push, tags, prune }))
+    }
+
+    /// A remote that is only known by its url or path
+    fn from_url(url: String, config: &Config) -> Self {
+        Self { name: None, url, fetch: vec![], push: vec![], tags: TagMode::Follow, prune: fetch_prune(config) }
+    }
+
+    /// The refs of the remote the current branch merges, from branch.<name>.merge if the branch is set up to merge
+    /// from this remote
+    pub fn merge_refs<'a>(&self, config: &'a Config) -> anyhow::Result<Vec<&'a str>> {
+        let Head::Branch(ref_name) = read_head()? else {
+            return Ok(vec![]);
+        };
+        let branch = ref_name.strip_prefix(HEADS_PREFIX).unwrap_or(&ref_name);
+        match self.name.is_some() && config.get(&format!("branch.{branch}.remote")) == self.name.as_deref() {
+            true => Ok(config.get_all(&format!("branch.{branch}.merge"))),
+            false => Ok(vec![]),
+        }
+    }
+}
+
+fn fetch_prune(config: &Config) -> bool {
+    matches!(config.get("fetch.prune"), Some("true" | "yes" | "on" | "1"))
+}
+
+/// The value of branch.<name>.<key> for the current branch, None on a detached HEAD
+fn branch_setting(key: &str, config: &Config) -> anyhow::Result<Option<String>> {
+    let Head::Branch(ref_name) = read_head()? else {
+        return Ok(None);
+    };
+    let branch = ref_name.strip_prefix(HEADS_PREFIX).unwrap_or(&ref_name);
+    Ok(config.get(&format!("branch.{branch}.{key}")).map(str::to_string))
+}
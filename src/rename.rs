@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use anyhow::Context;
+use crate::common::ObjectMode;
+use crate::config::Config;
+use crate::diff::is_binary;
+use crate::diff_output::load_side;
+use crate::tree_diff::{ChangeStatus, DiffSide, FileChange, hash_worktree_file};
+
+/// The similarity of identical files, scores are fractions of it
+pub(crate) const MAX_SCORE: usize = 60000;
+const DEFAULT_MIN_SCORE: usize = MAX_SCORE / 2;
+const DEFAULT_RENAME_LIMIT: usize = 1000;
+/// a limit of zero means no limit, git caps it at this value
+const UNLIMITED_RENAME_LIMIT: usize = 32767;
+/// how many of the most similar sources are remembered for each destination
+const CANDIDATES_PER_DESTINATION: usize = 4;
+/// contents are split into spans of up to this many bytes, a newline always ends a span
+const MAX_SPAN_LEN: usize = 64;
+const SPAN_HASH_BASE: u32 = 107927;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub(crate) enum RenameDetection {
+    #[default]
+    Off,
+    Renames,
+    /// deleted and modified files are the sources of copies
+    Copies,
+    /// unmodified files are the sources of copies too
+    CopiesHarder,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RenameOptions {
+    pub detection: RenameDetection,
+    /// the minimum similarity of an inexact rename, out of MAX_SCORE
+    pub min_score: usize,
+    /// the maximum number of sources and destinations compared by their contents
+    pub limit: usize,
+}
+impl Default for RenameOptions {
+    fn default() -> Self {
+        Self { detection: RenameDetection::Off, min_score: DEFAULT_MIN_SCORE, limit: DEFAULT_RENAME_LIMIT }
+    }
+}
+impl RenameOptions {
+    /// The limit from diff.renameLimit, porcelain commands also detect renames by default unless diff.renames says otherwise
+    pub fn from_config(config: &Config, porcelain: bool) -> anyhow::Result<Self> {
+        let mut options = Self::default();
+        if porcelain {
+            options.detection = match config.get("diff.renames").map(|x| x.to_ascii_lowercase()).as_deref() {
+                None | Some("true" | "yes" | "on" | "1") => RenameDetection::Renames,
+                Some("false" | "no" | "off" | "0") => RenameDetection::Off,
+                Some("copies" | "copy") => RenameDetection::Copies,
+                Some(value) => anyhow::bail!("Invalid value '{value}' for diff.renames"),
+            };
+        }
+        if let Some(limit) = config.get("diff.renamelimit") {
+            options.limit = limit.parse().context(format!("Invalid value '{limit}' for diff.renameLimit"))?;
+        }
+        Ok(options)
+    }
+}
+
+/// Parses a similarity like `50%`, or `5` and `.5` which both mean 50%, into a score out of MAX_SCORE
+pub(crate) fn parse_score(value: &str) -> Result<usize, String> {
+    let (mut number, mut scale) = (0, 1);
+    let mut dot = false;
+    let mut chars = value.chars().peekable();
+    while let Some(char) = chars.next() {
+        match char {
+            '.' if !dot => {
+                scale = 1;
+                dot = true;
+            },
+            '%' if chars.peek().is_none() => {
+                scale = if dot { scale * 100 } else { 100 };
+            },
+            '0'..='9' => {
+                if scale < 100000 {
+                    scale *= 10;
+                    number = number * 10 + char.to_digit(10).unwrap() as usize;
+                }
+            },
+            _ => return Err(format!("invalid similarity '{value}'")),
+        }
+    }
+    Ok(if number >= scale { MAX_SCORE } else { MAX_SCORE * number / scale })
+}
+
+/// A file that an added file could have been renamed or copied from
+struct Source<'a> {
+    path: &'a str,
+    side: &'a DiffSide,
+    /// unmodified files are only used by the exhaustive copy detection
+    unmodified: bool,
+    /// how many destinations use the file, files that are kept count as a use too
+    used: usize,
+}
+
+/// An added file with the source it was found to come from
+struct Destination<'a> {
+    change: usize,
+    path: &'a str,
+    side: &'a DiffSide,
+    pair: Option<(usize, usize)>,
+}
+
+/// A possible pairing of a destination with a source
+#[derive(Copy, Clone)]
+struct Candidate {
+    score: usize,
+    /// sources with the same file name are preferred
+    same_name: bool,
+    destination: usize,
+    source: usize,
+}
+
+/// Contents of a file reduced to the counts of its hashed spans
+struct Spans {
+    size: usize,
+    counts: HashMap<u32, usize>,
+}
+
+/// Pairs added files with deleted files, and with modified files when detecting copies, which have the same or
+/// similar contents, the same way git does. The paired files replace the added ones, the deleted files are dropped.
+/// The old versions of unmodified files are only loaded for `CopiesHarder`, by calling `unmodified`.
+pub(crate) fn detect_renames(
+    changes: Vec<FileChange>,
+    options: &RenameOptions,
+    unmodified: impl FnOnce() -> anyhow::Result<Vec<(String, DiffSide)>>,
+) -> anyhow::Result<Vec<FileChange>> {
+    if options.detection == RenameDetection::Off {
+        return Ok(changes);
+    }
+    let copies = options.detection != RenameDetection::Renames;
+    let unmodified = match options.detection {
+        RenameDetection::CopiesHarder => unmodified()?,
+        _ => vec![],
+    };
+
+    let mut destinations = vec![];
+    let mut sources = vec![];
+    for (index, change) in changes.iter().enumerate() {
+        match (&change.old, &change.new) {
+            (None, Some(new)) if new.mode != ObjectMode::Tree => {
+                destinations.push(Destination { change: index, path: &change.path, side: new, pair: None });
+            },
+            (Some(old), None) if old.mode != ObjectMode::Tree => {
+                sources.push(Source { path: &change.path, side: old, unmodified: false, used: 0 });
+            },
+            (Some(old), Some(_)) if copies && old.mode != ObjectMode::Tree => {
+                sources.push(Source { path: &change.path, side: old, unmodified: false, used: 1 });
+            },
+            _ => {},
+        }
+    }
+    for (path, side) in &unmodified {
+        if !changes.iter().any(|x| x.old.is_some() && x.path == *path) {
+            sources.push(Source { path, side, unmodified: true, used: 1 });
+        }
+    }
+    if destinations.is_empty() || sources.is_empty() {
+        return Ok(changes);
+    }
+    sources.sort_by(|a, b| a.path.cmp(b.path));
+
+    find_exact_renames(&mut sources, &mut destinations, copies)?;
+    if options.min_score < MAX_SCORE {
+        find_inexact_renames(&mut sources, &mut destinations, copies, options)?;
+    }
+
+    let mut pairs = destinations.iter()
+        .filter_map(|x| x.pair.map(|pair| (x.change, pair)))
+        .collect::<HashMap<_, _>>();
+    let used_sources = sources.iter().filter(|x| x.used > 0).map(|x| x.path).collect::<Vec<_>>();
+    let mut result = vec![];
+    for (index, change) in changes.iter().enumerate() {
+        if change.new.is_none() && used_sources.contains(&change.path.as_str()) {
+            // the deleted file was renamed
+            continue;
+        }
+        let Some((source_index, score)) = pairs.remove(&index) else {
+            result.push(change.clone());
+            continue;
+        };
+        // the last destination of a deleted file is its rename, the other ones are copies
+        let source = &mut sources[source_index];
+        source.used -= 1;
+        let similarity = score * 100 / MAX_SCORE;
+        let status = if source.used > 0 { ChangeStatus::Copied(similarity) } else { ChangeStatus::Renamed(similarity) };
+        result.push(FileChange {
+            path: change.path.clone(),
+            source: Some(source.path.to_string()),
+            status,
+            old: Some(source.side.clone()),
+            new: change.new.clone(),
+        });
+    }
+    Ok(result)
+}
+
+/// Pairs destinations with sources that have the same contents, preferring unused sources with the same file name
+fn find_exact_renames(sources: &mut [Source], destinations: &mut [Destination], copies: bool) -> anyhow::Result<()> {
+    let mut source_hashes: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, source) in sources.iter().enumerate() {
+        source_hashes.entry(side_hash(source.path, source.side)?).or_default().push(index);
+    }
+    for destination in destinations.iter_mut() {
+        let hash = side_hash(destination.path, destination.side)?;
+        let mut best: Option<(usize, usize)> = None;
+        for &index in source_hashes.get(&hash).into_iter().flatten() {
+            let source = &sources[index];
+            // non-regular files must have the same mode
+            if (!is_regular(source.side.mode) || !is_regular(destination.side.mode)) && source.side.mode != destination.side.mode {
+                continue;
+            }
+            if source.used > 0 && !copies {
+                continue;
+            }
+            let score = (source.used == 0) as usize + same_file_name(source.path, destination.path) as usize;
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((index, score));
+                if score == 2 {
+                    break;
+                }
+            }
+        }
+        if let Some((index, _)) = best {
+            sources[index].used += 1;
+            destination.pair = Some((index, MAX_SCORE));
+        }
+    }
+    Ok(())
+}
+
+/// Pairs the remaining destinations with the most similar sources. Renames of files with the same unique file name
+/// are found first, then every source is compared with every destination unless there are too many of them.
+fn find_inexact_renames(sources: &mut [Source], destinations: &mut [Destination], copies: bool, options: &RenameOptions) -> anyhow::Result<()> {
+    let mut source_spans = sources.iter().map(|_| None).collect::<Vec<_>>();
+    let mut destination_spans = destinations.iter().map(|_| None).collect::<Vec<_>>();
+    if !copies {
+        // a source can only be renamed once
+        let min_name_score = options.min_score + (MAX_SCORE - options.min_score) / 2;
+        let available = sources.iter().enumerate().filter(|(_, x)| x.used == 0).map(|(index, _)| index).collect::<Vec<_>>();
+        let source_names = unique_file_names(available.iter().map(|x| sources[*x].path));
+        let destination_names = unique_file_names(destinations.iter().filter(|x| x.pair.is_none()).map(|x| x.path));
+        for &source_index in &available {
+            let name = file_name(sources[source_index].path);
+            let (Some(Some(_)), Some(Some(destination_path))) = (source_names.get(name), destination_names.get(name)) else {
+                continue;
+            };
+            let destination_index = destinations.iter().position(|x| x.path == *destination_path).unwrap();
+            if destinations[destination_index].pair.is_some() {
+                continue;
+            }
+            let score = estimate_similarity(
+                sources, source_index, &mut source_spans, destinations, destination_index, &mut destination_spans, options.min_score,
+            )?;
+            if score >= min_name_score {
+                sources[source_index].used += 1;
+                destinations[destination_index].pair = Some((source_index, score));
+            }
+        }
+    }
+
+    let remaining_sources = sources.iter().filter(|x| copies || x.used == 0).count();
+    let remaining_destinations = destinations.iter().filter(|x| x.pair.is_none()).count();
+    if remaining_sources == 0 || remaining_destinations == 0 {
+        return Ok(());
+    }
+    let limit = if options.limit == 0 { UNLIMITED_RENAME_LIMIT } else { options.limit };
+    let mut skip_unmodified = false;
+    if too_many_candidates(remaining_destinations, remaining_sources, limit) {
+        let modified_sources = sources.iter().filter(|x| !x.unmodified).count();
+        if options.detection == RenameDetection::CopiesHarder && !too_many_candidates(remaining_destinations, modified_sources, limit) {
+            eprintln!("warning: only found copies from modified paths due to too many files.");
+            skip_unmodified = true;
+        } else {
+            eprintln!("warning: exhaustive rename detection was skipped due to too many files.");
+        }
+        eprintln!(
+            "warning: you may want to set your diff.renameLimit variable to at least {} and retry the command.",
+            remaining_sources.max(remaining_destinations),
+        );
+        if !skip_unmodified {
+            return Ok(());
+        }
+    }
+
+    let mut candidates = vec![];
+    for destination_index in 0..destinations.len() {
+        if destinations[destination_index].pair.is_some() {
+            continue;
+        }
+        let mut best: Vec<Candidate> = Vec::with_capacity(CANDIDATES_PER_DESTINATION);
+        for source_index in 0..sources.len() {
+            let source = &sources[source_index];
+            if !copies && source.used > 0 || skip_unmodified && source.unmodified {
+                continue;
+            }
+            let score = estimate_similarity(
+                sources, source_index, &mut source_spans, destinations, destination_index, &mut destination_spans, options.min_score,
+            )?;
+            let same_name = same_file_name(sources[source_index].path, destinations[destination_index].path);
+            let candidate = Candidate { score, same_name, destination: destination_index, source: source_index };
+            // the worst candidate is replaced, the first one of equally bad ones
+            if best.len() < CANDIDATES_PER_DESTINATION {
+                best.push(candidate);
+            } else {
+                let worst = (1..best.len()).fold(0, |worst, x| if is_better(&best[worst], &best[x]) { x } else { worst });
+                if is_better(&candidate, &best[worst]) {
+                    best[worst] = candidate;
+                }
+            }
+        }
+        candidates.extend(best);
+    }
+    candidates.sort_by(|a, b| b.score.cmp(&a.score).then(b.same_name.cmp(&a.same_name)));
+
+    for reuse_sources in [false, true] {
+        if reuse_sources && !copies {
+            break;
+        }
+        for candidate in candidates.iter().take_while(|x| x.score >= options.min_score) {
+            if destinations[candidate.destination].pair.is_some() || !reuse_sources && sources[candidate.source].used > 0 {
+                continue;
+            }
+            sources[candidate.source].used += 1;
+            destinations[candidate.destination].pair = Some((candidate.source, candidate.score));
+        }
+    }
+    Ok(())
+}
+
+fn is_better(candidate: &Candidate, other: &Candidate) -> bool {
+    (candidate.score, candidate.same_name) > (other.score, other.same_name)
+}
+
+fn too_many_candidates(destinations: usize, sources: usize, limit: usize) -> bool {
+    !((destinations <= limit || sources <= limit) && destinations * sources <= limit * limit)
+}
+
+/// The file names that occur once, with the path that has it, names that occur several times map to None
+fn unique_file_names<'a>(paths: impl Iterator<Item = &'a str>) -> HashMap<&'a str, Option<&'a str>> {
+    let mut names = HashMap::new();
+    for path in paths {
+        names.entry(file_name(path)).and_modify(|x| *x = None).or_insert(Some(path));
+    }
+    names
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit_once('/').map_or(path, |(_, name)| name)
+}
+
+fn same_file_name(a: &str, b: &str) -> bool {
+    file_name(a) == file_name(b)
+}
+
+fn is_regular(mode: ObjectMode) -> bool {
+    mode == ObjectMode::Normal || mode == ObjectMode::Executable
+}
+
+/// The hash of a file version, files in the working tree are hashed
+fn side_hash(path: &str, side: &DiffSide) -> anyhow::Result<String> {
+    if !side.is_in_worktree() {
+        return Ok(side.hash.clone());
+    }
+    let path = Path::new(path);
+    let meta = fs::symlink_metadata(path).context(format!("Failed to read metadata for {}", path.display()))?;
+    hash_worktree_file(path, &meta)
+}
+
+/// How much of the destination is made of the contents of the source, out of MAX_SCORE.
+/// Only regular files are compared, files with too different sizes are not compared at all.
+#[allow(clippy::too_many_arguments)]
+fn estimate_similarity(
+    sources: &[Source],
+    source_index: usize,
+    source_spans: &mut [Option<Spans>],
+    destinations: &[Destination],
+    destination_index: usize,
+    destination_spans: &mut [Option<Spans>],
+    min_score: usize,
+) -> anyhow::Result<usize> {
+    let source = &sources[source_index];
+    let destination = &destinations[destination_index];
+    if !is_regular(source.side.mode) || !is_regular(destination.side.mode) {
+        return Ok(0);
+    }
+    if source_spans[source_index].is_none() {
+        source_spans[source_index] = Some(hash_spans(source.path, source.side)?);
+    }
+    if destination_spans[destination_index].is_none() {
+        destination_spans[destination_index] = Some(hash_spans(destination.path, destination.side)?);
+    }
+    let source_spans = source_spans[source_index].as_ref().unwrap();
+    let destination_spans = destination_spans[destination_index].as_ref().unwrap();
+
+    let max_size = source_spans.size.max(destination_spans.size);
+    let min_size = source_spans.size.min(destination_spans.size);
+    if max_size * (MAX_SCORE - min_score) < (max_size - min_size) * MAX_SCORE || destination_spans.size == 0 {
+        return Ok(0);
+    }
+    let copied = source_spans.counts.iter()
+        .map(|(hash, count)| destination_spans.counts.get(hash).map_or(0, |x| *x.min(count)))
+        .sum::<usize>();
+    Ok(copied * MAX_SCORE / max_size)
+}
+
+fn hash_spans(path: &str, side: &DiffSide) -> anyhow::Result<Spans> {
+    let data = load_side(path, side)?.data;
+    Ok(Spans { size: data.len(), counts: count_spans(&data) })
+}
+
+/// Splits the data into lines, long lines into spans of MAX_SPAN_LEN bytes, and counts the bytes of the spans
+/// by their hashes. The carriage returns of CRLF line endings are ignored in text files.
+fn count_spans(data: &[u8]) -> HashMap<u32, usize> {
+    let text = !is_binary(data);
+    let mut counts = HashMap::new();
+    let (mut accumulator1, mut accumulator2) = (0u32, 0u32);
+    let mut len = 0;
+    for (index, byte) in data.iter().enumerate() {
+        if text && *byte == b'\r' && data.get(index + 1) == Some(&b'\n') {
+            continue;
+        }
+        let old_accumulator1 = accumulator1;
+        accumulator1 = (accumulator1 << 7) ^ (accumulator2 >> 25);
+        accumulator2 = (accumulator2 << 7) ^ (old_accumulator1 >> 25);
+        accumulator1 = accumulator1.wrapping_add(*byte as u32);
+        len += 1;
+        if len < MAX_SPAN_LEN && *byte != b'\n' {
+            continue;
+        }
+        *counts.entry(span_hash(accumulator1, accumulator2)).or_default() += len;
+        (accumulator1, accumulator2, len) = (0, 0, 0);
+    }
+    if len > 0 {
+        *counts.entry(span_hash(accumulator1, accumulator2)).or_default() += len;
+    }
+    counts
+}
+
+fn span_hash(accumulator1: u32, accumulator2: u32) -> u32 {
+    accumulator1.wrapping_add(accumulator2.wrapping_mul(0x61)) % SPAN_HASH_BASE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_score() {
+        assert_eq!(Ok(MAX_SCORE / 2), parse_score("50%"));
+        assert_eq!(Ok(MAX_SCORE / 2), parse_score("5"));
+        assert_eq!(Ok(MAX_SCORE / 2), parse_score(".5"));
+        assert_eq!(Ok(MAX_SCORE * 9 / 10), parse_score("90"));
+        assert_eq!(Ok(MAX_SCORE / 20), parse_score("05"));
+        assert_eq!(Ok(MAX_SCORE), parse_score("100%"));
+        assert!(parse_score("5x").is_err());
+    }
+
+    #[test]
+    fn test_detect_exact_renames() -> anyhow::Result<()> {
+        let side = |hash: &str| Some(DiffSide::new(ObjectMode::Normal, &hash.repeat(40)));
+        let changes = vec![
+            FileChange::new("a/file".to_string(), side("1"), None),
+            FileChange::new("b/copy".to_string(), None, side("1")),
+            FileChange::new("b/file".to_string(), None, side("1")),
+            FileChange::new("c".to_string(), side("2"), side("3")),
+            FileChange::new("d".to_string(), None, side("2")),
+            FileChange::new("e".to_string(), None, side("4")),
+        ];
+        let statuses = |detection| -> anyhow::Result<Vec<_>> {
+            let options = RenameOptions { detection, min_score: MAX_SCORE, ..Default::default() };
+            let changes = detect_renames(changes.clone(), &options, || Ok(vec![("f".to_string(), side("4").unwrap())]))?;
+            Ok(changes.into_iter().map(|x| (x.source, x.path, x.status)).collect())
+        };
+        // without copies the first destination takes the source
+        let expected = vec![
+            (Some("a/file".to_string()), "b/copy".to_string(), ChangeStatus::Renamed(100)),
+            (None, "b/file".to_string(), ChangeStatus::Added),
+            (None, "c".to_string(), ChangeStatus::Modified),
+            (None, "d".to_string(), ChangeStatus::Added),
+            (None, "e".to_string(), ChangeStatus::Added),
+        ];
+        assert_eq!(expected, statuses(RenameDetection::Renames)?);
+        let expected = vec![
+            (Some("a/file".to_string()), "b/copy".to_string(), ChangeStatus::Copied(100)),
+            (Some("a/file".to_string()), "b/file".to_string(), ChangeStatus::Renamed(100)),
+            (None, "c".to_string(), ChangeStatus::Modified),
+            (Some("c".to_string()), "d".to_string(), ChangeStatus::Copied(100)),
+            (None, "e".to_string(), ChangeStatus::Added),
+        ];
+        assert_eq!(expected, statuses(RenameDetection::Copies)?);
+        assert_eq!(Some("f".to_string()), statuses(RenameDetection::CopiesHarder)?.pop().unwrap().0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_spans() {
+        let counts = count_spans(b"line\nline\r\nlonger line\n");
+        assert_eq!(2, counts.len());
+        assert_eq!(10, counts.values().copied().min().unwrap());
+        assert_eq!(12, counts.values().copied().max().unwrap());
+        let long_line = count_spans(&[b'x'; 100]);
+        assert_eq!(100, long_line.values().sum::<usize>());
+        assert_eq!(2, long_line.len());
+    }
+}
@@ -0,0 +1,57 @@
+use std::env;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process;
+use anyhow::Context;
+use crate::common::GIT_PATH;
+
+/// The git dir of the repository at the path: `<path>/.git` for a repository with a working tree,
+/// the path itself for a bare repository or when the path is already a git dir
+pub(crate) fn find_git_dir(path: &Path) -> Option<PathBuf> {
+    let nested = path.join(GIT_PATH);
+    if is_git_dir(&nested) {
+        return Some(nested);
+    }
+    is_git_dir(path).then(|| path.to_path_buf())
+}
+
+fn is_git_dir(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir()
+}
+
+/// The repository of a command that works on another repository than the one it was started in, like a service or
+/// a clone. All paths of a repository are relative to the current dir, so entering it changes the current dir
+/// for the rest of the process. A bare repository is entered through a temporary dir that links to it,
+/// which is removed when this is dropped
+pub(crate) struct EnteredRepository {
+    /// the dir that holds a `.git` link to a bare repository
+    shim_dir: Option<PathBuf>,
+}
+impl EnteredRepository {
+    /// Changes to the working tree of the git dir, for a bare repository to a temporary dir that links to it,
+    /// so that the paths below `.git` lead to it as well. Commands call this once, before they touch the repository
+    pub fn enter(git_dir: &Path) -> anyhow::Result<Self> {
+        let git_dir = env::current_dir().context("failed to get current dir")?.join(git_dir);
+        let (work_dir, shim_dir) = match git_dir.parent() {
+            Some(parent) if git_dir.file_name().is_some_and(|x| x == GIT_PATH) => (parent.to_path_buf(), None),
+            _ => {
+                let shim_dir = env::temp_dir().join(format!("git-repository-{}", process::id()));
+                fs::create_dir_all(&shim_dir).context(format!("Failed to create {}", shim_dir.display()))?;
+                symlink(&git_dir, shim_dir.join(GIT_PATH)).context(format!("Failed to link {}", git_dir.display()))?;
+                (shim_dir.clone(), Some(shim_dir))
+            },
+        };
+        let entered = Self { shim_dir };
+        env::set_current_dir(&work_dir).context(format!("cannot change to '{}'", work_dir.display()))?;
+        Ok(entered)
+    }
+}
+impl Drop for EnteredRepository {
+    fn drop(&mut self) {
+        if let Some(shim_dir) = &self.shim_dir {
+            let _ = fs::remove_file(shim_dir.join(GIT_PATH));
+            let _ = fs::remove_dir(shim_dir);
+        }
+    }
+}
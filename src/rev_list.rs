@@ -0,0 +1,361 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use anyhow::bail;
+use crate::commit_object_read::{CommitObject, read_shallow_commits};
+use crate::common::{ObjectMode, ObjectType};
+use crate::refs::{list_refs, read_head_commit};
+use crate::rev_parse::{read_object_type, resolve_revision};
+use crate::tag_object_read::TagObject;
+use crate::tree_object_read::read_tree;
+
+#[derive(Default)]
+pub(crate) struct RevListOptions {
+    /// also list trees and blobs reachable from the listed commits
+    pub objects: bool,
+    /// also list commits that are parents of the listed commits but are not listed themselves
+    pub boundary: bool,
+    /// also list excluded commits that are parents of the listed commits
+    pub edges: bool,
+    pub max_count: Option<usize>,
+    /// only list commits that are at most this many generations from the included ones, like a shallow fetch
+    pub depth: Option<usize>,
+    /// commits whose parents are not walked, like the ones where the history of a shallow clone ends
+    pub shallow: Vec<String>,
+}
+
+/// A non-commit object reachable from the listed commits, with the path it was found at
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ListedObject {
+    pub hash: String,
+    pub object_type: ObjectType,
+    pub path: String,
+}
+
+#[derive(Default)]
+pub(crate) struct RevListResult {
+    /// listed commits, newest first
+    pub commits: Vec<CommitObject>,
+    pub boundary: Vec<CommitObject>,
+    pub edges: Vec<String>,
+    pub objects: Vec<ListedObject>,
+    /// listed commits whose parents were cut off by the depth
+    pub shallow: Vec<String>,
+}
+
+/// Splits command line revisions into included and excluded object hashes.
+/// Supports `<rev>`, `^<rev>` and `<from>..<to>`, `all` adds HEAD and all refs.
+pub(crate) fn parse_rev_args(args: &[String], all: bool) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let mut include = vec![];
+    let mut exclude = vec![];
+    if all {
+        include.extend(read_head_commit()?);
+        include.extend(list_refs("refs/")?.into_iter().map(|(_, hash)| hash));
+    }
+    for arg in args {
+        if let Some(rev) = arg.strip_prefix('^') {
+            exclude.push(resolve_revision(rev)?);
+        } else if arg.contains("...") {
+            bail!("Symmetric difference {arg} is not supported");
+        } else if let Some((from, to)) = arg.split_once("..") {
+            exclude.push(resolve_revision(from)?);
+            include.push(resolve_revision(to)?);
+        } else {
+            include.push(resolve_revision(arg)?);
+        }
+    }
+    Ok((include, exclude))
+}
+
+/// Walks commits reachable from `include` but not from `exclude`, newest commits first
+pub(crate) fn rev_list(include: &[String], exclude: &[String], options: &RevListOptions) -> anyhow::Result<RevListResult> {
+    let mut result = RevListResult::default();
+    let mut include_commits = vec![];
+    let mut pending_objects = vec![];
+    for hash in include {
+        let (hash, object_type) = peel_tips(hash, options.objects.then_some(&mut result.objects))?;
+        match object_type {
+            ObjectType::Commit => include_commits.push(hash),
+            _ if options.objects => pending_objects.push((hash, object_type)),
+            _ => bail!("Object {hash} is a {object_type}, not a commit"),
+        }
+    }
+    let mut exclude_commits = vec![];
+    let mut exclude_trees = vec![];
+    for hash in exclude {
+        let (hash, object_type) = peel_tips(hash, None)?;
+        match object_type {
+            ObjectType::Commit => exclude_commits.push(hash),
+            ObjectType::Tree => exclude_trees.push(hash),
+            _ => {},
+        }
+    }
+
+    let shallow = read_shallow_commits()?;
+    let uninteresting = collect_ancestors(&exclude_commits, &shallow)?;
+
+    let mut queue = BinaryHeap::new();
+    let mut queued = HashSet::new();
+    let mut generations = HashMap::new();
+    let mut sequence = 0usize;
+    for hash in include_commits {
+        if uninteresting.contains(&hash) || !queued.insert(hash.clone()) {
+            continue;
+        }
+        generations.insert(hash.clone(), 1);
+        let commit = CommitObject::read_cut(&hash, &shallow)?;
+        queue.push((commit.committer.timestamp, Reverse(sequence), hash));
+        sequence += 1;
+    }
+    let mut loaded = HashMap::new();
+    let mut listed = HashSet::new();
+    while let Some((_, _, hash)) = queue.pop() {
+        if options.max_count.is_some_and(|max| result.commits.len() >= max) {
+            break;
+        }
+        let commit = match loaded.remove(&hash) {
+            Some(commit) => commit,
+            None => CommitObject::read_cut(&hash, &shallow)?,
+        };
+        let generation = generations[&hash];
+        let cut = options.depth.is_some_and(|depth| generation >= depth) || options.shallow.contains(&hash);
+        if cut && !commit.parents.is_empty() {
+            result.shallow.push(hash.clone());
+            listed.insert(hash);
+            result.commits.push(commit);
+            continue;
+        }
+        for parent in &commit.parents {
+            let parent_generation = generations.entry(parent.clone()).or_insert(generation + 1);
+            *parent_generation = (*parent_generation).min(generation + 1);
+            if uninteresting.contains(parent) || !queued.insert(parent.clone()) {
+                continue;
+            }
+            let parent_commit = CommitObject::read_cut(parent, &shallow)?;
+            queue.push((parent_commit.committer.timestamp, Reverse(sequence), parent.clone()));
+            loaded.insert(parent.clone(), parent_commit);
+            sequence += 1;
+        }
+        listed.insert(hash);
+        result.commits.push(commit);
+    }
+
+    let mut boundary_seen = HashSet::new();
+    let mut boundary = vec![];
+    for commit in &result.commits {
+        for parent in &commit.parents {
+            if listed.contains(parent) || !boundary_seen.insert(parent.clone()) {
+                continue;
+            }
+            if uninteresting.contains(parent) {
+                result.edges.push(parent.clone());
+            }
+            boundary.push(parent.clone());
+        }
+    }
+    if options.boundary {
+        // like git, the boundary is collected newest discovery first and then sorted topologically,
+        // edges are already printed as boundary
+        let mut commits = vec![];
+        for hash in boundary.into_iter().rev() {
+            if options.edges && result.edges.contains(&hash) {
+                continue;
+            }
+            commits.push(CommitObject::read_cut(&hash, &shallow)?);
+        }
+        result.boundary = sort_topologically(commits);
+    }
+
+    if options.objects {
+        let mut seen = HashSet::new();
+        for tree in &exclude_trees {
+            mark_tree_seen(tree, &mut seen)?;
+        }
+        for edge in &result.edges {
+            let edge_commit = CommitObject::read(edge)?;
+            mark_tree_seen(&edge_commit.tree, &mut seen)?;
+        }
+        for commit in &result.commits {
+            walk_tree(&commit.tree, "", &mut seen, &mut result.objects)?;
+        }
+        for (hash, object_type) in pending_objects {
+            match object_type {
+                ObjectType::Tree => walk_tree(&hash, "", &mut seen, &mut result.objects)?,
+                _ => {
+                    if seen.insert(hash.clone()) {
+                        result.objects.push(ListedObject { hash, object_type, path: String::new() });
+                    }
+                }
+            }
+        }
+    }
+    if !options.edges {
+        result.edges.clear();
+    }
+
+    Ok(result)
+}
+
+/// Orders the commits so that children come before their parents, keeping the order of the list otherwise,
+/// the way git sorts in graph order: commits are taken from a stack that starts with the ones without children
+fn sort_topologically(commits: Vec<CommitObject>) -> Vec<CommitObject> {
+    let positions: HashMap<String, usize> = commits.iter().enumerate().map(|(i, x)| (x.hash.clone(), i)).collect();
+    let mut children = vec![0; commits.len()];
+    for commit in &commits {
+        for parent in commit.parents.iter().filter_map(|x| positions.get(x)) {
+            children[*parent] += 1;
+        }
+    }
+    let mut stack: Vec<usize> = (0..commits.len()).rev().filter(|x| children[*x] == 0).collect();
+    let mut order = vec![];
+    while let Some(index) = stack.pop() {
+        order.push(index);
+        for parent in commits[index].parents.iter().filter_map(|x| positions.get(x)) {
+            children[*parent] -= 1;
+            if children[*parent] == 0 {
+                stack.push(*parent);
+            }
+        }
+    }
+    let mut commits: Vec<Option<CommitObject>> = commits.into_iter().map(Some).collect();
+    order.into_iter().filter_map(|x| commits[x].take()).collect()
+}
+
+/// Peels tags, optionally recording the tag objects themselves
+fn peel_tips(hash: &str, mut tags: Option<&mut Vec<ListedObject>>) -> anyhow::Result<(String, ObjectType)> {
+    let mut hash = hash.to_string();
+    loop {
+        let object_type = read_object_type(&hash)?;
+        if object_type != ObjectType::Tag {
+            return Ok((hash, object_type));
+        }
+        let tag = TagObject::read(&hash)?;
+        if let Some(tags) = tags.as_mut() {
+            if !tags.iter().any(|x| x.hash == hash) {
+                tags.push(ListedObject { hash, object_type, path: tag.tag.clone() });
+            }
+        }
+        hash = tag.object;
+    }
+}
+
+/// All commits reachable from the given commits, including themselves, down to the shallow commits
+pub(crate) fn collect_ancestors(commits: &[String], shallow: &HashSet<String>) -> anyhow::Result<HashSet<String>> {
+    let mut seen = HashSet::new();
+    let mut stack = commits.to_vec();
+    while let Some(hash) = stack.pop() {
+        if !seen.insert(hash.clone()) {
+            continue;
+        }
+        let commit = CommitObject::read_cut(&hash, shallow)?;
+        stack.extend(commit.parents.into_iter().filter(|x| !seen.contains(x)));
+    }
+    Ok(seen)
+}
+
+fn mark_tree_seen(tree: &str, seen: &mut HashSet<String>) -> anyhow::Result<()> {
+    if !seen.insert(tree.to_string()) {
+        return Ok(());
+    }
+    for item in read_tree(tree)? {
+        match item.mode {
+            ObjectMode::Tree => mark_tree_seen(&item.hash, seen)?,
+            ObjectMode::Gitlink => {},
+            _ => {
+                seen.insert(item.hash);
+            },
+        }
+    }
+    Ok(())
+}
+
+fn walk_tree(tree: &str, path: &str, seen: &mut HashSet<String>, objects: &mut Vec<ListedObject>) -> anyhow::Result<()> {
+    if !seen.insert(tree.to_string()) {
+        return Ok(());
+    }
+    objects.push(ListedObject { hash: tree.to_string(), object_type: ObjectType::Tree, path: path.to_string() });
+    for item in read_tree(tree)? {
+        let file_name = item.file_name.to_string_lossy();
+        let item_path = if path.is_empty() { file_name.to_string() } else { format!("{path}/{file_name}") };
+        match item.mode {
+            ObjectMode::Tree => walk_tree(&item.hash, &item_path, seen, objects)?,
+            ObjectMode::Gitlink => {},
+            _ => {
+                if seen.insert(item.hash.clone()) {
+                    objects.push(ListedObject { hash: item.hash, object_type: ObjectType::Blob, path: item_path });
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::slice;
+    use super::*;
+    use crate::commit_object_read::Signature;
+    use crate::common::init_test;
+    use crate::object_write::{hash_object, write_commit};
+    use crate::tree_object_write::write_tree_level;
+
+    /// Writes a commit whose tree has the files of the commits before it and a file of its own
+    fn commit(name: &str, files: &mut Vec<(String, String)>, parents: &[&str], timestamp: i64) -> anyhow::Result<String> {
+        let content = format!("rev-list test {name}\n");
+        let blob = hash_object(content.as_bytes(), ObjectType::Blob, content.len() as u64, true)?;
+        files.push((format!("{name}.txt"), blob));
+        files.sort();
+        let entries: Vec<_> = files.iter().map(|(path, hash)| (path.as_str(), ObjectMode::Normal, hash.as_str())).collect();
+        let tree = write_tree_level(&entries)?;
+        let signature = Signature { name: "test".to_string(), email: "test@example.com".to_string(), timestamp, timezone: "+0000".to_string() };
+        write_commit(&tree, parents, &signature, &signature, name)
+    }
+
+    fn hashes(commits: &[CommitObject]) -> Vec<&str> {
+        commits.iter().map(|x| x.hash.as_str()).collect()
+    }
+
+    #[test]
+    fn test_rev_list() -> anyhow::Result<()> {
+        let _guard = init_test()?;
+        // a - b - c - merge
+        //      \- side -/
+        let mut files = vec![];
+        let a = commit("a", &mut files, &[], 1_000)?;
+        let b = commit("b", &mut files, &[&a], 2_000)?;
+        let mut side_files = files.clone();
+        let c = commit("c", &mut files, &[&b], 3_000)?;
+        let side = commit("side", &mut side_files, &[&b], 2_500)?;
+        files.extend(side_files.into_iter().filter(|x| x.0 == "side.txt"));
+        let merge = commit("merge", &mut files, &[&c, &side], 4_000)?;
+
+        let result = rev_list(slice::from_ref(&merge), slice::from_ref(&b), &RevListOptions::default())?;
+        assert_eq!(vec![merge.as_str(), &c, &side], hashes(&result.commits));
+        let (include, exclude) = parse_rev_args(&[format!("{a}..{c}")], false)?;
+        assert_eq!((vec![c.clone()], vec![a.clone()]), (include.clone(), exclude.clone()));
+        assert_eq!(vec![c.as_str(), &b], hashes(&rev_list(&include, &exclude, &RevListOptions::default())?.commits));
+
+        let options = RevListOptions { max_count: Some(2), ..RevListOptions::default() };
+        assert_eq!(vec![merge.as_str(), &c], hashes(&rev_list(slice::from_ref(&merge), &[], &options)?.commits));
+
+        // c is a child of b, so it comes first although b was found first
+        let options = RevListOptions { boundary: true, ..RevListOptions::default() };
+        let result = rev_list(slice::from_ref(&merge), slice::from_ref(&c), &options)?;
+        assert_eq!(vec![merge.as_str(), &side], hashes(&result.commits));
+        assert_eq!(vec![c.as_str(), &b], hashes(&result.boundary));
+        assert!(result.edges.is_empty());
+
+        let options = RevListOptions { objects: true, ..RevListOptions::default() };
+        let result = rev_list(slice::from_ref(&b), slice::from_ref(&a), &options)?;
+        let b_commit = CommitObject::read(&b)?;
+        let objects: Vec<_> = result.objects.iter().map(|x| (x.object_type, x.path.as_str())).collect();
+        assert_eq!(vec![(ObjectType::Tree, ""), (ObjectType::Blob, "b.txt")], objects);
+        assert_eq!(b_commit.tree, result.objects[0].hash);
+
+        let options = RevListOptions { objects: true, edges: true, ..RevListOptions::default() };
+        let result = rev_list(slice::from_ref(&merge), slice::from_ref(&c), &options)?;
+        assert_eq!(vec![c.clone(), b.clone()], result.edges);
+        let paths: Vec<_> = result.objects.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(vec!["", "merge.txt", "side.txt", ""], paths);
+        Ok(())
+    }
+}
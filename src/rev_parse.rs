@@ -0,0 +1,134 @@
+use anyhow::{bail, Context};
+use crate::commit_object_read::CommitObject;
+use crate::common::{get_hash_by_object_path, MIN_OBJECT_SEARCH_LEN, ObjectType};
+use crate::object_read::find_and_decode_object;
+use crate::refs::{dwim_ref, Head, is_full_hash, read_head, read_reflog};
+use crate::tag_object_read::TagObject;
+use crate::tree_object_read::find_tree_entry;
+
+/// Resolves a revision like `main`, `HEAD~2`, `abc1234^2`, `v1.0^{tree}`, `stash@{1}` or `HEAD:src/main.rs` into a full object hash
+pub(crate) fn resolve_revision(rev: &str) -> anyhow::Result<String> {
+    if let Some((tree_rev, path)) = rev.split_once(':') {
+        let tree = peel(&resolve_revision(tree_rev)?, ObjectType::Tree)?;
+        if path.is_empty() {
+            return Ok(tree);
+        }
+        let Some(item) = find_tree_entry(&tree, path)? else {
+            bail!("Path {path} does not exist in {tree_rev}");
+        };
+        return Ok(item.hash);
+    }
+    let base_end = rev.find(['^', '~']).unwrap_or(rev.len());
+    let (base, mut suffix) = rev.split_at(base_end);
+    let mut hash = resolve_base(base).context(format!("Failed to resolve revision {rev}"))?;
+
+    while !suffix.is_empty() {
+        let (operator, rest) = suffix.split_at(1);
+        if operator == "^" && rest.starts_with('{') {
+            let Some(end) = rest.find('}') else {
+                bail!("Invalid revision {rev}: unclosed brace");
+            };
+            let peel_to = &rest[1..end];
+            hash = match peel_to {
+                "" => peel_tags(&hash)?,
+                _ => {
+                    let object_type = ObjectType::try_from(peel_to.as_bytes())
+                        .context(format!("Invalid revision {rev}: unknown object type {peel_to}"))?;
+                    peel(&hash, object_type)?
+                }
+            };
+            suffix = &rest[end + 1..];
+            continue;
+        }
+        let digits_end = rest.find(|x: char| !x.is_ascii_digit()).unwrap_or(rest.len());
+        let (digits, rest) = rest.split_at(digits_end);
+        let number = match digits {
+            "" => 1,
+            x => x.parse::<usize>().context(format!("Invalid revision {rev}: bad number {x}"))?,
+        };
+        if operator == "^" {
+            hash = nth_parent(&hash, number).context(format!("Failed to resolve revision {rev}"))?;
+        } else {
+            for _ in 0..number {
+                hash = nth_parent(&hash, 1).context(format!("Failed to resolve revision {rev}"))?;
+            }
+        }
+        suffix = rest;
+    }
+    Ok(hash)
+}
+
+fn resolve_base(name: &str) -> anyhow::Result<String> {
+    let name = if name.is_empty() || name == "@" { "HEAD" } else { name };
+    if is_full_hash(name) {
+        return Ok(name.to_lowercase());
+    }
+    if let Some((ref_part, position)) = name.strip_suffix('}').and_then(|x| x.rsplit_once("@{")) {
+        return resolve_reflog_entry(ref_part, position);
+    }
+    if let Some((_, hash)) = dwim_ref(name)? {
+        return Ok(hash);
+    }
+    let is_hex = name.bytes().all(|x| x.is_ascii_hexdigit());
+    if is_hex && name.len() >= MIN_OBJECT_SEARCH_LEN {
+        let object = find_and_decode_object(&name.to_lowercase())?;
+        return Ok(get_hash_by_object_path(&object.file_path));
+    }
+    bail!("Unknown revision {name}");
+}
+
+/// The commit a ref pointed to the given number of changes ago according to its reflog, the current branch without a ref
+fn resolve_reflog_entry(ref_part: &str, position: &str) -> anyhow::Result<String> {
+    let position = position.parse::<usize>().context(format!("Invalid reflog position {position}"))?;
+    let ref_name = match (ref_part, read_head()?) {
+        ("" | "@", Head::Branch(name)) => name,
+        ("" | "@", Head::Detached(_)) => "HEAD".to_string(),
+        (name, _) => dwim_ref(name)?.context(format!("Unknown revision {name}"))?.0,
+    };
+    let entries = read_reflog(&ref_name)?;
+    match entries.len().checked_sub(position + 1) {
+        Some(index) => Ok(entries[index].new.clone()),
+        None => bail!("log for '{ref_part}' only has {} entries", entries.len()),
+    }
+}
+
+pub(crate) fn read_object_type(hash: &str) -> anyhow::Result<ObjectType> {
+    Ok(find_and_decode_object(hash)?.object_type)
+}
+
+/// Follows tags until a non-tag object is reached
+pub(crate) fn peel_tags(hash: &str) -> anyhow::Result<String> {
+    let mut hash = hash.to_string();
+    while read_object_type(&hash)? == ObjectType::Tag {
+        hash = TagObject::read(&hash)?.object;
+    }
+    Ok(hash)
+}
+
+/// Follows tags and commits until an object of the expected type is reached
+pub(crate) fn peel(hash: &str, expected_type: ObjectType) -> anyhow::Result<String> {
+    let mut hash = hash.to_string();
+    loop {
+        let object_type = read_object_type(&hash)?;
+        if object_type == expected_type {
+            return Ok(hash);
+        }
+        hash = match (object_type, expected_type) {
+            (ObjectType::Tag, _) => TagObject::read(&hash)?.object,
+            (ObjectType::Commit, ObjectType::Tree) => CommitObject::read(&hash)?.tree,
+            _ => bail!("Object {hash} is a {object_type}, it can not be peeled to a {expected_type}"),
+        };
+    }
+}
+
+fn nth_parent(hash: &str, number: usize) -> anyhow::Result<String> {
+    let hash = peel(hash, ObjectType::Commit)?;
+    if number == 0 {
+        return Ok(hash);
+    }
+    let commit = CommitObject::read(&hash)?;
+    let Some(parent) = commit.parents.get(number - 1) else {
+        bail!("Commit {hash} does not have parent number {number}");
+    };
+    Ok(parent.clone())
+}
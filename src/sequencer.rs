@@ -0,0 +1,680 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use anyhow::{bail, Context};
+use crate::{apply_merge_result, commit_command, merge_options, print_commit_summary, print_nothing_to_commit, remove_merge_state, report_unmerged_files, reset_merge, write_merge_message};
+use crate::cli::{CommitFlags, ReplayFlags};
+use crate::commit_object_read::CommitObject;
+use crate::common::{CHERRY_PICK_HEAD_PATH, Exit, ObjectType, REVERT_HEAD_PATH};
+use crate::config::Config;
+use crate::diff::{abbreviate, NULL_HASH};
+use crate::ident::{read_ident, Role};
+use crate::index::Index;
+use crate::message_cleanup::CleanupMode;
+use crate::object_write::{hash_object, write_commit};
+use crate::pathspec::Pathspec;
+use crate::refs::{read_head, read_head_commit, update_head};
+use crate::rev_list::{parse_rev_args, rev_list, RevListOptions};
+use crate::rev_parse::{peel, resolve_revision};
+use crate::tree_diff::diff_tree_to_index;
+use crate::tree_merge::merge_trees;
+use crate::tree_object_write::write_index_tree;
+
+/// the state of a cherry-pick or revert of several commits, while it waits for conflicts to be resolved
+const SEQUENCER_PATH: &str = ".git/sequencer";
+/// the commits that are still to be replayed, the first one is the current one
+const TODO_PATH: &str = ".git/sequencer/todo";
+/// where HEAD was before the first commit was replayed
+const HEAD_PATH: &str = ".git/sequencer/head";
+const OPTS_PATH: &str = ".git/sequencer/opts";
+/// where HEAD was after the last replayed commit, an abort does not rewind HEAD if it was moved since
+const ABORT_SAFETY_PATH: &str = ".git/sequencer/abort-safety";
+
+const CHERRY_PICKED_PREFIX: &str = "(cherry picked from commit ";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReplayAction {
+    Pick,
+    Revert,
+}
+impl ReplayAction {
+    /// The name of the command, like cherry-pick
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Pick => "cherry-pick",
+            Self::Revert => "revert",
+        }
+    }
+
+    /// The file that names the commit whose conflicts wait to be resolved
+    pub fn head_path(&self) -> &'static str {
+        match self {
+            Self::Pick => CHERRY_PICK_HEAD_PATH,
+            Self::Revert => REVERT_HEAD_PATH,
+        }
+    }
+
+    fn todo_command(&self) -> &'static str {
+        match self {
+            Self::Pick => "pick",
+            Self::Revert => "revert",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ReplayOptions {
+    /// the parent number, starting from 1, that the changes of merge commits are taken relative to
+    pub mainline: Option<usize>,
+    pub no_commit: bool,
+    /// add a line saying which commit was cherry-picked to the message
+    pub record_origin: bool,
+}
+
+/// A cherry-pick or revert of several commits in progress
+pub(crate) struct Sequencer {
+    pub action: ReplayAction,
+    pub options: ReplayOptions,
+    /// the commit HEAD pointed to when the sequence started
+    pub head: String,
+    pub todo: Vec<String>,
+}
+impl Sequencer {
+    pub fn in_progress() -> bool {
+        Path::new(SEQUENCER_PATH).is_dir()
+    }
+
+    pub fn read() -> anyhow::Result<Self> {
+        let head = fs::read_to_string(HEAD_PATH).context(format!("Failed to read {HEAD_PATH}"))?.trim().to_string();
+        let todo_data = fs::read_to_string(TODO_PATH).context(format!("Failed to read {TODO_PATH}"))?;
+        let mut action = None;
+        let mut todo = vec![];
+        for line in todo_data.lines().filter(|x| !x.is_empty() && !x.starts_with('#')) {
+            let mut words = line.split_whitespace();
+            let line_action = match words.next() {
+                Some("pick" | "p") => ReplayAction::Pick,
+                Some("revert" | "r") => ReplayAction::Revert,
+                _ => bail!("Invalid line in {TODO_PATH}: {line}"),
+            };
+            if action.is_some_and(|x| x != line_action) {
+                bail!("Cannot cherry-pick during a revert.");
+            }
+            action = Some(line_action);
+            let Some(hash) = words.next() else {
+                bail!("Missing commit in {TODO_PATH}: {line}");
+            };
+            todo.push(resolve_revision(hash).and_then(|x| peel(&x, ObjectType::Commit))?);
+        }
+        let mut opts = Config::default();
+        if let Ok(data) = fs::read_to_string(OPTS_PATH) {
+            opts.parse(&data).context(format!("Failed to parse {OPTS_PATH}"))?;
+        }
+        let mainline = match opts.get("options.mainline") {
+            Some(value) => Some(value.parse().context(format!("Invalid mainline in {OPTS_PATH}: {value}"))?),
+            None => None,
+        };
+        let options = ReplayOptions {
+            mainline,
+            no_commit: opts.get("options.no-commit") == Some("true"),
+            record_origin: opts.get("options.record-origin") == Some("true"),
+        };
+        Ok(Self { action: action.unwrap_or(ReplayAction::Pick), options, head, todo })
+    }
+
+    /// Creates the state of a new sequence, HEAD is the current commit
+    pub fn write(&self) -> anyhow::Result<()> {
+        fs::create_dir_all(SEQUENCER_PATH).context(format!("Failed to create {SEQUENCER_PATH}"))?;
+        fs::write(HEAD_PATH, format!("{}\n", self.head)).context(format!("Failed to write {HEAD_PATH}"))?;
+        let mut opts = String::new();
+        if self.options.no_commit {
+            opts.push_str("\tno-commit = true\n");
+        }
+        if self.options.record_origin {
+            opts.push_str("\trecord-origin = true\n");
+        }
+        if let Some(mainline) = self.options.mainline {
+            opts.push_str(&format!("\tmainline = {mainline}\n"));
+        }
+        // like git, the file is only written when there are options
+        if !opts.is_empty() {
+            fs::write(OPTS_PATH, format!("[options]\n{opts}")).context(format!("Failed to write {OPTS_PATH}"))?;
+        }
+        self.write_todo(&self.head)
+    }
+
+    /// Saves the commits that are left, after HEAD was moved to the given commit
+    pub fn write_todo(&self, head: &str) -> anyhow::Result<()> {
+        let mut todo = String::new();
+        for hash in &self.todo {
+            let commit = CommitObject::read(hash)?;
+            todo.push_str(&format!("{} {} {}\n", self.action.todo_command(), abbreviate(hash), first_line(&commit.message)));
+        }
+        fs::write(TODO_PATH, todo).context(format!("Failed to write {TODO_PATH}"))?;
+        fs::write(ABORT_SAFETY_PATH, format!("{head}\n")).context(format!("Failed to write {ABORT_SAFETY_PATH}"))
+    }
+
+    /// Where HEAD was after the last commit replayed by the sequence
+    pub fn read_abort_safety() -> Option<String> {
+        fs::read_to_string(ABORT_SAFETY_PATH).ok().map(|x| x.trim().to_string())
+    }
+
+    pub fn remove() -> anyhow::Result<()> {
+        match fs::remove_dir_all(SEQUENCER_PATH) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error).context(format!("Failed to remove {SEQUENCER_PATH}")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The cherry-pick or revert in progress, with the commit whose conflicts wait to be resolved.
+/// The commit is left out while several commits are replayed, like git status does
+pub(crate) fn replay_in_progress() -> anyhow::Result<Option<(ReplayAction, Option<String>)>> {
+    if Sequencer::in_progress() {
+        return Ok(Some((Sequencer::read()?.action, None)));
+    }
+    for action in [ReplayAction::Pick, ReplayAction::Revert] {
+        if let Ok(hash) = fs::read_to_string(action.head_path()) {
+            return Ok(Some((action, Some(hash.trim().to_string()))));
+        }
+    }
+    Ok(None)
+}
+
+pub(crate) fn first_line(message: &str) -> &str {
+    message.lines().next().unwrap_or("")
+}
+
+/// The message of a commit that reverts another one, mentioning the parent the changes were taken relative to for merges
+pub(crate) fn revert_message(commit: &CommitObject, mainline_parent: Option<&str>) -> String {
+    let reversing = match mainline_parent {
+        Some(parent) => format!(", reversing\nchanges made to {parent}"),
+        None => String::new(),
+    };
+    format!("Revert \"{}\"\n\nThis reverts commit {}{reversing}.\n", first_line(&commit.message), commit.hash)
+}
+
+/// Adds the "(cherry picked from commit ...)" line, as a trailer after the other trailers if the message ends with some
+pub(crate) fn append_cherry_picked_from(message: &str, hash: &str) -> String {
+    let mut message = message.to_string();
+    if !message.is_empty() && !message.ends_with('\n') {
+        message.push('\n');
+    }
+    if !ends_with_trailers(&message) {
+        message.push('\n');
+    }
+    message.push_str(&format!("{CHERRY_PICKED_PREFIX}{hash})\n"));
+    message
+}
+
+/// Whether the last paragraph, other than the subject, only has lines like "Signed-off-by: name"
+fn ends_with_trailers(message: &str) -> bool {
+    let paragraphs = message.trim_end().split("\n\n").collect::<Vec<_>>();
+    let [_, .., last] = paragraphs.as_slice() else {
+        return false;
+    };
+    last.lines().all(|line| {
+        let is_trailer = line.split_once(": ")
+            .is_some_and(|(key, _)| !key.is_empty() && key.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'-'));
+        is_trailer || line.starts_with(CHERRY_PICKED_PREFIX)
+    })
+}
+
+/// Applies the changes of commits on top of HEAD, or undoes them for a revert. A single commit is replayed on its own,
+/// several commits go through the sequencer, which stops at conflicts until it is continued, skipped or aborted
+pub(crate) fn replay_command(action: ReplayAction, flags: ReplayFlags, record_origin: bool) -> anyhow::Result<()> {
+    let config = Config::read()?;
+    let command = action.command();
+    if flags.resume {
+        return replay_continue(action, &config);
+    }
+    if flags.skip {
+        return replay_skip(action, &config);
+    }
+    if flags.abort {
+        return replay_abort(action, &config);
+    }
+    let gerund = match action {
+        ReplayAction::Pick => "Cherry-picking",
+        ReplayAction::Revert => "Reverting",
+    };
+    if report_unmerged_files(&Index::read()?, gerund, false) {
+        bail!("{command} failed");
+    }
+    let options = ReplayOptions { mainline: flags.mainline, no_commit: flags.no_commit, record_origin };
+    let (include, exclude) = parse_rev_args(&flags.commits, false)?;
+    // a single commit does not touch the sequencer, so it can be picked in the middle of a sequence
+    if let ([commit], []) = (include.as_slice(), exclude.as_slice()) {
+        let hash = peel(commit, ObjectType::Commit)?;
+        if !replay_commit(action, &hash, &options, &config)? {
+            return Err(Exit(1).into());
+        }
+        return Ok(());
+    }
+
+    // ranges are picked oldest first and reverted newest first, listed commits keep their order
+    let todo = if exclude.is_empty() {
+        include.iter().map(|x| peel(x, ObjectType::Commit)).collect::<anyhow::Result<Vec<_>>>()?
+    } else {
+        let mut commits = rev_list(&include, &exclude, &RevListOptions::default())?.commits.into_iter().map(|x| x.hash).collect::<Vec<_>>();
+        if action == ReplayAction::Pick {
+            commits.reverse();
+        }
+        commits
+    };
+    if todo.is_empty() {
+        eprintln!("error: empty commit set passed");
+        bail!("{command} failed");
+    }
+    if Sequencer::in_progress() {
+        eprintln!("error: {command} is already in progress");
+        eprintln!("hint: try \"git {command} (--continue | --abort | --quit)\"");
+        bail!("{command} failed");
+    }
+    let head = match read_head_commit()? {
+        Some(hash) => hash,
+        None if action == ReplayAction::Revert => {
+            eprintln!("error: can't revert as initial commit");
+            bail!("{command} failed");
+        },
+        None => NULL_HASH.to_string(),
+    };
+    let sequencer = Sequencer { action, options, head, todo };
+    sequencer.write()?;
+    run_sequencer(sequencer, &config)
+}
+
+/// Replays the commits left in the sequencer, saving the state after each one. Exits when a commit stops the sequence
+fn run_sequencer(mut sequencer: Sequencer, config: &Config) -> anyhow::Result<()> {
+    while let Some(hash) = sequencer.todo.first().cloned() {
+        let done = replay_commit(sequencer.action, &hash, &sequencer.options, config)?;
+        let head = read_head_commit()?.unwrap_or_else(|| NULL_HASH.to_string());
+        if !done {
+            // the stopped commit stays the first one, continuing commits it or skipping drops it
+            sequencer.write_todo(&head)?;
+            return Err(Exit(1).into());
+        }
+        sequencer.todo.remove(0);
+        sequencer.write_todo(&head)?;
+    }
+    Sequencer::remove()
+}
+
+/// Applies the changes of a commit relative to its parent, or the reverse changes for a revert, with a three-way merge
+/// into HEAD and commits the result. Returns false when the commit stops, because of conflicts or an empty result
+fn replay_commit(action: ReplayAction, hash: &str, options: &ReplayOptions, config: &Config) -> anyhow::Result<bool> {
+    let command = action.command();
+    let commit = CommitObject::read(hash)?;
+    // the changes of a merge are taken relative to the given parent, other commits accept the first parent
+    let parent = if commit.parents.len() > 1 || options.mainline.is_some_and(|x| x > 1) {
+        let Some(number) = options.mainline else {
+            eprintln!("error: commit {hash} is a merge but no -m option was given.");
+            bail!("{command} failed");
+        };
+        let Some(parent) = number.checked_sub(1).and_then(|x| commit.parents.get(x)) else {
+            eprintln!("error: commit {hash} does not have parent {number}");
+            bail!("{command} failed");
+        };
+        Some(parent.clone())
+    } else {
+        commit.parents.first().cloned()
+    };
+
+    let head_commit = read_head_commit()?;
+    let head_tree = match &head_commit {
+        Some(hash) => Some(peel(hash, ObjectType::Tree)?),
+        None => None,
+    };
+    let mut index = Index::read()?;
+    // without committing the changes pile up in the index, otherwise they would be committed along with the commit
+    let ours = if options.no_commit {
+        write_index_tree(&index)?
+    } else {
+        if !diff_tree_to_index(head_tree.as_deref(), &index, true, &Pathspec::new(&[]))?.is_empty() {
+            eprintln!("error: your local changes would be overwritten by {command}.");
+            eprintln!("hint: commit your changes or stash them to proceed.");
+            bail!("{command} failed");
+        }
+        match &head_tree {
+            Some(tree) => tree.clone(),
+            None => hash_object(&[][..], ObjectType::Tree, 0, true)?,
+        }
+    };
+    let commit_tree = commit.tree.clone();
+    let parent_tree = match &parent {
+        Some(parent) => peel(parent, ObjectType::Tree)?,
+        None => hash_object(&[][..], ObjectType::Tree, 0, true)?,
+    };
+
+    let label = format!("{} ({})", abbreviate(hash), first_line(&commit.message));
+    let parent_label = format!("parent of {label}");
+    let (base, theirs, base_label, theirs_label, message) = match action {
+        ReplayAction::Pick => {
+            let message = match options.record_origin {
+                true => append_cherry_picked_from(&commit.message, hash),
+                false => commit.message.clone(),
+            };
+            (parent_tree, commit_tree, parent_label, label.clone(), message)
+        },
+        ReplayAction::Revert => {
+            let mainline_parent = parent.as_deref().filter(|_| commit.parents.len() > 1);
+            (commit_tree, parent_tree, label.clone(), parent_label, revert_message(&commit, mainline_parent))
+        },
+    };
+    let result = merge_trees(Some(&base), &ours, &theirs, &base_label, &merge_options("HEAD".to_string(), theirs_label, config)?)?;
+    let merged_tree = result.tree.clone();
+    let conflicted_paths = apply_merge_result(&ours, result, &mut index, &format!("{command} failed"), false)?;
+    write_merge_message(&message, &conflicted_paths)?;
+    let head_path = action.head_path();
+    let write_replay_head = || fs::write(head_path, format!("{hash}\n")).context(format!("Failed to write {head_path}"));
+    if !conflicted_paths.is_empty() {
+        if !options.no_commit {
+            write_replay_head()?;
+        }
+        let verb = if action == ReplayAction::Pick { "apply" } else { "revert" };
+        eprintln!("error: could not {verb} {}... {}", abbreviate(hash), first_line(&commit.message));
+        if options.no_commit {
+            eprintln!("hint: after resolving the conflicts, mark the corrected paths");
+            eprintln!("hint: with 'git add <paths>' or 'git rm <paths>'");
+        } else {
+            eprintln!("hint: After resolving the conflicts, mark them with");
+            eprintln!("hint: \"git add/rm <pathspec>\", then run");
+            eprintln!("hint: \"git {command} --continue\".");
+            eprintln!("hint: You can instead skip this commit with \"git {command} --skip\".");
+            eprintln!("hint: To abort and get back to the state before \"git {command}\",");
+            eprintln!("hint: run \"git {command} --abort\".");
+        }
+        return Ok(false);
+    }
+    if options.no_commit {
+        return Ok(true);
+    }
+    if head_tree.as_deref().unwrap_or(&ours) == merged_tree {
+        write_replay_head()?;
+        print_nothing_to_commit(&read_head()?, head_commit.is_none(), &index, config)?;
+        return Ok(false);
+    }
+
+    let committer = read_ident(Role::Committer, config)?;
+    // a cherry-picked commit keeps its author, a revert is by the one who reverts
+    let author = match action {
+        ReplayAction::Pick => commit.author.clone(),
+        ReplayAction::Revert => read_ident(Role::Author, config)?,
+    };
+    let parents = head_commit.iter().map(String::as_str).collect::<Vec<_>>();
+    let new_hash = write_commit(&merged_tree, &parents, &author, &committer, &message)?;
+    update_head(&new_hash, head_commit.as_deref(), &committer, &format!("{command}: {}", first_line(&message)))?;
+    remove_merge_state()?;
+    print_commit_summary(&read_head()?, &new_hash, &message, head_tree.as_deref(), &merged_tree, &author, &committer, true, config)?;
+    Ok(true)
+}
+
+/// Commits the resolved conflicts of the stopped commit, then replays the commits left in the sequencer
+fn replay_continue(action: ReplayAction, config: &Config) -> anyhow::Result<()> {
+    let command = action.command();
+    if replay_in_progress()?.is_none() {
+        eprintln!("error: no cherry-pick or revert in progress");
+        bail!("{command} failed");
+    }
+    // the commit is made like git commit --no-edit would, with the comments about conflicts stripped from the message
+    if [CHERRY_PICK_HEAD_PATH, REVERT_HEAD_PATH].iter().any(|x| Path::new(x).exists()) {
+        commit_command(CommitFlags {
+            message: vec![],
+            file: None,
+            all: false,
+            amend: false,
+            allow_empty: false,
+            author: None,
+            date: None,
+            quiet: false,
+            edit: false,
+            no_edit: true,
+            cleanup: Some(CleanupMode::Strip),
+        })?;
+    }
+    if !Sequencer::in_progress() {
+        return Ok(());
+    }
+    let head_tree = match read_head_commit()? {
+        Some(hash) => Some(peel(&hash, ObjectType::Tree)?),
+        None => None,
+    };
+    if !diff_tree_to_index(head_tree.as_deref(), &Index::read()?, true, &Pathspec::new(&[]))?.is_empty() {
+        eprintln!("error: your local changes would be overwritten by {command}.");
+        eprintln!("hint: commit your changes or stash them to proceed.");
+        bail!("{command} failed");
+    }
+    let mut sequencer = Sequencer::read()?;
+    if !sequencer.todo.is_empty() {
+        sequencer.todo.remove(0);
+    }
+    run_sequencer(sequencer, config)
+}
+
+/// Drops the changes of the stopped commit and goes on with the commits left in the sequencer
+fn replay_skip(action: ReplayAction, config: &Config) -> anyhow::Result<()> {
+    let command = action.command();
+    if replay_in_progress()?.is_none() {
+        eprintln!("error: no cherry-pick or revert in progress");
+        bail!("{command} failed");
+    }
+    if [CHERRY_PICK_HEAD_PATH, REVERT_HEAD_PATH].iter().any(|x| Path::new(x).exists()) {
+        reset_replay(read_head_commit()?.as_deref(), config)?;
+    }
+    if !Sequencer::in_progress() {
+        return Ok(());
+    }
+    replay_continue(action, config)
+}
+
+/// Goes back to the commit the sequence started from, unless HEAD was moved since the last replayed commit.
+/// Without a sequence only the changes of the stopped commit are dropped
+fn replay_abort(action: ReplayAction, config: &Config) -> anyhow::Result<()> {
+    let command = action.command();
+    if !Sequencer::in_progress() {
+        if replay_in_progress()?.is_none() {
+            eprintln!("error: no cherry-pick or revert in progress");
+            bail!("{command} failed");
+        }
+        return reset_replay(read_head_commit()?.as_deref(), config);
+    }
+    let sequencer = Sequencer::read()?;
+    if Sequencer::read_abort_safety() != read_head_commit()? {
+        eprintln!("warning: You seem to have moved HEAD. Not rewinding, check your HEAD!");
+    } else {
+        reset_replay(Some(&sequencer.head), config)?;
+    }
+    Sequencer::remove()
+}
+
+/// Moves HEAD to the commit like reset --merge, which is how git drops the changes of a stopped commit
+fn reset_replay(commit: Option<&str>, config: &Config) -> anyhow::Result<()> {
+    reset_merge(commit)?;
+    let Some(commit) = commit else {
+        return Ok(());
+    };
+    let committer = read_ident(Role::Committer, config)?;
+    update_head(commit, read_head_commit()?.as_deref(), &committer, &format!("reset: moving to {commit}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::commit_object_read::Signature;
+    use crate::common::init_worktree_test;
+
+    /// Writes the files, stages them and commits the index on top of HEAD, with more parents for a merge
+    fn commit_files(files: &[(&str, &str)], message: &str, merged: &[&str]) -> anyhow::Result<String> {
+        let mut index = Index::read()?;
+        for (path, content) in files {
+            fs::write(path, content)?;
+            index.add_file(path)?;
+        }
+        index.write()?;
+        let tree = write_index_tree(&index)?;
+        let head = read_head_commit()?;
+        let parents = head.iter().map(String::as_str).chain(merged.iter().copied()).collect::<Vec<_>>();
+        let signature = Signature { name: "author".to_string(), email: "author@example.com".to_string(), timestamp: 1_000, timezone: "+0000".to_string() };
+        let hash = write_commit(&tree, &parents, &signature, &signature, message)?;
+        update_head(&hash, head.as_deref(), &signature, "test")?;
+        Ok(hash)
+    }
+
+    fn replay(action: ReplayAction, commits: &[&str], mainline: Option<usize>, record_origin: bool) -> anyhow::Result<()> {
+        let commits = commits.iter().map(|x| x.to_string()).collect();
+        replay_command(action, ReplayFlags { mainline, no_commit: false, resume: false, skip: false, abort: false, commits }, record_origin)
+    }
+
+    fn resume(action: ReplayAction, resume: bool, skip: bool, abort: bool) -> anyhow::Result<()> {
+        replay_command(action, ReplayFlags { mainline: None, no_commit: false, resume, skip, abort, commits: vec![] }, false)
+    }
+
+    fn head_commit() -> anyhow::Result<CommitObject> {
+        CommitObject::read(&read_head_commit()?.context("HEAD is unborn")?)
+    }
+
+    /// Sets the identity of the committer, for the commits that the tests replay
+    fn init_replay_test(name: &str) -> anyhow::Result<std::sync::MutexGuard<'static, ()>> {
+        let guard = init_worktree_test(name)?;
+        fs::write(".git/config", "[user]\n\tname = committer\n\temail = committer@example.com\n")?;
+        Ok(guard)
+    }
+
+    #[test]
+    fn test_replay() -> anyhow::Result<()> {
+        let _guard = init_replay_test("replay")?;
+        let config = Config::read()?;
+        let first = commit_files(&[("a.txt", "1\n2\n3\n")], "first\n", &[])?;
+        commit_files(&[("b.txt", "b\n")], "second\n", &[])?;
+        let third = commit_files(&[("a.txt", "1\n2\nthree\n")], "third\n", &[])?;
+        reset_replay(Some(&first), &config)?;
+
+        // a picked commit keeps its author and message, -x records where it comes from
+        replay(ReplayAction::Pick, &[&third], None, true)?;
+        let picked = head_commit()?;
+        assert_eq!(("1\n2\nthree\n", false), (fs::read_to_string("a.txt")?.as_str(), Path::new("b.txt").exists()));
+        assert_eq!((vec![first.clone()], "author"), (picked.parents.clone(), picked.author.name.as_str()));
+        assert_eq!(format!("third\n\n(cherry picked from commit {third})\n"), picked.message);
+
+        // a revert undoes the changes and is made by the committer
+        replay(ReplayAction::Revert, &["HEAD"], None, false)?;
+        let reverted = head_commit()?;
+        assert_eq!("1\n2\n3\n", fs::read_to_string("a.txt")?);
+        assert_eq!((vec![picked.hash.clone()], "committer"), (reverted.parents.clone(), reverted.author.name.as_str()));
+        assert_eq!(revert_message(&picked, None), reverted.message);
+
+        // a range is picked oldest first
+        reset_replay(Some(&first), &config)?;
+        replay(ReplayAction::Pick, &[&format!("{first}..{third}")], None, false)?;
+        let last = head_commit()?;
+        let before = CommitObject::read(&last.parents[0])?;
+        assert_eq!(("third\n", "second\n"), (last.message.as_str(), before.message.as_str()));
+        assert_eq!(vec![first.clone()], before.parents);
+        assert_eq!(peel(&third, ObjectType::Tree)?, last.tree);
+        assert!(!Sequencer::in_progress());
+
+        // reverting a range undoes the newest commit first
+        replay(ReplayAction::Revert, &[&format!("{first}..HEAD")], None, false)?;
+        let reverted = head_commit()?;
+        assert_eq!("Revert \"second\"", first_line(&reverted.message));
+        assert_eq!(peel(&first, ObjectType::Tree)?, reverted.tree);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_mainline() -> anyhow::Result<()> {
+        let _guard = init_replay_test("replay-mainline")?;
+        let config = Config::read()?;
+        let base = commit_files(&[("a.txt", "a\n")], "base\n", &[])?;
+        let side = commit_files(&[("b.txt", "b\n")], "side\n", &[])?;
+        reset_replay(Some(&base), &config)?;
+        let main = commit_files(&[("c.txt", "c\n")], "main\n", &[])?;
+        let merge = commit_files(&[("b.txt", "b\n")], "merge\n", &[&side])?;
+
+        // the changes of a merge need a parent to be taken relative to
+        reset_replay(Some(&base), &config)?;
+        assert!(replay(ReplayAction::Pick, &[&merge], None, false).is_err());
+        assert!(replay(ReplayAction::Pick, &[&merge], Some(3), false).is_err());
+        assert_eq!(Some(base.clone()), read_head_commit()?);
+
+        // relative to the first parent the merge brings in the side branch
+        replay(ReplayAction::Pick, &[&merge], Some(1), false)?;
+        assert_eq!((true, false), (Path::new("b.txt").exists(), Path::new("c.txt").exists()));
+        // relative to the second parent it brings in the main branch
+        reset_replay(Some(&base), &config)?;
+        replay(ReplayAction::Pick, &[&merge], Some(2), false)?;
+        assert_eq!((false, true), (Path::new("b.txt").exists(), Path::new("c.txt").exists()));
+
+        // reverting the merge relative to the main branch drops the side branch
+        reset_replay(Some(&merge), &config)?;
+        replay(ReplayAction::Revert, &[&merge], Some(1), false)?;
+        assert_eq!((false, true), (Path::new("b.txt").exists(), Path::new("c.txt").exists()));
+        let merge_commit = CommitObject::read(&merge)?;
+        assert_eq!(revert_message(&merge_commit, Some(&main)), head_commit()?.message);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_conflicts() -> anyhow::Result<()> {
+        let _guard = init_replay_test("replay-conflicts")?;
+        let config = Config::read()?;
+        let base = commit_files(&[("a.txt", "a\n")], "base\n", &[])?;
+        commit_files(&[("a.txt", "b\n")], "conflicting\n", &[])?;
+        let last = commit_files(&[("d.txt", "d\n")], "clean\n", &[])?;
+        reset_replay(Some(&base), &config)?;
+        let other = commit_files(&[("a.txt", "c\n")], "other\n", &[])?;
+        let range = format!("{base}..{last}");
+
+        // a conflict stops the sequence at the commit
+        assert!(replay(ReplayAction::Pick, &[&range], None, false).is_err());
+        assert!(Sequencer::in_progress() && Path::new(CHERRY_PICK_HEAD_PATH).exists());
+        assert_eq!(Some(other.clone()), read_head_commit()?);
+        // another sequence waits for this one to end
+        assert!(replay(ReplayAction::Pick, &[&range], None, false).is_err());
+        assert_eq!(Some(other.clone()), read_head_commit()?);
+
+        // aborting goes back to where the sequence started
+        resume(ReplayAction::Pick, false, false, true)?;
+        assert!(!Sequencer::in_progress() && !Path::new(CHERRY_PICK_HEAD_PATH).exists());
+        assert_eq!((Some(other.clone()), "c\n".to_string()), (read_head_commit()?, fs::read_to_string("a.txt")?));
+
+        // skipping drops the stopped commit and picks the rest
+        assert!(replay(ReplayAction::Pick, &[&range], None, false).is_err());
+        resume(ReplayAction::Pick, false, true, false)?;
+        let head = head_commit()?;
+        assert_eq!((vec![other.clone()], "clean\n"), (head.parents.clone(), head.message.as_str()));
+        assert_eq!(("c\n".to_string(), true), (fs::read_to_string("a.txt")?, Path::new("d.txt").exists()));
+        assert!(!Sequencer::in_progress());
+
+        // continuing commits the resolved conflicts without the conflict comments, then picks the rest
+        reset_replay(Some(&other), &config)?;
+        assert!(replay(ReplayAction::Pick, &[&range], None, false).is_err());
+        fs::write("a.txt", "resolved\n")?;
+        let mut index = Index::read()?;
+        index.add_file("a.txt")?;
+        index.write()?;
+        resume(ReplayAction::Pick, true, false, false)?;
+        let head = head_commit()?;
+        let resolved = CommitObject::read(&head.parents[0])?;
+        assert_eq!(("clean\n", "conflicting\n"), (head.message.as_str(), resolved.message.as_str()));
+        assert_eq!((vec![other.clone()], "author"), (resolved.parents.clone(), resolved.author.name.as_str()));
+        assert_eq!("resolved\n", fs::read_to_string("a.txt")?);
+        assert!(!Sequencer::in_progress() && !Path::new(CHERRY_PICK_HEAD_PATH).exists());
+
+        // nothing is left to continue
+        assert!(resume(ReplayAction::Pick, true, false, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_cherry_picked_from() {
+        let hash = "1".repeat(40);
+        let line = format!("(cherry picked from commit {hash})\n");
+        assert_eq!(format!("subject\n\n{line}"), append_cherry_picked_from("subject\n", &hash));
+        assert_eq!(format!("Signed-off-by: A <a@b>\n\n{line}"), append_cherry_picked_from("Signed-off-by: A <a@b>", &hash));
+        assert_eq!(
+            format!("subject\n\nbody\n\nSigned-off-by: A <a@b>\n{line}"),
+            append_cherry_picked_from("subject\n\nbody\n\nSigned-off-by: A <a@b>\n", &hash),
+        );
+        assert_eq!(format!("subject\n\nnot a: trailer\n\n{line}"), append_cherry_picked_from("subject\n\nnot a: trailer\n", &hash));
+    }
+}
@@ -0,0 +1,939 @@
+use std::env;
+use std::io;
+use std::io::{IsTerminal, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use anyhow::{bail, Context};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{ACCEPT, CONTENT_ENCODING, CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{StatusCode, Url};
+use crate::config::Config;
+use crate::credential::Credential;
+use crate::pkt_line::{Packet, PacketReader, PacketWriter, RemoteMessages, text_line};
+use crate::receive_pack::{encode_push_request, PushAdvertisement, PushReport, ReceivePackOptions, RefUpdate};
+use crate::upload_pack::{AdvertisedRef, RefAdvertisement, UploadPackOptions, UploadPackResult};
+
+pub(crate) const AGENT: &str = concat!("git/", env!("CARGO_PKG_VERSION"));
+
+const PROTOCOL_HEADER: &str = "Git-Protocol";
+/// the name of the first ref of a v0 advertisement without refs, which only carries the capabilities
+pub(crate) const CAPABILITIES_REF: &str = "capabilities^{}";
+pub(crate) const PEELED_SUFFIX: &str = "^{}";
+pub(crate) const OBJECT_FORMAT: &str = "sha1";
+/// the port a git daemon listens on when the url has none
+pub(crate) const DAEMON_PORT: u16 = 9418;
+/// request bodies longer than this are compressed
+const GZIP_THRESHOLD: usize = 1024;
+/// how many haves are sent in each round of the negotiation
+const HAVES_PER_ROUND: usize = 32;
+
+/// The program a request runs on the server
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Service {
+    /// sends packs to fetches
+    UploadPack,
+    /// receives the packs of pushes
+    ReceivePack,
+}
+impl Service {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UploadPack => "git-upload-pack",
+            Self::ReceivePack => "git-receive-pack",
+        }
+    }
+
+    /// The content type of the ref advertisement, the request or the result, like `application/x-git-upload-pack-request`
+    pub fn content_type(self, kind: &str) -> String {
+        format!("application/x-{}-{kind}", self.name())
+    }
+}
+
+/// The version of the wire protocol, v1 is v0 with a version line in front
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ProtocolVersion {
+    V0,
+    V1,
+    V2,
+}
+impl ProtocolVersion {
+    /// The version protocol.version asks for, 2 by default
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        match config.get("protocol.version") {
+            Some("0") => Ok(Self::V0),
+            Some("1") => Ok(Self::V1),
+            Some("2") | None => Ok(Self::V2),
+            Some(version) => bail!("unknown value for config 'protocol.version': {version}"),
+        }
+    }
+
+    /// The version a client asks a server for, with parameters like `version=2` separated by colons,
+    /// the way GIT_PROTOCOL and the Git-Protocol header pass them. The highest one wins, v0 by default
+    pub fn requested(parameters: &str) -> Self {
+        parameters.split(':').fold(Self::V0, |version, parameter| match parameter {
+            "version=2" => Self::V2,
+            "version=1" if version == Self::V0 => Self::V1,
+            _ => version,
+        })
+    }
+
+    /// The value of the Git-Protocol header that asks for the version
+    fn header(self) -> Option<&'static str> {
+        match self {
+            Self::V0 => None,
+            Self::V1 => Some("version=1"),
+            Self::V2 => Some("version=2"),
+        }
+    }
+}
+
+/// What the server answered to one request of the negotiation
+struct NegotiationRound {
+    /// the haves the server has as well
+    common: Vec<String>,
+    /// the server has enough common commits to send the pack
+    ready: bool,
+    /// the pack, once the negotiation is done
+    result: Option<UploadPackResult>,
+}
+
+/// How the requests of a smart remote reach upload-pack or receive-pack
+enum Connection {
+    /// each request is sent over http(s) and answered by a new run of the service
+    Http(Box<HttpConnection>),
+    /// a git:// connection to a daemon, which runs the service once to answer all requests
+    Daemon(Option<TcpStream>),
+    /// the service of this program run on a repository on this machine, which talks over its standard input and output
+    Local {
+        git_dir: PathBuf,
+        child: Option<Child>,
+    },
+}
+
+struct HttpConnection {
+    client: Client,
+    config: Config,
+    extra_headers: HeaderMap,
+    credential: Credential,
+    /// the credential was filled in after the server asked for one
+    credential_filled: bool,
+    credential_approved: bool,
+}
+
+/// A repository served by the smart protocol, over http(s), git:// or the pipes of a local service
+pub(crate) struct SmartRemote {
+    /// the url without credentials and without a trailing slash
+    url: String,
+    service: Service,
+    connection: Connection,
+    version: ProtocolVersion,
+    /// the capabilities of the first ref in v0 and v1, the capability advertisement in v2
+    capabilities: Vec<String>,
+    /// the refs of the discovery in v0 and v1, v2 lists them with ls-refs
+    advertisement: RefAdvertisement,
+    discovered: bool,
+}
+impl SmartRemote {
+    /// The repository at the url, nothing is sent before its refs are listed
+    pub fn new(url: &str, service: Service, config: &Config) -> anyhow::Result<Self> {
+        let mut parsed = Url::parse(url).context(format!("invalid url '{url}'"))?;
+        let connection = match parsed.scheme() {
+            "git" => Connection::Daemon(None),
+            _ => {
+                let credential = Credential::from_url(&parsed);
+                let _ = parsed.set_username("");
+                let _ = parsed.set_password(None);
+                let client = Client::builder().user_agent(AGENT).build().context("Failed to create the http client")?;
+                Connection::Http(Box::new(HttpConnection {
+                    client,
+                    config: config.clone(),
+                    extra_headers: extra_headers(config)?,
+                    credential,
+                    credential_filled: false,
+                    credential_approved: false,
+                }))
+            },
+        };
+        Self::with_connection(parsed.as_str().trim_end_matches('/'), service, connection, config)
+    }
+
+    /// The repository of the git dir on this machine, served by a child process like git does for local urls
+    pub fn local(git_dir: &Path, url: &str, service: Service, config: &Config) -> anyhow::Result<Self> {
+        Self::with_connection(url, service, Connection::Local { git_dir: git_dir.to_path_buf(), child: None }, config)
+    }
+
+    fn with_connection(url: &str, service: Service, connection: Connection, config: &Config) -> anyhow::Result<Self> {
+        // pushing only speaks v0 and v1
+        let version = match (service, ProtocolVersion::from_config(config)?) {
+            (Service::ReceivePack, ProtocolVersion::V2) => ProtocolVersion::V0,
+            (_, version) => version,
+        };
+        Ok(Self {
+            url: url.to_string(),
+            service,
+            connection,
+            version,
+            capabilities: vec![],
+            advertisement: RefAdvertisement::default(),
+            discovered: false,
+        })
+    }
+
+    /// Asks for the capabilities and, before protocol v2, the refs of the repository
+    fn discover(&mut self) -> anyhow::Result<()> {
+        if self.discovered {
+            return Ok(());
+        }
+        let service = self.service.name();
+        let protocol = self.version.header();
+        let body = match &mut self.connection {
+            Connection::Http(http) => {
+                let discovery_url = format!("{}/info/refs?service={service}", self.url);
+                let response = http.send(&self.url, |client| {
+                    let request = client.get(&discovery_url).header("Pragma", "no-cache");
+                    match protocol {
+                        Some(protocol) => request.header(PROTOCOL_HEADER, protocol),
+                        None => request,
+                    }
+                })?;
+                if content_type(&response) != self.service.content_type("advertisement") {
+                    bail!("dumb http transport is not supported: '{}/'", self.url);
+                }
+                response.bytes().context(format!("unable to access '{}/'", self.url))?.to_vec()
+            },
+            Connection::Daemon(stream) => {
+                let (connected, advertisement) = connect_daemon(&self.url, self.service, protocol)?;
+                *stream = Some(connected);
+                advertisement
+            },
+            Connection::Local { git_dir, child } => {
+                let (spawned, advertisement) = spawn_service(git_dir, self.service, protocol)?;
+                *child = Some(spawned);
+                advertisement
+            },
+        };
+        let mut reader = PacketReader::new(body.as_slice());
+        let mut packet = reader.read_packet()?;
+        // the service line is only sent before v2
+        if packet.as_ref().and_then(Packet::text).is_some_and(|x| x == format!("# service={service}")) {
+            if reader.read_packet()? != Some(Packet::Flush) {
+                bail!("invalid server response; expected flush after service line");
+            }
+            packet = reader.read_packet()?;
+        }
+        match packet.as_ref().and_then(Packet::text).as_deref() {
+            Some("version 2") => {
+                self.version = ProtocolVersion::V2;
+                let (lines, _) = reader.read_until_special()?;
+                self.capabilities = lines.iter().map(|x| text_line(x)).collect();
+            },
+            Some("version 1") => {
+                self.version = ProtocolVersion::V1;
+                let packet = reader.read_packet()?;
+                self.parse_v0_refs(packet, &mut reader)?;
+            },
+            _ => {
+                self.version = ProtocolVersion::V0;
+                self.parse_v0_refs(packet, &mut reader)?;
+            },
+        }
+        self.discovered = true;
+        Ok(())
+    }
+
+    /// Reads the refs of a v0 advertisement, the first one carries the capabilities after a NUL
+    fn parse_v0_refs(&mut self, mut packet: Option<Packet>, reader: &mut PacketReader<&[u8]>) -> anyhow::Result<()> {
+        let mut refs: Vec<AdvertisedRef> = vec![];
+        let mut first = true;
+        while let Some(Packet::Data(data)) = &packet {
+            let mut line = text_line(data);
+            if first {
+                if let Some((ref_line, capabilities)) = line.clone().split_once('\0') {
+                    self.capabilities = capabilities.split_whitespace().map(str::to_string).collect();
+                    line = ref_line.to_string();
+                }
+                first = false;
+            }
+            if !line.starts_with("shallow ") {
+                let (hash, name) = line.split_once(' ').context(format!("protocol error: unexpected '{line}'"))?;
+                match name.strip_suffix(PEELED_SUFFIX) {
+                    _ if name == CAPABILITIES_REF => {},
+                    Some(peeled_name) => {
+                        if let Some(peeled_ref) = refs.last_mut().filter(|x| x.name == peeled_name) {
+                            peeled_ref.peeled = Some(hash.to_string());
+                        }
+                    },
+                    None => refs.push(AdvertisedRef { name: name.to_string(), hash: hash.to_string(), peeled: None }),
+                }
+            }
+            packet = reader.read_packet()?;
+        }
+        let head_target = self.capabilities.iter()
+            .find_map(|x| x.strip_prefix("symref=HEAD:"))
+            .map(str::to_string);
+        self.advertisement = RefAdvertisement { refs, head_target };
+        Ok(())
+    }
+
+    fn has_capability(&self, name: &str) -> bool {
+        self.capability(name).is_some()
+    }
+
+    /// The value of a capability, empty for a capability without one
+    fn capability(&self, name: &str) -> Option<&str> {
+        self.capabilities.iter().find_map(|x| match x.split_once('=') {
+            Some((key, value)) if key == name => Some(value),
+            None if x == name => Some(""),
+            _ => None,
+        })
+    }
+
+    /// The refs of the repository whose names start with one of the prefixes, all of them without prefixes
+    pub fn list_refs(&mut self, prefixes: &[&str]) -> anyhow::Result<RefAdvertisement> {
+        self.discover()?;
+        if self.version != ProtocolVersion::V2 {
+            let mut advertisement = self.advertisement.clone();
+            advertisement.refs.retain(|x| prefixes.is_empty() || prefixes.iter().any(|prefix| x.name.starts_with(prefix)));
+            return Ok(advertisement);
+        }
+        let mut arguments = vec!["peel".to_string(), "symrefs".to_string()];
+        if self.capability("ls-refs").is_some_and(|x| x.split(' ').any(|x| x == "unborn")) {
+            arguments.push("unborn".to_string());
+        }
+        arguments.extend(prefixes.iter().map(|x| format!("ref-prefix {x}")));
+        let request = self.command_request("ls-refs", &arguments)?;
+        let response = self.post(request)?;
+        let mut reader = PacketReader::new(response);
+        let (lines, _) = reader.read_until_special()?;
+        let mut advertisement = RefAdvertisement::default();
+        for line in lines {
+            let line = text_line(&line);
+            let mut parts = line.split(' ');
+            let (Some(hash), Some(name)) = (parts.next(), parts.next()) else {
+                bail!("invalid ls-refs response: {line}");
+            };
+            let mut peeled = None;
+            for attribute in parts {
+                if let Some(target) = attribute.strip_prefix("symref-target:").filter(|_| name == "HEAD") {
+                    advertisement.head_target = Some(target.to_string());
+                }
+                if let Some(hash) = attribute.strip_prefix("peeled:") {
+                    peeled = Some(hash.to_string());
+                }
+            }
+            // an unborn HEAD only tells which branch it is on
+            if hash != "unborn" {
+                advertisement.refs.push(AdvertisedRef { name: name.to_string(), hash: hash.to_string(), peeled });
+            }
+        }
+        Ok(advertisement)
+    }
+
+    /// Fetches the pack of the objects reachable from the wants that are not reachable from the haves.
+    /// The haves are offered in rounds until the server is ready or there are no more of them
+    pub fn fetch(&mut self, wants: &[String], haves: &[String], options: &UploadPackOptions) -> anyhow::Result<UploadPackResult> {
+        self.discover()?;
+        let mut common = vec![];
+        let mut pending = haves;
+        let mut ready = false;
+        let mut first_round = true;
+        loop {
+            let count = match ready {
+                true => 0,
+                false => pending.len().min(HAVES_PER_ROUND),
+            };
+            let (batch, rest) = pending.split_at(count);
+            pending = rest;
+            let done = ready || batch.is_empty();
+            let round = match self.version {
+                ProtocolVersion::V2 => self.fetch_round_v2(wants, &common, batch, done, options)?,
+                _ => self.fetch_round_v0(wants, &common, batch, done, first_round, options)?,
+            };
+            first_round = false;
+            if let Some(result) = round.result {
+                return Ok(result);
+            }
+            if done {
+                bail!("protocol error: the server did not send a pack");
+            }
+            common.extend(round.common);
+            ready = round.ready;
+        }
+    }
+
+    fn fetch_round_v2(&mut self, wants: &[String], common: &[String], haves: &[String], done: bool, options: &UploadPackOptions) -> anyhow::Result<NegotiationRound> {
+        let mut arguments = vec!["ofs-delta".to_string()];
+        if !io::stderr().is_terminal() {
+            arguments.push("no-progress".to_string());
+        }
+        if options.include_tag {
+            arguments.push("include-tag".to_string());
+        }
+        if let Some(depth) = options.depth {
+            arguments.push(format!("deepen {depth}"));
+        }
+        arguments.extend(wants.iter().map(|x| format!("want {x}")));
+        arguments.extend(common.iter().chain(haves).map(|x| format!("have {x}")));
+        if done {
+            arguments.push("done".to_string());
+        }
+        let request = self.command_request("fetch", &arguments)?;
+        let response = self.post(request)?;
+        let mut reader = PacketReader::new(response);
+        let mut round = NegotiationRound { common: vec![], ready: false, result: None };
+        let mut shallow = vec![];
+        loop {
+            let section = reader.read_packet()?.and_then(|x| x.text()).context("the remote end hung up unexpectedly")?;
+            if section == "packfile" {
+                let mut pack = vec![];
+                reader.sideband(RemoteMessages::new(io::stderr())).read_to_end(&mut pack).context("fetch-pack: invalid index-pack output")?;
+                round.result = Some(UploadPackResult { pack, shallow });
+                return Ok(round);
+            }
+            let (lines, end) = reader.read_until_special()?;
+            let lines = lines.iter().map(|x| text_line(x));
+            match section.as_str() {
+                "acknowledgments" => {
+                    for line in lines {
+                        match line.strip_prefix("ACK ") {
+                            Some(hash) => round.common.push(hash.to_string()),
+                            None => round.ready |= line == "ready",
+                        }
+                    }
+                },
+                "shallow-info" => shallow.extend(lines.filter_map(|x| x.strip_prefix("shallow ").map(str::to_string))),
+                "wanted-refs" | "packfile-uris" => {},
+                _ => bail!("expected packfile, got '{section}'"),
+            }
+            if end != Packet::Delim {
+                return Ok(round);
+            }
+        }
+    }
+
+    /// A round of the v0 negotiation. Over http each round repeats the wants and the common commits,
+    /// a daemon remembers them from the rounds before on the same connection
+    fn fetch_round_v0(
+        &mut self,
+        wants: &[String],
+        common: &[String],
+        haves: &[String],
+        done: bool,
+        first_round: bool,
+        options: &UploadPackOptions,
+    ) -> anyhow::Result<NegotiationRound> {
+        let mut capabilities = vec![];
+        for alternatives in [&["multi_ack_detailed", "multi_ack"][..], &["side-band-64k", "side-band"], &["ofs-delta"]] {
+            capabilities.extend(alternatives.iter().find(|x| self.has_capability(x)).copied());
+        }
+        // like git, progress is only shown on a terminal
+        if !io::stderr().is_terminal() && self.has_capability("no-progress") {
+            capabilities.push("no-progress");
+        }
+        if options.include_tag && self.has_capability("include-tag") {
+            capabilities.push("include-tag");
+        }
+        if options.depth.is_some() {
+            if !self.has_capability("shallow") {
+                bail!("Server does not support shallow clients");
+            }
+            capabilities.push("shallow");
+        }
+        let agent = format!("agent={AGENT}");
+        if self.has_capability("agent") {
+            capabilities.push(&agent);
+        }
+        let sideband = capabilities.iter().any(|x| x.starts_with("side-band"));
+        let stateless = matches!(self.connection, Connection::Http(_));
+        let send_wants = stateless || first_round;
+
+        let mut writer = PacketWriter::new(vec![]);
+        if send_wants {
+            for (position, want) in wants.iter().enumerate() {
+                match position {
+                    0 => writer.write_line(&format!("want {want} {}", capabilities.join(" ")))?,
+                    _ => writer.write_line(&format!("want {want}"))?,
+                }
+            }
+            if let Some(depth) = options.depth {
+                writer.write_line(&format!("deepen {depth}"))?;
+            }
+            writer.write_flush()?;
+        }
+        for have in common.iter().filter(|_| stateless).chain(haves) {
+            writer.write_line(&format!("have {have}"))?;
+        }
+        match done {
+            true => writer.write_line("done")?,
+            false => writer.write_flush()?,
+        }
+        let response = self.post(writer.into_inner())?;
+        let mut reader = PacketReader::new(response);
+        let mut shallow = vec![];
+        if options.depth.is_some() && send_wants {
+            let (lines, _) = reader.read_until_special()?;
+            shallow.extend(lines.iter().filter_map(|x| text_line(x).strip_prefix("shallow ").map(str::to_string)));
+        }
+        let mut round = NegotiationRound { common: vec![], ready: false, result: None };
+        loop {
+            let line = reader.read_packet()?.and_then(|x| x.text()).context("the remote end hung up unexpectedly")?;
+            let finished = match line.strip_prefix("ACK ").map(|x| x.split_once(' ').unwrap_or((x, ""))) {
+                Some((hash, status)) => {
+                    round.common.push(hash.to_string());
+                    round.ready |= status == "ready";
+                    // only the last ACK after done has no status
+                    status.is_empty()
+                },
+                None if line == "NAK" => true,
+                None => match line.strip_prefix("ERR ") {
+                    Some(message) => bail!("remote error: {message}"),
+                    None => bail!("expected ACK/NAK, got '{line}'"),
+                },
+            };
+            if finished {
+                break;
+            }
+        }
+        if done {
+            let mut pack = vec![];
+            match sideband {
+                true => reader.sideband(RemoteMessages::new(io::stderr())).read_to_end(&mut pack),
+                false => reader.into_inner().read_to_end(&mut pack),
+            }.context("fetch-pack: invalid index-pack output")?;
+            round.result = Some(UploadPackResult { pack, shallow });
+        }
+        Ok(round)
+    }
+
+    /// The refs receive-pack advertises and its capabilities
+    pub fn push_advertisement(&mut self) -> anyhow::Result<PushAdvertisement> {
+        self.discover()?;
+        let refs = self.advertisement.refs.clone();
+        Ok(PushAdvertisement { refs, capabilities: self.capabilities.clone() })
+    }
+
+    /// Sends the ref updates and the pack of their objects to receive-pack and reads its report
+    pub fn push(&mut self, updates: &[RefUpdate], pack: Option<&[u8]>, options: &ReceivePackOptions) -> anyhow::Result<PushReport> {
+        self.discover()?;
+        let mut capabilities = vec!["report-status"];
+        capabilities.extend(["side-band-64k", "quiet"].iter().filter(|x| self.has_capability(x)));
+        if options.atomic {
+            capabilities.push("atomic");
+        }
+        if !options.push_options.is_empty() {
+            capabilities.push("push-options");
+        }
+        let agent = format!("agent={AGENT}");
+        if self.has_capability("agent") {
+            capabilities.push(&agent);
+        }
+        let sideband = capabilities.contains(&"side-band-64k");
+        let request = encode_push_request(updates, &capabilities, &options.push_options, pack)?;
+        let response = self.post(request)?;
+        let mut reader = PacketReader::new(response);
+        if !sideband {
+            return PushReport::read(&mut reader);
+        }
+        let mut report = vec![];
+        reader.sideband(RemoteMessages::new(io::stderr())).read_to_end(&mut report).context("the remote end hung up unexpectedly")?;
+        PushReport::read(&mut PacketReader::new(report.as_slice()))
+    }
+
+    /// A v2 command with its arguments, after the capabilities the client uses
+    fn command_request(&self, command: &str, arguments: &[String]) -> anyhow::Result<Vec<u8>> {
+        let mut writer = PacketWriter::new(vec![]);
+        writer.write_line(&format!("command={command}"))?;
+        if self.has_capability("agent") {
+            writer.write_line(&format!("agent={AGENT}"))?;
+        }
+        if self.has_capability("object-format") {
+            writer.write_line(&format!("object-format={OBJECT_FORMAT}"))?;
+        }
+        writer.write_delim()?;
+        for argument in arguments {
+            writer.write_line(argument)?;
+        }
+        writer.write_flush()?;
+        Ok(writer.into_inner())
+    }
+
+    /// Sends a request to the service and returns its answer. Over http the request is compressed if it is long
+    fn post(&mut self, body: Vec<u8>) -> anyhow::Result<Box<dyn Read + '_>> {
+        let http = match &mut self.connection {
+            Connection::Http(http) => http,
+            Connection::Daemon(stream) => {
+                let stream = stream.as_ref().context("the remote end hung up unexpectedly")?;
+                (&mut &*stream).write_all(&body).context("the remote end hung up unexpectedly")?;
+                return Ok(Box::new(stream));
+            },
+            Connection::Local { child, .. } => {
+                let child = child.as_mut().context("the remote end hung up unexpectedly")?;
+                let (Some(input), Some(output)) = (child.stdin.as_mut(), child.stdout.as_mut()) else {
+                    bail!("the remote end hung up unexpectedly");
+                };
+                input.write_all(&body).context("the remote end hung up unexpectedly")?;
+                return Ok(Box::new(output));
+            },
+        };
+        let service_url = format!("{}/{}", self.url, self.service.name());
+        let (request_type, result_type) = (self.service.content_type("request"), self.service.content_type("result"));
+        let (body, gzip) = match body.len() > GZIP_THRESHOLD {
+            true => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(&body).context("Failed to compress the request")?;
+                (encoder.finish().context("Failed to compress the request")?, true)
+            },
+            false => (body, false),
+        };
+        let version = self.version;
+        let response = http.send(&self.url, |client| {
+            let mut request = client.post(&service_url)
+                .header(CONTENT_TYPE, &request_type)
+                .header(ACCEPT, &result_type)
+                .body(body.clone());
+            if gzip {
+                request = request.header(CONTENT_ENCODING, "gzip");
+            }
+            match version.header() {
+                Some(protocol) => request.header(PROTOCOL_HEADER, protocol),
+                None => request,
+            }
+        })?;
+        if content_type(&response) != result_type {
+            bail!("invalid content-type: '{}'", content_type(&response));
+        }
+        Ok(Box::new(response))
+    }
+}
+
+impl HttpConnection {
+    /// Sends a request with the extra headers and the credential, which is filled in and sent again
+    /// when the server asks for one
+    fn send(&mut self, url: &str, request: impl Fn(&Client) -> RequestBuilder) -> anyhow::Result<Response> {
+        loop {
+            let mut builder = request(&self.client).headers(self.extra_headers.clone());
+            if let Some(username) = &self.credential.username {
+                builder = builder.basic_auth(username, self.credential.password.as_ref());
+            }
+            let response = builder.send().context(format!("unable to access '{url}/'"))?;
+            match response.status() {
+                StatusCode::UNAUTHORIZED if !self.credential_filled => {
+                    self.credential.fill(&self.config)?;
+                    self.credential_filled = true;
+                },
+                StatusCode::UNAUTHORIZED => {
+                    self.credential.reject(&self.config)?;
+                    bail!("Authentication failed for '{url}/'");
+                },
+                StatusCode::NOT_FOUND => bail!("repository '{url}/' not found"),
+                status if !status.is_success() => {
+                    bail!("unable to access '{url}/': The requested URL returned error: {}", status.as_u16());
+                },
+                _ => {
+                    if self.credential_filled && !self.credential_approved {
+                        self.credential.approve(&self.config)?;
+                        self.credential_approved = true;
+                    }
+                    return Ok(response);
+                },
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    /// Tells a daemon or a local service that no more requests follow, it may have hung up already
+    fn drop(&mut self) {
+        match self {
+            Connection::Daemon(Some(stream)) => {
+                let _ = stream.write_all(b"0000");
+            },
+            Connection::Local { child: Some(child), .. } => {
+                if let Some(mut input) = child.stdin.take() {
+                    let _ = input.write_all(b"0000");
+                }
+                let _ = child.wait();
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Connects to the daemon of a git:// url and asks it to run the service on the repository of its path.
+/// Returns the connection and the ref advertisement
+fn connect_daemon(url: &str, service: Service, protocol: Option<&str>) -> anyhow::Result<(TcpStream, Vec<u8>)> {
+    let parsed = Url::parse(url).context(format!("invalid url '{url}'"))?;
+    let host = parsed.host_str().context(format!("no host in url '{url}'"))?;
+    let port = parsed.port().unwrap_or(DAEMON_PORT);
+    let stream = TcpStream::connect((host, port)).context(format!("unable to connect to {host}"))?;
+    // like git, the host is sent with the port only when the url has one
+    let host = match parsed.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    let mut request = format!("{} {}\0host={host}\0", service.name(), parsed.path());
+    if let Some(protocol) = protocol {
+        request.push_str(&format!("\0{protocol}\0"));
+    }
+    PacketWriter::new(&stream).write_data(request.as_bytes())?;
+    let advertisement = read_advertisement(&stream)?;
+    Ok((stream, advertisement))
+}
+
+/// Runs upload-pack or receive-pack of this program on the git dir. Returns the child and the ref advertisement
+fn spawn_service(git_dir: &Path, service: Service, protocol: Option<&str>) -> anyhow::Result<(Child, Vec<u8>)> {
+    let program = env::current_exe().context("Failed to find the program of the service")?;
+    let name = service.name().trim_start_matches("git-");
+    let mut command = Command::new(program);
+    command.arg(name).arg(git_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .env_remove("GIT_PROTOCOL");
+    if let Some(protocol) = protocol {
+        command.env("GIT_PROTOCOL", protocol);
+    }
+    let mut child = command.spawn().context(format!("cannot run {name}"))?;
+    let output = child.stdout.as_mut().context("the remote end hung up unexpectedly")?;
+    // on errors the child is dropped with its input, so it ends
+    let advertisement = read_advertisement(output)?;
+    Ok((child, advertisement))
+}
+
+/// Reads the packets of a ref advertisement over a connection, which ends at the first flush in all versions
+fn read_advertisement(input: impl Read) -> anyhow::Result<Vec<u8>> {
+    let mut reader = PacketReader::new(input);
+    let mut writer = PacketWriter::new(vec![]);
+    loop {
+        match reader.read_packet()? {
+            Some(Packet::Data(data)) => {
+                if let Some(message) = text_line(&data).strip_prefix("ERR ") {
+                    bail!("remote error: {message}");
+                }
+                writer.write_data(&data)?;
+            },
+            Some(packet) => {
+                writer.write_packet(&packet)?;
+                return Ok(writer.into_inner());
+            },
+            None => bail!("the remote end hung up unexpectedly"),
+        }
+    }
+}
+
+/// The headers of http.extraHeader, an empty value drops the headers before it
+fn extra_headers(config: &Config) -> anyhow::Result<HeaderMap> {
+    let values = config.get_all("http.extraHeader");
+    let start = values.iter().rposition(|x| x.is_empty()).map_or(0, |x| x + 1);
+    let mut headers = HeaderMap::new();
+    for value in &values[start..] {
+        let (name, header_value) = value.split_once(':').context(format!("invalid http.extraHeader: {value}"))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).context(format!("invalid http.extraHeader: {value}"))?;
+        let header_value = HeaderValue::from_str(header_value.trim()).context(format!("invalid http.extraHeader: {value}"))?;
+        headers.append(name, header_value);
+    }
+    Ok(headers)
+}
+
+fn content_type(response: &Response) -> String {
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|x| x.to_str().ok()).unwrap_or_default();
+    content_type.split(';').next().unwrap_or_default().trim().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::slice;
+    use std::thread;
+    use flate2::read::GzDecoder;
+    use crate::pkt_line::{Band, MAX_PACKET_LEN};
+
+    const ADVERTISEMENT_TYPE: &str = "application/x-git-upload-pack-advertisement";
+    const RESULT_TYPE: &str = "application/x-git-upload-pack-result";
+
+    /// A request as the server got it: the request line and headers, lowercased, and the body
+    struct ReceivedRequest {
+        head: String,
+        body: Vec<u8>,
+    }
+
+    fn packets(packets: &[Packet]) -> Vec<u8> {
+        packets.iter().flat_map(|x| x.encode().unwrap()).collect()
+    }
+
+    fn line(line: &str) -> Packet {
+        Packet::Data(format!("{line}\n").into_bytes())
+    }
+
+    /// Serves the responses, one per connection, and returns the requests once all were answered
+    fn serve(responses: Vec<(u16, &'static str, Vec<u8>)>) -> (String, thread::JoinHandle<Vec<ReceivedRequest>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/repo.git", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for (status, content_type, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut head).unwrap();
+                }
+                let head = head.to_ascii_lowercase();
+                let length = head.lines()
+                    .find_map(|x| x.strip_prefix("content-length: "))
+                    .map_or(0, |x| x.parse().unwrap());
+                let mut request_body = vec![0; length];
+                reader.read_exact(&mut request_body).unwrap();
+                requests.push(ReceivedRequest { head, body: request_body });
+                let mut stream = reader.into_inner();
+                write!(stream, "HTTP/1.1 {status} X\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_requested_version() {
+        assert_eq!(ProtocolVersion::V0, ProtocolVersion::requested(""));
+        assert_eq!(ProtocolVersion::V1, ProtocolVersion::requested("version=1"));
+        assert_eq!(ProtocolVersion::V2, ProtocolVersion::requested("version=2:version=1"));
+        assert_eq!(ProtocolVersion::V2, ProtocolVersion::requested("object-format=sha1:version=2"));
+    }
+
+    #[test]
+    fn test_fetch_v2() -> anyhow::Result<()> {
+        let (commit, tag, have) = ("1".repeat(40), "2".repeat(40), "3".repeat(40));
+        let advertisement = packets(&[line("version 2"), line("agent=git/2.39.5"), line("ls-refs=unborn"), line("fetch=shallow"), Packet::Flush]);
+        let refs = packets(&[
+            line(&format!("{commit} HEAD symref-target:refs/heads/main")),
+            line(&format!("{commit} refs/heads/main")),
+            line(&format!("{tag} refs/tags/v1 peeled:{commit}")),
+            Packet::Flush,
+        ]);
+        let mut pack = packets(&[line("acknowledgments"), line(&format!("ACK {have}")), line("ready"), Packet::Delim]);
+        pack.extend(packets(&[line("shallow-info"), line(&format!("shallow {commit}")), Packet::Delim, line("packfile")]));
+        let mut writer = PacketWriter::new(pack);
+        writer.write_band(Band::Data, b"PACK", MAX_PACKET_LEN)?;
+        writer.write_band(Band::Data, b" data", MAX_PACKET_LEN)?;
+        writer.write_flush()?;
+        let pack = writer.into_inner();
+        let (url, server) = serve(vec![(200, ADVERTISEMENT_TYPE, advertisement), (200, RESULT_TYPE, refs), (200, RESULT_TYPE, pack)]);
+
+        let mut remote = SmartRemote::new(&url, Service::UploadPack, &Config::default())?;
+        let listed = remote.list_refs(&["HEAD", "refs/heads/"])?;
+        assert_eq!(Some("refs/heads/main"), listed.head_target.as_deref());
+        assert_eq!(3, listed.refs.len());
+        assert_eq!(Some(commit.clone()), listed.refs[2].peeled);
+        let result = remote.fetch(slice::from_ref(&commit), slice::from_ref(&have), &UploadPackOptions { depth: Some(1), ..UploadPackOptions::default() })?;
+        assert_eq!(b"PACK data".to_vec(), result.pack);
+        assert_eq!(vec![commit.clone()], result.shallow);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].head.starts_with("get /repo.git/info/refs?service=git-upload-pack "));
+        assert!(requests[0].head.contains("git-protocol: version=2"));
+        let ls_refs = String::from_utf8(requests[1].body.clone())?;
+        assert!(ls_refs.starts_with("0014command=ls-refs\n") && ls_refs.contains("unborn\n") && ls_refs.contains("ref-prefix refs/heads/\n"));
+        let fetch = String::from_utf8(requests[2].body.clone())?;
+        assert!(fetch.contains("deepen 1\n") && fetch.contains(&format!("want {commit}\n")) && fetch.contains(&format!("have {have}\n")));
+        assert!(!fetch.contains("done"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_v0_with_credentials() -> anyhow::Result<()> {
+        let (commit, tag) = ("1".repeat(40), "2".repeat(40));
+        let advertisement = packets(&[
+            line("# service=git-upload-pack"),
+            Packet::Flush,
+            Packet::Data(format!("{commit} HEAD\0multi_ack_detailed side-band-64k ofs-delta symref=HEAD:refs/heads/main agent=git/2.39.5\n").into_bytes()),
+            line(&format!("{commit} refs/heads/main")),
+            line(&format!("{tag} refs/tags/v1")),
+            line(&format!("{commit} refs/tags/v1^{{}}")),
+            Packet::Flush,
+        ]);
+        let mut writer = PacketWriter::new(packets(&[line("NAK")]));
+        writer.write_band(Band::Progress, b"Total 1\n", MAX_PACKET_LEN)?;
+        writer.write_band(Band::Data, b"PACK", MAX_PACKET_LEN)?;
+        writer.write_flush()?;
+        let pack = writer.into_inner();
+        let (url, server) = serve(vec![(401, "text/plain", vec![]), (200, ADVERTISEMENT_TYPE, advertisement), (200, RESULT_TYPE, pack)]);
+
+        let mut config = Config::default();
+        config.parse("[protocol]\n\tversion = 0\n[credential]\n\thelper = \"!f() { echo username=me; echo password=secret; }; f\"\n")?;
+        config.parse("[http]\n\textraHeader = X-Trace: 1\n")?;
+        let mut remote = SmartRemote::new(&url, Service::UploadPack, &config)?;
+        let listed = remote.list_refs(&[])?;
+        assert_eq!(Some("refs/heads/main"), listed.head_target.as_deref());
+        assert_eq!(vec!["HEAD", "refs/heads/main", "refs/tags/v1"], listed.refs.iter().map(|x| x.name.as_str()).collect::<Vec<_>>());
+        assert_eq!(Some(commit.clone()), listed.refs[2].peeled);
+        // enough wants to make the request long enough to compress
+        let wants = (0..30).map(|x| format!("{x:040}")).collect::<Vec<_>>();
+        let result = remote.fetch(&wants, &[], &UploadPackOptions::default())?;
+        assert_eq!(b"PACK".to_vec(), result.pack);
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].head.contains("authorization"));
+        assert!(requests[1].head.contains("authorization: basic bwu6c2vjcmv0"));
+        assert!(requests[2].head.contains("x-trace: 1") && requests[2].head.contains("content-encoding: gzip"));
+        let mut fetch = String::new();
+        GzDecoder::new(requests[2].body.as_slice()).read_to_string(&mut fetch)?;
+        assert!(fetch[4..].starts_with(&format!("want {} multi_ack_detailed side-band-64k ofs-delta agent=", wants[0])));
+        assert!(fetch.ends_with("00000009done\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_push() -> anyhow::Result<()> {
+        let (old, new) = ("1".repeat(40), "2".repeat(40));
+        let advertisement = packets(&[
+            line("# service=git-receive-pack"),
+            Packet::Flush,
+            Packet::Data(format!("{old} refs/heads/main\0report-status delete-refs side-band-64k quiet atomic ofs-delta push-options agent=git/2.39.5\n").into_bytes()),
+            line(&format!("{old} refs/heads/old")),
+            Packet::Flush,
+        ]);
+        let mut report = PacketWriter::new(vec![]);
+        report.write_line("unpack ok")?;
+        report.write_line("ok refs/heads/main")?;
+        report.write_line("ng refs/heads/old deletion prohibited")?;
+        report.write_flush()?;
+        let mut writer = PacketWriter::new(vec![]);
+        writer.write_band(Band::Progress, b"error: denying ref deletion for refs/heads/old\n", MAX_PACKET_LEN)?;
+        writer.write_band(Band::Data, &report.into_inner(), MAX_PACKET_LEN)?;
+        writer.write_flush()?;
+        let result = writer.into_inner();
+        let (url, server) = serve(vec![
+            (200, "application/x-git-receive-pack-advertisement", advertisement),
+            (200, "application/x-git-receive-pack-result", result),
+        ]);
+
+        let mut remote = SmartRemote::new(&url, Service::ReceivePack, &Config::default())?;
+        let listed = remote.push_advertisement()?;
+        assert_eq!(vec!["refs/heads/main", "refs/heads/old"], listed.refs.iter().map(|x| x.name.as_str()).collect::<Vec<_>>());
+        assert!(listed.has_capability("push-options"));
+        let updates = [
+            RefUpdate { name: "refs/heads/main".to_string(), old: Some(old.clone()), new: Some(new.clone()) },
+            RefUpdate { name: "refs/heads/old".to_string(), old: Some(old.clone()), new: None },
+        ];
+        let options = ReceivePackOptions { atomic: false, push_options: vec!["ci.skip".to_string()] };
+        let report = remote.push(&updates, Some(b"PACK"), &options)?;
+        assert_eq!(None, report.unpack_error);
+        assert_eq!(vec![("refs/heads/main".to_string(), None), ("refs/heads/old".to_string(), Some("deletion prohibited".to_string()))], report.refs);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].head.starts_with("get /repo.git/info/refs?service=git-receive-pack "));
+        assert!(!requests[0].head.contains("git-protocol"));
+        assert!(requests[1].head.starts_with("post /repo.git/git-receive-pack "));
+        let push = String::from_utf8(requests[1].body.clone())?;
+        assert!(push[4..].starts_with(&format!("{old} {new} refs/heads/main\0report-status side-band-64k quiet push-options agent=")));
+        assert!(push.contains(&format!("{old} {} refs/heads/old\n0000", "0".repeat(40))));
+        assert!(push.ends_with("000cci.skip\n0000PACK"));
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::slice;
+use anyhow::{bail, Context};
+use crate::{apply_merge_result, merge_options, print_status};
+use crate::checkout::{apply_targets, checkout_file, remove_worktree_file, reset_index};
+use crate::cli::StashPushFlags;
+use crate::commit_object_read::CommitObject;
+use crate::common::{Exit, ObjectType};
+use crate::config::Config;
+use crate::diff::abbreviate;
+use crate::ident::{read_ident_or_default, Role};
+use crate::index::Index;
+use crate::object_write::write_commit;
+use crate::pathspec::Pathspec;
+use crate::refs::{delete_reflog_entry, dwim_ref, Head, HEADS_PREFIX, read_head, read_head_commit, read_ref, update_ref};
+use crate::rev_parse::{peel, resolve_revision};
+use crate::tree_diff::{ChangeStatus, diff_tree_to_index, diff_trees, drop_unchanged_worktree_files, list_index_files, list_tree_files, list_untracked_files};
+use crate::tree_merge::merge_trees;
+use crate::tree_object_write::write_index_tree;
+
+/// the ref of the latest stash entry, its reflog is the list of all entries, the newest one first
+pub(crate) const STASH_REF: &str = "refs/stash";
+
+/// A stash entry: a commit of the working tree whose parents are the commit the changes were based on,
+/// a commit of the index and optionally a commit of the untracked files
+pub(crate) struct Stash {
+    /// the name the entry was given by, like refs/stash@{0}
+    pub revision: String,
+    /// the position of the entry in the list, None if it is a commit that is not in the list
+    pub position: Option<usize>,
+    pub commit: String,
+    pub base: String,
+    pub tree: String,
+    pub base_tree: String,
+    pub index_tree: String,
+    pub untracked_tree: Option<String>,
+}
+impl Stash {
+    /// Finds the entry by its name, the latest one by default. A number is the position of an entry in the list
+    pub fn find(name: Option<&str>) -> anyhow::Result<Self> {
+        let revision = match name {
+            None if read_ref(STASH_REF)?.is_none() => bail!("No stash entries found."),
+            None => format!("{STASH_REF}@{{0}}"),
+            Some(number) if !number.is_empty() && number.bytes().all(|x| x.is_ascii_digit()) => format!("{STASH_REF}@{{{number}}}"),
+            Some(name) => name.to_string(),
+        };
+        let hash = match resolve_revision(&revision) {
+            Ok(hash) => hash,
+            // a position past the end of the list keeps the message that says how long the list is
+            Err(error) if revision.ends_with('}') => bail!("{}", error.root_cause()),
+            Err(_) => bail!("{revision} is not a valid reference"),
+        };
+        let commit = peel(&hash, ObjectType::Commit)
+            .and_then(|x| CommitObject::read(&x))
+            .ok()
+            .context(format!("'{revision}' is not a stash-like commit"))?;
+        let [base, index_commit, rest @ ..] = commit.parents.as_slice() else {
+            bail!("'{revision}' is not a stash-like commit");
+        };
+        let untracked_tree = match rest.first() {
+            Some(untracked_commit) => Some(peel(untracked_commit, ObjectType::Tree)?),
+            None => None,
+        };
+        Ok(Self {
+            position: stash_position(&revision)?,
+            base_tree: peel(base, ObjectType::Tree)?,
+            index_tree: peel(index_commit, ObjectType::Tree)?,
+            base: base.clone(),
+            tree: commit.tree,
+            commit: commit.hash,
+            untracked_tree,
+            revision,
+        })
+    }
+}
+
+/// The position in a name like stash@{1} that selects an entry of the list of stash entries
+fn stash_position(revision: &str) -> anyhow::Result<Option<usize>> {
+    let Some((name, position)) = revision.strip_suffix('}').and_then(|x| x.rsplit_once("@{")) else {
+        return Ok(None);
+    };
+    let is_stash = dwim_ref(name)?.is_some_and(|(ref_name, _)| ref_name == STASH_REF);
+    Ok(position.parse().ok().filter(|_| is_stash))
+}
+
+/// The branch the changes are stashed on, "(no branch)" for a detached HEAD
+pub(crate) fn stash_branch_name(head: &Head) -> &str {
+    match head {
+        Head::Branch(name) => name.strip_prefix(HEADS_PREFIX).unwrap_or(name),
+        Head::Detached(_) => "(no branch)",
+    }
+}
+
+/// How the messages of stash commits describe the commit the changes are based on, like "main: 1234567 subject"
+pub(crate) fn describe_stash_base(head: &Head, commit: &CommitObject) -> String {
+    format!("{}: {} {}", stash_branch_name(head), abbreviate(&commit.hash), commit.subject())
+}
+
+/// Saves the local changes as a new stash entry and reverts them to HEAD, only for the paths matching the pathspec
+/// if one is given. The entry is a commit of the working tree whose parents are HEAD, a commit of the index
+/// and with --include-untracked a commit of the untracked files
+pub(crate) fn stash_push(flags: StashPushFlags, config: &Config) -> anyhow::Result<()> {
+    let mut index = Index::read()?;
+    let Some(head) = read_head_commit()? else {
+        eprintln!("You do not have the initial commit yet");
+        return Err(Exit(1).into());
+    };
+    if !flags.include_untracked {
+        let unmatched = flags.paths
+            .iter()
+            .filter(|pattern| {
+                let single = Pathspec::new(slice::from_ref(pattern));
+                !index.entries.iter().any(|x| single.matches(&x.path))
+            })
+            .collect::<Vec<_>>();
+        if !unmatched.is_empty() {
+            unmatched.iter().for_each(|x| eprintln!("error: pathspec '{x}' did not match any file(s) known to git"));
+            eprintln!("Did you forget to 'git add'?");
+            return Err(Exit(1).into());
+        }
+    }
+    let head_commit = CommitObject::read(&head)?;
+    let pathspec = Pathspec::new(&flags.paths);
+    let staged = diff_tree_to_index(Some(&head_commit.tree), &index, true, &pathspec)?;
+    // like git, files deleted from the index but not from the working tree are stashed as they are in the working tree
+    let unstaged = drop_unchanged_worktree_files(diff_tree_to_index(Some(&head_commit.tree), &index, false, &pathspec)?)?;
+    let untracked = match flags.include_untracked {
+        true => list_untracked_files(&index, &pathspec, false)?,
+        false => vec![],
+    };
+    if staged.is_empty() && unstaged.is_empty() && untracked.is_empty() {
+        if !flags.quiet {
+            println!("No local changes to save");
+        }
+        return Ok(());
+    }
+
+    let author = read_ident_or_default(Role::Author, config)?;
+    let committer = read_ident_or_default(Role::Committer, config)?;
+    let head_ref = read_head()?;
+    let base = describe_stash_base(&head_ref, &head_commit);
+    let index_tree = write_index_tree(&index)?;
+    let index_commit = write_commit(&index_tree, &[&head], &author, &committer, &format!("index on {base}\n"))?;
+    let mut parents = vec![head.as_str(), index_commit.as_str()];
+    let untracked_commit = match untracked.is_empty() {
+        true => None,
+        false => {
+            let mut untracked_index = Index::default();
+            for path in &untracked {
+                untracked_index.add_file(path)?;
+            }
+            let tree = write_index_tree(&untracked_index)?;
+            Some(write_commit(&tree, &[], &author, &committer, &format!("untracked files on {base}\n"))?)
+        },
+    };
+    parents.extend(untracked_commit.as_deref());
+    let mut worktree_index = Index { entries: index.entries.clone(), ..Index::default() };
+    for change in &unstaged {
+        match fs::symlink_metadata(&change.path) {
+            Ok(meta) if !meta.is_dir() => worktree_index.add_file(&change.path)?,
+            _ => worktree_index.remove(&change.path),
+        }
+    }
+    let worktree_tree = write_index_tree(&worktree_index)?;
+    let message = match &flags.message {
+        Some(message) => format!("On {}: {message}", stash_branch_name(&head_ref)),
+        None => format!("WIP on {base}"),
+    };
+    let stash = write_commit(&worktree_tree, &parents, &author, &committer, &message)?;
+    update_ref(STASH_REF, &stash, read_ref(STASH_REF)?.as_deref(), &committer, &message)?;
+    if !flags.quiet {
+        println!("Saved working directory and index state {message}");
+    }
+
+    // the stashed paths go back to HEAD, or to the index with --keep-index
+    for path in &untracked {
+        remove_worktree_file(path)?;
+    }
+    let kept_files = match flags.keep_index {
+        true => list_index_files(&index, &pathspec),
+        false => list_tree_files(Some(&head_commit.tree), &pathspec)?,
+    };
+    let kept_files = kept_files.into_iter().collect::<HashMap<_, _>>();
+    let targets = staged.iter()
+        .chain(&unstaged)
+        .map(|x| (x.path.clone(), kept_files.get(&x.path).cloned()))
+        .collect::<BTreeMap<_, _>>();
+    apply_targets(&mut index, targets.into_iter().collect(), true)?;
+    index.write()
+}
+
+/// Merges the changes of a stash entry into the working tree, the changes are left unstaged except for new files.
+/// With `restore_index` the changes to the index are restored as well. The untracked files of the entry are written
+/// again, unless they already exist. Returns whether it all worked, after showing the status unless `quiet`
+pub(crate) fn stash_apply(stash: &Stash, restore_index: bool, quiet: bool, config: &Config) -> anyhow::Result<bool> {
+    let mut index = Index::read()?;
+    if index.entries.iter().any(|x| x.stage != 0) {
+        bail!("cannot apply a stash in the middle of a merge");
+    }
+    let current_tree = write_index_tree(&index)?;
+    let mut restored_index_tree = None;
+    if restore_index && stash.index_tree != stash.base_tree && stash.index_tree != current_tree {
+        let options = merge_options("Updated upstream".to_string(), "Stashed changes".to_string(), config)?;
+        let result = merge_trees(Some(&stash.base_tree), &current_tree, &stash.index_tree, "Stash base", &options)?;
+        if !result.conflicts.is_empty() {
+            eprintln!("error: conflicts in index. Try without --index.");
+            return Ok(false);
+        }
+        restored_index_tree = Some(result.tree);
+    }
+
+    let mut applied = true;
+    let pathspec = Pathspec::new(&[]);
+    let head_tree = match read_head_commit()? {
+        Some(head) => Some(CommitObject::read(&head)?.tree),
+        None => None,
+    };
+    if restored_index_tree.is_some() && head_tree.as_ref() != Some(&current_tree) {
+        // like git, the index is reset to HEAD before the merge, which then refuses to run with the staged changes
+        reset_index(head_tree.as_deref(), &pathspec, &mut index)?;
+        let paths = diff_trees(head_tree.as_deref(), Some(&current_tree), true, &pathspec)?
+            .into_iter()
+            .map(|x| format!("  {}", x.path))
+            .collect::<Vec<_>>();
+        eprintln!("error: Your local changes to the following files would be overwritten by merge:\n{}", paths.join("\n"));
+        applied = false;
+    } else if stash.tree == stash.base_tree {
+        println!("Already up to date.");
+    } else {
+        let ours_label = if stash.base_tree == current_tree { "Version stash was based on" } else { "Updated upstream" };
+        let options = merge_options(ours_label.to_string(), "Stashed changes".to_string(), config)?;
+        let result = merge_trees(Some(&stash.base_tree), &current_tree, &stash.tree, "Stash base", &options)?;
+        applied = match apply_merge_result(&current_tree, result, &mut index, "", false) {
+            Ok(conflicted_paths) => conflicted_paths.is_empty(),
+            Err(error) => {
+                eprintln!("error: {error}");
+                false
+            },
+        };
+    }
+    if !applied && restore_index {
+        eprintln!("Index was not unstashed.");
+    }
+    match (&restored_index_tree, applied) {
+        (_, false) => {},
+        (Some(tree), true) => reset_index(Some(tree), &pathspec, &mut index)?,
+        (None, true) => {
+            // the merged changes stay unstaged, except for the new files
+            let current_files = list_tree_files(Some(&current_tree), &pathspec)?.into_iter().collect::<HashMap<_, _>>();
+            let targets = diff_tree_to_index(Some(&current_tree), &index, true, &pathspec)?
+                .into_iter()
+                .filter(|x| x.status != ChangeStatus::Added)
+                .map(|x| {
+                    let side = current_files.get(&x.path).cloned();
+                    (x.path, side)
+                })
+                .collect();
+            apply_targets(&mut index, targets, false)?;
+        },
+    }
+    index.write()?;
+
+    if let Some(untracked_tree) = &stash.untracked_tree {
+        let mut restored = true;
+        for (path, side) in list_tree_files(Some(untracked_tree), &pathspec)? {
+            if fs::symlink_metadata(&path).is_ok() {
+                eprintln!("{path} already exists, no checkout");
+                restored = false;
+            } else {
+                checkout_file(&path, &side)?;
+            }
+        }
+        if !restored {
+            eprintln!("error: could not restore untracked files from stash");
+            applied = false;
+        }
+    }
+    if !quiet {
+        print_status(&read_head()?, false, &index, config)?;
+    }
+    Ok(applied)
+}
+
+/// Fails unless the stash entry is in the list of entries, rather than any commit that looks like one
+fn check_stash_ref(stash: &Stash) -> anyhow::Result<usize> {
+    stash.position.context(format!("'{}' is not a stash reference", stash.revision))
+}
+
+/// Applies the stash entry and removes it from the list, unless it did not apply. Returns whether it applied
+pub(crate) fn stash_pop(stash: &Stash, restore_index: bool, quiet: bool, config: &Config) -> anyhow::Result<bool> {
+    check_stash_ref(stash)?;
+    if !stash_apply(stash, restore_index, quiet, config)? {
+        println!("The stash entry is kept in case you need it again.");
+        return Ok(false);
+    }
+    stash_drop(stash, quiet)?;
+    Ok(true)
+}
+
+/// Removes the entry from the list of stash entries
+pub(crate) fn stash_drop(stash: &Stash, quiet: bool) -> anyhow::Result<()> {
+    delete_reflog_entry(STASH_REF, check_stash_ref(stash)?)?;
+    if !quiet {
+        println!("Dropped {} ({})", stash.revision, stash.commit);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::commit_object_read::Signature;
+    use crate::common::init_worktree_test;
+    use crate::refs::{read_reflog, update_head};
+
+    /// Writes the files, stages them and commits the index on top of HEAD
+    fn commit_files(files: &[(&str, &str)]) -> anyhow::Result<String> {
+        let mut index = Index::read()?;
+        for (path, content) in files {
+            fs::write(path, content)?;
+            index.add_file(path)?;
+        }
+        index.write()?;
+        let tree = write_index_tree(&index)?;
+        let head = read_head_commit()?;
+        let signature = Signature { name: "test".to_string(), email: "test@example.com".to_string(), timestamp: 1_000, timezone: "+0000".to_string() };
+        let hash = write_commit(&tree, &head.iter().map(String::as_str).collect::<Vec<_>>(), &signature, &signature, "test")?;
+        update_head(&hash, head.as_deref(), &signature, "test")?;
+        Ok(hash)
+    }
+
+    fn push(include_untracked: bool) -> anyhow::Result<()> {
+        stash_push(StashPushFlags { include_untracked, keep_index: false, quiet: true, message: None, paths: vec![] }, &Config::default())
+    }
+
+    /// Stages the file with the contents in the index, leaving the working tree with other contents
+    fn stage(path: &str, staged: &str, worktree: &str) -> anyhow::Result<()> {
+        fs::write(path, staged)?;
+        let mut index = Index::read()?;
+        index.add_file(path)?;
+        index.write()?;
+        fs::write(path, worktree)?;
+        Ok(())
+    }
+
+    fn index_tree() -> anyhow::Result<String> {
+        write_index_tree(&Index::read()?)
+    }
+
+    #[test]
+    fn test_stash() -> anyhow::Result<()> {
+        let _guard = init_worktree_test("stash")?;
+        let config = Config::default();
+        let head = commit_files(&[("a.txt", "a\n"), ("b.txt", "b\n")])?;
+        let head_tree = peel(&head, ObjectType::Tree)?;
+
+        // nothing to save leaves no entry
+        push(false)?;
+        assert_eq!(None, read_ref(STASH_REF)?);
+
+        // push saves the index and the working tree and reverts both to HEAD
+        stage("a.txt", "staged a\n", "worktree a\n")?;
+        fs::write("b.txt", "worktree b\n")?;
+        push(false)?;
+        assert_eq!(("a\n".to_string(), "b\n".to_string()), (fs::read_to_string("a.txt")?, fs::read_to_string("b.txt")?));
+        assert_eq!(head_tree, index_tree()?);
+        let stash = Stash::find(None)?;
+        assert_eq!((head.as_str(), Some(0), None), (stash.base.as_str(), stash.position, stash.untracked_tree.as_deref()));
+        assert_ne!(stash.index_tree, stash.tree);
+
+        // apply restores the working tree but leaves the changes unstaged, and keeps the entry
+        assert!(stash_apply(&stash, false, true, &config)?);
+        assert_eq!(("worktree a\n".to_string(), "worktree b\n".to_string()), (fs::read_to_string("a.txt")?, fs::read_to_string("b.txt")?));
+        assert_eq!(head_tree, index_tree()?);
+        assert_eq!(1, read_reflog(STASH_REF)?.len());
+
+        // --index restores the staged changes as well, the second entry has none
+        push(false)?;
+        assert_eq!(2, read_reflog(STASH_REF)?.len());
+        assert_eq!(head_tree, Stash::find(None)?.index_tree);
+        assert!(stash_apply(&Stash::find(Some("1"))?, true, true, &config)?);
+        assert_eq!(stash.index_tree, index_tree()?);
+        assert_eq!("worktree a\n", fs::read_to_string("a.txt")?);
+
+        // drop removes an entry, the entries after it move up the list
+        stash_drop(&Stash::find(Some("0"))?, true)?;
+        assert_eq!(1, read_reflog(STASH_REF)?.len());
+        assert_eq!(stash.commit, Stash::find(None)?.commit);
+        stash_drop(&Stash::find(None)?, true)?;
+        assert_eq!(None, read_ref(STASH_REF)?);
+        assert!(Stash::find(None).is_err());
+
+        // -u saves the untracked files and removes them, pop brings them back and removes the entry
+        commit_files(&[("a.txt", "a\n")])?;
+        fs::write("b.txt", "b\n")?;
+        fs::write("new.txt", "new\n")?;
+        push(true)?;
+        assert!(fs::symlink_metadata("new.txt").is_err());
+        let stash = Stash::find(None)?;
+        assert!(stash.untracked_tree.is_some());
+        assert!(stash_pop(&stash, false, true, &config)?);
+        assert_eq!("new\n", fs::read_to_string("new.txt")?);
+        assert_eq!(None, read_ref(STASH_REF)?);
+        assert_eq!(head_tree, index_tree()?);
+
+        // a pop that does not apply keeps the entry
+        fs::write("a.txt", "changed a\n")?;
+        push(false)?;
+        commit_files(&[("a.txt", "conflicting a\n")])?;
+        let stash = Stash::find(None)?;
+        assert!(!stash_pop(&stash, false, true, &config)?);
+        assert_eq!(1, read_reflog(STASH_REF)?.len());
+        Ok(())
+    }
+}
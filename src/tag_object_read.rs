@@ -0,0 +1,55 @@
+use anyhow::{bail, Context};
+use crate::commit_object_read::{Signature, split_headers};
+use crate::common::{get_hash_by_object_path, ObjectType};
+use crate::object_read::find_and_decode_object;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TagObject {
+    pub hash: String,
+    pub object: String,
+    pub object_type: ObjectType,
+    pub tag: String,
+    pub tagger: Option<Signature>,
+    pub message: String,
+}
+impl TagObject {
+    pub fn read(hash: &str) -> anyhow::Result<Self> {
+        let object = find_and_decode_object(hash)?;
+        if object.object_type != ObjectType::Tag {
+            bail!("Object {hash} is not a tag, it is actually a {}", object.object_type);
+        }
+        let (file_path, _, _, data) = object.into_vec()?;
+        let hash = get_hash_by_object_path(&file_path);
+        Self::parse(hash, &data).context(format!("Failed to parse tag from {file_path}"))
+    }
+    pub fn parse(hash: String, data: &[u8]) -> anyhow::Result<Self> {
+        let data = String::from_utf8_lossy(data);
+        let (headers, message) = split_headers(&data);
+
+        let mut object = None;
+        let mut object_type = None;
+        let mut tag = String::new();
+        let mut tagger = None;
+        for (key, value) in headers {
+            match key.as_str() {
+                "object" => object = Some(value),
+                "type" => object_type = Some(ObjectType::try_from(value.as_bytes()).context(format!("Invalid object type {value} in tag {hash}"))?),
+                "tag" => tag = value,
+                "tagger" => tagger = Some(Signature::parse(&value)?),
+                _ => {},
+            }
+        }
+        let (Some(object), Some(object_type)) = (object, object_type) else {
+            bail!("Tag {hash} has no target object");
+        };
+        let res = Self {
+            hash,
+            object,
+            object_type,
+            tag,
+            tagger,
+            message: message.to_string(),
+        };
+        Ok(res)
+    }
+}
@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+use crate::config::Config;
+use crate::repository::find_git_dir;
+use crate::receive_pack::{PushAdvertisement, PushReport, ReceivePackOptions, RefUpdate};
+use crate::smart_http::{Service, SmartRemote};
+use crate::upload_pack::{RefAdvertisement, UploadPackOptions, UploadPackResult};
+
+pub(crate) const FILE_URL_PREFIX: &str = "file://";
+
+/// A repository to fetch from or push to
+pub(crate) enum Transport {
+    /// a repository on this machine, given as a path or as a file:// url, served by a child process
+    Local {
+        git_dir: PathBuf,
+        url: String,
+        is_path: bool,
+        remote: Box<SmartRemote>,
+    },
+    /// a repository served by the smart protocol over http(s) or by a git daemon
+    Smart(Box<SmartRemote>),
+}
+impl Transport {
+    /// Connects to the repository at the url or path, to fetch from it with upload-pack or push to it with receive-pack
+    pub fn open(repository: &str, service: Service, config: &Config) -> anyhow::Result<Self> {
+        let (path, is_path) = match repository.strip_prefix(FILE_URL_PREFIX) {
+            Some(path) => (path, false),
+            None => match repository.split_once("://") {
+                Some(("http" | "https" | "git", _)) => return Ok(Self::Smart(Box::new(SmartRemote::new(repository, service, config)?))),
+                Some((scheme, _)) => bail!("Unable to find remote helper for '{scheme}'"),
+                None => (repository, true),
+            },
+        };
+        let git_dir = find_git_dir(Path::new(path)).context(format!("repository '{repository}' does not exist"))?;
+        let git_dir = fs::canonicalize(&git_dir).context(format!("Failed to resolve {}", git_dir.display()))?;
+        // like git, a local path is recorded as an absolute one
+        let url = match is_path {
+            true => fs::canonicalize(path).context(format!("Failed to resolve {path}"))?.display().to_string(),
+            false => repository.to_string(),
+        };
+        let remote = Box::new(SmartRemote::local(&git_dir, &url, service, config)?);
+        Ok(Self::Local { git_dir, url, is_path, remote })
+    }
+
+    /// The refs of the repository whose names start with one of the prefixes, all of them without prefixes
+    pub fn list_refs(&mut self, prefixes: &[&str]) -> anyhow::Result<RefAdvertisement> {
+        match self {
+            Self::Local { remote, .. } | Self::Smart(remote) => remote.list_refs(prefixes),
+        }
+    }
+
+    /// Fetches a pack of the objects reachable from the wants that are not reachable from the haves
+    pub fn fetch(&mut self, wants: &[String], haves: &[String], options: &UploadPackOptions) -> anyhow::Result<UploadPackResult> {
+        match self {
+            Self::Local { remote, .. } | Self::Smart(remote) => remote.fetch(wants, haves, options),
+        }
+    }
+
+    /// The refs a push can update and the capabilities of the receiving end
+    pub fn push_advertisement(&mut self) -> anyhow::Result<PushAdvertisement> {
+        match self {
+            Self::Local { remote, .. } | Self::Smart(remote) => remote.push_advertisement(),
+        }
+    }
+
+    /// Sends the ref updates with the pack of the objects they need and returns what the receiving end reports
+    pub fn push(&mut self, updates: &[RefUpdate], pack: Option<&[u8]>, options: &ReceivePackOptions) -> anyhow::Result<PushReport> {
+        match self {
+            Self::Local { remote, .. } | Self::Smart(remote) => remote.push(updates, pack, options),
+        }
+    }
+}
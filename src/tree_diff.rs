@@ -0,0 +1,464 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::Metadata;
+use std::io::{BufRead, ErrorKind};
+use std::iter::Peekable;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use anyhow::{bail, Context};
+use crate::common::{GIT_PATH, ObjectMode, ObjectType, TreeItem};
+use crate::diff::NULL_HASH;
+use crate::index::{Index, IndexEntry};
+use crate::object_read::find_and_decode_object;
+use crate::object_write::hash_object;
+use crate::pathspec::Pathspec;
+use crate::tree_object_read::TreeObjectIterator;
+use crate::tree_object_write::{compare_tree_names, mode_from_metadata};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum ChangeStatus {
+    Added,
+    Deleted,
+    Modified,
+    /// moved to another path, with the similarity of the contents in percent
+    Renamed(usize),
+    /// copied from another path, with the similarity of the contents in percent
+    Copied(usize),
+    /// changed between a regular file, a symlink and a submodule
+    TypeChanged,
+    Unmerged,
+}
+impl ChangeStatus {
+    pub fn letter(self) -> char {
+        match self {
+            Self::Added => 'A',
+            Self::Deleted => 'D',
+            Self::Modified => 'M',
+            Self::Renamed(_) => 'R',
+            Self::Copied(_) => 'C',
+            Self::TypeChanged => 'T',
+            Self::Unmerged => 'U',
+        }
+    }
+
+    /// The letter followed by the similarity for renames and copies, like R087
+    pub fn label(self) -> String {
+        match self {
+            Self::Renamed(similarity) | Self::Copied(similarity) => format!("{}{similarity:03}", self.letter()),
+            _ => self.letter().to_string(),
+        }
+    }
+}
+
+/// One version of a changed file. The null hash means the contents are in the working tree and were not hashed yet.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct DiffSide {
+    pub mode: ObjectMode,
+    pub hash: String,
+}
+impl DiffSide {
+    pub fn new(mode: ObjectMode, hash: &str) -> Self {
+        Self { mode, hash: hash.to_string() }
+    }
+    pub fn is_in_worktree(&self) -> bool {
+        self.hash == NULL_HASH
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FileChange {
+    pub path: String,
+    /// the old path of a renamed or copied file
+    pub source: Option<String>,
+    pub status: ChangeStatus,
+    pub old: Option<DiffSide>,
+    pub new: Option<DiffSide>,
+}
+impl FileChange {
+    pub fn new(path: String, old: Option<DiffSide>, new: Option<DiffSide>) -> Self {
+        let status = match (&old, &new) {
+            (None, _) => ChangeStatus::Added,
+            (_, None) => ChangeStatus::Deleted,
+            (Some(old), Some(new)) if old.mode.get_type() != new.mode.get_type() || is_symlink_change(old.mode, new.mode) => {
+                ChangeStatus::TypeChanged
+            },
+            _ => ChangeStatus::Modified,
+        };
+        Self { path, source: None, status, old, new }
+    }
+    fn unmerged(path: String) -> Self {
+        Self { path, source: None, status: ChangeStatus::Unmerged, old: None, new: None }
+    }
+
+    pub fn old_path(&self) -> &str {
+        self.source.as_deref().unwrap_or(&self.path)
+    }
+}
+
+fn is_symlink_change(old: ObjectMode, new: ObjectMode) -> bool {
+    (old == ObjectMode::Symlink) != (new == ObjectMode::Symlink)
+}
+
+/// Compares two trees, None stands for an empty tree. Without `recursive` changed subtrees are reported as a whole.
+pub(crate) fn diff_trees(old: Option<&str>, new: Option<&str>, recursive: bool, pathspec: &Pathspec) -> anyhow::Result<Vec<FileChange>> {
+    let mut changes = vec![];
+    diff_subtrees(old, new, "", recursive, pathspec, &mut changes)?;
+    Ok(changes)
+}
+
+fn diff_subtrees(old: Option<&str>, new: Option<&str>, prefix: &str, recursive: bool, pathspec: &Pathspec, changes: &mut Vec<FileChange>) -> anyhow::Result<()> {
+    let old_items = old.map(open_tree).transpose()?.into_iter().flatten();
+    let new_items = new.map(open_tree).transpose()?.into_iter().flatten();
+    walk_lockstep(old_items, new_items, |old, new| {
+        if old.as_ref().zip(new.as_ref()).is_some_and(|(old, new)| old.hash == new.hash && old.mode == new.mode) {
+            // identical subtrees are skipped without descending into them
+            return Ok(());
+        }
+        let name = old.as_ref().or(new.as_ref()).unwrap().file_name.as_bytes();
+        let path = format!("{prefix}{}", String::from_utf8_lossy(name));
+        let old_tree = old.as_ref().filter(|x| x.mode == ObjectMode::Tree).map(|x| x.hash.as_str());
+        let new_tree = new.as_ref().filter(|x| x.mode == ObjectMode::Tree).map(|x| x.hash.as_str());
+        let has_tree = old_tree.is_some() || new_tree.is_some();
+        if has_tree && !pathspec.matches_dir(&path) || !has_tree && !pathspec.matches(&path) {
+            return Ok(());
+        }
+        if recursive && has_tree {
+            return diff_subtrees(old_tree, new_tree, &format!("{path}/"), recursive, pathspec, changes);
+        }
+        let old = old.map(|x| DiffSide { mode: x.mode, hash: x.hash });
+        let new = new.map(|x| DiffSide { mode: x.mode, hash: x.hash });
+        changes.push(FileChange::new(path, old, new));
+        Ok(())
+    })
+}
+
+fn open_tree(hash: &str) -> anyhow::Result<TreeObjectIterator<impl BufRead>> {
+    let object = find_and_decode_object(hash)?;
+    let object_type = object.object_type;
+    let Some(iterator) = TreeObjectIterator::from_decoded_object(object) else {
+        bail!("Object {hash} is not a tree, it is actually a {object_type}");
+    };
+    Ok(iterator)
+}
+
+/// Walks entries of two trees in tree order, calling back with the old and new versions of each name
+fn walk_lockstep<O, N>(old: O, new: N, mut callback: impl FnMut(Option<TreeItem>, Option<TreeItem>) -> anyhow::Result<()>) -> anyhow::Result<()>
+where
+    O: Iterator<Item = anyhow::Result<TreeItem>>,
+    N: Iterator<Item = anyhow::Result<TreeItem>>,
+{
+    let mut old = old.peekable();
+    let mut new = new.peekable();
+    loop {
+        let ordering = match (peek_item(&mut old)?, peek_item(&mut new)?) {
+            (None, None) => return Ok(()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(old), Some(new)) => compare_tree_names(
+                old.file_name.as_bytes(), old.mode == ObjectMode::Tree,
+                new.file_name.as_bytes(), new.mode == ObjectMode::Tree,
+            ),
+        };
+        match ordering {
+            Ordering::Less => callback(old.next().transpose()?, None)?,
+            Ordering::Greater => callback(None, new.next().transpose()?)?,
+            Ordering::Equal => callback(old.next().transpose()?, new.next().transpose()?)?,
+        }
+    }
+}
+
+fn peek_item<I: Iterator<Item = anyhow::Result<TreeItem>>>(iterator: &mut Peekable<I>) -> anyhow::Result<Option<&TreeItem>> {
+    if iterator.peek().is_some_and(|x| x.is_err()) {
+        iterator.next().unwrap()?;
+    }
+    Ok(iterator.peek().map(|x| x.as_ref().unwrap()))
+}
+
+/// Walks the files of several trees in lockstep and index order, calling back with the versions of each path.
+/// None stands for an empty tree. A name that is a file in one tree and a directory in another is reported for both
+pub(crate) fn walk_trees(
+    trees: &[Option<&str>],
+    prefix: &str,
+    callback: &mut impl FnMut(String, Vec<Option<DiffSide>>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut iterators = trees.iter().map(|x| x.map(open_tree).transpose()).collect::<anyhow::Result<Vec<_>>>()?;
+    let mut heads = iterators.iter_mut()
+        .map(|x| x.as_mut().and_then(|x| x.next()).transpose())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let compare = |left: &TreeItem, right: &TreeItem| compare_tree_names(
+        left.file_name.as_bytes(), left.mode == ObjectMode::Tree,
+        right.file_name.as_bytes(), right.mode == ObjectMode::Tree,
+    );
+    loop {
+        let Some(smallest) = heads.iter().flatten().min_by(|left, right| compare(left, right)) else {
+            return Ok(());
+        };
+        let (name, is_tree) = (smallest.file_name.clone(), smallest.mode == ObjectMode::Tree);
+        let mut items = Vec::with_capacity(heads.len());
+        for (head, iterator) in heads.iter_mut().zip(iterators.iter_mut()) {
+            let matches = head.as_ref().is_some_and(|x| {
+                compare_tree_names(x.file_name.as_bytes(), x.mode == ObjectMode::Tree, name.as_bytes(), is_tree) == Ordering::Equal
+            });
+            if matches {
+                items.push(head.take());
+                *head = iterator.as_mut().and_then(|x| x.next()).transpose()?;
+            } else {
+                items.push(None);
+            }
+        }
+        let path = format!("{prefix}{}", String::from_utf8_lossy(name.as_bytes()));
+        if is_tree {
+            let subtrees = items.iter().map(|x| x.as_ref().map(|x| x.hash.as_str())).collect::<Vec<_>>();
+            walk_trees(&subtrees, &format!("{path}/"), callback)?;
+        } else {
+            callback(path, items.into_iter().map(|x| x.map(|x| DiffSide { mode: x.mode, hash: x.hash })).collect())?;
+        }
+    }
+}
+
+/// All files of a tree with their full paths, in the same order as the index
+fn flatten_tree(tree: &str, prefix: &str, pathspec: &Pathspec, result: &mut Vec<(String, DiffSide)>) -> anyhow::Result<()> {
+    for item in open_tree(tree)? {
+        let item = item?;
+        let path = format!("{prefix}{}", String::from_utf8_lossy(item.file_name.as_bytes()));
+        if item.mode == ObjectMode::Tree {
+            if pathspec.matches_dir(&path) {
+                flatten_tree(&item.hash, &format!("{path}/"), pathspec, result)?;
+            }
+        } else if pathspec.matches(&path) {
+            result.push((path, DiffSide { mode: item.mode, hash: item.hash }));
+        }
+    }
+    Ok(())
+}
+
+/// All files of a tree, None stands for an empty tree
+pub(crate) fn list_tree_files(tree: Option<&str>, pathspec: &Pathspec) -> anyhow::Result<Vec<(String, DiffSide)>> {
+    let mut files = vec![];
+    if let Some(tree) = tree {
+        flatten_tree(tree, "", pathspec, &mut files)?;
+    }
+    Ok(files)
+}
+
+/// All merged files of the index
+pub(crate) fn list_index_files(index: &Index, pathspec: &Pathspec) -> Vec<(String, DiffSide)> {
+    index.entries.iter()
+        .filter(|x| x.stage == 0 && pathspec.matches(&x.path))
+        .map(|x| (x.path.clone(), DiffSide::new(x.mode, &x.hash)))
+        .collect()
+}
+
+/// Compares a tree with the index, or with the working tree as seen through the index when not `cached`
+pub(crate) fn diff_tree_to_index(tree: Option<&str>, index: &Index, cached: bool, pathspec: &Pathspec) -> anyhow::Result<Vec<FileChange>> {
+    let mut tree_files = list_tree_files(tree, pathspec)?.into_iter().peekable();
+    let mut entries = index.entries.iter().filter(|x| pathspec.matches(&x.path)).peekable();
+
+    let mut changes = vec![];
+    loop {
+        let ordering = match (tree_files.peek(), entries.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((path, _)), Some(entry)) => path.as_str().cmp(entry.path.as_str()),
+        };
+        if ordering == Ordering::Less {
+            let (path, old) = tree_files.next().unwrap();
+            changes.push(FileChange::new(path, Some(old), None));
+            continue;
+        }
+        let old = match ordering {
+            Ordering::Equal => tree_files.next().map(|(_, side)| side),
+            _ => None,
+        };
+        let entry = entries.next().unwrap();
+        if entry.stage != 0 {
+            while entries.next_if(|x| x.path == entry.path).is_some() {}
+            changes.push(FileChange::unmerged(entry.path.clone()));
+            continue;
+        }
+        let new = match cached {
+            true => Some(DiffSide::new(entry.mode, &entry.hash)),
+            false => worktree_side(index, entry)?,
+        };
+        if old != new {
+            changes.push(FileChange::new(entry.path.clone(), old, new));
+        }
+    }
+    Ok(changes)
+}
+
+/// Compares the index with the working tree, untracked files are ignored
+pub(crate) fn diff_index_to_worktree(index: &Index, pathspec: &Pathspec) -> anyhow::Result<Vec<FileChange>> {
+    let mut changes = vec![];
+    let mut entries = index.entries.iter().filter(|x| pathspec.matches(&x.path)).peekable();
+    while let Some(entry) = entries.next() {
+        if entry.stage != 0 {
+            while entries.next_if(|x| x.path == entry.path).is_some() {}
+            changes.push(FileChange::unmerged(entry.path.clone()));
+            continue;
+        }
+        let old = Some(DiffSide::new(entry.mode, &entry.hash));
+        let new = worktree_side(index, entry)?;
+        if old != new {
+            changes.push(FileChange::new(entry.path.clone(), old, new));
+        }
+    }
+    Ok(changes)
+}
+
+/// Files of the working tree that are not in the index, sorted by path. With `collapse_dirs` a directory without
+/// tracked files is listed once as "dir/", the way git status shows it
+pub(crate) fn list_untracked_files(index: &Index, pathspec: &Pathspec, collapse_dirs: bool) -> anyhow::Result<Vec<String>> {
+    let tracked = index.entries.iter().map(|x| x.path.as_str()).collect::<HashSet<_>>();
+    let tracked_dirs = index.entries
+        .iter()
+        .flat_map(|x| x.path.match_indices('/').map(|(end, _)| &x.path[..end]))
+        .collect::<HashSet<_>>();
+    let mut untracked = vec![];
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        let dir_path = if dir.is_empty() { Path::new(".") } else { Path::new(&dir) };
+        let dir_iterator = fs::read_dir(dir_path).context(format!("Failed to read dir {}", dir_path.display()))?;
+        for dir_entry in dir_iterator {
+            let dir_entry = dir_entry.context(format!("Failed to read an entry of {}", dir_path.display()))?;
+            let file_name = dir_entry.file_name();
+            let Some(name) = file_name.to_str() else {
+                continue;
+            };
+            if dir.is_empty() && name == GIT_PATH {
+                continue;
+            }
+            let path = if dir.is_empty() { name.to_string() } else { format!("{dir}/{name}") };
+            if tracked.contains(path.as_str()) {
+                continue;
+            }
+            if !dir_entry.file_type().context(format!("Failed to read the type of {path}"))?.is_dir() {
+                if pathspec.matches(&path) {
+                    untracked.push(path);
+                }
+            } else if collapse_dirs && !tracked_dirs.contains(path.as_str()) && pathspec.matches(&path) {
+                // empty directories are not shown
+                if contains_files(Path::new(&path))? {
+                    untracked.push(format!("{path}/"));
+                }
+            } else if pathspec.matches_dir(&path) {
+                dirs.push(path);
+            }
+        }
+    }
+    untracked.sort_unstable();
+    Ok(untracked)
+}
+
+fn contains_files(dir_path: &Path) -> anyhow::Result<bool> {
+    for dir_entry in fs::read_dir(dir_path).context(format!("Failed to read dir {}", dir_path.display()))? {
+        let dir_entry = dir_entry.context(format!("Failed to read an entry of {}", dir_path.display()))?;
+        if !dir_entry.file_type()?.is_dir() || contains_files(&dir_entry.path())? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The working tree version of an index entry. It has the hash of the entry if the file looks unchanged,
+/// and the null hash otherwise. None if the file was deleted.
+fn worktree_side(index: &Index, entry: &IndexEntry) -> anyhow::Result<Option<DiffSide>> {
+    if entry.mode == ObjectMode::Gitlink {
+        // submodules are not checked out by us, so they are always considered unchanged
+        return Ok(Some(DiffSide::new(entry.mode, &entry.hash)));
+    }
+    let path = Path::new(&entry.path);
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(error) if error.kind() == ErrorKind::NotFound || error.kind() == ErrorKind::NotADirectory => return Ok(None),
+        Err(error) => return Err(error).context(format!("Failed to read metadata for {}", path.display())),
+    };
+    let Some(mode) = mode_from_metadata(&meta).filter(|x| *x != ObjectMode::Tree) else {
+        return Ok(None);
+    };
+    let mut unchanged = mode == entry.mode && entry.matches_stat(&meta);
+    if unchanged && index.is_racy(entry) {
+        // the file could have been changed right after the index was written, without changing its timestamp
+        unchanged = hash_worktree_file(path, &meta)? == entry.hash;
+    }
+    let hash = if unchanged { entry.hash.as_str() } else { NULL_HASH };
+    Ok(Some(DiffSide::new(mode, hash)))
+}
+
+/// Contents of a file in the working tree the way git stores them, symlinks are stored as their target path
+pub(crate) fn read_worktree_file(path: &Path, meta: &Metadata) -> anyhow::Result<Vec<u8>> {
+    if meta.is_symlink() {
+        let target = fs::read_link(path).context(format!("Failed to read symlink {}", path.display()))?;
+        return Ok(target.as_os_str().as_bytes().to_vec());
+    }
+    fs::read(path).context(format!("Failed to read {}", path.display()))
+}
+
+/// Drops the changes where the working tree file was not hashed because of its new timestamp, but has the old contents
+pub(crate) fn drop_unchanged_worktree_files(changes: Vec<FileChange>) -> anyhow::Result<Vec<FileChange>> {
+    let mut result = vec![];
+    for change in changes {
+        if let (Some(old), Some(new)) = (&change.old, &change.new) {
+            let path = Path::new(&change.path);
+            if new.is_in_worktree() && old.mode == new.mode && hash_worktree_file(path, &fs::symlink_metadata(path)?)? == old.hash {
+                continue;
+            }
+        }
+        result.push(change);
+    }
+    Ok(result)
+}
+
+pub(crate) fn hash_worktree_file(path: &Path, meta: &Metadata) -> anyhow::Result<String> {
+    let data = read_worktree_file(path, meta)?;
+    hash_object(data.as_slice(), ObjectType::Blob, data.len() as u64, false)
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use super::*;
+
+    fn item(name: &str, mode: ObjectMode, hash: &str) -> anyhow::Result<TreeItem> {
+        Ok(TreeItem { mode, file_name: OsString::from(name), hash: hash.repeat(40) })
+    }
+
+    #[test]
+    fn test_walk_lockstep() -> anyhow::Result<()> {
+        let old = vec![
+            item("a", ObjectMode::Normal, "1"),
+            item("a.txt", ObjectMode::Normal, "2"),
+            item("b", ObjectMode::Tree, "3"),
+            item("c", ObjectMode::Normal, "4"),
+        ];
+        let new = vec![
+            item("a.txt", ObjectMode::Normal, "5"),
+            item("a", ObjectMode::Tree, "6"),
+            item("b", ObjectMode::Tree, "3"),
+            item("d", ObjectMode::Executable, "7"),
+        ];
+        let mut pairs = vec![];
+        walk_lockstep(old.into_iter(), new.into_iter(), |old, new| {
+            let name = |x: &Option<TreeItem>| x.as_ref().map(|x| (x.file_name.to_string_lossy().to_string(), x.mode));
+            pairs.push((name(&old), name(&new)));
+            Ok(())
+        })?;
+        let expected = vec![
+            (Some(("a".to_string(), ObjectMode::Normal)), None),
+            (Some(("a.txt".to_string(), ObjectMode::Normal)), Some(("a.txt".to_string(), ObjectMode::Normal))),
+            (None, Some(("a".to_string(), ObjectMode::Tree))),
+            (Some(("b".to_string(), ObjectMode::Tree)), Some(("b".to_string(), ObjectMode::Tree))),
+            (Some(("c".to_string(), ObjectMode::Normal)), None),
+            (None, Some(("d".to_string(), ObjectMode::Executable))),
+        ];
+        assert_eq!(expected, pairs);
+
+        let side = |mode| Some(DiffSide::new(mode, NULL_HASH));
+        assert_eq!(ChangeStatus::Added, FileChange::new(String::new(), None, side(ObjectMode::Normal)).status);
+        assert_eq!(ChangeStatus::Modified, FileChange::new(String::new(), side(ObjectMode::Normal), side(ObjectMode::Executable)).status);
+        assert_eq!(ChangeStatus::TypeChanged, FileChange::new(String::new(), side(ObjectMode::Normal), side(ObjectMode::Symlink)).status);
+        Ok(())
+    }
+}
@@ -0,0 +1,633 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{BufWriter, stdout};
+use std::path::Path;
+use anyhow::{anyhow, bail, Context};
+use crate::{apply_merge_result, check_unmerged_files, merge_options, show_commit, write_merge_message};
+use crate::checkout::{switch_trees, SwitchAction};
+use crate::common::{Exit, MERGE_HEAD_PATH, MERGE_MODE_PATH, ObjectMode, ObjectType, SQUASH_MSG_PATH};
+use crate::config::Config;
+use crate::diff::{abbreviate, DiffOptions, is_binary, read_blob};
+use crate::diff_output::{DiffFormat, write_changes};
+use crate::file_merge::{ConflictStyle, FileMergeOptions, merge_texts, MergeLabels};
+use crate::ident::{read_ident, Role};
+use crate::index::Index;
+use crate::merge_base::merge_bases;
+use crate::object_write::{hash_object, write_commit};
+use crate::pathspec::Pathspec;
+use crate::refs::{dwim_ref, Head, HEADS_PREFIX, read_head, read_head_commit, REMOTES_PREFIX, TAGS_PREFIX, update_head, write_orig_head};
+use crate::rename::{detect_renames, RenameDetection, RenameOptions};
+use crate::rev_list::{rev_list, RevListOptions};
+use crate::rev_parse::{peel, resolve_revision};
+use crate::tree_diff::{ChangeStatus, diff_tree_to_index, diff_trees, DiffSide, walk_trees};
+use crate::tree_object_write::write_tree_level;
+
+const VIRTUAL_BASE_LABEL: &str = "merged common ancestors";
+const EMPTY_BASE_LABEL: &str = "empty tree";
+
+pub(crate) struct MergeOptions {
+    /// the name of our side in messages and conflict markers, like HEAD
+    pub ours_label: String,
+    pub theirs_label: String,
+    pub style: ConflictStyle,
+    /// renames are detected unless the detection is off
+    pub renames: RenameOptions,
+    /// the length of the conflict markers, the merges of merge bases use longer ones to keep their conflicts apart
+    pub marker_size: usize,
+}
+
+pub(crate) struct MergeResult {
+    /// the merged tree, conflicted files are in it with conflict markers or as the version that was kept
+    pub tree: String,
+    /// the base, ours and theirs versions of the conflicted paths, which become the conflict stages 1 to 3
+    pub conflicts: Vec<(String, [Option<DiffSide>; 3])>,
+    /// what happened to the merged files and why they conflict, ordered by path
+    pub messages: Vec<String>,
+}
+
+/// A file in the base, ours and theirs, its paths differ between the versions when it was renamed
+struct MergeFile {
+    paths: [String; 3],
+    sides: [Option<DiffSide>; 3],
+}
+
+struct TreeMerge<'a> {
+    options: &'a MergeOptions,
+    base_label: &'a str,
+    /// the directories of our and their tree, files in their way are moved
+    ours_dirs: HashSet<String>,
+    theirs_dirs: HashSet<String>,
+    merged: BTreeMap<String, DiffSide>,
+    conflicts: BTreeMap<String, [Option<DiffSide>; 3]>,
+    messages: Vec<(String, String)>,
+}
+
+/// Merges two commits with the given merge bases, the way the ort strategy of git does.
+/// Several merge bases are merged into a virtual one first, without merge bases the files are merged as added
+pub(crate) fn merge_commits(bases: &[String], ours: &str, theirs: &str, options: &MergeOptions) -> anyhow::Result<MergeResult> {
+    let base_tree = virtual_base_tree(bases, options)?;
+    let ours_tree = peel(ours, ObjectType::Tree)?;
+    let theirs_tree = peel(theirs, ObjectType::Tree)?;
+    merge_trees(base_tree.as_deref(), &ours_tree, &theirs_tree, &base_label(bases), options)
+}
+
+/// How the merge base is shown in conflicts
+fn base_label(bases: &[String]) -> String {
+    match bases {
+        [] => EMPTY_BASE_LABEL.to_string(),
+        [base] => abbreviate(base).to_string(),
+        _ => VIRTUAL_BASE_LABEL.to_string(),
+    }
+}
+
+/// The tree of the merge bases merged into each other, conflicts are left in it with longer markers.
+/// Like git, the merge bases are merged from the last one, each merge with the merge bases of its own
+fn virtual_base_tree(bases: &[String], options: &MergeOptions) -> anyhow::Result<Option<String>> {
+    let Some((first, rest)) = bases.split_last() else {
+        return Ok(None);
+    };
+    let inner_options = MergeOptions {
+        ours_label: "Temporary merge branch 1".to_string(),
+        theirs_label: "Temporary merge branch 2".to_string(),
+        style: options.style,
+        renames: options.renames.clone(),
+        marker_size: options.marker_size + 2,
+    };
+    let mut tree = peel(first, ObjectType::Tree)?;
+    for other in rest.iter().rev() {
+        let inner_bases = merge_bases(first, other)?;
+        let inner_base = virtual_base_tree(&inner_bases, &inner_options)?;
+        let other_tree = peel(other, ObjectType::Tree)?;
+        tree = merge_trees(inner_base.as_deref(), &tree, &other_tree, &base_label(&inner_bases), &inner_options)?.tree;
+    }
+    Ok(Some(tree))
+}
+
+/// Merges the changes from the base tree to ours and to theirs. Files changed on both sides are merged line by line,
+/// files renamed on one side get the changes of the other side
+pub(crate) fn merge_trees(base: Option<&str>, ours: &str, theirs: &str, base_label: &str, options: &MergeOptions) -> anyhow::Result<MergeResult> {
+    let mut entries = BTreeMap::new();
+    walk_trees(&[base, Some(ours), Some(theirs)], "", &mut |path, sides| {
+        let sides = <[Option<DiffSide>; 3]>::try_from(sides).map_err(|_| anyhow!("Expected three versions of {path}"))?;
+        entries.insert(path, sides);
+        Ok(())
+    })?;
+    let directories = |side: usize| entries.iter()
+        .filter(|(_, sides)| sides[side].is_some())
+        .flat_map(|(path, _)| path.match_indices('/').map(|(position, _)| path[..position].to_string()))
+        .collect::<HashSet<_>>();
+    let mut merge = TreeMerge {
+        options,
+        base_label,
+        ours_dirs: directories(1),
+        theirs_dirs: directories(2),
+        merged: BTreeMap::new(),
+        conflicts: BTreeMap::new(),
+        messages: vec![],
+    };
+
+    let (ours_renames, theirs_renames) = match options.renames.detection {
+        RenameDetection::Off => (BTreeMap::new(), BTreeMap::new()),
+        _ => (find_renames(base, ours, &options.renames)?, find_renames(base, theirs, &options.renames)?),
+    };
+    let mut files = vec![];
+    for (source, ours_target) in &ours_renames {
+        let theirs_target = theirs_renames.get(source);
+        if let Some(theirs_target) = theirs_target.filter(|x| *x != ours_target) {
+            let [base_side, _, _] = entries.remove(source).unwrap_or_default();
+            let [_, ours_side, _] = entries.remove(ours_target).unwrap_or_default();
+            let [_, _, theirs_side] = entries.remove(theirs_target).unwrap_or_default();
+            let (ours_label, theirs_label) = (&options.ours_label, &options.theirs_label);
+            merge.message(source, format!(
+                "CONFLICT (rename/rename): {source} renamed to {ours_target} in {ours_label} and to {theirs_target} in {theirs_label}."
+            ));
+            merge.record(source.clone(), None, Some([base_side, None, None]));
+            merge.record(ours_target.clone(), ours_side.clone(), Some([None, ours_side, None]));
+            merge.record(theirs_target.clone(), theirs_side.clone(), Some([None, None, theirs_side]));
+            continue;
+        }
+        // a file they added at the same path is not merged with the renamed one
+        if theirs_target.is_none() && entries.get(ours_target).is_some_and(|x| x[2].is_some()) {
+            continue;
+        }
+        let [base_side, _, theirs_source] = entries.remove(source).unwrap_or_default();
+        let [_, ours_side, theirs_at_target] = entries.remove(ours_target).unwrap_or_default();
+        let (theirs_path, theirs_side) = match theirs_target {
+            Some(target) => (target, theirs_at_target),
+            None => (source, theirs_source),
+        };
+        files.push(MergeFile { paths: [source.clone(), ours_target.clone(), theirs_path.clone()], sides: [base_side, ours_side, theirs_side] });
+    }
+    for (source, theirs_target) in &theirs_renames {
+        if ours_renames.contains_key(source) || entries.get(theirs_target).is_some_and(|x| x[1].is_some()) {
+            continue;
+        }
+        let [base_side, ours_side, _] = entries.remove(source).unwrap_or_default();
+        let [_, _, theirs_side] = entries.remove(theirs_target).unwrap_or_default();
+        files.push(MergeFile { paths: [source.clone(), source.clone(), theirs_target.clone()], sides: [base_side, ours_side, theirs_side] });
+    }
+    files.extend(entries.into_iter().map(|(path, sides)| MergeFile { paths: [path.clone(), path.clone(), path], sides }));
+    for file in files {
+        merge.merge_file(file)?;
+    }
+
+    let tree_entries = merge.merged.iter().map(|(path, side)| (path.as_str(), side.mode, side.hash.as_str())).collect::<Vec<_>>();
+    let tree = write_tree_level(&tree_entries)?;
+    merge.messages.sort_by(|left, right| left.0.cmp(&right.0));
+    Ok(MergeResult {
+        tree,
+        conflicts: merge.conflicts.into_iter().collect(),
+        messages: merge.messages.into_iter().map(|(_, message)| message).collect(),
+    })
+}
+
+/// The files renamed from the base to the side, by their old paths
+fn find_renames(base: Option<&str>, side: &str, options: &RenameOptions) -> anyhow::Result<BTreeMap<String, String>> {
+    let options = RenameOptions { detection: RenameDetection::Renames, ..options.clone() };
+    let changes = diff_trees(base, Some(side), true, &Pathspec::new(&[]))?;
+    let renames = detect_renames(changes, &options, || Ok(vec![]))?
+        .into_iter()
+        .filter(|x| matches!(x.status, ChangeStatus::Renamed(_)))
+        .filter_map(|x| x.source.map(|source| (source, x.path)))
+        .collect();
+    Ok(renames)
+}
+
+impl TreeMerge<'_> {
+    fn message(&mut self, path: &str, message: String) {
+        self.messages.push((path.to_string(), message));
+    }
+
+    /// Puts the merged version of a path into the tree, conflicted paths also get their stages
+    fn record(&mut self, path: String, side: Option<DiffSide>, conflict: Option<[Option<DiffSide>; 3]>) {
+        if let Some(stages) = conflict {
+            self.conflicts.insert(path.clone(), stages);
+        }
+        if let Some(side) = side {
+            self.merged.insert(path, side);
+        }
+    }
+
+    fn merge_file(&mut self, file: MergeFile) -> anyhow::Result<()> {
+        let MergeFile { paths, sides: [base, ours, theirs] } = file;
+        let (ours_label, theirs_label) = (self.options.ours_label.clone(), self.options.theirs_label.clone());
+        let mut path = if paths[1] != paths[0] { paths[1].clone() } else { paths[2].clone() };
+        // a file where the other side has a directory is moved out of its way
+        let in_the_way = match (&ours, &theirs) {
+            (Some(_), _) if self.theirs_dirs.contains(&path) => Some(&ours_label),
+            (_, Some(_)) if self.ours_dirs.contains(&path) => Some(&theirs_label),
+            _ => None,
+        };
+        if let Some(label) = in_the_way {
+            let new_path = format!("{path}~{label}");
+            self.message(&new_path, format!(
+                "CONFLICT (file/directory): directory in the way of {path} from {label}; moving it to {new_path} instead."
+            ));
+            path = new_path;
+        }
+        let stages = [base.clone(), ours.clone(), theirs.clone()];
+
+        match (&base, &ours, &theirs) {
+            (Some(_), Some(_), None) if paths[1] != paths[0] => {
+                self.message(&path, format!("CONFLICT (rename/delete): {} renamed to {path} in {ours_label}, but deleted in {theirs_label}.", paths[0]));
+                self.record(path, ours, Some(stages));
+                return Ok(());
+            },
+            (Some(_), None, Some(_)) if paths[2] != paths[0] => {
+                self.message(&path, format!("CONFLICT (rename/delete): {} renamed to {path} in {theirs_label}, but deleted in {ours_label}.", paths[0]));
+                self.record(path, theirs, Some(stages));
+                return Ok(());
+            },
+            _ => {},
+        }
+        let (side, conflicted) = if ours == theirs || base == theirs {
+            (ours, false)
+        } else if base == ours {
+            (theirs, false)
+        } else {
+            match (&ours, &theirs) {
+                (Some(ours), Some(theirs)) => {
+                    let (side, conflicted) = self.merge_contents(&path, &paths, base.as_ref(), ours, theirs)?;
+                    (Some(side), conflicted)
+                },
+                (Some(_), None) => {
+                    self.message(&path, format!(
+                        "CONFLICT (modify/delete): {path} deleted in {theirs_label} and modified in {ours_label}.  Version {ours_label} of {path} left in tree."
+                    ));
+                    (ours, true)
+                },
+                (None, _) => {
+                    self.message(&path, format!(
+                        "CONFLICT (modify/delete): {path} deleted in {ours_label} and modified in {theirs_label}.  Version {theirs_label} of {path} left in tree."
+                    ));
+                    (theirs, true)
+                },
+            }
+        };
+        // a moved file is a conflict even when its versions merge cleanly
+        let conflicted = conflicted || in_the_way.is_some() && side.is_some();
+        self.record(path, side, conflicted.then_some(stages));
+        Ok(())
+    }
+
+    /// Merges the modes and the contents of a file that both sides changed, returns the merged version and
+    /// whether it is conflicted
+    fn merge_contents(&mut self, path: &str, paths: &[String; 3], base: Option<&DiffSide>, ours: &DiffSide, theirs: &DiffSide) -> anyhow::Result<(DiffSide, bool)> {
+        let base_mode = base.map(|x| x.mode);
+        let (mode, mut conflicted) = if ours.mode == theirs.mode || base_mode == Some(theirs.mode) {
+            (ours.mode, false)
+        } else if base_mode == Some(ours.mode) {
+            (theirs.mode, false)
+        } else {
+            (ours.mode, true)
+        };
+
+        let base_hash = base.map(|x| x.hash.as_str());
+        let hash = if ours.hash == theirs.hash || base_hash == Some(&theirs.hash) {
+            ours.hash.clone()
+        } else if base_hash == Some(&ours.hash) {
+            theirs.hash.clone()
+        } else {
+            let (ours_label, theirs_label) = (&self.options.ours_label, &self.options.theirs_label);
+            let is_text_file = |x: &DiffSide| matches!(x.mode, ObjectMode::Normal | ObjectMode::Executable);
+            let base_data = base.map(|x| read_blob(&x.hash)).transpose()?.unwrap_or_default();
+            let (ours_data, theirs_data) = match is_text_file(ours) && is_text_file(theirs) {
+                true => (read_blob(&ours.hash)?, read_blob(&theirs.hash)?),
+                false => (vec![], vec![]),
+            };
+            let binary = [&base_data, &ours_data, &theirs_data].iter().any(|x| is_binary(x));
+            if binary {
+                self.message(path, format!("warning: Cannot merge binary files: {path} ({ours_label} vs. {theirs_label})"));
+            }
+            self.message(path, format!("Auto-merging {path}"));
+            if binary || !is_text_file(ours) || !is_text_file(theirs) {
+                conflicted = true;
+                ours.hash.clone()
+            } else {
+                // the paths of renamed files are shown with the labels
+                let label = |label: &str, path: &str| match paths.iter().all(|x| *x == paths[0]) {
+                    true => label.to_string(),
+                    false => format!("{label}:{path}"),
+                };
+                let (ours_label, base_label, theirs_label) = (
+                    label(ours_label, &paths[1]),
+                    label(self.base_label, &paths[0]),
+                    label(theirs_label, &paths[2]),
+                );
+                let labels = MergeLabels { ours: &ours_label, base: &base_label, theirs: &theirs_label };
+                let file_options = FileMergeOptions { style: self.options.style, marker_size: self.options.marker_size, ..FileMergeOptions::default() };
+                let (data, conflicts) = merge_texts(&base_data, &ours_data, &theirs_data, &labels, &file_options);
+                conflicted |= conflicts > 0;
+                hash_object(data.as_slice(), ObjectType::Blob, data.len() as u64, true)?
+            }
+        };
+        if conflicted {
+            let kind = if base.is_some() { "content" } else { "add/add" };
+            self.message(path, format!("CONFLICT ({kind}): Merge conflict in {path}"));
+        }
+        Ok((DiffSide { mode, hash }, conflicted))
+    }
+}
+
+/// Merges the commit into the current branch, as a fast-forward when possible, otherwise with a merge commit.
+/// Stops before committing when there are conflicts or with --squash
+pub(crate) fn merge_command(name: String, no_ff: bool, ff_only: bool, squash: bool, message: Option<String>, allow_unrelated: bool) -> anyhow::Result<()> {
+    let mut index = Index::read()?;
+    check_unmerged_files(&index, "Merging", false)?;
+    if Path::new(MERGE_HEAD_PATH).exists() {
+        bail!("You have not concluded your merge (MERGE_HEAD exists).\nPlease, commit your changes before you merge.");
+    }
+    let config = Config::read()?;
+    let theirs = resolve_revision(&name)
+        .and_then(|x| peel(&x, ObjectType::Commit))
+        .ok()
+        .context(format!("{name} - not something we can merge"))?;
+    let theirs_tree = peel(&theirs, ObjectType::Tree)?;
+    let committer = read_ident(Role::Committer, &config)?;
+    let reflog_message = format!("merge {name}");
+    let Some(ours) = read_head_commit()? else {
+        // an unborn branch just starts at the merged commit
+        switch_trees(None, Some(&theirs_tree), &mut index, false, SwitchAction::Merge)?;
+        index.write()?;
+        return update_head(&theirs, None, &committer, "initial pull");
+    };
+    let ours_tree = peel(&ours, ObjectType::Tree)?;
+    write_orig_head(&ours)?;
+
+    let bases = merge_bases(&ours, &theirs)?;
+    if bases.contains(&theirs) {
+        println!("Already up to date.{}", if squash { " (nothing to squash)" } else { "" });
+        return Ok(());
+    }
+    if bases == [ours.clone()] && !no_ff {
+        println!("Updating {}..{}", abbreviate(&ours), abbreviate(&theirs));
+        println!("Fast-forward");
+        switch_trees(Some(&ours_tree), Some(&theirs_tree), &mut index, false, SwitchAction::Merge)?;
+        index.write()?;
+        if squash {
+            println!("Squash commit -- not updating HEAD");
+            write_squash_message(&ours, &theirs)?;
+        } else {
+            update_head(&theirs, Some(&ours), &committer, &format!("{reflog_message}: Fast-forward"))?;
+        }
+        return print_merge_stat(&ours_tree, &theirs_tree, &config);
+    }
+    if ff_only {
+        bail!("Not possible to fast-forward, aborting.");
+    }
+    if bases.is_empty() && !allow_unrelated {
+        bail!("refusing to merge unrelated histories");
+    }
+
+    // the merge starts from the index of HEAD, local changes in the working tree only stop it where it changes files
+    let staged = diff_tree_to_index(Some(&ours_tree), &index, true, &Pathspec::new(&[]))?;
+    if !staged.is_empty() {
+        let paths = staged.iter().map(|x| format!("  {}\n", x.path)).collect::<String>();
+        bail!("Your local changes to the following files would be overwritten by merge:\n{paths}Merge with strategy ort failed.");
+    }
+    let options = merge_options("HEAD".to_string(), name.clone(), &config)?;
+    let result = merge_commits(&bases, &ours, &theirs, &options)?;
+    let merged_tree = result.tree.clone();
+    let conflicted_paths = apply_merge_result(&ours_tree, result, &mut index, "Merge with strategy ort failed.", false)?;
+    let merge_message = match message {
+        Some(message) => format!("{message}\n"),
+        None => default_merge_message(&name)?,
+    };
+
+    if squash {
+        write_squash_message(&ours, &theirs)?;
+        if conflicted_paths.is_empty() {
+            println!("Automatic merge went well; stopped before committing as requested");
+        }
+        println!("Squash commit -- not updating HEAD");
+    } else if !conflicted_paths.is_empty() {
+        fs::write(MERGE_HEAD_PATH, format!("{theirs}\n")).context(format!("Failed to write {MERGE_HEAD_PATH}"))?;
+        fs::write(MERGE_MODE_PATH, if no_ff { "no-ff" } else { "" }).context(format!("Failed to write {MERGE_MODE_PATH}"))?;
+        write_merge_message(&merge_message, &conflicted_paths)?;
+    }
+    if !conflicted_paths.is_empty() {
+        println!("Automatic merge failed; fix conflicts and then commit the result.");
+        return Err(Exit(1).into());
+    }
+    if squash {
+        return Ok(());
+    }
+
+    let author = read_ident(Role::Author, &config)?;
+    let hash = write_commit(&merged_tree, &[&ours, &theirs], &author, &committer, &merge_message)?;
+    update_head(&hash, Some(&ours), &committer, &format!("{reflog_message}: Merge made by the 'ort' strategy."))?;
+    println!("Merge made by the 'ort' strategy.");
+    print_merge_stat(&ours_tree, &merged_tree, &config)
+}
+
+/// The message of a merge commit, like "Merge branch 'topic' into feature". The branch merged into is left out
+/// for main and master
+fn default_merge_message(name: &str) -> anyhow::Result<String> {
+    let merged = match dwim_ref(name)? {
+        Some((ref_name, _)) => {
+            if let Some(branch) = ref_name.strip_prefix(HEADS_PREFIX) {
+                format!("branch '{branch}'")
+            } else if let Some(tag) = ref_name.strip_prefix(TAGS_PREFIX) {
+                format!("tag '{tag}'")
+            } else if let Some(branch) = ref_name.strip_prefix(REMOTES_PREFIX) {
+                format!("remote-tracking branch '{branch}'")
+            } else {
+                format!("commit '{name}'")
+            }
+        },
+        None => format!("commit '{name}'"),
+    };
+    let into = match read_head()? {
+        Head::Branch(ref_name) => match ref_name.strip_prefix(HEADS_PREFIX).unwrap_or(&ref_name) {
+            "main" | "master" => String::new(),
+            branch => format!(" into {branch}"),
+        },
+        Head::Detached(_) => String::new(),
+    };
+    Ok(format!("Merge {merged}{into}\n"))
+}
+
+/// Lists the squashed commits in the message prepared for the next commit
+fn write_squash_message(ours: &str, theirs: &str) -> anyhow::Result<()> {
+    let commits = rev_list(&[theirs.to_string()], &[ours.to_string()], &RevListOptions::default())?.commits;
+    let mut message = b"Squashed commit of the following:\n".to_vec();
+    for commit in &commits {
+        message.push(b'\n');
+        show_commit(commit, &Pathspec::new(&[]), &DiffFormat::default(), &DiffOptions::default(), &mut message)?;
+    }
+    fs::write(SQUASH_MSG_PATH, message).context(format!("Failed to write {SQUASH_MSG_PATH}"))
+}
+
+/// The statistics and the summary of the changes a merge made to the current branch
+fn print_merge_stat(old_tree: &str, new_tree: &str, config: &Config) -> anyhow::Result<()> {
+    let changes = diff_trees(Some(old_tree), Some(new_tree), true, &Pathspec::new(&[]))?;
+    let renames = RenameOptions { detection: RenameDetection::Renames, ..RenameOptions::from_config(config, true)? };
+    let changes = detect_renames(changes, &renames, || Ok(vec![]))?;
+    let format = DiffFormat { stat: true, summary: true, ..Default::default() };
+    let options = DiffOptions { renames, ..Default::default() };
+    write_changes(&changes, &format, &options, &mut BufWriter::new(stdout().lock()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::commit_object_read::Signature;
+    use crate::common::init_worktree_test;
+    use crate::file_merge::MARKER_SIZE;
+
+    /// Writes a tree of files with the contents
+    fn tree(files: &[(&str, &str)]) -> anyhow::Result<String> {
+        let mut blobs = vec![];
+        for (path, content) in files {
+            blobs.push((*path, hash_object(content.as_bytes(), ObjectType::Blob, content.len() as u64, true)?));
+        }
+        blobs.sort();
+        write_tree_level(&blobs.iter().map(|(path, hash)| (*path, ObjectMode::Normal, hash.as_str())).collect::<Vec<_>>())
+    }
+
+    /// The files of a tree with their contents
+    fn files(tree: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let mut files = vec![];
+        walk_trees(&[Some(tree)], "", &mut |path, sides| {
+            if let Some(side) = &sides[0] {
+                files.push((path, String::from_utf8_lossy(&read_blob(&side.hash)?).to_string()));
+            }
+            Ok(())
+        })?;
+        Ok(files)
+    }
+
+    /// The conflicted paths with the stages they have
+    fn conflicts(result: &MergeResult) -> Vec<(&str, [bool; 3])> {
+        result.conflicts.iter().map(|(path, sides)| (path.as_str(), sides.each_ref().map(Option::is_some))).collect()
+    }
+
+    fn merge(base: &[(&str, &str)], ours: &[(&str, &str)], theirs: &[(&str, &str)]) -> anyhow::Result<MergeResult> {
+        let options = MergeOptions {
+            ours_label: "ours".to_string(),
+            theirs_label: "theirs".to_string(),
+            style: ConflictStyle::Merge,
+            renames: RenameOptions { detection: RenameDetection::Renames, ..RenameOptions::default() },
+            marker_size: MARKER_SIZE,
+        };
+        merge_trees(Some(&tree(base)?), &tree(ours)?, &tree(theirs)?, "base", &options)
+    }
+
+    fn owned(files: &[(&str, &str)]) -> Vec<(String, String)> {
+        files.iter().map(|(path, content)| (path.to_string(), content.to_string())).collect()
+    }
+
+    const CONTENT: &str = "one\ntwo\nthree\nfour\nfive\n";
+
+    #[test]
+    fn test_rename_rename() -> anyhow::Result<()> {
+        let _guard = init_worktree_test("rename-rename")?;
+        let result = merge(&[("a.txt", CONTENT)], &[("b.txt", CONTENT)], &[("c.txt", CONTENT)])?;
+        assert_eq!(vec!["CONFLICT (rename/rename): a.txt renamed to b.txt in ours and to c.txt in theirs."], result.messages);
+        assert_eq!(owned(&[("b.txt", CONTENT), ("c.txt", CONTENT)]), files(&result.tree)?);
+        assert_eq!(vec![("a.txt", [true, false, false]), ("b.txt", [false, true, false]), ("c.txt", [false, false, true])], conflicts(&result));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_delete() -> anyhow::Result<()> {
+        let _guard = init_worktree_test("rename-delete")?;
+        let result = merge(&[("a.txt", CONTENT), ("x.txt", "x\n")], &[("b.txt", CONTENT), ("x.txt", "x\n")], &[("x.txt", "x\n")])?;
+        assert_eq!(vec!["CONFLICT (rename/delete): a.txt renamed to b.txt in ours, but deleted in theirs."], result.messages);
+        assert_eq!(owned(&[("b.txt", CONTENT), ("x.txt", "x\n")]), files(&result.tree)?);
+        assert_eq!(vec![("b.txt", [true, true, false])], conflicts(&result));
+
+        let result = merge(&[("a.txt", CONTENT), ("x.txt", "x\n")], &[("x.txt", "x\n")], &[("b.txt", CONTENT), ("x.txt", "x\n")])?;
+        assert_eq!(vec!["CONFLICT (rename/delete): a.txt renamed to b.txt in theirs, but deleted in ours."], result.messages);
+        assert_eq!(vec![("b.txt", [true, false, true])], conflicts(&result));
+        Ok(())
+    }
+
+    #[test]
+    fn test_modify_delete() -> anyhow::Result<()> {
+        let _guard = init_worktree_test("modify-delete")?;
+        let result = merge(&[("a.txt", "a\n"), ("x.txt", "x\n")], &[("a.txt", "changed\n"), ("x.txt", "x\n")], &[("x.txt", "x\n")])?;
+        assert_eq!(vec!["CONFLICT (modify/delete): a.txt deleted in theirs and modified in ours.  Version ours of a.txt left in tree."], result.messages);
+        assert_eq!(owned(&[("a.txt", "changed\n"), ("x.txt", "x\n")]), files(&result.tree)?);
+        assert_eq!(vec![("a.txt", [true, true, false])], conflicts(&result));
+
+        let result = merge(&[("a.txt", "a\n"), ("x.txt", "x\n")], &[("x.txt", "x\n")], &[("a.txt", "changed\n"), ("x.txt", "x\n")])?;
+        assert_eq!(vec!["CONFLICT (modify/delete): a.txt deleted in ours and modified in theirs.  Version theirs of a.txt left in tree."], result.messages);
+        assert_eq!(owned(&[("a.txt", "changed\n"), ("x.txt", "x\n")]), files(&result.tree)?);
+        assert_eq!(vec![("a.txt", [true, false, true])], conflicts(&result));
+
+        // a deletion of an unchanged file is clean
+        let result = merge(&[("a.txt", "a\n"), ("x.txt", "x\n")], &[("a.txt", "a\n"), ("x.txt", "x\n")], &[("x.txt", "x\n")])?;
+        assert!(result.messages.is_empty() && result.conflicts.is_empty());
+        assert_eq!(owned(&[("x.txt", "x\n")]), files(&result.tree)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_directory() -> anyhow::Result<()> {
+        let _guard = init_worktree_test("file-directory")?;
+        // the file of ours is moved out of the way of their directory
+        let result = merge(&[("x.txt", "x\n")], &[("path", "file\n"), ("x.txt", "x\n")], &[("path/inner.txt", "inner\n"), ("x.txt", "x\n")])?;
+        assert_eq!(vec!["CONFLICT (file/directory): directory in the way of path from ours; moving it to path~ours instead."], result.messages);
+        assert_eq!(owned(&[("path/inner.txt", "inner\n"), ("path~ours", "file\n"), ("x.txt", "x\n")]), files(&result.tree)?);
+        assert_eq!(vec![("path~ours", [false, true, false])], conflicts(&result));
+
+        // and their file out of the way of our directory
+        let result = merge(&[("x.txt", "x\n")], &[("path/inner.txt", "inner\n"), ("x.txt", "x\n")], &[("path", "file\n"), ("x.txt", "x\n")])?;
+        assert_eq!(vec!["CONFLICT (file/directory): directory in the way of path from theirs; moving it to path~theirs instead."], result.messages);
+        assert_eq!(owned(&[("path/inner.txt", "inner\n"), ("path~theirs", "file\n"), ("x.txt", "x\n")]), files(&result.tree)?);
+        assert_eq!(vec![("path~theirs", [false, false, true])], conflicts(&result));
+        Ok(())
+    }
+
+    #[test]
+    fn test_virtual_base() -> anyhow::Result<()> {
+        let _guard = init_worktree_test("virtual-base")?;
+        let signature = Signature { name: "test".to_string(), email: "test@example.com".to_string(), timestamp: 1_000, timezone: "+0000".to_string() };
+        let commit = |files: &[(&str, &str)], parents: &[&str]| write_commit(&tree(files)?, parents, &signature, &signature, "test");
+        // both sides of a criss-cross merged the two branches, resolving the conflict between them the same way
+        let root = commit(&[("a.txt", "base\n")], &[])?;
+        let one = commit(&[("a.txt", "one\n"), ("one.txt", "1\n")], &[&root])?;
+        let two = commit(&[("a.txt", "two\n"), ("two.txt", "2\n")], &[&root])?;
+        let merged = [("a.txt", "resolved\n"), ("one.txt", "1\n"), ("two.txt", "2\n")];
+        let ours = commit(&merged, &[&one, &two])?;
+        let theirs = commit(&merged, &[&two, &one])?;
+        let ours = commit(&[("a.txt", "resolved\n"), ("two.txt", "2\n")], &[&ours])?;
+        let theirs = commit(&[merged.as_slice(), &[("c.txt", "c\n")]].concat(), &[&theirs])?;
+
+        let bases = merge_bases(&ours, &theirs)?;
+        assert_eq!(2, bases.len());
+        let options = MergeOptions {
+            ours_label: "ours".to_string(),
+            theirs_label: "theirs".to_string(),
+            style: ConflictStyle::Diff3,
+            renames: RenameOptions { detection: RenameDetection::Renames, ..RenameOptions::default() },
+            marker_size: MARKER_SIZE,
+        };
+        // the merge bases are merged into each other from the last one, with their conflicts left in with longer markers
+        let base = virtual_base_tree(&bases, &options)?.unwrap();
+        let (first, second) = match bases[1] == one {
+            true => ("one", "two"),
+            false => ("two", "one"),
+        };
+        let conflicted = format!(
+            "<<<<<<<<< Temporary merge branch 1\n{first}\n||||||||| {}\nbase\n=========\n{second}\n>>>>>>>>> Temporary merge branch 2\n",
+            abbreviate(&root),
+        );
+        assert_eq!(owned(&[("a.txt", &conflicted), ("one.txt", "1\n"), ("two.txt", "2\n")]), files(&base)?);
+
+        // against the virtual base, the deletion of ours and the addition of theirs merge cleanly
+        let result = merge_commits(&bases, &ours, &theirs, &options)?;
+        assert!(result.messages.is_empty() && result.conflicts.is_empty());
+        assert_eq!(owned(&[("a.txt", "resolved\n"), ("c.txt", "c\n"), ("two.txt", "2\n")]), files(&result.tree)?);
+
+        // a conflict shows the virtual base by its label
+        let theirs = commit(&[("a.txt", "theirs\n"), ("one.txt", "1\n"), ("two.txt", "2\n")], &[&theirs])?;
+        let ours = commit(&[("a.txt", "ours\n"), ("two.txt", "2\n")], &[&ours])?;
+        let result = merge_commits(&bases, &ours, &theirs, &options)?;
+        assert_eq!(vec![("a.txt", [true, true, true])], conflicts(&result));
+        let merged = files(&result.tree)?;
+        assert_eq!(("a.txt", format!("<<<<<<< ours\nours\n||||||| merged common ancestors\n{conflicted}=======\ntheirs\n>>>>>>> theirs\n")), (merged[0].0.as_str(), merged[0].1.clone()));
+        Ok(())
+    }
+}
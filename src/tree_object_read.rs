@@ -1,8 +1,8 @@
 use std::ffi::{OsStr, OsString};
 use std::io::{BufRead, Read};
 use anyhow::{bail, Context};
-use crate::common::{HASH_RAW_LEN, ObjectType, TreeItem};
-use crate::object_read::LazyDecodedObject;
+use crate::common::{HASH_RAW_LEN, ObjectMode, ObjectType, TreeItem};
+use crate::object_read::{find_and_decode_object, LazyDecodedObject};
 use std::os::unix::ffi::OsStrExt;
 
 pub(crate) struct TreeObjectIterator<R: BufRead> {
@@ -62,7 +62,7 @@ impl<R: BufRead> TreeObjectIterator<R> {
                 bail!("content size is larger than expected {}", self.size);
             };
         };
-        let mode_len = mode.as_bytes().len();
+        let mode_len = mode.len();
         let mode = mode.parse::<usize>().context(format!("Failed to parse mode {} as int for entry {} from {}", mode, self.entry_no, self.file_path))?;
         let mode = mode.try_into().context(format!("Unexpected mode {} for entry {} from {}", mode, self.entry_no, self.file_path))?;
 
@@ -91,10 +91,10 @@ impl<R: BufRead> TreeObjectIterator<R> {
         if *last != delimiter {
             bail!("Failed to read mode for entry {entry} from {file_path}, delimiter not found");
         }
-        if mode.len() == 0 {
+        if mode.is_empty() {
             bail!("Failed to read mode for entry {entry} from {file_path}: empty name");
         }
-        let mode = mode.into_iter().map(|x| *x as char).collect();
+        let mode = mode.iter().map(|x| *x as char).collect();
         Ok(Some(mode))
     }
     fn parse_name(reader: &mut impl BufRead, entry: usize, file_path: &String) -> anyhow::Result<OsString> {
@@ -108,7 +108,7 @@ impl<R: BufRead> TreeObjectIterator<R> {
         if *last != name_delimiter {
             bail!("Failed to read file name for entry {entry} from {file_path}: delimiter not found");
         }
-        if name.len() == 0 {
+        if name.is_empty() {
             bail!("Failed to read file name for entry {entry} from {file_path}: empty name");
         }
         let name = OsString::from(OsStr::from_bytes(name));
@@ -123,6 +123,38 @@ impl<R: BufRead> TreeObjectIterator<R> {
     }
 }
 
+/// Reads all entries of a tree object into memory
+pub(crate) fn read_tree(hash: &str) -> anyhow::Result<Vec<TreeItem>> {
+    let object = find_and_decode_object(hash)?;
+    let object_type = object.object_type;
+    let Some(iterator) = TreeObjectIterator::from_decoded_object(object) else {
+        bail!("Object {hash} is not a tree, it is actually a {object_type}");
+    };
+    iterator.collect()
+}
+
+/// Looks up an entry by a slash separated path, starting from the given tree
+pub(crate) fn find_tree_entry(tree: &str, path: &str) -> anyhow::Result<Option<TreeItem>> {
+    let mut tree = tree.to_string();
+    let mut components = path.split('/').filter(|x| !x.is_empty()).peekable();
+    while let Some(component) = components.next() {
+        let found = read_tree(&tree)?
+            .into_iter()
+            .find(|x| x.file_name.as_bytes() == component.as_bytes());
+        let Some(found) = found else {
+            return Ok(None);
+        };
+        if components.peek().is_none() {
+            return Ok(Some(found));
+        }
+        if found.mode != ObjectMode::Tree {
+            return Ok(None);
+        }
+        tree = found.hash;
+    }
+    Ok(None)
+}
+
 impl<R: BufRead> Iterator for TreeObjectIterator<R> {
     type Item = anyhow::Result<TreeItem>;
 
@@ -1,9 +1,12 @@
 use std::cmp::Ordering;
 use std::{cmp, fs};
+use std::fs::Metadata;
 use std::io::Write;
 use anyhow::{bail, Context};
 use crate::common::{GIT_PATH, ObjectMode, ObjectType, TreeItem};
+use crate::index::Index;
 use crate::object_write::{hash_blob, hash_object};
+use crate::tree_diff::read_worktree_file;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
@@ -11,7 +14,7 @@ type DirEntry = (PathBuf, ObjectMode);
 
 pub(crate) fn hash_tree(dir_path: &Path, write_files: bool) -> anyhow::Result<Option<String>> {
     let dir_entries = get_dir_entries_sorted(dir_path)?;
-    if dir_entries.len() == 0 {
+    if dir_entries.is_empty() {
         return Ok(None);
     }
 
@@ -34,6 +37,50 @@ pub(crate) fn hash_tree(dir_path: &Path, write_files: bool) -> anyhow::Result<Op
     Ok(Some(hash))
 }
 
+/// Writes the trees for the entries of the index and returns the hash of the root tree, which can be empty
+pub(crate) fn write_index_tree(index: &Index) -> anyhow::Result<String> {
+    if let Some(entry) = index.entries.iter().find(|x| x.stage != 0) {
+        bail!("{}: unmerged ({})", entry.path, entry.hash);
+    }
+    let entries = index.entries
+        .iter()
+        .map(|x| (x.path.as_str(), x.mode, x.hash.as_str()))
+        .collect::<Vec<_>>();
+    write_tree_level(&entries)
+}
+
+/// Writes a tree for entries with paths relative to it, the entries are sorted by path, so each subdirectory is contiguous
+pub(crate) fn write_tree_level(entries: &[(&str, ObjectMode, &str)]) -> anyhow::Result<String> {
+    let mut items = vec![];
+    let mut position = 0;
+    while position < entries.len() {
+        let (path, mode, hash) = entries[position];
+        let Some((dir, _)) = path.split_once('/') else {
+            items.push((path, mode, hash.to_string()));
+            position += 1;
+            continue;
+        };
+        let children = entries[position..]
+            .iter()
+            .take_while(|(path, _, _)| path.split_once('/').is_some_and(|(x, _)| x == dir))
+            .map(|(path, mode, hash)| (&path[dir.len() + 1..], *mode, *hash))
+            .collect::<Vec<_>>();
+        position += children.len();
+        items.push((dir, ObjectMode::Tree, write_tree_level(&children)?));
+    }
+    items.sort_by(|left, right| {
+        compare_tree_names(left.0.as_bytes(), left.1 == ObjectMode::Tree, right.0.as_bytes(), right.1 == ObjectMode::Tree)
+    });
+
+    let mut tree_data = vec![];
+    for (name, mode, hash) in items {
+        let hex = hex::decode(&hash).context(format!("failed to decode hash {hash}"))?;
+        write!(tree_data, "{mode} {name}\0")?;
+        tree_data.extend(hex);
+    }
+    hash_object(tree_data.as_slice(), ObjectType::Tree, tree_data.len() as u64, true)
+}
+
 struct TreeIterator<I: Iterator<Item = DirEntry>> {
     inner: I,
     write_files: bool,
@@ -55,7 +102,12 @@ impl<I: Iterator<Item = DirEntry>> TreeIterator<I> {
         let hash = match mode {
             ObjectMode::Tree => hash_tree(path, self.write_files)?,
             ObjectMode::Normal | ObjectMode::Executable => Some(hash_blob(path, self.write_files)?),
-            ObjectMode::Symlink => bail!("Handling symlinks is not implemented yet! {}", path.display()),
+            ObjectMode::Symlink => {
+                let meta = fs::symlink_metadata(path).context(format!("Failed to read metadata for {}", path.display()))?;
+                let target = read_worktree_file(path, &meta)?;
+                Some(hash_object(target.as_slice(), ObjectType::Blob, target.len() as u64, self.write_files)?)
+            },
+            ObjectMode::Gitlink => bail!("Handling submodules is not implemented yet! {}", path.display()),
         };
         let Some(hash) = hash else {
             return Ok(None);
@@ -85,39 +137,53 @@ fn get_dir_entries_sorted(dir_path: &Path) -> anyhow::Result<Vec<DirEntry>> {
         let dir_entry = dir_entry.context(format!("Some weird error while reading dir entry name in {}", dir_path.to_str().unwrap()))?;
 
         let path = dir_entry.path();
-        let meta = path.metadata().context(format!("Failed to read metadata for {}", path.display()))?;
-        if meta.is_symlink() {
-            bail!("Handling symlinks is not implemented yet! {}", path.display());
-        }
         if path.file_name().unwrap().as_encoded_bytes() == GIT_PATH.as_bytes() {
             // todo: what is the correct way to handle .git dirs and files that are not at the top level?
             continue;
         }
 
-        let meta = path.metadata().context(format!("Failed to read metadata for {}", path.display()))?;
-        if meta.is_symlink() {
-            bail!("Handling symlinks is not implemented yet! {}", path.display());
-        }
-        let mode = if meta.is_dir() {
-            ObjectMode::Tree
-        } else if meta.is_file() {
-            if meta.permissions().mode() & 0o111 != 0 {
-                ObjectMode::Executable
-            } else {
-                ObjectMode::Normal
-            }
-        } else {
-            bail!("found path is neither dir nor file {}", path.display());
-        };
+        let mode = get_file_mode(&path)?;
         files.push((path, mode));
     }
     files.sort_unstable_by(entry_sort);
     Ok(files)
 }
 
+/// Mode of a path in the working tree, a symlink is not followed
+pub(crate) fn get_file_mode(path: &Path) -> anyhow::Result<ObjectMode> {
+    let meta = path.symlink_metadata().context(format!("Failed to read metadata for {}", path.display()))?;
+    let Some(mode) = mode_from_metadata(&meta) else {
+        bail!("found path is neither dir nor file {}", path.display());
+    };
+    Ok(mode)
+}
+
+/// Mode of a file in the working tree, None for special files like sockets
+pub(crate) fn mode_from_metadata(meta: &Metadata) -> Option<ObjectMode> {
+    let mode = if meta.is_symlink() {
+        ObjectMode::Symlink
+    } else if meta.is_dir() {
+        ObjectMode::Tree
+    } else if meta.is_file() {
+        if meta.permissions().mode() & 0o111 != 0 {
+            ObjectMode::Executable
+        } else {
+            ObjectMode::Normal
+        }
+    } else {
+        return None;
+    };
+    Some(mode)
+}
+
 fn entry_sort(left: &DirEntry, right: &DirEntry) -> Ordering {
     let left_name = left.0.file_name().unwrap().as_encoded_bytes();
     let right_name = right.0.file_name().unwrap().as_encoded_bytes();
+    compare_tree_names(left_name, left.1 == ObjectMode::Tree, right_name, right.1 == ObjectMode::Tree)
+}
+
+/// Order of entries in a tree object: by name, where directories are compared as if they had a trailing slash
+pub(crate) fn compare_tree_names(left_name: &[u8], left_is_tree: bool, right_name: &[u8], right_is_tree: bool) -> Ordering {
     let common_len = cmp::min(left_name.len(), right_name.len());
     let (left_base, left_rest) = left_name.split_at(common_len);
     let (right_base, right_rest) = right_name.split_at(common_len);
@@ -127,11 +193,11 @@ fn entry_sort(left: &DirEntry, right: &DirEntry) -> Ordering {
     }
     let left_next = match left_rest.first() {
         Some(x) => x,
-        None => if left.1 == ObjectMode::Tree { &b'/' } else { &0 },
+        None => if left_is_tree { &b'/' } else { &0 },
     };
     let right_next = match right_rest.first() {
         Some(x) => x,
-        None => if right.1 == ObjectMode::Tree { &b'/' } else { &0 },
+        None => if right_is_tree { &b'/' } else { &0 },
     };
     left_next.cmp(right_next)
 }
@@ -146,7 +212,7 @@ mod test {
 
     #[test]
     fn test_hash_tree() -> anyhow::Result<()> {
-        init_test()?;
+        let _guard = init_test()?;
         let path = Path::new("empty");
         fs::create_dir_all(path)?;
         let hash = hash_tree(path, true)?;
@@ -0,0 +1,643 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use anyhow::{bail, Context};
+use crate::common::ObjectType;
+use crate::config::Config;
+use crate::diff::NULL_HASH;
+use crate::fetch::has_object;
+use crate::merge_base::CommitGraph;
+use crate::pack::{ObjectFilter, pack_objects, PackObjectsOptions};
+use crate::pkt_line::{Band, MAX_PACKET_LEN, Packet, PacketReader, PacketWriter, SMALL_SIDEBAND_PACKET_LEN, text_line};
+use crate::refs::{Head, list_refs, read_head, read_head_commit, read_ref};
+use crate::commit_object_read::{CommitObject, read_shallow_commits};
+use crate::rev_list::{collect_ancestors, rev_list, RevListOptions};
+use crate::rev_parse::{peel, peel_tags, read_object_type, resolve_revision};
+use crate::smart_http::{AGENT, CAPABILITIES_REF, OBJECT_FORMAT, PEELED_SUFFIX, ProtocolVersion};
+
+/// A ref as a repository advertises it to the ones fetching from it
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct AdvertisedRef {
+    pub name: String,
+    pub hash: String,
+    /// what an annotated tag points to, after peeling all the tags
+    pub peeled: Option<String>,
+}
+
+/// The refs of a repository: HEAD first if it points to a commit, then all refs sorted by name
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct RefAdvertisement {
+    pub refs: Vec<AdvertisedRef>,
+    /// the branch HEAD is on, even if it has no commits yet
+    pub head_target: Option<String>,
+}
+impl RefAdvertisement {
+    /// The refs of the current repository
+    pub fn read() -> anyhow::Result<Self> {
+        let mut refs = vec![];
+        if let Some(hash) = read_head_commit()? {
+            refs.push(AdvertisedRef { name: "HEAD".to_string(), hash, peeled: None });
+        }
+        for (name, hash) in list_refs("refs/")? {
+            let peeled = match read_object_type(&hash)? {
+                ObjectType::Tag => Some(peel_tags(&hash)?),
+                _ => None,
+            };
+            refs.push(AdvertisedRef { name, hash, peeled });
+        }
+        let head_target = match read_head()? {
+            Head::Branch(name) => Some(name),
+            Head::Detached(_) => None,
+        };
+        Ok(Self { refs, head_target })
+    }
+
+    pub fn find(&self, name: &str) -> Option<&AdvertisedRef> {
+        self.refs.iter().find(|x| x.name == name)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct UploadPackOptions {
+    /// only send this many commits of the history of each wanted commit
+    pub depth: Option<usize>,
+    /// also send the annotated tags that point to sent objects
+    pub include_tag: bool,
+}
+
+pub(crate) struct UploadPackResult {
+    pub pack: Vec<u8>,
+    /// the sent commits whose parents were not sent because of the depth
+    pub shallow: Vec<String>,
+}
+
+/// How a server command talks to its client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ServiceOptions {
+    /// the client sends one request and reads the answer, the way smart HTTP runs the command for each request,
+    /// so the refs are not advertised first
+    pub stateless_rpc: bool,
+    /// only advertise the refs and the capabilities
+    pub advertise_refs: bool,
+    /// the version the client asked for
+    pub version: ProtocolVersion,
+}
+
+/// What a fetch asks upload-pack for
+#[derive(Clone, Debug, Default, PartialEq)]
+struct FetchRequest {
+    wants: Vec<String>,
+    /// the refs the client wants by name in v2, with their values
+    wanted_refs: Vec<(String, String)>,
+    haves: Vec<String>,
+    /// the capabilities of the first want in v0, the flags of the arguments in v2, like thin-pack or include-tag
+    flags: HashSet<String>,
+    /// the commits where the history of the client ends
+    shallow: Vec<String>,
+    depth: Option<usize>,
+    /// the depth counts from the shallow commits of the client instead of from the wants
+    deepen_relative: bool,
+    /// only send the commits made since this time
+    deepen_since: Option<i64>,
+    /// do not send the commits reachable from these refs
+    deepen_not: Vec<String>,
+    filter: Option<ObjectFilter>,
+    done: bool,
+}
+impl FetchRequest {
+    /// Handles the arguments both versions share, false for one it does not know
+    fn parse_argument(&mut self, line: &str, config: &Config) -> anyhow::Result<bool> {
+        if let Some(hash) = line.strip_prefix("shallow ") {
+            self.shallow.push(parse_hash(hash)?);
+        } else if let Some(depth) = line.strip_prefix("deepen ") {
+            let depth = depth.parse::<usize>().ok().filter(|x| *x > 0).context(format!("invalid deepen: {depth}"))?;
+            self.depth = Some(depth);
+        } else if line == "deepen-relative" {
+            self.deepen_relative = true;
+        } else if let Some(time) = line.strip_prefix("deepen-since ") {
+            self.deepen_since = Some(time.parse().ok().context(format!("Invalid deepen-since: {line}"))?);
+        } else if let Some(name) = line.strip_prefix("deepen-not ") {
+            self.deepen_not.push(name.to_string());
+        } else if let Some(spec) = line.strip_prefix("filter ").filter(|_| allows_filter(config)) {
+            self.filter = Some(ObjectFilter::parse(spec)?);
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(flag)
+    }
+
+    /// Whether the fetch changes where the history of the client ends
+    fn deepens(&self) -> bool {
+        self.depth.is_some() || self.deepen_since.is_some() || !self.deepen_not.is_empty()
+    }
+}
+
+fn parse_hash(hash: &str) -> anyhow::Result<String> {
+    if hash.len() != 40 || !hash.bytes().all(|x| x.is_ascii_hexdigit()) {
+        bail!("git upload-pack: protocol error, expected to get object ID, not '{hash}'");
+    }
+    Ok(hash.to_ascii_lowercase())
+}
+
+fn allows_filter(config: &Config) -> bool {
+    matches!(config.get("uploadpack.allowFilter"), Some("true" | "yes" | "on" | "1"))
+}
+
+/// Tells the client why its request is refused before giving up, as an ERR packet
+fn refuse<T>(writer: &mut PacketWriter<impl Write>, message: String) -> anyhow::Result<T> {
+    writer.write_data(format!("ERR {message}").as_bytes())?;
+    writer.flush()?;
+    bail!("git {message}")
+}
+
+/// Serves fetches from the current repository to the client at the other end of the streams, like git upload-pack
+pub(crate) fn serve_upload_pack(input: impl Read, output: impl Write, options: &ServiceOptions) -> anyhow::Result<()> {
+    let config = Config::read()?;
+    let mut reader = PacketReader::new(input);
+    let mut writer = PacketWriter::new(output);
+    if options.version == ProtocolVersion::V2 {
+        if options.advertise_refs || !options.stateless_rpc {
+            write_v2_capabilities(&mut writer, &config)?;
+        }
+        if !options.advertise_refs {
+            while serve_v2_command(&mut reader, &mut writer, &config)? {}
+        }
+        return Ok(());
+    }
+    let advertisement = RefAdvertisement::read()?;
+    if options.advertise_refs || !options.stateless_rpc {
+        if options.version == ProtocolVersion::V1 {
+            writer.write_line("version 1")?;
+        }
+        write_v0_refs(&mut writer, &advertisement, &config)?;
+    }
+    if options.advertise_refs {
+        return Ok(());
+    }
+    let mut request = FetchRequest::default();
+    loop {
+        let line = match reader.read_packet()? {
+            Some(Packet::Data(data)) => text_line(&data),
+            // a client that only wanted the refs hangs up or sends an empty request
+            _ => break,
+        };
+        if let Some(rest) = line.strip_prefix("want ") {
+            let (hash, capabilities) = rest.split_once(' ').unwrap_or((rest, ""));
+            let hash = parse_hash(hash)?;
+            if !is_allowed_want(&hash, &advertisement, &config)? {
+                return refuse(&mut writer, format!("upload-pack: not our ref {hash}"));
+            }
+            if request.wants.is_empty() {
+                request.flags = capabilities.split_whitespace().map(str::to_string).collect();
+                // an argument of its own in v2
+                request.deepen_relative = request.has_flag("deepen-relative");
+            }
+            request.wants.push(hash);
+        } else if !request.parse_argument(&line, &config)? {
+            bail!("git upload-pack: protocol error, expected to get object ID, not '{line}'");
+        }
+    }
+    if request.wants.is_empty() {
+        return Ok(());
+    }
+    let boundary = ShallowBoundary::find(&request)?;
+    if request.deepens() {
+        for line in boundary.lines(&request) {
+            writer.write_line(&line)?;
+        }
+        writer.write_flush()?;
+    }
+    let Some(common) = negotiate_v0(&mut reader, &mut writer, &request, options.stateless_rpc)? else {
+        return Ok(());
+    };
+    let pack = create_pack(&request, &common, &boundary)?;
+    let sideband = match (request.has_flag("side-band-64k"), request.has_flag("side-band")) {
+        (true, _) => Some(MAX_PACKET_LEN),
+        (_, true) => Some(SMALL_SIDEBAND_PACKET_LEN),
+        _ => None,
+    };
+    match sideband {
+        Some(max_packet_len) => {
+            writer.write_band(Band::Data, &pack, max_packet_len)?;
+            writer.write_flush()
+        },
+        None => {
+            let mut output = writer.into_inner();
+            output.write_all(&pack).context("Failed to write to the remote")?;
+            output.flush().context("Failed to write to the remote")
+        },
+    }
+}
+
+/// The refs with the capabilities after a NUL on the first line, then their peeled values and a flush
+fn write_v0_refs(writer: &mut PacketWriter<impl Write>, advertisement: &RefAdvertisement, config: &Config) -> anyhow::Result<()> {
+    let mut capabilities = [
+        "multi_ack", "thin-pack", "side-band", "side-band-64k", "ofs-delta", "shallow", "deepen-since", "deepen-not",
+        "deepen-relative", "no-progress", "include-tag", "multi_ack_detailed",
+    ].map(str::to_string).to_vec();
+    let enabled = |key: &str| matches!(config.get(key), Some("true" | "yes" | "on" | "1"));
+    // allowing any object allows the tips of refs and what they reach as well
+    let any = enabled("uploadpack.allowAnySHA1InWant");
+    if any || enabled("uploadpack.allowTipSHA1InWant") {
+        capabilities.push("allow-tip-sha1-in-want".to_string());
+    }
+    if any || enabled("uploadpack.allowReachableSHA1InWant") {
+        capabilities.push("allow-reachable-sha1-in-want".to_string());
+    }
+    capabilities.push("no-done".to_string());
+    if let Some(target) = advertisement.head_target.as_ref().filter(|_| advertisement.find("HEAD").is_some()) {
+        capabilities.push(format!("symref=HEAD:{target}"));
+    }
+    if allows_filter(config) {
+        capabilities.push("filter".to_string());
+    }
+    capabilities.push(format!("object-format={OBJECT_FORMAT}"));
+    capabilities.push(format!("agent={AGENT}"));
+    let capabilities = capabilities.join(" ");
+    match advertisement.refs.split_first() {
+        None => writer.write_data(format!("{NULL_HASH} {CAPABILITIES_REF}\0{capabilities}\n").as_bytes())?,
+        Some((first, _)) => {
+            writer.write_data(format!("{} {}\0{capabilities}\n", first.hash, first.name).as_bytes())?;
+            for (position, advertised) in advertisement.refs.iter().enumerate() {
+                if position > 0 {
+                    writer.write_line(&format!("{} {}", advertised.hash, advertised.name))?;
+                }
+                if let Some(peeled) = &advertised.peeled {
+                    writer.write_line(&format!("{peeled} {}{PEELED_SUFFIX}", advertised.name))?;
+                }
+            }
+        },
+    }
+    writer.write_flush()
+}
+
+/// Whether a v0 client may want the object: it must be advertised, unless uploadpack.allowTipSHA1InWant allows any
+/// ref or uploadpack.allowReachableSHA1InWant or uploadpack.allowAnySHA1InWant allow more
+fn is_allowed_want(hash: &str, advertisement: &RefAdvertisement, config: &Config) -> anyhow::Result<bool> {
+    let enabled = |key: &str| matches!(config.get(key), Some("true" | "yes" | "on" | "1"));
+    if advertisement.refs.iter().any(|x| x.hash == hash || x.peeled.as_deref() == Some(hash)) {
+        return Ok(true);
+    }
+    if enabled("uploadpack.allowAnySHA1InWant") {
+        return Ok(has_object(hash));
+    }
+    if enabled("uploadpack.allowReachableSHA1InWant") {
+        let Ok(commit) = peel(hash, ObjectType::Commit) else {
+            return Ok(false);
+        };
+        let mut graph = CommitGraph::default();
+        for advertised in &advertisement.refs {
+            if let Ok(tip) = peel(&advertised.hash, ObjectType::Commit) {
+                if graph.is_ancestor(&commit, &tip)? {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Where the history of the client ends after a fetch that deepens it
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ShallowBoundary {
+    /// the sent commits whose parents are not sent
+    shallow: Vec<String>,
+    /// shallow commits of the client whose parents it gets now
+    unshallow: Vec<String>,
+}
+impl ShallowBoundary {
+    fn find(request: &FetchRequest) -> anyhow::Result<Self> {
+        if !request.deepens() {
+            return Ok(Self::default());
+        }
+        if request.depth.is_some() && (request.deepen_since.is_some() || !request.deepen_not.is_empty()) {
+            bail!("git upload-pack: deepen and deepen-since (or deepen-not) cannot be used together");
+        }
+        let (shallow, listed) = match request.depth {
+            Some(depth) => {
+                // relative to the shallow commits of the client, which are one generation into its history
+                let (tips, depth) = match request.deepen_relative {
+                    true => (request.shallow.iter().filter(|x| has_object(x)).cloned().collect(), depth + 1),
+                    false => (request.wants.clone(), depth),
+                };
+                let listed = rev_list(&tips, &[], &RevListOptions { depth: Some(depth), ..RevListOptions::default() })?;
+                (listed.shallow, listed.commits.into_iter().map(|x| x.hash).collect())
+            },
+            None => walk_deepen_by_rev(request)?,
+        };
+        let unshallow = request.shallow.iter().filter(|x| listed.contains(*x) && !shallow.contains(x)).cloned().collect();
+        Ok(Self { shallow, unshallow })
+    }
+
+    /// The shallow lines of the new shallow commits of the client and the unshallow lines
+    fn lines(&self, request: &FetchRequest) -> Vec<String> {
+        self.shallow.iter()
+            .filter(|x| !request.shallow.contains(x))
+            .map(|x| format!("shallow {x}"))
+            .chain(self.unshallow.iter().map(|x| format!("unshallow {x}")))
+            .collect()
+    }
+}
+
+/// The commits a fetch with deepen-since or deepen-not sends, and the ones of them whose parents it does not send
+fn walk_deepen_by_rev(request: &FetchRequest) -> anyhow::Result<(Vec<String>, HashSet<String>)> {
+    let mut not_commits = vec![];
+    for name in &request.deepen_not {
+        let commit = resolve_revision(name).and_then(|x| peel(&x, ObjectType::Commit));
+        not_commits.push(commit.ok().context(format!("git upload-pack: deepen-not is not a ref: {name}"))?);
+    }
+    let shallow_commits = read_shallow_commits()?;
+    let excluded = collect_ancestors(&not_commits, &shallow_commits)?;
+    let kept = |commit: &CommitObject| {
+        !excluded.contains(&commit.hash) && request.deepen_since.is_none_or(|since| commit.committer.timestamp >= since)
+    };
+    let mut pending = request.wants.iter().filter_map(|x| peel(x, ObjectType::Commit).ok()).collect::<Vec<_>>();
+    let mut listed = HashSet::new();
+    let mut shallow = vec![];
+    while let Some(hash) = pending.pop() {
+        let commit = CommitObject::read_cut(&hash, &shallow_commits)?;
+        if listed.contains(&hash) || !kept(&commit) {
+            continue;
+        }
+        let mut parents = vec![];
+        for parent in &commit.parents {
+            parents.push(CommitObject::read_cut(parent, &shallow_commits)?);
+        }
+        match parents.iter().all(kept) {
+            true => pending.extend(parents.into_iter().map(|x| x.hash)),
+            false => shallow.push(hash.clone()),
+        }
+        listed.insert(hash);
+    }
+    if listed.is_empty() {
+        bail!("git upload-pack: no commits selected for shallow requests");
+    }
+    Ok((shallow, listed))
+}
+
+/// Whether the haves the client and this repository share are enough to send a pack: each wanted commit
+/// has one of them in its history
+struct Negotiation<'a> {
+    wants: &'a [String],
+    common: Vec<String>,
+    graph: CommitGraph,
+}
+impl<'a> Negotiation<'a> {
+    fn new(wants: &'a [String]) -> Self {
+        Self { wants, common: vec![], graph: CommitGraph::default() }
+    }
+
+    /// Records a have, true if this repository has it
+    fn add_have(&mut self, hash: &str) -> bool {
+        if !has_object(hash) {
+            return false;
+        }
+        if !self.common.iter().any(|x| x == hash) {
+            self.common.push(hash.to_string());
+        }
+        true
+    }
+
+    fn ok_to_give_up(&mut self) -> anyhow::Result<bool> {
+        let common = self.common.iter().filter_map(|x| peel(x, ObjectType::Commit).ok()).collect::<Vec<_>>();
+        for want in self.wants {
+            let Ok(want) = peel(want, ObjectType::Commit) else {
+                return Ok(false);
+            };
+            let mut found = false;
+            for have in &common {
+                if self.graph.is_ancestor(have, &want)? {
+                    found = true;
+                    break;
+                }
+            }
+            if !found {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Reads the haves of a v0 client and acknowledges them the way its multi_ack mode asks for, until it is done.
+/// Returns the common commits to leave out of the pack, None when a stateless request ends before the client is done
+fn negotiate_v0(reader: &mut PacketReader<impl Read>, writer: &mut PacketWriter<impl Write>, request: &FetchRequest, stateless_rpc: bool) -> anyhow::Result<Option<Vec<String>>> {
+    let detailed = request.has_flag("multi_ack_detailed");
+    let multi_ack = detailed || request.has_flag("multi_ack");
+    let no_done = detailed && request.has_flag("no-done");
+    let mut negotiation = Negotiation::new(&request.wants);
+    let mut last_common = None;
+    let (mut got_common, mut got_other, mut sent_ready) = (false, false, false);
+    loop {
+        match reader.read_packet()? {
+            Some(Packet::Data(data)) => {
+                let line = text_line(&data);
+                if line == "done" {
+                    match (&last_common, multi_ack) {
+                        (Some(hash), true) => writer.write_line(&format!("ACK {hash}"))?,
+                        (Some(_), false) => {},
+                        (None, _) => writer.write_line("NAK")?,
+                    }
+                    writer.flush()?;
+                    return Ok(Some(negotiation.common));
+                }
+                let Some(hash) = line.strip_prefix("have ") else {
+                    bail!("git upload-pack: expected SHA1 list, got '{line}'");
+                };
+                let hash = parse_hash(hash)?;
+                if negotiation.add_have(&hash) {
+                    got_common = true;
+                    match (detailed, multi_ack) {
+                        (true, _) => writer.write_line(&format!("ACK {hash} common"))?,
+                        (_, true) => writer.write_line(&format!("ACK {hash} continue"))?,
+                        // without multi_ack only the first common commit is acknowledged
+                        _ if last_common.is_none() => writer.write_line(&format!("ACK {hash}"))?,
+                        _ => {},
+                    }
+                    last_common = Some(hash);
+                } else {
+                    got_other = true;
+                    if multi_ack && negotiation.ok_to_give_up()? {
+                        match detailed {
+                            true => {
+                                sent_ready = true;
+                                writer.write_line(&format!("ACK {hash} ready"))?;
+                            },
+                            false => writer.write_line(&format!("ACK {hash} continue"))?,
+                        }
+                    }
+                }
+            },
+            Some(Packet::Flush) => {
+                if let Some(hash) = last_common.as_ref().filter(|_| detailed && got_common && !got_other) {
+                    if negotiation.ok_to_give_up()? {
+                        sent_ready = true;
+                        writer.write_line(&format!("ACK {hash} ready"))?;
+                    }
+                }
+                if negotiation.common.is_empty() || multi_ack {
+                    writer.write_line("NAK")?;
+                }
+                if let Some(hash) = last_common.as_ref().filter(|_| no_done && sent_ready) {
+                    writer.write_line(&format!("ACK {hash}"))?;
+                    writer.flush()?;
+                    return Ok(Some(negotiation.common));
+                }
+                writer.flush()?;
+                if stateless_rpc {
+                    return Ok(None);
+                }
+                got_common = false;
+                got_other = false;
+            },
+            Some(packet) => bail!("git upload-pack: expected SHA1 list, got {packet:?}"),
+            None => bail!("the remote end hung up unexpectedly"),
+        }
+    }
+}
+
+/// The pack of the wanted objects, without the ones the client has and without the history beyond where
+/// the history of the client ends
+fn create_pack(request: &FetchRequest, common: &[String], boundary: &ShallowBoundary) -> anyhow::Result<Vec<u8>> {
+    // the history of a shallow client ends somewhere below its haves, so deepening it cannot rely on them
+    let (exclude, shallow) = match (request.deepens(), request.shallow.is_empty()) {
+        (true, true) => (common, &boundary.shallow),
+        (true, false) => (&[][..], &boundary.shallow),
+        (false, _) => (common, &request.shallow),
+    };
+    let options = PackObjectsOptions {
+        shallow: shallow.clone(),
+        include_tag: request.has_flag("include-tag"),
+        thin: request.has_flag("thin-pack"),
+        filter: request.filter,
+        ..PackObjectsOptions::default()
+    };
+    pack_objects(&request.wants, exclude, &options)
+}
+
+fn write_v2_capabilities(writer: &mut PacketWriter<impl Write>, config: &Config) -> anyhow::Result<()> {
+    writer.write_line("version 2")?;
+    writer.write_line(&format!("agent={AGENT}"))?;
+    writer.write_line("ls-refs=unborn")?;
+    match allows_filter(config) {
+        true => writer.write_line("fetch=shallow wait-for-done filter")?,
+        false => writer.write_line("fetch=shallow wait-for-done")?,
+    }
+    writer.write_line("server-option")?;
+    writer.write_line(&format!("object-format={OBJECT_FORMAT}"))?;
+    writer.write_flush()
+}
+
+/// Reads a v2 command with its arguments and answers it, false when the client hung up or sent an empty request
+fn serve_v2_command(reader: &mut PacketReader<impl Read>, writer: &mut PacketWriter<impl Write>, config: &Config) -> anyhow::Result<bool> {
+    let command = match reader.read_packet()? {
+        Some(Packet::Data(data)) => text_line(&data),
+        _ => return Ok(false),
+    };
+    let Some(command) = command.strip_prefix("command=").map(str::to_string) else {
+        return refuse(writer, format!("upload-pack: expected command, got '{command}'"));
+    };
+    // the capabilities of the client, like its agent, do not change the answers
+    let (_, end) = reader.read_until_special()?;
+    let arguments = match end {
+        Packet::Delim => reader.read_until_special()?.0.iter().map(|x| text_line(x)).collect(),
+        _ => vec![],
+    };
+    match command.as_str() {
+        "ls-refs" => serve_ls_refs(writer, &arguments)?,
+        "fetch" => serve_fetch(writer, &arguments, config)?,
+        _ => return refuse(writer, format!("upload-pack: invalid command '{command}'")),
+    }
+    Ok(true)
+}
+
+fn serve_ls_refs(writer: &mut PacketWriter<impl Write>, arguments: &[String]) -> anyhow::Result<()> {
+    let prefixes = arguments.iter().filter_map(|x| x.strip_prefix("ref-prefix ")).collect::<Vec<_>>();
+    let has = |name: &str| arguments.iter().any(|x| x == name);
+    let advertisement = RefAdvertisement::read()?;
+    let matches = |name: &str| prefixes.is_empty() || prefixes.iter().any(|x| name.starts_with(x));
+    if let Some(target) = advertisement.head_target.as_ref().filter(|_| has("unborn") && advertisement.find("HEAD").is_none() && matches("HEAD")) {
+        writer.write_line(&format!("unborn HEAD symref-target:{target}"))?;
+    }
+    for advertised in advertisement.refs.iter().filter(|x| matches(&x.name)) {
+        let mut line = format!("{} {}", advertised.hash, advertised.name);
+        if let Some(target) = advertisement.head_target.as_ref().filter(|_| has("symrefs") && advertised.name == "HEAD") {
+            line.push_str(&format!(" symref-target:{target}"));
+        }
+        if let Some(peeled) = advertised.peeled.as_ref().filter(|_| has("peel")) {
+            line.push_str(&format!(" peeled:{peeled}"));
+        }
+        writer.write_line(&line)?;
+    }
+    writer.write_flush()
+}
+
+fn serve_fetch(writer: &mut PacketWriter<impl Write>, arguments: &[String], config: &Config) -> anyhow::Result<()> {
+    let mut request = FetchRequest::default();
+    for argument in arguments {
+        if let Some(hash) = argument.strip_prefix("want ") {
+            let hash = parse_hash(hash)?;
+            if !has_object(&hash) {
+                return refuse(writer, format!("upload-pack: not our ref {hash}"));
+            }
+            request.wants.push(hash);
+        } else if let Some(name) = argument.strip_prefix("want-ref ") {
+            let Some(hash) = read_ref(name)? else {
+                return refuse(writer, format!("unknown ref {name}"));
+            };
+            request.wants.push(hash.clone());
+            request.wanted_refs.push((name.to_string(), hash));
+        } else if let Some(hash) = argument.strip_prefix("have ") {
+            request.haves.push(parse_hash(hash)?);
+        } else if argument == "done" {
+            request.done = true;
+        } else if matches!(argument.as_str(), "thin-pack" | "no-progress" | "include-tag" | "ofs-delta" | "wait-for-done" | "sideband-all") {
+            request.flags.insert(argument.clone());
+        } else if !request.parse_argument(argument, config)? {
+            return refuse(writer, format!("upload-pack: unexpected line: '{argument}'"));
+        }
+    }
+    if request.wants.is_empty() && !request.has_flag("wait-for-done") {
+        return writer.write_flush();
+    }
+    let mut negotiation = Negotiation::new(&request.wants);
+    for have in &request.haves {
+        negotiation.add_have(have);
+    }
+    if !request.done && !request.haves.is_empty() {
+        writer.write_line("acknowledgments")?;
+        if negotiation.common.is_empty() {
+            writer.write_line("NAK")?;
+        }
+        for hash in &negotiation.common {
+            writer.write_line(&format!("ACK {hash}"))?;
+        }
+        if request.has_flag("wait-for-done") || request.wants.is_empty() || !negotiation.ok_to_give_up()? {
+            return writer.write_flush();
+        }
+        writer.write_line("ready")?;
+        writer.write_delim()?;
+    }
+    let boundary = ShallowBoundary::find(&request)?;
+    if request.deepens() || !request.shallow.is_empty() {
+        writer.write_line("shallow-info")?;
+        for line in boundary.lines(&request) {
+            writer.write_line(&line)?;
+        }
+        writer.write_delim()?;
+    }
+    if !request.wanted_refs.is_empty() {
+        writer.write_line("wanted-refs")?;
+        for (name, hash) in &request.wanted_refs {
+            writer.write_line(&format!("{hash} {name}"))?;
+        }
+        writer.write_delim()?;
+    }
+    let pack = create_pack(&request, &negotiation.common, &boundary)?;
+    writer.write_line("packfile")?;
+    writer.write_band(Band::Data, &pack, MAX_PACKET_LEN)?;
+    writer.write_flush()
+}
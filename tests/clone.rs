@@ -0,0 +1,138 @@
+mod common;
+
+use common::{stderr, TestDir};
+
+/// The commits of the repository that the tests clone
+struct Remote {
+    first: String,
+    second: String,
+    side: String,
+}
+
+/// A repository with two commits on main, one more on the branch side, a tag at the first commit and a note
+fn init_remote(test: &TestDir) -> Remote {
+    test.init("remote", false);
+    let first = test.commit("remote", &[("a.txt", "a\n")], "first");
+    let second = test.commit("remote", &[("dir/b.txt", "b\n")], "second");
+    test.git("remote", &["tag", "v1", &first]);
+    test.git("remote", &["checkout", "-q", "-b", "side"]);
+    let side = test.commit("remote", &[("side.txt", "side\n")], "side");
+    test.git("remote", &["checkout", "-q", "main"]);
+    test.git("remote", &["notes", "add", "-m", "note", &first]);
+    Remote { first, second, side }
+}
+
+#[test]
+fn test_clone() {
+    let test = TestDir::new("clone");
+    let remote = init_remote(&test);
+    test.run_ok(".", &["clone", "remote", "work"]);
+
+    // the branch HEAD of the remote points to is checked out and tracks the one of the remote
+    assert_eq!(remote.second, test.rev_parse("work", "HEAD"));
+    assert_eq!("refs/heads/main", test.git("work", &["symbolic-ref", "HEAD"]));
+    assert_eq!(("origin", "refs/heads/main"), (test.git("work", &["config", "branch.main.remote"]).as_str(), test.git("work", &["config", "branch.main.merge"]).as_str()));
+    assert_eq!("", test.git("work", &["status", "--porcelain"]));
+    assert!(test.exists("work/dir/b.txt") && !test.exists("work/side.txt"));
+
+    // branches become remote-tracking branches, tags are kept and other refs are left out
+    let refs = format!(
+        "{second} refs/heads/main\n{second} refs/remotes/origin/HEAD\n{second} refs/remotes/origin/main\n{side} refs/remotes/origin/side\n{first} refs/tags/v1",
+        first = remote.first,
+        second = remote.second,
+        side = remote.side,
+    );
+    assert_eq!(refs, test.refs("work"));
+    assert_eq!("refs/remotes/origin/main", test.git("work", &["symbolic-ref", "refs/remotes/origin/HEAD"]));
+    test.git("work", &["fsck", "--no-progress"]);
+
+    // a clone does not go into a dir that has files
+    let output = test.run(".", &["clone", "remote", "work"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("destination path 'work' already exists and is not an empty directory."));
+}
+
+#[test]
+fn test_clone_bare() {
+    let test = TestDir::new("clone-bare");
+    let remote = init_remote(&test);
+
+    // a bare clone keeps the branches and tags as they are, without a working tree
+    test.run_ok(".", &["clone", "--bare", "remote"]);
+    let refs = format!("{} refs/heads/main\n{} refs/heads/side\n{} refs/tags/v1", remote.second, remote.side, remote.first);
+    assert_eq!(refs, test.refs("remote.git"));
+    assert_eq!("true", test.git("remote.git", &["config", "core.bare"]));
+    assert_eq!("refs/heads/main", test.git("remote.git", &["symbolic-ref", "HEAD"]));
+    assert!(!test.exists("remote.git/a.txt") && !test.exists("remote.git/index"));
+    assert!(!test.git_succeeds("remote.git", &["config", "remote.origin.fetch"]));
+
+    // a mirror keeps all refs and fetches them as they are
+    test.run_ok(".", &["clone", "--mirror", "remote", "mirror.git"]);
+    let notes = test.rev_parse("remote", "refs/notes/commits");
+    let refs = format!("{} refs/heads/main\n{} refs/heads/side\n{notes} refs/notes/commits\n{} refs/tags/v1", remote.second, remote.side, remote.first);
+    assert_eq!(refs, test.refs("mirror.git"));
+    assert_eq!(("+refs/*:refs/*", "true"), (test.git("mirror.git", &["config", "remote.origin.fetch"]).as_str(), test.git("mirror.git", &["config", "remote.origin.mirror"]).as_str()));
+    test.git("mirror.git", &["fsck", "--no-progress"]);
+}
+
+#[test]
+fn test_clone_branch() {
+    let test = TestDir::new("clone-branch");
+    let remote = init_remote(&test);
+
+    // another branch is checked out and tracked
+    test.run_ok(".", &["clone", "--branch", "side", "remote", "side"]);
+    assert_eq!("refs/heads/side", test.git("side", &["symbolic-ref", "HEAD"]));
+    assert_eq!(remote.side, test.rev_parse("side", "HEAD"));
+    assert_eq!("refs/heads/side", test.git("side", &["config", "branch.side.merge"]));
+    assert!(test.exists("side/side.txt"));
+    // HEAD of the remote still leads to the branch it points to
+    assert_eq!("refs/remotes/origin/main", test.git("side", &["symbolic-ref", "refs/remotes/origin/HEAD"]));
+
+    // a tag detaches HEAD at its commit
+    let output = test.run_ok(".", &["clone", "-b", "v1", "remote", "tag"]);
+    assert!(!test.git_succeeds("tag", &["symbolic-ref", "-q", "HEAD"]));
+    assert_eq!(remote.first, test.rev_parse("tag", "HEAD"));
+    assert!(stderr(&output).contains("You are in 'detached HEAD' state."));
+    assert!(test.exists("tag/a.txt") && !test.exists("tag/dir"));
+
+    // a branch that does not exist leaves nothing behind
+    let output = test.run(".", &["clone", "--branch", "missing", "remote", "missing"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Remote branch missing not found in upstream origin"));
+    assert!(!test.exists("missing"));
+}
+
+#[test]
+fn test_clone_depth() {
+    let test = TestDir::new("clone-depth");
+    let remote = init_remote(&test);
+
+    // a shallow clone over a url fetches the last commits of the branch it checks out
+    test.run_ok(".", &["clone", "--depth", "1", &test.url("remote"), "shallow"]);
+    assert_eq!(remote.second, test.git("shallow", &["rev-list", "HEAD"]));
+    assert_eq!(remote.second, std::fs::read_to_string(test.join("shallow/.git/shallow")).unwrap().trim());
+    assert_eq!("+refs/heads/main:refs/remotes/origin/main", test.git("shallow", &["config", "remote.origin.fetch"]));
+    assert!(!test.git_succeeds("shallow", &["rev-parse", "--verify", "-q", "refs/remotes/origin/side"]));
+    assert_eq!("", test.git("shallow", &["status", "--porcelain"]));
+    test.git("shallow", &["fsck", "--no-progress"]);
+
+    // a local path copies the objects as they are, so the whole history
+    let output = test.run_ok(".", &["clone", "--depth", "1", "remote", "local"]);
+    assert!(stderr(&output).contains("warning: --depth is ignored in local clones; use file:// instead."));
+    assert_eq!("2", test.git("local", &["rev-list", "--count", "HEAD"]));
+    assert!(!test.exists("local/.git/shallow"));
+}
+
+#[test]
+fn test_clone_no_checkout() {
+    let test = TestDir::new("clone-no-checkout");
+    let remote = init_remote(&test);
+
+    // the branch is made, but the working tree and the index stay empty
+    test.run_ok(".", &["clone", "--no-checkout", &test.url("remote"), "work"]);
+    assert_eq!(remote.second, test.rev_parse("work", "HEAD"));
+    assert!(!test.exists("work/a.txt") && !test.exists("work/.git/index"));
+    assert_eq!(remote.side, test.rev_parse("work", "refs/remotes/origin/side"));
+    test.git("work", &["fsck", "--no-progress"]);
+}
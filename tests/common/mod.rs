@@ -0,0 +1,114 @@
+//! Runs the program on repositories that git makes and checks, for the commands that need a process of the program
+//! on the other end, like clone, fetch and push
+// each test file uses some of the helpers
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A dir of its own for a test, with a home dir without config files, so that the identities and the dates are fixed
+pub struct TestDir {
+    pub path: PathBuf,
+}
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("git-e2e-{}-{name}", std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).expect("failed to clean the test dir");
+        }
+        fs::create_dir_all(path.join("home")).expect("failed to create the test dir");
+        Self { path }
+    }
+
+    pub fn join(&self, dir: &str) -> PathBuf {
+        self.path.join(dir)
+    }
+
+    fn command(&self, program: &str, dir: &str) -> Command {
+        let mut command = Command::new(program);
+        command.current_dir(self.join(dir))
+            .env("HOME", self.join("home"))
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_AUTHOR_NAME", "author")
+            .env("GIT_AUTHOR_EMAIL", "author@example.com")
+            .env("GIT_AUTHOR_DATE", "1700000000 +0000")
+            .env("GIT_COMMITTER_NAME", "committer")
+            .env("GIT_COMMITTER_EMAIL", "committer@example.com")
+            .env("GIT_COMMITTER_DATE", "1700000000 +0000")
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("GIT_DIR")
+            .env_remove("GIT_PROTOCOL");
+        command
+    }
+
+    /// Runs git in a dir of the test and returns its trimmed output, it has to succeed
+    pub fn git(&self, dir: &str, args: &[&str]) -> String {
+        let output = self.command("git", dir).args(args).output().expect("failed to run git");
+        assert!(output.status.success(), "git {args:?} failed: {}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    /// Whether git succeeds in a dir of the test, for the checks of refs that may not exist
+    pub fn git_succeeds(&self, dir: &str, args: &[&str]) -> bool {
+        self.command("git", dir).args(args).output().expect("failed to run git").status.success()
+    }
+
+    /// Runs the program in a dir of the test
+    pub fn run(&self, dir: &str, args: &[&str]) -> Output {
+        self.command(env!("CARGO_BIN_EXE_git-starter-rust"), dir).args(args).output().expect("failed to run the program")
+    }
+
+    /// Runs the program in a dir of the test, it has to succeed
+    pub fn run_ok(&self, dir: &str, args: &[&str]) -> Output {
+        let output = self.run(dir, args);
+        assert!(output.status.success(), "{args:?} failed: {}", String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    /// Makes a repository with git, with a branch named main
+    pub fn init(&self, dir: &str, bare: bool) {
+        let path = self.join(dir).display().to_string();
+        match bare {
+            true => self.git(".", &["init", "-q", "--bare", "-b", "main", &path]),
+            false => self.git(".", &["init", "-q", "-b", "main", &path]),
+        };
+    }
+
+    /// Writes the files into the working tree of the repository and commits them with git, returns the commit
+    pub fn commit(&self, dir: &str, files: &[(&str, &str)], message: &str) -> String {
+        for (path, content) in files {
+            let path = self.join(dir).join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).expect("failed to create the dir of a file");
+            }
+            fs::write(path, content).expect("failed to write a file");
+        }
+        self.git(dir, &["add", "-A"]);
+        self.git(dir, &["commit", "-q", "-m", message]);
+        self.rev_parse(dir, "HEAD")
+    }
+
+    pub fn rev_parse(&self, dir: &str, revision: &str) -> String {
+        self.git(dir, &["rev-parse", "--verify", "-q", revision])
+    }
+
+    /// The refs of the repository as `<hash> <name>` lines
+    pub fn refs(&self, dir: &str) -> String {
+        self.git(dir, &["for-each-ref", "--format=%(objectname) %(refname)"])
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        Path::new(&self.join(path)).exists()
+    }
+
+    pub fn url(&self, dir: &str) -> String {
+        format!("file://{}", self.join(dir).display())
+    }
+}
+
+/// The standard error of a run of the program
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
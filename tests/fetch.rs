@@ -0,0 +1,104 @@
+mod common;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use common::{stderr, TestDir};
+
+/// A remote with two commits on main and one on the branch side, and a clone of it by git
+fn init_remote(test: &TestDir) {
+    test.init("remote", false);
+    test.commit("remote", &[("a.txt", "a\n")], "first");
+    test.commit("remote", &[("b.txt", "b\n")], "second");
+    test.git("remote", &["checkout", "-q", "-b", "side"]);
+    test.commit("remote", &[("side.txt", "side\n")], "side");
+    test.git("remote", &["checkout", "-q", "main"]);
+    test.git(".", &["clone", "-q", &test.url("remote"), "work"]);
+}
+
+fn pack_files(test: &TestDir) -> HashSet<PathBuf> {
+    fs::read_dir(test.join("work/.git/objects/pack"))
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.extension().is_some_and(|x| x == "pack"))
+        .collect()
+}
+
+#[test]
+fn test_fetch_negotiation() {
+    let test = TestDir::new("fetch-negotiation");
+    init_remote(&test);
+    let new = test.commit("remote", &[("c.txt", "c\n")], "third");
+    // a local commit the remote does not know does not get in the way
+    test.commit("work", &[("local.txt", "local\n")], "local");
+
+    let packs = pack_files(&test);
+    let output = test.run_ok("work", &["fetch"]);
+    assert_eq!(new, test.rev_parse("work", "refs/remotes/origin/main"));
+    assert!(stderr(&output).contains("main       -> origin/main"));
+    // the haves leave only the commit, its tree and the new file to send
+    let new_packs = pack_files(&test).difference(&packs).cloned().collect::<Vec<_>>();
+    assert_eq!(1, new_packs.len());
+    let pack = fs::read(&new_packs[0]).unwrap();
+    assert_eq!(3, u32::from_be_bytes(pack[8..12].try_into().unwrap()));
+    test.git("work", &["fsck", "--no-progress"]);
+
+    // nothing new leaves the refs and the objects as they are
+    let output = test.run_ok("work", &["fetch"]);
+    assert_eq!("", stderr(&output));
+    assert_eq!(1 + packs.len(), pack_files(&test).len());
+}
+
+#[test]
+fn test_fetch_prune() {
+    let test = TestDir::new("fetch-prune");
+    init_remote(&test);
+    test.git("remote", &["branch", "-D", "side"]);
+
+    // the remote-tracking branch of a deleted branch stays without --prune
+    test.run_ok("work", &["fetch"]);
+    assert!(test.git_succeeds("work", &["rev-parse", "--verify", "-q", "refs/remotes/origin/side"]));
+
+    let output = test.run_ok("work", &["fetch", "--prune"]);
+    assert!(!test.git_succeeds("work", &["rev-parse", "--verify", "-q", "refs/remotes/origin/side"]));
+    assert!(stderr(&output).contains(" - [deleted]         (none)     -> origin/side"), "{}", stderr(&output));
+    assert!(test.git_succeeds("work", &["rev-parse", "--verify", "-q", "refs/remotes/origin/main"]));
+
+    // --no-prune wins over the config
+    test.git("remote", &["branch", "gone"]);
+    test.run_ok("work", &["fetch"]);
+    test.git("remote", &["branch", "-D", "gone"]);
+    test.git("work", &["config", "fetch.prune", "true"]);
+    test.run_ok("work", &["fetch", "--no-prune"]);
+    assert!(test.git_succeeds("work", &["rev-parse", "--verify", "-q", "refs/remotes/origin/gone"]));
+    test.run_ok("work", &["fetch"]);
+    assert!(!test.git_succeeds("work", &["rev-parse", "--verify", "-q", "refs/remotes/origin/gone"]));
+}
+
+#[test]
+fn test_fetch_non_fast_forward() {
+    let test = TestDir::new("fetch-non-fast-forward");
+    init_remote(&test);
+    let old = test.rev_parse("work", "refs/remotes/origin/main");
+    test.git("remote", &["reset", "-q", "--hard", "HEAD~"]);
+    let rewritten = test.commit("remote", &[("c.txt", "c\n")], "rewritten");
+    let refspec = "refs/heads/main:refs/remotes/origin/main";
+
+    // a refspec without + does not rewrite the history of the ref
+    let output = test.run("work", &["fetch", "origin", refspec]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(" ! [rejected]        main       -> origin/main  (non-fast-forward)"), "{}", stderr(&output));
+    assert_eq!(old, test.rev_parse("work", "refs/remotes/origin/main"));
+
+    // + forces the update for the refspec
+    let output = test.run_ok("work", &["fetch", "origin", &format!("+{refspec}")]);
+    assert!(stderr(&output).contains(" + "), "{}", stderr(&output));
+    assert!(stderr(&output).contains("main       -> origin/main  (forced update)"));
+    assert_eq!(rewritten, test.rev_parse("work", "refs/remotes/origin/main"));
+
+    // and --force for all refspecs
+    test.git("work", &["update-ref", "refs/remotes/origin/main", &old]);
+    assert!(!test.run("work", &["fetch", "origin", refspec]).status.success());
+    test.run_ok("work", &["fetch", "--force", "origin", refspec]);
+    assert_eq!(rewritten, test.rev_parse("work", "refs/remotes/origin/main"));
+}
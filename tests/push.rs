@@ -0,0 +1,121 @@
+mod common;
+
+use common::{stderr, TestDir};
+
+/// A bare remote with a commit on main, and a clone of it by git to push from. Returns the commit
+fn init_remote(test: &TestDir) -> String {
+    test.init("remote.git", true);
+    test.git(".", &["clone", "-q", "remote.git", "seed"]);
+    let first = test.commit("seed", &[("a.txt", "a\n")], "first");
+    test.git("seed", &["push", "-q", "origin", "main"]);
+    test.git(".", &["clone", "-q", "remote.git", "work"]);
+    first
+}
+
+/// Whether the ref exists in the repository
+fn has_ref(test: &TestDir, dir: &str, name: &str) -> bool {
+    test.git_succeeds(dir, &["rev-parse", "--verify", "-q", name])
+}
+
+#[test]
+fn test_push() {
+    let test = TestDir::new("push");
+    let first = init_remote(&test);
+    let second = test.commit("work", &[("b.txt", "b\n")], "second");
+
+    // the branch of the remote and its remote-tracking branch move to the pushed commit
+    let output = test.run_ok("work", &["push"]);
+    assert_eq!(second, test.rev_parse("remote.git", "refs/heads/main"));
+    assert_eq!(second, test.rev_parse("work", "refs/remotes/origin/main"));
+    assert!(stderr(&output).contains(&format!("   {}..{}  main -> main", &first[..7], &second[..7])), "{}", stderr(&output));
+    test.git("remote.git", &["fsck", "--no-progress"]);
+
+    // a new branch gets a remote-tracking branch too
+    let output = test.run_ok("work", &["push", "origin", "HEAD:refs/heads/topic"]);
+    assert!(stderr(&output).contains(" * [new branch]      HEAD -> topic"), "{}", stderr(&output));
+    assert_eq!(second, test.rev_parse("remote.git", "refs/heads/topic"));
+    assert_eq!(second, test.rev_parse("work", "refs/remotes/origin/topic"));
+
+    let output = test.run_ok("work", &["push"]);
+    assert!(stderr(&output).contains("Everything up-to-date"));
+}
+
+#[test]
+fn test_push_delete() {
+    let test = TestDir::new("push-delete");
+    init_remote(&test);
+    test.run_ok("work", &["push", "origin", "main:topic"]);
+    assert!(has_ref(&test, "work", "refs/remotes/origin/topic"));
+
+    // the branch and its remote-tracking branch are deleted
+    let output = test.run_ok("work", &["push", "--delete", "origin", "topic"]);
+    assert!(stderr(&output).contains(" - [deleted]         topic"), "{}", stderr(&output));
+    assert!(!has_ref(&test, "remote.git", "refs/heads/topic"));
+    assert!(!has_ref(&test, "work", "refs/remotes/origin/topic"));
+    assert!(has_ref(&test, "remote.git", "refs/heads/main"));
+
+    let output = test.run("work", &["push", "--delete", "origin"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--delete doesn't make sense without any refs"));
+}
+
+#[test]
+fn test_push_force_with_lease() {
+    let test = TestDir::new("push-force-with-lease");
+    init_remote(&test);
+    // someone else pushes to main after the clone, so the remote-tracking branch does not know the new commit
+    let theirs = test.commit("seed", &[("theirs.txt", "theirs\n")], "theirs");
+    test.git("seed", &["push", "-q", "origin", "main"]);
+    let ours = test.commit("work", &[("ours.txt", "ours\n")], "ours");
+
+    let output = test.run("work", &["push"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(" ! [rejected]        main -> main (fetch first)"), "{}", stderr(&output));
+
+    // the lease expects the value of the remote-tracking branch, which the remote no longer has
+    let output = test.run("work", &["push", "--force-with-lease"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(" ! [rejected]        main -> main (stale info)"), "{}", stderr(&output));
+    let output = test.run("work", &["push", &format!("--force-with-lease=main:{ours}")]);
+    assert!(!output.status.success());
+    assert_eq!(theirs, test.rev_parse("remote.git", "refs/heads/main"));
+
+    // a lease on the value the remote has lets the push rewrite the branch
+    let output = test.run_ok("work", &["push", &format!("--force-with-lease=main:{theirs}")]);
+    assert!(stderr(&output).contains(" + "), "{}", stderr(&output));
+    assert!(stderr(&output).contains("main -> main (forced update)"));
+    assert_eq!(ours, test.rev_parse("remote.git", "refs/heads/main"));
+    assert_eq!(ours, test.rev_parse("work", "refs/remotes/origin/main"));
+}
+
+#[test]
+fn test_push_atomic() {
+    let test = TestDir::new("push-atomic");
+    let first = init_remote(&test);
+    test.commit("seed", &[("theirs.txt", "theirs\n")], "theirs");
+    test.git("seed", &["push", "-q", "origin", "main"]);
+    let theirs = test.rev_parse("remote.git", "refs/heads/main");
+    test.commit("work", &[("ours.txt", "ours\n")], "ours");
+    test.git("work", &["branch", "topic", &first]);
+
+    // the rejected update of main keeps the new branch from being created as well
+    let output = test.run("work", &["push", "--atomic", "origin", "main", "topic"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(" ! [rejected]        main -> main (fetch first)"), "{}", stderr(&output));
+    assert!(stderr(&output).contains(" ! [rejected]        topic -> topic (atomic push failed)"));
+    assert_eq!(theirs, test.rev_parse("remote.git", "refs/heads/main"));
+    assert!(!has_ref(&test, "remote.git", "refs/heads/topic"));
+    assert!(!has_ref(&test, "work", "refs/remotes/origin/topic"));
+
+    // without --atomic the branch is created and only main is rejected
+    let output = test.run("work", &["push", "origin", "main", "topic"]);
+    assert!(!output.status.success());
+    assert_eq!(first, test.rev_parse("remote.git", "refs/heads/topic"));
+    assert_eq!(theirs, test.rev_parse("remote.git", "refs/heads/main"));
+    assert!(has_ref(&test, "work", "refs/remotes/origin/topic"));
+
+    // all updates go through when none is rejected
+    test.git("work", &["branch", "other", &first]);
+    test.run_ok("work", &["push", "--atomic", "origin", "other", "topic:renamed"]);
+    assert!(has_ref(&test, "remote.git", "refs/heads/other") && has_ref(&test, "remote.git", "refs/heads/renamed"));
+}